@@ -0,0 +1,143 @@
+#ifndef F_XOSS_H
+#define F_XOSS_H
+
+/* Generated by cbindgen from crates/f-xoss-ffi, don't edit by hand */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// A connection to a device
+typedef struct XossConnection XossConnection;
+
+// A simulated device, for testing the applications without the hardware
+typedef struct XossMockDevice XossMockDevice;
+
+// Connect to a XOSS device found by a scan
+//
+// `address` is the BLE address of the device (like `"C0:11:22:33:44:55"`), or NULL for the first XOSS device found.
+// Gives up when the device is not found in `scan_timeout_ms`. Returns NULL on failure.
+//
+// # Safety
+//
+// `address` must be NULL or a NUL-terminated string.
+struct XossConnection *xoss_connect(const char *address,
+                                    uint32_t scan_timeout_ms);
+
+// Disconnect from the device and free the connection
+//
+// Returns 0, or -1 if the disconnection failed. The connection is freed either way.
+//
+// # Safety
+//
+// `connection` must come from [xoss_connect] or [xoss_connect_mock] and not be used after this call.
+int xoss_disconnect(struct XossConnection *connection);
+
+// The workouts on the device, as a JSON array of `{"name": 1686990000, "size": 12345, "state": 0}`
+//
+// The workout is in the `<name>.fit` file. The states: 0 is not synced, 1 recording, 2 syncing, 3 synced, 4 broken.
+// Returns NULL on failure.
+//
+// # Safety
+//
+// `connection` must be a connection that was not disconnected.
+char *xoss_list_workouts(const struct XossConnection *connection);
+
+// Download a file from the device
+//
+// Stores the length of the file in `len`. Returns NULL on failure.
+//
+// # Safety
+//
+// `connection` must be a connection that was not disconnected, `filename` a NUL-terminated string and `len` a valid
+// pointer.
+uint8_t *xoss_read_file(const struct XossConnection *connection,
+                        const char *filename,
+                        size_t *len);
+
+// Upload the AssistNow Offline data, as downloaded from u-blox
+//
+// The frames with a wrong checksum are dropped. Returns 0, or -1 on failure.
+//
+// # Safety
+//
+// `connection` must be a connection that was not disconnected and `data` point to `len` bytes.
+int xoss_write_mga(const struct XossConnection *connection, const uint8_t *data, size_t len);
+
+// The user profile, as the JSON of `user_profile.json` without the header
+//
+// Returns NULL on failure.
+//
+// # Safety
+//
+// `connection` must be a connection that was not disconnected.
+char *xoss_read_user_profile(const struct XossConnection *connection);
+
+// Replace the user profile, given as the JSON returned by [xoss_read_user_profile]
+//
+// The changed values are checked to be in the ranges the device accepts. Returns 0, or -1 on failure.
+//
+// # Safety
+//
+// `connection` must be a connection that was not disconnected and `profile` a NUL-terminated string.
+int xoss_write_user_profile(const struct XossConnection *connection,
+                            const char *profile);
+
+// Create a simulated device with no files
+//
+// # Safety
+//
+// `serial_number` must be a NUL-terminated string.
+struct XossMockDevice *xoss_mock_device_new(const char *serial_number);
+
+// Put a file on the simulated device, replacing the one with the same name
+//
+// Returns 0, or -1 on failure.
+//
+// # Safety
+//
+// `device` must come from [xoss_mock_device_new], `filename` be a NUL-terminated string and `data` point to `len`
+// bytes.
+int xoss_mock_device_set_file(const struct XossMockDevice *device,
+                              const char *filename,
+                              const uint8_t *data,
+                              size_t len);
+
+// Free the simulated device, the connections to it keep working
+//
+// # Safety
+//
+// `device` must be NULL or come from [xoss_mock_device_new], and not be used after this call.
+void xoss_mock_device_free(struct XossMockDevice *device);
+
+// Connect to a simulated device
+//
+// Returns NULL on failure.
+//
+// # Safety
+//
+// `device` must come from [xoss_mock_device_new].
+struct XossConnection *xoss_connect_mock(const struct XossMockDevice *device);
+
+// Why the last failed call on this thread failed, NULL if none has failed
+//
+// The string is freed with [xoss_string_free].
+char *xoss_last_error(void);
+
+// Free a string returned by the library
+//
+// # Safety
+//
+// `s` must be NULL or a string returned by the library, not freed before.
+void xoss_string_free(char *s);
+
+// Free a buffer returned by the library
+//
+// # Safety
+//
+// `data` must be NULL or a buffer returned by the library along with `len`, not freed before.
+void xoss_bytes_free(uint8_t *data, size_t len);
+
+#endif /* F_XOSS_H */
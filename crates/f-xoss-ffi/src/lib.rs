@@ -0,0 +1,417 @@
+//! C bindings of the core device API, for the mobile and the desktop applications not written in Rust
+//!
+//! Every call blocks until the operation is done, each connection has its own runtime for that. The functions fail by
+//! returning `NULL` or `-1`, and [xoss_last_error] tells why. The structured values (the workouts, the user profile)
+//! are passed as JSON, in the format of the device files.
+//!
+//! The strings and the buffers returned by the library are freed with [xoss_string_free] and [xoss_bytes_free]. The
+//! header is generated with cbindgen, see `cbindgen.toml`.
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use btleplug::api::{BDAddr, Manager as _, Peripheral as _};
+use btleplug::platform::Manager;
+use f_xoss::device::XossDevice;
+use f_xoss::mga::{parse_mga_data, CorruptFrames};
+use f_xoss::model::{UserProfile, UserProfileBuilder};
+use f_xoss::progress::NoProgress;
+use f_xoss::scan::find_device;
+use f_xoss::transport::mock::MockDevice;
+use f_xoss::transport::{DeviceInformation, XossTransport};
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr::null_mut;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+
+/// The file the A-GNSS data is uploaded to
+const MGA_FILENAME: &str = "offline.gnss";
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Run `f`, keeping its error (or panic) for [xoss_last_error] and returning `failed` instead
+fn call<T>(failed: T, f: impl FnOnce() -> Result<T>) -> T {
+    let result = catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|panic| {
+        let message = panic
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        Err(anyhow!("The library panicked: {}", message))
+    });
+    result.unwrap_or_else(|e| {
+        LAST_ERROR.with(|last| *last.borrow_mut() = Some(format!("{:#}", e)));
+        failed
+    })
+}
+
+unsafe fn str_arg<'a>(s: *const c_char, name: &str) -> Result<&'a str> {
+    if s.is_null() {
+        bail!("{} is NULL", name);
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .with_context(|| format!("{} is not valid UTF-8", name))
+}
+
+unsafe fn bytes_arg<'a>(data: *const u8, len: usize, name: &str) -> Result<&'a [u8]> {
+    if len == 0 {
+        return Ok(&[]);
+    }
+    ensure!(!data.is_null(), "{} is NULL", name);
+    Ok(std::slice::from_raw_parts(data, len))
+}
+
+unsafe fn connection_arg<'a>(connection: *const XossConnection) -> Result<&'a XossConnection> {
+    connection.as_ref().context("connection is NULL")
+}
+
+fn string_result(s: String) -> Result<*mut c_char> {
+    Ok(CString::new(s)
+        .context("The string has a NUL byte")?
+        .into_raw())
+}
+
+unsafe fn bytes_result(data: Vec<u8>, len: *mut usize) -> Result<*mut u8> {
+    ensure!(!len.is_null(), "len is NULL");
+    let data = data.into_boxed_slice();
+    *len = data.len();
+    Ok(Box::into_raw(data) as *mut u8)
+}
+
+fn new_runtime() -> Result<Runtime> {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .context("Failed to start the runtime")
+}
+
+/// A connection to a device
+pub struct XossConnection {
+    runtime: Runtime,
+    device: XossDevice,
+}
+
+impl XossConnection {
+    fn run<T>(&self, f: impl std::future::Future<Output = Result<T>>) -> Result<T> {
+        self.runtime.block_on(f)
+    }
+}
+
+/// Connect to a XOSS device found by a scan
+///
+/// `address` is the BLE address of the device (like `"C0:11:22:33:44:55"`), or NULL for the first XOSS device found.
+/// Gives up when the device is not found in `scan_timeout_ms`. Returns NULL on failure.
+///
+/// # Safety
+///
+/// `address` must be NULL or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn xoss_connect(
+    address: *const c_char,
+    scan_timeout_ms: u32,
+) -> *mut XossConnection {
+    call(null_mut(), || {
+        let address = if address.is_null() {
+            None
+        } else {
+            let address = str_arg(address, "address")?;
+            Some(
+                BDAddr::from_str(address)
+                    .with_context(|| format!("Invalid address {:?}", address))?,
+            )
+        };
+
+        let runtime = new_runtime()?;
+        let device = runtime.block_on(async {
+            let manager = Manager::new()
+                .await
+                .context("Failed to create the BLE manager")?;
+            let adapter = manager
+                .adapters()
+                .await
+                .context("Failed to list the Bluetooth adapters")?
+                .into_iter()
+                .next()
+                .context("No Bluetooth adapters found")?;
+
+            let peripheral = find_device(
+                &adapter,
+                address,
+                Duration::from_millis(scan_timeout_ms.into()),
+            )
+            .await?;
+
+            peripheral
+                .connect()
+                .await
+                .context("Failed to connect to the device")?;
+            XossDevice::new(peripheral)
+                .await
+                .context("Failed to initialize the connection to the XOSS device")
+        })?;
+
+        Ok(Box::into_raw(Box::new(XossConnection { runtime, device })))
+    })
+}
+
+/// Disconnect from the device and free the connection
+///
+/// Returns 0, or -1 if the disconnection failed. The connection is freed either way.
+///
+/// # Safety
+///
+/// `connection` must come from [xoss_connect] or [xoss_connect_mock] and not be used after this call.
+#[no_mangle]
+pub unsafe extern "C" fn xoss_disconnect(connection: *mut XossConnection) -> c_int {
+    call(-1, || {
+        ensure!(!connection.is_null(), "connection is NULL");
+        let XossConnection { runtime, device } = *Box::from_raw(connection);
+        runtime.block_on(device.disconnect())?;
+        Ok(0)
+    })
+}
+
+/// The workouts on the device, as a JSON array of `{"name": 1686990000, "size": 12345, "state": 0}`
+///
+/// The workout is in the `<name>.fit` file. The states: 0 is not synced, 1 recording, 2 syncing, 3 synced, 4 broken.
+/// Returns NULL on failure.
+///
+/// # Safety
+///
+/// `connection` must be a connection that was not disconnected.
+#[no_mangle]
+pub unsafe extern "C" fn xoss_list_workouts(connection: *const XossConnection) -> *mut c_char {
+    call(null_mut(), || {
+        let connection = connection_arg(connection)?;
+        let workouts = connection.run(connection.device.read_workouts())?;
+        let workouts = workouts
+            .iter()
+            .map(|workout| {
+                serde_json::json!({
+                    "name": workout.name,
+                    "size": workout.size,
+                    "state": workout.state as u8,
+                })
+            })
+            .collect::<Vec<_>>();
+        string_result(serde_json::Value::from(workouts).to_string())
+    })
+}
+
+/// Download a file from the device
+///
+/// Stores the length of the file in `len`. Returns NULL on failure.
+///
+/// # Safety
+///
+/// `connection` must be a connection that was not disconnected, `filename` a NUL-terminated string and `len` a valid
+/// pointer.
+#[no_mangle]
+pub unsafe extern "C" fn xoss_read_file(
+    connection: *const XossConnection,
+    filename: *const c_char,
+    len: *mut usize,
+) -> *mut u8 {
+    call(null_mut(), || {
+        let connection = connection_arg(connection)?;
+        let filename = str_arg(filename, "filename")?;
+        let data = connection.run(connection.device.read_file(filename, &NoProgress))?;
+        bytes_result(data, len)
+    })
+}
+
+/// Upload the AssistNow Offline data, as downloaded from u-blox
+///
+/// The frames with a wrong checksum are dropped. Returns 0, or -1 on failure.
+///
+/// # Safety
+///
+/// `connection` must be a connection that was not disconnected and `data` point to `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn xoss_write_mga(
+    connection: *const XossConnection,
+    data: *const u8,
+    len: usize,
+) -> c_int {
+    call(-1, || {
+        let connection = connection_arg(connection)?;
+        let data = parse_mga_data(bytes_arg(data, len, "data")?.to_vec(), CorruptFrames::Skip)
+            .context("Invalid MGA data")?;
+        connection.run(async {
+            let device = &connection.device;
+            device
+                .ensure_free_space(MGA_FILENAME, data.data.len() as u64)
+                .await?;
+            device
+                .write_file(MGA_FILENAME, &data.data, &NoProgress)
+                .await
+                .context("Failed to send the MGA data")
+        })?;
+        Ok(0)
+    })
+}
+
+/// The user profile, as the JSON of `user_profile.json` without the header
+///
+/// Returns NULL on failure.
+///
+/// # Safety
+///
+/// `connection` must be a connection that was not disconnected.
+#[no_mangle]
+pub unsafe extern "C" fn xoss_read_user_profile(connection: *const XossConnection) -> *mut c_char {
+    call(null_mut(), || {
+        let connection = connection_arg(connection)?;
+        let profile = connection.run(connection.device.read_user_profile())?;
+        string_result(serde_json::to_string(&profile)?)
+    })
+}
+
+/// Replace the user profile, given as the JSON returned by [xoss_read_user_profile]
+///
+/// The changed values are checked to be in the ranges the device accepts. Returns 0, or -1 on failure.
+///
+/// # Safety
+///
+/// `connection` must be a connection that was not disconnected and `profile` a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn xoss_write_user_profile(
+    connection: *const XossConnection,
+    profile: *const c_char,
+) -> c_int {
+    call(-1, || {
+        let connection = connection_arg(connection)?;
+        let profile: UserProfile = serde_json::from_str(str_arg(profile, "profile")?)
+            .context("Invalid user profile JSON")?;
+        connection.run(async {
+            let device = &connection.device;
+            let current = device.read_user_profile().await?;
+            let mut builder = UserProfileBuilder::new(current).values(profile.user_profile);
+            if let Some(user) = profile.user {
+                builder = builder.user(user);
+            }
+            device.write_user_profile(&builder.build()?).await
+        })?;
+        Ok(0)
+    })
+}
+
+/// A simulated device, for testing the applications without the hardware
+pub struct XossMockDevice(MockDevice);
+
+/// Create a simulated device with no files
+///
+/// # Safety
+///
+/// `serial_number` must be a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn xoss_mock_device_new(serial_number: *const c_char) -> *mut XossMockDevice {
+    call(null_mut(), || {
+        let device = MockDevice::new(DeviceInformation {
+            firmware_revision: "1.0.0".to_string(),
+            manufacturer_name: "XOSS".to_string(),
+            model_number: "XOSS NAV".to_string(),
+            hardware_revision: "A1".to_string(),
+            serial_number: str_arg(serial_number, "serial_number")?.to_string(),
+        });
+        Ok(Box::into_raw(Box::new(XossMockDevice(device))))
+    })
+}
+
+/// Put a file on the simulated device, replacing the one with the same name
+///
+/// Returns 0, or -1 on failure.
+///
+/// # Safety
+///
+/// `device` must come from [xoss_mock_device_new], `filename` be a NUL-terminated string and `data` point to `len`
+/// bytes.
+#[no_mangle]
+pub unsafe extern "C" fn xoss_mock_device_set_file(
+    device: *const XossMockDevice,
+    filename: *const c_char,
+    data: *const u8,
+    len: usize,
+) -> c_int {
+    call(-1, || {
+        let device = device.as_ref().context("device is NULL")?;
+        device.0.set_file(
+            str_arg(filename, "filename")?,
+            bytes_arg(data, len, "data")?,
+        );
+        Ok(0)
+    })
+}
+
+/// Free the simulated device, the connections to it keep working
+///
+/// # Safety
+///
+/// `device` must be NULL or come from [xoss_mock_device_new], and not be used after this call.
+#[no_mangle]
+pub unsafe extern "C" fn xoss_mock_device_free(device: *mut XossMockDevice) {
+    if !device.is_null() {
+        drop(Box::from_raw(device));
+    }
+}
+
+/// Connect to a simulated device
+///
+/// Returns NULL on failure.
+///
+/// # Safety
+///
+/// `device` must come from [xoss_mock_device_new].
+#[no_mangle]
+pub unsafe extern "C" fn xoss_connect_mock(device: *const XossMockDevice) -> *mut XossConnection {
+    call(null_mut(), || {
+        let mock = &device.as_ref().context("device is NULL")?.0;
+        let runtime = new_runtime()?;
+        // the simulated device runs on the runtime too
+        let device = runtime.block_on(async {
+            XossDevice::with_transport(XossTransport::mock(mock, Default::default())).await
+        })?;
+        Ok(Box::into_raw(Box::new(XossConnection { runtime, device })))
+    })
+}
+
+/// Why the last failed call on this thread failed, NULL if none has failed
+///
+/// The string is freed with [xoss_string_free].
+#[no_mangle]
+pub extern "C" fn xoss_last_error() -> *mut c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .and_then(|e| CString::new(e.replace('\0', " ")).ok())
+            .map_or(null_mut(), CString::into_raw)
+    })
+}
+
+/// Free a string returned by the library
+///
+/// # Safety
+///
+/// `s` must be NULL or a string returned by the library, not freed before.
+#[no_mangle]
+pub unsafe extern "C" fn xoss_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+/// Free a buffer returned by the library
+///
+/// # Safety
+///
+/// `data` must be NULL or a buffer returned by the library along with `len`, not freed before.
+#[no_mangle]
+pub unsafe extern "C" fn xoss_bytes_free(data: *mut u8, len: usize) {
+    if !data.is_null() {
+        drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(data, len)));
+    }
+}
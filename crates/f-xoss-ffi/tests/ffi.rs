@@ -0,0 +1,103 @@
+use f_xoss_ffi::*;
+use std::ffi::{CStr, CString};
+use std::ptr::null;
+
+const HEADER: &str =
+    r#""device_model":"XOSS NAV","sn":"0000000001","updated_at":1686990000,"version":"2.0.0""#;
+
+unsafe fn take_string(s: *mut std::ffi::c_char) -> String {
+    assert!(!s.is_null(), "the call failed: {}", last_error());
+    let string = CStr::from_ptr(s).to_str().unwrap().to_string();
+    xoss_string_free(s);
+    string
+}
+
+fn last_error() -> String {
+    let error = xoss_last_error();
+    if error.is_null() {
+        return String::new();
+    }
+    unsafe { take_string(error) }
+}
+
+unsafe fn set_file(mock: *const XossMockDevice, filename: &str, content: &[u8]) {
+    let filename = CString::new(filename).unwrap();
+    assert_eq!(
+        xoss_mock_device_set_file(mock, filename.as_ptr(), content.as_ptr(), content.len()),
+        0
+    );
+}
+
+#[test]
+fn device_is_used_through_the_c_api() {
+    unsafe {
+        let serial_number = CString::new("0000000001").unwrap();
+        let mock = xoss_mock_device_new(serial_number.as_ptr());
+        set_file(
+            mock,
+            "workouts.json",
+            format!(r#"{{{},"workouts":[[1686990000,4,3]]}}"#, HEADER).as_bytes(),
+        );
+        set_file(mock, "1686990000.fit", b"\x0e\x10\x00\x00");
+        set_file(
+            mock,
+            "user_profile.json",
+            format!(
+                r#"{{{},"user_profile":{{"ALAHR":0,"ALASPEED":0,"FTP":200,"LTHR":0,"MAXHR":0,"birthday":0,"gender":0,"height":0,"time_zone":0,"weight":70}}}}"#,
+                HEADER
+            )
+            .as_bytes(),
+        );
+
+        let connection = xoss_connect_mock(mock);
+        assert!(!connection.is_null(), "{}", last_error());
+
+        assert_eq!(
+            take_string(xoss_list_workouts(connection)),
+            r#"[{"name":1686990000,"size":4,"state":3}]"#
+        );
+
+        let filename = CString::new("1686990000.fit").unwrap();
+        let mut len = 0;
+        let data = xoss_read_file(connection, filename.as_ptr(), &mut len);
+        assert!(!data.is_null(), "{}", last_error());
+        assert_eq!(std::slice::from_raw_parts(data, len), b"\x0e\x10\x00\x00");
+        xoss_bytes_free(data, len);
+
+        let profile = take_string(xoss_read_user_profile(connection));
+        let mut profile: serde_json::Value = serde_json::from_str(&profile).unwrap();
+        assert_eq!(profile["user_profile"]["FTP"], 200);
+        profile["user_profile"]["FTP"] = 250.into();
+        let json = CString::new(profile.to_string()).unwrap();
+        assert_eq!(
+            xoss_write_user_profile(connection, json.as_ptr()),
+            0,
+            "{}",
+            last_error()
+        );
+        let profile = take_string(xoss_read_user_profile(connection));
+        assert!(profile.contains(r#""FTP":250"#), "{}", profile);
+
+        // the values out of the device ranges are refused
+        profile_with_weight(connection, 1000);
+        assert!(last_error().contains("weight"));
+
+        // so is the data that isn't MGA
+        assert_eq!(xoss_write_mga(connection, b"garbage".as_ptr(), 7), -1);
+        assert!(last_error().contains("Invalid MGA data"));
+
+        assert!(xoss_read_file(connection, null(), &mut len).is_null());
+        assert_eq!(last_error(), "filename is NULL");
+
+        assert_eq!(xoss_disconnect(connection), 0);
+        xoss_mock_device_free(mock);
+    }
+}
+
+unsafe fn profile_with_weight(connection: *const XossConnection, weight: i64) {
+    let mut profile: serde_json::Value =
+        serde_json::from_str(&take_string(xoss_read_user_profile(connection))).unwrap();
+    profile["user_profile"]["weight"] = weight.into();
+    let json = CString::new(profile.to_string()).unwrap();
+    assert_eq!(xoss_write_user_profile(connection, json.as_ptr()), -1);
+}
@@ -0,0 +1,34 @@
+"""Download the workouts not synced yet to a directory, like `f-xoss-util sync` does
+
+    python sync_workouts.py ~/workouts [C0:11:22:33:44:55]
+"""
+
+import asyncio
+import sys
+from pathlib import Path
+from typing import Optional
+
+import f_xoss_py
+
+NOT_SYNCED = 0
+
+
+async def main(directory: Path, address: Optional[str]):
+    device = await f_xoss_py.connect(address)
+    try:
+        info = await device.device_info()
+        print(f"Connected to {info['model_number']} {info['serial_number']}")
+        await device.set_time()
+
+        for workout in await device.workouts():
+            path = directory / workout["filename"]
+            if workout["state"] != NOT_SYNCED or path.exists():
+                continue
+            print(f"Downloading {workout['filename']} ({workout['size']} bytes)")
+            path.write_bytes(await device.read_file(workout["filename"]))
+    finally:
+        await device.disconnect()
+
+
+if __name__ == "__main__":
+    asyncio.run(main(Path(sys.argv[1]), sys.argv[2] if len(sys.argv) > 2 else None))
@@ -0,0 +1,381 @@
+//! Python bindings of the core device API, for scripting the syncs and the analysis of the workouts
+//!
+//! The device operations are coroutines, run on a tokio runtime shared by all the connections. The structured values
+//! (the workouts, the user profile, the JSON files) are passed as the Python objects `json.loads` would make of the
+//! device files, without the header. The failed operations raise `XossError`.
+//!
+//! The module is built with maturin, see `pyproject.toml`.
+
+use anyhow::Context;
+use btleplug::api::{BDAddr, Manager as _, Peripheral as _};
+use btleplug::platform::Manager;
+use f_xoss::device::XossDevice;
+use f_xoss::mga::{parse_mga_data, CorruptFrames};
+use f_xoss::model::{UserProfile, UserProfileBuilder};
+use f_xoss::progress::NoProgress;
+use f_xoss::scan::find_device;
+use f_xoss::transport::mock::MockDevice;
+use f_xoss::transport::{DeviceInformation, XossTransport};
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use pyo3_async_runtimes::tokio::future_into_py;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// The file the A-GNSS data is uploaded to
+const MGA_FILENAME: &str = "offline.gnss";
+
+/// How long the runtime threads are given to hand the last results to the event loop before the interpreter exits
+const EXIT_GRACE_PERIOD: Duration = Duration::from_millis(100);
+
+create_exception!(
+    f_xoss_py,
+    XossError,
+    PyException,
+    "A device operation failed"
+);
+
+fn error(e: anyhow::Error) -> PyErr {
+    XossError::new_err(format!("{:#}", e))
+}
+
+/// Make a Python object of the JSON value, the way `json.loads` would
+fn to_python(value: &serde_json::Value) -> PyResult<Py<PyAny>> {
+    Python::with_gil(|py| {
+        let json = py.import("json")?;
+        Ok(json.call_method1("loads", (value.to_string(),))?.unbind())
+    })
+}
+
+/// Make a JSON value of the Python object, the way `json.dumps` would
+fn from_python(value: &Bound<'_, PyAny>) -> PyResult<serde_json::Value> {
+    let json = value.py().import("json")?;
+    let text: String = json.call_method1("dumps", (value,))?.extract()?;
+    serde_json::from_str(&text)
+        .context("Invalid JSON")
+        .map_err(error)
+}
+
+/// A connection to a device
+#[pyclass(module = "f_xoss_py")]
+struct Device {
+    /// Each call holds the device until it's done, `None` after the disconnection
+    device: Mutex<Option<Arc<XossDevice>>>,
+}
+
+impl Device {
+    fn new(device: XossDevice) -> Self {
+        Self {
+            device: Mutex::new(Some(Arc::new(device))),
+        }
+    }
+
+    fn get(&self) -> PyResult<Arc<XossDevice>> {
+        self.device
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| XossError::new_err("The device is disconnected"))
+    }
+}
+
+#[pymethods]
+impl Device {
+    /// The device information: `manufacturer_name`, `model_number`, `serial_number`, `firmware_revision` and
+    /// `hardware_revision`
+    fn device_info<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let device = self.get()?;
+        future_into_py(py, async move {
+            let info = device.device_info();
+            to_python(&serde_json::json!({
+                "manufacturer_name": info.manufacturer_name,
+                "model_number": info.model_number,
+                "serial_number": info.serial_number,
+                "firmware_revision": info.firmware_revision,
+                "hardware_revision": info.hardware_revision,
+            }))
+        })
+    }
+
+    /// The battery level in percent, `None` if the device doesn't report it
+    fn battery_level<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let device = self.get()?;
+        future_into_py(py, async move { Ok(device.battery_level().await) })
+    }
+
+    /// The workouts on the device, as a list of `{"name": 1686990000, "size": 12345, "state": 0, "filename":
+    /// "1686990000.fit"}`
+    ///
+    /// The states: 0 is not synced, 1 recording, 2 syncing, 3 synced, 4 broken.
+    fn workouts<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let device = self.get()?;
+        future_into_py(py, async move {
+            let workouts = device.read_workouts().await.map_err(error)?;
+            let workouts = workouts
+                .iter()
+                .map(|workout| {
+                    serde_json::json!({
+                        "name": workout.name,
+                        "size": workout.size,
+                        "state": workout.state as u8,
+                        "filename": workout.filename(),
+                    })
+                })
+                .collect::<Vec<_>>();
+            to_python(&serde_json::Value::from(workouts))
+        })
+    }
+
+    /// Download a file from the device, as `bytes`
+    fn read_file<'py>(&self, py: Python<'py>, filename: String) -> PyResult<Bound<'py, PyAny>> {
+        let device = self.get()?;
+        future_into_py(py, async move {
+            let data = device
+                .read_file(&filename, &NoProgress)
+                .await
+                .map_err(error)?;
+            Ok(Python::with_gil(|py| PyBytes::new(py, &data).unbind()))
+        })
+    }
+
+    /// Upload a file to the device, replacing the one with the same name
+    fn write_file<'py>(
+        &self,
+        py: Python<'py>,
+        filename: String,
+        data: &[u8],
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let device = self.get()?;
+        let data = data.to_vec();
+        future_into_py(py, async move {
+            device
+                .write_file(&filename, &data, &NoProgress)
+                .await
+                .map_err(error)
+        })
+    }
+
+    fn delete_file<'py>(&self, py: Python<'py>, filename: String) -> PyResult<Bound<'py, PyAny>> {
+        let device = self.get()?;
+        future_into_py(py, async move {
+            device.delete_file(&filename).await.map_err(error)
+        })
+    }
+
+    /// Read a JSON file of the device (like `settings.json`), without the header
+    fn read_json<'py>(&self, py: Python<'py>, filename: String) -> PyResult<Bound<'py, PyAny>> {
+        let device = self.get()?;
+        future_into_py(py, async move {
+            let value: serde_json::Value = device.read_json_file(&filename).await.map_err(error)?;
+            to_python(&value)
+        })
+    }
+
+    /// Write a JSON file of the device, adding the header
+    fn write_json<'py>(
+        &self,
+        py: Python<'py>,
+        filename: String,
+        value: &Bound<'py, PyAny>,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let device = self.get()?;
+        let value = from_python(value)?;
+        future_into_py(py, async move {
+            device
+                .write_json_file(&filename, &value)
+                .await
+                .map_err(error)
+        })
+    }
+
+    /// The user profile, as `user_profile.json` without the header
+    fn user_profile<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let device = self.get()?;
+        future_into_py(py, async move {
+            let profile = device.read_user_profile().await.map_err(error)?;
+            let profile = serde_json::to_value(&profile)
+                .context("Failed to serialize the user profile")
+                .map_err(error)?;
+            to_python(&profile)
+        })
+    }
+
+    /// Replace the user profile, given as returned by `user_profile`
+    ///
+    /// The changed values are checked to be in the ranges the device accepts.
+    fn write_user_profile<'py>(
+        &self,
+        py: Python<'py>,
+        profile: &Bound<'py, PyAny>,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let device = self.get()?;
+        let profile: UserProfile = serde_json::from_value(from_python(profile)?)
+            .context("Invalid user profile")
+            .map_err(error)?;
+        future_into_py(py, async move {
+            async {
+                let current = device.read_user_profile().await?;
+                let mut builder = UserProfileBuilder::new(current).values(profile.user_profile);
+                if let Some(user) = profile.user {
+                    builder = builder.user(user);
+                }
+                device.write_user_profile(&builder.build()?).await
+            }
+            .await
+            .map_err(error)
+        })
+    }
+
+    /// Upload the AssistNow Offline data, as downloaded from u-blox
+    ///
+    /// The frames with a wrong checksum are dropped.
+    fn write_mga<'py>(&self, py: Python<'py>, data: &[u8]) -> PyResult<Bound<'py, PyAny>> {
+        let device = self.get()?;
+        let data = parse_mga_data(data.to_vec(), CorruptFrames::Skip)
+            .context("Invalid MGA data")
+            .map_err(error)?;
+        future_into_py(py, async move {
+            async {
+                device
+                    .ensure_free_space(MGA_FILENAME, data.data.len() as u64)
+                    .await?;
+                device
+                    .write_file(MGA_FILENAME, &data.data, &NoProgress)
+                    .await
+                    .context("Failed to send the MGA data")
+            }
+            .await
+            .map_err(error)
+        })
+    }
+
+    /// Set the device clock to the current time
+    fn set_time<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let device = self.get()?;
+        future_into_py(py, async move {
+            device.set_time(SystemTime::now()).await.map_err(error)
+        })
+    }
+
+    /// Disconnect from the device, after the other calls on it are done
+    fn disconnect<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let mut slot = self.device.lock().unwrap();
+        let device = slot
+            .take()
+            .ok_or_else(|| XossError::new_err("The device is disconnected"))?;
+        let device = Arc::try_unwrap(device).map_err(|device| {
+            *slot = Some(device);
+            XossError::new_err("The device is still used by other calls, wait for them first")
+        })?;
+        future_into_py(py, async move { device.disconnect().await.map_err(error) })
+    }
+}
+
+/// Connect to a XOSS device found by a scan
+///
+/// `address` is the BLE address of the device (like `"C0:11:22:33:44:55"`), or `None` for the first XOSS device found.
+/// Gives up when the device is not found in `scan_timeout` seconds.
+#[pyfunction]
+#[pyo3(signature = (address=None, scan_timeout=10.0))]
+fn connect(
+    py: Python<'_>,
+    address: Option<String>,
+    scan_timeout: f64,
+) -> PyResult<Bound<'_, PyAny>> {
+    let address = address
+        .map(|address| {
+            BDAddr::from_str(&address).with_context(|| format!("Invalid address {:?}", address))
+        })
+        .transpose()
+        .map_err(error)?;
+    let scan_timeout = Duration::try_from_secs_f64(scan_timeout)
+        .context("Invalid scan timeout")
+        .map_err(error)?;
+
+    future_into_py(py, async move {
+        async {
+            let manager = Manager::new()
+                .await
+                .context("Failed to create the BLE manager")?;
+            let adapter = manager
+                .adapters()
+                .await
+                .context("Failed to list the Bluetooth adapters")?
+                .into_iter()
+                .next()
+                .context("No Bluetooth adapters found")?;
+            let peripheral = find_device(&adapter, address, scan_timeout).await?;
+
+            peripheral
+                .connect()
+                .await
+                .context("Failed to connect to the device")?;
+            let device = XossDevice::new(peripheral)
+                .await
+                .context("Failed to initialize the connection to the XOSS device")?;
+            Ok(Device::new(device))
+        }
+        .await
+        .map_err(error)
+    })
+}
+
+/// A simulated device, for testing the scripts without the hardware
+#[pyclass(name = "MockDevice", module = "f_xoss_py")]
+struct PyMockDevice(MockDevice);
+
+#[pymethods]
+impl PyMockDevice {
+    /// A simulated device with no files
+    #[new]
+    fn new(serial_number: String) -> Self {
+        Self(MockDevice::new(DeviceInformation {
+            firmware_revision: "1.0.0".to_string(),
+            manufacturer_name: "XOSS".to_string(),
+            model_number: "XOSS NAV".to_string(),
+            hardware_revision: "A1".to_string(),
+            serial_number,
+        }))
+    }
+
+    /// Put a file on the simulated device, replacing the one with the same name
+    fn set_file(&self, filename: &str, data: &[u8]) {
+        self.0.set_file(filename, data);
+    }
+
+    /// Connect to the simulated device
+    fn connect<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let mock = self.0.clone();
+        future_into_py(py, async move {
+            // the simulated device runs on the runtime too
+            let device = XossDevice::with_transport(XossTransport::mock(&mock, Default::default()))
+                .await
+                .map_err(error)?;
+            Ok(Device::new(device))
+        })
+    }
+}
+
+/// Let the runtime threads finish handing the results to the event loop, registered with `atexit`
+///
+/// Passing a result wakes the event loop through a socket, which releases the GIL. A runtime thread that takes it back
+/// when the interpreter is already finalizing crashes the process.
+#[pyfunction]
+fn settle_runtime(py: Python<'_>) {
+    py.allow_threads(|| std::thread::sleep(EXIT_GRACE_PERIOD));
+}
+
+#[pymodule]
+fn f_xoss_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.py()
+        .import("atexit")?
+        .call_method1("register", (wrap_pyfunction!(settle_runtime, m)?,))?;
+    m.add("XossError", m.py().get_type::<XossError>())?;
+    m.add_class::<Device>()?;
+    m.add_class::<PyMockDevice>()?;
+    m.add_function(wrap_pyfunction!(connect, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,415 @@
+//! A background helper keeping the connection to a device between the CLI invocations
+//!
+//! Connecting to a device takes a few seconds, which adds up when running several commands in a row.
+//! With `agent.enabled` in the config (or `--keep-connection`) the first `dev` command starts an agent process,
+//! which connects to the device and listens on a unix socket in the data directory. The following `dev` commands
+//! send their command line to it instead of connecting themselves: the agent runs the command on its connection
+//! and sends the log back. It disconnects and exits after some time without commands.
+//!
+//! The protocol is JSON lines: one [AgentRequest] from the client, then [AgentMessage]s until [AgentMessage::Done].
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc;
+use tracing::field::{Field, Visit};
+use tracing::{debug, error, info, trace, warn, Event, Level, Subscriber};
+use tracing_subscriber::layer::Context as LayerContext;
+use tracing_subscriber::Layer;
+
+use crate::cli::{Cli, CliCommand};
+use crate::config::{XossDeviceInfo, XossUtilConfig};
+use f_xoss::device::XossDevice;
+
+/// How long to wait for a newly started agent to connect to the device
+const AGENT_START_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize, Debug)]
+enum AgentRequest {
+    /// Run a `dev` command, `args` is the whole command line
+    Run { args: Vec<String>, cwd: PathBuf },
+    /// Disconnect and exit
+    Stop,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+enum AgentMessage {
+    Log { level: String, message: String },
+    Done { error: Option<String> },
+}
+
+/// The socket of the agent connected to the device
+///
+/// There is one agent per device, so that commands for different devices don't get mixed up.
+pub fn socket_path(device_info: &XossDeviceInfo) -> PathBuf {
+    let key = device_info
+        .serial_number
+        .clone()
+        .unwrap_or_else(|| device_info.peripheral_id.to_string())
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect::<String>();
+    crate::config::APP_DIRS
+        .data_dir()
+        .join(format!("agent-{}.sock", key))
+}
+
+/// Where the log of the current client goes, set while the agent is running a command
+static CLIENT_LOG: Mutex<Option<mpsc::UnboundedSender<AgentMessage>>> = Mutex::new(None);
+
+#[derive(Default)]
+struct MessageVisitor {
+    message: String,
+    fields: Vec<String>,
+}
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            self.message = format!("{:?}", value);
+        } else {
+            self.fields.push(format!("{}={:?}", field.name(), value));
+        }
+    }
+}
+
+/// Sends the log events to the client the agent is running a command for
+///
+/// Installed in all the processes, it does nothing unless the process is an agent running a command.
+pub struct ForwardLayer;
+
+impl<S: Subscriber> Layer<S> for ForwardLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: LayerContext<'_, S>) {
+        let client = CLIENT_LOG.lock().unwrap();
+        let Some(client) = client.as_ref() else {
+            return;
+        };
+
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+        let mut message = visitor.message;
+        if !visitor.fields.is_empty() {
+            message = format!("{} {}", message, visitor.fields.join(" "));
+        }
+
+        let _ = client.send(AgentMessage::Log {
+            level: event.metadata().level().to_string(),
+            message,
+        });
+    }
+}
+
+async fn write_message(
+    stream: &mut (impl AsyncWriteExt + Unpin),
+    message: &impl Serialize,
+) -> Result<()> {
+    let mut line = serde_json::to_vec(message).context("Serializing an agent message")?;
+    line.push(b'\n');
+    stream
+        .write_all(&line)
+        .await
+        .context("Writing to the agent socket")
+}
+
+/// Run the agent in the foreground until it's idle for `idle_timeout`
+///
+/// With a `keepalive_interval`, the device is checked to be idle between the commands, see [XossDevice::keepalive].
+pub async fn run_agent(
+    device: XossDevice,
+    socket_path: &Path,
+    idle_timeout: Duration,
+    keepalive_interval: Option<Duration>,
+) -> Result<()> {
+    std::fs::create_dir_all(socket_path.parent().unwrap())
+        .context("Creating the data directory")?;
+    // a stale socket of an agent that has crashed
+    if socket_path.exists() {
+        if UnixStream::connect(socket_path).await.is_ok() {
+            bail!("Another agent is already running for this device");
+        }
+        std::fs::remove_file(socket_path).context("Removing the stale agent socket")?;
+    }
+    let listener = UnixListener::bind(socket_path)
+        .with_context(|| format!("Listening on {}", socket_path.display()))?;
+    info!("Agent is listening on {}", socket_path.display());
+
+    let result = match keepalive_interval {
+        Some(interval) => {
+            tokio::select! {
+                result = serve(&device, &listener, idle_timeout) => result,
+                _ = device.keepalive(interval) => unreachable!("the keepalive never returns"),
+            }
+        }
+        None => serve(&device, &listener, idle_timeout).await,
+    };
+
+    let _ = std::fs::remove_file(socket_path);
+    if let Err(e) = device.disconnect().await {
+        warn!("Failed to disconnect from the device: {:#}", e);
+    }
+
+    result
+}
+
+async fn serve(device: &XossDevice, listener: &UnixListener, idle_timeout: Duration) -> Result<()> {
+    loop {
+        let stream = match tokio::time::timeout(idle_timeout, listener.accept()).await {
+            Err(_) => {
+                info!("No commands for {:?}, exiting", idle_timeout);
+                return Ok(());
+            }
+            Ok(accepted) => accepted.context("Accepting an agent connection")?.0,
+        };
+
+        match handle_client(device, stream).await {
+            Ok(true) => {}
+            Ok(false) => return Ok(()),
+            Err(e) => {
+                // the connection to the device may be in a bad state, let the next command connect anew
+                error!("Exiting after an error: {:#}", e);
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Handle a single client, returns whether the agent should keep running
+async fn handle_client(device: &XossDevice, stream: UnixStream) -> Result<bool> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+
+    let Some(line) = lines.next_line().await.context("Reading the request")? else {
+        return Ok(true);
+    };
+    let request: AgentRequest = serde_json::from_str(&line).context("Parsing the request")?;
+    debug!("Agent request: {:?}", request);
+
+    let (args, cwd) = match request {
+        AgentRequest::Stop => {
+            info!("Stopping the agent");
+            write_message(&mut write, &AgentMessage::Done { error: None }).await?;
+            return Ok(false);
+        }
+        AgentRequest::Run { args, cwd } => (args, cwd),
+    };
+
+    let (log_sender, mut log_receiver) = mpsc::unbounded_channel();
+    *CLIENT_LOG.lock().unwrap() = Some(log_sender);
+
+    let command = run_command(device, args, &cwd);
+    tokio::pin!(command);
+    let result = loop {
+        tokio::select! {
+            result = &mut command => break Some(result),
+            Some(message) = log_receiver.recv() => {
+                // the client is gone if this fails, it's noticed below
+                let _ = write_message(&mut write, &message).await;
+            }
+            line = lines.next_line() => {
+                if !matches!(line, Ok(Some(_))) {
+                    break None;
+                }
+            }
+        }
+    };
+    *CLIENT_LOG.lock().unwrap() = None;
+
+    let Some(result) = result else {
+        warn!("The client has disconnected, stopping the command");
+        device
+            .stop_transfer()
+            .await
+            .context("Failed to stop the transfer")?;
+        return Ok(true);
+    };
+
+    while let Ok(message) = log_receiver.try_recv() {
+        let _ = write_message(&mut write, &message).await;
+    }
+    let error = result.err().map(|e| format!("{:#}", e));
+    let _ = write_message(&mut write, &AgentMessage::Done { error }).await;
+
+    Ok(true)
+}
+
+async fn run_command(device: &XossDevice, args: Vec<String>, cwd: &Path) -> Result<()> {
+    let cli = Cli::try_parse_from(args).context("Parsing the command line")?;
+    let CliCommand::Dev(dev) = cli.command else {
+        bail!("The agent only runs dev commands");
+    };
+
+    // commands are run one at a time, so changing the directory of the whole process is fine
+    std::env::set_current_dir(cwd)
+        .with_context(|| format!("Changing the directory to {}", cwd.display()))?;
+    let config = crate::config::load_config().context("Failed to load the config")?;
+
+    device.set_strict_protocol(cli.strict_protocol);
+    let stats = device.transport_stats();
+    let result = dev.run(device, config).await;
+    if cli.verbose {
+        // the connection outlives the command, only its own share is of interest
+        info!(
+            "Link statistics: {}",
+            device.transport_stats().since(&stats)
+        );
+    }
+    result
+}
+
+fn start_agent(device_info: &XossDeviceInfo, adapter: Option<&str>) -> Result<std::process::Child> {
+    let log_path = crate::config::APP_DIRS.cache_dir().join("agent.log");
+    std::fs::create_dir_all(log_path.parent().unwrap()).context("Creating the cache directory")?;
+    let log = std::fs::File::create(&log_path)
+        .with_context(|| format!("Creating {}", log_path.display()))?;
+
+    let mut command =
+        std::process::Command::new(std::env::current_exe().context("Finding the executable")?);
+    command
+        .args([
+            "agent",
+            "run",
+            "--device",
+            &device_info.peripheral_id.to_string(),
+        ])
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .stderr(log);
+    if let Some(adapter) = adapter {
+        command.args(["--adapter", adapter]);
+    }
+    // don't receive the Ctrl+C meant for the command that started it
+    std::os::unix::process::CommandExt::process_group(&mut command, 0);
+
+    info!(
+        "Starting the agent for {}, its log is in {}",
+        device_info.identify(),
+        log_path.display()
+    );
+    command.spawn().context("Starting the agent")
+}
+
+fn any_agent_running() -> bool {
+    std::fs::read_dir(crate::config::APP_DIRS.data_dir()).is_ok_and(|entries| {
+        entries.filter_map(|e| e.ok()).any(|e| {
+            let name = e.file_name();
+            let name = name.to_string_lossy();
+            name.starts_with("agent-") && name.ends_with(".sock")
+        })
+    })
+}
+
+/// Connect to the agent, starting it first if there's none and `start` is set
+async fn connect_agent(
+    device_info: &XossDeviceInfo,
+    adapter: Option<&str>,
+    start: bool,
+) -> Result<Option<UnixStream>> {
+    let socket_path = socket_path(device_info);
+    if let Ok(stream) = UnixStream::connect(&socket_path).await {
+        return Ok(Some(stream));
+    }
+    if !start {
+        return Ok(None);
+    }
+
+    let mut child = start_agent(device_info, adapter)?;
+    let deadline = tokio::time::Instant::now() + AGENT_START_TIMEOUT;
+    while tokio::time::Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        if let Ok(stream) = UnixStream::connect(&socket_path).await {
+            return Ok(Some(stream));
+        }
+        if let Some(status) = child.try_wait().context("Checking the agent process")? {
+            bail!("The agent has exited ({}), see its log for details", status);
+        }
+    }
+
+    bail!("The agent didn't start in {:?}", AGENT_START_TIMEOUT)
+}
+
+fn log_forwarded(level: &str, message: &str) {
+    match level.parse::<Level>() {
+        Ok(Level::ERROR) => error!("{}", message),
+        Ok(Level::WARN) => warn!("{}", message),
+        Ok(Level::INFO) => info!("{}", message),
+        Ok(Level::DEBUG) => debug!("{}", message),
+        _ => trace!("{}", message),
+    }
+}
+
+/// Run the current command through the agent of the selected device
+///
+/// Returns `None` if there's no agent and it shouldn't be started, the command should connect to the device itself then.
+pub async fn run_via_agent(
+    config: &Option<XossUtilConfig>,
+    selector: Option<&str>,
+    adapter: Option<&str>,
+    start: bool,
+) -> Result<Option<Result<()>>> {
+    let Some(config) = config else {
+        return Ok(None);
+    };
+    // don't ask to select a device twice when there are no agents
+    if config.devices.is_empty() || (!start && !any_agent_running()) {
+        return Ok(None);
+    }
+    let device_info = crate::locate_util::select_configured_device(config, selector)?;
+
+    let Some(stream) = connect_agent(device_info, adapter, start).await? else {
+        return Ok(None);
+    };
+    debug!("Running the command through the agent");
+
+    let (read, mut write) = stream.into_split();
+    write_message(
+        &mut write,
+        &AgentRequest::Run {
+            args: std::env::args().collect(),
+            cwd: std::env::current_dir().context("Getting the current directory")?,
+        },
+    )
+    .await?;
+
+    let mut lines = BufReader::new(read).lines();
+    let receive = async {
+        while let Some(line) = lines.next_line().await.context("Reading from the agent")? {
+            match serde_json::from_str(&line).context("Parsing an agent message")? {
+                AgentMessage::Log { level, message } => log_forwarded(&level, &message),
+                AgentMessage::Done { error: None } => return Ok(Ok(())),
+                AgentMessage::Done { error: Some(e) } => return Ok(Err(anyhow!(e))),
+            }
+        }
+        bail!("The agent has closed the connection")
+    };
+
+    tokio::select! {
+        result = receive => result.map(Some),
+        // dropping the connection makes the agent stop the command
+        _ = tokio::signal::ctrl_c() => Err(crate::cli::Interrupted.into()),
+    }
+}
+
+/// Ask the agent of the selected device to exit
+pub async fn stop_agent(config: &Option<XossUtilConfig>, selector: Option<&str>) -> Result<()> {
+    let config = config
+        .as_ref()
+        .context("Config is required for the agent subcommands")?;
+    let device_info = crate::locate_util::select_configured_device(config, selector)?;
+
+    let Ok(stream) = UnixStream::connect(socket_path(device_info)).await else {
+        info!("No agent is running for {}", device_info.identify());
+        return Ok(());
+    };
+    let (read, mut write) = stream.into_split();
+    write_message(&mut write, &AgentRequest::Stop).await?;
+    // wait for the agent to acknowledge
+    let _ = BufReader::new(read).lines().next_line().await;
+    info!("Stopped the agent for {}", device_info.identify());
+
+    Ok(())
+}
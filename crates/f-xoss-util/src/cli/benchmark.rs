@@ -0,0 +1,187 @@
+//! Implementation of the `dev benchmark` subcommand: measuring the file transfer speed
+//!
+//! A synthetic file is uploaded to a scratch file and downloaded back, timing each YMODEM packet. The numbers make it
+//! possible to compare the adapters and to report the slow transfers with something more concrete than "it's slow".
+
+use anyhow::{bail, Context, Result};
+use prettytable::{row, Cell, Table};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+use crate::progress::SpanProgress;
+use f_xoss::device::XossDevice;
+use f_xoss::progress::ProgressSink;
+
+/// The upper bounds of the packet latency histogram buckets, in milliseconds
+const HISTOGRAM_BUCKETS: &[u64] = &[10, 20, 50, 100, 200, 500, 1000];
+
+/// Records the time each packet took and the retries, showing the progress bar as usual
+#[derive(Default)]
+struct PacketTimer {
+    progress: SpanProgress,
+    last_packet: Mutex<Option<Instant>>,
+    latencies: Mutex<Vec<Duration>>,
+    retries: AtomicU32,
+}
+
+impl ProgressSink for PacketTimer {
+    fn start(&self, total: u64) {
+        self.progress.start(total);
+        *self.last_packet.lock().unwrap() = Some(Instant::now());
+    }
+
+    fn advance(&self, delta: u64) {
+        self.progress.advance(delta);
+        let now = Instant::now();
+        if let Some(last_packet) = self.last_packet.lock().unwrap().replace(now) {
+            self.latencies.lock().unwrap().push(now - last_packet);
+        }
+    }
+
+    fn retry(&self) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+struct TransferResult {
+    time: Duration,
+    /// Sorted
+    latencies: Vec<Duration>,
+    retries: u32,
+}
+
+impl TransferResult {
+    fn new(time: Duration, timer: PacketTimer) -> Self {
+        let mut latencies = timer.latencies.into_inner().unwrap();
+        latencies.sort();
+        Self {
+            time,
+            latencies,
+            retries: timer.retries.into_inner(),
+        }
+    }
+
+    fn percentile(&self, percent: usize) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        self.latencies[(self.latencies.len() - 1) * percent / 100]
+    }
+}
+
+fn milliseconds(duration: Duration) -> String {
+    format!("{:.1} ms", duration.as_secs_f64() * 1000.0)
+}
+
+/// Data that doesn't compress and doesn't look like anything the device knows
+fn synthetic_data(size: usize) -> Vec<u8> {
+    let mut state = 0x2545f491u32;
+    (0..size)
+        .map(|_| {
+            // xorshift32
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect()
+}
+
+fn print_results(size: usize, results: &[(&str, TransferResult)]) {
+    let mut table = Table::new();
+    table.set_format(*prettytable::format::consts::FORMAT_BOX_CHARS);
+    table.set_titles(row![
+        "", "Time", "Speed", "Packets", "Retries", "Median", "95%", "Max"
+    ]);
+    for (name, result) in results {
+        table.add_row(row![
+            name,
+            format!("{:.2} s", result.time.as_secs_f64()),
+            format!(
+                "{:.2} KiB/s",
+                size as f64 / result.time.as_secs_f64() / 1024.0
+            ),
+            r -> result.latencies.len(),
+            r -> result.retries,
+            r -> milliseconds(result.percentile(50)),
+            r -> milliseconds(result.percentile(95)),
+            r -> milliseconds(result.latencies.last().copied().unwrap_or_default()),
+        ]);
+    }
+    table.printstd();
+
+    println!();
+    println!("Packet latency:");
+    let mut histogram = Table::new();
+    histogram.set_format(*prettytable::format::consts::FORMAT_CLEAN);
+    let mut titles = row![""];
+    for (name, _) in results {
+        titles.add_cell(Cell::new(name).style_spec("r"));
+    }
+    histogram.set_titles(titles);
+
+    let mut lower = 0;
+    for upper in HISTOGRAM_BUCKETS.iter().copied().map(Some).chain([None]) {
+        let label = match upper {
+            Some(upper) => format!("{}-{} ms", lower, upper),
+            None => format!("{}+ ms", lower),
+        };
+        let mut row = row![r -> label];
+        for (_, result) in results {
+            let count = result
+                .latencies
+                .iter()
+                .filter(|l| {
+                    let ms = l.as_millis() as u64;
+                    ms >= lower && upper.map_or(true, |upper| ms < upper)
+                })
+                .count();
+            row.add_cell(Cell::new(&count.to_string()).style_spec("r"));
+        }
+        histogram.add_row(row);
+        lower = upper.unwrap_or_default();
+    }
+    histogram.printstd();
+}
+
+/// Upload and download a synthetic file of `size_kib` KiB, printing the transfer statistics
+pub async fn benchmark(device: &XossDevice, size_kib: u64) -> Result<()> {
+    if size_kib == 0 {
+        bail!("The file size must be at least 1 KiB");
+    }
+    let data = &synthetic_data(size_kib as usize * 1024);
+
+    let results = device
+        .with_scratch_file(|filename| async move {
+            info!("Uploading {} KiB to {}", size_kib, filename);
+            let timer = PacketTimer::default();
+            let start = Instant::now();
+            device
+                .write_file(&filename, data, &timer)
+                .await
+                .context("Uploading the test file")?;
+            let upload = TransferResult::new(start.elapsed(), timer);
+
+            info!("Downloading it back");
+            let timer = PacketTimer::default();
+            let start = Instant::now();
+            let read = device
+                .read_file(&filename, &timer)
+                .await
+                .context("Downloading the test file")?;
+            let download = TransferResult::new(start.elapsed(), timer);
+
+            if &read != data {
+                bail!("The downloaded file differs from the uploaded one");
+            }
+
+            Ok([("Upload", upload), ("Download", download)])
+        })
+        .await?;
+
+    print_results(data.len(), &results);
+
+    Ok(())
+}
@@ -0,0 +1,97 @@
+//! Implementation of the `dev capabilities` subcommand: what the connected device supports
+//!
+//! Lists the GATT services and characteristics and sends the control messages that only read the device state,
+//! noting which of them the firmware answers. Comparing the reports tells the firmware versions (and the devices of
+//! other brands, like Cycplus) apart.
+
+use anyhow::Result;
+use btleplug::api::CharPropFlags;
+use prettytable::{row, Table};
+
+use f_xoss::device::XossDevice;
+use f_xoss::transport::ctl_message::ControlMessageType;
+use f_xoss::transport::gatt_name;
+
+/// The GATT characteristic properties, as printed
+const PROPERTY_NAMES: &[(CharPropFlags, &str)] = &[
+    (CharPropFlags::BROADCAST, "broadcast"),
+    (CharPropFlags::READ, "read"),
+    (
+        CharPropFlags::WRITE_WITHOUT_RESPONSE,
+        "write-without-response",
+    ),
+    (CharPropFlags::WRITE, "write"),
+    (CharPropFlags::NOTIFY, "notify"),
+    (CharPropFlags::INDICATE, "indicate"),
+    (CharPropFlags::AUTHENTICATED_SIGNED_WRITES, "signed-write"),
+    (CharPropFlags::EXTENDED_PROPERTIES, "extended"),
+];
+
+fn properties(flags: CharPropFlags) -> String {
+    PROPERTY_NAMES
+        .iter()
+        .filter(|(flag, _)| flags.contains(*flag))
+        .map(|(_, name)| *name)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// What the probed control message tells about the device
+fn feature(message_type: ControlMessageType) -> &'static str {
+    match message_type {
+        ControlMessageType::StatusReturn => "Transfer status",
+        ControlMessageType::DbgCmd => "Device ID",
+        ControlMessageType::RequestCap => "Memory capacity",
+        ControlMessageType::RequestMga => "A-GNSS status",
+        ControlMessageType::StatusAct => "Activity status",
+        _ => "(unknown)",
+    }
+}
+
+pub async fn capabilities(device: &XossDevice) -> Result<()> {
+    let device_info = device.device_info();
+    println!(
+        "{} {} (firmware {}, hardware {})",
+        device_info.manufacturer_name,
+        device_info.model_number,
+        device_info.firmware_revision,
+        device_info.hardware_revision
+    );
+    println!();
+
+    let mut gatt = Table::new();
+    gatt.set_format(*prettytable::format::consts::FORMAT_BOX_CHARS);
+    gatt.set_titles(row!["Service", "Characteristic", "Name", "Properties"]);
+    let mut characteristics = Vec::from_iter(device.gatt_characteristics().await);
+    characteristics.sort_by_key(|c| (c.service_uuid, c.uuid));
+    for characteristic in characteristics {
+        gatt.add_row(row![
+            gatt_name(characteristic.service_uuid)
+                .map(str::to_string)
+                .unwrap_or_else(|| characteristic.service_uuid.to_string()),
+            characteristic.uuid,
+            gatt_name(characteristic.uuid).unwrap_or(""),
+            properties(characteristic.properties),
+        ]);
+    }
+    println!("GATT characteristics:");
+    gatt.printstd();
+    println!();
+
+    let mut ctl = Table::new();
+    ctl.set_format(*prettytable::format::consts::FORMAT_BOX_CHARS);
+    ctl.set_titles(row!["Message", "Code", "Feature", "Supported", "Reply"]);
+    for (message_type, result) in device.probe_ctl().await {
+        ctl.add_row(row![
+            format!("{:?}", message_type),
+            format!("{:#04x}", message_type as u8),
+            feature(message_type),
+            if result.is_supported() { "yes" } else { "no" },
+            result,
+        ]);
+    }
+    println!("Control messages:");
+    ctl.printstd();
+
+    Ok(())
+}
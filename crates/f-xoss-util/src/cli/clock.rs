@@ -0,0 +1,172 @@
+//! Setting the device clock and keeping track of how far off it was
+//!
+//! The device can't be asked for its time, the best there is are the timestamps it puts into the headers of the JSON files.
+//! A header dated in the future means the clock is ahead by at least that much, one dated before the device could
+//! have been made means the clock was reset, which happens when the RTC battery runs out. A clock that is behind can't
+//! be told apart from a file that hasn't changed for a while, so it goes unnoticed.
+
+use crate::config::{TimeZoneSetting, XossUtilConfig};
+use crate::state::ClockRecord;
+use anyhow::{Context, Result};
+use chrono::{Local, Offset, TimeZone, Utc};
+use f_xoss::device::XossDevice;
+use f_xoss::model::UserProfileBuilder;
+use f_xoss::time_zone::format_offset;
+use std::time::SystemTime;
+use tracing::{info, warn};
+
+/// Any time before that on the device clock means it was reset (2020-01-01)
+const MIN_PLAUSIBLE_TIME: i64 = 1_577_836_800;
+
+/// The header timestamps this far in the future are not counted as a drift
+const DRIFT_TOLERANCE: i64 = 60;
+
+/// How many of the recorded clock checks finding a reset clock make the RTC battery suspicious
+const RESETS_TO_WARN: usize = 2;
+
+/// What the device clock was found to be, before setting it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockReading {
+    /// At least this many seconds ahead
+    Ahead(i64),
+    /// Reset, the files are dated at this time
+    Reset(i64),
+    /// Nothing wrong could be seen
+    Unknown,
+}
+
+impl ClockReading {
+    pub fn describe(&self) -> String {
+        match *self {
+            ClockReading::Ahead(seconds) => format!("was {} s ahead", seconds),
+            ClockReading::Reset(time) => format!(
+                "was reset to {}",
+                Utc.timestamp_opt(time, 0)
+                    .single()
+                    .map_or_else(|| time.to_string(), |t| t.format("%Y-%m-%d").to_string())
+            ),
+            ClockReading::Unknown => "no drift seen".to_string(),
+        }
+    }
+}
+
+/// Look at the device clock through the JSON header timestamp
+async fn read_clock(device: &XossDevice) -> Result<ClockReading> {
+    let header = device.get_device_json_header().await?;
+    let now = Utc::now().timestamp();
+    Ok(if header.updated_at < MIN_PLAUSIBLE_TIME {
+        ClockReading::Reset(header.updated_at)
+    } else if header.updated_at > now + DRIFT_TOLERANCE {
+        ClockReading::Ahead(header.updated_at - now)
+    } else {
+        ClockReading::Unknown
+    })
+}
+
+/// Log the clock reading, remember it and warn if the clock keeps getting reset
+async fn record_clock(device: &XossDevice, reading: ClockReading) -> Result<()> {
+    match reading {
+        ClockReading::Ahead(seconds) => {
+            info!("The device clock is at least {} s ahead", seconds)
+        }
+        ClockReading::Reset(_) => warn!("The device clock {}", reading.describe()),
+        ClockReading::Unknown => {}
+    }
+
+    let serial_number = device.device_info().serial_number.clone();
+    let mut resets = 0;
+    crate::state::update_state(|state| {
+        let device_state = state.device_mut(&serial_number);
+        device_state.record_clock(ClockRecord {
+            time: Utc::now().timestamp(),
+            drift: match reading {
+                ClockReading::Ahead(seconds) => Some(seconds),
+                _ => None,
+            },
+            reset: matches!(reading, ClockReading::Reset(_)),
+        });
+        resets = device_state.clock_resets();
+    })
+    .context("Saving the clock state")?;
+
+    if resets >= RESETS_TO_WARN {
+        warn!(
+            "The device clock was found reset {} times recently, its RTC battery may be failing",
+            resets
+        );
+    }
+
+    Ok(())
+}
+
+/// Check the clock before setting it
+///
+/// The check is only informational, failing it shouldn't stop the time from being set.
+pub async fn check_clock(device: &XossDevice) -> ClockReading {
+    let reading = match read_clock(device).await {
+        Ok(reading) => reading,
+        Err(e) => {
+            warn!("Failed to check the device clock: {:#}", e);
+            return ClockReading::Unknown;
+        }
+    };
+    if let Err(e) = record_clock(device, reading).await {
+        warn!("{:#}", e);
+    }
+    reading
+}
+
+/// Which time zone to set along with the time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeZoneChoice {
+    /// The one in the config
+    Configured,
+    Utc,
+    /// The current offset of this computer
+    LocalOffset,
+}
+
+/// Set the device time and time zone
+pub async fn set_time(
+    device: &XossDevice,
+    config: Option<&XossUtilConfig>,
+    time_zone: TimeZoneChoice,
+) -> Result<()> {
+    check_clock(device).await;
+
+    device
+        .set_time(SystemTime::now())
+        .await
+        .context("Failed to set the time")?;
+    info!("The device time is set");
+
+    let offset = match time_zone {
+        TimeZoneChoice::Configured => config
+            .map_or(Ok(TimeZoneSetting::Local), |c| c.time_zone())?
+            .offset_at(Utc::now()),
+        TimeZoneChoice::Utc => 0,
+        TimeZoneChoice::LocalOffset => Local
+            .offset_from_utc_datetime(&Utc::now().naive_utc())
+            .fix()
+            .local_minus_utc(),
+    };
+
+    let profile = device.read_user_profile().await?;
+    let device_offset = profile.user_profile.time_zone;
+    if device_offset == offset {
+        info!("The time zone is already {}", format_offset(offset));
+        return Ok(());
+    }
+    let profile = UserProfileBuilder::new(profile)
+        .time_zone(offset)
+        .build()
+        .context("Building the user profile")?;
+    device.write_user_profile(&profile).await?;
+    info!(
+        "The time zone is changed from {} to {}",
+        format_offset(device_offset),
+        format_offset(offset)
+    );
+
+    Ok(())
+}
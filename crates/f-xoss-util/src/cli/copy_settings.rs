@@ -0,0 +1,163 @@
+//! Implementation of the `dev copy-settings` subcommand
+//!
+//! Copies the settings from one device to another, like when replacing an old unit with a new one.
+//! The devices are connected to one after another, so it works with adapters that can't keep two connections.
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use owo_colors::colored::Color;
+use owo_colors::OwoColorize;
+use similar::ChangeTag;
+use tracing::info;
+
+use super::device::confirm;
+use crate::config::XossUtilConfig;
+use crate::locate_util::find_device_from_config;
+use f_xoss::device::XossDevice;
+use f_xoss::model::{Gear, Panels, Settings};
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CopyItem {
+    /// settings.json: language, units, backlight, auto-pause, key tone
+    Settings,
+    /// panels.json: the data screens
+    Panels,
+    /// gear_profile.json: the bikes
+    Gear,
+}
+
+impl CopyItem {
+    pub const ALL: [CopyItem; 3] = [CopyItem::Settings, CopyItem::Panels, CopyItem::Gear];
+
+    fn filename(self) -> &'static str {
+        match self {
+            CopyItem::Settings => "settings.json",
+            CopyItem::Panels => "panels.json",
+            CopyItem::Gear => "gear_profile.json",
+        }
+    }
+}
+
+/// The data of one item, as read from a device
+enum ItemData {
+    Settings(Settings),
+    Panels(Panels),
+    Gear(Vec<Gear>),
+}
+
+impl ItemData {
+    async fn read(device: &XossDevice, item: CopyItem) -> Result<Self> {
+        Ok(match item {
+            CopyItem::Settings => ItemData::Settings(device.read_settings().await?),
+            CopyItem::Panels => ItemData::Panels(device.read_panels().await?),
+            CopyItem::Gear => ItemData::Gear(device.read_gear_profile().await?),
+        })
+    }
+
+    async fn write(&self, device: &XossDevice) -> Result<()> {
+        match self {
+            ItemData::Settings(settings) => device.write_settings(settings).await,
+            ItemData::Panels(panels) => device.write_panels(panels).await,
+            ItemData::Gear(gears) => device.write_gear_profile(gears).await,
+        }
+    }
+
+    /// Pretty JSON to be diffed, without the header that differs between the devices anyway
+    fn to_json(&self) -> Result<String> {
+        let json = match self {
+            ItemData::Settings(settings) => serde_json::to_string_pretty(settings),
+            ItemData::Panels(panels) => serde_json::to_string_pretty(panels),
+            ItemData::Gear(gears) => serde_json::to_string_pretty(gears),
+        };
+        Ok(json? + "\n")
+    }
+}
+
+fn print_diff(old: &str, new: &str) {
+    let diff = similar::TextDiff::from_lines(old, new);
+    for change in diff.iter_all_changes() {
+        let (tag, color) = match change.tag() {
+            ChangeTag::Delete => ("-", Color::Red),
+            ChangeTag::Insert => ("+", Color::Green),
+            ChangeTag::Equal => (" ", Color::White),
+        };
+        print!("{} {}", tag.color(color), change.color(color));
+    }
+    println!();
+}
+
+async fn connect(
+    config: &Option<XossUtilConfig>,
+    selector: &str,
+    adapter: Option<&str>,
+) -> Result<XossDevice> {
+    let device = find_device_from_config(config, Some(selector), adapter)
+        .await
+        .with_context(|| format!("Failed to find the device {:?}", selector))?;
+    crate::history::record_transfers(&device).await;
+    crate::json_backup::back_up_json_files(&device).await;
+    Ok(device)
+}
+
+pub async fn copy_settings(
+    config: &Option<XossUtilConfig>,
+    adapter: Option<&str>,
+    from: &str,
+    to: &str,
+    include: &[CopyItem],
+    yes: bool,
+) -> Result<()> {
+    let include = if include.is_empty() {
+        &CopyItem::ALL[..]
+    } else {
+        include
+    };
+
+    info!("Reading the settings from {}", from);
+    let source = connect(config, from, adapter).await?;
+    let mut items = Vec::new();
+    for &item in include {
+        let data = ItemData::read(&source, item)
+            .await
+            .with_context(|| format!("Reading {} from {}", item.filename(), from))?;
+        items.push((item, data));
+    }
+    source
+        .disconnect()
+        .await
+        .context("Failed to disconnect from the source device")?;
+
+    info!("Comparing with {}", to);
+    let target = connect(config, to, adapter).await?;
+    let mut changed = Vec::new();
+    for (item, data) in items {
+        let current = ItemData::read(&target, item)
+            .await
+            .with_context(|| format!("Reading {} from {}", item.filename(), to))?;
+        let (old, new) = (current.to_json()?, data.to_json()?);
+        if old == new {
+            info!("{} is already the same", item.filename());
+            continue;
+        }
+        println!("Changes to {}:", item.filename());
+        print_diff(&old, &new);
+        changed.push((item, data));
+    }
+
+    if changed.is_empty() {
+        info!("Nothing to copy");
+    } else {
+        confirm(&format!("Apply the changes to {}?", to), yes)?;
+        for (item, data) in &changed {
+            data.write(&target)
+                .await
+                .with_context(|| format!("Writing {} to {}", item.filename(), to))?;
+            info!("Copied {}", item.filename());
+        }
+    }
+
+    target
+        .disconnect()
+        .await
+        .context("Failed to disconnect from the target device")
+}
@@ -0,0 +1,202 @@
+//! Implementation of the `daemon` command: syncing the configured devices whenever they come into range
+//!
+//! The daemon stays in the foreground (which is what a systemd service expects) and looks for the devices that are due
+//! for a sync every `daemon.scan_interval`. A device seen nearby is synced, then disconnected right away, so that it
+//! doesn't spend its battery on an idle connection and stays available to the other apps.
+
+use anyhow::{bail, Context, Result};
+use btleplug::platform::Manager;
+use chrono::Utc;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::select;
+use tokio::time::Instant;
+use tracing::{info, info_span, warn, Instrument};
+
+use crate::cli::sync::sync;
+use crate::cli::SyncOptions;
+use crate::config::{XossDeviceInfo, XossUtilConfig};
+use crate::locate_util;
+
+/// How long to wait before trying again to sync a device after a failed sync
+const RETRY_AFTER_FAILURE: Duration = Duration::from_secs(10 * 60);
+
+/// Waits for the request to stop: Ctrl+C, or SIGTERM from the service manager
+struct Shutdown {
+    #[cfg(unix)]
+    terminate: tokio::signal::unix::Signal,
+}
+
+impl Shutdown {
+    fn new() -> Result<Self> {
+        Ok(Self {
+            #[cfg(unix)]
+            terminate: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                .context("Failed to listen for SIGTERM")?,
+        })
+    }
+
+    async fn wait(&mut self) {
+        #[cfg(unix)]
+        select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = self.terminate.recv() => {}
+        }
+        #[cfg(not(unix))]
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+/// When the daemon last tried to sync a device
+#[derive(Clone, Copy)]
+struct Attempt {
+    time: Instant,
+    succeeded: bool,
+}
+
+/// Whether the device should be synced now
+fn is_due(
+    device_info: &XossDeviceInfo,
+    attempt: Option<&Attempt>,
+    state: &crate::state::UtilState,
+    sync_interval: Duration,
+) -> bool {
+    if let Some(attempt) = attempt {
+        let wait = if attempt.succeeded {
+            sync_interval
+        } else {
+            RETRY_AFTER_FAILURE
+        };
+        return attempt.time.elapsed() >= wait;
+    }
+
+    // the syncs done before the daemon started count too
+    let last_sync = device_info
+        .serial_number
+        .as_ref()
+        .and_then(|serial_number| state.devices.get(serial_number))
+        .and_then(|device_state| device_state.last_sync);
+    match last_sync {
+        Some(last_sync) => {
+            Utc::now().timestamp().saturating_sub(last_sync) >= sync_interval.as_secs() as i64
+        }
+        None => true,
+    }
+}
+
+/// Connect to a device, sync it and disconnect
+async fn sync_device(
+    peripheral: &btleplug::platform::Peripheral,
+    device_info: &XossDeviceInfo,
+    config: &XossUtilConfig,
+    shutdown: &mut Shutdown,
+) -> Result<()> {
+    let device =
+        locate_util::connect_configured_device(peripheral, device_info, &config.timeouts).await?;
+    crate::history::record_transfers(&device).await;
+    crate::json_backup::back_up_json_files(&device).await;
+
+    let result = select! {
+        result = sync(&device, Some(config), SyncOptions::default()) => Some(result),
+        _ = shutdown.wait() => None,
+    };
+    let Some(result) = result else {
+        warn!("Stopping, leaving the device in a consistent state");
+        if let Err(e) = device.stop_transfer().await {
+            warn!("Failed to stop the transfer: {:#}", e);
+        }
+        if let Err(e) = device.disconnect().await {
+            warn!("Failed to disconnect from the device: {:#}", e);
+        }
+        return Err(super::Interrupted.into());
+    };
+
+    if let Err(e) = device.disconnect().await {
+        warn!("Failed to disconnect from the device: {:#}", e);
+    }
+    result
+}
+
+pub async fn run_daemon(config: &XossUtilConfig, adapter: Option<&str>) -> Result<()> {
+    if config.devices.is_empty() {
+        bail!("No devices configured, run setup first");
+    }
+
+    let mut shutdown = Shutdown::new()?;
+    let manager = Manager::new().await.context("Failed to create a manager")?;
+    let adapter = locate_util::find_adapter(&manager, adapter)
+        .await
+        .context("Failed to find adapter")?;
+
+    let sync_interval = config.daemon.sync_interval();
+    let scan_interval = config.daemon.scan_interval();
+    info!(
+        "Watching for {}, syncing each every {} minutes",
+        config
+            .devices
+            .iter()
+            .map(|d| d.identify())
+            .collect::<Vec<_>>()
+            .join(", "),
+        sync_interval.as_secs() / 60
+    );
+
+    let mut attempts = HashMap::<String, Attempt>::new();
+    'watch: loop {
+        let state = crate::state::load_state()?;
+        let due = config
+            .devices
+            .iter()
+            .filter(|d| {
+                is_due(
+                    d,
+                    attempts.get(&d.peripheral_id.to_string()),
+                    &state,
+                    sync_interval,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        if !due.is_empty() {
+            let seen = select! {
+                seen = locate_util::scan_configured_devices(&adapter, &due, &config.timeouts) => seen,
+                _ = shutdown.wait() => break 'watch,
+            };
+            let seen = match seen {
+                Ok(seen) => seen,
+                Err(e) => {
+                    warn!("Failed to scan for the devices: {:#}", e);
+                    Vec::new()
+                }
+            };
+
+            for (device_info, peripheral) in seen {
+                info!("{} is in range, syncing it", device_info.identify());
+                let result = sync_device(&peripheral, device_info, config, &mut shutdown)
+                    .instrument(info_span!("sync", device = %device_info.identify()))
+                    .await;
+                if let Err(e) = &result {
+                    if e.downcast_ref::<super::Interrupted>().is_some() {
+                        break 'watch;
+                    }
+                    warn!("Failed to sync {}: {:#}", device_info.identify(), e);
+                }
+                attempts.insert(
+                    device_info.peripheral_id.to_string(),
+                    Attempt {
+                        time: Instant::now(),
+                        succeeded: result.is_ok(),
+                    },
+                );
+            }
+        }
+
+        select! {
+            _ = tokio::time::sleep(scan_interval) => {}
+            _ = shutdown.wait() => break,
+        }
+    }
+
+    info!("Stopping");
+    Ok(())
+}
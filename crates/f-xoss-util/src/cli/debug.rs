@@ -0,0 +1,185 @@
+use anyhow::{bail, ensure, Context, Result};
+use prettytable::{row, Table};
+use std::future::Future;
+use std::time::SystemTime;
+use tracing::{info, warn};
+
+use super::{DebugCli, DebugCommand};
+use crate::progress::SpanProgress;
+use f_xoss::device::XossDevice;
+use f_xoss::transport::ctl_message::ControlMessageType;
+
+struct CheckReport {
+    results: Vec<(&'static str, Result<String>)>,
+}
+
+impl CheckReport {
+    async fn check(&mut self, name: &'static str, check: impl Future<Output = Result<String>>) {
+        let result = check.await;
+        if let Err(e) = &result {
+            warn!("Check {:?} failed: {:#}", name, e);
+        }
+        self.results.push((name, result));
+    }
+
+    fn failed_count(&self) -> usize {
+        self.results.iter().filter(|(_, r)| r.is_err()).count()
+    }
+
+    fn table(&self) -> Table {
+        let mut table = Table::new();
+        table.set_format(*prettytable::format::consts::FORMAT_CLEAN);
+        for (name, result) in &self.results {
+            match result {
+                Ok(details) => table.add_row(row![name, Fg->"PASS", details]),
+                Err(e) => table.add_row(row![name, Fr->"FAIL", format!("{:#}", e)]),
+            };
+        }
+        table
+    }
+}
+
+/// Generate some file contents that would make a corruption obvious
+fn test_pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + i / 256) as u8).collect()
+}
+
+async fn round_trip(device: &XossDevice, len: usize) -> Result<String> {
+    let content = test_pattern(len);
+
+    device
+        .with_scratch_file(|filename| async move {
+            device
+                .write_file(&filename, &content, &SpanProgress::default())
+                .await
+                .context("Uploading the test file")?;
+
+            let read_back = device
+                .read_file(&filename, &SpanProgress::default())
+                .await
+                .context("Downloading the test file")?;
+            ensure!(
+                read_back == content,
+                "The downloaded file differs from the uploaded one ({} bytes vs {} bytes)",
+                read_back.len(),
+                content.len()
+            );
+
+            Ok(format!("{} bytes", len))
+        })
+        .await
+}
+
+async fn conformance(device: &XossDevice) -> Result<()> {
+    let mut report = CheckReport {
+        results: Vec::new(),
+    };
+
+    let device_info = device.device_info();
+    info!(
+        "Running conformance checks against {} (firmware {}, hardware {})",
+        device_info.model_number, device_info.firmware_revision, device_info.hardware_revision
+    );
+
+    report
+        .check("Transfer status", async {
+            let status = device.get_transfer_status().await?;
+            ensure!(
+                status == ControlMessageType::Idle,
+                "Expected the device to be idle, got {:?}",
+                status
+            );
+            Ok("idle".to_string())
+        })
+        .await;
+    report
+        .check("Memory capacity", async {
+            let capacity = device.get_memory_capacity().await?;
+            ensure!(
+                capacity.free_kb <= capacity.total_kb,
+                "Free space is larger than the total space"
+            );
+            Ok(capacity.to_string())
+        })
+        .await;
+    report
+        .check("Time set", async {
+            // the device echoes the time back, set_time checks it
+            device.set_time(SystemTime::now()).await?;
+            Ok("echo matches".to_string())
+        })
+        .await;
+    report
+        .check("A-GPS status", async {
+            Ok(device.get_mga_state().await?.to_string())
+        })
+        .await;
+    report
+        .check("Read user_profile.json", async {
+            let profile = device.read_user_profile().await?;
+            let header = device.get_device_json_header().await?;
+            Ok(format!(
+                "version {}, user {}",
+                header.version,
+                if profile.user.is_some() {
+                    "present"
+                } else {
+                    "missing"
+                }
+            ))
+        })
+        .await;
+    report
+        .check("Read settings.json", async {
+            device.read_settings().await?;
+            Ok("parsed".to_string())
+        })
+        .await;
+    report
+        .check("Read workouts.json", async {
+            let workouts = device.read_workouts().await?;
+            Ok(format!("{} workouts", workouts.len()))
+        })
+        .await;
+    report
+        .check("Read gear_profile.json", async {
+            let gears = device.read_gear_profile().await?;
+            Ok(format!("{} gears", gears.len()))
+        })
+        .await;
+    report
+        .check("Read routebooks.json", async {
+            let routes = device.read_routes().await?;
+            Ok(format!("{} routes", routes.len()))
+        })
+        .await;
+    // exercise both YMODEM packet sizes, including a partially filled last packet
+    report
+        .check("Empty file round-trip", round_trip(device, 0))
+        .await;
+    report
+        .check("Small file round-trip", round_trip(device, 100))
+        .await;
+    report
+        .check("Large file round-trip", round_trip(device, 3000))
+        .await;
+
+    info!("Conformance report:\n{}", report.table());
+
+    let failed = report.failed_count();
+    if failed > 0 {
+        bail!("{} of {} checks failed", failed, report.results.len());
+    }
+
+    Ok(())
+}
+
+impl DebugCli {
+    pub async fn run(self, device: &XossDevice) -> Result<()> {
+        match self.subcommand {
+            DebugCommand::Conformance => conformance(device).await?,
+        }
+
+        Ok(())
+    }
+}
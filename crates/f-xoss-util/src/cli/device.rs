@@ -1,140 +1,67 @@
 use anyhow::{anyhow, bail, Context, Result};
 use camino::{Utf8Path, Utf8PathBuf};
-use chrono::{FixedOffset, Local, TimeZone, Utc};
-use indicatif::ProgressStyle;
+use chrono::{FixedOffset, TimeZone, Utc};
 use prettytable::{row, table};
+use std::future::Future;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::time::SystemTime;
-use tracing::{info, instrument};
-use tracing_indicatif::span_ext::IndicatifSpanExt;
-
-use super::DeviceCli;
-use crate::cli::{DeviceCommand, SyncOptions};
-use crate::config::XossUtilConfig;
-use f_xoss::device::{MgaState, XossDevice};
-use f_xoss::model::{User, UserProfile, UserProfileInner};
-
-#[instrument(skip(device, _options))]
-async fn sync_workouts(device: &XossDevice, _options: &SyncOptions) -> Result<()> {
-    let local_workouts_dir = crate::config::APP_DIRS.data_dir().join("workouts");
-    tokio::fs::create_dir_all(&local_workouts_dir).await?;
-
-    info!("Syncing workouts to {}", local_workouts_dir.display());
-
-    let workouts = device.read_workouts().await?;
-
-    let missing_workouts = workouts
-        .iter()
-        .filter(|workout| !local_workouts_dir.join(workout.filename()).exists())
-        .collect::<Vec<_>>();
-
-    let current_span = tracing::Span::current();
-    current_span.pb_set_style(&ProgressStyle::default_bar()
-        .template("{span_child_prefix}{spinner:.green} [{bar:40.cyan/blue}] {human_pos}/{human_len} ({eta} @ {per_sec})")
-        .unwrap()
-        .progress_chars("#>-"));
-    current_span.pb_set_length(missing_workouts.len() as u64);
-
-    for workout in missing_workouts {
-        let workout_filename = workout.filename();
-        let workout_path = local_workouts_dir.join(&workout_filename);
-
-        info!(
-            "Downloading workout {:?} to {:?}",
-            workout.name, workout_path
-        );
-        let workout_data = device
-            .read_file(&workout_filename)
-            .await
-            .context("Failed to receive workout file")?;
-        tokio::fs::write(&workout_path, &workout_data)
-            .await
-            .context("Failed to write workout file")?;
-
-        current_span.pb_inc(1);
-    }
-
-    Ok(())
-}
-
-#[instrument(skip(device, config, options))]
-async fn sync_mga(
-    device: &XossDevice,
-    config: Option<&XossUtilConfig>,
-    options: &SyncOptions,
-) -> Result<()> {
-    let Some(config) = config else {
-        bail!("Config is required for sync subcommand");
-    };
-
-    let mga_state = device
-        .get_mga_state()
-        .await
-        .context("Failed to get MGA status")?;
-    let mga_data = crate::mga::get_mga_data(&config.mga, &options.mga_update).await?;
-
-    if match mga_state {
-        MgaState::MissingData => true,
-        MgaState::ValidUntil(date) => date < mga_data.valid_until,
-    } {
-        info!("Updating MGA data");
-        device
-            .write_file("offline.gnss", &mga_data.data)
-            .await
-            .context("Failed to send the MGA data")?;
-    } else {
-        info!("MGA data is up to date");
+use std::time::Duration;
+use tokio::select;
+use tokio_stream::StreamExt;
+use tracing::{debug, info, info_span, warn, Instrument};
+
+use super::{DeviceCli, DIALOGUER_THEME};
+use crate::cli::clock::TimeZoneChoice;
+use crate::cli::filter::WorkoutFilter;
+use crate::cli::sync::sync;
+use crate::cli::DeviceCommand;
+use crate::config::{SyncConfig, XossUtilConfig};
+use crate::json_backup;
+use crate::mga::MgaMode;
+use crate::progress::SpanProgress;
+use crate::state::CapacityRecord;
+use crate::workout_index::{workouts_dir, LocalCopies};
+use btleplug::platform::Manager;
+use f_xoss::device::{MemoryCapacity, MgaState, UploadVerification, XossDevice};
+use f_xoss::mga::CorruptFrames;
+use f_xoss::model::WorkoutState;
+use f_xoss::scan::{scan_devices, DiscoveredDevice, ScanOptions};
+use f_xoss::transport::ctl_message::{ControlMessageType, RawControlMessage};
+use num_enum::TryFromPrimitive;
+
+/// Remember the capacity for the trend, returns the description of the trend
+fn record_capacity(serial_number: &str, capacity: &MemoryCapacity) -> Option<String> {
+    let mut trend = None;
+    let result = crate::state::update_state(|state| {
+        let device_state = state.device_mut(serial_number);
+        device_state.record_capacity(CapacityRecord::now(capacity));
+        trend = device_state.describe_capacity_trend();
+    });
+    if let Err(e) = result {
+        warn!("Failed to record the memory capacity: {:#}", e);
     }
-
-    Ok(())
-}
-
-async fn sync(
-    device: &XossDevice,
-    config: Option<&XossUtilConfig>,
-    options: SyncOptions,
-) -> Result<()> {
-    device
-        .set_time(SystemTime::now())
-        .await
-        .context("Failed to set the time")?;
-    info!("Time set");
-
-    let user_profile = device.read_user_profile().await?;
-
-    let time_zone = Local::now().offset().local_minus_utc();
-
-    let user_profile = UserProfile {
-        user: Some(user_profile.user.unwrap_or_else(|| User {
-            platform: "XOSS".to_string(),
-            uid: 42,
-            user_name: "ABOBA".to_string(),
-        })),
-        user_profile: UserProfileInner {
-            time_zone,
-            ..user_profile.user_profile
-        },
-    };
-    device.write_user_profile(&user_profile).await?;
-
-    sync_workouts(device, &options)
-        .await
-        .context("Syncing workouts")?;
-
-    sync_mga(device, config, &options)
-        .await
-        .context("Syncing MGA data")?;
-
-    Ok(())
+    trend
 }
 
-async fn info(device: &XossDevice) -> Result<()> {
+pub(super) async fn info(device: &XossDevice) -> Result<()> {
     let user_profile = device.read_user_profile().await?;
 
     let header_json = device.get_device_json_header().await?;
     let updated_at = Utc.timestamp_opt(header_json.updated_at, 0).unwrap();
 
-    let device_info = device.device_info().await;
+    let device_info = device.device_info();
+    let debug_id = match device.debug_id().await {
+        Ok(id) => id.to_string(),
+        Err(e) => {
+            debug!("Failed to get the device identifier: {:#}", e);
+            "(not supported)".to_string()
+        }
+    };
+    let activity_status = match device.get_activity_status().await? {
+        Some(status) => status.to_string(),
+        None => "(not supported)".to_string(),
+    };
     let memory_capacity = device.get_memory_capacity().await?;
     let mga_status = device.get_mga_state().await?;
 
@@ -145,6 +72,7 @@ async fn info(device: &XossDevice) -> Result<()> {
     table.add_row(row!["Model Number:", device_info.model_number]);
     table.add_row(row!["Hardware Revision:", device_info.hardware_revision]);
     table.add_row(row!["Serial Number:", device_info.serial_number]);
+    table.add_row(row!["Device ID:", debug_id]);
     table.add_row(row!["Protocol Version:", header_json.version]);
     table.add_row(row!["", ""]);
 
@@ -165,23 +93,177 @@ async fn info(device: &XossDevice) -> Result<()> {
     table.add_row(row!["User Name:", user_profile_table]);
     table.add_row(row![
         "Time Zone:",
-        FixedOffset::east_opt(user_profile.user_profile.time_zone).unwrap()
+        f_xoss::time_zone::format_offset(user_profile.user_profile.time_zone)
     ]);
     table.add_row(row!["", ""]);
     table.add_row(row![
         "Battery Level:",
-        format!("{}%", device.battery_level().await)
+        battery_level_text(device.battery_level().await)
     ]);
+    table.add_row(row!["Signal:", device.link_quality().await]);
+    table.add_row(row!["Activity:", activity_status]);
     table.add_row(row!["Last Updated At:", updated_at]);
     table.add_row(row!["Memory Capacity:", memory_capacity]);
+    table.add_row(row![
+        "Memory Trend:",
+        record_capacity(&device_info.serial_number, &memory_capacity)
+            .unwrap_or_else(|| "(not enough readings yet)".to_string())
+    ]);
     table.add_row(row!["A-GPS Status:", mga_status]);
+    // the device only tells until when its data is valid, it's the last downloaded one that has been sent to it
+    let cached_data = match mga_status {
+        MgaState::MissingData => None,
+        MgaState::ValidUntil(_) => {
+            match crate::mga::get_current_mga_data(MgaMode::Online, CorruptFrames::Skip).await? {
+                Some(data) => Some(data),
+                None => {
+                    crate::mga::get_current_mga_data(MgaMode::Offline, CorruptFrames::Skip).await?
+                }
+            }
+        }
+    };
+    if let Some(data) = cached_data {
+        table.add_row(row![
+            "A-GPS Constellations:",
+            data.constellations
+                .iter()
+                .map(|gnss| gnss.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ]);
+    }
 
     info!("Device info:\n{}", table);
 
     Ok(())
 }
 
-async fn pull(
+/// How long to look for the devices nearby when listing them
+const LIST_SCAN_DURATION: Duration = Duration::from_secs(5);
+
+async fn scan_nearby(adapter: Option<&str>) -> Result<Vec<DiscoveredDevice>> {
+    let manager = Manager::new().await.context("Failed to create a manager")?;
+    let adapter = crate::locate_util::find_adapter(&manager, adapter).await?;
+    let results = scan_devices(
+        &adapter,
+        ScanOptions {
+            only_xoss: true,
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    let mut found = Vec::<DiscoveredDevice>::new();
+    let collect = async {
+        tokio::pin!(results);
+        while let Some(device) = results.next().await {
+            let device = device?;
+            // a device is reported again when its name changes
+            found.retain(|d| d.id != device.id);
+            found.push(device);
+        }
+
+        Ok::<_, anyhow::Error>(())
+    };
+
+    select! {
+        _ = tokio::time::sleep(LIST_SCAN_DURATION) => {},
+        result = collect => result?,
+    }
+
+    Ok(found)
+}
+
+fn describe_presence(device: Option<&DiscoveredDevice>) -> String {
+    match device {
+        None => "offline".to_string(),
+        Some(d) if d.in_dfu_mode => "in DFU mode".to_string(),
+        Some(DiscoveredDevice {
+            rssi: Some(rssi), ..
+        }) => format!("online ({} dBm)", rssi),
+        Some(_) => "online".to_string(),
+    }
+}
+
+pub async fn list(
+    config: Option<&XossUtilConfig>,
+    adapter: Option<&str>,
+    scan: bool,
+) -> Result<()> {
+    let configured = config.map_or(&[][..], |c| c.devices.as_slice());
+    if configured.is_empty() && !scan {
+        bail!("No devices configured, run setup first")
+    }
+    let state = crate::state::load_state()?;
+
+    let mut nearby = if scan {
+        scan_nearby(adapter)
+            .instrument(info_span!("Scanning for devices nearby"))
+            .await?
+    } else {
+        Vec::new()
+    };
+
+    let mut table = prettytable::Table::new();
+    table.set_format(*prettytable::format::consts::FORMAT_CLEAN);
+    table.set_titles(row![
+        "",
+        "Name",
+        "Serial Number",
+        "Presence",
+        "Battery",
+        "Status"
+    ]);
+    for device_info in configured {
+        let is_default = config
+            .and_then(|c| c.default_device.as_deref())
+            .is_some_and(|d| device_info.matches(d));
+        let seen = nearby
+            .iter()
+            .position(|d| device_info.is_discovered_as(d))
+            .map(|i| nearby.remove(i));
+        let device_state = state.device(device_info);
+        let battery_level = device_state
+            .and_then(|s| s.battery_level)
+            .map_or_else(|| "unknown".to_string(), |b| format!("{}%", b));
+        let battery_level = match device_state.and_then(|s| s.describe_battery_usage()) {
+            Some(usage) => format!("{} ({})", battery_level, usage),
+            None => battery_level,
+        };
+
+        table.add_row(row![
+            if is_default { "*" } else { "" },
+            device_info.identify(),
+            device_info.serial_number.as_deref().unwrap_or("unknown"),
+            if scan {
+                describe_presence(seen.as_ref())
+            } else {
+                "not scanned".to_string()
+            },
+            battery_level,
+            state.describe_device(device_info)
+        ]);
+    }
+    for device in &nearby {
+        table.add_row(row![
+            "",
+            device.name.as_deref().unwrap_or("unnamed"),
+            "",
+            describe_presence(Some(device)),
+            "",
+            format!("not configured ({})", device.address)
+        ]);
+    }
+
+    info!("Devices:\n{}", table);
+    if !nearby.is_empty() {
+        info!("Run setup to add the devices that are not configured yet");
+    }
+
+    Ok(())
+}
+
+pub(super) async fn pull(
     device: &XossDevice,
     device_filename: &str,
     output_filename: Option<&Utf8Path>,
@@ -200,21 +282,89 @@ async fn pull(
         .unwrap(),
     };
 
-    let contents = device
-        .read_file(device_filename)
-        .await
-        .with_context(|| format!("Pulling {} from the device", device_filename))?;
-    tokio::fs::write(&output_filename, contents)
+    device
+        .read_file_to_path(
+            device_filename,
+            output_filename.as_std_path(),
+            &SpanProgress::with_link_quality(device),
+        )
         .await
-        .with_context(|| format!("Writing {} to {}", device_filename, output_filename))?;
+        .with_context(|| format!("Pulling {} to {}", device_filename, output_filename))?;
 
     Ok(())
 }
 
-async fn push(
+/// Run a long transfer, warning if the battery is low before it or runs low during it
+///
+/// The threshold is the same as the one limiting the sync (`sync.low_battery_threshold`).
+/// Like "76%", or "unknown" for the devices not reporting it
+pub(super) fn battery_level_text(level: Option<u32>) -> String {
+    level.map_or_else(|| "unknown".to_string(), |level| format!("{}%", level))
+}
+
+pub(super) async fn watch_battery<T>(
+    device: &XossDevice,
+    config: Option<&XossUtilConfig>,
+    transfer: impl Future<Output = T>,
+) -> T {
+    let threshold = config.map_or_else(
+        || SyncConfig::default().low_battery_threshold(),
+        |c| c.sync.low_battery_threshold(),
+    );
+    let mut battery_level = device.subscribe_battery_level();
+    let low = |level: Option<u32>| level.filter(|&level| level < threshold);
+    let level = *battery_level.borrow_and_update();
+    if let Some(level) = low(level) {
+        warn!(
+            "The device battery is low ({}%), the transfer will fail if the device turns off",
+            level
+        );
+    }
+
+    let watch = async {
+        let mut warned = low(level).is_some();
+        while battery_level.changed().await.is_ok() {
+            let level = *battery_level.borrow_and_update();
+            debug!("The battery level is {}", battery_level_text(level));
+            if let (Some(level), false) = (low(level), warned) {
+                warn!(
+                    "The device battery ran low ({}%) during the transfer",
+                    level
+                );
+                warned = true;
+            }
+        }
+        // the connection is gone, the transfer will notice it
+        std::future::pending::<()>().await
+    };
+
+    select! {
+        result = transfer => result,
+        _ = watch => unreachable!("the battery watch never returns"),
+    }
+}
+
+pub(super) fn log_verification(device_filename: &str, verification: UploadVerification) {
+    match verification {
+        UploadVerification::Checksum(crc32) => {
+            info!("Verified {}: CRC-32 {:08x} matches", device_filename, crc32)
+        }
+        UploadVerification::IndexedSize(size) => info!(
+            "Verified {}: the device can't return it, but its size ({} bytes) in the index matches",
+            device_filename, size
+        ),
+        UploadVerification::Unverifiable => warn!(
+            "The device does not allow reading {} back, the upload could not be verified",
+            device_filename
+        ),
+    }
+}
+
+pub(super) async fn push(
     device: &XossDevice,
     input_filename: Utf8PathBuf,
     device_filename: Option<&str>,
+    verify: bool,
 ) -> Result<()> {
     let Some(device_filename) = device_filename.or(input_filename.file_name()) else {
         bail!("No device filename provided and could not infer it from input filename")
@@ -223,10 +373,155 @@ async fn push(
     let contents = tokio::fs::read(&input_filename)
         .await
         .with_context(|| format!("Reading {} from the filesystem", input_filename))?;
-    device
-        .write_file(device_filename, &contents)
+    // the JSON configs are small and replace the existing ones
+    if !device_filename.ends_with(".json") {
+        device
+            .ensure_free_space(device_filename, contents.len() as u64)
+            .await?;
+    }
+    if !verify {
+        return device
+            .write_file(
+                device_filename,
+                &contents,
+                &SpanProgress::with_link_quality(device),
+            )
+            .await
+            .with_context(|| format!("Writing {} to the device", device_filename));
+    }
+
+    let verification = device
+        .write_file_verified(
+            device_filename,
+            &contents,
+            &SpanProgress::with_link_quality(device),
+        )
         .await
         .with_context(|| format!("Writing {} to the device", device_filename))?;
+    log_verification(device_filename, verification);
+
+    Ok(())
+}
+
+pub(super) async fn ls(device: &XossDevice) -> Result<()> {
+    let files = device.list_files().await?;
+
+    let mut table = table!(["Name", "Kind", "Size"]);
+    table.set_format(*prettytable::format::consts::FORMAT_CLEAN);
+    for file in &files {
+        let size = file.size.map_or("?".to_string(), |s| {
+            humansize::format_size(s, humansize::BINARY)
+        });
+        table.add_row(row![file.name, file.kind, r->size]);
+    }
+    let total = files.iter().filter_map(|f| f.size).sum::<u64>();
+
+    info!(
+        "Files on the device ({} known):\n{}",
+        humansize::format_size(total, humansize::BINARY),
+        table
+    );
+
+    Ok(())
+}
+
+fn describe_state(state: WorkoutState) -> &'static str {
+    match state {
+        WorkoutState::NotSynchronized => "not synced",
+        WorkoutState::Recording => "recording",
+        WorkoutState::Syncing => "syncing",
+        WorkoutState::Synced => "synced",
+        WorkoutState::Broken => "broken",
+    }
+}
+
+async fn workouts(
+    device: &XossDevice,
+    config: Option<&XossUtilConfig>,
+    filter: &WorkoutFilter,
+) -> Result<()> {
+    let time_zone = device.read_user_profile().await?.user_profile.time_zone;
+    let serial_number = device.device_info().serial_number.clone();
+    let local_copies = LocalCopies::load(workouts_dir(config), serial_number)?;
+
+    let all_workouts = device.read_workouts().await?;
+    let workouts = all_workouts
+        .iter()
+        .filter(|workout| filter.matches(workout, time_zone))
+        .collect::<Vec<_>>();
+
+    let offset = FixedOffset::east_opt(time_zone).context("Invalid device time zone")?;
+    let mut table = table!(["Name", "Started", "Size", "State", "Local copy"]);
+    table.set_format(*prettytable::format::consts::FORMAT_CLEAN);
+    for workout in &workouts {
+        let started = workout
+            .start_time(time_zone)
+            .map_or("?".to_string(), |start| {
+                start
+                    .with_timezone(&offset)
+                    .format("%Y-%m-%d %H:%M")
+                    .to_string()
+            });
+        let local_copy = local_copies.find(workout).map_or("-".to_string(), |path| {
+            path.file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned()
+        });
+        table.add_row(row![
+            workout.name,
+            started,
+            r->humansize::format_size(workout.size, humansize::BINARY),
+            describe_state(workout.state),
+            local_copy
+        ]);
+    }
+
+    let count = if filter.is_empty() {
+        workouts.len().to_string()
+    } else {
+        format!("{} of {}", workouts.len(), all_workouts.len())
+    };
+    info!("Workouts on the device ({}):\n{}", count, table);
+
+    Ok(())
+}
+
+async fn restore_json(device: &XossDevice, file: &str, yes: bool) -> Result<()> {
+    let serial_number = device.device_info().serial_number.clone();
+
+    let (device_filename, backup) = if Path::new(file).is_file() {
+        let device_filename = json_backup::backed_up_file(Path::new(file))
+            .with_context(|| format!("{} is not named like a backup of a device file", file))?;
+        (device_filename, PathBuf::from(file))
+    } else {
+        let backup = json_backup::list_backups(&serial_number, file)?
+            .pop()
+            .with_context(|| {
+                format!(
+                    "There are no backups of {} of this device in {}",
+                    file,
+                    json_backup::backup_dir(&serial_number).display()
+                )
+            })?;
+        (file.to_string(), backup)
+    };
+    let content =
+        std::fs::read(&backup).with_context(|| format!("Reading {}", backup.display()))?;
+
+    confirm(
+        &format!(
+            "Replace {} on the device with {}?",
+            device_filename,
+            backup.display()
+        ),
+        yes,
+    )?;
+    device
+        .write_raw_json_file(&device_filename, &content)
+        .await
+        .with_context(|| format!("Restoring {}", device_filename))?;
+    info!("Restored {} from {}", device_filename, backup.display());
 
     Ok(())
 }
@@ -240,20 +535,255 @@ async fn delete(device: &XossDevice, device_filename: &str) -> Result<()> {
     Ok(())
 }
 
+/// Ask the user to confirm a destructive action, unless `--yes` is passed
+pub(super) fn confirm(prompt: &str, yes: bool) -> Result<()> {
+    if yes {
+        return Ok(());
+    }
+    if !console::user_attended() {
+        bail!("Not running interactively, pass --yes to confirm");
+    }
+
+    let confirmed = dialoguer::Confirm::with_theme(DIALOGUER_THEME.deref())
+        .with_prompt(prompt)
+        .default(false)
+        .interact()
+        .context("Failed to get user confirmation")?;
+
+    if !confirmed {
+        bail!("Cancelled by the user");
+    }
+
+    Ok(())
+}
+
+async fn factory_reset(device: &XossDevice, yes: bool) -> Result<()> {
+    let device_info = device.device_info();
+    let pending = device
+        .read_workouts()
+        .await?
+        .iter()
+        .filter(|w| w.state == WorkoutState::NotSynchronized)
+        .count();
+    if pending > 0 {
+        warn!(
+            "The device has {} workouts that were not synchronized, they will be lost",
+            pending
+        );
+    }
+
+    confirm(
+        &format!(
+            "Erase all the data on {} ({})?",
+            device_info.model_number, device_info.serial_number
+        ),
+        yes,
+    )?;
+
+    device.factory_reset().await?;
+    info!("Factory reset done, the device is rebooting");
+
+    Ok(())
+}
+
+async fn enter_dfu(device: &XossDevice, yes: bool) -> Result<()> {
+    confirm(
+        "Reboot the device into the firmware update mode? It stays there until turned off and on again",
+        yes,
+    )?;
+
+    device.enter_dfu().await?;
+    info!("The device is rebooting into the DFU mode");
+
+    Ok(())
+}
+
+async fn raw_ctl(
+    device: &XossDevice,
+    message_type: &str,
+    body: Option<&str>,
+    i_know_what_i_am_doing: bool,
+) -> Result<()> {
+    let message_type = u8::from_str_radix(message_type.trim_start_matches("0x"), 16)
+        .with_context(|| format!("Invalid message type: {}", message_type))?;
+    let body = hex::decode(body.unwrap_or_default()).context("Invalid message body")?;
+
+    let known = ControlMessageType::try_from_primitive(message_type)
+        .map(|t| format!("{:?}", t))
+        .unwrap_or_else(|_| "unknown".to_string());
+    if !i_know_what_i_am_doing {
+        bail!(
+            "Not sending {:02x} ({}): raw messages can erase the data or brick the device, pass --i-know-what-i-am-doing to send it anyway",
+            message_type,
+            known
+        );
+    }
+
+    info!(
+        "Sending {:02x} ({}) {}",
+        message_type,
+        known,
+        hex::encode(&body)
+    );
+    let reply = device.request_ctl_raw(message_type, &body).await?;
+
+    println!("Reply: {}", hex::encode(&reply));
+    match RawControlMessage::read(&reply) {
+        Ok(reply) => {
+            println!("Type:  {:?}", reply.message_type);
+            println!("Body:  {}", hex::encode(reply.body));
+            if !reply.body.is_empty() {
+                println!("Text:  {:?}", String::from_utf8_lossy(reply.body));
+            }
+        }
+        Err(e) => warn!("Could not decode the reply: {:#}", e),
+    }
+
+    Ok(())
+}
+
 impl DeviceCli {
+    /// Whether the command can be run through the agent
+    ///
+    /// Not the ones that manage the connection themselves or reboot the device
+    pub fn can_use_agent(&self) -> bool {
+        !matches!(
+            self.subcommand,
+            DeviceCommand::List { .. }
+                | DeviceCommand::CopySettings { .. }
+                | DeviceCommand::FirmwareUpdate { .. }
+                | DeviceCommand::Dfu { .. }
+                | DeviceCommand::FactoryReset { .. }
+                | DeviceCommand::Shell
+                | DeviceCommand::RawCtl { .. }
+        )
+    }
+
+    /// Whether the command should run even if the device is recording a workout
+    fn force_while_recording(&self) -> bool {
+        match &self.subcommand {
+            DeviceCommand::Sync(options) => options.force,
+            DeviceCommand::Provision { force, .. }
+            | DeviceCommand::FactoryReset { force, .. }
+            | DeviceCommand::Dfu { force, .. }
+            | DeviceCommand::Delete { force, .. }
+            | DeviceCommand::RestoreJson { force, .. } => *force,
+            _ => false,
+        }
+    }
+
     pub async fn run(self, device: &XossDevice, config: Option<XossUtilConfig>) -> Result<()> {
+        device.set_allow_while_recording(self.force_while_recording());
         match self.subcommand {
             DeviceCommand::Sync(options) => sync(device, config.as_ref(), options).await?,
             DeviceCommand::Info => info(device).await?,
+            DeviceCommand::Ls => ls(device).await?,
+            DeviceCommand::Capabilities => crate::cli::capabilities::capabilities(device).await?,
+            DeviceCommand::Workouts(filter) => workouts(device, config.as_ref(), &filter).await?,
+            DeviceCommand::List { .. } => bail!("`dev list` doesn't work with a connected device"),
+            DeviceCommand::CopySettings { .. } => {
+                bail!("`dev copy-settings` connects to the devices by itself")
+            }
             DeviceCommand::Pull {
                 device_filename,
                 output_filename,
-            } => pull(device, &device_filename, output_filename.as_deref()).await?,
+            } => {
+                watch_battery(
+                    device,
+                    config.as_ref(),
+                    pull(device, &device_filename, output_filename.as_deref()),
+                )
+                .await?
+            }
             DeviceCommand::Push {
                 input_filename,
                 device_filename,
-            } => push(device, input_filename, device_filename.as_deref()).await?,
-            DeviceCommand::Delete { device_filename } => delete(device, &device_filename).await?,
+                no_verify,
+                ..
+            } => {
+                watch_battery(
+                    device,
+                    config.as_ref(),
+                    push(
+                        device,
+                        input_filename,
+                        device_filename.as_deref(),
+                        !no_verify,
+                    ),
+                )
+                .await?
+            }
+            DeviceCommand::Panels(command) => command.run(device).await?,
+            DeviceCommand::Gear(command) => command.run(device).await?,
+            DeviceCommand::Sensors(command) => command.run(device).await?,
+            DeviceCommand::SetTime { utc, local_offset } => {
+                let time_zone = if utc {
+                    TimeZoneChoice::Utc
+                } else if local_offset {
+                    TimeZoneChoice::LocalOffset
+                } else {
+                    TimeZoneChoice::Configured
+                };
+                crate::cli::clock::set_time(device, config.as_ref(), time_zone).await?
+            }
+            DeviceCommand::Provision { template, .. } => {
+                crate::cli::provision::provision(device, &template).await?
+            }
+            DeviceCommand::FactoryReset { yes, .. } => factory_reset(device, yes).await?,
+            DeviceCommand::Dfu { yes, .. } => enter_dfu(device, yes).await?,
+            DeviceCommand::FirmwareUpdate { .. } => {
+                bail!("`dev firmware-update` manages the connection by itself")
+            }
+            DeviceCommand::FirmwareCheck { manifest, download } => {
+                crate::cli::firmware::firmware_check(
+                    device,
+                    config.as_ref(),
+                    manifest.as_deref(),
+                    download,
+                )
+                .await?
+            }
+            DeviceCommand::Restore {
+                files,
+                naming,
+                dry_run,
+                verify,
+            } => {
+                watch_battery(
+                    device,
+                    config.as_ref(),
+                    crate::cli::restore::restore(device, &files, naming, dry_run, verify),
+                )
+                .await?
+            }
+            DeviceCommand::RestoreJson { file, yes, .. } => {
+                restore_json(device, &file, yes).await?
+            }
+            DeviceCommand::Delete {
+                device_filename, ..
+            } => delete(device, &device_filename).await?,
+            DeviceCommand::Shell => crate::cli::shell::shell(device).await?,
+            DeviceCommand::RawCtl {
+                message_type,
+                body,
+                i_know_what_i_am_doing,
+            } => {
+                raw_ctl(
+                    device,
+                    &message_type,
+                    body.as_deref(),
+                    i_know_what_i_am_doing,
+                )
+                .await?
+            }
+            DeviceCommand::Benchmark { size } => {
+                watch_battery(
+                    device,
+                    config.as_ref(),
+                    crate::cli::benchmark::benchmark(device, size),
+                )
+                .await?
+            }
         }
 
         Ok(())
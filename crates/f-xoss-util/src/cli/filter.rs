@@ -0,0 +1,67 @@
+//! Selecting the device workouts by their start date and state, for `dev sync` and `dev workouts`
+
+use chrono::{FixedOffset, NaiveDate};
+use clap::{Args, ValueEnum};
+use f_xoss::model::{WorkoutState, WorkoutsItem};
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WorkoutStateFilter {
+    /// Downloaded from the device before, by this tool or by the XOSS app
+    Synced,
+    /// Never downloaded from the device
+    NotSynced,
+}
+
+#[derive(Args, Debug, Clone, Default)]
+pub struct WorkoutFilter {
+    /// Only the workouts started on this date or later, like 2023-06-17
+    #[clap(long, value_name = "DATE")]
+    pub since: Option<NaiveDate>,
+    /// Only the workouts started on this date or earlier, like 2023-06-17
+    #[clap(long, value_name = "DATE")]
+    pub until: Option<NaiveDate>,
+    /// Only the workouts the device marks as synced or not
+    #[clap(long, value_enum)]
+    pub state: Option<WorkoutStateFilter>,
+}
+
+impl WorkoutFilter {
+    pub fn is_empty(&self) -> bool {
+        self.since.is_none() && self.until.is_none() && self.state.is_none()
+    }
+
+    /// Whether the workout is selected, `time_zone` is the device offset from UTC in seconds
+    ///
+    /// The dates are the ones the device shows. The workouts with the names that don't tell the start time are left
+    /// out when filtering by the date
+    pub fn matches(&self, workout: &WorkoutsItem, time_zone: i32) -> bool {
+        let state_matches = match self.state {
+            None => true,
+            Some(WorkoutStateFilter::Synced) => workout.state == WorkoutState::Synced,
+            Some(WorkoutStateFilter::NotSynced) => workout.state != WorkoutState::Synced,
+        };
+        if !state_matches {
+            return false;
+        }
+        if self.since.is_none() && self.until.is_none() {
+            return true;
+        }
+
+        let Some(date) = start_date(workout, time_zone) else {
+            return false;
+        };
+        self.since.map_or(true, |since| date >= since)
+            && self.until.map_or(true, |until| date <= until)
+    }
+}
+
+/// The date the workout was started on, in the device time zone
+pub fn start_date(workout: &WorkoutsItem, time_zone: i32) -> Option<NaiveDate> {
+    let offset = FixedOffset::east_opt(time_zone)?;
+    Some(
+        workout
+            .start_time(time_zone)?
+            .with_timezone(&offset)
+            .date_naive(),
+    )
+}
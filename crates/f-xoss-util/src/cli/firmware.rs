@@ -0,0 +1,154 @@
+//! Implementation of the `dev firmware-update` and `dev firmware-check` subcommands
+
+use anyhow::{bail, Context, Result};
+use btleplug::api::Peripheral as _;
+use btleplug::platform::Manager;
+use camino::Utf8Path;
+use std::time::Duration;
+use tracing::{info, info_span, warn, Instrument};
+
+use super::device::confirm;
+use crate::config::XossUtilConfig;
+use crate::firmware::{check_firmware, compare_versions};
+use crate::locate_util;
+use crate::progress::SpanProgress;
+use f_xoss::device::XossDevice;
+use f_xoss::dfu::DfuPackage;
+use std::cmp::Ordering;
+
+/// Updating the firmware with a low battery may leave the device without a working firmware
+const MIN_BATTERY_LEVEL: u32 = 30;
+
+pub async fn firmware_update(
+    config: &Option<XossUtilConfig>,
+    device_selector: Option<&str>,
+    adapter_selector: Option<&str>,
+    package_path: &Utf8Path,
+    yes: bool,
+) -> Result<()> {
+    let package = std::fs::read(package_path)
+        .with_context(|| format!("Reading {}", package_path))
+        .and_then(|data| DfuPackage::from_zip(&data))
+        .with_context(|| format!("Loading the firmware package {}", package_path))?;
+    info!(
+        "Loaded the firmware package: {} bytes of firmware, CRC32 {:08x}",
+        package.firmware.len(),
+        crc32fast::hash(&package.firmware)
+    );
+
+    let device = locate_util::find_device_from_config(config, device_selector, adapter_selector)
+        .await
+        .context("Failed to find the device")?;
+    let old_firmware = device.device_info().firmware_revision.clone();
+
+    match device.battery_level().await {
+        Some(battery_level) if battery_level < MIN_BATTERY_LEVEL => bail!(
+            "The battery level is {}%, charge the device to at least {}% before updating the firmware",
+            battery_level,
+            MIN_BATTERY_LEVEL
+        ),
+        Some(_) => {}
+        None => warn!(
+            "The device doesn't report its battery level, make sure it's charged to at least {}% before updating the firmware",
+            MIN_BATTERY_LEVEL
+        ),
+    }
+
+    confirm(
+        &format!(
+            "Update the firmware (currently {})? Don't turn the device off until it's done",
+            old_firmware
+        ),
+        yes,
+    )?;
+
+    device.enter_dfu().await?;
+    drop(device);
+
+    let manager = Manager::new().await.context("Failed to create a manager")?;
+    let adapter = locate_util::find_adapter(&manager, adapter_selector).await?;
+    let peripheral = locate_util::find_dfu_target(&adapter).await?;
+
+    peripheral
+        .connect()
+        .instrument(info_span!("ble_connect"))
+        .await
+        .context("Failed to connect to the device in the DFU mode")?;
+    f_xoss::dfu::flash(&peripheral, &package, &SpanProgress::default())
+        .await
+        .context("Failed to flash the firmware")?;
+
+    info!("Waiting for the device to boot the new firmware");
+    tokio::time::sleep(Duration::from_secs(10)).await;
+
+    let device = locate_util::find_device_from_config(config, device_selector, adapter_selector)
+        .await
+        .context("Failed to reconnect to the device after the update")?;
+    info!(
+        "Firmware updated: {} -> {}",
+        old_firmware,
+        device.device_info().firmware_revision
+    );
+
+    Ok(())
+}
+
+pub async fn firmware_check(
+    device: &XossDevice,
+    config: Option<&XossUtilConfig>,
+    manifest: Option<&str>,
+    download: bool,
+) -> Result<()> {
+    let device_info = device.device_info();
+    let current = &device_info.firmware_revision;
+
+    let firmware_config = config.map(|c| c.firmware.clone()).unwrap_or_default();
+    let Some(check) = check_firmware(&firmware_config, manifest, &device_info.model_number).await?
+    else {
+        info!(
+            "The manifest doesn't list any firmware for {}",
+            device_info.model_number
+        );
+        return Ok(());
+    };
+    let latest = &check.release.version;
+
+    match compare_versions(current, latest) {
+        Some(Ordering::Less) => {
+            info!("A firmware update is available: {} -> {}", current, latest);
+        }
+        Some(Ordering::Equal) => {
+            info!("The firmware is up to date ({})", current);
+            return Ok(());
+        }
+        Some(Ordering::Greater) => {
+            info!(
+                "The device firmware ({}) is newer than the latest one in the manifest ({})",
+                current, latest
+            );
+            return Ok(());
+        }
+        None => {
+            warn!(
+                "Can't compare the device firmware version ({}) with the one in the manifest ({})",
+                current, latest
+            );
+        }
+    }
+
+    if let Some(notes) = &check.release.notes {
+        info!("Release notes:\n{}", notes);
+    }
+
+    if download {
+        let path = check.download().await?;
+        info!(
+            "Run `f-xoss-util dev firmware-update {}` to install it",
+            path
+        );
+    } else {
+        info!("Use --download to download the update");
+    }
+
+    Ok(())
+}
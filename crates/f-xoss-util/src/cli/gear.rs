@@ -0,0 +1,86 @@
+use anyhow::{bail, Context, Result};
+use tracing::info;
+
+use super::GearCommand;
+use f_xoss::device::XossDevice;
+use f_xoss::model::{Gear, GearBuilder};
+use f_xoss::wheel::{rollout_circumference, TireSize};
+
+/// Find the gear by its name or id, or the active one
+fn select_gear<'a>(gears: &'a mut [Gear], selector: Option<&str>) -> Result<&'a mut Gear> {
+    let names = gears
+        .iter()
+        .map(|g| format!("{} ({})", g.name, g.gid))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let index = match selector {
+        Some(selector) => gears
+            .iter()
+            .position(|g| g.name == selector || g.gid.to_string() == selector)
+            .with_context(|| format!("No gear {:?}, the device has: {}", selector, names))?,
+        None => match gears.iter().position(|g| g.activated) {
+            Some(index) => index,
+            None if gears.len() == 1 => 0,
+            None if gears.is_empty() => bail!("The device has no gears"),
+            None => bail!(
+                "None of the gears is active, choose one with --gear: {}",
+                names
+            ),
+        },
+    };
+    Ok(&mut gears[index])
+}
+
+impl GearCommand {
+    pub async fn run(self, device: &XossDevice) -> Result<()> {
+        let mut gears = device.read_gear_profile().await?;
+
+        match self {
+            GearCommand::Calibrate {
+                gear,
+                distance,
+                revolutions,
+                tire,
+                dry_run,
+            } => {
+                let wheel_size = match (distance, tire) {
+                    (Some(distance), _) => rollout_circumference(distance, revolutions)
+                        .context("The distance and the revolutions must be positive")?,
+                    (None, Some(tire)) => TireSize::parse(&tire)
+                        .with_context(|| {
+                            format!(
+                                "Unknown tire size {:?}, use the ETRTO size from the sidewall, like 25-622",
+                                tire
+                            )
+                        })?
+                        .circumference(),
+                    (None, None) => unreachable!("clap requires one of them"),
+                };
+
+                let gear = select_gear(&mut gears, gear.as_deref())?;
+                let old_wheel_size = gear.wheel_size;
+                *gear = GearBuilder::modify(gear.clone())
+                    .wheel_size(wheel_size)
+                    .build()
+                    .context("Invalid wheel circumference")?;
+
+                info!(
+                    "Wheel circumference of {}: {} mm -> {} mm (the speed and the distance change by {:+.1}%)",
+                    gear.name,
+                    old_wheel_size,
+                    wheel_size,
+                    (wheel_size as f64 / old_wheel_size as f64 - 1.0) * 100.0
+                );
+                if dry_run {
+                    info!("Dry run, the gear was not changed");
+                    return Ok(());
+                }
+            }
+        }
+
+        device.write_gear_profile(&gears).await?;
+        info!("Gear profile updated");
+
+        Ok(())
+    }
+}
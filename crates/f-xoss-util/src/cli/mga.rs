@@ -0,0 +1,121 @@
+use crate::cli::MgaUpdateOptions;
+use crate::config::XossUtilConfig;
+use crate::mga::{mga_file_path, read_cached_mga_data, MgaMode};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use clap::ValueEnum;
+use f_xoss::mga::{CorruptFrames, MgaData};
+use prettytable::{row, Table};
+use tracing::info;
+
+fn format_constellations(data: &MgaData) -> String {
+    data.constellations
+        .iter()
+        .map(|gnss| gnss.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Show the cached data of each AssistNow service
+pub async fn status() -> Result<()> {
+    let mut table = Table::new();
+    table.set_format(*prettytable::format::consts::FORMAT_CLEAN);
+    table.set_titles(row![
+        "Mode",
+        "Downloaded",
+        "Valid",
+        "Constellations",
+        "Frames",
+        "File"
+    ]);
+
+    for &mode in MgaMode::value_variants() {
+        let name = mode.to_possible_value().unwrap().get_name().to_string();
+        let path = mga_file_path(mode);
+        // corrupt frames are shown, not a reason to fail
+        match read_cached_mga_data(mode, CorruptFrames::Skip).await {
+            Ok(Some((data, downloaded))) => {
+                let valid = if crate::mga::is_expired(mode, downloaded) {
+                    "expired".to_string()
+                } else if data.valid_since == data.valid_until {
+                    data.valid_since.to_string()
+                } else {
+                    format!("{} - {}", data.valid_since, data.valid_until)
+                };
+                let frames = if data.dropped_frames > 0 {
+                    format!("{} ({} corrupt)", data.frames, data.dropped_frames)
+                } else {
+                    data.frames.to_string()
+                };
+                table.add_row(row![
+                    name,
+                    DateTime::<Local>::from(downloaded).format("%Y-%m-%d %H:%M"),
+                    valid,
+                    format_constellations(&data),
+                    frames,
+                    path.display()
+                ]);
+            }
+            Ok(None) => {
+                table.add_row(row![name, "never", "", "", "", path.display()]);
+            }
+            Err(e) => {
+                table.add_row(row![
+                    name,
+                    format!("unreadable: {:#}", e),
+                    "",
+                    "",
+                    "",
+                    path.display()
+                ]);
+            }
+        }
+    }
+
+    info!("Cached A-GNSS data:\n{}", table);
+
+    Ok(())
+}
+
+/// Download the data again, even if the cached one is still good
+pub async fn update(config: Option<&XossUtilConfig>, mode: MgaMode) -> Result<()> {
+    let config = config.context("Config is required for mga update subcommand")?;
+    let data = crate::mga::get_mga_data(
+        &config.mga,
+        &MgaUpdateOptions {
+            mga_offline: false,
+            mga_force_update: true,
+            mga_mode: mode,
+        },
+    )
+    .await?;
+
+    info!(
+        "Downloaded the A-GNSS data valid until {} for {}",
+        data.valid_until,
+        format_constellations(&data)
+    );
+
+    Ok(())
+}
+
+/// Delete the cached data, of one service or of all of them
+pub fn clear(mode: Option<MgaMode>) -> Result<()> {
+    let modes = match mode {
+        Some(mode) => vec![mode],
+        None => MgaMode::value_variants().to_vec(),
+    };
+
+    for mode in modes {
+        let path = mga_file_path(mode);
+        match std::fs::remove_file(&path) {
+            Ok(()) => info!("Deleted {}", path.display()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e).with_context(|| format!("Deleting {}", path.display()));
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -1,28 +1,144 @@
+mod benchmark;
+mod capabilities;
+mod clock;
+mod copy_settings;
+mod daemon;
+mod debug;
 mod device;
+mod filter;
+mod firmware;
+mod gear;
+mod mga;
+mod panels;
+mod provision;
+mod restore;
+mod sensors;
 mod setup;
+mod shell;
+mod sync;
+mod workout;
 
 use crate::config;
-use crate::config::XossUtilConfig;
-use anyhow::{Context, Result};
-use camino::Utf8PathBuf;
+use crate::config::{TimeoutsConfig, XossUtilConfig};
+use crate::export::ExportFormat;
+use crate::locate_util::{find_device_from_config, troubleshooting_hints, LocateError};
+use anyhow::{bail, Context, Result};
+use btleplug::api::BDAddr;
+use camino::{Utf8Path, Utf8PathBuf};
 use clap::{Args, CommandFactory, Parser, Subcommand};
 use clap_complete::Shell;
+use dialoguer::theme::ColorfulTheme;
+use f_xoss::device::XossDevice;
+use f_xoss::model::SensorType;
+use f_xoss::transcript::Transcript;
+use once_cell::sync::Lazy;
 use prettytable::table;
+use std::future::Future;
 use std::ops::Deref;
-use tracing::info;
+use std::pin::Pin;
+use thiserror::Error;
+use tracing::{info, warn};
+
+pub static DIALOGUER_THEME: Lazy<ColorfulTheme> = Lazy::new(ColorfulTheme::default);
 
 #[derive(Parser, Debug)]
 #[command(name = "f-xoss-util", author, version, about, long_about = None)]
 /// An utility to interact with the Xoss NAV bike computer
 pub struct Cli {
+    /// The Bluetooth adapter to use, matched against the adapter info string (like "hci1")
+    ///
+    /// Overrides the adapter set in the config. The first adapter is used if neither is set
+    #[clap(long, global = true)]
+    pub adapter: Option<String>,
+    /// Record all the Bluetooth traffic to a file, to be attached to bug reports
+    ///
+    /// If a directory is given, a file with a timestamped name is created in it
+    #[clap(long, global = true, value_name = "PATH")]
+    pub debug_dump: Option<Utf8PathBuf>,
+    /// Write a transcript of the session to a file: the decoded control messages and the file transfers
+    ///
+    /// Easier to read than the debug dump, good for the bug reports. Makes the command skip the agent
+    #[clap(long, global = true, value_name = "PATH")]
+    pub transcript: Option<Utf8PathBuf>,
+    /// Record the Bluetooth session to a file, which can be run again with `--replay`
+    ///
+    /// Makes the command skip the agent
+    #[clap(long, global = true, value_name = "PATH")]
+    pub record: Option<Utf8PathBuf>,
+    /// Run the device command against a session recorded with `--record` instead of a device
+    ///
+    /// The command has to do the same as the recorded one, it fails where it diverges from the recording
+    #[clap(long, global = true, value_name = "PATH", conflicts_with = "record")]
+    #[cfg_attr(feature = "demo", clap(conflicts_with = "demo"))]
+    pub replay: Option<Utf8PathBuf>,
+    /// Keep the connection to the device open for the next commands, see `agent --help`
+    ///
+    /// Can also be enabled with agent.enabled in the config
+    #[clap(long, global = true)]
+    pub keep_connection: bool,
+    /// Fail on any deviation from the known protocol instead of working around it
+    ///
+    /// Unknown notifications, unexpected file versions, unknown JSON fields and malformed replies are reported with a
+    /// hex dump.
+    /// Useful to check new firmware versions for protocol changes
+    #[clap(long, global = true)]
+    pub strict_protocol: bool,
+    /// Print how many messages were lost, damaged or sent again when the device command is done
+    ///
+    /// Tells how lossy the combination of the Bluetooth adapter and the device is
+    #[clap(long, short, global = true)]
+    pub verbose: bool,
+    /// Run against a simulated device with a built-in demo dataset instead of a real one
+    ///
+    /// Lets you try the commands without owning a device. The demo has its own config and data directories,
+    /// the changes made to the simulated device are kept between the runs
+    #[cfg(feature = "demo")]
+    #[clap(long, global = true)]
+    pub demo: bool,
+    /// Also write the log to the daily rotating files, as JSON lines
+    ///
+    /// The files go to the directory given as `--log-file=DIR`, or to log.dir in the config (`logs` in the cache
+    /// directory by default). Can also be enabled for every run with log.enabled in the config
+    #[clap(
+        long,
+        global = true,
+        value_name = "DIR",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = ""
+    )]
+    pub log_file: Option<Utf8PathBuf>,
     #[clap(subcommand)]
     pub command: CliCommand,
 }
 
-#[derive(Args, Debug)]
-pub struct SetupCli {}
+#[derive(Args, Debug, Default)]
+pub struct SetupCli {
+    /// Set up the device with this Bluetooth address instead of choosing it from the scan results
+    #[clap(long, value_name = "ADDRESS")]
+    pub device_mac: Option<BDAddr>,
+    /// Set up the device advertising this name instead of choosing it from the scan results
+    #[clap(long, value_name = "NAME")]
+    pub device_name: Option<String>,
+    /// The u-blox AssistNow token to use instead of asking for it
+    ///
+    /// It's checked with the u-blox server before saving
+    #[clap(long, value_name = "TOKEN")]
+    pub ublox_token: Option<String>,
+    /// Pair with the device being set up, for the devices that refuse to connect otherwise
+    ///
+    /// Keep the device nearby and accept the pairing on it if it asks. The device is then paired again before
+    /// connecting if this computer has forgotten the pairing
+    #[clap(long)]
+    pub pair: bool,
+    /// Don't ask anything
+    ///
+    /// The steps without a value given on the command line are skipped and the config is saved without confirmation
+    #[clap(long)]
+    pub yes: bool,
+}
 
-#[derive(Args, Debug)]
+#[derive(Args, Debug, Default)]
 pub struct MgaUpdateOptions {
     /// Do not try to update the MGA data
     ///
@@ -32,12 +148,105 @@ pub struct MgaUpdateOptions {
     /// Force update of the MGA data
     #[clap(long)]
     pub mga_force_update: bool,
+    /// Which AssistNow service to use
+    ///
+    /// The online data gives the quickest fixes, but it has to be uploaded shortly before the ride
+    #[clap(long, value_enum, default_value_t)]
+    pub mga_mode: crate::mga::MgaMode,
 }
 
-#[derive(Args, Debug)]
+#[derive(Args, Debug, Default)]
 pub struct SyncOptions {
     #[clap(flatten)]
     mga_update: MgaUpdateOptions,
+    /// Only show what would be done, without changing anything on the device
+    #[clap(long)]
+    dry_run: bool,
+    /// Do all the sync operations even if the device battery is low or a workout is being recorded, and save the
+    /// downloaded workouts that have the same content as the ones already synced
+    #[clap(long)]
+    force: bool,
+    /// Delete the workouts from the device once their local copies are verified
+    ///
+    /// Can also be enabled with sync.delete_synced in the config
+    #[clap(long)]
+    delete_synced: bool,
+    #[clap(flatten)]
+    filter: filter::WorkoutFilter,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum GearCommand {
+    /// Set the wheel circumference of a gear from a measured rollout or from the tire size.
+    ///
+    /// The speed and the distance measured with a wheel speed sensor are only as accurate as the circumference.
+    /// For a rollout, mark the tire, roll the bike in a straight line with the rider on it and measure the distance.
+    Calibrate {
+        /// The gear to change, by its name or id. The active one by default
+        #[clap(long)]
+        gear: Option<String>,
+        /// The distance rolled, in mm
+        #[clap(
+            long,
+            value_name = "MM",
+            required_unless_present = "tire",
+            conflicts_with = "tire"
+        )]
+        distance: Option<f64>,
+        /// The number of wheel revolutions the distance was rolled over
+        #[clap(long, default_value_t = 1)]
+        revolutions: u32,
+        /// The tire size, like 700x25c, 25-622 (ETRTO) or 29x2.2
+        #[clap(long)]
+        tire: Option<String>,
+        /// Only show the new circumference, without changing the gear
+        #[clap(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum PanelsCommand {
+    /// Show the data fields of each panel.
+    Show,
+    /// Set the data fields shown on a panel.
+    ///
+    /// The number of fields selects the panel layout.
+    Set {
+        /// Panel number, as shown by `panels show`
+        panel: usize,
+        /// Data field identifiers, in the order they are shown on the screen
+        #[clap(required = true)]
+        fields: Vec<u16>,
+    },
+    /// Show a panel on the device.
+    Enable { panel: usize },
+    /// Hide a panel on the device.
+    Disable { panel: usize },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SensorsCommand {
+    /// Show the sensors paired with the device.
+    List,
+    /// Pair a sensor with the device by its BLE address.
+    ///
+    /// The device connects to it the next time the sensor is in range
+    Add {
+        /// The BLE address of the sensor, like AA:BB:CC:DD:EE:FF
+        address: BDAddr,
+        /// What the sensor measures: heart-rate, speed, cadence, speed-cadence or power
+        #[clap(long = "type", value_name = "TYPE")]
+        type_: SensorType,
+        /// The name shown on the device, the type by default
+        #[clap(long)]
+        name: Option<String>,
+    },
+    /// Unpair a sensor from the device.
+    Remove {
+        /// The sensor to remove, by its name or BLE address
+        sensor: String,
+    },
 }
 
 #[derive(Subcommand, Debug)]
@@ -48,29 +257,337 @@ pub enum DeviceCommand {
     Sync(SyncOptions),
     /// Shows various information about the device.
     Info,
+    /// List the files on the device.
+    ///
+    /// The device can't list its files, so the list is put together from the config files, the workouts and the routes
+    /// it knows about. The size of the A-GNSS data is not known.
+    Ls,
+    /// Show what the device supports: its GATT services and the control messages its firmware answers.
+    ///
+    /// Only the messages that read the device state are sent. Useful for reporting the devices and the firmware
+    /// versions that don't work as expected.
+    Capabilities,
+    /// List the workouts on the device, with their start times and whether they were downloaded.
+    Workouts(filter::WorkoutFilter),
+    /// List the configured devices and the XOSS devices nearby.
+    ///
+    /// Doesn't connect to the devices, the battery level and the number of pending workouts are the ones remembered from the last sync.
+    List {
+        /// Only show the configured devices, without scanning for the devices nearby
+        #[clap(long)]
+        no_scan: bool,
+    },
     /// Download a file from the device.
     Pull {
         device_filename: String,
         output_filename: Option<Utf8PathBuf>,
     },
     /// Upload a file to the device.
+    ///
+    /// The file is read back afterwards and its CRC-32 compared, to make sure it was not corrupted during the transfer.
     Push {
         input_filename: Utf8PathBuf,
         device_filename: Option<String>,
+        /// Read the file back to verify it (the default)
+        #[clap(long, overrides_with = "no_verify")]
+        verify: bool,
+        /// Do not read the file back to verify it
+        #[clap(long, overrides_with = "verify")]
+        no_verify: bool,
+    },
+    /// View or edit the data screens (panels) of the device.
+    #[clap(subcommand)]
+    Panels(PanelsCommand),
+    /// Change the gear (bike) profiles of the device.
+    #[clap(subcommand)]
+    Gear(GearCommand),
+    /// Manage the external sensors (heart rate straps, speed and cadence sensors) paired with the device.
+    #[clap(subcommand)]
+    Sensors(SensorsCommand),
+    /// Set the device time, reporting how far off its clock was.
+    ///
+    /// The time zone is set too: the configured one by default. The sync sets the time as well
+    SetTime {
+        /// Set the time zone to UTC
+        #[clap(long, conflicts_with = "local_offset")]
+        utc: bool,
+        /// Set the time zone to the current offset of this computer
+        #[clap(long)]
+        local_offset: bool,
+    },
+    /// Apply a template with settings, user profile fields, gears and panels to the device.
+    ///
+    /// Useful for setting up many devices identically.
+    Provision {
+        /// Path to the template file, or the name of a bundled template (club-default)
+        #[clap(long, default_value = "club-default")]
+        template: String,
+        /// Run even if the device is recording a workout, which may corrupt it
+        #[clap(long)]
+        force: bool,
+    },
+    /// Copy the settings, the panels and the gears from one configured device to another.
+    ///
+    /// Shows the differences and asks for confirmation before changing the target device.
+    /// Useful when replacing an older XOSS unit with a newer one.
+    CopySettings {
+        /// The device to copy from: its name, BLE address or serial number
+        #[clap(long)]
+        from: String,
+        /// The device to copy to: its name, BLE address or serial number
+        #[clap(long)]
+        to: String,
+        /// What to copy, everything by default
+        #[clap(long, value_enum, value_delimiter = ',')]
+        include: Vec<copy_settings::CopyItem>,
+        /// Do not ask for confirmation
+        #[clap(long)]
+        yes: bool,
+    },
+    /// Erase all the user data on the device and reboot it.
+    ///
+    /// Workouts that were not downloaded are lost!
+    FactoryReset {
+        /// Do not ask for confirmation
+        #[clap(long)]
+        yes: bool,
+        /// Run even if the device is recording a workout, which may corrupt it
+        #[clap(long)]
+        force: bool,
+    },
+    /// Reboot the device into the firmware update (DFU) mode.
+    ///
+    /// The device stays in this mode until a firmware is uploaded or it's turned off and on again.
+    Dfu {
+        /// Do not ask for confirmation
+        #[clap(long)]
+        yes: bool,
+        /// Run even if the device is recording a workout, which may corrupt it
+        #[clap(long)]
+        force: bool,
+    },
+    /// Update the device firmware.
+    ///
+    /// Takes a firmware package (a zip file in the nrfutil format). The device is rebooted into the DFU mode,
+    /// flashed and connected to again to check the new firmware version.
+    FirmwareUpdate {
+        package: Utf8PathBuf,
+        /// Do not ask for confirmation
+        #[clap(long)]
+        yes: bool,
+    },
+    /// Check whether a newer firmware is available.
+    ///
+    /// The firmware version reported by the device is compared against a manifest (see firmware.manifest in the config).
+    FirmwareCheck {
+        /// URL or path of the firmware manifest, overrides the one in the config
+        #[clap(long)]
+        manifest: Option<String>,
+        /// Download the update into the cache directory, to be used with firmware-update
+        #[clap(long)]
+        download: bool,
+    },
+    /// Upload workouts (FIT files) back to the device, for example when migrating to a new one.
+    ///
+    /// The device files are named after the workout start times, the workouts starting at the same second
+    /// as the ones on the device are moved a second later. Workouts already on the device are skipped.
+    Restore {
+        #[clap(required = true)]
+        files: Vec<Utf8PathBuf>,
+        /// How to name the files on the device
+        #[clap(long, value_enum, default_value_t)]
+        naming: restore::NamingOption,
+        /// Only show what would be uploaded
+        #[clap(long)]
+        dry_run: bool,
+        /// Read each workout back after uploading it to make sure it was not corrupted
+        #[clap(long)]
+        verify: bool,
+    },
+    /// Roll back a JSON config file on the device (like settings.json) to a backup.
+    ///
+    /// The files are backed up to the cache directory before each write. Takes a backup file, or the name of the device
+    /// file to restore its latest backup. The current content is backed up too, so the restore can be rolled back as well.
+    RestoreJson {
+        /// A backup file, or the name of a device file (settings.json)
+        file: String,
+        /// Do not ask for confirmation
+        #[clap(long)]
+        yes: bool,
+        /// Run even if the device is recording a workout, which may corrupt it
+        #[clap(long)]
+        force: bool,
     },
     /// Delete a file from the device.
     ///
     /// NOTE: don't delete .json files, not all of them are regenerated by the device.
-    Delete { device_filename: String },
+    Delete {
+        device_filename: String,
+        /// Run even if the device is recording a workout, which may corrupt it
+        #[clap(long)]
+        force: bool,
+    },
+    /// Measure the file transfer speed.
+    ///
+    /// Uploads a synthetic file and downloads it back, reporting the throughput, the retried packets and how long the
+    /// packets took. Useful for comparing the Bluetooth adapters and for reporting the slow transfers.
+    Benchmark {
+        /// Size of the test file, in KiB
+        #[clap(long, default_value_t = 64)]
+        size: u64,
+    },
+    /// Run commands (ls, cat, pull, push, info, cap, status) interactively over a single connection.
+    Shell,
+    /// Send a raw control message and print the reply, for exploring the protocol.
+    ///
+    /// The message is framed with the checksum, the type and the body are given in hex (like `raw-ctl ff` or
+    /// `raw-ctl 0d 746573742e62696e`). Some messages erase the data or reboot the device, nothing is checked!
+    RawCtl {
+        /// The message type, a hex byte
+        message_type: String,
+        /// The message body, in hex
+        body: Option<String>,
+        /// Required to actually send the message
+        #[clap(long)]
+        i_know_what_i_am_doing: bool,
+    },
+}
+
+#[derive(Args, Debug)]
+pub struct DeviceSelection {
+    /// The device to connect to: its name, BLE address or serial number
+    ///
+    /// Required only if there are several devices configured and no default one
+    #[clap(long, short, global = true)]
+    device: Option<String>,
 }
 
 #[derive(Args, Debug)]
 pub struct DeviceCli {
-    // TODO: include options for selecting the device
+    #[clap(flatten)]
+    selection: DeviceSelection,
     #[clap(subcommand)]
     subcommand: DeviceCommand,
 }
 
+#[derive(Subcommand, Debug)]
+pub enum DebugCommand {
+    /// Run a set of non-destructive protocol checks against the device and print a pass/fail report.
+    ///
+    /// Uses a scratch file to check that file transfers work, it is deleted afterwards.
+    Conformance,
+}
+
+#[derive(Args, Debug)]
+pub struct DebugCli {
+    #[clap(flatten)]
+    selection: DeviceSelection,
+    #[clap(subcommand)]
+    subcommand: DebugCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum AgentCommand {
+    /// Connect to the device and serve the commands in the foreground.
+    ///
+    /// Normally it's started in the background by the first command run with --keep-connection.
+    Run,
+    /// Disconnect from the device and exit.
+    Stop,
+}
+
+#[derive(Args, Debug)]
+pub struct AgentCli {
+    #[clap(flatten)]
+    selection: DeviceSelection,
+    #[clap(subcommand)]
+    subcommand: AgentCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum HistoryCommand {
+    /// Show when a file was uploaded to or downloaded from the devices.
+    File {
+        /// The file name on the device, like offline.gnss
+        filename: String,
+        /// Only show the transfers of this device: its name, BLE address or serial number
+        #[clap(long, short)]
+        device: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum MgaCommand {
+    /// Show the validity of the cached data, no device is needed.
+    Status,
+    /// Download the data again, even if the cached one is still good.
+    Update {
+        /// Which AssistNow service to use
+        #[clap(long, value_enum, default_value_t)]
+        mode: crate::mga::MgaMode,
+    },
+    /// Delete the cached data.
+    Clear {
+        /// Only delete the data of this AssistNow service
+        #[clap(long, value_enum)]
+        mode: Option<crate::mga::MgaMode>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum WorkoutCommand {
+    /// List the workouts downloaded from the devices.
+    ///
+    /// Files put into the workouts directory by hand are listed (and indexed) too.
+    List,
+    /// Salvage the damaged workouts, like the ones the device marks as broken.
+    ///
+    /// The incomplete records at the end are dropped and the header and the CRC are fixed to match the rest.
+    /// The repaired copies are named like `<name>-repaired.fit`, the original files are not changed.
+    Repair {
+        #[clap(required = true)]
+        files: Vec<Utf8PathBuf>,
+        /// Where to put the repaired files, the current directory by default
+        #[clap(long, short)]
+        output_dir: Option<Utf8PathBuf>,
+    },
+    /// Convert workouts to GPX or TCX, or copy them as FIT.
+    ///
+    /// The files can be given as paths or as names in the workouts directory. The damaged ones are converted as
+    /// far as they can be read. The title and the notes given with `workout annotate` are put into the exports.
+    Export {
+        #[clap(required = true)]
+        files: Vec<Utf8PathBuf>,
+        #[clap(long, value_enum, default_value_t)]
+        format: ExportFormat,
+        /// Where to put the exported files, the current directory by default
+        #[clap(long, short)]
+        output_dir: Option<Utf8PathBuf>,
+        /// Replace the recorded altitude with the terrain elevation from the offline DEM tiles
+        ///
+        /// With `auto` (the default when no value is given) only the obviously broken elevation is replaced.
+        /// The tiles are SRTM .hgt files (like N45E007.hgt) in the --dem-dir directory, nothing is downloaded.
+        #[clap(long, value_enum, num_args = 0..=1, default_missing_value = "auto")]
+        correct_elevation: Option<workout::ElevationCorrection>,
+        /// The directory with the DEM tiles, `dem` in the data directory by default
+        #[clap(long, value_name = "DIR")]
+        dem_dir: Option<Utf8PathBuf>,
+    },
+    /// Give a workout a title and notes, to be put into its exports.
+    ///
+    /// Shows the current ones when neither is given.
+    Annotate {
+        /// The file name in the workouts directory (the .fit can be left out) or the workout name on the device
+        workout: String,
+        /// The title, an empty one removes it
+        #[clap(long)]
+        title: Option<String>,
+        /// The notes, empty ones remove them
+        #[clap(long)]
+        notes: Option<String>,
+    },
+}
+
 #[derive(clap::Args, Debug)]
 pub struct GenerateCli {
     /// The shell to generate the completion for
@@ -85,18 +602,264 @@ pub enum CliCommand {
     /// Print paths to the config file and the data directory.
     Paths,
     /// Interact with the device.
+    #[command(visible_alias = "device")]
     Dev(DeviceCli),
+    /// Tools for debugging the device and the protocol implementation.
+    Debug(DebugCli),
+    /// Manage the background agent keeping the connection to a device open.
+    ///
+    /// Connecting to the device takes a few seconds. With --keep-connection (or agent.enabled in the config)
+    /// the first dev command starts an agent that stays connected, and the following commands are run through it.
+    /// The agent disconnects after agent.idle_timeout seconds (5 minutes by default) without commands.
+    Agent(AgentCli),
+    /// Keep syncing the configured devices whenever they come into range.
+    ///
+    /// Runs in the foreground until stopped with Ctrl+C or SIGTERM, so it can be used as a systemd service.
+    /// Every daemon.scan_interval seconds (1 minute by default) it looks for the devices that weren't synced for
+    /// daemon.sync_interval seconds (6 hours by default), syncs the ones in range and disconnects from them.
+    Daemon,
+    /// Show the history of the file transfers recorded by this tool.
+    #[clap(subcommand)]
+    History(HistoryCommand),
+    /// Browse the workouts synced from the devices.
+    #[clap(subcommand)]
+    Workout(WorkoutCommand),
+    /// Manage the cached A-GNSS (MGA) data, which the sync uploads to the devices
+    #[clap(subcommand)]
+    Mga(MgaCommand),
     /// Make sure the MGA data is up to date.
+    #[command(hide = true)]
     UpdateMga(MgaUpdateOptions),
     /// Generate shell completion
     Completion(GenerateCli),
 }
 
+impl CliCommand {
+    /// The name of the command if it needs the Bluetooth, so that the demo mode and the replays can't run it
+    fn unavailable_in_demo(&self) -> Option<&'static str> {
+        match self {
+            CliCommand::Setup(_) => Some("setup"),
+            CliCommand::Agent(_) => Some("agent"),
+            CliCommand::Daemon => Some("daemon"),
+            CliCommand::Dev(DeviceCli { subcommand, .. }) => match subcommand {
+                DeviceCommand::List { .. } => Some("dev list"),
+                DeviceCommand::CopySettings { .. } => Some("dev copy-settings"),
+                DeviceCommand::FirmwareUpdate { .. } => Some("dev firmware-update"),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// The command was interrupted with Ctrl+C
+#[derive(Error, Debug)]
+#[error("Interrupted")]
+pub struct Interrupted;
+
+/// Run a command working with the device, returning [Interrupted] on Ctrl+C
+///
+/// An interrupted command may leave a file transfer hanging on the device,
+/// so the transfer is stopped and the device is disconnected before returning.
+/// With `verbose`, the [link statistics](XossDevice::transport_stats) are printed after the command.
+async fn run_interruptible(
+    device: XossDevice,
+    verbose: bool,
+    command: impl FnOnce(&XossDevice) -> Pin<Box<dyn Future<Output = Result<()>> + '_>>,
+) -> Result<()> {
+    let result = tokio::select! {
+        result = command(&device) => Some(result),
+        _ = tokio::signal::ctrl_c() => None,
+    };
+    if verbose {
+        info!("Link statistics: {}", device.transport_stats());
+    }
+    if let Some(result) = result {
+        return result;
+    }
+
+    warn!("Interrupted, leaving the device in a consistent state. Press Ctrl+C again to exit right away");
+
+    let cleanup = async {
+        if let Err(e) = device.stop_transfer().await {
+            warn!("Failed to stop the transfer: {:#}", e);
+        }
+        if let Err(e) = device.disconnect().await {
+            warn!("Failed to disconnect from the device: {:#}", e);
+        }
+    };
+    tokio::select! {
+        _ = cleanup => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+
+    Err(Interrupted.into())
+}
+
+#[cfg(feature = "demo")]
+/// Run a command against the simulated device of the demo mode, keeping its files for the next run
+async fn run_demo(
+    timeouts: &TimeoutsConfig,
+    strict_protocol: bool,
+    verbose: bool,
+    transcript: Option<&Transcript>,
+    command: impl FnOnce(&XossDevice) -> Pin<Box<dyn Future<Output = Result<()>> + '_>>,
+) -> Result<()> {
+    let mock = crate::demo::load_device().context("Failed to load the simulated device")?;
+    let device = crate::demo::connect(&mock, timeouts).await?;
+    device.set_strict_protocol(strict_protocol);
+    if let Some(transcript) = transcript {
+        device.set_transcript(transcript.clone()).await;
+    }
+    crate::history::record_transfers(&device).await;
+    crate::json_backup::back_up_json_files(&device).await;
+
+    let result = run_interruptible(device, verbose, command).await;
+    let save_result =
+        crate::demo::save_device(&mock).context("Failed to save the simulated device");
+
+    result.and(save_result)
+}
+
+/// Run a command against a session recorded with `--record`
+async fn run_replay(
+    path: &Utf8Path,
+    timeouts: &TimeoutsConfig,
+    strict_protocol: bool,
+    verbose: bool,
+    transcript: Option<&Transcript>,
+    command: impl FnOnce(&XossDevice) -> Pin<Box<dyn Future<Output = Result<()>> + '_>>,
+) -> Result<()> {
+    let (device, peripheral) = crate::recording::connect_replay(path, timeouts).await?;
+    device.set_strict_protocol(strict_protocol);
+    if let Some(transcript) = transcript {
+        device.set_transcript(transcript.clone()).await;
+    }
+
+    let result = run_interruptible(device, verbose, command).await;
+    crate::recording::report_mismatches(&peripheral);
+    result
+}
+
+/// Connect to the configured device, offering to run the setup if there's no config yet
+async fn connect_device(
+    config: &mut Option<XossUtilConfig>,
+    selection: &DeviceSelection,
+    adapter: Option<&str>,
+) -> Result<XossDevice> {
+    let selector = selection.device.as_deref();
+
+    let error = match find_device_from_config(config, selector, adapter).await {
+        Ok(device) => return Ok(device),
+        Err(e) => e,
+    };
+
+    match error.downcast_ref::<LocateError>() {
+        Some(LocateError::NoConfig) if console::user_attended() => {
+            info!("No device is configured yet, the setup will find one and create a config");
+
+            let run_setup = dialoguer::Confirm::with_theme(DIALOGUER_THEME.deref())
+                .with_prompt("Do you want to run the setup now?")
+                .default(true)
+                .interact()
+                .context("Failed to get user confirmation")?;
+            if !run_setup {
+                return Err(error);
+            }
+
+            SetupCli::default()
+                .run(None, adapter)
+                .await
+                .context("Failed to run the setup")?;
+            *config = config::load_config().context("Failed to load the new config")?;
+
+            find_device_from_config(config, selector, adapter).await
+        }
+        Some(LocateError::DeviceNotFound(_)) => {
+            info!("{}", troubleshooting_hints());
+            Err(error)
+        }
+        _ => Err(error),
+    }
+}
+
+/// Save the transcript of the session, if it was requested with `--transcript`
+fn save_transcript(path: &Utf8Path, transcript: &Transcript) -> Result<()> {
+    std::fs::write(path, transcript.to_string())
+        .with_context(|| format!("Writing the transcript to {}", path))?;
+    info!("The transcript is written to {}", path);
+    Ok(())
+}
+
 impl Cli {
+    /// Whether `--demo` is given, never when built without the `demo` feature
+    pub fn is_demo(&self) -> bool {
+        #[cfg(feature = "demo")]
+        return self.demo;
+        #[cfg(not(feature = "demo"))]
+        false
+    }
+
     pub async fn run(self, config: Option<XossUtilConfig>) -> Result<()> {
+        let transcript_path = self.transcript.clone();
+        let transcript = transcript_path.as_ref().map(|_| Transcript::new());
+        let record_path = self.record.clone();
+        if record_path.is_some() {
+            crate::recording::enable();
+        }
+
+        let result = self.run_command(config, transcript.as_ref()).await;
+
+        if let (Some(path), Some(transcript)) = (&transcript_path, &transcript) {
+            // the transcript of a failed command is the most interesting one, so it's saved anyway
+            if let Err(e) = save_transcript(path, transcript) {
+                warn!("{:#}", e);
+            }
+        }
+        if let Some(path) = &record_path {
+            if let Err(e) = crate::recording::save(path) {
+                warn!("{:#}", e);
+            }
+        }
+
+        result
+    }
+
+    async fn run_command(
+        self,
+        mut config: Option<XossUtilConfig>,
+        transcript: Option<&Transcript>,
+    ) -> Result<()> {
+        let adapter = self
+            .adapter
+            .or_else(|| config.as_ref().and_then(|c| c.adapter.clone()));
+
+        #[cfg(feature = "demo")]
+        if self.demo {
+            if let Some(command) = self.command.unavailable_in_demo() {
+                bail!(
+                    "`{}` needs a real device, it's not available in the demo mode",
+                    command
+                );
+            }
+            crate::demo::prepare_mga_cache()?;
+        }
+        if self.replay.is_some() {
+            if let Some(command) = self.command.unavailable_in_demo() {
+                bail!(
+                    "`{}` needs a real device, it can't run against a recorded session",
+                    command
+                );
+            }
+        }
+        let timeouts = config
+            .as_ref()
+            .map(|c| c.timeouts.clone())
+            .unwrap_or_default();
+
         match self.command {
             CliCommand::Setup(setup) => setup
-                .run(config)
+                .run(config, adapter.as_deref())
                 .await
                 .context("Failed to run the setup subcommand"),
             CliCommand::Paths => {
@@ -113,12 +876,116 @@ impl Cli {
 
                 Ok(())
             }
+            CliCommand::Dev(DeviceCli {
+                subcommand: DeviceCommand::List { no_scan },
+                ..
+            }) => device::list(config.as_ref(), adapter.as_deref(), !no_scan)
+                .await
+                .context("Failed to list the devices"),
+            CliCommand::Dev(DeviceCli {
+                subcommand:
+                    DeviceCommand::CopySettings {
+                        from,
+                        to,
+                        include,
+                        yes,
+                    },
+                ..
+            }) => {
+                copy_settings::copy_settings(&config, adapter.as_deref(), &from, &to, &include, yes)
+                    .await
+                    .context("Failed to copy the settings")
+            }
+            CliCommand::Dev(DeviceCli {
+                subcommand: DeviceCommand::FirmwareUpdate { package, yes },
+                selection,
+            }) => firmware::firmware_update(
+                &config,
+                selection.device.as_deref(),
+                adapter.as_deref(),
+                &package,
+                yes,
+            )
+            .await
+            .context("Failed to update the firmware"),
+            #[cfg(feature = "demo")]
+            CliCommand::Dev(dev) if self.demo => run_demo(
+                &timeouts,
+                self.strict_protocol,
+                self.verbose,
+                transcript,
+                |device| Box::pin(dev.run(device, config)),
+            )
+            .await
+            .context("Failed to run the device subcommand"),
+            #[cfg(feature = "demo")]
+            CliCommand::Debug(debug) if self.demo => run_demo(
+                &timeouts,
+                self.strict_protocol,
+                self.verbose,
+                transcript,
+                |device| Box::pin(debug.run(device)),
+            )
+            .await
+            .context("Failed to run the debug subcommand"),
+            CliCommand::Dev(dev) if self.replay.is_some() => {
+                let path = self.replay.as_deref().unwrap();
+                run_replay(
+                    path,
+                    &timeouts,
+                    self.strict_protocol,
+                    self.verbose,
+                    transcript,
+                    |device| Box::pin(dev.run(device, config)),
+                )
+                .await
+                .context("Failed to run the device subcommand")
+            }
+            CliCommand::Debug(debug) if self.replay.is_some() => {
+                let path = self.replay.as_deref().unwrap();
+                run_replay(
+                    path,
+                    &timeouts,
+                    self.strict_protocol,
+                    self.verbose,
+                    transcript,
+                    |device| Box::pin(debug.run(device)),
+                )
+                .await
+                .context("Failed to run the debug subcommand")
+            }
             CliCommand::Dev(dev) => {
-                let device = crate::locate_util::find_device_from_config(&config)
+                #[cfg(unix)]
+                if dev.can_use_agent() && transcript.is_none() && self.record.is_none() {
+                    let start_agent =
+                        self.keep_connection || config.as_ref().is_some_and(|c| c.agent.enabled());
+                    if let Some(result) = crate::agent::run_via_agent(
+                        &config,
+                        dev.selection.device.as_deref(),
+                        adapter.as_deref(),
+                        start_agent,
+                    )
+                    .await?
+                    {
+                        return result.context("Failed to run the device subcommand");
+                    }
+                }
+
+                let device = connect_device(&mut config, &dev.selection, adapter.as_deref())
                     .await
                     .context("Failed to find the device")?;
+                device.set_strict_protocol(self.strict_protocol);
+                if let Some(transcript) = transcript {
+                    device.set_transcript(transcript.clone()).await;
+                }
+
+                crate::history::record_transfers(&device).await;
+                crate::json_backup::back_up_json_files(&device).await;
 
-                let result = dev.run(&device, config).await;
+                let result = run_interruptible(device, self.verbose, |device| {
+                    Box::pin(dev.run(device, config))
+                })
+                .await;
 
                 // let disconnect_result = device
                 //     .disconnect()
@@ -128,6 +995,91 @@ impl Cli {
                 result.context("Failed to run the device subcommand")
                 // .and(disconnect_result)
             }
+            CliCommand::Debug(debug) => {
+                let device = connect_device(&mut config, &debug.selection, adapter.as_deref())
+                    .await
+                    .context("Failed to find the device")?;
+                device.set_strict_protocol(self.strict_protocol);
+                if let Some(transcript) = transcript {
+                    device.set_transcript(transcript.clone()).await;
+                }
+
+                run_interruptible(device, self.verbose, |device| Box::pin(debug.run(device)))
+                    .await
+                    .context("Failed to run the debug subcommand")
+            }
+            #[cfg(unix)]
+            CliCommand::Agent(AgentCli {
+                selection,
+                subcommand: AgentCommand::Run,
+            }) => {
+                let device = connect_device(&mut config, &selection, adapter.as_deref())
+                    .await
+                    .context("Failed to find the device")?;
+                crate::history::record_transfers(&device).await;
+                crate::json_backup::back_up_json_files(&device).await;
+
+                let config = config.unwrap_or_default();
+                let device_info = crate::locate_util::select_configured_device(
+                    &config,
+                    selection.device.as_deref(),
+                )?;
+                crate::agent::run_agent(
+                    device,
+                    &crate::agent::socket_path(device_info),
+                    config.agent.idle_timeout(),
+                    config.agent.keepalive_interval(),
+                )
+                .await
+                .context("Failed to run the agent")
+            }
+            #[cfg(unix)]
+            CliCommand::Agent(AgentCli {
+                selection,
+                subcommand: AgentCommand::Stop,
+            }) => crate::agent::stop_agent(&config, selection.device.as_deref()).await,
+            #[cfg(not(unix))]
+            CliCommand::Agent(_) => {
+                anyhow::bail!("The agent is only supported on unix-like systems")
+            }
+            CliCommand::Daemon => {
+                let config =
+                    config.context("Config is required for the daemon, run setup first")?;
+                daemon::run_daemon(&config, adapter.as_deref())
+                    .await
+                    .context("Failed to run the daemon")
+            }
+            CliCommand::History(HistoryCommand::File { filename, device }) => {
+                crate::history::show_file_history(config.as_ref(), &filename, device.as_deref())
+            }
+            CliCommand::Workout(WorkoutCommand::List) => workout::list(config.as_ref()),
+            CliCommand::Workout(WorkoutCommand::Repair { files, output_dir }) => {
+                workout::repair(config.as_ref(), &files, output_dir.as_deref())
+            }
+            CliCommand::Workout(WorkoutCommand::Export {
+                files,
+                format,
+                output_dir,
+                correct_elevation,
+                dem_dir,
+            }) => workout::export(
+                config.as_ref(),
+                &files,
+                format,
+                output_dir.as_deref(),
+                correct_elevation,
+                dem_dir.as_deref(),
+            ),
+            CliCommand::Workout(WorkoutCommand::Annotate {
+                workout,
+                title,
+                notes,
+            }) => workout::annotate(config.as_ref(), &workout, title, notes),
+            CliCommand::Mga(MgaCommand::Status) => mga::status().await,
+            CliCommand::Mga(MgaCommand::Update { mode }) => {
+                mga::update(config.as_ref(), mode).await
+            }
+            CliCommand::Mga(MgaCommand::Clear { mode }) => mga::clear(mode),
             CliCommand::UpdateMga(mga_update) => {
                 let config = config.context("Config is required for update-mga subcommand")?;
                 crate::mga::get_mga_data(&config.mga, &mga_update).await?;
@@ -0,0 +1,65 @@
+use anyhow::{bail, Context, Result};
+use prettytable::{row, Table};
+use tracing::info;
+
+use super::PanelsCommand;
+use f_xoss::device::XossDevice;
+use f_xoss::model::Panel;
+
+fn panels_table(panels: &[Panel]) -> Table {
+    let mut table = Table::new();
+    table.set_format(*prettytable::format::consts::FORMAT_CLEAN);
+    table.set_titles(row!["#", "Enabled", "Fields"]);
+    for (index, panel) in panels.iter().enumerate() {
+        table.add_row(row![
+            index,
+            if panel.enabled { "yes" } else { "no" },
+            panel
+                .items
+                .iter()
+                .map(|i| i.to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
+        ]);
+    }
+    table
+}
+
+fn get_panel(panels: &mut [Panel], index: usize) -> Result<&mut Panel> {
+    let count = panels.len();
+    panels
+        .get_mut(index)
+        .with_context(|| format!("No panel #{} (the device has {} panels)", index, count))
+}
+
+impl PanelsCommand {
+    pub async fn run(self, device: &XossDevice) -> Result<()> {
+        let mut file = device.read_panels().await?;
+        let panels = &mut file.panels;
+
+        match self {
+            PanelsCommand::Show => {
+                info!("Panels:\n{}", panels_table(panels));
+                return Ok(());
+            }
+            PanelsCommand::Set { panel, fields } => {
+                if fields.is_empty() {
+                    bail!("A panel must show at least one field");
+                }
+                get_panel(panels, panel)?.items = fields;
+            }
+            PanelsCommand::Enable { panel } => get_panel(panels, panel)?.enabled = true,
+            PanelsCommand::Disable { panel } => {
+                get_panel(panels, panel)?.enabled = false;
+                if panels.iter().all(|p| !p.enabled) {
+                    bail!("At least one panel must stay enabled");
+                }
+            }
+        }
+
+        device.write_panels(&file).await?;
+        info!("Panels updated:\n{}", panels_table(&file.panels));
+
+        Ok(())
+    }
+}
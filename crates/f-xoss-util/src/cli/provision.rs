@@ -0,0 +1,161 @@
+//! Implementation of the `dev provision` subcommand
+//!
+//! Applies the same settings to a device in one go, useful when setting up many devices identically.
+
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use prettytable::{row, Table};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use f_xoss::device::XossDevice;
+use f_xoss::model::{GearBuilder, Panel, UserProfileBuilder};
+
+/// Templates shipped with the tool, selected by name instead of a path
+const BUNDLED_TEMPLATES: &[(&str, &str)] = &[(
+    "club-default",
+    include_str!("../../templates/club-default.toml"),
+)];
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct GearTemplate {
+    name: String,
+    weight: u32,
+    wheel_size: u32,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Template {
+    /// Overrides for the fields of settings.json
+    settings: Option<toml::Table>,
+    /// Overrides for the fields of user_profile.json
+    user_profile: Option<toml::Table>,
+    gears: Option<Vec<GearTemplate>>,
+    panels: Option<Vec<Panel>>,
+}
+
+fn load_template(template: &str) -> Result<Template> {
+    let bundled = BUNDLED_TEMPLATES
+        .iter()
+        .find(|(name, _)| *name == template || format!("{}.toml", name) == template);
+
+    // a file on disk takes precedence over a bundled template with the same name
+    let contents = match bundled {
+        Some((name, contents)) if !Utf8Path::new(template).exists() => {
+            info!("Using the bundled template {}", name);
+            contents.to_string()
+        }
+        _ => std::fs::read_to_string(template)
+            .with_context(|| format!("Reading template {}", template))?,
+    };
+
+    toml::from_str(&contents).with_context(|| format!("Parsing template {}", template))
+}
+
+/// Replace the fields of `base` with the ones from `overrides`
+///
+/// The result is deserialized back, so that invalid values are caught before anything is written to the device.
+fn apply_overrides<T: Serialize + DeserializeOwned>(
+    base: &T,
+    overrides: &toml::Table,
+) -> Result<(T, Vec<String>)> {
+    let mut value = serde_json::to_value(base).context("Serializing the current values")?;
+    let object = value
+        .as_object_mut()
+        .context("Expected the device data to be an object")?;
+
+    let mut changed = Vec::new();
+    for (key, new_value) in overrides {
+        anyhow::ensure!(object.contains_key(key), "Unknown field {:?}", key);
+        let new_value = serde_json::to_value(new_value)
+            .with_context(|| format!("Converting the value of {:?}", key))?;
+        if object.get(key) != Some(&new_value) {
+            changed.push(key.clone());
+        }
+        object.insert(key.clone(), new_value);
+    }
+
+    let result = serde_json::from_value(value).context("Applying the template values")?;
+    Ok((result, changed))
+}
+
+fn describe_changes(changed: &[String]) -> String {
+    if changed.is_empty() {
+        "already up to date".to_string()
+    } else {
+        format!("set {}", changed.join(", "))
+    }
+}
+
+pub async fn provision(device: &XossDevice, template: &str) -> Result<()> {
+    let template = load_template(template)?;
+
+    // the gears are checked before anything is written
+    let gears = template
+        .gears
+        .map(|gears| {
+            gears
+                .into_iter()
+                .enumerate()
+                .map(|(index, gear)| {
+                    let name = gear.name.clone();
+                    GearBuilder::new(gear.name)
+                        .gid(index as u32 + 1)
+                        .weight(gear.weight)
+                        .wheel_size(gear.wheel_size)
+                        .activated(index == 0)
+                        .build()
+                        .with_context(|| format!("Invalid gear {:?} in the template", name))
+                })
+                .collect::<Result<Vec<_>>>()
+        })
+        .transpose()?;
+
+    let mut summary = Table::new();
+    summary.set_format(*prettytable::format::consts::FORMAT_CLEAN);
+
+    if let Some(overrides) = &template.settings {
+        let settings = device.read_settings().await?;
+        let (settings, changed) =
+            apply_overrides(&settings, overrides).context("Applying the settings template")?;
+        if !changed.is_empty() {
+            device.write_settings(&settings).await?;
+        }
+        summary.add_row(row!["Settings:", describe_changes(&changed)]);
+    }
+
+    if let Some(overrides) = &template.user_profile {
+        let profile = device.read_user_profile().await?;
+        let (user_profile, changed) = apply_overrides(&profile.user_profile, overrides)
+            .context("Applying the user profile template")?;
+        if !changed.is_empty() {
+            let profile = UserProfileBuilder::new(profile)
+                .values(user_profile)
+                .build()
+                .context("Applying the user profile template")?;
+            device.write_user_profile(&profile).await?;
+        }
+        summary.add_row(row!["User profile:", describe_changes(&changed)]);
+    }
+
+    if let Some(gears) = gears {
+        device.write_gear_profile(&gears).await?;
+        summary.add_row(row!["Gears:", format!("wrote {}", gears.len())]);
+    }
+
+    if let Some(panels) = template.panels {
+        let count = panels.len();
+        // the rest of panels.json stays as the device has it
+        let mut file = device.read_panels().await?;
+        file.panels = panels;
+        device.write_panels(&file).await?;
+        summary.add_row(row!["Panels:", format!("wrote {}", count)]);
+    }
+
+    info!("Provisioning summary:\n{}", summary);
+
+    Ok(())
+}
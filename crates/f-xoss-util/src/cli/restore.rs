@@ -0,0 +1,162 @@
+//! Implementation of the `dev restore` subcommand: uploading workouts back to a device
+//!
+//! The device only lists the workouts named after their start time, so the names are generated from the FIT files.
+
+use anyhow::{anyhow, bail, Context, Result};
+use camino::Utf8PathBuf;
+use clap::ValueEnum;
+use prettytable::{row, Table};
+use std::collections::HashSet;
+use tracing::{info, warn};
+
+use crate::progress::SpanProgress;
+use f_xoss::device::XossDevice;
+use f_xoss::fit::{FitFile, WorkoutSummary};
+use f_xoss::model::WorkoutNaming;
+
+#[derive(ValueEnum, Debug, Clone, Copy, Default)]
+pub enum NamingOption {
+    /// Use the same naming as the workouts already on the device
+    #[default]
+    Auto,
+    /// Unix time of the workout start
+    UnixTime,
+    /// Local date and time of the workout start, as digits
+    DateTime,
+}
+
+struct RestoreItem {
+    path: Utf8PathBuf,
+    data: Vec<u8>,
+    device_name: u64,
+    /// The same workout is already on the device
+    already_present: bool,
+}
+
+impl RestoreItem {
+    fn device_filename(&self) -> String {
+        format!("{}.fit", self.device_name)
+    }
+}
+
+pub async fn restore(
+    device: &XossDevice,
+    files: &[Utf8PathBuf],
+    naming: NamingOption,
+    dry_run: bool,
+    verify: bool,
+) -> Result<()> {
+    let workouts = device
+        .read_workouts()
+        .await
+        .context("Reading the workout list")?;
+    let time_zone = device
+        .read_user_profile()
+        .await
+        .context("Reading the user profile")?
+        .user_profile
+        .time_zone;
+
+    let naming = match naming {
+        NamingOption::UnixTime => WorkoutNaming::UnixTime,
+        NamingOption::DateTime => WorkoutNaming::DateTime,
+        NamingOption::Auto => WorkoutNaming::detect(&workouts).unwrap_or_else(|| {
+            warn!("There are no workouts on the device to detect the naming from, using the unix time. Pass --naming if the device doesn't show the restored workouts");
+            WorkoutNaming::UnixTime
+        }),
+    };
+
+    let mut taken = workouts.iter().map(|w| w.name).collect::<HashSet<_>>();
+
+    // sort by the start time, so that the renames of the clashing workouts don't depend on the argument order
+    let mut items = Vec::new();
+    for path in files {
+        let data = tokio::fs::read(path)
+            .await
+            .with_context(|| format!("Reading {}", path))?;
+        let fit = FitFile::parse(&data).with_context(|| format!("Parsing {}", path))?;
+        let start = WorkoutSummary::from_fit(&fit)
+            .start_time
+            .ok_or_else(|| anyhow!("{} has no start time", path))?;
+        items.push((start, path.clone(), data));
+    }
+    items.sort_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+
+    let mut plan = Vec::new();
+    for (start, path, data) in items {
+        let name = naming.name(start, time_zone);
+        let already_present = workouts
+            .iter()
+            .any(|w| w.name == name && w.size as usize == data.len());
+        let device_name = if already_present {
+            name
+        } else {
+            naming.unique_name(start, time_zone, &taken)
+        };
+        if device_name != name && !already_present {
+            info!(
+                "{}.fit is already taken, {} will be uploaded as {}.fit",
+                name, path, device_name
+            );
+        }
+        taken.insert(device_name);
+        plan.push(RestoreItem {
+            path,
+            data,
+            device_name,
+            already_present,
+        });
+    }
+
+    let mut table = Table::new();
+    table.set_format(*prettytable::format::consts::FORMAT_CLEAN);
+    table.set_titles(row!["File", "Device file", "Size", "Action"]);
+    for item in &plan {
+        table.add_row(row![
+            item.path,
+            item.device_filename(),
+            humansize::format_size(item.data.len(), humansize::BINARY),
+            if item.already_present {
+                "skip (already on the device)"
+            } else {
+                "upload"
+            }
+        ]);
+    }
+
+    if dry_run {
+        info!("Dry run, the following would be done:\n{}", table);
+        return Ok(());
+    }
+    info!("Restoring the workouts:\n{}", table);
+
+    let mut failed = Vec::new();
+    for item in plan.iter().filter(|item| !item.already_present) {
+        let device_filename = item.device_filename();
+        let progress = SpanProgress::default();
+        let result = if verify {
+            device
+                .write_file_verified(&device_filename, &item.data, &progress)
+                .await
+                .map(|verification| super::device::log_verification(&device_filename, verification))
+        } else {
+            device
+                .write_file(&device_filename, &item.data, &progress)
+                .await
+        };
+        if let Err(e) = result {
+            warn!("Failed to upload {}: {:#}", item.path, e);
+            failed.push(item.path.to_string());
+        }
+    }
+
+    if !failed.is_empty() {
+        bail!(
+            "{} workouts could not be restored: {}",
+            failed.len(),
+            failed.join(", ")
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,71 @@
+use anyhow::{bail, Result};
+use btleplug::api::BDAddr;
+use prettytable::{row, Table};
+use tracing::info;
+
+use super::SensorsCommand;
+use f_xoss::device::XossDevice;
+use f_xoss::model::Sensor;
+
+fn sensors_table(sensors: &[Sensor]) -> Table {
+    let mut table = Table::new();
+    table.set_format(*prettytable::format::consts::FORMAT_CLEAN);
+    table.set_titles(row!["Address", "Type", "Name"]);
+    for sensor in sensors {
+        table.add_row(row![sensor.address, sensor.type_, sensor.name]);
+    }
+    table
+}
+
+/// Whether the sensor has this address, whatever the case of the stored one
+fn has_address(sensor: &Sensor, address: BDAddr) -> bool {
+    BDAddr::from_str_delim(&sensor.address).is_ok_and(|a| a == address)
+}
+
+impl SensorsCommand {
+    pub async fn run(self, device: &XossDevice) -> Result<()> {
+        let mut sensors = device.read_sensors().await?;
+
+        match self {
+            SensorsCommand::List => {
+                if sensors.is_empty() {
+                    info!("No sensors are paired with the device");
+                } else {
+                    info!("Sensors:\n{}", sensors_table(&sensors));
+                }
+                return Ok(());
+            }
+            SensorsCommand::Add {
+                address,
+                type_,
+                name,
+            } => {
+                if let Some(sensor) = sensors.iter().find(|s| has_address(s, address)) {
+                    bail!(
+                        "{} is already paired as {} ({}), remove it first to change it",
+                        address,
+                        sensor.name,
+                        sensor.type_
+                    );
+                }
+                let name = name.unwrap_or_else(|| type_.name().to_string());
+                sensors.push(Sensor::new(address.to_string(), type_, name));
+            }
+            SensorsCommand::Remove { sensor } => {
+                let count = sensors.len();
+                sensors.retain(|s| {
+                    s.name != sensor
+                        && !BDAddr::from_str_delim(&sensor).is_ok_and(|a| has_address(s, a))
+                });
+                if sensors.len() == count {
+                    bail!("No sensor {:?} is paired with the device", sensor);
+                }
+            }
+        }
+
+        device.write_sensors(&sensors).await?;
+        info!("Sensors updated:\n{}", sensors_table(&sensors));
+
+        Ok(())
+    }
+}
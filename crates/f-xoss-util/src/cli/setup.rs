@@ -1,220 +1,293 @@
 use crate::{config, mga};
-use anyhow::{anyhow, Context, Result};
-use btleplug::api::{
-    BDAddr, Central, CentralEvent, Peripheral as _, PeripheralProperties, ScanFilter,
-};
-use btleplug::platform::{Adapter, Peripheral, PeripheralId};
-use console::Term;
-use dialoguer::theme::ColorfulTheme;
+use anyhow::{anyhow, bail, Context, Result};
+use btleplug::api::{BDAddr, Peripheral as _};
+use btleplug::platform::PeripheralId;
+use console::{Key, Term};
 use f_xoss::device::XossDevice;
+use f_xoss::scan::{scan_devices, DiscoveredDevice, ScanOptions};
 use itertools::Itertools;
-use once_cell::sync::Lazy;
 use owo_colors::colored::Color;
 use owo_colors::OwoColorize;
 use similar::ChangeTag;
 use std::fmt::{Display, Formatter};
+use std::io::ErrorKind;
 use std::ops::{Deref, Not};
-use std::pin::Pin;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::select;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Notify};
+use tokio::task::JoinHandle;
 use tokio_stream::{Stream, StreamExt};
-use tracing::{error, info, info_span, warn, Instrument};
+use tracing::{error, info, warn};
 
-use super::SetupCli;
-use crate::config::{MgaConfig, XossDeviceInfo, XossUtilConfig};
-
-static DIALOGUER_THEME: Lazy<ColorfulTheme> = Lazy::new(|| ColorfulTheme::default());
+use super::{SetupCli, DIALOGUER_THEME};
+use crate::config::{MgaConfig, TimeoutsConfig, XossDeviceInfo, XossUtilConfig};
+use crate::secrets::{Secret, UBLOX_TOKEN_ENTRY};
 
 #[derive(Clone, Debug)]
-struct ScannerDevice {
-    peripheral_id: PeripheralId,
-    peripheral: Peripheral,
-    address: BDAddr,
-    properties: PeripheralProperties,
-}
-
-impl ScannerDevice {
-    pub fn likely_xoss_device(&self) -> bool {
-        self.properties
-            .local_name
-            .as_ref()
-            .map(|v| v.contains("XOSS"))
-            .unwrap_or(false)
-    }
-}
+struct ScannerDevice(DiscoveredDevice);
 
 impl Display for ScannerDevice {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        if let Some(name) = &self.properties.local_name {
-            write!(f, "{} ({})", name.blue(), self.address.bright_black())
+        if let Some(name) = &self.0.name {
+            write!(f, "{} ({})", name.blue(), self.0.address.bright_black())
         } else {
-            write!(f, "{}", self.address.bright_black())
+            write!(f, "{}", self.0.address.bright_black())
         }
     }
 }
 impl PartialEq for ScannerDevice {
     fn eq(&self, other: &Self) -> bool {
-        ScannerDevice::partial_cmp(self, other) == Some(std::cmp::Ordering::Equal)
+        ScannerDevice::cmp(self, other) == std::cmp::Ordering::Equal
     }
 }
 
 impl PartialOrd for ScannerDevice {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Eq for ScannerDevice {}
+impl Ord for ScannerDevice {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
         // put the XOSS devices first
         // then the ones with a name
         // then the other ones
 
-        let self_xoss = self.likely_xoss_device();
-        let other_xoss = other.likely_xoss_device();
+        let self_xoss = self.0.likely_xoss;
+        let other_xoss = other.0.likely_xoss;
 
-        let self_name = self.properties.local_name.is_some();
-        let other_name = other.properties.local_name.is_some();
+        let self_name = self.0.name.is_some();
+        let other_name = other.0.name.is_some();
 
         // note: order reversed
-        Some(
-            self_xoss
-                .cmp(&other_xoss)
-                .reverse()
-                .then(self_name.cmp(&other_name).reverse()),
-        )
+        self_xoss
+            .cmp(&other_xoss)
+            .reverse()
+            .then(self_name.cmp(&other_name).reverse())
     }
 }
 
-impl Eq for ScannerDevice {}
-impl Ord for ScannerDevice {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        ScannerDevice::partial_cmp(self, other).unwrap()
-    }
-}
+/// How many devices the scanner shows at once, the list scrolls to show the others
+const MAX_SHOWN_DEVICES: usize = 10;
+
+/// How long to wait for a XOSS device before showing the troubleshooting hints
+const HINTS_DELAY: Duration = Duration::from_secs(10);
 
 struct ScannerState {
     devices: Mutex<Vec<ScannerDevice>>,
+    /// Notified when a device is discovered or updated
+    changed: Notify,
+}
+
+/// Read a key without blocking the runtime
+///
+/// The read can't be cancelled, so a new one is only started after the previous one has returned
+fn read_key(term: &Term) -> JoinHandle<std::io::Result<Key>> {
+    let term = term.clone();
+    tokio::task::spawn_blocking(move || term.read_key())
+}
+
+fn format_rssi(rssi: Option<i16>) -> String {
+    match rssi {
+        Some(rssi) => format!("{:>4} dBm", rssi),
+        None => "    ? dBm".to_string(),
+    }
 }
 
 impl ScannerState {
-    async fn add_device(&self, device: ScannerDevice) {
+    async fn add_device(&self, device: DiscoveredDevice) {
         let mut devices = self.devices.lock().await;
 
-        if !devices
+        // the scanner reports the device again when its name or RSSI changes
+        match devices.iter_mut().find(|d| d.0.id == device.id) {
+            Some(existing) => *existing = ScannerDevice(device),
+            None => devices.push(ScannerDevice(device)),
+        }
+        self.changed.notify_one();
+    }
+
+    async fn sorted_devices(&self) -> Vec<ScannerDevice> {
+        self.devices.lock().await.iter().cloned().sorted().collect()
+    }
+
+    /// The lines showing the scan results, with the device at `index` highlighted
+    fn render(
+        devices: &[ScannerDevice],
+        index: usize,
+        scanning_for: Duration,
+        width: usize,
+    ) -> Vec<String> {
+        let mut lines = vec![
+            format!(
+                "{} {} {}",
+                "?".yellow(),
+                "Select a XOSS device to connect to".bold(),
+                "(↑/↓ to move, Enter to connect, Esc to cancel)".bright_black()
+            ),
+            format!(
+                "  Scanning for {} s, {} devices found",
+                scanning_for.as_secs(),
+                devices.len()
+            )
+            .bright_black()
+            .to_string(),
+        ];
+
+        let first_shown = index
+            .saturating_sub(MAX_SHOWN_DEVICES - 1)
+            .min(devices.len().saturating_sub(MAX_SHOWN_DEVICES));
+        for (i, device) in devices
             .iter()
-            .any(|d| d.peripheral_id == device.peripheral_id)
+            .enumerate()
+            .skip(first_shown)
+            .take(MAX_SHOWN_DEVICES)
         {
-            devices.push(device);
+            let rssi = format_rssi(device.0.rssi);
+            lines.push(if i == index {
+                format!("{} {}  {}", ">".green(), rssi.green(), device)
+            } else {
+                format!("  {}  {}", rssi.bright_black(), device)
+            });
         }
+        if devices.len() > MAX_SHOWN_DEVICES {
+            lines.push(
+                format!("  ({} more)", devices.len() - MAX_SHOWN_DEVICES)
+                    .bright_black()
+                    .to_string(),
+            );
+        }
+
+        if scanning_for >= HINTS_DELAY && !devices.iter().any(|d| d.0.likely_xoss) {
+            lines.push(format!(
+                "  No XOSS devices found yet. {}",
+                crate::locate_util::troubleshooting_hints()
+            ));
+        }
+
+        // a wrapped line would throw off the clearing of the list before it's drawn again
+        lines
+            .into_iter()
+            .map(|line| console::truncate_str(&line, width, "…").into_owned())
+            .collect()
     }
 
+    /// Let the user choose a device, updating the list as the devices are discovered
+    ///
+    /// Returns `None` if the user cancels
     async fn select_device(&self, term: &Term) -> Result<Option<ScannerDevice>> {
-        let devices = {
-            self.devices
-                .lock()
-                .await
-                .iter()
-                .cloned()
-                .sorted()
-                .collect::<Vec<_>>()
-        };
+        term.hide_cursor()?;
+        let result = self.run_selection(term).await;
+        term.show_cursor()?;
+        result
+    }
 
-        let selected = dialoguer::Select::with_theme(DIALOGUER_THEME.deref())
-            .items(&devices)
-            .item("[Rescan]")
-            .default(0)
-            .with_prompt("Select a XOSS device to connect to")
-            .interact_on_opt(term)
-            .context("Failed to select a device")?;
-
-        Ok(selected.and_then(|index| {
-            if index == devices.len() {
-                None
-            } else {
-                Some(devices[index].clone())
+    async fn run_selection(&self, term: &Term) -> Result<Option<ScannerDevice>> {
+        let started = Instant::now();
+        // the selection stays on the same device when the list is reordered, the first one until the user moves
+        let mut selected: Option<PeripheralId> = None;
+        let mut drawn_lines = 0;
+        let mut tick = tokio::time::interval(Duration::from_secs(1));
+        let mut key = read_key(term);
+
+        let chosen = loop {
+            let devices = self.sorted_devices().await;
+            let index = selected
+                .as_ref()
+                .and_then(|id| devices.iter().position(|d| &d.0.id == id))
+                .unwrap_or(0);
+
+            let width = term.size().1 as usize;
+            let lines = Self::render(&devices, index, started.elapsed(), width);
+            term.clear_last_lines(drawn_lines)?;
+            for line in &lines {
+                term.write_line(line)?;
             }
-        }))
+            drawn_lines = lines.len();
+
+            select! {
+                pressed = &mut key => {
+                    let pressed = match pressed.context("Failed to read the key")? {
+                        Err(e) if e.kind() == ErrorKind::Interrupted => {
+                            term.clear_last_lines(drawn_lines)?;
+                            return Err(super::Interrupted.into());
+                        }
+                        result => result.context("Failed to read the key")?,
+                    };
+                    let index = match pressed {
+                        Key::ArrowUp | Key::Char('k') => index.saturating_sub(1),
+                        Key::ArrowDown | Key::Char('j') => {
+                            (index + 1).min(devices.len().saturating_sub(1))
+                        }
+                        Key::Enter if !devices.is_empty() => break Some(devices[index].clone()),
+                        Key::Escape | Key::Char('q') => break None,
+                        _ => index,
+                    };
+                    selected = devices.get(index).map(|d| d.0.id.clone());
+                    key = read_key(term);
+                }
+                _ = self.changed.notified() => {}
+                _ = tick.tick() => {}
+            }
+        };
+
+        term.clear_last_lines(drawn_lines)?;
+        Ok(chosen)
     }
 
-    async fn handle_scan_events(
+    async fn handle_scan_results(
         &self,
-        adapter: &Adapter,
-        mut events: Pin<Box<dyn Stream<Item = CentralEvent> + Send>>,
+        results: impl Stream<Item = Result<DiscoveredDevice>>,
     ) -> Result<()> {
-        while let Some(event) = events.next().await {
-            if let CentralEvent::DeviceDiscovered(peripheral_id) = event {
-                let peripheral = adapter
-                    .peripheral(&peripheral_id)
-                    .await
-                    .context("Failed to get peripheral properties")?;
-
-                let address = peripheral.address();
-                let Some(properties) = peripheral.properties().await? else {
-                    warn!("Failed to get peripheral properties for {}", address);
-                    continue;
-                };
+        tokio::pin!(results);
 
-                let device = ScannerDevice {
-                    peripheral_id,
-                    peripheral,
-                    address,
-                    properties,
-                };
-
-                self.add_device(device).await;
-            }
+        while let Some(device) = results.next().await {
+            self.add_device(device?).await;
         }
 
         Ok(())
     }
 }
 
-async fn find_device() -> Result<XossDeviceInfo> {
+async fn find_device(
+    adapter: Option<&str>,
+    pair: bool,
+    timeouts: &TimeoutsConfig,
+) -> Result<XossDeviceInfo> {
     let manager = btleplug::platform::Manager::new()
         .await
         .context("Failed to create a manager")?;
-    let adapter = crate::locate_util::find_adapter(&manager).await?;
-
-    let events = adapter
-        .events()
-        .await
-        .context("Failed to get adapter events stream")?;
-
-    adapter
-        .start_scan(ScanFilter::default())
-        .await
-        .context("Starting scan")?;
+    let adapter = crate::locate_util::find_adapter(&manager, adapter).await?;
+
+    let results = scan_devices(
+        &adapter,
+        ScanOptions {
+            report_rssi_changes: true,
+            ..Default::default()
+        },
+    )
+    .await?;
 
     let scanner = ScannerState {
         devices: Mutex::new(Vec::new()),
+        changed: Notify::new(),
     };
 
     let term = Term::stdout();
 
     let cli = async {
         loop {
-            tokio::time::sleep(Duration::from_secs(5))
-                .instrument(info_span!("Scanning for bluetooth devices"))
-                .await;
-
             let Some(device) = scanner
                 .select_device(&term)
                 .await
                 .context("Selecting device")?
-                else { continue; };
+            else {
+                bail!("No device selected, setup cancelled");
+            };
 
             info!("Connecting to {}...", device);
 
-            let connect_result = async {
-                device
-                    .peripheral
-                    .connect()
-                    .await
-                    .context("Connecting to device...")?;
-
-                XossDevice::new(device.peripheral.clone())
-                    .await
-                    .context("Failed to connect to XOSS device")
-            }
-            .await;
-
-            let xoss_device = match connect_result {
+            let xoss_device = match connect_scanned(&device.0, pair, timeouts).await {
                 Ok(d) => d,
                 Err(e) => {
                     error!("Failed to connect to XOSS device:\n {:?}", e);
@@ -226,28 +299,136 @@ async fn find_device() -> Result<XossDeviceInfo> {
         }
     };
 
-    let events_handler = scanner.handle_scan_events(&adapter, events);
+    // the scan is stopped when the results stream is dropped at the end of the select
+    let results_handler = scanner.handle_scan_results(results);
 
     let result = select! {
         res = cli => res,
-        res = events_handler => {
+        res = results_handler => {
             match res {
-                Ok(()) => Err(anyhow!("Scan events handler exited")),
+                Ok(()) => Err(anyhow!("Scan results stream ended")),
                 Err(e) => Err(e),
             }
         }
     };
 
-    adapter.stop_scan().await.context("Stopping scan")?;
+    let (xoss_device, ScannerDevice(device)): (XossDevice, ScannerDevice) = result?;
 
-    let (xoss_device, device): (XossDevice, ScannerDevice) = result?;
+    Ok(describe_device(device, &xoss_device, pair).await)
+}
 
-    info!("Device info: {:#?}", xoss_device.device_info().await);
+async fn connect_scanned(
+    device: &DiscoveredDevice,
+    pair: bool,
+    timeouts: &TimeoutsConfig,
+) -> Result<XossDevice> {
+    if pair {
+        crate::pairing::pair(&device.peripheral)
+            .await
+            .context("Failed to pair with the device")?;
+    }
+
+    device
+        .peripheral
+        .connect()
+        .await
+        .context("Connecting to device...")?;
+
+    XossDevice::with_options(device.peripheral.clone(), timeouts.transport_options())
+        .await
+        .context("Failed to connect to XOSS device")
+        .map_err(|e| crate::pairing::with_hint(e, pair))
+}
 
-    Ok(XossDeviceInfo {
-        name: device.properties.local_name.clone(),
-        peripheral_id: device.peripheral_id,
-    })
+async fn describe_device(
+    device: DiscoveredDevice,
+    xoss_device: &XossDevice,
+    pair: bool,
+) -> XossDeviceInfo {
+    let device_info = xoss_device.device_info();
+    info!("Device info: {:#?}", device_info);
+
+    XossDeviceInfo {
+        name: device.name,
+        peripheral_id: device.id,
+        address: Some(device.address),
+        serial_number: Some(device_info.serial_number.clone()),
+        pair,
+        json_protocol: None,
+        battery_fallback: None,
+    }
+}
+
+/// Find the device given on the command line, without asking anything
+async fn find_device_by(
+    adapter: Option<&str>,
+    address: Option<BDAddr>,
+    name: Option<&str>,
+    pair: bool,
+    timeouts: &TimeoutsConfig,
+) -> Result<XossDeviceInfo> {
+    let manager = btleplug::platform::Manager::new()
+        .await
+        .context("Failed to create a manager")?;
+    let adapter = crate::locate_util::find_adapter(&manager, adapter).await?;
+
+    let description = [
+        address.map(|a| a.to_string()),
+        name.map(|n| format!("{:?}", n)),
+    ]
+    .into_iter()
+    .flatten()
+    .join(" ");
+    info!("Scanning for {}...", description);
+
+    let results = scan_devices(&adapter, ScanOptions::default()).await?;
+    let find = async {
+        tokio::pin!(results);
+        while let Some(device) = results.next().await {
+            let device = device?;
+            let address_matches = address.map_or(true, |a| a == device.address);
+            let name_matches = name.map_or(true, |n| device.name.as_deref() == Some(n));
+            if address_matches && name_matches {
+                return Ok(Some(device));
+            }
+        }
+        Ok::<_, anyhow::Error>(None)
+    };
+    let device = select! {
+        _ = tokio::time::sleep(timeouts.scan()) => None,
+        result = find => result?,
+    };
+    let Some(device) = device else {
+        bail!(
+            "Could not find {}. {}",
+            description,
+            crate::locate_util::troubleshooting_hints()
+        );
+    };
+
+    info!("Connecting to {}...", ScannerDevice(device.clone()));
+    let xoss_device = connect_scanned(&device, pair, timeouts).await?;
+    Ok(describe_device(device, &xoss_device, pair).await)
+}
+
+async fn check_ublox_token(token: &str) -> Result<()> {
+    let token_valid = mga::check_ublox_token(token)
+        .await
+        .context("Failed to check u-blox token")?;
+    if !token_valid {
+        bail!("The u-blox server does not accept the token");
+    }
+    info!("The u-blox token is valid!");
+    Ok(())
+}
+
+/// Keep the token in the system keyring unless the config says otherwise
+fn store_ublox_token(config: &XossUtilConfig, token: String) -> Secret {
+    if config.use_keyring() {
+        Secret::store(UBLOX_TOKEN_ENTRY, token)
+    } else {
+        Secret::Plain(token)
+    }
 }
 
 async fn get_ublox_token() -> Result<Option<String>> {
@@ -265,34 +446,13 @@ async fn get_ublox_token() -> Result<Option<String>> {
             return Ok(None);
         };
 
-        let token_valid = mga::check_ublox_token(&token)
-            .await
-            .context("Failed to check u-blox token")?;
-
-        if token_valid {
-            info!("The u-blox token is valid!");
-            return Ok(Some(token));
-        } else {
-            println!("The u-blox server does not accept the token you entered. Please try again.");
+        match check_ublox_token(&token).await {
+            Ok(()) => return Ok(Some(token)),
+            Err(e) => println!("{:#}. Please try again.", e),
         }
     }
 }
 
-async fn save_config(config: &XossUtilConfig) -> Result<()> {
-    let config_path = config::config_path();
-
-    info!("Saving the config to {}", config_path.display());
-    std::fs::create_dir_all(config_path.parent().unwrap())
-        .context("Creating the config directory")?;
-    std::fs::write(
-        &config_path,
-        toml::to_string_pretty(config).context("Serializing the config file")?,
-    )
-    .context("Writing the config file")?;
-
-    Ok(())
-}
-
 async fn save_config_with_confirmation(config: &XossUtilConfig) -> Result<()> {
     // find the diff of the config & the current config, and show it to the user
 
@@ -328,19 +488,54 @@ async fn save_config_with_confirmation(config: &XossUtilConfig) -> Result<()> {
         .context("Failed to get user confirmation")?;
 
     if confirm {
-        save_config(config).await?;
+        config::save_config(config)?;
         Ok(())
     } else {
         Err(anyhow!("User cancelled the config save"))
     }
 }
 
+/// Ask which device should be used when none is selected on the command line
+fn select_default_device(devices: &[XossDeviceInfo]) -> Result<Option<String>> {
+    let selected = dialoguer::Select::with_theme(DIALOGUER_THEME.deref())
+        .with_prompt("Several devices are configured, which one should be used by default?")
+        .items(&devices.iter().map(|d| d.identify()).collect::<Vec<_>>())
+        .item("[Ask every time]")
+        .default(0)
+        .interact()
+        .context("Failed to select the default device")?;
+
+    Ok(devices
+        .get(selected)
+        .map(|d| d.serial_number.clone().unwrap_or_else(|| d.identify())))
+}
+
 impl SetupCli {
-    pub async fn run(self, config: Option<XossUtilConfig>) -> Result<()> {
+    pub async fn run(self, config: Option<XossUtilConfig>, adapter: Option<&str>) -> Result<()> {
         let mut devices = config.as_ref().map_or_else(Vec::new, |v| v.devices.clone());
         let mut new_config = config.clone().unwrap_or_default();
 
+        let device_given = self.device_mac.is_some() || self.device_name.is_some();
+        let timeouts = new_config.timeouts.clone();
+        let find_device = || async {
+            if device_given {
+                find_device_by(
+                    adapter,
+                    self.device_mac,
+                    self.device_name.as_deref(),
+                    self.pair,
+                    &timeouts,
+                )
+                .await
+            } else {
+                find_device(adapter, self.pair, &timeouts).await
+            }
+        };
+
         if devices.is_empty() {
+            if !device_given && self.yes {
+                bail!("No devices configured, pass --device-mac or --device-name to set one up without prompts");
+            }
             info!("No devices configured, scanning for devices...");
             let device = find_device().await?;
             devices.push(device);
@@ -351,26 +546,71 @@ impl SetupCli {
             // save the config file, but only if it doesn't exist
             // for the final save we'll ask the user
             if config.is_none() {
-                save_config(&new_config).await?;
+                config::save_config(&new_config)?;
             }
         } else {
-            info!("Found device in config, skipping scan");
+            let state = crate::state::load_state()?;
+            for device in &devices {
+                info!(
+                    "Found {} in config ({})",
+                    device.identify(),
+                    state.describe_device(device)
+                );
+            }
+
+            let add_device = if device_given || self.yes {
+                device_given
+            } else {
+                dialoguer::Confirm::with_theme(DIALOGUER_THEME.deref())
+                    .with_prompt("Do you want to add another device?")
+                    .default(false)
+                    .interact()
+                    .context("Failed to get user confirmation")?
+            };
+
+            if add_device {
+                let device = find_device().await?;
+                if let Some(existing) = devices
+                    .iter()
+                    .find(|d| d.serial_number.is_some() && d.serial_number == device.serial_number)
+                {
+                    warn!(
+                        "This device is already configured as {}, not adding it again",
+                        existing.identify()
+                    );
+                } else {
+                    devices.push(device);
+                    new_config = XossUtilConfig {
+                        devices: devices.clone(),
+                        ..new_config
+                    };
+                }
+            }
+        }
+
+        if devices.len() > 1 && new_config.default_device.is_none() && !self.yes {
+            new_config.default_device = select_default_device(&devices)?;
         }
 
         let ublox_token = config.as_ref().and_then(|v| v.mga.ublox_token.clone());
-        if ublox_token.is_none() {
+        if let Some(token) = self.ublox_token.clone() {
+            check_ublox_token(&token).await?;
+            new_config.mga.ublox_token = Some(store_ublox_token(&new_config, token));
+        } else if ublox_token.is_none() && self.yes {
+            info!("No ublox token configured, pass --ublox-token to set it");
+        } else if ublox_token.is_none() {
             info!("No ublox token configured, asking for it...");
             if let Some(ublox_token) = get_ublox_token().await? {
                 new_config = XossUtilConfig {
                     mga: MgaConfig {
-                        ublox_token: Some(ublox_token),
+                        ublox_token: Some(store_ublox_token(&new_config, ublox_token)),
                         ..new_config.mga
                     },
                     ..new_config
                 };
 
                 if config.is_none() {
-                    save_config(&new_config).await?;
+                    config::save_config(&new_config)?;
                 }
             } else {
                 info!("No ublox token provided, not saving it");
@@ -379,11 +619,11 @@ impl SetupCli {
             info!("Found ublox token in config, skipping prompt");
         }
 
-        if config.as_ref().map_or(true, |config| config != &new_config) {
+        if config.as_ref() != Some(&new_config) {
             // changes!
-            if config.is_none() {
+            if config.is_none() || self.yes {
                 // no confirmation
-                save_config(&new_config).await?;
+                config::save_config(&new_config)?;
             } else {
                 // confirmation
                 save_config_with_confirmation(&new_config).await?;
@@ -0,0 +1,149 @@
+//! Implementation of the `dev shell` subcommand: running commands over a single connection
+//!
+//! Connecting takes a few seconds, which adds up when poking around the device with separate invocations.
+//! The shell reads the commands from stdin until `exit` or the end of input.
+
+use anyhow::{bail, Context, Result};
+use camino::Utf8PathBuf;
+use clap::{Parser, Subcommand};
+use std::io::Write;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tracing::warn;
+
+use crate::progress::SpanProgress;
+use f_xoss::device::XossDevice;
+
+#[derive(Parser, Debug)]
+#[command(
+    no_binary_name = true,
+    disable_version_flag = true,
+    override_usage = "<COMMAND> [ARGS]"
+)]
+struct ShellLine {
+    #[command(subcommand)]
+    command: ShellCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum ShellCommand {
+    /// List the files on the device
+    Ls,
+    /// Print a text file from the device, the JSON ones are pretty-printed
+    Cat { device_filename: String },
+    /// Download a file from the device
+    Pull {
+        device_filename: String,
+        output_filename: Option<Utf8PathBuf>,
+    },
+    /// Upload a file to the device, reading it back to verify it
+    Push {
+        input_filename: Utf8PathBuf,
+        device_filename: Option<String>,
+    },
+    /// Show the device information
+    Info,
+    /// Show the free memory
+    Cap,
+    /// Show the transfer and the activity status and the battery level
+    Status,
+    /// Leave the shell
+    #[command(alias = "quit")]
+    Exit,
+}
+
+async fn cat(device: &XossDevice, device_filename: &str) -> Result<()> {
+    let content = device
+        .read_file(device_filename, &SpanProgress::default())
+        .await
+        .with_context(|| format!("Reading {}", device_filename))?;
+
+    if let Ok(json) = serde_json::from_slice::<serde_json::Value>(&content) {
+        println!("{}", serde_json::to_string_pretty(&json)?);
+    } else if let Ok(text) = std::str::from_utf8(&content) {
+        println!("{}", text);
+    } else {
+        bail!(
+            "{} is a binary file ({} bytes), use pull to download it",
+            device_filename,
+            content.len()
+        );
+    }
+
+    Ok(())
+}
+
+/// Run a single command, returns `false` when the shell should exit
+async fn run_command(device: &XossDevice, command: ShellCommand) -> Result<bool> {
+    match command {
+        ShellCommand::Ls => super::device::ls(device).await?,
+        ShellCommand::Cat { device_filename } => cat(device, &device_filename).await?,
+        ShellCommand::Pull {
+            device_filename,
+            output_filename,
+        } => super::device::pull(device, &device_filename, output_filename.as_deref()).await?,
+        ShellCommand::Push {
+            input_filename,
+            device_filename,
+        } => super::device::push(device, input_filename, device_filename.as_deref(), true).await?,
+        ShellCommand::Info => super::device::info(device).await?,
+        ShellCommand::Cap => println!("Free: {}", device.get_memory_capacity().await?),
+        ShellCommand::Status => {
+            println!("Transfer: {:?}", device.get_transfer_status().await?);
+            if let Some(activity) = device.get_activity_status().await? {
+                println!("Activity: {}", activity);
+            }
+            println!(
+                "Battery: {}",
+                super::device::battery_level_text(device.battery_level().await)
+            );
+        }
+        ShellCommand::Exit => return Ok(false),
+    }
+    Ok(true)
+}
+
+pub async fn shell(device: &XossDevice) -> Result<()> {
+    let device_info = device.device_info();
+    println!(
+        "Connected to {} ({}), type help for the commands",
+        device_info.model_number, device_info.serial_number
+    );
+
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    loop {
+        print!("xoss> ");
+        std::io::stdout().flush()?;
+
+        let Some(line) = lines.next_line().await.context("Reading the command")? else {
+            // the end of input
+            println!();
+            break;
+        };
+        let words = match shell_words::split(&line) {
+            Ok(words) => words,
+            Err(e) => {
+                warn!("{}", e);
+                continue;
+            }
+        };
+        if words.is_empty() {
+            continue;
+        }
+
+        let command = match ShellLine::try_parse_from(words) {
+            Ok(line) => line.command,
+            Err(e) => {
+                // also the help, which clap reports as an error
+                let _ = e.print();
+                continue;
+            }
+        };
+        match run_command(device, command).await {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => warn!("{:#}", e),
+        }
+    }
+
+    Ok(())
+}
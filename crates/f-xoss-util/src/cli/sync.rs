@@ -0,0 +1,1064 @@
+//! Implementation of the `dev sync` subcommand
+//!
+//! Syncing is split into two phases: planning, which only reads from the device and decides what needs to be done,
+//! and execution, which actually changes things. This allows to show the plan without executing it (`--dry-run`).
+
+use anyhow::{bail, ensure, Context, Result};
+use chrono::{Duration, FixedOffset, TimeZone, Utc};
+use fs4::FileExt;
+use indicatif::ProgressStyle;
+use prettytable::{row, Table};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tracing::{debug, info, instrument, warn};
+use tracing_indicatif::span_ext::IndicatifSpanExt;
+
+use crate::cli::device::battery_level_text;
+use crate::cli::filter::WorkoutFilter;
+use crate::cli::SyncOptions;
+use crate::config::{ProfileConfig, TimeZoneSetting, XossUtilConfig};
+use crate::mga::MgaMode;
+use crate::mqtt::DeviceStatus;
+use crate::progress::SpanProgress;
+use crate::state::{CapacityRecord, DeviceState, SyncRecord, TransferDirection};
+use crate::workout_index::{
+    load_index, unique_file, workouts_dir, FilenameTemplate, FilenameValues, LocalCopies,
+    WorkoutRecord,
+};
+use f_xoss::device::{MgaState, XossDevice};
+use f_xoss::fit::FitFile;
+use f_xoss::mga::MgaData;
+use f_xoss::model::{User, UserProfile, UserProfileBuilder, WorkoutsItem};
+use f_xoss::time_zone::{format_offset, offset_changes, zone_at, OffsetChange, Zone};
+
+/// How many times a workout download is attempted before giving up on it until the next sync
+///
+/// The downloads are not resumed: the file request has no offset and the YMODEM transfer always starts from the first
+/// block, so each attempt starts from the beginning of the file. A flaky link usually recovers after a pause, so the
+/// attempts are spread out rather than made right away.
+const MAX_DOWNLOAD_ATTEMPTS: u32 = 5;
+/// The wait before the first retry, doubled after each one
+const INITIAL_DOWNLOAD_BACKOFF: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Download a file, retrying with a growing pause between the attempts
+///
+/// Nothing is kept from a failed attempt, see [MAX_DOWNLOAD_ATTEMPTS]
+async fn download_with_retries(device: &XossDevice, device_filename: &str) -> Result<Vec<u8>> {
+    let mut backoff = INITIAL_DOWNLOAD_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        match device
+            .read_file(device_filename, &SpanProgress::with_link_quality(device))
+            .await
+        {
+            Err(e) if attempt < MAX_DOWNLOAD_ATTEMPTS => {
+                warn!(
+                    "Failed to download {} ({:#}), retrying in {}s ({}/{})",
+                    device_filename,
+                    e,
+                    backoff.as_secs(),
+                    attempt,
+                    MAX_DOWNLOAD_ATTEMPTS
+                );
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Makes sure only one sync runs at a time, so that two processes don't download the same workouts into the same files
+///
+/// An advisory lock on `sync.lock` in the data directory, held while the file is open. The OS releases it when the
+/// process exits, even on a crash, so a stale lock file doesn't block the next syncs.
+struct SyncLock {
+    _file: File,
+}
+
+impl SyncLock {
+    fn acquire() -> Result<Self> {
+        let data_dir = crate::config::APP_DIRS.data_dir();
+        std::fs::create_dir_all(data_dir).context("Creating the data directory")?;
+
+        let path = data_dir.join("sync.lock");
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .context("Opening the sync lock file")?;
+        match file.try_lock_exclusive() {
+            Ok(()) => {}
+            Err(e) if e.raw_os_error() == fs4::lock_contended_error().raw_os_error() => {
+                // the lock may also keep the file from being read (on Windows)
+                match std::fs::read_to_string(&path) {
+                    Ok(pid) if !pid.trim().is_empty() => {
+                        bail!("Another sync is running (process {})", pid.trim())
+                    }
+                    _ => bail!("Another sync is running"),
+                }
+            }
+            Err(e) => return Err(e).context("Locking the sync lock file"),
+        }
+        // purely informational, tells which process holds the lock
+        let _ = file
+            .set_len(0)
+            .and_then(|()| writeln!(file, "{}", std::process::id()));
+
+        Ok(Self { _file: file })
+    }
+}
+
+struct ProfilePlan {
+    profile: UserProfile,
+    changes: Vec<String>,
+    /// The time zone of the device before the sync, the workouts are named after it
+    device_time_zone: i32,
+}
+
+struct WorkoutsPlan {
+    local_dir: PathBuf,
+    filename: FilenameTemplate,
+    serial_number: String,
+    /// The configured name of the device, or its serial number
+    device_name: String,
+    missing: Vec<WorkoutsItem>,
+    /// Finished workouts that were downloaded by the previous syncs, along with their local copies
+    synced: Vec<(WorkoutsItem, PathBuf)>,
+    /// Whether to delete the downloaded workouts from the device
+    delete_synced: bool,
+    /// Whether to save the workouts with the same content as the ones already synced
+    keep_duplicates: bool,
+    /// Number of missing workouts that won't be downloaded this time because of low battery
+    postponed: usize,
+    /// Number of missing workouts left out by the `--since`, `--until` and `--state` filters
+    filtered_out: usize,
+}
+
+enum MgaPlan {
+    UpToDate(MgaState),
+    Update {
+        device_state: MgaState,
+        data: MgaData,
+    },
+    SkippedLowBattery,
+}
+
+struct SyncPlan {
+    /// Battery level, if it's low enough to limit the sync
+    low_battery: Option<u32>,
+    profile: ProfilePlan,
+    workouts: WorkoutsPlan,
+    mga: MgaPlan,
+}
+
+/// How far to look for the DST changes that happened since the last sync or will happen before the next one
+const OFFSET_CHANGE_WINDOW_DAYS: i64 = 60;
+const UPCOMING_OFFSET_CHANGE_DAYS: i64 = 7;
+
+/// The moment of an offset change, in the new offset of the configured zone (not necessarily the one of this computer)
+fn format_change_time(change: &OffsetChange) -> String {
+    match FixedOffset::east_opt(change.to) {
+        Some(offset) => change
+            .time
+            .with_timezone(&offset)
+            .format("%Y-%m-%d %H:%M")
+            .to_string(),
+        None => change.time.format("%Y-%m-%d %H:%M UTC").to_string(),
+    }
+}
+
+/// Explain the time zone offset changes around now, so that the shifted workout times on the device are not a surprise
+fn report_offset_changes(device_time_zone: i32, time_zone: &TimeZoneSetting) {
+    let now = Utc::now();
+    let offset_at = |t| time_zone.offset_at(t);
+
+    let past = offset_changes(
+        offset_at,
+        now - Duration::days(OFFSET_CHANGE_WINDOW_DAYS),
+        now,
+    );
+    if let Some(change) = past.last() {
+        if change.from == device_time_zone {
+            info!(
+                "The time zone offset changed from {} to {} on {}, the device shows the workouts recorded since then {} off (the synced files are not affected)",
+                format_offset(change.from),
+                format_offset(change.to),
+                format_change_time(change),
+                format_offset(change.to - change.from),
+            );
+        }
+    }
+
+    let upcoming = offset_changes(
+        offset_at,
+        now,
+        now + Duration::days(UPCOMING_OFFSET_CHANGE_DAYS),
+    );
+    if let Some(change) = upcoming.first() {
+        info!(
+            "The time zone offset changes to {} on {}, sync again after that to keep the workout times right",
+            format_offset(change.to),
+            format_change_time(change),
+        );
+    }
+}
+
+/// The time zone where the last synced workout ended, for `time_zone_from_gps`
+///
+/// The workouts downloaded by the current sync are not there yet, so a ride in a new zone is accounted for by the next
+/// sync.
+fn last_workout_zone(config: Option<&XossUtilConfig>) -> Result<Option<Zone>> {
+    let dir = workouts_dir(config);
+    let index = load_index()?;
+    let mut records = index
+        .workouts
+        .iter()
+        .filter(|record| record.start_time.is_some())
+        .collect::<Vec<_>>();
+    records.sort_by_key(|record| std::cmp::Reverse(record.start_time));
+
+    for record in records {
+        let path = dir.join(&record.file);
+        let read = || -> Result<FitFile> { Ok(FitFile::parse(&std::fs::read(&path)?)?) };
+        let position = match read() {
+            Ok(fit) => fit.track().iter().rev().find_map(|point| point.position),
+            Err(e) => {
+                debug!(
+                    "Skipping {} when looking for the last workout location: {:#}",
+                    path.display(),
+                    e
+                );
+                continue;
+            }
+        };
+        // the indoor rides have no track
+        let Some(position) = position else {
+            continue;
+        };
+
+        return Ok(match zone_at(position) {
+            Some(zone) => {
+                info!(
+                    "The last workout ({}) ended in the {} time zone",
+                    record.file,
+                    zone.name()
+                );
+                Some(zone)
+            }
+            None => {
+                warn!(
+                    "The time zone of the last workout ({}, ended at {:.3}, {:.3}) is not known, keeping the configured one",
+                    record.file, position.lat, position.lon
+                );
+                None
+            }
+        });
+    }
+
+    info!("None of the synced workouts has a GPS track, keeping the configured time zone");
+    Ok(None)
+}
+
+async fn plan_profile(
+    device: &XossDevice,
+    time_zone: &TimeZoneSetting,
+    pinned: &ProfileConfig,
+) -> Result<ProfilePlan> {
+    let user_profile = device.read_user_profile().await?;
+
+    let device_time_zone = user_profile.user_profile.time_zone;
+    report_offset_changes(device_time_zone, time_zone);
+    let time_zone = time_zone.offset_at(Utc::now());
+
+    let mut changes = Vec::new();
+    let user = match user_profile.user.clone() {
+        Some(mut user) => {
+            if let Some(name) = pinned
+                .user_name
+                .as_ref()
+                .filter(|&name| *name != user.user_name)
+            {
+                changes.push(format!("user name {:?} -> {:?}", user.user_name, name));
+                user.user_name = name.clone();
+            }
+            if let Some(uid) = pinned.uid.filter(|&uid| uid != user.uid) {
+                changes.push(format!("uid {} -> {}", user.uid, uid));
+                user.uid = uid;
+            }
+            user
+        }
+        None => {
+            let user = User {
+                platform: "XOSS".to_string(),
+                uid: pinned.uid.unwrap_or(42),
+                user_name: pinned
+                    .user_name
+                    .clone()
+                    .unwrap_or_else(|| "ABOBA".to_string()),
+                unknown: Default::default(),
+            };
+            changes.push(if pinned.user_name.is_some() || pinned.uid.is_some() {
+                format!("create the user {:?} (uid {})", user.user_name, user.uid)
+            } else {
+                "create a placeholder user".to_string()
+            });
+            user
+        }
+    };
+
+    let values = &user_profile.user_profile;
+    let mut builder = UserProfileBuilder::new(user_profile.clone())
+        .user(user)
+        .time_zone(time_zone);
+    if let Some(weight) = pinned.weight.filter(|&weight| weight != values.weight) {
+        changes.push(format!("weight {} -> {} kg", values.weight, weight));
+        builder = builder.weight(weight);
+    }
+    if let Some(ftp) = pinned.ftp.filter(|&ftp| ftp != values.ftp) {
+        changes.push(format!("FTP {} -> {} W", values.ftp, ftp));
+        builder = builder.ftp(ftp);
+    }
+    if device_time_zone != time_zone {
+        changes.push(format!(
+            "time zone {} -> {}",
+            format_offset(device_time_zone),
+            format_offset(time_zone)
+        ));
+    }
+
+    let profile = builder
+        .build()
+        .context("Building the user profile (check the [profile] values in the config)")?;
+
+    Ok(ProfilePlan {
+        profile,
+        changes,
+        device_time_zone,
+    })
+}
+
+async fn plan_workouts(
+    device: &XossDevice,
+    config: Option<&XossUtilConfig>,
+    delete_synced: bool,
+    filter: &WorkoutFilter,
+    time_zone: i32,
+) -> Result<WorkoutsPlan> {
+    let local_dir = crate::workout_index::workouts_dir(config);
+    let filename = config.map_or_else(
+        || Ok(FilenameTemplate::default()),
+        |c| c.workouts.filename(),
+    )?;
+    let serial_number = device.device_info().serial_number.clone();
+    let device_name = config
+        .and_then(|c| {
+            c.devices
+                .iter()
+                .find(|d| d.serial_number.as_deref() == Some(serial_number.as_str()))
+        })
+        .and_then(|d| d.name.clone())
+        .unwrap_or_else(|| serial_number.clone());
+
+    let local_copies = LocalCopies::load(local_dir.clone(), serial_number.clone())?;
+    let local_path = |workout: &WorkoutsItem| local_copies.find(workout);
+
+    let (workouts, filtered_out): (Vec<_>, Vec<_>) = device
+        .read_workouts()
+        .await?
+        .into_iter()
+        .partition(|workout| filter.matches(workout, time_zone));
+    let filtered_out = filtered_out
+        .iter()
+        .filter(|workout| workout.state.is_finished() && local_path(workout).is_none())
+        .count();
+
+    let (finished, recording): (Vec<_>, Vec<_>) = workouts
+        .into_iter()
+        .partition(|workout| workout.state.is_finished());
+    let mut synced = Vec::new();
+    let mut missing = Vec::new();
+    for workout in finished {
+        match local_path(&workout) {
+            Some(path) => synced.push((workout, path)),
+            None => missing.push(workout),
+        }
+    }
+    let recording = recording
+        .into_iter()
+        .filter(|workout| local_path(workout).is_none());
+
+    for workout in recording {
+        info!(
+            "Workout {} is still being recorded, it will be downloaded by the next sync",
+            workout.name
+        );
+    }
+
+    Ok(WorkoutsPlan {
+        local_dir,
+        filename,
+        serial_number,
+        device_name,
+        missing,
+        synced,
+        delete_synced,
+        keep_duplicates: false,
+        postponed: 0,
+        filtered_out,
+    })
+}
+
+async fn plan_mga(
+    device: &XossDevice,
+    config: Option<&XossUtilConfig>,
+    options: &SyncOptions,
+) -> Result<MgaPlan> {
+    let Some(config) = config else {
+        bail!("Config is required for sync subcommand");
+    };
+
+    let device_state = device
+        .get_mga_state()
+        .await
+        .context("Failed to get MGA status")?;
+    let data = crate::mga::get_mga_data(&config.mga, &options.mga_update).await?;
+
+    Ok(
+        // the online data is fresher than anything on the device, even if it's valid for less time
+        if options.mga_update.mga_mode == MgaMode::Online
+            || match device_state {
+                MgaState::MissingData => true,
+                MgaState::ValidUntil(date) => date < data.valid_until,
+            }
+        {
+            MgaPlan::Update { device_state, data }
+        } else {
+            MgaPlan::UpToDate(device_state)
+        },
+    )
+}
+
+#[instrument(skip_all)]
+async fn plan_sync(
+    device: &XossDevice,
+    config: Option<&XossUtilConfig>,
+    options: &SyncOptions,
+) -> Result<SyncPlan> {
+    let sync_config = config.map(|c| c.sync.clone()).unwrap_or_default();
+
+    let battery_level = device.battery_level().await;
+    let low_battery = battery_level.filter(|&level| level < sync_config.low_battery_threshold());
+    if let Some(level) = low_battery {
+        if options.force {
+            warn!(
+                "The device battery is low ({}%), but --force is set, doing a full sync anyway",
+                level
+            );
+        } else {
+            warn!(
+                "The device battery is low ({}%), skipping the A-GPS update and downloading at most {} workouts. Use --force to do a full sync",
+                level,
+                sync_config.low_battery_max_workouts()
+            );
+        }
+    }
+    let limit_sync = low_battery.is_some() && !options.force;
+
+    let mut time_zone = config.map_or(Ok(TimeZoneSetting::Local), |c| c.time_zone())?;
+    if sync_config.time_zone_from_gps() {
+        if let Some(zone) =
+            last_workout_zone(config).context("Finding the last workout location")?
+        {
+            time_zone = TimeZoneSetting::Located(zone);
+        }
+    }
+    let pinned = config.map(|c| c.profile.clone()).unwrap_or_default();
+    let profile = plan_profile(device, &time_zone, &pinned)
+        .await
+        .context("Planning the user profile update")?;
+
+    let delete_synced = options.delete_synced || sync_config.delete_synced();
+    let mut workouts = plan_workouts(
+        device,
+        config,
+        delete_synced,
+        &options.filter,
+        profile.device_time_zone,
+    )
+    .await
+    .context("Planning the workouts download")?;
+    if limit_sync && workouts.missing.len() > sync_config.low_battery_max_workouts() {
+        let postponed = workouts
+            .missing
+            .split_off(sync_config.low_battery_max_workouts());
+        workouts.postponed = postponed.len();
+    }
+    workouts.keep_duplicates = options.force;
+
+    let mga = if limit_sync {
+        MgaPlan::SkippedLowBattery
+    } else {
+        plan_mga(device, config, options)
+            .await
+            .context("Planning the MGA data update")?
+    };
+
+    Ok(SyncPlan {
+        low_battery,
+        profile,
+        workouts,
+        mga,
+    })
+}
+
+/// The missing workouts that are not downloaded this time, if any
+fn describe_skipped(workouts: &WorkoutsPlan) -> String {
+    let mut description = String::new();
+    if workouts.postponed > 0 {
+        description += &format!(", {} postponed because of low battery", workouts.postponed);
+    }
+    if workouts.filtered_out > 0 {
+        description += &format!(", {} filtered out", workouts.filtered_out);
+    }
+    description
+}
+
+impl SyncPlan {
+    fn describe(&self) -> Table {
+        let mut table = Table::new();
+        table.set_format(*prettytable::format::consts::FORMAT_CLEAN);
+
+        if let Some(level) = self.low_battery {
+            table.add_row(row!["Battery:", format!("low ({}%)", level)]);
+        }
+        table.add_row(row!["Time:", "set to the current time"]);
+        table.add_row(row![
+            "User profile:",
+            if self.profile.changes.is_empty() {
+                "up to date".to_string()
+            } else {
+                format!("update ({})", self.profile.changes.join(", "))
+            }
+        ]);
+        table.add_row(row![
+            "Workouts:",
+            if self.workouts.missing.is_empty() {
+                format!("nothing to download{}", describe_skipped(&self.workouts))
+            } else {
+                format!(
+                    "download {} ({}) to {}{}",
+                    self.workouts.missing.len(),
+                    humansize::format_size(
+                        self.workouts
+                            .missing
+                            .iter()
+                            .map(|w| w.size as u64)
+                            .sum::<u64>(),
+                        humansize::BINARY
+                    ),
+                    self.workouts.local_dir.display(),
+                    describe_skipped(&self.workouts)
+                )
+            }
+        ]);
+        if self.workouts.delete_synced {
+            table.add_row(row![
+                "Cleanup:",
+                format!(
+                    "delete {} downloaded workouts from the device",
+                    self.workouts.synced.len() + self.workouts.missing.len()
+                )
+            ]);
+        }
+        table.add_row(row![
+            "A-GPS data:",
+            match &self.mga {
+                MgaPlan::UpToDate(state) => format!("up to date ({})", state),
+                MgaPlan::Update { device_state, data } => format!(
+                    "upload ({} -> valid until {})",
+                    device_state, data.valid_until
+                ),
+                MgaPlan::SkippedLowBattery => "skip (low battery)".to_string(),
+            }
+        ]);
+
+        table
+    }
+}
+
+/// The outcome of each sync step
+///
+/// The steps are independent, so a failing one doesn't prevent the others from running
+#[derive(Default)]
+struct SyncSummary {
+    steps: Vec<(&'static str, Result<String>)>,
+    /// The workouts downloaded by this sync
+    workouts: Vec<WorkoutRecord>,
+    /// Number of the missing workouts that failed to download
+    not_downloaded: usize,
+    /// Number of the verified workouts that failed to be deleted from the device
+    not_deleted: usize,
+}
+
+impl SyncSummary {
+    fn record(&mut self, step: &'static str, result: Result<String>) {
+        if let Err(e) = &result {
+            warn!("{} failed: {:#}", step, e);
+        }
+        self.steps.push((step, result));
+    }
+
+    fn failed_count(&self) -> usize {
+        self.steps.iter().filter(|(_, r)| r.is_err()).count()
+    }
+
+    fn table(&self) -> Table {
+        let mut table = Table::new();
+        table.set_format(*prettytable::format::consts::FORMAT_CLEAN);
+        for (step, result) in &self.steps {
+            match result {
+                Ok(status) => table.add_row(row![format!("{}:", step), status]),
+                Err(e) => table.add_row(row![format!("{}:", step), Fr->format!("failed: {:#}", e)]),
+            };
+        }
+        table
+    }
+}
+
+/// Download the missing workouts, continuing with the next one if one fails
+#[instrument(skip(device, workouts, downloaded, not_downloaded))]
+async fn download_workouts(
+    device: &XossDevice,
+    workouts: &WorkoutsPlan,
+    downloaded: &mut Vec<WorkoutRecord>,
+    not_downloaded: &mut usize,
+) -> Result<()> {
+    *not_downloaded = workouts.missing.len();
+    tokio::fs::create_dir_all(&workouts.local_dir).await?;
+
+    info!("Syncing workouts to {}", workouts.local_dir.display());
+
+    let current_span = tracing::Span::current();
+    current_span.pb_set_style(&ProgressStyle::default_bar()
+        .template("{span_child_prefix}{spinner:.green} [{bar:40.cyan/blue}] {human_pos}/{human_len} ({eta} @ {per_sec})")
+        .unwrap()
+        .progress_chars("#>-"));
+    current_span.pb_set_length(workouts.missing.len() as u64);
+
+    let mut failed = Vec::new();
+    for workout in &workouts.missing {
+        let workout_filename = workout.filename();
+        let local_file = unique_file(
+            &workouts.local_dir,
+            &workouts.filename.render(&FilenameValues {
+                name: workout.name,
+                device: &workouts.device_name,
+                serial_number: &workouts.serial_number,
+            }),
+        );
+        let workout_path = workouts.local_dir.join(&local_file);
+
+        info!(
+            "Downloading workout {:?} to {:?}",
+            workout.name, workout_path
+        );
+        let result = async {
+            if let Some(parent) = workout_path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            let workout_data = download_with_retries(device, &workout_filename)
+                .await
+                .context("Failed to receive workout file")?;
+            let record = WorkoutRecord::new(
+                local_file.clone(),
+                &workout_data,
+                Some(workouts.serial_number.clone()),
+                Some(workout.name),
+            );
+
+            if !workouts.keep_duplicates {
+                let index = crate::workout_index::load_index()?;
+                if let Some(original) = index.find_duplicate(&workouts.local_dir, &record, &workout_data) {
+                    warn!(
+                        "Workout {} has the same content as {} (the device clock was probably reset), not saving it again. Use --force to save it anyway",
+                        workout.name, original.file
+                    );
+                    let original = original.file.clone();
+                    crate::workout_index::update_index(|index| {
+                        index.add_alias(&original, &workouts.serial_number, workout.name)
+                    })
+                    .context("Failed to record the duplicate in the workout index")?;
+                    return anyhow::Ok(());
+                }
+            }
+
+            tokio::fs::write(&workout_path, &workout_data)
+                .await
+                .context("Failed to write workout file")?;
+
+            // saved as it is anyway, so that it can be checked against the device before deleting it
+            if let Ok((_, Some(report))) = f_xoss::fit::repair(&workout_data) {
+                warn!(
+                    "Workout {} is damaged, `workout repair {}` can salvage it: {}",
+                    workout.name,
+                    local_file,
+                    crate::cli::workout::describe_repair(&report)
+                );
+            }
+
+            downloaded.push(record.clone());
+            // the file is already saved, the index can be rebuilt from the directory
+            if let Err(e) = crate::workout_index::update_index(|index| index.insert(record)) {
+                warn!(
+                    "Failed to add {} to the workout index: {:#}",
+                    workout_filename, e
+                );
+            }
+            anyhow::Ok(())
+        }
+        .await;
+        match result {
+            Ok(()) => *not_downloaded -= 1,
+            Err(e) => {
+                warn!("Failed to download {}: {:#}", workout_filename, e);
+                failed.push(workout_filename);
+            }
+        }
+
+        current_span.pb_inc(1);
+    }
+
+    if !failed.is_empty() {
+        bail!(
+            "{} of {} workouts could not be downloaded: {}",
+            failed.len(),
+            workouts.missing.len(),
+            failed.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+/// Check that the local copy of a workout is complete, so that the one on the device can be deleted
+///
+/// The copy has to match the checksum in the workout index or in the transfer history, a file that only has the same
+/// name and size might be another workout
+fn verify_local_workout(
+    path: &Path,
+    workout: &WorkoutsItem,
+    index_record: Option<&WorkoutRecord>,
+    device_state: Option<&DeviceState>,
+) -> Result<()> {
+    let data = std::fs::read(path).context("Failed to read the local copy")?;
+    ensure!(
+        data.len() == workout.size as usize,
+        "The local copy has {} bytes, but the device has {} bytes",
+        data.len(),
+        workout.size
+    );
+
+    // the transfer history has the checksum of what was actually received
+    let last_download = device_state
+        .and_then(|s| s.transfers.get(&workout.filename()))
+        .and_then(|records| {
+            records
+                .iter()
+                .rev()
+                .find(|r| r.direction == TransferDirection::Download)
+        });
+    let known_crcs = index_record
+        .map(|r| r.crc32)
+        .into_iter()
+        .chain(last_download.map(|r| r.crc32))
+        .collect::<Vec<_>>();
+    ensure!(
+        !known_crcs.is_empty(),
+        "There is no record of downloading it to check the local copy against"
+    );
+    ensure!(
+        known_crcs.contains(&crc32fast::hash(&data)),
+        "The local copy differs from the downloaded data (CRC32 mismatch)"
+    );
+
+    Ok(())
+}
+
+/// Delete the workouts that have verified local copies from the device, continuing with the next one if one fails
+#[instrument(skip_all)]
+async fn delete_synced_workouts(
+    device: &XossDevice,
+    workouts: &WorkoutsPlan,
+    not_deleted: &mut usize,
+) -> Result<String> {
+    let serial_number = device.device_info().serial_number.clone();
+    let state = crate::state::load_state()?;
+    let device_state = state.devices.get(&serial_number);
+
+    // the file names of the workouts just downloaded are in the index
+    let index = crate::workout_index::load_index()?;
+
+    let mut deleted = 0;
+    let mut kept = 0;
+    let mut failed = Vec::new();
+    let missing = workouts.missing.iter().map(|workout| {
+        // not in the index when the download failed
+        let path = index
+            .find_synced(&serial_number, workout.name)
+            .map(|record| workouts.local_dir.join(&record.file));
+        (workout, path)
+    });
+    let synced = workouts
+        .synced
+        .iter()
+        .map(|(workout, path)| (workout, Some(path.clone())));
+    for (workout, path) in synced.chain(missing) {
+        let filename = workout.filename();
+        let Some(path) = path.filter(|path| path.exists()) else {
+            // failed to download, it was already reported
+            kept += 1;
+            continue;
+        };
+
+        let index_record = index.find_synced(&serial_number, workout.name);
+        if let Err(e) = verify_local_workout(&path, workout, index_record, device_state) {
+            warn!("Keeping {} on the device: {:#}", filename, e);
+            kept += 1;
+            continue;
+        }
+
+        match device.delete_file(&filename).await {
+            Ok(()) => {
+                info!("Deleted {} from the device", filename);
+                deleted += 1;
+            }
+            Err(e) => {
+                warn!("Failed to delete {}: {:#}", filename, e);
+                failed.push(filename);
+            }
+        }
+    }
+
+    *not_deleted = failed.len();
+    if !failed.is_empty() {
+        bail!(
+            "{} of {} verified workouts could not be deleted: {}",
+            failed.len(),
+            deleted + failed.len(),
+            failed.join(", ")
+        );
+    }
+
+    Ok(if kept == 0 {
+        format!("deleted {}", deleted)
+    } else {
+        format!(
+            "deleted {}, kept {} that could not be verified",
+            deleted, kept
+        )
+    })
+}
+
+/// Set the time, telling how far off the clock was
+async fn set_time(device: &XossDevice) -> Result<String> {
+    let reading = crate::cli::clock::check_clock(device).await;
+    device
+        .set_time(SystemTime::now())
+        .await
+        .context("Failed to set the time")?;
+    Ok(format!("set ({})", reading.describe()))
+}
+
+async fn execute_plan(device: &XossDevice, plan: &SyncPlan) -> SyncSummary {
+    let mut summary = SyncSummary::default();
+
+    summary.record("Time", set_time(device).await);
+
+    summary.record(
+        "User profile",
+        if plan.profile.changes.is_empty() {
+            Ok("up to date".to_string())
+        } else {
+            device
+                .write_user_profile(&plan.profile.profile)
+                .await
+                .map(|()| format!("updated ({})", plan.profile.changes.join(", ")))
+        },
+    );
+
+    let workouts_result = download_workouts(
+        device,
+        &plan.workouts,
+        &mut summary.workouts,
+        &mut summary.not_downloaded,
+    )
+    .await;
+    summary.record(
+        "Workouts",
+        workouts_result.map(|()| {
+            format!(
+                "downloaded {}{}",
+                plan.workouts.missing.len(),
+                describe_skipped(&plan.workouts)
+            )
+        }),
+    );
+
+    if plan.workouts.delete_synced {
+        let cleanup_result =
+            delete_synced_workouts(device, &plan.workouts, &mut summary.not_deleted).await;
+        summary.record("Cleanup", cleanup_result);
+    }
+
+    let mga_result = match &plan.mga {
+        MgaPlan::UpToDate(state) => {
+            info!("MGA data is up to date");
+            Ok(format!("up to date ({})", state))
+        }
+        MgaPlan::Update { data, .. } => {
+            info!("Updating MGA data");
+            async {
+                device
+                    .ensure_free_space("offline.gnss", data.data.len() as u64)
+                    .await?;
+                device
+                    .write_file(
+                        "offline.gnss",
+                        &data.data,
+                        &SpanProgress::with_link_quality(device),
+                    )
+                    .await
+                    .context("Failed to send the MGA data")
+            }
+            .await
+            .map(|()| format!("uploaded (valid until {})", data.valid_until))
+        }
+        MgaPlan::SkippedLowBattery => {
+            info!("Skipping the MGA data update because of low battery");
+            Ok("skipped (low battery)".to_string())
+        }
+    };
+    summary.record("A-GPS data", mga_result);
+
+    summary
+}
+
+pub async fn sync(
+    device: &XossDevice,
+    config: Option<&XossUtilConfig>,
+    options: SyncOptions,
+) -> Result<()> {
+    let _lock = SyncLock::acquire()?;
+
+    let battery_before = device.battery_level().await;
+    let plan = plan_sync(device, config, &options).await?;
+
+    if options.dry_run {
+        info!("Dry run, the following would be done:\n{}", plan.describe());
+        return Ok(());
+    }
+
+    let mut summary = execute_plan(device, &plan).await;
+
+    let failed = summary.failed_count();
+    let steps = summary.steps.len();
+    // the level queried with a control message is not notified about, it's queried again
+    let battery_after = match device.refresh_battery_level().await {
+        Ok(level) => level,
+        Err(e) => {
+            warn!("Failed to get the battery level: {:#}", e);
+            device.battery_level().await
+        }
+    };
+    summary.steps.push((
+        "Battery",
+        Ok(format!(
+            "{} -> {}",
+            battery_level_text(battery_before),
+            battery_level_text(battery_after)
+        )),
+    ));
+
+    info!("Sync summary:\n{}", summary.table());
+    if summary.not_deleted > 0 {
+        warn!(
+            "{} synced workouts stay on the device, the next sync tries to delete them again",
+            summary.not_deleted
+        );
+    }
+    if !summary.workouts.is_empty() {
+        info!(
+            "Downloaded workouts:\n{}",
+            crate::cli::workout::summary_table(&summary.workouts)
+        );
+    }
+
+    let capacity = device
+        .get_memory_capacity()
+        .await
+        .map_err(|e| warn!("Failed to get the memory capacity: {:#}", e))
+        .ok();
+
+    // the battery is spent on the failed syncs too, so they are recorded as well
+    let device_info = device.device_info();
+    let serial_number = device_info.serial_number.clone();
+    let mut battery_usage = None;
+    let mut synced = (None, None);
+    crate::state::update_state(|state| {
+        let device_state = state.device_mut(&serial_number);
+        if let (Some(battery_before), Some(battery_after)) = (battery_before, battery_after) {
+            device_state.record_sync(SyncRecord {
+                time: Utc::now().timestamp(),
+                battery_before,
+                battery_after,
+                failed_steps: failed,
+            });
+            device_state.battery_level = Some(battery_after);
+        }
+        if let Some(capacity) = &capacity {
+            device_state.record_capacity(CapacityRecord::now(capacity));
+        }
+        if failed == 0 {
+            device_state.last_sync = Some(Utc::now().timestamp());
+        }
+        device_state.pending_workouts = Some(plan.workouts.postponed + summary.not_downloaded);
+        battery_usage = device_state.battery_usage();
+        synced = (device_state.last_sync, device_state.pending_workouts);
+    })
+    .context("Saving the sync state")?;
+
+    if let Some(config) = config {
+        let (last_sync, pending_workouts) = synced;
+        let status = DeviceStatus {
+            serial_number,
+            model: device_info.model_number.clone(),
+            firmware: device_info.firmware_revision.clone(),
+            battery_level: battery_after,
+            last_sync: last_sync
+                .and_then(|t| Utc.timestamp_opt(t, 0).single())
+                .map(|t| t.to_rfc3339()),
+            pending_workouts,
+            failed_steps: failed,
+        };
+        crate::mqtt::publish_sync(&config.mqtt, &status, &summary.workouts).await;
+    }
+
+    if let Some((usage, count)) = battery_usage {
+        info!(
+            "A sync takes ~{:.1}% of the battery on average (over the last {} syncs)",
+            usage, count
+        );
+    }
+
+    if failed != 0 {
+        bail!("{} of {} sync steps failed", failed, steps);
+    }
+
+    Ok(())
+}
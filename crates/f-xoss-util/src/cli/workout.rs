@@ -0,0 +1,381 @@
+//! Implementation of the `workout` subcommands, working with the local workout index
+
+use anyhow::{bail, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::{Local, TimeZone};
+use clap::ValueEnum;
+use f_xoss::dem::{self, Dem};
+use f_xoss::fit::{self, FitFile, RepairReport, TrackPoint};
+use prettytable::{row, Table};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+use crate::config::XossUtilConfig;
+use crate::export::ExportFormat;
+use crate::workout_index::{load_index, save_index, workouts_dir, WorkoutRecord};
+
+fn format_time(timestamp: i64) -> String {
+    Local.timestamp_opt(timestamp, 0).single().map_or_else(
+        || timestamp.to_string(),
+        |t| t.format("%Y-%m-%d %H:%M").to_string(),
+    )
+}
+
+pub fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    format!(
+        "{}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+pub fn format_distance(meters: f64) -> String {
+    format!("{:.2} km", meters / 1000.0)
+}
+
+fn format_speed(meters_per_second: f64) -> String {
+    format!("{:.1} km/h", meters_per_second * 3.6)
+}
+
+fn format_ascent(meters: f64) -> String {
+    format!("{:.0} m", meters)
+}
+
+/// A line for each workout with a totals row, to show what a sync has brought
+pub fn summary_table(records: &[WorkoutRecord]) -> Table {
+    let unknown = || "?".to_string();
+
+    let mut table = Table::new();
+    table.set_format(*prettytable::format::consts::FORMAT_CLEAN);
+    table.set_titles(row![
+        "Start",
+        "Duration",
+        "Distance",
+        "Avg speed",
+        "Elevation"
+    ]);
+    for record in records {
+        table.add_row(row![
+            record.start_time.map_or_else(unknown, format_time),
+            record.duration.map_or_else(unknown, format_duration),
+            record.distance.map_or_else(unknown, format_distance),
+            record.avg_speed.map_or_else(unknown, format_speed),
+            record.ascent.map_or_else(unknown, format_ascent),
+        ]);
+    }
+
+    if records.len() > 1 {
+        let sum = |field: fn(&WorkoutRecord) -> Option<f64>| records.iter().filter_map(field).sum();
+        let distance: f64 = sum(|r| r.distance);
+        // weighted by the moving time of each workout
+        let moving_time: f64 = sum(|r| match (r.distance, r.avg_speed) {
+            (Some(distance), Some(speed)) if speed > 0.0 => Some(distance / speed),
+            _ => None,
+        });
+        let moving_distance: f64 = sum(|r| r.avg_speed.filter(|&s| s > 0.0).and(r.distance));
+        table.add_row(row![
+            b->format!("Total ({})", records.len()),
+            b->format_duration(sum(|r| r.duration)),
+            b->format_distance(distance),
+            b->if moving_time > 0.0 {
+                format_speed(moving_distance / moving_time)
+            } else {
+                unknown()
+            },
+            b->format_ascent(sum(|r| r.ascent)),
+        ]);
+    }
+
+    table
+}
+
+pub fn list(config: Option<&XossUtilConfig>) -> Result<()> {
+    let dir = workouts_dir(config);
+    let mut index = load_index()?;
+    if index.refresh(&dir)? {
+        save_index(&index)?;
+    }
+
+    let device_name = |serial_number: &str| {
+        config
+            .and_then(|c| {
+                c.devices
+                    .iter()
+                    .find(|d| d.serial_number.as_deref() == Some(serial_number))
+            })
+            .map_or_else(|| serial_number.to_string(), |d| d.identify())
+    };
+
+    let mut workouts = index.workouts.iter().collect::<Vec<_>>();
+    // most recent first, the ones without a start time at the end
+    workouts.sort_by_key(|r| std::cmp::Reverse(r.start_time.unwrap_or(i64::MIN)));
+
+    let mut table = Table::new();
+    table.set_format(*prettytable::format::consts::FORMAT_CLEAN);
+    table.set_titles(row![
+        "Start", "Duration", "Distance", "Device", "File", "Synced", "Title"
+    ]);
+    for record in workouts {
+        let file = if dir.join(&record.file).exists() {
+            record.file.clone()
+        } else {
+            format!("{} (missing)", record.file)
+        };
+        table.add_row(row![
+            record
+                .start_time
+                .map_or_else(|| "?".to_string(), format_time),
+            record
+                .duration
+                .map_or_else(|| "?".to_string(), format_duration),
+            record
+                .distance
+                .map_or_else(|| "?".to_string(), format_distance),
+            record
+                .device
+                .as_deref()
+                .map_or_else(|| "-".to_string(), device_name),
+            file,
+            format_time(record.synced_at),
+            record.annotation.title.as_deref().unwrap_or(""),
+        ]);
+    }
+
+    if table.is_empty() {
+        info!("No workouts were synced yet");
+    } else {
+        info!("Workouts in {}:\n{}", dir.display(), table);
+    }
+
+    Ok(())
+}
+
+/// When to replace the recorded altitude with the terrain elevation
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElevationCorrection {
+    /// Only when the recorded altitude is obviously broken
+    Auto,
+    /// Always, even when the recorded altitude looks fine
+    Always,
+}
+
+pub fn default_dem_dir() -> PathBuf {
+    crate::config::APP_DIRS.data_dir().join("dem")
+}
+
+/// Find the workout file: a path, or a file name in the workouts directory
+fn resolve_workout(file: &Utf8Path, workouts_dir: &Path) -> PathBuf {
+    if file.exists() {
+        return file.into();
+    }
+    let in_workouts = workouts_dir.join(file);
+    if in_workouts.exists() {
+        in_workouts
+    } else {
+        file.into()
+    }
+}
+
+fn correct_track(
+    file: &Utf8Path,
+    track: &mut [TrackPoint],
+    correction: ElevationCorrection,
+    dem: &mut Dem,
+) -> Result<()> {
+    if correction == ElevationCorrection::Auto {
+        match dem::elevation_problem_with_dem(track, dem)? {
+            None => {
+                info!("{}: the recorded elevation looks fine, keeping it", file);
+                return Ok(());
+            }
+            Some(problem) => info!("{}: the recorded elevation is broken ({:?})", file, problem),
+        }
+    }
+
+    let positions = track.iter().filter_map(|p| p.position).collect::<Vec<_>>();
+    let missing = dem.missing_tiles(&positions)?;
+    if !missing.is_empty() {
+        warn!(
+            "{}: no elevation data for a part of the track, put {} into {}",
+            file,
+            missing.join(", "),
+            dem.dir().display()
+        );
+    }
+
+    let corrected = dem::correct_elevation(track, dem)?;
+    info!(
+        "{}: corrected the elevation of {} of {} points",
+        file,
+        corrected,
+        track.len()
+    );
+    Ok(())
+}
+
+/// What was recovered from a damaged workout, like "kept 1.2 MiB of 1.3 MiB, 3600 track points up to 14:05"
+pub fn describe_repair(report: &RepairReport) -> String {
+    let mut description = format!(
+        "kept {} of {}, {} track points",
+        humansize::format_size(report.kept_len as u64, humansize::BINARY),
+        humansize::format_size(report.original_len as u64, humansize::BINARY),
+        report.track_points
+    );
+    if let Some(time) = report.last_point_time {
+        description += &format!(" up to {}", format_time(time.timestamp()));
+    }
+    description
+}
+
+/// Parse the workout read from `path`, salvaging what's possible from a damaged one
+fn read_fit(path: &Path, data: &[u8]) -> Result<FitFile> {
+    let error = match FitFile::parse(data) {
+        Ok(fit) => return Ok(fit),
+        Err(e) => e,
+    };
+
+    let (repaired, report) =
+        fit::repair(data).with_context(|| format!("Parsing {}", path.display()))?;
+    let report = report.expect("the file could not be parsed, so it's not intact");
+    warn!(
+        "{} is damaged ({}), using what's left of it: {}",
+        path.display(),
+        error,
+        describe_repair(&report)
+    );
+    FitFile::parse(&repaired).with_context(|| format!("Parsing the repaired {}", path.display()))
+}
+
+/// Write the salvaged copies of damaged workouts, named like `<name>-repaired.fit`
+pub fn repair(
+    config: Option<&XossUtilConfig>,
+    files: &[Utf8PathBuf],
+    output_dir: Option<&Utf8Path>,
+) -> Result<()> {
+    let workouts_dir = workouts_dir(config);
+
+    for file in files {
+        let path = resolve_workout(file, &workouts_dir);
+        let data = std::fs::read(&path).with_context(|| format!("Reading {}", path.display()))?;
+        let (repaired, report) =
+            fit::repair(&data).with_context(|| format!("Repairing {}", file))?;
+        let Some(report) = report else {
+            info!("{} is intact, nothing to repair", file);
+            continue;
+        };
+
+        let name = file.file_stem().unwrap_or(file.as_str());
+        let output = output_dir
+            .unwrap_or(Utf8Path::new("."))
+            .join(format!("{}-repaired.fit", name));
+        std::fs::write(&output, repaired).with_context(|| format!("Writing {}", output))?;
+
+        let mut fixed = Vec::new();
+        if report.header_fixed {
+            fixed.push("the header");
+        }
+        if report.crc_fixed {
+            fixed.push("the CRC");
+        }
+        info!(
+            "Repaired {} into {}: {}, dropped {} bytes of incomplete records{}",
+            file,
+            output,
+            describe_repair(&report),
+            report.dropped_len(),
+            if fixed.is_empty() {
+                String::new()
+            } else {
+                format!(", fixed {}", fixed.join(" and "))
+            }
+        );
+    }
+
+    Ok(())
+}
+
+pub fn export(
+    config: Option<&XossUtilConfig>,
+    files: &[Utf8PathBuf],
+    format: ExportFormat,
+    output_dir: Option<&Utf8Path>,
+    correction: Option<ElevationCorrection>,
+    dem_dir: Option<&Utf8Path>,
+) -> Result<()> {
+    if correction.is_some() && !format.is_from_track() {
+        bail!("The elevation can only be corrected in the GPX and TCX exports");
+    }
+
+    let workouts_dir = workouts_dir(config);
+    let mut dem = Dem::new(dem_dir.map_or_else(default_dem_dir, |d| d.into()));
+    let index = load_index()?;
+
+    for file in files {
+        let path = resolve_workout(file, &workouts_dir);
+        let data = std::fs::read(&path).with_context(|| format!("Reading {}", path.display()))?;
+        let mut track = read_fit(&path, &data)?.track();
+        // found by the content, so that the copies outside of the workouts directory have it too
+        let annotation = index
+            .find_by_content(&data)
+            .map(|r| r.annotation.clone())
+            .unwrap_or_default();
+
+        if let Some(correction) = correction {
+            correct_track(file, &mut track, correction, &mut dem)
+                .with_context(|| format!("Correcting the elevation of {}", file))?;
+        }
+
+        let name = file.file_stem().unwrap_or(file.as_str());
+        let output = output_dir.unwrap_or(Utf8Path::new(".")).join(format!(
+            "{}.{}",
+            name,
+            format.extension()
+        ));
+        let exported = format
+            .write(name, &annotation, &data, &track)
+            .with_context(|| format!("Exporting {}", file))?;
+        std::fs::write(&output, exported).with_context(|| format!("Writing {}", output))?;
+        info!("Exported {} to {}", file, output);
+    }
+
+    Ok(())
+}
+
+/// Set the title and the notes of a workout, an empty value removes them
+pub fn annotate(
+    config: Option<&XossUtilConfig>,
+    workout: &str,
+    title: Option<String>,
+    notes: Option<String>,
+) -> Result<()> {
+    let mut index = load_index()?;
+    // the files put into the directory by hand can be annotated too
+    index.refresh(&workouts_dir(config))?;
+
+    let record = index.find_mut(workout)?;
+    let non_empty = |value: String| (!value.is_empty()).then_some(value);
+    if let Some(title) = title {
+        record.annotation.title = non_empty(title);
+    }
+    if let Some(notes) = notes {
+        record.annotation.notes = non_empty(notes);
+    }
+    info!(
+        "{}: title {}, notes {}",
+        record.file,
+        record
+            .annotation
+            .title
+            .as_deref()
+            .map_or_else(|| "(none)".to_string(), |t| format!("{:?}", t)),
+        record
+            .annotation
+            .notes
+            .as_deref()
+            .map_or_else(|| "(none)".to_string(), |n| format!("{:?}", n)),
+    );
+
+    save_index(&index)
+}
@@ -1,35 +1,23 @@
-use anyhow::{Context, Result};
+use crate::secrets::Secret;
+use crate::workout_index::FilenameTemplate;
+use anyhow::{anyhow, bail, Context, Result};
 use btleplug::api::BDAddr;
 use btleplug::platform::PeripheralId;
+use chrono::{DateTime, Local, Offset, TimeZone, Utc};
 use directories::ProjectDirs;
+use f_xoss::mga::{CorruptFrames, Gnss};
+use f_xoss::scan::DiscoveredDevice;
+use f_xoss::time_zone::Zone;
+use f_xoss::transport::{BatterySource, TransportOptions, UartWriteType};
 use once_cell::sync::Lazy;
-use serde::Deserialize;
-use serde::{de, Serialize};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
 use std::io::ErrorKind;
 use std::path::PathBuf;
-
-fn deserialize_bdaddr<'de, D>(deserializer: D) -> Result<BDAddr, D::Error>
-where
-    D: serde::Deserializer<'de>,
-{
-    use std::str::FromStr;
-    let s = String::deserialize(deserializer)?;
-    let addr = BDAddr::from_str(&s).map_err(|e| {
-        de::Error::custom(format!(
-            "Failed to parse BDAddr from string: {:?}: {}",
-            s, e
-        ))
-    })?;
-
-    Ok(addr)
-}
-
-fn serialize_bdaddr<S>(addr: &BDAddr, serializer: S) -> Result<S::Ok, S::Error>
-where
-    S: serde::Serializer,
-{
-    serializer.serialize_str(&addr.to_string())
-}
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tracing::{info, warn};
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct XossDeviceInfo {
@@ -41,6 +29,81 @@ pub struct XossDeviceInfo {
     //
     // This makes config platform-specific... Kinda sad, but it's not like user would want to move it or something
     pub peripheral_id: PeripheralId,
+    /// Last known BLE address of the device
+    ///
+    /// Used to find the device again if the platform decides to assign it a new [PeripheralId]
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "optional_address"
+    )]
+    pub address: Option<BDAddr>,
+    /// Serial number reported by the device
+    ///
+    /// Unlike the name and the address, it doesn't change, so it's used to tell whether we are talking to the right device
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial_number: Option<String>,
+    /// Pair with the device before connecting to it, set up by `setup --pair`
+    ///
+    /// For the devices that refuse to talk to a computer they are not bonded with
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pair: bool,
+    /// The format of the device JSON files (like "2.0.0"), instead of the version their headers have
+    ///
+    /// For the firmware versions that write an unknown version, but are known to work like a supported one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub json_protocol: Option<String>,
+    /// Where to read the battery level from when the device has no standard Battery Service, like some clones
+    ///
+    /// `{ characteristic = "<uuid>" }` for a vendor characteristic, `{ ctl = <message type> }` for a control message
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_battery_source"
+    )]
+    pub battery_fallback: Option<BatterySource>,
+}
+
+/// Written as the tables the doc of [XossDeviceInfo::battery_fallback] shows, the TOML serializer doesn't support
+/// the enum variants with a value
+fn serialize_battery_source<S: serde::Serializer>(
+    source: &Option<BatterySource>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match source {
+        Some(BatterySource::Characteristic(uuid)) => {
+            BTreeMap::from([("characteristic", uuid.to_string())]).serialize(serializer)
+        }
+        Some(BatterySource::Ctl(message_type)) => {
+            BTreeMap::from([("ctl", message_type)]).serialize(serializer)
+        }
+        source => source.serialize(serializer),
+    }
+}
+
+/// The addresses as strings, the deserializer of [BDAddr] only takes the borrowed ones and TOML doesn't give those
+mod optional_address {
+    use btleplug::api::BDAddr;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::str::FromStr;
+
+    pub fn serialize<S: Serializer>(
+        address: &Option<BDAddr>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        address
+            .map(|address| format!("{:X}", address))
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<BDAddr>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|address| BDAddr::from_str(&address).map_err(D::Error::custom))
+            .transpose()
+    }
 }
 
 impl XossDeviceInfo {
@@ -50,44 +113,620 @@ impl XossDeviceInfo {
             .map(|s| s.to_string())
             .unwrap_or_else(|| self.peripheral_id.to_string())
     }
+
+    /// Whether the device is referred to by `selector`, which is a name, a BLE address, a serial number or a peripheral id
+    pub fn matches(&self, selector: &str) -> bool {
+        self.name
+            .as_ref()
+            .is_some_and(|name| name.eq_ignore_ascii_case(selector))
+            || self
+                .address
+                .is_some_and(|address| BDAddr::from_str(selector) == Ok(address))
+            || self.serial_number.as_deref() == Some(selector)
+            || self.peripheral_id.to_string() == selector
+    }
+
+    /// The transport options to connect to this device with
+    pub fn transport_options(&self, timeouts: &TimeoutsConfig) -> TransportOptions {
+        TransportOptions {
+            battery_fallback: self.battery_fallback,
+            ..timeouts.transport_options()
+        }
+    }
+
+    /// Whether a device seen in a scan is this one
+    ///
+    /// Only the address and the peripheral id are compared, the names are not unique
+    pub fn is_discovered_as(&self, device: &DiscoveredDevice) -> bool {
+        self.address == Some(device.address) || self.peripheral_id == device.id
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
 pub struct MgaConfig {
     pub base_url: Option<String>,
+    /// The AssistNow Online server, used with `--mga-mode online`
+    pub online_base_url: Option<String>,
     pub period_weeks: Option<u32>,
     pub resolution_days: Option<u32>,
-    pub ublox_token: Option<String>,
+    /// The u-blox AssistNow token, either as it is or as `{ keyring = "<entry>" }` when it's in the system keyring
+    ///
+    /// The `UBLOX_TOKEN` environment variable overrides it
+    pub ublox_token: Option<Secret>,
+    /// The constellations to get the data for: `gps`, `glo`, `gal` and `bds`
+    ///
+    /// Only GPS and GLONASS by default, the older devices don't use the others
+    pub gnss_constellations: Option<Vec<String>>,
+    /// Fail on the frames with a wrong checksum instead of dropping them
+    pub fail_on_corrupt_frames: Option<bool>,
+}
+
+impl MgaConfig {
+    pub fn ublox_token(&self) -> Result<Option<String>> {
+        if let Some(token) = std::env::var(crate::secrets::UBLOX_TOKEN_ENV)
+            .ok()
+            .filter(|t| !t.is_empty())
+        {
+            return Ok(Some(token));
+        }
+        self.ublox_token.as_ref().map(Secret::get).transpose()
+    }
+
+    pub fn corrupt_frames(&self) -> CorruptFrames {
+        if self.fail_on_corrupt_frames.unwrap_or(false) {
+            CorruptFrames::Fail
+        } else {
+            CorruptFrames::Skip
+        }
+    }
+
+    pub fn gnss_constellations(&self) -> Result<BTreeSet<Gnss>> {
+        let Some(names) = &self.gnss_constellations else {
+            return Ok(Gnss::DEFAULT.iter().copied().collect());
+        };
+        let constellations = names
+            .iter()
+            .map(|name| {
+                Gnss::parse(name).ok_or_else(|| {
+                    anyhow!(
+                        "Unknown constellation `{}`, expected one of gps, glo, gal, bds",
+                        name
+                    )
+                })
+            })
+            .collect::<Result<BTreeSet<_>>>()
+            .context("Invalid mga.gnss_constellations in the config")?;
+        if constellations.is_empty() {
+            bail!("The mga.gnss_constellations in the config is empty");
+        }
+        Ok(constellations)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SyncConfig {
+    /// Battery level (in percent) below which the heavy sync operations are limited
+    pub low_battery_threshold: Option<u32>,
+    /// How many workouts to download when the battery is low
+    pub low_battery_max_workouts: Option<usize>,
+    /// Delete the workouts from the device once they are safely downloaded (same as `--delete-synced`)
+    pub delete_synced: Option<bool>,
+    /// Set the time zone of the device from where the last synced workout ended, rather than from `time_zone`
+    ///
+    /// Keeps the device showing the local time of the place when traveling. The configured time zone is kept when
+    /// none of the synced workouts has a GPS track
+    pub time_zone_from_gps: Option<bool>,
+}
+
+impl SyncConfig {
+    pub fn low_battery_threshold(&self) -> u32 {
+        self.low_battery_threshold.unwrap_or(15)
+    }
+
+    pub fn low_battery_max_workouts(&self) -> usize {
+        self.low_battery_max_workouts.unwrap_or(3)
+    }
+
+    pub fn delete_synced(&self) -> bool {
+        self.delete_synced.unwrap_or(false)
+    }
+
+    pub fn time_zone_from_gps(&self) -> bool {
+        self.time_zone_from_gps.unwrap_or(false)
+    }
+}
+
+/// The user profile values `dev sync` keeps on the devices, setting them back when they are changed on a device
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ProfileConfig {
+    /// The user name shown on the device
+    pub user_name: Option<String>,
+    /// The id of the account the device is bound to
+    pub uid: Option<u32>,
+    /// Rider weight, in kilograms
+    pub weight: Option<i64>,
+    /// Functional threshold power, in watts
+    pub ftp: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct WorkoutsConfig {
+    /// Where the synced workouts are stored, `<data dir>/workouts` by default
+    pub dir: Option<PathBuf>,
+    /// How the synced workout files are named, `{name}.fit` (the name on the device) by default
+    ///
+    /// Can use `{name}`, `{date}` and `{time}` (of the workout start), `{device}` (the configured device name)
+    /// and `{serial}`. Slashes make subdirectories, like `{device}/{date}_{name}.fit`.
+    /// A number is added to the name if the file already exists.
+    pub filename: Option<String>,
+}
+
+impl WorkoutsConfig {
+    pub fn filename(&self) -> Result<FilenameTemplate> {
+        self.filename
+            .as_deref()
+            .map_or_else(|| Ok(FilenameTemplate::default()), FilenameTemplate::parse)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct FirmwareConfig {
+    /// URL or path of the manifest listing the latest firmware versions
+    pub manifest: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct AgentConfig {
+    /// Start an agent keeping the connection to the device open between the commands (same as `--keep-connection`)
+    pub enabled: Option<bool>,
+    /// How long the agent waits for the next command before disconnecting, in seconds
+    pub idle_timeout: Option<u64>,
+    /// How often the agent checks that an unused device is idle, in seconds, 0 to disable
+    pub keepalive_interval: Option<f64>,
+}
+
+impl AgentConfig {
+    pub fn enabled(&self) -> bool {
+        self.enabled.unwrap_or(false)
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout.unwrap_or(300))
+    }
+
+    pub fn keepalive_interval(&self) -> Option<Duration> {
+        let interval = seconds_or(self.keepalive_interval, Duration::from_secs(30));
+        (!interval.is_zero()).then_some(interval)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct DaemonConfig {
+    /// How often the daemon syncs a device that stays in range, in seconds
+    pub sync_interval: Option<f64>,
+    /// How often the daemon looks for the devices that are due for a sync, in seconds
+    pub scan_interval: Option<f64>,
+}
+
+impl DaemonConfig {
+    pub fn sync_interval(&self) -> Duration {
+        seconds_or(self.sync_interval, Duration::from_secs(6 * 60 * 60))
+    }
+
+    pub fn scan_interval(&self) -> Duration {
+        seconds_or(self.scan_interval, Duration::from_secs(60))
+    }
+}
+
+/// Negative and non-finite values are ignored
+fn seconds_or(value: Option<f64>, default: Duration) -> Duration {
+    value
+        .and_then(|v| Duration::try_from_secs_f64(v).ok())
+        .unwrap_or(default)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct TimeoutsConfig {
+    /// How long to scan for a device before giving up, in seconds
+    pub scan: Option<f64>,
+    /// How long to wait for a reply to a control message, in seconds
+    pub ctl_response: Option<f64>,
+    /// How long to wait for the device to process a transferred file, in seconds
+    pub file_response: Option<f64>,
+    /// How long to wait for each packet of a file transfer, in seconds
+    pub uart: Option<f64>,
+    /// The size of the file transfer packets, in bytes; derived from the MTU by default
+    ///
+    /// Lower it if the adapter can't handle the default, the transfers failing right away
+    pub uart_packet_size: Option<usize>,
+    /// How the file transfer packets are written: `auto`, `with-response` or `without-response`
+    pub uart_write_type: Option<UartWriteType>,
+}
+
+impl TimeoutsConfig {
+    pub fn scan(&self) -> Duration {
+        seconds_or(self.scan, Duration::from_secs(10))
+    }
+
+    pub fn transport_options(&self) -> TransportOptions {
+        let defaults = TransportOptions::default();
+        TransportOptions {
+            ctl_response_timeout: seconds_or(self.ctl_response, defaults.ctl_response_timeout),
+            file_response_timeout: seconds_or(self.file_response, defaults.file_response_timeout),
+            uart_timeout: seconds_or(self.uart, defaults.uart_timeout),
+            uart_packet_size: self.uart_packet_size.or(defaults.uart_packet_size),
+            uart_write_type: self.uart_write_type.unwrap_or(defaults.uart_write_type),
+            battery_fallback: defaults.battery_fallback,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct HttpConfig {
+    /// Sent instead of the default `f-xoss-util/<version>` user agent
+    pub user_agent: Option<String>,
+    /// How long a single request may take, in seconds
+    pub timeout: Option<f64>,
+    /// How many times to repeat a request that timed out or got a server error, 3 by default
+    pub retries: Option<u32>,
+    /// A PEM file with additional CA certificates to trust, like the one of a TLS-intercepting proxy
+    pub ca_certificates: Option<PathBuf>,
+    /// URL prefixes to replace, to use mirrors or local test servers
+    ///
+    /// For example, `"https://offline-live1.services.u-blox.com/" = "http://localhost:8080/mga/"`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub endpoints: BTreeMap<String, String>,
+}
+
+impl HttpConfig {
+    pub fn timeout(&self) -> Duration {
+        seconds_or(self.timeout, Duration::from_secs(60))
+    }
+
+    pub fn retries(&self) -> u32 {
+        self.retries.unwrap_or(3)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct LogConfig {
+    /// Write the log of every run to the rotating files, as JSON lines
+    ///
+    /// Same as passing `--log-file` to every command, meant for the daemon
+    #[serde(default)]
+    pub enabled: bool,
+    /// Where the log files go, `logs` in the cache directory by default
+    pub dir: Option<PathBuf>,
+    /// How many daily files to keep, 7 by default
+    pub max_files: Option<usize>,
+    /// What gets written to the files, in the `RUST_LOG` syntax; regardless of `RUST_LOG`
+    pub filter: Option<String>,
+}
+
+impl LogConfig {
+    pub fn dir(&self) -> PathBuf {
+        self.dir
+            .clone()
+            .unwrap_or_else(|| APP_DIRS.cache_dir().join("logs"))
+    }
+
+    pub fn max_files(&self) -> usize {
+        self.max_files.unwrap_or(7).max(1)
+    }
+
+    pub fn filter(&self) -> &str {
+        self.filter.as_deref().unwrap_or("info,f_xoss=debug")
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct MqttConfig {
+    /// The MQTT broker to publish the sync results to, as `host` or `host:port`
+    ///
+    /// Nothing is published if not set
+    pub broker: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// The prefix of the topics, `f-xoss` by default
+    pub topic_prefix: Option<String>,
+    /// Also publish the Home Assistant discovery configs, so that the sensors of the devices show up there by themselves
+    pub home_assistant: Option<bool>,
+}
+
+impl MqttConfig {
+    /// The broker host and port, if publishing is enabled
+    pub fn broker(&self) -> Option<Result<(String, u16)>> {
+        let broker = self.broker.as_deref()?;
+        Some(match broker.rsplit_once(':') {
+            Some((host, port)) => port
+                .parse()
+                .map(|port| (host.to_string(), port))
+                .with_context(|| format!("Invalid port in mqtt.broker {:?}", broker)),
+            None => Ok((broker.to_string(), 1883)),
+        })
+    }
+
+    pub fn topic_prefix(&self) -> &str {
+        self.topic_prefix.as_deref().unwrap_or("f-xoss")
+    }
+
+    pub fn home_assistant(&self) -> bool {
+        self.home_assistant.unwrap_or(false)
+    }
+}
+
+/// The version of the config format, the configs written by the older versions are migrated when loaded
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(transparent)]
+pub struct ConfigVersion(pub u32);
+
+impl Default for ConfigVersion {
+    fn default() -> Self {
+        Self(CONFIG_VERSION)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
 pub struct XossUtilConfig {
+    /// Set by f-xoss-util, don't change it by hand
+    #[serde(default)]
+    pub version: ConfigVersion,
+    /// The device to use when there are several configured and none is selected on the command line
+    ///
+    /// Can be a name, a BLE address or a serial number
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_device: Option<String>,
+    /// The Bluetooth adapter to use, matched against the adapter info string (like "hci1")
+    ///
+    /// The first adapter is used if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub adapter: Option<String>,
+    /// The time zone to set on the devices: an IANA name like "Europe/Berlin" or a fixed offset like "+05:30"
+    ///
+    /// The time zone of this computer is used if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time_zone: Option<String>,
+    /// Keep the secrets (the u-blox token) in the system keyring instead of this file, `true` by default
+    ///
+    /// The secrets written here as they are get moved to the keyring when there is one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub use_keyring: Option<bool>,
     pub devices: Vec<XossDeviceInfo>,
     #[serde(default)]
     pub mga: MgaConfig,
+    #[serde(default)]
+    pub sync: SyncConfig,
+    #[serde(default)]
+    pub profile: ProfileConfig,
+    #[serde(default)]
+    pub workouts: WorkoutsConfig,
+    #[serde(default)]
+    pub firmware: FirmwareConfig,
+    #[serde(default)]
+    pub agent: AgentConfig,
+    #[serde(default)]
+    pub daemon: DaemonConfig,
+    #[serde(default)]
+    pub mqtt: MqttConfig,
+    #[serde(default)]
+    pub timeouts: TimeoutsConfig,
+    #[serde(default)]
+    pub http: HttpConfig,
+    #[serde(default)]
+    pub log: LogConfig,
+}
+
+/// The time zone to set on the devices
+#[derive(Debug, Clone, PartialEq)]
+pub enum TimeZoneSetting {
+    /// The time zone of this computer
+    Local,
+    /// A fixed offset in seconds, never changing for DST
+    Fixed(i32),
+    /// A zone of the tz database, by its IANA name
+    Named(Zone),
+    /// The zone of a place, see [f_xoss::time_zone::zone_at]
+    Located(Zone),
+}
+
+impl TimeZoneSetting {
+    pub fn parse(s: &str) -> Result<Self> {
+        if let Some(offset) = f_xoss::time_zone::parse_offset(s) {
+            return Ok(Self::Fixed(offset));
+        }
+        match Zone::named(s) {
+            Some(zone) => Ok(Self::Named(zone)),
+            None => bail!(
+                "Unknown time zone {:?}, expected a name from the time zone database (like \"Europe/Berlin\") or an offset (like \"+05:30\")",
+                s
+            ),
+        }
+    }
+
+    /// The offset in seconds at the given moment
+    pub fn offset_at(&self, time: DateTime<Utc>) -> i32 {
+        match self {
+            Self::Local => Local
+                .offset_from_utc_datetime(&time.naive_utc())
+                .fix()
+                .local_minus_utc(),
+            Self::Fixed(offset) => *offset,
+            Self::Named(zone) | Self::Located(zone) => zone.offset_at(time),
+        }
+    }
+}
+
+impl XossUtilConfig {
+    pub fn use_keyring(&self) -> bool {
+        self.use_keyring.unwrap_or(true)
+    }
+
+    pub fn time_zone(&self) -> Result<TimeZoneSetting> {
+        self.time_zone
+            .as_deref()
+            .map_or(Ok(TimeZoneSetting::Local), TimeZoneSetting::parse)
+            .context("Invalid time_zone in the config")
+    }
+}
+
+/// Set by `--demo`, the demo mode keeps its config and data apart from the real ones
+static DEMO_MODE: AtomicBool = AtomicBool::new(false);
+
+/// Switch to the demo directories, must be called before [APP_DIRS] is used
+pub fn enable_demo_mode() {
+    assert!(
+        Lazy::get(&APP_DIRS).is_none(),
+        "The demo mode is enabled after the directories have been used"
+    );
+    DEMO_MODE.store(true, Ordering::Relaxed);
+}
+
+pub fn is_demo_mode() -> bool {
+    DEMO_MODE.load(Ordering::Relaxed)
 }
 
 pub static APP_DIRS: Lazy<ProjectDirs> = Lazy::new(|| {
-    ProjectDirs::from("com.dcnick3", "", "f-xoss").expect("Failed to get the project directories")
+    let application = if is_demo_mode() {
+        "f-xoss-demo"
+    } else {
+        "f-xoss"
+    };
+    ProjectDirs::from("com.dcnick3", "", application)
+        .expect("Failed to get the project directories")
 });
 
 pub fn config_path() -> PathBuf {
     APP_DIRS.config_dir().join("config.toml")
 }
 
+/// A change of the config format, updating a config written in the previous format in place
+type Migration = fn(&mut toml::Table) -> Result<()>;
+
+/// The migrations from each version of the config format to the next one
+const MIGRATIONS: &[Migration] = &[migrate_device_addresses];
+
+/// The version of the config format written by this version
+pub const CONFIG_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// Version 1: the device address is recorded next to the peripheral id
+///
+/// The configs written before only have the peripheral id. On Linux and Windows the address is a part of it,
+/// the configs from macOS stay without one until the device is found under a different peripheral id.
+fn migrate_device_addresses(config: &mut toml::Table) -> Result<()> {
+    let Some(toml::Value::Array(devices)) = config.get_mut("devices") else {
+        return Ok(());
+    };
+
+    for device in devices.iter_mut().filter_map(|d| d.as_table_mut()) {
+        if device.contains_key("address") {
+            continue;
+        }
+        let address = match device.get("peripheral_id") {
+            // BlueZ: a D-Bus object path like /org/bluez/hci0/dev_AA_BB_CC_DD_EE_FF
+            Some(toml::Value::Table(id)) => id
+                .get("object_path")
+                .and_then(|path| path.as_str())
+                .and_then(|path| path.rsplit_once("/dev_"))
+                .and_then(|(_, address)| BDAddr::from_str(&address.replace('_', ":")).ok()),
+            // WinRT: the address itself
+            Some(toml::Value::String(id)) => BDAddr::from_str(id).ok(),
+            _ => None,
+        };
+        if let Some(address) = address {
+            device.insert(
+                "address".to_string(),
+                toml::Value::String(address.to_string()),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Bring a config to the current format, returning the version it was in if it wasn't
+fn migrate_config(config: &mut toml::Table) -> Result<Option<u32>> {
+    let version = match config.get("version") {
+        None => 0,
+        Some(version) => version
+            .as_integer()
+            .and_then(|v| u32::try_from(v).ok())
+            .context("The config version is not a valid number")?,
+    };
+
+    if version == CONFIG_VERSION {
+        return Ok(None);
+    }
+    if version > CONFIG_VERSION {
+        bail!(
+            "The config was written by a newer version of f-xoss-util (format version {}, this one knows up to {}). Update f-xoss-util",
+            version,
+            CONFIG_VERSION
+        );
+    }
+
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        migration(config).with_context(|| format!("Migrating the config from version {}", from))?;
+    }
+    config.insert(
+        "version".to_string(),
+        toml::Value::Integer(CONFIG_VERSION.into()),
+    );
+
+    Ok(Some(version))
+}
+
+/// Load the config, migrating it to the current format
+///
+/// A migrated config is saved right away, the old one is kept next to it as `config.toml.v<version>.bak`
 pub fn load_config() -> Result<Option<XossUtilConfig>> {
     let config_path = config_path();
 
     let config = match std::fs::read_to_string(&config_path) {
-        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
-        r => r.map(Some),
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        r => r,
     }
     .context(format!("Reading config file {}", config_path.display()))?;
 
-    config
-        .map(|config| {
-            toml::from_str(&config)
-                .with_context(|| format!("Parsing config file {}", config_path.display()))
-        })
-        .transpose()
+    let parse_error = || format!("Parsing config file {}", config_path.display());
+    let mut table: toml::Table = toml::from_str(&config).with_context(parse_error)?;
+    let Some(old_version) = migrate_config(&mut table).with_context(parse_error)? else {
+        // parsed from the text again for the error messages pointing at the line
+        return toml::from_str(&config).with_context(parse_error).map(Some);
+    };
+    // through the text too, some types (like the addresses) can only be deserialized from it
+    let config: XossUtilConfig = toml::to_string(&table)
+        .context("Serializing the migrated config")
+        .and_then(|migrated| toml::from_str(&migrated).with_context(parse_error))?;
+
+    let backup_path = config_path.with_extension(format!("toml.v{}.bak", old_version));
+    info!(
+        "Migrating the config from version {} to {}, the old one is kept at {}",
+        old_version,
+        CONFIG_VERSION,
+        backup_path.display()
+    );
+    if let Err(e) = std::fs::copy(&config_path, &backup_path)
+        .context("Backing up the config")
+        .and_then(|_| save_config(&config))
+    {
+        // still usable, it's migrated again on the next run
+        warn!("Failed to save the migrated config: {:#}", e);
+    }
+
+    Ok(Some(config))
+}
+
+pub fn save_config(config: &XossUtilConfig) -> Result<()> {
+    let config_path = config_path();
+
+    info!("Saving the config to {}", config_path.display());
+    std::fs::create_dir_all(config_path.parent().unwrap())
+        .context("Creating the config directory")?;
+    std::fs::write(
+        &config_path,
+        toml::to_string_pretty(config).context("Serializing the config file")?,
+    )
+    .context("Writing the config file")?;
+
+    Ok(())
 }
@@ -0,0 +1,368 @@
+//! The demo mode (`--demo`): the commands run against a simulated device instead of a real one
+//!
+//! The simulated device starts with a built-in dataset: the JSON configs in the `demo` directory of the crate and a few
+//! generated workouts. Its files are kept in the demo data directory between the runs, so the changes made by one command
+//! are seen by the next one. A factory reset brings back the initial dataset.
+//!
+//! All the paths (the config, the synced workouts, the caches) are separate from the real ones, see [crate::config::enable_demo_mode].
+
+use crate::config::{TimeoutsConfig, XossUtilConfig};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, NaiveTime, TimeZone, Utc};
+use f_xoss::device::XossDevice;
+use f_xoss::fit::{self, message, FitHeader, FIT_EPOCH_OFFSET};
+use f_xoss::geo::{CoordinateEncoding, LatLon};
+use f_xoss::mga::{CorruptFrames, Gnss};
+use f_xoss::model::{WorkoutState, WorkoutsItem};
+use f_xoss::transport::mock::MockDevice;
+use f_xoss::transport::{DeviceInformation, XossTransport};
+use std::path::PathBuf;
+use tracing::{debug, info};
+
+const SERIAL_NUMBER: &str = "DEMO000001";
+
+/// The JSON configs as the device has them after a few rides
+const FIXTURES: &[(&str, &str)] = &[
+    (
+        "user_profile.json",
+        include_str!("../demo/user_profile.json"),
+    ),
+    ("settings.json", include_str!("../demo/settings.json")),
+    (
+        "gear_profile.json",
+        include_str!("../demo/gear_profile.json"),
+    ),
+    ("panels.json", include_str!("../demo/panels.json")),
+    ("routebooks.json", include_str!("../demo/routebooks.json")),
+];
+
+/// The demo workouts: start (days before today, UTC hour), duration in minutes and the loop radius in meters
+const WORKOUTS: &[(i64, u32, i64, f64)] =
+    &[(9, 18, 95, 4200.0), (4, 7, 48, 2500.0), (1, 17, 70, 3300.0)];
+
+/// The seconds between the track points
+const RECORD_INTERVAL: i64 = 5;
+
+pub fn config() -> XossUtilConfig {
+    XossUtilConfig {
+        time_zone: Some("+02:00".to_string()),
+        ..Default::default()
+    }
+}
+
+fn device_dir() -> PathBuf {
+    crate::config::APP_DIRS.data_dir().join("device")
+}
+
+fn device_information() -> DeviceInformation {
+    DeviceInformation {
+        firmware_revision: "2.4.3".to_string(),
+        manufacturer_name: "XOSS".to_string(),
+        model_number: "XOSS NAV".to_string(),
+        hardware_revision: "V1.0".to_string(),
+        serial_number: SERIAL_NUMBER.to_string(),
+    }
+}
+
+/// Load the simulated device from the demo data directory, filling it with the initial dataset if it's empty
+pub fn load_device() -> Result<MockDevice> {
+    let device = MockDevice::new(device_information());
+    device.set_battery_level(76);
+
+    let dir = device_dir();
+    if let Ok(entries) = std::fs::read_dir(&dir) {
+        for entry in entries {
+            let entry = entry.with_context(|| format!("Reading {}", dir.display()))?;
+            let content = std::fs::read(entry.path())
+                .with_context(|| format!("Reading {}", entry.path().display()))?;
+            device.set_file(entry.file_name().to_string_lossy(), content);
+        }
+    }
+
+    if device.files().is_empty() {
+        info!("Starting the demo with a fresh simulated device");
+        fill_device(&device);
+    } else {
+        debug!("Loaded the simulated device from {}", dir.display());
+    }
+
+    Ok(device)
+}
+
+/// Keep the files of the simulated device for the next run
+pub fn save_device(device: &MockDevice) -> Result<()> {
+    let dir = device_dir();
+    // the files deleted from the device have to go too
+    match std::fs::remove_dir_all(&dir) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            return Err(e).with_context(|| format!("Removing {}", dir.display()))
+        }
+        _ => {}
+    }
+    std::fs::create_dir_all(&dir).with_context(|| format!("Creating {}", dir.display()))?;
+
+    for (filename, content) in device.files() {
+        std::fs::write(dir.join(&filename), content)
+            .with_context(|| format!("Saving {} of the simulated device", filename))?;
+    }
+
+    Ok(())
+}
+
+pub async fn connect(device: &MockDevice, timeouts: &TimeoutsConfig) -> Result<XossDevice> {
+    info!("Connecting to the simulated device (demo mode)");
+    XossDevice::with_transport(XossTransport::mock(device, timeouts.transport_options())).await
+}
+
+fn fill_device(device: &MockDevice) {
+    for (filename, content) in FIXTURES {
+        device.set_file(*filename, *content);
+    }
+
+    let today = Utc::now().date_naive();
+    let mut workouts = Vec::new();
+    for &(days_ago, hour, minutes, radius) in WORKOUTS {
+        let start = Utc.from_utc_datetime(
+            &(today - Duration::days(days_ago))
+                .and_time(NaiveTime::from_hms_opt(hour, 0, 0).unwrap()),
+        );
+        let content = generate_workout(start, Duration::minutes(minutes), radius);
+
+        let item = WorkoutsItem {
+            name: start.timestamp() as u64,
+            size: content.len() as u32,
+            state: if days_ago > 5 {
+                WorkoutState::Synced
+            } else {
+                WorkoutState::NotSynchronized
+            },
+        };
+        device.set_file(item.filename(), content);
+        workouts.push(item);
+    }
+
+    let workouts = serde_json::json!({
+        "device_model": "XOSS NAV",
+        "sn": SERIAL_NUMBER,
+        "updated_at": Utc::now().timestamp(),
+        "version": "2.0.0",
+        "workouts": workouts,
+    });
+    device.set_file("workouts.json", workouts.to_string());
+}
+
+/// Writes the FIT records, all the fields are little-endian
+struct FitWriter {
+    records: Vec<u8>,
+}
+
+impl FitWriter {
+    /// `fields` are (number, size, base type)
+    fn define(&mut self, local_type: u8, global_number: u16, fields: &[(u8, u8, u8)]) {
+        self.records.extend_from_slice(&[0x40 | local_type, 0, 0]);
+        self.records.extend_from_slice(&global_number.to_le_bytes());
+        self.records.push(fields.len() as u8);
+        for &(number, size, base_type) in fields {
+            self.records.extend_from_slice(&[number, size, base_type]);
+        }
+    }
+
+    fn data(&mut self, local_type: u8, values: &[&[u8]]) {
+        self.records.push(local_type);
+        for value in values {
+            self.records.extend_from_slice(value);
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        let mut file = FitHeader {
+            header_size: 14,
+            protocol_version: 0x10,
+            profile_version: 2132,
+            data_size: self.records.len() as u32,
+        }
+        .to_bytes();
+        file.extend_from_slice(&self.records);
+        let crc = fit::crc(&file);
+        file.extend_from_slice(&crc.to_le_bytes());
+        file
+    }
+}
+
+fn fit_timestamp(time: DateTime<Utc>) -> [u8; 4] {
+    ((time.timestamp() - FIT_EPOCH_OFFSET) as u32).to_le_bytes()
+}
+
+/// A ride around a loop with a hill on one side
+fn generate_workout(start: DateTime<Utc>, duration: Duration, radius: f64) -> Vec<u8> {
+    const FILE_ID: u8 = 0;
+    const RECORD: u8 = 1;
+    const SESSION: u8 = 2;
+
+    let center = LatLon::new(47.2692, 11.4041);
+    let encoding = CoordinateEncoding::Semicircles;
+
+    let mut writer = FitWriter {
+        records: Vec::new(),
+    };
+    writer.define(
+        FILE_ID,
+        message::FILE_ID,
+        &[(0, 1, 0x00), (1, 2, 0x84), (4, 4, 0x86)],
+    );
+    // activity, development manufacturer
+    writer.data(
+        FILE_ID,
+        &[&[4], &255u16.to_le_bytes(), &fit_timestamp(start)],
+    );
+
+    writer.define(
+        RECORD,
+        message::RECORD,
+        &[
+            (253, 4, 0x86),
+            (0, 4, 0x85),
+            (1, 4, 0x85),
+            (2, 2, 0x84),
+            (3, 1, 0x02),
+            (4, 1, 0x02),
+            (5, 4, 0x86),
+            (6, 2, 0x84),
+        ],
+    );
+
+    let points = duration.num_seconds() / RECORD_INTERVAL;
+    let laps = 2.0;
+    let mut distance = 0.0;
+    let mut ascent = 0.0;
+    let mut previous: Option<(LatLon, f64)> = None;
+    for i in 0..=points {
+        let angle = i as f64 / points as f64 * laps * std::f64::consts::TAU;
+        let position = LatLon::new(
+            center.lat + radius * angle.sin() / 111_320.0,
+            center.lon + radius * (1.0 - angle.cos()) / (111_320.0 * center.lat.to_radians().cos()),
+        );
+        let altitude = 580.0 + 45.0 * (1.0 - angle.cos());
+        let (speed, climbing) = match previous {
+            Some((previous_position, previous_altitude)) => {
+                let step = previous_position.distance_to(&position);
+                distance += step;
+                ascent += (altitude - previous_altitude).max(0.0);
+                (step / RECORD_INTERVAL as f64, altitude > previous_altitude)
+            }
+            None => (0.0, false),
+        };
+        previous = Some((position, altitude));
+
+        let (lat, lon) = encoding.encode_point(position);
+        let heart_rate: u8 = if climbing { 152 } else { 128 } + (i % 7) as u8;
+        let cadence: u8 = if climbing { 78 } else { 88 } + (i % 5) as u8;
+        writer.data(
+            RECORD,
+            &[
+                &fit_timestamp(start + Duration::seconds(i * RECORD_INTERVAL)),
+                &lat.to_le_bytes(),
+                &lon.to_le_bytes(),
+                &(((altitude + 500.0) * 5.0) as u16).to_le_bytes(),
+                &[heart_rate],
+                &[cadence],
+                &((distance * 100.0) as u32).to_le_bytes(),
+                &((speed * 1000.0) as u16).to_le_bytes(),
+            ],
+        );
+    }
+
+    writer.define(
+        SESSION,
+        message::SESSION,
+        &[
+            (253, 4, 0x86),
+            (2, 4, 0x86),
+            (7, 4, 0x86),
+            (8, 4, 0x86),
+            (9, 4, 0x86),
+            (14, 2, 0x84),
+            (22, 2, 0x84),
+        ],
+    );
+    let seconds = points * RECORD_INTERVAL;
+    let end = start + Duration::seconds(seconds);
+    let milliseconds = (seconds * 1000) as u32;
+    writer.data(
+        SESSION,
+        &[
+            &fit_timestamp(end),
+            &fit_timestamp(start),
+            &milliseconds.to_le_bytes(),
+            &milliseconds.to_le_bytes(),
+            &((distance * 100.0) as u32).to_le_bytes(),
+            &((distance / seconds as f64 * 1000.0) as u16).to_le_bytes(),
+            &(ascent as u16).to_le_bytes(),
+        ],
+    );
+
+    writer.finish()
+}
+
+/// Put the A-GNSS data valid from today into the demo cache, so that the sync doesn't need the internet
+pub fn prepare_mga_cache() -> Result<()> {
+    let path = crate::mga::mga_file_path(crate::mga::MgaMode::Offline);
+    let today = Utc::now().date_naive();
+    let fresh = std::fs::read(&path)
+        .ok()
+        .and_then(|data| f_xoss::mga::parse_mga_data(data, CorruptFrames::Fail).ok())
+        .is_some_and(|data| {
+            data.valid_since == today
+                && Gnss::DEFAULT
+                    .iter()
+                    .all(|gnss| data.constellations.contains(gnss))
+        });
+    if fresh {
+        return Ok(());
+    }
+
+    let mut data = Vec::new();
+    for day in 0..28 {
+        let date = today + Duration::days(day);
+        for gnss_id in DEMO_GNSS_IDS {
+            for satellite in 1..=4u8 {
+                data.extend_from_slice(&mga_ano_message(gnss_id, satellite, date));
+            }
+        }
+    }
+
+    std::fs::create_dir_all(path.parent().unwrap()).context("Creating the cache directory")?;
+    std::fs::write(&path, data)
+        .with_context(|| format!("Writing the demo MGA data to {}", path.display()))
+}
+
+/// The UBX gnssId of GPS and GLONASS, the constellations requested by default
+const DEMO_GNSS_IDS: [u8; 2] = [0, 6];
+
+/// A UBX-MGA-ANO message with a made-up orbit
+fn mga_ano_message(gnss_id: u8, satellite: u8, date: chrono::NaiveDate) -> Vec<u8> {
+    use chrono::Datelike;
+
+    let mut message = vec![0xb5, 0x62, 0x13, 0x20, 0x4c, 0x00];
+    // type, version, satellite, GNSS, date, reserved
+    message.extend_from_slice(&[
+        0x00,
+        0x00,
+        satellite,
+        gnss_id,
+        (date.year() - 2000) as u8,
+        date.month() as u8,
+        date.day() as u8,
+        0x00,
+    ]);
+    message.extend((0..64).map(|i: u8| i.wrapping_mul(satellite).wrapping_add(date.day() as u8)));
+    message.extend_from_slice(&[0; 4]);
+
+    let (mut ck_a, mut ck_b) = (0u8, 0u8);
+    for &b in &message[2..] {
+        ck_a = ck_a.wrapping_add(b);
+        ck_b = ck_b.wrapping_add(ck_a);
+    }
+    message.extend_from_slice(&[ck_a, ck_b]);
+    message
+}
@@ -0,0 +1,225 @@
+//! Conversion of the recorded tracks to the formats understood by the other apps
+//!
+//! Only the track itself is exported, the laps and the device-specific data stay in the FIT file.
+//! The FIT export is the original file, only named with the [annotation](Annotation) of the workout.
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use clap::ValueEnum;
+use f_xoss::fit::{fit_time, message, FitFile, FitValue, TrackPoint, WorkoutSummary};
+use std::fmt::Write;
+
+use crate::workout_index::Annotation;
+
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExportFormat {
+    #[default]
+    Gpx,
+    Tcx,
+    Fit,
+}
+
+impl ExportFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Gpx => "gpx",
+            ExportFormat::Tcx => "tcx",
+            ExportFormat::Fit => "fit",
+        }
+    }
+
+    /// Whether the export is made from the track, so that the changes to the track (like the corrected elevation)
+    /// get into it
+    pub fn is_from_track(self) -> bool {
+        !matches!(self, ExportFormat::Fit)
+    }
+
+    /// Export the workout `data` (the contents of its FIT file) with the `track` read from it
+    ///
+    /// `name` is the track name if the annotation has no title
+    pub fn write(
+        self,
+        name: &str,
+        annotation: &Annotation,
+        data: &[u8],
+        track: &[TrackPoint],
+    ) -> Result<Vec<u8>> {
+        Ok(match self {
+            ExportFormat::Gpx => write_gpx(name, annotation, track).into_bytes(),
+            ExportFormat::Tcx => write_tcx(annotation, data, track)?.into_bytes(),
+            ExportFormat::Fit => f_xoss::fit::annotate(
+                data,
+                annotation.title.as_deref(),
+                annotation.notes.as_deref(),
+            )
+            .context("Adding the title and the notes to the FIT file")?,
+        })
+    }
+}
+
+fn format_time(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// writing to a String never fails, hence the unwraps
+
+/// GPX 1.1 with the heart rate and the cadence in the Garmin extension, the points without a position are skipped
+fn write_gpx(name: &str, annotation: &Annotation, track: &[TrackPoint]) -> String {
+    let mut out = String::new();
+    out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    out.push_str("<gpx version=\"1.1\" creator=\"f-xoss-util\" xmlns=\"http://www.topografix.com/GPX/1/1\" xmlns:gpxtpx=\"http://www.garmin.com/xmlschemas/TrackPointExtension/v1\">\n");
+    if let Some(first) = track.first() {
+        writeln!(
+            out,
+            "  <metadata><time>{}</time></metadata>",
+            format_time(first.time)
+        )
+        .unwrap();
+    }
+    let name = annotation.title.as_deref().unwrap_or(name);
+    writeln!(out, "  <trk>\n    <name>{}</name>", escape(name)).unwrap();
+    if let Some(notes) = &annotation.notes {
+        writeln!(out, "    <desc>{}</desc>", escape(notes)).unwrap();
+    }
+    out.push_str("    <type>cycling</type>\n    <trkseg>\n");
+
+    for point in track {
+        let Some(position) = point.position else {
+            continue;
+        };
+        writeln!(
+            out,
+            "      <trkpt lat=\"{:.7}\" lon=\"{:.7}\">",
+            position.lat, position.lon
+        )
+        .unwrap();
+        if let Some(altitude) = point.altitude {
+            writeln!(out, "        <ele>{:.1}</ele>", altitude).unwrap();
+        }
+        writeln!(out, "        <time>{}</time>", format_time(point.time)).unwrap();
+        if point.heart_rate.is_some() || point.cadence.is_some() {
+            out.push_str("        <extensions><gpxtpx:TrackPointExtension>");
+            if let Some(hr) = point.heart_rate {
+                write!(out, "<gpxtpx:hr>{}</gpxtpx:hr>", hr).unwrap();
+            }
+            if let Some(cadence) = point.cadence {
+                write!(out, "<gpxtpx:cad>{}</gpxtpx:cad>", cadence).unwrap();
+            }
+            out.push_str("</gpxtpx:TrackPointExtension></extensions>\n");
+        }
+        out.push_str("      </trkpt>\n");
+    }
+
+    out.push_str("    </trkseg>\n  </trk>\n</gpx>\n");
+    out
+}
+
+/// The start time recorded in the FIT file itself: the session start, or the file creation time
+fn fit_start_time(data: &[u8]) -> Option<DateTime<Utc>> {
+    let fit = FitFile::parse(data).ok()?;
+    WorkoutSummary::from_fit(&fit).start_time.or_else(|| {
+        fit.messages(message::FILE_ID)
+            .find_map(|m| m.field(4))
+            .and_then(FitValue::as_i64)
+            .and_then(fit_time)
+    })
+}
+
+/// Training Center XML with the whole track as a single lap
+///
+/// TCX has no title, it's put into the notes before the notes themselves.
+/// The activity id is its start time, which is taken from the FIT file if the track is empty.
+fn write_tcx(annotation: &Annotation, data: &[u8], track: &[TrackPoint]) -> Result<String> {
+    let summary = WorkoutSummary::from_track(track);
+    let start = summary
+        .start_time
+        .or_else(|| fit_start_time(data))
+        .ok_or_else(|| {
+            anyhow!("The workout has no track and no start time, which a TCX file requires")
+        })?;
+    let start = format_time(start);
+
+    let mut out = String::new();
+    out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    out.push_str("<TrainingCenterDatabase xmlns=\"http://www.garmin.com/xmlschemas/TrainingCenterDatabase/v2\">\n");
+    out.push_str("  <Activities>\n    <Activity Sport=\"Biking\">\n");
+    writeln!(out, "      <Id>{}</Id>", start).unwrap();
+    writeln!(out, "      <Lap StartTime=\"{}\">", start).unwrap();
+    writeln!(
+        out,
+        "        <TotalTimeSeconds>{:.1}</TotalTimeSeconds>",
+        summary.elapsed_time.unwrap_or_default()
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "        <DistanceMeters>{:.1}</DistanceMeters>",
+        summary.distance.unwrap_or_default()
+    )
+    .unwrap();
+    out.push_str("        <Calories>0</Calories>\n        <Intensity>Active</Intensity>\n        <TriggerMethod>Manual</TriggerMethod>\n");
+    if !track.is_empty() {
+        out.push_str("        <Track>\n");
+
+        for point in track {
+            out.push_str("          <Trackpoint>\n");
+            writeln!(out, "            <Time>{}</Time>", format_time(point.time)).unwrap();
+            if let Some(position) = point.position {
+                writeln!(
+                    out,
+                    "            <Position><LatitudeDegrees>{:.7}</LatitudeDegrees><LongitudeDegrees>{:.7}</LongitudeDegrees></Position>",
+                    position.lat, position.lon
+                )
+                .unwrap();
+            }
+            if let Some(altitude) = point.altitude {
+                writeln!(
+                    out,
+                    "            <AltitudeMeters>{:.1}</AltitudeMeters>",
+                    altitude
+                )
+                .unwrap();
+            }
+            if let Some(distance) = point.distance {
+                writeln!(
+                    out,
+                    "            <DistanceMeters>{:.1}</DistanceMeters>",
+                    distance
+                )
+                .unwrap();
+            }
+            if let Some(hr) = point.heart_rate {
+                writeln!(
+                    out,
+                    "            <HeartRateBpm><Value>{}</Value></HeartRateBpm>",
+                    hr
+                )
+                .unwrap();
+            }
+            if let Some(cadence) = point.cadence {
+                writeln!(out, "            <Cadence>{}</Cadence>", cadence).unwrap();
+            }
+            out.push_str("          </Trackpoint>\n");
+        }
+
+        out.push_str("        </Track>\n");
+    }
+    out.push_str("      </Lap>\n");
+    let notes = [&annotation.title, &annotation.notes]
+        .into_iter()
+        .flatten()
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    if !notes.is_empty() {
+        writeln!(out, "      <Notes>{}</Notes>", escape(&notes.join("\n\n"))).unwrap();
+    }
+    out.push_str("    </Activity>\n  </Activities>\n</TrainingCenterDatabase>\n");
+    Ok(out)
+}
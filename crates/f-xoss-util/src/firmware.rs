@@ -0,0 +1,231 @@
+//! Checking for firmware updates against a manifest
+//!
+//! XOSS doesn't publish its firmware updates in a machine-readable form, so the manifest is a JSON file
+//! maintained by whoever distributes the packages (see `FirmwareManifest` for the format).
+
+use crate::config::FirmwareConfig;
+use anyhow::{anyhow, Context, Result};
+use camino::Utf8PathBuf;
+use f_xoss::dfu::DfuPackage;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::path::PathBuf;
+use tracing::{debug, info, instrument};
+
+/// The list of the latest firmware versions, one entry per device model
+///
+/// ```json
+/// {
+///   "firmwares": [
+///     {
+///       "model": "XOSS NAV",
+///       "version": "V3.0.4",
+///       "url": "nav-3.0.4.zip",
+///       "notes": "Fixes the climb page"
+///     }
+///   ]
+/// }
+/// ```
+///
+/// `model` is matched against the model number reported by the device.
+/// `url` can be relative to the manifest location.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FirmwareManifest {
+    pub firmwares: Vec<FirmwareRelease>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FirmwareRelease {
+    pub model: String,
+    pub version: String,
+    pub url: String,
+    #[serde(default)]
+    pub notes: Option<String>,
+}
+
+/// Where the manifest (and the packages with relative urls) come from
+enum ManifestLocation {
+    Url(Url),
+    File(PathBuf),
+}
+
+impl ManifestLocation {
+    fn parse(location: &str) -> Self {
+        match Url::parse(location) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => Self::Url(url),
+            _ => Self::File(PathBuf::from(location)),
+        }
+    }
+
+    fn join(&self, relative: &str) -> Result<Self> {
+        Ok(match self {
+            Self::Url(url) => Self::Url(
+                url.join(relative)
+                    .with_context(|| format!("Invalid package url {:?}", relative))?,
+            ),
+            Self::File(path) => match Url::parse(relative) {
+                Ok(url) if matches!(url.scheme(), "http" | "https") => Self::Url(url),
+                _ => Self::File(
+                    path.parent()
+                        .map(|dir| dir.join(relative))
+                        .unwrap_or_else(|| PathBuf::from(relative)),
+                ),
+            },
+        })
+    }
+
+    async fn read(&self) -> Result<Vec<u8>> {
+        match self {
+            Self::Url(url) => Ok(crate::http::client()
+                .get(url)
+                .await
+                .with_context(|| format!("Failed to download {}", url))?
+                .to_vec()),
+            Self::File(path) => tokio::fs::read(path)
+                .await
+                .with_context(|| format!("Reading {}", path.display())),
+        }
+    }
+}
+
+/// Parse the numeric components of a version string, like "V3.0.4" -> [3, 0, 4]
+fn version_components(version: &str) -> Vec<u64> {
+    version
+        .split(|c: char| !c.is_ascii_digit())
+        .filter(|s| !s.is_empty())
+        .filter_map(|s| s.parse().ok())
+        .collect()
+}
+
+/// Split a version string into the release and the pre-release suffix, like "V3.0.4-beta.2" -> ("V3.0.4", Some("beta.2"))
+fn split_pre_release(version: &str) -> (&str, Option<&str>) {
+    match version.trim().split_once('-') {
+        Some((release, pre_release)) => (release, Some(pre_release)),
+        None => (version.trim(), None),
+    }
+}
+
+/// Compare the pre-release suffixes like semver does: by the dot-separated identifiers, numbers before words
+fn compare_pre_releases<'a>(a: &'a str, b: &'a str) -> Ordering {
+    let identifiers = |s: &'a str| {
+        s.split('.')
+            .map(|identifier| identifier.parse::<u64>().map_err(|_| identifier))
+    };
+    identifiers(a).cmp(identifiers(b))
+}
+
+/// Compare two firmware versions, `None` if they can't be compared
+///
+/// The missing components are zeros ("3.1" is "3.1.0"), and a pre-release ("3.1.0-beta") comes before its release.
+pub fn compare_versions(a: &str, b: &str) -> Option<Ordering> {
+    let ((a_release, a_pre_release), (b_release, b_pre_release)) =
+        (split_pre_release(a), split_pre_release(b));
+    let (a_components, b_components) =
+        (version_components(a_release), version_components(b_release));
+    if a_components.is_empty() || b_components.is_empty() {
+        return (a.trim() == b.trim()).then_some(Ordering::Equal);
+    }
+
+    let len = a_components.len().max(b_components.len());
+    let padded = |components: &[u64]| {
+        let mut components = components.to_vec();
+        components.resize(len, 0);
+        components
+    };
+    let release = padded(&a_components).cmp(&padded(&b_components));
+
+    Some(release.then(match (a_pre_release, b_pre_release) {
+        (None, None) => Ordering::Equal,
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (Some(a), Some(b)) => compare_pre_releases(a, b),
+    }))
+}
+
+pub struct FirmwareCheck {
+    pub release: FirmwareRelease,
+    location: ManifestLocation,
+}
+
+impl FirmwareCheck {
+    /// Download the package into the cache directory and make sure it can be flashed
+    #[instrument(skip(self), fields(version = %self.release.version))]
+    pub async fn download(&self) -> Result<Utf8PathBuf> {
+        let data = self
+            .location
+            .join(&self.release.url)?
+            .read()
+            .await
+            .context("Failed to get the firmware package")?;
+        DfuPackage::from_zip(&data).context("The downloaded firmware package is not valid")?;
+
+        let dir = firmware_cache_dir()?;
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .context("Creating the firmware cache directory")?;
+
+        let path = dir.join(package_file_name(&self.release));
+        tokio::fs::write(&path, &data)
+            .await
+            .with_context(|| format!("Writing {}", path))?;
+
+        info!("Downloaded the firmware package to {}", path);
+
+        Ok(path)
+    }
+}
+
+fn firmware_cache_dir() -> Result<Utf8PathBuf> {
+    Utf8PathBuf::from_path_buf(crate::config::APP_DIRS.cache_dir().join("firmware"))
+        .map_err(|path| anyhow!("The cache directory {} is not UTF-8", path.display()))
+}
+
+fn package_file_name(release: &FirmwareRelease) -> String {
+    let sanitize = |s: &str| {
+        s.chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '.' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect::<String>()
+    };
+
+    format!(
+        "{}-{}.zip",
+        sanitize(&release.model),
+        sanitize(&release.version)
+    )
+}
+
+/// Find the latest firmware release for the device model in the manifest
+///
+/// `manifest` overrides the one set in the config.
+pub async fn check_firmware(
+    config: &FirmwareConfig,
+    manifest: Option<&str>,
+    model_number: &str,
+) -> Result<Option<FirmwareCheck>> {
+    let location = manifest
+        .or(config.manifest.as_deref())
+        .context("No firmware manifest is configured, pass --manifest or set firmware.manifest in the config")?;
+    let location = ManifestLocation::parse(location);
+
+    let manifest: FirmwareManifest = serde_json::from_slice(
+        &location
+            .read()
+            .await
+            .context("Failed to get the firmware manifest")?,
+    )
+    .context("Parsing the firmware manifest")?;
+    debug!("Firmware manifest: {:?}", manifest);
+
+    Ok(manifest
+        .firmwares
+        .into_iter()
+        .find(|release| release.model.eq_ignore_ascii_case(model_number))
+        .map(|release| FirmwareCheck { release, location }))
+}
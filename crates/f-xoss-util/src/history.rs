@@ -0,0 +1,113 @@
+//! Keeps a log of the files transferred to and from each device in the state file
+
+use anyhow::Result;
+use chrono::{Local, TimeZone, Utc};
+use prettytable::{row, Table};
+use tracing::{info, warn};
+
+use crate::config::XossUtilConfig;
+use crate::state::{TransferDirection, TransferRecord};
+use f_xoss::device::{self, XossDevice};
+
+/// How many transfers of each file to remember
+const MAX_RECORDS_PER_FILE: usize = 20;
+
+/// Record all the transfers made with the device from now on
+pub async fn record_transfers(device: &XossDevice) {
+    let serial_number = device.device_info().serial_number.clone();
+
+    device.set_transfer_observer(Box::new(move |event| {
+        let record = TransferRecord {
+            time: Utc::now().timestamp(),
+            direction: match event.direction {
+                device::TransferDirection::Download => TransferDirection::Download,
+                device::TransferDirection::Upload => TransferDirection::Upload,
+            },
+            size: event.size,
+            crc32: event.crc32,
+        };
+
+        let result = crate::state::update_state(|state| {
+            let records = state
+                .device_mut(&serial_number)
+                .transfers
+                .entry(event.filename.to_string())
+                .or_default();
+            records.push(record);
+            if records.len() > MAX_RECORDS_PER_FILE {
+                records.drain(..records.len() - MAX_RECORDS_PER_FILE);
+            }
+        });
+        // the history is nice to have, it's not worth failing the transfer
+        if let Err(e) = result {
+            warn!("Failed to record the transfer history: {:#}", e);
+        }
+    }));
+}
+
+/// Show the transfers of a file, for all the devices or only the selected one
+pub fn show_file_history(
+    config: Option<&XossUtilConfig>,
+    filename: &str,
+    device_selector: Option<&str>,
+) -> Result<()> {
+    let state = crate::state::load_state()?;
+
+    let device_name = |serial_number: &str| {
+        config
+            .and_then(|c| {
+                c.devices
+                    .iter()
+                    .find(|d| d.serial_number.as_deref() == Some(serial_number))
+            })
+            .map_or_else(|| serial_number.to_string(), |d| d.identify())
+    };
+
+    let mut table = Table::new();
+    table.set_format(*prettytable::format::consts::FORMAT_CLEAN);
+    table.set_titles(row!["Device", "Time", "Direction", "Size", "CRC32"]);
+
+    for (serial_number, device_state) in &state.devices {
+        if let Some(selector) = device_selector {
+            let selected = serial_number == selector
+                || config.is_some_and(|c| {
+                    c.devices.iter().any(|d| {
+                        d.matches(selector) && d.serial_number.as_ref() == Some(serial_number)
+                    })
+                });
+            if !selected {
+                continue;
+            }
+        }
+
+        for record in device_state
+            .transfers
+            .get(filename)
+            .into_iter()
+            .flatten()
+            .rev()
+        {
+            table.add_row(row![
+                device_name(serial_number),
+                Local
+                    .timestamp_opt(record.time, 0)
+                    .single()
+                    .map_or_else(|| record.time.to_string(), |t| t.to_string()),
+                match record.direction {
+                    TransferDirection::Download => "download",
+                    TransferDirection::Upload => "upload",
+                },
+                humansize::format_size(record.size, humansize::BINARY),
+                format!("{:08x}", record.crc32)
+            ]);
+        }
+    }
+
+    if table.is_empty() {
+        info!("No transfers of {} were recorded", filename);
+    } else {
+        info!("Transfers of {} (most recent first):\n{}", filename, table);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,221 @@
+//! The HTTP client used for everything that goes online (MGA data, firmware updates)
+//!
+//! It's set up once from the `[http]` section of the config, see [configure]. The requests failing for the reasons
+//! that usually go away by themselves (timeouts, dropped connections, 5xx responses) are retried a few times, waiting
+//! longer after each attempt.
+
+use crate::config::HttpConfig;
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use once_cell::sync::OnceCell;
+use reqwest::{Certificate, StatusCode, Url};
+use std::time::Duration;
+use thiserror::Error;
+use tracing::{debug, warn};
+
+static CLIENT: OnceCell<HttpClient> = OnceCell::new();
+
+/// The wait before the first retry, doubled after each one
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+pub fn default_user_agent() -> String {
+    format!(
+        "f-xoss-util/{} (+https://github.com/DCNick3/f-xoss)",
+        env!("CARGO_PKG_VERSION")
+    )
+}
+
+/// The URL as shown in the errors, the query is left out as it may carry tokens
+fn without_query(url: &Url) -> &str {
+    &url[..url::Position::AfterPath]
+}
+
+#[derive(Error, Debug)]
+pub enum HttpError {
+    #[error("Invalid endpoint override {replacement:?} for {prefix:?}")]
+    InvalidEndpoint {
+        prefix: String,
+        replacement: String,
+        #[source]
+        source: url::ParseError,
+    },
+    #[error("Timed out requesting {}", without_query(url))]
+    Timeout { url: Url },
+    #[error("Failed to request {}", without_query(url))]
+    Request {
+        url: Url,
+        #[source]
+        source: reqwest::Error,
+    },
+    #[error("{} responded with {status}", without_query(url))]
+    Status {
+        url: Url,
+        status: StatusCode,
+        /// The response body, servers often explain the error there
+        body: Bytes,
+    },
+}
+
+impl HttpError {
+    fn request(url: Url, source: reqwest::Error) -> Self {
+        if source.is_timeout() {
+            Self::Timeout { url }
+        } else {
+            Self::Request {
+                url,
+                source: source.without_url(),
+            }
+        }
+    }
+
+    /// Whether the same request has a chance to succeed if made again
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::InvalidEndpoint { .. } => false,
+            Self::Timeout { .. } => true,
+            Self::Request { source, .. } => !source.is_builder() && !source.is_redirect(),
+            Self::Status { status, .. } => {
+                status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+            }
+        }
+    }
+}
+
+pub struct HttpClient {
+    client: reqwest::Client,
+    /// How many times a transiently failed request is repeated
+    retries: u32,
+    /// URL prefixes and their replacements, the longest prefixes first
+    endpoints: Vec<(String, String)>,
+}
+
+impl HttpClient {
+    pub fn new(config: &HttpConfig) -> Result<Self> {
+        let user_agent = config.user_agent.clone().unwrap_or_else(default_user_agent);
+        let mut builder = reqwest::Client::builder()
+            .user_agent(user_agent)
+            .timeout(config.timeout())
+            .use_rustls_tls();
+
+        if let Some(path) = &config.ca_certificates {
+            let pem = std::fs::read(path)
+                .with_context(|| format!("Reading CA certificates {}", path.display()))?;
+            let certificates = Certificate::from_pem_bundle(&pem)
+                .with_context(|| format!("Failed to parse CA certificates {}", path.display()))?;
+            if certificates.is_empty() {
+                bail!("No valid CA certificates found in {}", path.display());
+            }
+            debug!(
+                "Loaded {} CA certificates from {}",
+                certificates.len(),
+                path.display()
+            );
+            for certificate in certificates {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+
+        let client = builder.build().context("Creating the HTTP client")?;
+
+        let mut endpoints = config
+            .endpoints
+            .iter()
+            .map(|(prefix, replacement)| (prefix.clone(), replacement.clone()))
+            .collect::<Vec<_>>();
+        endpoints.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+
+        Ok(Self {
+            client,
+            retries: config.retries(),
+            endpoints,
+        })
+    }
+
+    /// Apply the endpoint overrides to the URL
+    pub fn rewrite(&self, url: &Url) -> Result<Url, HttpError> {
+        let url_str = url.as_str();
+        let Some((prefix, replacement)) = self
+            .endpoints
+            .iter()
+            .find(|(prefix, _)| url_str.starts_with(prefix.as_str()))
+        else {
+            return Ok(url.clone());
+        };
+
+        let rewritten = format!("{}{}", replacement, &url_str[prefix.len()..]);
+        debug!("Using {} instead of {}", rewritten, url);
+        Url::parse(&rewritten).map_err(|source| HttpError::InvalidEndpoint {
+            prefix: prefix.clone(),
+            replacement: replacement.clone(),
+            source,
+        })
+    }
+
+    async fn get_once(&self, url: &Url) -> Result<Bytes, HttpError> {
+        debug!("GET {}", without_query(url));
+        let response = self
+            .client
+            .get(url.clone())
+            .send()
+            .await
+            .map_err(|e| HttpError::request(url.clone(), e))?;
+        let status = response.status();
+        debug!("{} responded with {}", without_query(url), status);
+
+        let body = response
+            .bytes()
+            .await
+            .map_err(|e| HttpError::request(url.clone(), e))?;
+        if !status.is_success() {
+            return Err(HttpError::Status {
+                url: url.clone(),
+                status,
+                body,
+            });
+        }
+
+        Ok(body)
+    }
+
+    /// Download the body of a successful response, retrying the transient failures
+    pub async fn get(&self, url: &Url) -> Result<Bytes, HttpError> {
+        let url = self.rewrite(url)?;
+
+        let mut backoff = INITIAL_BACKOFF;
+        let mut attempt = 0;
+        loop {
+            match self.get_once(&url).await {
+                Err(e) if e.is_transient() && attempt < self.retries => {
+                    attempt += 1;
+                    warn!(
+                        "{:#}, retrying in {}s ({}/{})",
+                        anyhow::Error::from(e),
+                        backoff.as_secs(),
+                        attempt,
+                        self.retries
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Set up the shared client, has to be called before the first [client] call to have any effect
+pub fn configure(config: &HttpConfig) -> Result<()> {
+    let client = HttpClient::new(config).context("Setting up the HTTP client")?;
+    if CLIENT.set(client).is_err() {
+        bail!("The HTTP client is already set up");
+    }
+    Ok(())
+}
+
+/// The shared client, with the default settings if [configure] wasn't called
+pub fn client() -> &'static HttpClient {
+    CLIENT.get_or_init(|| {
+        HttpClient::new(&HttpConfig::default()).expect("The default HTTP client should be valid")
+    })
+}
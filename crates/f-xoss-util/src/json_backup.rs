@@ -0,0 +1,85 @@
+//! Local copies of the device JSON files, kept before they are overwritten
+//!
+//! Every write of a config file (like `settings.json`) keeps its previous content in the cache directory, one directory
+//! per device. A write that went wrong can be rolled back with `dev restore-json`.
+
+use anyhow::{Context, Result};
+use chrono::Local;
+use std::path::{Path, PathBuf};
+use tracing::{debug, warn};
+
+use f_xoss::device::XossDevice;
+
+/// How many copies of each file to keep
+const MAX_BACKUPS_PER_FILE: usize = 10;
+
+pub fn backup_dir(serial_number: &str) -> PathBuf {
+    crate::config::APP_DIRS
+        .cache_dir()
+        .join("json-backups")
+        .join(serial_number)
+}
+
+/// Keep the JSON files of the device before they are overwritten from now on
+pub async fn back_up_json_files(device: &XossDevice) {
+    let serial_number = device.device_info().serial_number.clone();
+
+    device.set_json_backup(Box::new(move |filename, content| {
+        save_backup(&serial_number, filename, content).map(|_| ())
+    }));
+}
+
+/// The device file a backup is of, like `settings.json` for `settings.20230617-120000-000.json`
+pub fn backed_up_file(path: &Path) -> Option<String> {
+    let name = path.file_name()?.to_str()?;
+    let (stem, _) = name.strip_suffix(".json")?.split_once('.')?;
+    Some(format!("{}.json", stem))
+}
+
+pub fn save_backup(serial_number: &str, filename: &str, content: &[u8]) -> Result<PathBuf> {
+    let dir = backup_dir(serial_number);
+    std::fs::create_dir_all(&dir).context("Creating the backup directory")?;
+
+    let stem = filename.strip_suffix(".json").unwrap_or(filename);
+    let path = dir.join(format!(
+        "{}.{}.json",
+        stem,
+        Local::now().format("%Y%m%d-%H%M%S-%3f")
+    ));
+    std::fs::write(&path, content).with_context(|| format!("Writing {}", path.display()))?;
+    debug!("Backed up {} to {}", filename, path.display());
+
+    let backups = list_backups(serial_number, filename)?;
+    if backups.len() > MAX_BACKUPS_PER_FILE {
+        for old in &backups[..backups.len() - MAX_BACKUPS_PER_FILE] {
+            // the old copies are only taking space, it's not worth failing the write
+            if let Err(e) = std::fs::remove_file(old) {
+                warn!("Failed to remove the old backup {}: {}", old.display(), e);
+            }
+        }
+    }
+
+    Ok(path)
+}
+
+/// The backups of a device file, the oldest first
+pub fn list_backups(serial_number: &str, filename: &str) -> Result<Vec<PathBuf>> {
+    let dir = backup_dir(serial_number);
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Listing {}", dir.display())),
+    };
+
+    let mut backups = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if backed_up_file(&path).as_deref() == Some(filename) {
+            backups.push(path);
+        }
+    }
+    // the timestamps in the names sort in time order
+    backups.sort();
+
+    Ok(backups)
+}
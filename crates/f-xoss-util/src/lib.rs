@@ -0,0 +1,20 @@
+#[cfg(unix)]
+pub mod agent;
+pub mod cli;
+pub mod config;
+#[cfg(feature = "demo")]
+pub mod demo;
+pub mod export;
+pub mod firmware;
+pub mod history;
+pub mod http;
+pub mod json_backup;
+pub mod locate_util;
+pub mod mga;
+pub mod mqtt;
+pub mod pairing;
+pub mod progress;
+pub mod recording;
+pub mod secrets;
+pub mod state;
+pub mod workout_index;
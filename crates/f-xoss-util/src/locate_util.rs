@@ -1,24 +1,80 @@
-use std::pin::Pin;
 use std::time::Duration;
 
-use crate::config::XossUtilConfig;
-use anyhow::{bail, Context, Result};
-use btleplug::api::{BDAddr, Central, CentralEvent, Manager as _, Peripheral as _, ScanFilter};
+use crate::cli::DIALOGUER_THEME;
+use crate::config;
+use crate::config::{TimeoutsConfig, XossDeviceInfo, XossUtilConfig};
+use anyhow::{anyhow, bail, Context, Result};
+use btleplug::api::{BDAddr, Central, Manager as _, Peripheral as _};
 use btleplug::platform::{Adapter, Manager, Peripheral};
 use f_xoss::device::XossDevice;
+use f_xoss::scan::{scan_devices, DiscoveredDevice, ScanOptions};
+use f_xoss::transport::session::RecordingPeripheral;
+use std::ops::Deref;
+use thiserror::Error;
 use tokio::select;
-use tokio_stream::{Stream, StreamExt};
+use tokio_stream::StreamExt;
 use tracing::{info, info_span, instrument, warn};
 use tracing_futures::Instrument;
 
-pub async fn find_adapter(manager: &Manager) -> Result<Adapter> {
+#[derive(Error, Debug)]
+pub enum LocateError {
+    #[error("Cannot connect to device without a config")]
+    NoConfig,
+    #[error("Could not find {0}")]
+    DeviceNotFound(String),
+}
+
+/// Things to check when no device can be found, depending on the platform
+pub fn troubleshooting_hints() -> String {
+    #[cfg(target_os = "linux")]
+    const PLATFORM_HINT: &str = "the bluetooth service is running (`systemctl status bluetooth`), the adapter is powered on (`bluetoothctl power on`) and your user is allowed to use BlueZ over D-Bus";
+    #[cfg(target_os = "macos")]
+    const PLATFORM_HINT: &str = "Bluetooth is on and your terminal is allowed to use it (System Settings > Privacy & Security > Bluetooth)";
+    #[cfg(target_os = "windows")]
+    const PLATFORM_HINT: &str = "Bluetooth is turned on (Settings > Bluetooth & devices)";
+    #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+    const PLATFORM_HINT: &str = "Bluetooth is turned on";
+
+    format!(
+        "Make sure that the device is turned on, is nearby and is not connected to another app (like the official one), and that {}",
+        PLATFORM_HINT
+    )
+}
+
+/// Find the Bluetooth adapter to use
+///
+/// If `selector` is set, the first adapter whose info string (as shown in the error message) contains it is used.
+/// Otherwise, the first adapter is used.
+pub async fn find_adapter(manager: &Manager, selector: Option<&str>) -> Result<Adapter> {
     let adapter_list = manager.adapters().await.context("Listing adapters")?;
-    let adapter_count = adapter_list.len();
 
-    let result = adapter_list
-        .into_iter()
-        .next()
-        .context("No Bluetooth adapters found")?;
+    if adapter_list.is_empty() {
+        bail!("No Bluetooth adapters found. {}", troubleshooting_hints());
+    }
+
+    if let Some(selector) = selector {
+        let mut infos = Vec::new();
+        for adapter in adapter_list {
+            let info = adapter
+                .adapter_info()
+                .await
+                .context("Failed to get adapter info")?;
+            if info.contains(selector) {
+                info!("Using Bluetooth adapter {}", info);
+                return Ok(adapter);
+            }
+            infos.push(info);
+        }
+
+        bail!(
+            "No Bluetooth adapter matches {:?}, available adapters: {}",
+            selector,
+            infos.join(", ")
+        );
+    }
+
+    let adapter_count = adapter_list.len();
+    let result = adapter_list.into_iter().next().unwrap();
 
     if adapter_count > 1 {
         let info = result
@@ -27,7 +83,7 @@ pub async fn find_adapter(manager: &Manager) -> Result<Adapter> {
             .context("Failed to get adapter info")?;
 
         warn!(
-            "More than one Bluetooth adapter found, using the first one: {}",
+            "More than one Bluetooth adapter found, using the first one: {}. Use --adapter to select a different one",
             info
         );
     }
@@ -37,103 +93,91 @@ pub async fn find_adapter(manager: &Manager) -> Result<Adapter> {
 
 #[instrument(skip(adapter))]
 async fn find_ble_peripheral(adapter: &Adapter, ble_addr: BDAddr) -> Result<Option<Peripheral>> {
-    let events = adapter.events().await?;
-
-    async fn find_inner(
-        adapter: &Adapter,
-        mut events: Pin<Box<dyn Stream<Item = CentralEvent> + Send>>,
-        ble_addr: BDAddr,
-    ) -> Result<Option<Peripheral>> {
-        while let Some(event) = events.next().await {
-            if let CentralEvent::DeviceDiscovered(id) = event {
-                let p = adapter
-                    .peripheral(&id)
-                    .await
-                    .context("Failed to get the discovered peripheral")?;
-
-                let address = p
-                    .properties()
-                    .await
-                    .context("Failed to get peripheral properties")?
-                    .context("No peripheral properties")?
-                    .address;
-
-                if address == ble_addr {
-                    return Ok(Some(p));
-                }
+    info!("Starting scan for {}", ble_addr);
+    let results = scan_devices(adapter, ScanOptions::default()).await?;
+
+    let find = async {
+        tokio::pin!(results);
+        while let Some(device) = results.next().await {
+            let device = device?;
+            if device.address == ble_addr {
+                return Ok(Some(device.peripheral));
             }
         }
 
-        warn!("The event stream ended before the device was found");
+        warn!("The scan ended before the device was found");
 
         Ok(None)
-    }
-
-    info!("Starting scan for {}", ble_addr);
-    adapter
-        .start_scan(ScanFilter::default())
-        .await
-        .context("Failed to start scan")?;
-
-    let timeout = tokio::time::sleep(Duration::from_secs(10));
-    let find = find_inner(adapter, events, ble_addr);
+    };
 
-    let result = select! {
-        _ = timeout => {
+    select! {
+        _ = tokio::time::sleep(Duration::from_secs(10)) => {
             warn!("Timeout while waiting for the device to be found");
             Ok(None)
         }
         result = find => result,
-    };
-
-    adapter.stop_scan().await.context("Failed to stop scan")?;
-
-    result
+    }
 }
 
-pub async fn find_device_from_config(config: &Option<XossUtilConfig>) -> Result<XossDevice> {
-    // TODO: accept cli options allowing to specify the device from cli
-    let Some(config) = config.as_ref() else {
-        bail!("Cannot connect to device without a config")
-    };
-
-    let [device_info] = config.devices.as_slice() else {
-        bail!("Only exactly one device in config is supported for now")
-    };
+async fn connect_peripheral(
+    peripheral: &Peripheral,
+    device_info: &XossDeviceInfo,
+    timeouts: &TimeoutsConfig,
+) -> Result<XossDevice> {
+    let pair = device_info.pair;
+    if pair {
+        crate::pairing::pair(peripheral)
+            .instrument(info_span!("ble_pair"))
+            .await
+            .context("Failed to pair with the device")?;
+    }
 
-    info!("Will try to connect to {}", device_info.identify());
+    peripheral
+        .connect()
+        .instrument(info_span!("ble_connect"))
+        .await
+        .context("Failed to connect to device")?;
 
-    let peripheral_id = &device_info.peripheral_id;
+    let options = device_info.transport_options(timeouts);
+    match crate::recording::start() {
+        Some(recording) => {
+            XossDevice::with_options(
+                RecordingPeripheral::new(peripheral.clone(), recording),
+                options,
+            )
+            .await
+        }
+        None => XossDevice::with_options(peripheral.clone(), options).await,
+    }
+    .context("Failed to initialize connection to a XOSS device")
+    .map_err(|e| crate::pairing::with_hint(e, pair))
+}
 
-    let manager = Manager::new().await.context("Failed to create a manager")?;
-    let adapter = find_adapter(&manager) // TODO: allow specifying adapter in config/cli
-        .await
-        .context("Failed to find adapter")?;
+/// Apply the per-device settings from the config to a connected device
+fn configure_device(device: &XossDevice, device_info: &XossDeviceInfo) -> Result<()> {
+    if let Some(version) = &device_info.json_protocol {
+        device
+            .set_json_protocol(version)
+            .context("Invalid json_protocol in the device config")?;
+    }
+    Ok(())
+}
 
+async fn connect_with_retries(
+    peripheral: &Peripheral,
+    device_info: &XossDeviceInfo,
+    timeouts: &TimeoutsConfig,
+) -> Result<XossDevice> {
     const MAX_RECONNECTION_ATTEMPTS: usize = 3;
     for attempt in 0..=MAX_RECONNECTION_ATTEMPTS {
-        let attempt_result = async {
-            let peripheral = adapter
-                .peripheral(peripheral_id)
-                .await
-                .context("Failed to get peripheral")?;
-
-            peripheral
-                .connect()
-                .instrument(info_span!("ble_connect"))
-                .await
-                .context("Failed to connect to device")?;
-
-            XossDevice::new(peripheral)
-                .await
-                .context("Failed to initialize connection to a XOSS device")
-        }
-        .instrument(info_span!("connect_attempt", attempt = attempt + 1))
-        .await;
+        let attempt_result = connect_peripheral(peripheral, device_info, timeouts)
+            .instrument(info_span!("connect_attempt", attempt = attempt + 1))
+            .await;
 
         match attempt_result {
             Ok(device) => {
                 info!("Connected to {}", device_info.identify());
+                configure_device(&device, device_info)?;
                 return Ok(device);
             }
             Err(e) => {
@@ -153,3 +197,413 @@ pub async fn find_device_from_config(config: &Option<XossUtilConfig>) -> Result<
 
     bail!("Failed to connect to {}", device_info.identify())
 }
+
+/// Collect the peripherals that might be the configured device, judging by their advertisement data
+#[instrument(skip(adapter, device_info, timeouts), fields(device = %device_info.identify()))]
+async fn scan_for_candidates(
+    adapter: &Adapter,
+    device_info: &XossDeviceInfo,
+    timeouts: &TimeoutsConfig,
+) -> Result<Vec<DiscoveredDevice>> {
+    info!(
+        "Scanning for devices that look like {}",
+        device_info.identify()
+    );
+    let results = scan_devices(adapter, ScanOptions::default()).await?;
+
+    let mut candidates = Vec::<DiscoveredDevice>::new();
+    let collect = async {
+        tokio::pin!(results);
+        while let Some(device) = results.next().await {
+            let device = device?;
+
+            let name_matches = match (&device.name, &device_info.name) {
+                (Some(name), Some(configured_name)) => name == configured_name,
+                _ => false,
+            };
+
+            if name_matches || device.likely_xoss || device_info.address == Some(device.address) {
+                // a device is reported again when its name changes
+                candidates.retain(|c| c.id != device.id);
+                candidates.push(device);
+            }
+        }
+
+        Ok::<_, anyhow::Error>(())
+    };
+
+    select! {
+        _ = tokio::time::sleep(timeouts.scan()) => {},
+        result = collect => result?,
+    };
+
+    Ok(candidates)
+}
+
+/// Scan for the configured devices, returning the ones seen nearby
+///
+/// The devices are recognized by their peripheral id or their address. Whether it's really the configured device is
+/// checked by [connect_configured_device] when connecting.
+pub async fn scan_configured_devices<'a>(
+    adapter: &Adapter,
+    devices: &[&'a XossDeviceInfo],
+    timeouts: &TimeoutsConfig,
+) -> Result<Vec<(&'a XossDeviceInfo, Peripheral)>> {
+    let results = scan_devices(adapter, ScanOptions::default()).await?;
+
+    let mut seen = Vec::<(&XossDeviceInfo, Peripheral)>::new();
+    let collect = async {
+        tokio::pin!(results);
+        while let Some(device) = results.next().await {
+            let device = device?;
+
+            let Some(&device_info) = devices
+                .iter()
+                .find(|d| d.peripheral_id == device.id || d.address == Some(device.address))
+            else {
+                continue;
+            };
+            if seen.iter().all(|(d, _)| *d != device_info) {
+                seen.push((device_info, device.peripheral));
+            }
+            if seen.len() == devices.len() {
+                break;
+            }
+        }
+
+        Ok::<_, anyhow::Error>(())
+    };
+
+    select! {
+        _ = tokio::time::sleep(timeouts.scan()) => {},
+        result = collect => result?,
+    };
+
+    Ok(seen)
+}
+
+/// Connect to a configured device that was seen nearby, checking that it reports the configured serial number
+pub async fn connect_configured_device(
+    peripheral: &Peripheral,
+    device_info: &XossDeviceInfo,
+    timeouts: &TimeoutsConfig,
+) -> Result<XossDevice> {
+    let device = connect_with_retries(peripheral, device_info, timeouts).await?;
+
+    let serial_number = device.device_info().serial_number.clone();
+    if let Some(expected_serial_number) = &device_info.serial_number {
+        if &serial_number != expected_serial_number {
+            if let Err(e) = device.disconnect().await {
+                warn!(
+                    "Failed to disconnect from {}: {:#}",
+                    device_info.identify(),
+                    e
+                );
+            }
+            bail!(
+                "Connected to {}, but it reports a different serial number ({}, expected {}). Re-run setup if the device was replaced",
+                device_info.identify(),
+                serial_number,
+                expected_serial_number
+            );
+        }
+    }
+
+    Ok(device)
+}
+
+/// Wait for a device in the DFU mode to show up
+///
+/// After rebooting into the bootloader the device uses a different name ("DfuTarg") and usually a different address,
+/// so it's recognized by the advertised DFU service.
+#[instrument(skip(adapter))]
+pub async fn find_dfu_target(adapter: &Adapter) -> Result<Peripheral> {
+    info!("Waiting for the device to appear in the DFU mode");
+    let results = scan_devices(
+        adapter,
+        ScanOptions {
+            only_xoss: true,
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    let find = async {
+        tokio::pin!(results);
+        while let Some(device) = results.next().await {
+            let device = device?;
+            if device.in_dfu_mode {
+                info!("Found a device in the DFU mode: {}", device.address);
+                return Ok(device.peripheral);
+            }
+        }
+
+        bail!("The scan ended before the device was found")
+    };
+
+    select! {
+        _ = tokio::time::sleep(Duration::from_secs(30)) => Err(anyhow!("No device in the DFU mode found")),
+        result = find => result,
+    }
+}
+
+/// Look for the configured device under a different name or address
+///
+/// The candidates are connected to one by one and are accepted only if the serial number matches the one in the config.
+/// If the config doesn't have a serial number (it was created by an older version), only the address is trusted.
+async fn find_relocated_device(
+    adapter: &Adapter,
+    device_info: &XossDeviceInfo,
+    timeouts: &TimeoutsConfig,
+) -> Result<Option<(XossDevice, XossDeviceInfo)>> {
+    let candidates = scan_for_candidates(adapter, device_info, timeouts).await?;
+    if candidates.is_empty() {
+        warn!("No XOSS devices were seen nearby");
+    }
+
+    for candidate in candidates {
+        if device_info.serial_number.is_none() && device_info.address != Some(candidate.address) {
+            info!(
+                "Seen {} ({}), but can't tell whether it's the configured device. Re-run setup if it is",
+                candidate.name.as_deref().unwrap_or("unnamed"),
+                candidate.address
+            );
+            continue;
+        }
+
+        info!("Checking {}", candidate.address);
+        let device = match connect_peripheral(&candidate.peripheral, device_info, timeouts).await {
+            Ok(device) => device,
+            Err(e) => {
+                warn!("Failed to connect to {}: {:#}", candidate.address, e);
+                continue;
+            }
+        };
+
+        let serial_number = device.device_info().serial_number.clone();
+        if let Some(expected_serial_number) = &device_info.serial_number {
+            if &serial_number != expected_serial_number {
+                info!(
+                    "{} has a different serial number ({}), skipping it",
+                    candidate.address, serial_number
+                );
+                if let Err(e) = device.disconnect().await {
+                    warn!("Failed to disconnect from {}: {:#}", candidate.address, e);
+                }
+                continue;
+            }
+        }
+
+        let new_info = XossDeviceInfo {
+            name: candidate.name.or_else(|| device_info.name.clone()),
+            peripheral_id: candidate.id,
+            address: Some(candidate.address),
+            serial_number: Some(serial_number),
+            pair: device_info.pair,
+            json_protocol: device_info.json_protocol.clone(),
+            battery_fallback: device_info.battery_fallback,
+        };
+        configure_device(&device, &new_info)?;
+
+        return Ok(Some((device, new_info)));
+    }
+
+    Ok(None)
+}
+
+/// Ask the user whether the changed device info should be saved to the config
+fn offer_config_update(
+    config: &XossUtilConfig,
+    old_info: &XossDeviceInfo,
+    new_info: &XossDeviceInfo,
+) -> Result<()> {
+    if old_info == new_info {
+        return Ok(());
+    }
+
+    if old_info.name != new_info.name {
+        info!(
+            "The device name has changed: {:?} -> {:?}",
+            old_info.name, new_info.name
+        );
+    }
+    if old_info.peripheral_id != new_info.peripheral_id || old_info.address != new_info.address {
+        info!(
+            "The device address has changed: {} -> {}",
+            old_info
+                .address
+                .map_or_else(|| old_info.peripheral_id.to_string(), |a| a.to_string()),
+            new_info
+                .address
+                .map_or_else(|| new_info.peripheral_id.to_string(), |a| a.to_string()),
+        );
+    }
+
+    if !console::user_attended() {
+        warn!(
+            "Not running interactively, the config will not be updated. Re-run setup to update it"
+        );
+        return Ok(());
+    }
+
+    let confirm = dialoguer::Confirm::with_theme(DIALOGUER_THEME.deref())
+        .with_prompt("Do you want to update the config entry for this device?")
+        .default(true)
+        .interact()
+        .context("Failed to get user confirmation")?;
+
+    if confirm {
+        let mut new_config = config.clone();
+        for device in new_config.devices.iter_mut() {
+            if device == old_info {
+                *device = new_info.clone();
+            }
+        }
+        config::save_config(&new_config)?;
+    }
+
+    Ok(())
+}
+
+fn find_configured_device<'a>(
+    config: &'a XossUtilConfig,
+    selector: &str,
+) -> Result<&'a XossDeviceInfo> {
+    let matching = config
+        .devices
+        .iter()
+        .filter(|d| d.matches(selector))
+        .collect::<Vec<_>>();
+
+    match matching.as_slice() {
+        [] => bail!("No configured device matches {:?}", selector),
+        [device_info] => Ok(device_info),
+        _ => bail!(
+            "More than one configured device matches {:?}, use the serial number to select one",
+            selector
+        ),
+    }
+}
+
+/// Decide which of the configured devices to connect to
+///
+/// The device selected on the command line takes precedence over the default one from the config.
+/// If neither is set and there are several devices, the user is asked to pick one.
+pub fn select_configured_device<'a>(
+    config: &'a XossUtilConfig,
+    selector: Option<&str>,
+) -> Result<&'a XossDeviceInfo> {
+    if let Some(selector) = selector.or(config.default_device.as_deref()) {
+        return find_configured_device(config, selector);
+    }
+
+    match config.devices.as_slice() {
+        [] => bail!("No devices configured, run setup first"),
+        [device_info] => Ok(device_info),
+        devices => {
+            if !console::user_attended() {
+                bail!("Several devices are configured, select one with --device or set default_device in the config");
+            }
+
+            let state = crate::state::load_state()?;
+            let items = devices
+                .iter()
+                .map(|d| format!("{} ({})", d.identify(), state.describe_device(d)))
+                .collect::<Vec<_>>();
+
+            let index = dialoguer::Select::with_theme(DIALOGUER_THEME.deref())
+                .with_prompt("Select a device")
+                .items(&items)
+                .default(0)
+                .interact()
+                .context("Failed to select a device")?;
+
+            Ok(&devices[index])
+        }
+    }
+}
+
+pub async fn find_device_from_config(
+    config: &Option<XossUtilConfig>,
+    selector: Option<&str>,
+    adapter: Option<&str>,
+) -> Result<XossDevice> {
+    let Some(config) = config.as_ref() else {
+        return Err(LocateError::NoConfig.into());
+    };
+
+    let device_info = select_configured_device(config, selector)?;
+
+    info!("Will try to connect to {}", device_info.identify());
+
+    let peripheral_id = &device_info.peripheral_id;
+
+    let manager = Manager::new().await.context("Failed to create a manager")?;
+    let adapter = find_adapter(&manager, adapter)
+        .await
+        .context("Failed to find adapter")?;
+
+    let connect_result = match adapter.peripheral(peripheral_id).await {
+        Ok(peripheral) => connect_with_retries(&peripheral, device_info, &config.timeouts)
+            .await
+            .map(|device| (device, peripheral)),
+        Err(e) => Err(anyhow!(e).context("Device not found")),
+    };
+
+    let (device, new_info) = match connect_result {
+        Ok((device, peripheral)) => {
+            let properties = peripheral
+                .properties()
+                .await
+                .context("Failed to get peripheral properties")?;
+            let serial_number = device.device_info().serial_number.clone();
+
+            if let Some(expected_serial_number) = &device_info.serial_number {
+                if &serial_number != expected_serial_number {
+                    bail!(
+                        "Connected to {}, but it reports a different serial number ({}, expected {}). Re-run setup if the device was replaced",
+                        device_info.identify(),
+                        serial_number,
+                        expected_serial_number
+                    );
+                }
+            }
+
+            let new_info = XossDeviceInfo {
+                name: properties
+                    .as_ref()
+                    .and_then(|p| p.local_name.clone())
+                    .or_else(|| device_info.name.clone()),
+                address: properties
+                    .as_ref()
+                    .map(|p| p.address)
+                    .or(device_info.address),
+                serial_number: Some(serial_number),
+                ..device_info.clone()
+            };
+
+            (device, new_info)
+        }
+        Err(e) => {
+            warn!("{:#}", e);
+            info!("The device might have changed its name or address, looking for it");
+
+            let Some((device, new_info)) =
+                find_relocated_device(&adapter, device_info, &config.timeouts).await?
+            else {
+                return Err(e.context(LocateError::DeviceNotFound(device_info.identify())));
+            };
+            info!(
+                "Found {} as {}",
+                device_info.identify(),
+                new_info.identify()
+            );
+
+            (device, new_info)
+        }
+    };
+
+    offer_config_update(config, device_info, &new_info)?;
+
+    Ok(device)
+}
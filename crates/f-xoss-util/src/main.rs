@@ -1,40 +1,152 @@
-mod cli;
-mod config;
-mod locate_util;
-mod mga;
+#[cfg(unix)]
+use f_xoss_util::agent;
+#[cfg(feature = "demo")]
+use f_xoss_util::demo;
+use f_xoss_util::{cli, config, http, secrets};
 
 use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
 use clap::Parser;
+use f_xoss::json_protocol::UnsupportedJsonVersion;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Mutex;
 
-use tracing::info;
+use tracing::{info, warn};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
 use tracing_indicatif::IndicatifLayer;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
-use tracing_subscriber::Layer;
+use tracing_subscriber::{EnvFilter, Layer};
 
 const DEFAULT_ENV_FILTER: &str = "info";
 // const DEFAULT_ENV_FILTER: &str = "debug";
 
+/// What goes into the `--debug-dump` file, regardless of `RUST_LOG`
+///
+/// The hex dumps of the BLE traffic are logged by the library at the trace level
+const DEBUG_DUMP_ENV_FILTER: &str = "debug,f_xoss=trace";
+
+fn env_filter() -> EnvFilter {
+    EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_ENV_FILTER))
+}
+
+fn create_debug_dump(path: &Utf8Path) -> Result<(Utf8PathBuf, File)> {
+    let path = if path.is_dir() {
+        path.join(format!(
+            "f-xoss-dump-{}.log",
+            chrono::Local::now().format("%Y%m%d-%H%M%S")
+        ))
+    } else {
+        path.to_path_buf()
+    };
+
+    let file = File::create(&path).with_context(|| format!("Creating debug dump {}", path))?;
+
+    Ok((path, file))
+}
+
+/// Daily rotating files in `dir`, only the last `max_files` of them are kept
+fn create_log_file(
+    dir: &Path,
+    config: &config::LogConfig,
+) -> Result<(RollingFileAppender, EnvFilter)> {
+    let filter = EnvFilter::try_new(config.filter())
+        .with_context(|| format!("Invalid log.filter in the config: {}", config.filter()))?;
+    let appender = RollingFileAppender::builder()
+        .rotation(Rotation::DAILY)
+        .filename_prefix("f-xoss-util")
+        .filename_suffix("log")
+        .max_log_files(config.max_files())
+        .build(dir)
+        .with_context(|| format!("Creating the log files in {}", dir.display()))?;
+
+    Ok((appender, filter))
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     #[cfg(windows)]
     let _enabled = ansi_term::enable_ansi_support();
 
+    let cli = cli::Cli::parse();
+
+    let (debug_dump_path, debug_dump_file) = cli
+        .debug_dump
+        .as_deref()
+        .map(create_debug_dump)
+        .transpose()
+        .context("Failed to create the debug dump file")?
+        .unzip();
+
+    if cli.is_demo() {
+        config::enable_demo_mode();
+    }
+
+    // loaded before the logging is set up, the config says where the log files go
+    // until then, the messages (like the ones about migrating the config) only go to stderr
+    let mut config = tracing::subscriber::with_default(
+        tracing_subscriber::fmt()
+            .with_writer(std::io::stderr)
+            .with_env_filter(env_filter())
+            .finish(),
+        config::load_config,
+    )
+    .context("Failed to load the config")?;
+    let log_config = config
+        .as_ref()
+        .map(|config| config.log.clone())
+        .unwrap_or_default();
+    let log_dir = match cli.log_file.as_deref() {
+        Some(dir) if !dir.as_str().is_empty() => Some(dir.as_std_path().to_path_buf()),
+        Some(_) => Some(log_config.dir()),
+        None => log_config.enabled.then(|| log_config.dir()),
+    };
+    let log_file = log_dir
+        .map(|dir| create_log_file(&dir, &log_config))
+        .transpose()
+        .context("Failed to set up the log files")?;
+
     let indicatif_layer = IndicatifLayer::new();
 
-    tracing_subscriber::EnvFilter::try_from_default_env()
-        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new(DEFAULT_ENV_FILTER))
-        .with_subscriber(
-            tracing_subscriber::registry()
-                .with(
-                    tracing_subscriber::fmt::layer()
-                        .with_writer(indicatif_layer.get_stderr_writer()),
-                )
-                .with(indicatif_layer),
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_writer(indicatif_layer.get_stderr_writer())
+                .with_filter(env_filter()),
         )
+        .with(indicatif_layer.with_filter(env_filter()))
+        .with({
+            #[cfg(unix)]
+            let layer = Some(agent::ForwardLayer.with_filter(env_filter()));
+            #[cfg(not(unix))]
+            let layer: Option<tracing_subscriber::layer::Identity> = None;
+            layer
+        })
+        .with(log_file.map(|(appender, filter)| {
+            tracing_subscriber::fmt::layer()
+                .json()
+                .with_writer(appender)
+                .with_filter(filter)
+        }))
+        .with(debug_dump_file.map(|file| {
+            tracing_subscriber::fmt::layer()
+                .with_writer(Mutex::new(file))
+                .with_ansi(false)
+                .with_filter(EnvFilter::new(DEBUG_DUMP_ENV_FILTER))
+        }))
         .init();
 
-    let config = config::load_config().context("Failed to load the config")?;
+    if let Some(path) = &debug_dump_path {
+        info!("Recording the debug dump to {}", path);
+        tracing::debug!(
+            "f-xoss-util {} ({} {}), args: {:?}",
+            env!("CARGO_PKG_VERSION"),
+            std::env::consts::OS,
+            std::env::consts::ARCH,
+            secrets::redacted_args(std::env::args())
+        );
+    }
 
     match config {
         None => info!(
@@ -47,9 +159,37 @@ async fn main() -> Result<()> {
         ),
     }
 
-    let cli = cli::Cli::parse();
+    if let Some(config) = config.as_mut().filter(|_| !cli.is_demo()) {
+        if let Err(e) = secrets::move_to_keyring(config) {
+            warn!("Failed to move the secrets to the system keyring: {:#}", e);
+        }
+    }
+
+    #[cfg(feature = "demo")]
+    if cli.demo {
+        info!("Demo mode: running against a simulated device, nothing is sent over Bluetooth");
+        config.get_or_insert_with(demo::config);
+    }
 
-    cli.run(config).await?;
+    if let Some(config) = &config {
+        // fail early on a bad time zone, rather than in the middle of a sync
+        config.time_zone()?;
+        http::configure(&config.http)?;
+    }
+
+    if let Err(e) = cli.run(config).await {
+        if e.downcast_ref::<cli::Interrupted>().is_some() {
+            // the conventional exit code for SIGINT
+            std::process::exit(130);
+        }
+        if e.chain().any(|e| e.is::<UnsupportedJsonVersion>()) {
+            return Err(e.context(
+                "Set json_protocol for the device in the config to use it anyway, and please report the device \
+                 model and the firmware version",
+            ));
+        }
+        return Err(e);
+    }
 
     Ok(())
 }
@@ -1,15 +1,59 @@
 use crate::cli::MgaUpdateOptions;
 use crate::config::MgaConfig;
+use crate::http::HttpError;
 use anyhow::{anyhow, Context, Result};
-use f_xoss::mga::{parse_mga_data, MgaData};
+use clap::ValueEnum;
+use f_xoss::mga::{parse_mga_data, parse_mga_online_data, CorruptFrames, MgaData};
+use reqwest::{StatusCode, Url};
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
-use surf::{StatusCode, Url};
+use std::time::{Duration, SystemTime};
 use thiserror::Error;
 use tracing::{debug, instrument, warn};
 
-fn mga_file_path() -> PathBuf {
-    crate::config::APP_DIRS.cache_dir().join("mgaoffline.ubx")
+/// Which AssistNow service to get the A-GNSS data from
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MgaMode {
+    /// The almanacs for the next weeks, for the fixes within a minute or so
+    #[default]
+    Offline,
+    /// The current ephemerides, for the fixes within seconds, but only for a few hours
+    Online,
+}
+
+/// How long the AssistNow Online data is used before downloading it again
+const ONLINE_DATA_MAX_AGE: Duration = Duration::from_secs(2 * 60 * 60);
+
+pub fn mga_file_path(mode: MgaMode) -> PathBuf {
+    crate::config::APP_DIRS.cache_dir().join(match mode {
+        MgaMode::Offline => "mgaoffline.ubx",
+        MgaMode::Online => "mgaonline.ubx",
+    })
+}
+
+fn parse(
+    mode: MgaMode,
+    data: Vec<u8>,
+    downloaded: SystemTime,
+    corrupt_frames: CorruptFrames,
+) -> Result<MgaData> {
+    let data = match mode {
+        MgaMode::Offline => parse_mga_data(data, corrupt_frames)?,
+        MgaMode::Online => parse_mga_online_data(
+            data,
+            chrono::DateTime::<chrono::Utc>::from(downloaded).date_naive(),
+            corrupt_frames,
+        )?,
+    };
+    if data.dropped_frames > 0 {
+        warn!(
+            "Dropped {} corrupt frames of the MGA data, {} are left",
+            data.dropped_frames, data.frames
+        );
+    } else {
+        debug!("Parsed {} frames of the MGA data", data.frames);
+    }
+    Ok(data)
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -21,32 +65,52 @@ struct ErrorResponse {
 enum Error {
     #[error("The u-blox token is invalid")]
     BadToken,
+    #[error("Failed to download the MGA data")]
+    Http(#[from] HttpError),
     #[error("Some other error has occurred")]
     Other(#[from] anyhow::Error),
 }
 
-fn mga_build_url(config: &MgaConfig) -> Result<Url> {
-    let url = config
-        .base_url
-        .as_deref()
-        .unwrap_or("https://offline-live1.services.u-blox.com");
-    let mut url = Url::parse(url)?.join("GetOfflineData.ashx").unwrap();
+fn mga_build_url(config: &MgaConfig, token: &str, mode: MgaMode) -> Result<Url> {
+    let mut url = match mode {
+        MgaMode::Offline => {
+            let url = config
+                .base_url
+                .as_deref()
+                .unwrap_or("https://offline-live1.services.u-blox.com");
+            Url::parse(url)?.join("GetOfflineData.ashx").unwrap()
+        }
+        MgaMode::Online => {
+            let url = config
+                .online_base_url
+                .as_deref()
+                .unwrap_or("https://online-live1.services.u-blox.com");
+            Url::parse(url)?.join("GetOnlineData.ashx").unwrap()
+        }
+    };
 
     let period_str = config.period_weeks.unwrap_or(4).to_string();
     let resolution_str = config.resolution_days.unwrap_or(2).to_string();
+    let gnss_str = config
+        .gnss_constellations()?
+        .iter()
+        .map(|gnss| gnss.query_name())
+        .collect::<Vec<_>>()
+        .join(",");
 
     let mut query_pairs = Vec::new();
-    query_pairs.push((
-        "token",
-        config
-            .ublox_token
-            .as_deref()
-            .ok_or_else(|| anyhow!("Updating MGA data requires a u-blox AssistNow token"))?,
-    ));
-    query_pairs.push(("gnss", "gps,glo"));
+    query_pairs.push(("token", token));
+    query_pairs.push(("gnss", gnss_str.as_str()));
     query_pairs.push(("format", "mga"));
-    query_pairs.push(("period", period_str.as_str()));
-    query_pairs.push(("resolution", resolution_str.as_str()));
+    match mode {
+        MgaMode::Offline => {
+            query_pairs.push(("period", period_str.as_str()));
+            query_pairs.push(("resolution", resolution_str.as_str()));
+        }
+        MgaMode::Online => {
+            query_pairs.push(("datatype", "eph,alm,aux"));
+        }
+    }
 
     // u-blox API uses a non-standard query string format
     let query_string = query_pairs
@@ -56,25 +120,31 @@ fn mga_build_url(config: &MgaConfig) -> Result<Url> {
         .join(";");
     url.set_query(Some(query_string.as_str()));
 
-    let url_str = url.to_string();
-    debug!("Constructed MGA URL: {}", url_str);
+    debug!(
+        "Constructed MGA URL: {}",
+        url.as_str().replace(token, "<token>")
+    );
 
     Ok(url)
 }
 
-#[instrument(skip(config))]
-async fn download_mga_data(config: &MgaConfig) -> Result<MgaData, Error> {
-    let url = mga_build_url(config)?;
+#[instrument(skip(config, token))]
+async fn download_mga_data(
+    config: &MgaConfig,
+    token: &str,
+    mode: MgaMode,
+) -> Result<MgaData, Error> {
+    let url = mga_build_url(config, token, mode)?;
 
-    let mut response = surf::get(url)
-        .await
-        .map_err(|err| anyhow!(err))
-        .context("Failed to download MGA data")?;
-
-    match response.status() {
-        StatusCode::Ok => {}
-        StatusCode::BadRequest => {
-            let error: ErrorResponse = response.body_json().await.map_err(|err| anyhow!(err))?;
+    let raw_data = match crate::http::client().get(&url).await {
+        Ok(data) => data,
+        Err(HttpError::Status {
+            status: StatusCode::BAD_REQUEST,
+            body,
+            ..
+        }) => {
+            let error: ErrorResponse =
+                serde_json::from_slice(&body).context("Parsing the u-blox error response")?;
             let error = match error.message.as_str() {
                 message if message.starts_with("Invalid token: ") => Error::BadToken,
                 message => {
@@ -85,26 +155,32 @@ async fn download_mga_data(config: &MgaConfig) -> Result<MgaData, Error> {
 
             return Err(error);
         }
-        _ => return Err(anyhow!("Unexpected response status: {}", response.status()).into()),
-    }
-
-    let raw_data = response
-        .body_bytes()
-        .await
-        .map_err(|err| anyhow!(err))
-        .context("Failed to read MGA data")?;
+        Err(e) => return Err(e.into()),
+    };
 
-    Ok(parse_mga_data(raw_data).context("Parsing downloaded MGA data")?)
+    Ok(parse(
+        mode,
+        raw_data.to_vec(),
+        SystemTime::now(),
+        config.corrupt_frames(),
+    )
+    .context("Parsing downloaded MGA data")?)
 }
 
-async fn get_current_mga_data() -> Result<Option<MgaData>> {
-    let path = mga_file_path();
+/// The cached data and when it was downloaded, even if it's too old to be used
+pub async fn read_cached_mga_data(
+    mode: MgaMode,
+    corrupt_frames: CorruptFrames,
+) -> Result<Option<(MgaData, SystemTime)>> {
+    let path = mga_file_path(mode);
 
     async {
         match tokio::fs::read(&path).await {
             Ok(data) => {
-                let data = parse_mga_data(data).context("Parsing cached MGA data")?;
-                Ok::<_, anyhow::Error>(Some(data))
+                let downloaded = tokio::fs::metadata(&path).await?.modified()?;
+                let data = parse(mode, data, downloaded, corrupt_frames)
+                    .context("Parsing cached MGA data")?;
+                Ok::<_, anyhow::Error>(Some((data, downloaded)))
             }
             Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
             Err(e) => Err(e.into()),
@@ -114,11 +190,45 @@ async fn get_current_mga_data() -> Result<Option<MgaData>> {
     .with_context(|| format!("Reading cached MGA data from {}", path.display()))
 }
 
+/// Whether the cached data is too old to be used, the AssistNow Online one only lasts a few hours
+pub fn is_expired(mode: MgaMode, downloaded: SystemTime) -> bool {
+    mode == MgaMode::Online && downloaded.elapsed().unwrap_or_default() > ONLINE_DATA_MAX_AGE
+}
+
+/// The last downloaded data, which is the one sent to the devices
+pub async fn get_current_mga_data(
+    mode: MgaMode,
+    corrupt_frames: CorruptFrames,
+) -> Result<Option<MgaData>> {
+    Ok(match read_cached_mga_data(mode, corrupt_frames).await? {
+        Some((_, downloaded)) if is_expired(mode, downloaded) => {
+            debug!("The cached AssistNow Online data is too old");
+            None
+        }
+        data => data.map(|(data, _)| data),
+    })
+}
+
 pub async fn get_mga_data(config: &MgaConfig, options: &MgaUpdateOptions) -> Result<MgaData> {
-    let cached_data = get_current_mga_data().await?;
+    let mode = options.mga_mode;
+    let cached_data = match get_current_mga_data(mode, config.corrupt_frames()).await {
+        Ok(data) => data,
+        // a corrupt cache is as good as none
+        Err(e) if !options.mga_offline => {
+            warn!("{:#}, downloading the data again", e);
+            None
+        }
+        Err(e) => return Err(e),
+    };
+    let constellations = config.gnss_constellations()?;
     let today = chrono::Utc::now().date_naive();
-    // update if we are > 2 days out of date
+    // update if we are > 2 days out of date or the constellations have changed
     let out_of_date = |data: &MgaData| {
+        if !data.constellations.is_superset(&constellations) {
+            debug!("The cached MGA data doesn't cover all the configured constellations");
+            return true;
+        }
+
         let duration = today.signed_duration_since(data.valid_since);
         if duration < chrono::Duration::zero() {
             warn!("MGA data is from the future? (or is it timezone troubles?...) (valid since: {}, today: {})", data.valid_since, today);
@@ -127,7 +237,7 @@ pub async fn get_mga_data(config: &MgaConfig, options: &MgaUpdateOptions) -> Res
         duration > chrono::Duration::days(2)
     };
 
-    tokio::fs::create_dir_all(mga_file_path().parent().unwrap()).await?;
+    tokio::fs::create_dir_all(mga_file_path(mode).parent().unwrap()).await?;
 
     match cached_data {
         Some(data) if options.mga_offline || !out_of_date(&data) && !options.mga_force_update => {
@@ -139,8 +249,11 @@ pub async fn get_mga_data(config: &MgaConfig, options: &MgaUpdateOptions) -> Res
         )),
         _ => {
             debug!("Downloading new MGA data");
-            let data = download_mga_data(config).await?;
-            tokio::fs::write(mga_file_path(), &data.data)
+            let token = config
+                .ublox_token()?
+                .ok_or_else(|| anyhow!("Updating MGA data requires a u-blox AssistNow token"))?;
+            let data = download_mga_data(config, &token, mode).await?;
+            tokio::fs::write(mga_file_path(mode), &data.data)
                 .await
                 .context("Writing MGA data to cache")?;
             Ok(data)
@@ -149,11 +262,7 @@ pub async fn get_mga_data(config: &MgaConfig, options: &MgaUpdateOptions) -> Res
 }
 
 pub async fn check_ublox_token(token: &str) -> Result<bool> {
-    let result = download_mga_data(&MgaConfig {
-        ublox_token: Some(token.to_string()),
-        ..Default::default()
-    })
-    .await;
+    let result = download_mga_data(&MgaConfig::default(), token, MgaMode::Offline).await;
 
     match result {
         Ok(_) => Ok(true),
@@ -0,0 +1,198 @@
+//! Publishing the sync results to an MQTT broker, for the home automation dashboards (like Home Assistant)
+//!
+//! After each sync the device status is published (retained) to `<prefix>/<serial number>/state`, and each newly
+//! downloaded workout to `<prefix>/<serial number>/workout`. With `mqtt.home_assistant` the discovery configs are
+//! published as well, so that Home Assistant creates the sensors of the device by itself.
+
+use crate::config::MqttConfig;
+use crate::workout_index::WorkoutRecord;
+use anyhow::{Context, Result};
+use rumqttc::{AsyncClient, Event, MqttOptions, Outgoing, Packet, QoS};
+use serde::Serialize;
+use serde_json::json;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// How long connecting and publishing everything may take
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// The status of a device after a sync, as published to the state topic
+#[derive(Serialize, Debug, Clone)]
+pub struct DeviceStatus {
+    pub serial_number: String,
+    pub model: String,
+    pub firmware: String,
+    /// Percent, `None` if the device doesn't report it
+    pub battery_level: Option<u32>,
+    /// RFC 3339 time of the last successful sync
+    pub last_sync: Option<String>,
+    pub pending_workouts: Option<usize>,
+    /// The number of the steps that failed in the sync just done
+    pub failed_steps: usize,
+}
+
+struct Message {
+    topic: String,
+    payload: String,
+    retain: bool,
+}
+
+/// The Home Assistant discovery configs of the device sensors
+fn discovery_messages(config: &MqttConfig, status: &DeviceStatus) -> Vec<Message> {
+    let object_id = format!("f_xoss_{}", status.serial_number);
+    let device = json!({
+        "identifiers": [object_id],
+        "name": format!("XOSS {}", status.serial_number),
+        "manufacturer": "XOSS",
+        "model": status.model,
+        "sw_version": status.firmware,
+    });
+
+    [
+        (
+            "battery",
+            "Battery",
+            "battery_level",
+            Some("battery"),
+            Some("%"),
+        ),
+        (
+            "last_sync",
+            "Last sync",
+            "last_sync",
+            Some("timestamp"),
+            None,
+        ),
+        (
+            "pending_workouts",
+            "Pending workouts",
+            "pending_workouts",
+            None,
+            None,
+        ),
+    ]
+    .into_iter()
+    .map(|(sensor, name, field, device_class, unit)| {
+        let mut sensor_config = json!({
+            "name": name,
+            "unique_id": format!("{}_{}", object_id, sensor),
+            "state_topic": format!("{}/{}/state", config.topic_prefix(), status.serial_number),
+            "value_template": format!("{{{{ value_json.{} }}}}", field),
+            "device": device,
+        });
+        if let Some(device_class) = device_class {
+            sensor_config["device_class"] = device_class.into();
+        }
+        if let Some(unit) = unit {
+            sensor_config["unit_of_measurement"] = unit.into();
+        }
+        Message {
+            topic: format!("homeassistant/sensor/{}/{}/config", object_id, sensor),
+            payload: sensor_config.to_string(),
+            retain: true,
+        }
+    })
+    .collect()
+}
+
+fn sync_messages(
+    config: &MqttConfig,
+    status: &DeviceStatus,
+    workouts: &[WorkoutRecord],
+) -> Result<Vec<Message>> {
+    let device_topic = format!("{}/{}", config.topic_prefix(), status.serial_number);
+
+    let mut messages = Vec::new();
+    if config.home_assistant() {
+        messages.extend(discovery_messages(config, status));
+    }
+    messages.push(Message {
+        topic: format!("{}/state", device_topic),
+        payload: serde_json::to_string(status)?,
+        retain: true,
+    });
+    for workout in workouts {
+        messages.push(Message {
+            topic: format!("{}/workout", device_topic),
+            payload: serde_json::to_string(workout)?,
+            retain: false,
+        });
+    }
+
+    Ok(messages)
+}
+
+/// Connect to the broker, publish the messages and disconnect once the broker has received all of them
+async fn publish(
+    config: &MqttConfig,
+    host: String,
+    port: u16,
+    messages: Vec<Message>,
+) -> Result<()> {
+    let mut options = MqttOptions::new(format!("f-xoss-util-{}", std::process::id()), host, port);
+    options.set_keep_alive(Duration::from_secs(30));
+    if let Some(username) = &config.username {
+        options.set_credentials(username, config.password.clone().unwrap_or_default());
+    }
+
+    // the requests are queued before the event loop runs, so there has to be room for all of them
+    let (client, mut event_loop) = AsyncClient::new(options, messages.len() + 1);
+    let mut pending = messages.len();
+    for message in messages {
+        client
+            .publish(
+                message.topic,
+                QoS::AtLeastOnce,
+                message.retain,
+                message.payload,
+            )
+            .await?;
+    }
+
+    let run = async {
+        loop {
+            let event = event_loop
+                .poll()
+                .await
+                .context("The connection to the MQTT broker failed")?;
+            debug!("MQTT: {:?}", event);
+            match event {
+                Event::Incoming(Packet::PubAck(_)) => {
+                    pending -= 1;
+                    if pending == 0 {
+                        client.disconnect().await?;
+                    }
+                }
+                Event::Outgoing(Outgoing::Disconnect) => return Ok(()),
+                _ => {}
+            }
+        }
+    };
+
+    tokio::time::timeout(PUBLISH_TIMEOUT, run)
+        .await
+        .context("Timed out publishing to the MQTT broker")?
+}
+
+/// Publish the results of a sync, if a broker is configured
+///
+/// The sync is done by then, so a failure is only reported.
+pub async fn publish_sync(config: &MqttConfig, status: &DeviceStatus, workouts: &[WorkoutRecord]) {
+    let Some(broker) = config.broker() else {
+        return;
+    };
+
+    let result = async {
+        let (host, port) = broker?;
+        let messages = sync_messages(config, status, workouts)?;
+        publish(config, host.clone(), port, messages)
+            .await
+            .with_context(|| format!("Publishing to {}:{}", host, port))
+    }
+    .await;
+
+    match result {
+        Ok(()) => info!("Published the sync results to MQTT"),
+        Err(e) => warn!("Failed to publish the sync results to MQTT: {:#}", e),
+    }
+}
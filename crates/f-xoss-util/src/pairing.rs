@@ -0,0 +1,145 @@
+//! Pairing (bonding) with the devices that refuse to talk to an unpaired computer
+//!
+//! btleplug doesn't know about pairing, so on Linux it's done by asking BlueZ over D-Bus directly. The other platforms
+//! pair on their own when the device asks for it, the most we can do there is to tell the user where to look.
+
+use anyhow::Result;
+use btleplug::api::Peripheral as _;
+use btleplug::platform::Peripheral;
+use thiserror::Error;
+use tracing::info;
+
+#[derive(Error, Debug)]
+pub enum PairingError {
+    #[error("The device rejected the pairing. Accept it on the device when it asks, and remove the old pairing from the device settings if there is one")]
+    Rejected,
+    #[error("The pairing was not confirmed on the device in time")]
+    NotConfirmed,
+    #[error(
+        "Could not reach the device to pair with it. {}",
+        crate::locate_util::troubleshooting_hints()
+    )]
+    Unreachable,
+    #[error(
+        "Another pairing is in progress, finish or cancel it first (is bluetoothctl running?)"
+    )]
+    InProgress,
+    #[error("Failed to pair with the device: {0}")]
+    Other(String),
+}
+
+/// The error texts the platforms use when a characteristic can't be accessed without pairing
+const PAIRING_REQUIRED_MARKERS: &[&str] = &[
+    "Insufficient Authentication",
+    "Insufficient Encryption",
+    "NotAuthorized",
+    "NotPermitted",
+    "AuthenticationFailed",
+    "ATT error: 0x05",
+    "ATT error: 0x0f",
+];
+
+/// Whether a connection failed because the device wants to be paired first
+pub fn is_pairing_required(error: &anyhow::Error) -> bool {
+    let message = format!("{:#}", error);
+    PAIRING_REQUIRED_MARKERS
+        .iter()
+        .any(|marker| message.contains(marker))
+}
+
+/// Point at `setup --pair` when a connection without pairing failed because of it
+pub fn with_hint(error: anyhow::Error, paired: bool) -> anyhow::Error {
+    if !paired && is_pairing_required(&error) {
+        error.context("The device seems to require pairing, re-run setup with --pair")
+    } else {
+        error
+    }
+}
+
+/// What to do on the device while pairing
+pub fn instructions() -> &'static str {
+    #[cfg(target_os = "linux")]
+    const INSTRUCTIONS: &str =
+        "If the device shows a code or asks to confirm the pairing, accept it on the device";
+    #[cfg(target_os = "macos")]
+    const INSTRUCTIONS: &str = "macOS pairs with the device by itself when the device asks for it. Accept the pairing in the system prompt and on the device";
+    #[cfg(target_os = "windows")]
+    const INSTRUCTIONS: &str = "Windows pairs with the device by itself when the device asks for it. If the connection still fails, pair it in Settings > Bluetooth & devices > Add device first";
+    #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+    const INSTRUCTIONS: &str = "Pair with the device in the system Bluetooth settings";
+
+    INSTRUCTIONS
+}
+
+/// Pair with the device, unless it's already paired
+///
+/// The device is marked as trusted too, so that BlueZ accepts its reconnections without asking
+#[cfg(target_os = "linux")]
+pub async fn pair(peripheral: &Peripheral) -> Result<()> {
+    use anyhow::{anyhow, Context};
+    use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
+    use dbus::blocking::Connection;
+    use std::time::Duration;
+
+    const DEVICE_INTERFACE: &str = "org.bluez.Device1";
+    /// Long enough for the user to find the device and confirm the pairing on it
+    const PAIRING_TIMEOUT: Duration = Duration::from_secs(60);
+
+    // the peripheral id is the BlueZ object path without the /org/bluez/ prefix
+    let path = dbus::Path::new(format!("/org/bluez/{}", peripheral.id()))
+        .map_err(|e| anyhow!("Invalid BlueZ object path {}", e))?;
+
+    tokio::task::spawn_blocking(move || {
+        let connection = Connection::new_system().context("Connecting to the system D-Bus")?;
+        let proxy = connection.with_proxy("org.bluez", path, PAIRING_TIMEOUT);
+
+        let paired: bool = proxy
+            .get(DEVICE_INTERFACE, "Paired")
+            .context("Checking whether the device is paired")?;
+        if paired {
+            info!("The device is already paired");
+        } else {
+            info!("Pairing with the device. {}", instructions());
+            match proxy.method_call::<(), _, _, _>(DEVICE_INTERFACE, "Pair", ()) {
+                Ok(()) => info!("Paired with the device"),
+                Err(e) => match e.name() {
+                    Some("org.bluez.Error.AlreadyExists") => info!("The device is already paired"),
+                    Some("org.bluez.Error.AuthenticationFailed")
+                    | Some("org.bluez.Error.AuthenticationRejected") => {
+                        return Err(PairingError::Rejected.into())
+                    }
+                    Some("org.bluez.Error.AuthenticationCanceled")
+                    | Some("org.bluez.Error.AuthenticationTimeout")
+                    | Some("org.freedesktop.DBus.Error.NoReply") => {
+                        return Err(PairingError::NotConfirmed.into())
+                    }
+                    Some("org.bluez.Error.ConnectionAttemptFailed")
+                    | Some("org.bluez.Error.DoesNotExist")
+                    | Some("org.freedesktop.DBus.Error.UnknownObject") => {
+                        return Err(PairingError::Unreachable.into())
+                    }
+                    Some("org.bluez.Error.InProgress") => {
+                        return Err(PairingError::InProgress.into())
+                    }
+                    _ => return Err(PairingError::Other(e.to_string()).into()),
+                },
+            }
+        }
+
+        proxy
+            .set(DEVICE_INTERFACE, "Trusted", true)
+            .context("Marking the device as trusted")?;
+
+        Ok(())
+    })
+    .await?
+}
+
+/// Pair with the device, unless it's already paired
+///
+/// Only tells the user what to do, the system pairs on its own when the device asks for it
+#[cfg(not(target_os = "linux"))]
+pub async fn pair(peripheral: &Peripheral) -> Result<()> {
+    info!("Pairing with {}. {}", peripheral.id(), instructions());
+    Ok(())
+}
@@ -0,0 +1,80 @@
+use f_xoss::device::XossDevice;
+use f_xoss::progress::ProgressSink;
+use f_xoss::transport::LinkMonitor;
+use indicatif::ProgressStyle;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::Span;
+use tracing_indicatif::span_ext::IndicatifSpanExt;
+
+/// How often the link quality next to the progress bar is updated
+const LINK_QUALITY_INTERVAL: Duration = Duration::from_secs(2);
+
+fn bytes_progressbar_style() -> ProgressStyle {
+    ProgressStyle::default_bar()
+        .template("{span_child_prefix}{spinner:.green} [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta} @ {binary_bytes_per_sec}) {msg}")
+        .unwrap()
+        .progress_chars("#>-")
+}
+
+/// Shows the progress of a library operation as a progress bar
+///
+/// The bar is attached to the span the operation is running in when it starts (like `read_file`),
+/// so it goes away when the operation finishes.
+#[derive(Default)]
+pub struct SpanProgress {
+    span: Mutex<Option<Span>>,
+    link_monitor: Option<LinkMonitor>,
+    /// Updates the link quality shown next to the bar
+    link_task: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl SpanProgress {
+    /// Also show the signal strength next to the bar, to tell whether a slow transfer is due to a poor signal
+    pub fn with_link_quality(device: &XossDevice) -> Self {
+        Self {
+            span: Mutex::new(None),
+            link_monitor: Some(device.link_monitor()),
+            link_task: Mutex::new(None),
+        }
+    }
+}
+
+impl ProgressSink for SpanProgress {
+    fn start(&self, total: u64) {
+        let span = Span::current();
+        span.pb_set_style(&bytes_progressbar_style());
+        span.pb_set_length(total);
+
+        if let Some(monitor) = self.link_monitor.clone() {
+            let span = span.clone();
+            let task = tokio::spawn(async move {
+                loop {
+                    let link_quality = monitor.link_quality().await;
+                    span.pb_set_message(&link_quality.to_string());
+                    tokio::time::sleep(LINK_QUALITY_INTERVAL).await;
+                }
+            });
+            if let Some(previous) = self.link_task.lock().unwrap().replace(task) {
+                previous.abort();
+            }
+        }
+
+        *self.span.lock().unwrap() = Some(span);
+    }
+
+    fn advance(&self, delta: u64) {
+        if let Some(span) = self.span.lock().unwrap().as_ref() {
+            span.pb_inc(delta);
+        }
+    }
+}
+
+impl Drop for SpanProgress {
+    fn drop(&mut self) {
+        if let Some(task) = self.link_task.get_mut().unwrap().take() {
+            task.abort();
+        }
+    }
+}
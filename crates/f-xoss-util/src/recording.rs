@@ -0,0 +1,86 @@
+//! Recording the Bluetooth sessions (`--record`) and running the commands against a recorded one (`--replay`)
+//!
+//! A recording has all the traffic with the device, so a problem seen by a user can be reproduced from it without their
+//! device. Only the last connection is kept: the reconnection attempts start a new recording.
+
+use crate::config::TimeoutsConfig;
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use f_xoss::device::XossDevice;
+use f_xoss::transport::session::{ReplayPeripheral, Session, SessionRecording};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tracing::{info, warn};
+
+/// Set by `--record`
+static RECORD: AtomicBool = AtomicBool::new(false);
+static LAST_RECORDING: Mutex<Option<SessionRecording>> = Mutex::new(None);
+
+pub fn enable() {
+    RECORD.store(true, Ordering::Relaxed);
+}
+
+/// Start recording the session of a new connection, if the recording is enabled
+pub fn start() -> Option<SessionRecording> {
+    if !RECORD.load(Ordering::Relaxed) {
+        return None;
+    }
+    let recording = SessionRecording::new();
+    *LAST_RECORDING.lock().unwrap() = Some(recording.clone());
+    Some(recording)
+}
+
+/// Save the session of the last connection
+pub fn save(path: &Utf8Path) -> Result<()> {
+    let Some(recording) = LAST_RECORDING.lock().unwrap().clone() else {
+        warn!("Nothing to record, the command didn't connect to a device");
+        return Ok(());
+    };
+    let session = recording.session();
+    let file = std::fs::File::create(path)
+        .with_context(|| format!("Creating the session recording {}", path))?;
+    session
+        .write_to(std::io::BufWriter::new(file))
+        .with_context(|| format!("Writing the session recording to {}", path))?;
+    info!(
+        "The session ({} events) is recorded to {}",
+        session.events.len(),
+        path
+    );
+    Ok(())
+}
+
+/// Connect to a recorded session instead of a device
+pub async fn connect_replay(
+    path: &Utf8Path,
+    timeouts: &TimeoutsConfig,
+) -> Result<(XossDevice, Arc<ReplayPeripheral>)> {
+    let file = std::fs::File::open(path)
+        .with_context(|| format!("Opening the session recording {}", path))?;
+    let session = Session::read_from(std::io::BufReader::new(file))
+        .with_context(|| format!("Reading the session recording {}", path))?;
+    info!(
+        "Replaying the recorded session from {} ({} events)",
+        path,
+        session.events.len()
+    );
+
+    let peripheral = Arc::new(ReplayPeripheral::new(session));
+    let device = XossDevice::with_options(peripheral.clone(), timeouts.transport_options())
+        .await
+        .context("Failed to initialize the connection to the recorded device")?;
+    Ok((device, peripheral))
+}
+
+/// Report the writes that differed from the recorded ones, they may explain a different outcome
+pub fn report_mismatches(peripheral: &ReplayPeripheral) {
+    let mismatches = peripheral.mismatches();
+    if mismatches.is_empty() {
+        return;
+    }
+    warn!(
+        "{} writes differed from the recorded session:\n{}",
+        mismatches.len(),
+        mismatches.join("\n")
+    );
+}
@@ -0,0 +1,123 @@
+//! Keeping the secrets (like the u-blox token) out of the config file
+//!
+//! The config only refers to the secrets stored in the system keyring: the Secret Service on Linux, the Keychain on
+//! macOS and the Credential Manager on Windows. Where there's no keyring, they stay in the config as they are.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::config::XossUtilConfig;
+
+/// The service the secrets are stored under in the keyring
+const KEYRING_SERVICE: &str = "f-xoss";
+
+/// The keyring entry of the u-blox AssistNow token
+pub const UBLOX_TOKEN_ENTRY: &str = "ublox-token";
+
+/// Overrides the u-blox token from the config, wherever it's stored
+pub const UBLOX_TOKEN_ENV: &str = "UBLOX_TOKEN";
+
+/// The command line flags whose values are secrets, not to be written to the debug dump
+pub const SECRET_ARGS: &[&str] = &["--ublox-token"];
+
+/// The command line arguments with the values of [`SECRET_ARGS`] replaced
+///
+/// For logging them: the debug dump is meant to be attached to bug reports
+pub fn redacted_args(args: impl IntoIterator<Item = String>) -> Vec<String> {
+    let mut redact_next = false;
+    args.into_iter()
+        .map(|arg| {
+            if std::mem::take(&mut redact_next) {
+                return "<redacted>".to_string();
+            }
+            if SECRET_ARGS.contains(&arg.as_str()) {
+                redact_next = true;
+                return arg;
+            }
+            match SECRET_ARGS
+                .iter()
+                .find(|flag| arg.strip_prefix(*flag).is_some_and(|v| v.starts_with('=')))
+            {
+                Some(flag) => format!("{}=<redacted>", flag),
+                None => arg,
+            }
+        })
+        .collect()
+}
+
+/// A secret value in the config
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Secret {
+    /// Written in the config as it is
+    Plain(String),
+    /// Stored in the system keyring, the config has only the name of the entry
+    Keyring { keyring: String },
+}
+
+impl Secret {
+    /// Store the value in the keyring under `entry`, or keep it plain if there's no keyring
+    pub fn store(entry: &str, value: String) -> Self {
+        match keyring::Entry::new(KEYRING_SERVICE, entry).and_then(|e| e.set_password(&value)) {
+            Ok(()) => {
+                info!("Stored the {} in the system keyring", entry);
+                Self::Keyring {
+                    keyring: entry.to_string(),
+                }
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to store the {} in the system keyring, it's written to the config file instead: {}",
+                    entry, e
+                );
+                Self::Plain(value)
+            }
+        }
+    }
+
+    pub fn get(&self) -> Result<String> {
+        match self {
+            Self::Plain(value) => Ok(value.clone()),
+            Self::Keyring { keyring: entry } => keyring::Entry::new(KEYRING_SERVICE, entry)
+                .and_then(|e| e.get_password())
+                .map_err(|e| match e {
+                    keyring::Error::NoEntry => anyhow::anyhow!(
+                        "There is no {} in the system keyring, re-run setup to store it again",
+                        entry
+                    ),
+                    e => anyhow::Error::new(e),
+                })
+                .with_context(|| format!("Getting the {} from the system keyring", entry)),
+        }
+    }
+}
+
+/// Move the secrets written in the config as they are to the keyring, saving the config if any were moved
+///
+/// Nothing is moved if `use_keyring` is off in the config, or if there's no keyring to move them to
+pub fn move_to_keyring(config: &mut XossUtilConfig) -> Result<()> {
+    if !config.use_keyring() {
+        return Ok(());
+    }
+    let Some(Secret::Plain(token)) = &config.mga.ublox_token else {
+        return Ok(());
+    };
+
+    match keyring::Entry::new(KEYRING_SERVICE, UBLOX_TOKEN_ENTRY)
+        .and_then(|e| e.set_password(token))
+    {
+        Ok(()) => {
+            info!("Moved the u-blox token from the config file to the system keyring");
+            config.mga.ublox_token = Some(Secret::Keyring {
+                keyring: UBLOX_TOKEN_ENTRY.to_string(),
+            });
+            crate::config::save_config(config)
+        }
+        Err(e) => {
+            // tried again on the next run, there might be a keyring by then
+            debug!("Not moving the u-blox token to the system keyring: {}", e);
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,275 @@
+//! Information remembered between runs that is not a part of the config
+//!
+//! Unlike the config, it's written by the tool itself, so it lives in the data directory.
+
+use anyhow::{Context, Result};
+use chrono::{Local, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use crate::config::XossDeviceInfo;
+use f_xoss::device::MemoryCapacity;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferDirection {
+    Download,
+    Upload,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TransferRecord {
+    /// Unix timestamp of the moment the transfer has finished
+    pub time: i64,
+    pub direction: TransferDirection,
+    pub size: u64,
+    pub crc32: u32,
+}
+
+/// The battery levels (in percent) around a sync, to estimate how much a sync costs
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SyncRecord {
+    /// Unix timestamp of the moment the sync has finished
+    pub time: i64,
+    pub battery_before: u32,
+    pub battery_after: u32,
+    /// Number of the sync steps that failed
+    pub failed_steps: usize,
+}
+
+/// How many syncs to remember for the battery usage estimates
+const MAX_SYNC_RECORDS: usize = 20;
+
+/// What the device clock was found to be before it was set
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClockRecord {
+    /// Unix timestamp of the moment the clock was set
+    pub time: i64,
+    /// How many seconds ahead the clock was at least, if it could be seen
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub drift: Option<i64>,
+    /// Whether the clock was reset
+    #[serde(default)]
+    pub reset: bool,
+}
+
+/// How many clock settings to remember, to notice a failing RTC battery
+const MAX_CLOCK_RECORDS: usize = 10;
+
+/// The free memory of the device at some moment, to tell how fast it fills up
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CapacityRecord {
+    /// Unix timestamp of the moment the capacity was read
+    pub time: i64,
+    pub free_kb: u32,
+    pub total_kb: u32,
+}
+
+impl CapacityRecord {
+    /// The capacity as read just now
+    pub fn now(capacity: &MemoryCapacity) -> Self {
+        Self {
+            time: Utc::now().timestamp(),
+            free_kb: capacity.free_kb,
+            total_kb: capacity.total_kb,
+        }
+    }
+
+    fn used_kb(&self) -> f64 {
+        self.total_kb as f64 - self.free_kb as f64
+    }
+}
+
+/// How many capacity readings to remember for the trend
+const MAX_CAPACITY_RECORDS: usize = 30;
+
+/// The readings closer than this (in seconds) to the previous one replace it, so that the trend covers a longer time
+const CAPACITY_RECORD_INTERVAL: i64 = 6 * 60 * 60;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DeviceState {
+    /// Unix timestamp of the last successful sync
+    pub last_sync: Option<i64>,
+    /// Number of workouts that were left on the device not downloaded after the last sync
+    ///
+    /// These are the ones postponed because of low battery and the ones that failed to download
+    pub pending_workouts: Option<usize>,
+    /// Battery level (in percent) at the last successful sync
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub battery_level: Option<u32>,
+    /// The most recent transfers of each file, oldest first
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub transfers: BTreeMap<String, Vec<TransferRecord>>,
+    /// The most recent syncs, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub syncs: Vec<SyncRecord>,
+    /// The most recent clock settings, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub clock: Vec<ClockRecord>,
+    /// The most recent capacity readings, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub capacity: Vec<CapacityRecord>,
+}
+
+impl DeviceState {
+    /// A short human-readable description, to be shown next to the device name
+    pub fn describe(&self) -> String {
+        let pending = match self.pending_workouts {
+            Some(0) => "no pending workouts".to_string(),
+            Some(1) => "1 pending workout".to_string(),
+            Some(n) => format!("{} pending workouts", n),
+            None => "pending workouts unknown".to_string(),
+        };
+        let last_sync = match self
+            .last_sync
+            .and_then(|t| Local.timestamp_opt(t, 0).single())
+        {
+            Some(t) => format!("last synced {}", t.format("%Y-%m-%d %H:%M")),
+            None => "never synced".to_string(),
+        };
+
+        format!("{}, {}", pending, last_sync)
+    }
+
+    pub fn record_sync(&mut self, record: SyncRecord) {
+        self.syncs.push(record);
+        if self.syncs.len() > MAX_SYNC_RECORDS {
+            self.syncs.drain(..self.syncs.len() - MAX_SYNC_RECORDS);
+        }
+    }
+
+    pub fn record_clock(&mut self, record: ClockRecord) {
+        self.clock.push(record);
+        if self.clock.len() > MAX_CLOCK_RECORDS {
+            self.clock.drain(..self.clock.len() - MAX_CLOCK_RECORDS);
+        }
+    }
+
+    pub fn record_capacity(&mut self, record: CapacityRecord) {
+        if let [.., previous] = self.capacity.as_slice() {
+            if record.time - previous.time < CAPACITY_RECORD_INTERVAL {
+                self.capacity.pop();
+            }
+        }
+        self.capacity.push(record);
+        if self.capacity.len() > MAX_CAPACITY_RECORDS {
+            self.capacity
+                .drain(..self.capacity.len() - MAX_CAPACITY_RECORDS);
+        }
+    }
+
+    /// How fast the used memory grows (in KiB per day), along with the number of days it's based on
+    ///
+    /// Needs the readings spanning a day at least. The used memory drops when the synced workouts are deleted, so the
+    /// trend can be negative too.
+    pub fn capacity_trend(&self) -> Option<(f64, f64)> {
+        let (first, last) = (self.capacity.first()?, self.capacity.last()?);
+        let days = (last.time - first.time) as f64 / (24 * 60 * 60) as f64;
+        if days < 1.0 {
+            return None;
+        }
+        Some(((last.used_kb() - first.used_kb()) / days, days))
+    }
+
+    /// Like "+120 KiB per day over 14 days, full in ~60 days", if there are enough readings
+    pub fn describe_capacity_trend(&self) -> Option<String> {
+        let (trend, days) = self.capacity_trend()?;
+        let mut description = format!("{:+.0} KiB per day over {:.0} days", trend, days);
+        if let (true, Some(last)) = (trend > 0.0, self.capacity.last()) {
+            description += &format!(", full in ~{:.0} days", last.free_kb as f64 / trend);
+        }
+        Some(description)
+    }
+
+    /// How many of the recorded clock settings found the clock reset
+    pub fn clock_resets(&self) -> usize {
+        self.clock.iter().filter(|c| c.reset).count()
+    }
+
+    /// The average battery drop (in percent) of the recorded syncs, along with the number of the syncs it's based on
+    ///
+    /// The syncs during which the battery level went up were done on a charger, they don't tell anything and are skipped.
+    pub fn battery_usage(&self) -> Option<(f64, usize)> {
+        let drops = self
+            .syncs
+            .iter()
+            .filter(|s| s.battery_after <= s.battery_before)
+            .map(|s| (s.battery_before - s.battery_after) as f64)
+            .collect::<Vec<_>>();
+        if drops.is_empty() {
+            return None;
+        }
+        Some((drops.iter().sum::<f64>() / drops.len() as f64, drops.len()))
+    }
+
+    /// Like "~2% per sync", if there are any syncs recorded
+    pub fn describe_battery_usage(&self) -> Option<String> {
+        self.battery_usage()
+            .map(|(usage, _)| format!("~{:.1}% per sync", usage))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct UtilState {
+    /// Per-device state, keyed by the serial number
+    #[serde(default)]
+    pub devices: BTreeMap<String, DeviceState>,
+}
+
+impl UtilState {
+    /// Find the state of a configured device
+    ///
+    /// Devices configured by older versions don't have a serial number recorded, so they don't have any state
+    pub fn device(&self, device_info: &XossDeviceInfo) -> Option<&DeviceState> {
+        self.devices.get(device_info.serial_number.as_ref()?)
+    }
+
+    pub fn describe_device(&self, device_info: &XossDeviceInfo) -> String {
+        self.device(device_info)
+            .map_or_else(|| "never synced".to_string(), |s| s.describe())
+    }
+
+    pub fn device_mut(&mut self, serial_number: &str) -> &mut DeviceState {
+        self.devices.entry(serial_number.to_string()).or_default()
+    }
+}
+
+pub fn state_path() -> PathBuf {
+    crate::config::APP_DIRS.data_dir().join("state.json")
+}
+
+pub fn load_state() -> Result<UtilState> {
+    let state_path = state_path();
+
+    match std::fs::read_to_string(&state_path) {
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(UtilState::default()),
+        r => {
+            let state =
+                r.with_context(|| format!("Reading state file {}", state_path.display()))?;
+            serde_json::from_str(&state)
+                .with_context(|| format!("Parsing state file {}", state_path.display()))
+        }
+    }
+}
+
+pub fn save_state(state: &UtilState) -> Result<()> {
+    let state_path = state_path();
+
+    std::fs::create_dir_all(state_path.parent().unwrap()).context("Creating the data directory")?;
+    std::fs::write(
+        &state_path,
+        serde_json::to_string_pretty(state).context("Serializing the state file")?,
+    )
+    .with_context(|| format!("Writing state file {}", state_path.display()))?;
+
+    Ok(())
+}
+
+/// Load the state, apply a change to it and save it back
+pub fn update_state(f: impl FnOnce(&mut UtilState)) -> Result<()> {
+    let mut state = load_state()?;
+    f(&mut state);
+    save_state(&state)
+}
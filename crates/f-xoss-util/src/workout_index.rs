@@ -0,0 +1,531 @@
+//! The index of the workouts downloaded from the devices
+//!
+//! The workouts are stored as plain FIT files in the workouts directory, the index remembers where each of them
+//! came from along with a short summary, so that they can be listed without parsing all the files.
+//! It also allows to recognize the synced workouts after the local files were renamed (the content checksum stays the same).
+
+use crate::config::XossUtilConfig;
+use anyhow::{bail, Context, Result};
+use chrono::{Local, NaiveDateTime, TimeZone, Utc};
+use f_xoss::fit::{FitFile, WorkoutSummary};
+use f_xoss::model::WorkoutsItem;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+use tracing::{info, warn};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WorkoutRecord {
+    /// Name of the local file, relative to the workouts directory
+    pub file: String,
+    /// Serial number of the device the workout was downloaded from
+    ///
+    /// Unknown for the files that were found in the directory and not downloaded by a sync
+    pub device: Option<String>,
+    /// The workout name on the device (the file is named `{name}.fit` there)
+    pub device_name: Option<u64>,
+    /// Unix timestamp of the workout start
+    pub start_time: Option<i64>,
+    /// Elapsed time, in seconds
+    pub duration: Option<f64>,
+    /// Meters
+    pub distance: Option<f64>,
+    /// Total ascent, meters
+    pub ascent: Option<f64>,
+    /// Meters per second, over the moving time
+    pub avg_speed: Option<f64>,
+    /// Unix timestamp of the moment the workout was added to the index
+    pub synced_at: i64,
+    pub size: u64,
+    pub crc32: u32,
+    /// The other names the same workout got on the devices, like after a clock reset
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aliases: Vec<WorkoutAlias>,
+    #[serde(flatten)]
+    pub annotation: Annotation,
+}
+
+/// The title and the notes given to a workout with `workout annotate`, put into its exports
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Annotation {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notes: Option<String>,
+}
+
+impl Annotation {
+    pub fn is_empty(&self) -> bool {
+        self.title.is_none() && self.notes.is_none()
+    }
+}
+
+/// Another name of a workout on a device, its file had the same content as the indexed one
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct WorkoutAlias {
+    pub device: String,
+    pub device_name: u64,
+}
+
+impl WorkoutRecord {
+    /// Whether the record is for the workout `device_name` on the device `serial_number`
+    fn is_synced_from(&self, serial_number: &str, device_name: u64) -> bool {
+        (self.device.as_deref() == Some(serial_number) && self.device_name == Some(device_name))
+            || self
+                .aliases
+                .iter()
+                .any(|a| a.device == serial_number && a.device_name == device_name)
+    }
+
+    /// Create a record for the workout file contents, summarizing it if it can be parsed
+    pub fn new(
+        file: String,
+        data: &[u8],
+        device: Option<String>,
+        device_name: Option<u64>,
+    ) -> Self {
+        let summary = match FitFile::parse(data) {
+            Ok(fit) => Some(WorkoutSummary::from_fit(&fit)),
+            Err(e) => {
+                // a damaged workout is summarized as far as it can be read
+                let salvaged = f_xoss::fit::repair(data)
+                    .ok()
+                    .and_then(|(repaired, _)| FitFile::parse(&repaired).ok());
+                match salvaged {
+                    Some(fit) => {
+                        warn!(
+                            "{} is damaged ({}), it's indexed with the summary of what can be read",
+                            file, e
+                        );
+                        Some(WorkoutSummary::from_fit(&fit))
+                    }
+                    None => {
+                        warn!(
+                            "Could not parse {}, it's indexed without a summary: {}",
+                            file, e
+                        );
+                        None
+                    }
+                }
+            }
+        };
+        let summary = summary.as_ref();
+
+        Self {
+            file,
+            device,
+            device_name,
+            start_time: summary.and_then(|s| s.start_time).map(|t| t.timestamp()),
+            duration: summary.and_then(|s| s.elapsed_time),
+            distance: summary.and_then(|s| s.distance),
+            ascent: summary.and_then(|s| s.ascent),
+            avg_speed: summary.and_then(|s| s.avg_speed),
+            synced_at: Utc::now().timestamp(),
+            size: data.len() as u64,
+            crc32: crc32fast::hash(data),
+            aliases: Vec::new(),
+            annotation: Annotation::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct WorkoutIndex {
+    #[serde(default)]
+    pub workouts: Vec<WorkoutRecord>,
+}
+
+impl WorkoutIndex {
+    /// Find the record of a workout downloaded from the device
+    pub fn find_synced(&self, serial_number: &str, device_name: u64) -> Option<&WorkoutRecord> {
+        self.workouts
+            .iter()
+            .find(|r| r.is_synced_from(serial_number, device_name))
+    }
+
+    /// Find a record of another file with the same content as `data`, which local file is still there
+    ///
+    /// The size and the checksum only pick the candidates, the files are compared byte by byte: a CRC-32 collision
+    /// would otherwise lose a workout
+    pub fn find_duplicate(
+        &self,
+        dir: &Path,
+        record: &WorkoutRecord,
+        data: &[u8],
+    ) -> Option<&WorkoutRecord> {
+        self.workouts.iter().find(|r| {
+            r.file != record.file
+                && r.size == record.size
+                && r.crc32 == record.crc32
+                && std::fs::read(dir.join(&r.file)).is_ok_and(|existing| existing == data)
+        })
+    }
+
+    /// Remember that the workout `device_name` on the device `serial_number` is the one in `file`
+    pub fn add_alias(&mut self, file: &str, serial_number: &str, device_name: u64) {
+        if let Some(record) = self.workouts.iter_mut().find(|r| r.file == file) {
+            if !record.is_synced_from(serial_number, device_name) {
+                record.aliases.push(WorkoutAlias {
+                    device: serial_number.to_string(),
+                    device_name,
+                });
+            }
+        }
+    }
+
+    /// Find the record of a file with this content, wherever the file is now
+    pub fn find_by_content(&self, data: &[u8]) -> Option<&WorkoutRecord> {
+        let crc32 = crc32fast::hash(data);
+        self.workouts
+            .iter()
+            .find(|r| r.size == data.len() as u64 && r.crc32 == crc32)
+    }
+
+    /// Find a workout by its file (relative to the workouts directory, the `.fit` can be left out) or its name on
+    /// the device
+    pub fn find_mut(&mut self, workout: &str) -> Result<&mut WorkoutRecord> {
+        let matches = |r: &WorkoutRecord| {
+            r.file == workout
+                || r.file.strip_suffix(".fit") == Some(workout)
+                || r.device_name.is_some_and(|n| n.to_string() == workout)
+                || r.aliases
+                    .iter()
+                    .any(|a| a.device_name.to_string() == workout)
+        };
+        let found = self
+            .workouts
+            .iter()
+            .filter(|r| matches(r))
+            .map(|r| r.file.clone())
+            .collect::<Vec<_>>();
+        match found.len() {
+            0 => bail!(
+                "No workout {:?} in the index, see `workout list` for the file names",
+                workout
+            ),
+            1 => Ok(self.workouts.iter_mut().find(|r| matches(r)).unwrap()),
+            _ => bail!(
+                "Several workouts match {:?}, give the file name instead: {}",
+                workout,
+                found.join(", ")
+            ),
+        }
+    }
+
+    /// Add a record, replacing the one for the same file or for the same workout on the same device
+    ///
+    /// The annotation of the replaced record is kept, unless the new one has its own
+    pub fn insert(&mut self, mut record: WorkoutRecord) {
+        let replaces = |r: &WorkoutRecord| {
+            r.file == record.file
+                || (r.device.is_some()
+                    && r.device == record.device
+                    && r.device_name.is_some()
+                    && r.device_name == record.device_name)
+        };
+        if record.annotation.is_empty() {
+            if let Some(replaced) = self.workouts.iter().find(|r| replaces(r)) {
+                record.annotation = replaced.annotation.clone();
+            }
+        }
+        self.workouts.retain(|r| !replaces(r));
+        self.workouts.push(record);
+    }
+
+    /// Bring the index up to date with the contents of the workouts directory
+    ///
+    /// The records of the renamed files are updated (matched by the size and the checksum),
+    /// the files that are not in the index yet are added to it. Returns whether anything has changed.
+    pub fn refresh(&mut self, dir: &Path) -> Result<bool> {
+        let mut unindexed = HashMap::new();
+        let mut files = Vec::new();
+        match list_fit_files(dir, "", &mut files) {
+            Err(e)
+                if e.downcast_ref::<std::io::Error>()
+                    .is_some_and(|e| e.kind() == ErrorKind::NotFound) =>
+            {
+                return Ok(false)
+            }
+            r => r?,
+        }
+        for (file, path) in files {
+            if self.workouts.iter().any(|r| r.file == file) {
+                continue;
+            }
+            let data =
+                std::fs::read(&path).with_context(|| format!("Reading {}", path.display()))?;
+            unindexed.insert(file, data);
+        }
+
+        let mut changed = false;
+        for record in &mut self.workouts {
+            if dir.join(&record.file).exists() {
+                continue;
+            }
+            let renamed = unindexed.iter().find_map(|(file, data)| {
+                (data.len() as u64 == record.size && crc32fast::hash(data) == record.crc32)
+                    .then(|| file.clone())
+            });
+            if let Some(file) = renamed {
+                info!("{} was renamed to {}", record.file, file);
+                unindexed.remove(&file);
+                record.file = file;
+                changed = true;
+            }
+        }
+
+        let mut new_files = unindexed.into_iter().collect::<Vec<_>>();
+        new_files.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for (file, data) in new_files {
+            info!("Adding {} to the workout index", file);
+            self.workouts
+                .push(WorkoutRecord::new(file, &data, None, None));
+            changed = true;
+        }
+
+        Ok(changed)
+    }
+}
+
+/// Find the FIT files in `dir` and its subdirectories (the file name template can make them), named relative to it
+fn list_fit_files(dir: &Path, prefix: &str, files: &mut Vec<(String, PathBuf)>) -> Result<()> {
+    let entries = std::fs::read_dir(dir).with_context(|| format!("Listing {}", dir.display()))?;
+    for entry in entries {
+        let entry = entry.with_context(|| format!("Listing {}", dir.display()))?;
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        let file = format!("{}{}", prefix, name);
+        let file_type = entry
+            .file_type()
+            .with_context(|| format!("Listing {}", dir.display()))?;
+        if file_type.is_dir() {
+            list_fit_files(&entry.path(), &format!("{}/", file), files)?;
+        } else if name.to_ascii_lowercase().ends_with(".fit") {
+            files.push((file, entry.path()));
+        }
+    }
+    Ok(())
+}
+
+pub fn default_workouts_dir() -> PathBuf {
+    crate::config::APP_DIRS.data_dir().join("workouts")
+}
+
+/// The directory the workouts are synced to, see `workouts.dir` in the config
+pub fn workouts_dir(config: Option<&XossUtilConfig>) -> PathBuf {
+    config
+        .and_then(|c| c.workouts.dir.clone())
+        .unwrap_or_else(default_workouts_dir)
+}
+
+/// Finds the local copies of the workouts of a device, also the ones renamed since the sync
+pub struct LocalCopies {
+    dir: PathBuf,
+    index: WorkoutIndex,
+    serial_number: String,
+}
+
+impl LocalCopies {
+    pub fn load(dir: PathBuf, serial_number: String) -> Result<Self> {
+        // the index is only refreshed in memory here, to find the renamed files
+        let mut index = load_index()?;
+        index.refresh(&dir)?;
+        Ok(Self {
+            dir,
+            index,
+            serial_number,
+        })
+    }
+
+    pub fn find(&self, workout: &WorkoutsItem) -> Option<PathBuf> {
+        let path = self.dir.join(workout.filename());
+        if path.exists() {
+            return Some(path);
+        }
+        self.index
+            .find_synced(&self.serial_number, workout.name)
+            .map(|record| self.dir.join(&record.file))
+            .filter(|path| path.exists())
+    }
+}
+
+const PLACEHOLDERS: &[&str] = &["name", "date", "time", "device", "serial"];
+
+/// How the synced workout files are named, see `workouts.filename` in the config
+#[derive(Debug, Clone)]
+pub struct FilenameTemplate(String);
+
+impl Default for FilenameTemplate {
+    /// The name the workout has on the device
+    fn default() -> Self {
+        Self("{name}.fit".to_string())
+    }
+}
+
+/// What a workout file name is made of
+pub struct FilenameValues<'a> {
+    /// The workout name on the device
+    pub name: u64,
+    /// The configured device name, or the serial number
+    pub device: &'a str,
+    pub serial_number: &'a str,
+}
+
+/// The local start time of a workout, from its name on the device
+///
+/// The names are either unix times or local date and time digits, see [f_xoss::model::WorkoutNaming]
+fn start_from_name(name: u64) -> Option<NaiveDateTime> {
+    if name >= 10_000_000_000_000 {
+        NaiveDateTime::parse_from_str(&name.to_string(), "%Y%m%d%H%M%S").ok()
+    } else {
+        Local
+            .timestamp_opt(name as i64, 0)
+            .single()
+            .map(|t| t.naive_local())
+    }
+}
+
+/// Make a value safe to use as a part of a file name
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| {
+            if c.is_control() || "/\\:*?\"<>|".contains(c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
+impl FilenameTemplate {
+    pub fn parse(template: &str) -> Result<Self> {
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let Some(end) = rest[start..].find('}') else {
+                bail!(
+                    "Unclosed {{ in the workout file name template {:?}",
+                    template
+                );
+            };
+            let placeholder = &rest[start + 1..start + end];
+            if !PLACEHOLDERS.contains(&placeholder) {
+                bail!(
+                    "Unknown placeholder {{{}}} in the workout file name template {:?}, the known ones are {}",
+                    placeholder,
+                    template,
+                    PLACEHOLDERS
+                        .iter()
+                        .map(|p| format!("{{{}}}", p))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+            }
+            rest = &rest[start + end + 1..];
+        }
+
+        let path = Path::new(template);
+        if template.trim().is_empty()
+            || template.ends_with('/')
+            || path.is_absolute()
+            || path
+                .components()
+                .any(|c| !matches!(c, Component::Normal(_)))
+        {
+            bail!(
+                "The workout file name template {:?} must be a relative path without . and .. in it",
+                template
+            );
+        }
+
+        Ok(Self(template.to_string()))
+    }
+
+    /// The file name, relative to the workouts directory
+    pub fn render(&self, values: &FilenameValues) -> String {
+        let start = start_from_name(values.name);
+        let mut result = String::new();
+        let mut rest = self.0.as_str();
+        // the placeholders were checked by parse
+        while let Some(start_index) = rest.find('{') {
+            let end_index = start_index + rest[start_index..].find('}').unwrap();
+            result.push_str(&rest[..start_index]);
+            let value = match &rest[start_index + 1..end_index] {
+                "name" => values.name.to_string(),
+                "date" => start.map_or_else(
+                    || "unknown-date".to_string(),
+                    |s| s.format("%Y-%m-%d").to_string(),
+                ),
+                "time" => start.map_or_else(
+                    || "unknown-time".to_string(),
+                    |s| s.format("%H%M%S").to_string(),
+                ),
+                "device" => values.device.to_string(),
+                "serial" => values.serial_number.to_string(),
+                _ => unreachable!(),
+            };
+            result.push_str(&sanitize(&value));
+            rest = &rest[end_index + 1..];
+        }
+        result.push_str(rest);
+        result
+    }
+}
+
+/// `file` (relative to `dir`), or the same name with a number added if it's already taken
+pub fn unique_file(dir: &Path, file: &str) -> String {
+    if !dir.join(file).exists() {
+        return file.to_string();
+    }
+    let (stem, extension) = match file.rfind('.') {
+        Some(dot) if !file[dot..].contains('/') => file.split_at(dot),
+        _ => (file, ""),
+    };
+    (2..)
+        .map(|n| format!("{}_{}{}", stem, n, extension))
+        .find(|f| !dir.join(f).exists())
+        .unwrap()
+}
+
+pub fn index_path() -> PathBuf {
+    crate::config::APP_DIRS
+        .data_dir()
+        .join("workout_index.json")
+}
+
+pub fn load_index() -> Result<WorkoutIndex> {
+    let index_path = index_path();
+
+    match std::fs::read_to_string(&index_path) {
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(WorkoutIndex::default()),
+        r => {
+            let index =
+                r.with_context(|| format!("Reading workout index {}", index_path.display()))?;
+            serde_json::from_str(&index)
+                .with_context(|| format!("Parsing workout index {}", index_path.display()))
+        }
+    }
+}
+
+pub fn save_index(index: &WorkoutIndex) -> Result<()> {
+    let index_path = index_path();
+
+    std::fs::create_dir_all(index_path.parent().unwrap()).context("Creating the data directory")?;
+    std::fs::write(
+        &index_path,
+        serde_json::to_string_pretty(index).context("Serializing the workout index")?,
+    )
+    .with_context(|| format!("Writing workout index {}", index_path.display()))?;
+
+    Ok(())
+}
+
+/// Load the index, apply a change to it and save it back
+pub fn update_index(f: impl FnOnce(&mut WorkoutIndex)) -> Result<()> {
+    let mut index = load_index()?;
+    f(&mut index);
+    save_index(&index)
+}
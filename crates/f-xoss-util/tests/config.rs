@@ -0,0 +1,62 @@
+// the peripheral ids are written in the BlueZ format
+#![cfg(target_os = "linux")]
+
+use btleplug::api::BDAddr;
+use f_xoss::transport::BatterySource;
+use f_xoss_util::config::{XossUtilConfig, CONFIG_VERSION};
+use std::str::FromStr;
+
+const CONFIG: &str = r#"
+default_device = "XOSS NAV"
+
+[[devices]]
+name = "XOSS NAV"
+peripheral_id = { object_path = "/org/bluez/hci0/dev_AA_BB_CC_DD_EE_FF" }
+address = "AA:BB:CC:DD:EE:FF"
+serial_number = "XN2301234"
+pair = true
+json_protocol = "2.0.0"
+battery_fallback = { ctl = 66 }
+
+[[devices]]
+peripheral_id = { object_path = "/org/bluez/hci0/dev_11_22_33_44_55_66" }
+
+[[devices]]
+peripheral_id = { object_path = "/org/bluez/hci0/dev_22_33_44_55_66_77" }
+battery_fallback = { characteristic = "0000fff1-0000-1000-8000-00805f9b34fb" }
+"#;
+
+#[test]
+fn device_identity_survives_a_round_trip() {
+    let config: XossUtilConfig = toml::from_str(CONFIG).unwrap();
+    assert_eq!(config.version.0, CONFIG_VERSION);
+
+    let device = &config.devices[0];
+    assert_eq!(
+        device.address,
+        Some(BDAddr::from_str("AA:BB:CC:DD:EE:FF").unwrap())
+    );
+    assert_eq!(device.serial_number.as_deref(), Some("XN2301234"));
+    assert!(device.pair);
+    assert_eq!(device.json_protocol.as_deref(), Some("2.0.0"));
+    assert_eq!(device.battery_fallback, Some(BatterySource::Ctl(66)));
+
+    let saved = toml::to_string_pretty(&config).unwrap();
+    assert_eq!(toml::from_str::<XossUtilConfig>(&saved).unwrap(), config);
+}
+
+#[test]
+fn unset_fields_are_not_written() {
+    let config: XossUtilConfig = toml::from_str(CONFIG).unwrap();
+
+    let saved = toml::to_string_pretty(&config.devices[1]).unwrap();
+    for field in [
+        "address",
+        "serial_number",
+        "pair",
+        "json_protocol",
+        "battery_fallback",
+    ] {
+        assert!(!saved.contains(field), "{} in {}", field, saved);
+    }
+}
@@ -0,0 +1,47 @@
+use f_xoss::fit::{self, message, FitHeader};
+use f_xoss_util::export::ExportFormat;
+use f_xoss_util::workout_index::Annotation;
+
+/// A FIT file with a single file id message, with the creation time if given
+fn fit_file(time_created: Option<u32>) -> Vec<u8> {
+    let mut records = vec![0x40, 0, 0];
+    records.extend_from_slice(&message::FILE_ID.to_le_bytes());
+    // type, and time_created
+    records.extend_from_slice(&[2, 0, 1, 0x00, 4, 4, 0x86]);
+    records.extend_from_slice(&[0, 4]);
+    records.extend_from_slice(&time_created.unwrap_or(u32::MAX).to_le_bytes());
+
+    let mut file = FitHeader {
+        header_size: 14,
+        protocol_version: 0x10,
+        profile_version: 2132,
+        data_size: records.len() as u32,
+    }
+    .to_bytes();
+    file.extend_from_slice(&records);
+    let crc = fit::crc(&file);
+    file.extend_from_slice(&crc.to_le_bytes());
+    file
+}
+
+#[test]
+fn tcx_without_a_track_uses_the_file_creation_time() {
+    let data = fit_file(Some(1_000_000_000));
+    let tcx = ExportFormat::Tcx
+        .write("workout", &Annotation::default(), &data, &[])
+        .unwrap();
+    let tcx = String::from_utf8(tcx).unwrap();
+
+    assert!(tcx.contains("<Id>2021-09-08T01:46:40Z</Id>"), "{}", tcx);
+    assert!(tcx.contains("<Lap StartTime=\"2021-09-08T01:46:40Z\">"));
+    assert!(!tcx.contains("<Track>"));
+}
+
+#[test]
+fn tcx_without_a_start_time_is_refused() {
+    let data = fit_file(None);
+    let e = ExportFormat::Tcx
+        .write("workout", &Annotation::default(), &data, &[])
+        .unwrap_err();
+    assert!(e.to_string().contains("no start time"), "{:#}", e);
+}
@@ -0,0 +1,49 @@
+use f_xoss_util::firmware::compare_versions;
+use std::cmp::Ordering;
+
+#[test]
+fn versions_are_compared_by_their_numbers() {
+    assert_eq!(compare_versions("V3.0.4", "3.0.4"), Some(Ordering::Equal));
+    assert_eq!(compare_versions("V3.0.4", "V3.0.10"), Some(Ordering::Less));
+    assert_eq!(
+        compare_versions("V3.1.0", "V3.0.10"),
+        Some(Ordering::Greater)
+    );
+}
+
+#[test]
+fn missing_components_are_zeros() {
+    assert_eq!(compare_versions("3.1", "3.1.0"), Some(Ordering::Equal));
+    assert_eq!(compare_versions("3.1", "3.1.1"), Some(Ordering::Less));
+    assert_eq!(compare_versions("3.1.0.1", "3.1"), Some(Ordering::Greater));
+}
+
+#[test]
+fn pre_releases_come_before_the_release() {
+    assert_eq!(
+        compare_versions("3.1.0-beta", "3.1.0"),
+        Some(Ordering::Less)
+    );
+    assert_eq!(
+        compare_versions("3.1.0", "3.1.0-rc.1"),
+        Some(Ordering::Greater)
+    );
+    assert_eq!(
+        compare_versions("3.1.0-rc.1", "3.0.9"),
+        Some(Ordering::Greater)
+    );
+    assert_eq!(
+        compare_versions("3.1.0-beta.2", "3.1.0-beta.10"),
+        Some(Ordering::Less)
+    );
+    assert_eq!(
+        compare_versions("3.1.0-beta.2", "3.1.0-rc.1"),
+        Some(Ordering::Less)
+    );
+}
+
+#[test]
+fn versions_without_numbers_are_only_equal() {
+    assert_eq!(compare_versions("dev", "dev"), Some(Ordering::Equal));
+    assert_eq!(compare_versions("dev", "3.0.4"), None);
+}
@@ -0,0 +1,37 @@
+use f_xoss_util::secrets::redacted_args;
+
+fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|v| v.to_string()).collect()
+}
+
+#[test]
+fn secret_args_are_redacted() {
+    assert_eq!(
+        redacted_args(args(&[
+            "f-xoss-util",
+            "setup",
+            "--ublox-token",
+            "abc",
+            "--yes"
+        ])),
+        args(&[
+            "f-xoss-util",
+            "setup",
+            "--ublox-token",
+            "<redacted>",
+            "--yes"
+        ])
+    );
+    assert_eq!(
+        redacted_args(args(&["f-xoss-util", "setup", "--ublox-token=abc"])),
+        args(&["f-xoss-util", "setup", "--ublox-token=<redacted>"])
+    );
+}
+
+#[test]
+fn other_args_are_kept() {
+    assert_eq!(
+        redacted_args(args(&["f-xoss-util", "device", "sync", "--ublox-tokens"])),
+        args(&["f-xoss-util", "device", "sync", "--ublox-tokens"])
+    );
+}
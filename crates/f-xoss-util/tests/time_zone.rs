@@ -0,0 +1,21 @@
+use chrono::{TimeZone, Utc};
+use f_xoss_util::config::TimeZoneSetting;
+
+#[test]
+fn named_zones_are_resolved_without_the_system_database() {
+    let berlin = TimeZoneSetting::parse("Europe/Berlin").unwrap();
+    let winter = Utc.with_ymd_and_hms(2024, 1, 15, 12, 0, 0).unwrap();
+    let summer = Utc.with_ymd_and_hms(2024, 7, 15, 12, 0, 0).unwrap();
+    assert_eq!(berlin.offset_at(winter), 3600);
+    assert_eq!(berlin.offset_at(summer), 7200);
+
+    let kolkata = TimeZoneSetting::parse("Asia/Kolkata").unwrap();
+    assert_eq!(kolkata.offset_at(summer), 19800);
+
+    assert_eq!(
+        TimeZoneSetting::parse("+05:45").unwrap(),
+        TimeZoneSetting::Fixed(20700)
+    );
+    assert!(TimeZoneSetting::parse("Mars/Olympus_Mons").is_err());
+    assert!(TimeZoneSetting::parse("../../etc/passwd").is_err());
+}
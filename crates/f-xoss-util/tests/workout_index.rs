@@ -0,0 +1,31 @@
+use f_xoss_util::workout_index::{WorkoutIndex, WorkoutRecord};
+
+#[test]
+fn duplicates_are_confirmed_by_the_content() {
+    let dir = std::env::temp_dir().join(format!("f-xoss-workout-index-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("synced.fit"), b"first workout").unwrap();
+    std::fs::write(dir.join("colliding.fit"), b"other workout").unwrap();
+
+    let downloaded = b"first workout";
+    let mut colliding = WorkoutRecord::new("colliding.fit".to_string(), downloaded, None, None);
+    // a record with the same size and checksum, as with a CRC-32 collision
+    colliding.crc32 = crc32fast::hash(downloaded);
+    let index = WorkoutIndex {
+        workouts: vec![
+            colliding,
+            WorkoutRecord::new("synced.fit".to_string(), downloaded, None, None),
+        ],
+    };
+
+    let record = WorkoutRecord::new("new.fit".to_string(), downloaded, None, None);
+    let duplicate = index.find_duplicate(&dir, &record, downloaded);
+    assert_eq!(duplicate.map(|r| r.file.as_str()), Some("synced.fit"));
+
+    let record = WorkoutRecord::new("new.fit".to_string(), b"third workout", None, None);
+    assert!(index
+        .find_duplicate(&dir, &record, b"third workout")
+        .is_none());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,120 @@
+//! Protocol-layer overhead of the file transfers, without any BLE involved
+//!
+//! The `ymodem` group sends the files through an in-memory pipe, so the numbers show how fast the YModem
+//! implementation itself is. The `device` group goes through [XossDevice::read_file] and [XossDevice::write_file]
+//! against the simulated device, adding the control messages and the UART framing.
+//! The regression check in CI compares them against the base branch.
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use common::test_pattern;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use f_xoss::device::XossDevice;
+use f_xoss::progress::NoProgress;
+use f_xoss::transport::mock::MockDevice;
+use f_xoss::transport::ymodem::{receive_file, send_file};
+use f_xoss::transport::{DeviceInformation, TransportOptions, XossTransport};
+use std::io::Cursor;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio_stream::StreamExt;
+
+const FILE_SIZES: [usize; 3] = [4 * 1024, 64 * 1024, 1024 * 1024];
+
+async fn transfer(content: &[u8]) -> usize {
+    // roughly the amount of data in flight over BLE
+    let (mut sender_io, mut receiver_io) = tokio::io::duplex(4096);
+
+    let send = async {
+        send_file(
+            &mut sender_io,
+            "bench.bin",
+            &mut Cursor::new(content),
+            &NoProgress,
+        )
+        .await
+        .expect("Sending failed");
+    };
+    let receive = async {
+        let (_, stream) = receive_file(&mut receiver_io, &NoProgress)
+            .await
+            .expect("Receiving the header failed");
+        tokio::pin!(stream);
+
+        let mut received = 0;
+        while let Some(chunk) = stream.next().await {
+            received += chunk.expect("Receiving failed").len();
+        }
+        received
+    };
+
+    let ((), received) = tokio::join!(send, receive);
+    received
+}
+
+fn ymodem(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+
+    let mut group = c.benchmark_group("ymodem");
+    for size in FILE_SIZES {
+        let content = test_pattern(size);
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &content, |b, content| {
+            b.to_async(&runtime).iter(|| transfer(content))
+        });
+    }
+    group.finish();
+}
+
+fn device(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+
+    let mock = MockDevice::new(DeviceInformation {
+        firmware_revision: "1.0.0".to_string(),
+        manufacturer_name: "XOSS".to_string(),
+        model_number: "XOSS NAV".to_string(),
+        hardware_revision: "A1".to_string(),
+        serial_number: "0000000001".to_string(),
+    });
+    let device = runtime.block_on(async {
+        XossDevice::with_transport(XossTransport::mock(&mock, TransportOptions::default()))
+            .await
+            .expect("Connecting to the simulated device failed")
+    });
+
+    let mut group = c.benchmark_group("device");
+    for size in FILE_SIZES {
+        let content = test_pattern(size);
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new("write", size), &content, |b, content| {
+            b.to_async(&runtime).iter(|| async {
+                device
+                    .write_file("bench.bin", content, &NoProgress)
+                    .await
+                    .expect("Writing failed")
+            })
+        });
+        mock.set_file("bench.bin", content.clone());
+        group.bench_with_input(BenchmarkId::new("read", size), &content, |b, content| {
+            b.to_async(&runtime).iter(|| async {
+                let read = device
+                    .read_file("bench.bin", &NoProgress)
+                    .await
+                    .expect("Reading failed");
+                assert_eq!(read.len(), content.len());
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    // changes smaller than this are not reported
+    config = Criterion::default()
+        .noise_threshold(0.05)
+        .measurement_time(Duration::from_secs(5));
+    targets = ymodem, device
+}
+criterion_main!(benches);
@@ -0,0 +1,251 @@
+//! Terrain elevation from offline digital elevation model (DEM) tiles
+//!
+//! The tiles are in the SRTM `.hgt` format: one file per 1x1 degree cell, named after its south-west corner
+//! (like `N45E007.hgt`), holding a square grid of big-endian 16-bit heights in meters, rows from north to south.
+//! The usual grids are 1201x1201 (3 arc-seconds) and 3601x3601 (1 arc-second).
+//!
+//! The barometric altitude recorded by the device is sometimes obviously broken (a wrong calibration at the start,
+//! a clogged sensor port), [correct_elevation] replaces it with the terrain elevation.
+
+use crate::fit::TrackPoint;
+use crate::geo::LatLon;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// The value used for the missing samples
+const VOID: i16 = -32768;
+
+#[derive(Error, Debug)]
+pub enum DemError {
+    #[error("The tile size ({0} bytes) does not match a square grid of 16-bit samples")]
+    InvalidSize(usize),
+    #[error("Failed to read {path}")]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+}
+
+/// A single 1x1 degree tile
+#[derive(Debug, Clone)]
+pub struct DemTile {
+    /// Samples per side
+    size: usize,
+    samples: Vec<i16>,
+}
+
+impl DemTile {
+    pub fn parse(data: &[u8]) -> Result<Self, DemError> {
+        let count = data.len() / 2;
+        let size = (count as f64).sqrt().round() as usize;
+        if data.len() % 2 != 0 || size < 2 || size * size != count {
+            return Err(DemError::InvalidSize(data.len()));
+        }
+
+        let samples = data
+            .chunks_exact(2)
+            .map(|c| i16::from_be_bytes([c[0], c[1]]))
+            .collect();
+        Ok(Self { size, samples })
+    }
+
+    fn sample(&self, row: usize, col: usize) -> Option<f64> {
+        let value = self.samples[row * self.size + col];
+        (value != VOID).then_some(value as f64)
+    }
+
+    /// Bilinear interpolation at a position inside the tile, `x` and `y` being in 0..1 from the south-west corner
+    fn interpolate(&self, x: f64, y: f64) -> Option<f64> {
+        let n = (self.size - 1) as f64;
+        let col = (x * n).clamp(0.0, n);
+        let row = ((1.0 - y) * n).clamp(0.0, n);
+
+        let (col0, row0) = (col.floor() as usize, row.floor() as usize);
+        let (col1, row1) = ((col0 + 1).min(self.size - 1), (row0 + 1).min(self.size - 1));
+        let (dx, dy) = (col - col0 as f64, row - row0 as f64);
+
+        let top = self.sample(row0, col0)? * (1.0 - dx) + self.sample(row0, col1)? * dx;
+        let bottom = self.sample(row1, col0)? * (1.0 - dx) + self.sample(row1, col1)? * dx;
+        Some(top * (1.0 - dy) + bottom * dy)
+    }
+}
+
+/// The name of the tile covering the point, like `N45E007.hgt`
+pub fn tile_name(point: LatLon) -> String {
+    let lat = point.lat.floor() as i32;
+    let lon = point.lon.floor() as i32;
+    format!(
+        "{}{:02}{}{:03}.hgt",
+        if lat < 0 { 'S' } else { 'N' },
+        lat.abs(),
+        if lon < 0 { 'W' } else { 'E' },
+        lon.abs()
+    )
+}
+
+/// The tiles in a directory, loaded when first needed
+pub struct Dem {
+    dir: PathBuf,
+    /// `None` for the tiles missing from the directory
+    tiles: HashMap<(i32, i32), Option<DemTile>>,
+}
+
+impl Dem {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            tiles: HashMap::new(),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn tile(&mut self, lat: i32, lon: i32) -> Result<Option<&DemTile>, DemError> {
+        if !self.tiles.contains_key(&(lat, lon)) {
+            let path = self
+                .dir
+                .join(tile_name(LatLon::new(lat as f64, lon as f64)));
+            let tile = match std::fs::read(&path) {
+                Ok(data) => Some(DemTile::parse(&data)?),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(source) => return Err(DemError::Io { path, source }),
+            };
+            self.tiles.insert((lat, lon), tile);
+        }
+        Ok(self.tiles[&(lat, lon)].as_ref())
+    }
+
+    /// Terrain elevation in meters, `None` if the tile is missing or has a void there
+    pub fn elevation(&mut self, point: LatLon) -> Result<Option<f64>, DemError> {
+        let (lat, lon) = (point.lat.floor(), point.lon.floor());
+        let Some(tile) = self.tile(lat as i32, lon as i32)? else {
+            return Ok(None);
+        };
+        Ok(tile.interpolate(point.lon - lon, point.lat - lat))
+    }
+
+    /// Names of the tiles needed for the points that are not in the directory
+    pub fn missing_tiles<'a>(
+        &mut self,
+        points: impl IntoIterator<Item = &'a LatLon>,
+    ) -> Result<Vec<String>, DemError> {
+        let mut missing = Vec::new();
+        for point in points {
+            let name = tile_name(*point);
+            if self
+                .tile(point.lat.floor() as i32, point.lon.floor() as i32)?
+                .is_none()
+                && !missing.contains(&name)
+            {
+                missing.push(name);
+            }
+        }
+        Ok(missing)
+    }
+}
+
+/// Why the recorded elevation is considered broken
+#[derive(Debug, Clone, PartialEq)]
+pub enum ElevationProblem {
+    /// Most of the points have no altitude at all
+    Missing,
+    /// Altitudes no place on Earth has
+    OutOfRange,
+    /// The altitude doesn't change over a long distance
+    Flat,
+    /// The altitude jumps faster than a bike can climb or fall
+    Spikes,
+    /// The altitude is consistently off from the terrain, by the median difference in meters
+    Offset(f64),
+}
+
+/// The lowest and the highest altitudes that make sense for a bike ride, with some margin
+const PLAUSIBLE_ALTITUDE: std::ops::RangeInclusive<f64> = -450.0..=6000.0;
+/// Meters per second
+const MAX_VERTICAL_SPEED: f64 = 20.0;
+/// Meters, the usual barometric drift over a ride stays well below that
+const MAX_TERRAIN_OFFSET: f64 = 100.0;
+
+/// Check the recorded elevation for the problems that are obvious without the terrain data
+pub fn elevation_problem(track: &[TrackPoint]) -> Option<ElevationProblem> {
+    let with_altitude = track
+        .iter()
+        .filter_map(|p| p.altitude.map(|a| (p, a)))
+        .collect::<Vec<_>>();
+    if with_altitude.len() * 2 < track.len() {
+        return Some(ElevationProblem::Missing);
+    }
+    if with_altitude
+        .iter()
+        .any(|(_, a)| !PLAUSIBLE_ALTITUDE.contains(a))
+    {
+        return Some(ElevationProblem::OutOfRange);
+    }
+    if with_altitude.windows(2).any(|w| {
+        let (p0, a0) = w[0];
+        let (p1, a1) = w[1];
+        let dt = (p1.time - p0.time).num_seconds().max(1) as f64;
+        (a1 - a0).abs() / dt > MAX_VERTICAL_SPEED
+    }) {
+        return Some(ElevationProblem::Spikes);
+    }
+
+    let positions = track.iter().filter_map(|p| p.position).collect::<Vec<_>>();
+    let (min, max) = with_altitude
+        .iter()
+        .fold((f64::MAX, f64::MIN), |(min, max), (_, a)| {
+            (min.min(*a), max.max(*a))
+        });
+    if crate::geo::track_length(&positions) > 5000.0 && max - min < 1.0 {
+        return Some(ElevationProblem::Flat);
+    }
+
+    None
+}
+
+/// Same as [elevation_problem], also comparing the altitude with the terrain
+pub fn elevation_problem_with_dem(
+    track: &[TrackPoint],
+    dem: &mut Dem,
+) -> Result<Option<ElevationProblem>, DemError> {
+    if let Some(problem) = elevation_problem(track) {
+        return Ok(Some(problem));
+    }
+
+    let mut differences = Vec::new();
+    for point in track {
+        if let (Some(position), Some(altitude)) = (point.position, point.altitude) {
+            if let Some(terrain) = dem.elevation(position)? {
+                differences.push((altitude - terrain).abs());
+            }
+        }
+    }
+    if differences.is_empty() {
+        return Ok(None);
+    }
+    differences.sort_by(f64::total_cmp);
+    let median = differences[differences.len() / 2];
+
+    Ok((median > MAX_TERRAIN_OFFSET).then_some(ElevationProblem::Offset(median)))
+}
+
+/// Replace the altitude of the points with the terrain elevation, returns the number of the points changed
+///
+/// The points without a position or outside of the available tiles keep their altitude.
+pub fn correct_elevation(track: &mut [TrackPoint], dem: &mut Dem) -> Result<usize, DemError> {
+    let mut corrected = 0;
+    for point in track {
+        let Some(position) = point.position else {
+            continue;
+        };
+        if let Some(elevation) = dem.elevation(position)? {
+            point.altitude = Some(elevation);
+            corrected += 1;
+        }
+    }
+    Ok(corrected)
+}
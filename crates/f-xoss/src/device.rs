@@ -1,31 +1,160 @@
 //! This module provides high-level device communication functions. They try to be atomic and leave the device in a consistent state.
 
-use crate::transport::{CtlBuffer, XossTransport, CTL_BUFFER_SIZE};
+use crate::transcript::{Transcript, TranscriptEntry};
+use crate::transport::peripheral::{BlePeripheral, Characteristic};
+use crate::transport::{CtlBuffer, TransportOptions, XossTransport};
+use std::collections::{BTreeSet, HashSet};
 use std::fmt::{Debug, Display};
-use std::io::{Cursor, ErrorKind};
-use std::time::SystemTime;
-
-use crate::model::{Gear, HeaderJson, Route, Settings, UserProfile, WithHeader, WorkoutsItem};
+use std::future::Future;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::json_protocol::{JsonProtocol, UnsupportedJsonVersion};
+use crate::model::{
+    collect_unknown_fields, Gear, HeaderJson, Panels, Route, Sensor, Settings, UserProfile,
+    WithHeader, WorkoutState, WorkoutsItem,
+};
+use crate::progress::{format_size, NoProgress, ProgressSink};
 use crate::transport;
-use crate::transport::ctl_message::ControlMessageType;
-use anyhow::{Context, Result};
-use btleplug::platform::Peripheral;
+use crate::transport::ctl_message::{ControlError, ControlMessageType, UnexpectedReply};
+use crate::transport::deviation::{DeviationPolicy, ProtocolDeviation};
+use anyhow::{bail, Context, Result};
 use chrono::{NaiveDate, NaiveDateTime};
 use futures_util::{pin_mut, TryStreamExt};
 use serde::{Deserialize, Serialize};
-use tokio::io::AsyncReadExt;
-use tokio::sync::{Mutex, OnceCell};
+use thiserror::Error;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::{watch, Mutex, MutexGuard, OnceCell};
 use tokio::time::Instant;
-use tokio_util::io::StreamReader;
 use tracing::{debug, info, instrument, trace, warn, Level, Span};
 
+/// Files used by the firmware itself
+const SYSTEM_FILES: &[&str] = &[
+    "user_profile.json",
+    "settings.json",
+    "workouts.json",
+    "gear_profile.json",
+    "routebooks.json",
+    "panels.json",
+    "sensors.json",
+    "offline.gnss",
+];
+
+/// What a file on the device is
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DeviceFileKind {
+    /// One of the JSON files with the settings and the indexes
+    Config,
+    Workout,
+    Route,
+    /// The A-GNSS data (`offline.gnss`)
+    Mga,
+}
+
+impl Display for DeviceFileKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceFileKind::Config => write!(f, "config"),
+            DeviceFileKind::Workout => write!(f, "workout"),
+            DeviceFileKind::Route => write!(f, "route"),
+            DeviceFileKind::Mga => write!(f, "A-GNSS data"),
+        }
+    }
+}
+
+/// A file known to be on the device, see [XossDevice::list_files]
+#[derive(Debug, Clone)]
+pub struct DeviceFile {
+    pub name: String,
+    pub kind: DeviceFileKind,
+    /// Size in bytes, `None` when the device doesn't tell it
+    pub size: Option<u64>,
+}
+
+/// How an upload was checked by [XossDevice::write_file_verified]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UploadVerification {
+    /// The file was read back, its CRC-32 matches the uploaded one
+    Checksum(u32),
+    /// The file can't be read back, but its size in the workout or route index matches
+    IndexedSize(u64),
+    /// The file can't be read back and it's not in any index (like offline.gnss, which the device consumes)
+    Unverifiable,
+}
+
+#[derive(Error, Debug)]
+pub enum VerificationError {
+    #[error("The device has {actual} bytes, but {expected} bytes were uploaded")]
+    SizeMismatch { expected: u64, actual: u64 },
+    #[error(
+        "The file on the device has CRC-32 {actual:08x}, but the uploaded one has {expected:08x}"
+    )]
+    ChecksumMismatch { expected: u32, actual: u32 },
+}
+
+#[derive(Deserialize, Default)]
+struct WorkoutsWrap {
+    pub workouts: Vec<WorkoutsItem>,
+}
+
+#[derive(Deserialize, Default)]
+struct RoutesWrap {
+    pub routes: Vec<Route>,
+}
+
+fn is_no_file(error: &anyhow::Error) -> bool {
+    error
+        .chain()
+        .any(|e| matches!(e.downcast_ref(), Some(ControlError::NoFile(_))))
+}
+
 pub struct XossDevice {
     // TODO: should we allow reconnecting? This might be a good place to do it
     // This would also necessitate BLE disconnect detection
     transport: Mutex<XossTransport>,
+    deviations: Arc<DeviationPolicy>,
     json_header: OnceCell<HeaderJson>,
+    /// See [XossDevice::set_json_protocol]
+    json_protocol: std::sync::Mutex<Option<&'static JsonProtocol>>,
+    transfer_observer: std::sync::Mutex<Option<TransferObserver>>,
+    json_backup: std::sync::Mutex<Option<JsonBackup>>,
+    transcript: std::sync::Mutex<Option<Transcript>>,
+    /// When the transport was last taken for an operation, see [XossDevice::keepalive]
+    last_used: std::sync::Mutex<Instant>,
+    battery_level: watch::Receiver<Option<u32>>,
+    link_monitor: transport::LinkMonitor,
+    device_info: transport::DeviceInformation,
+    /// The device has rejected [ControlMessageType::StatusAct], it's not asked again
+    activity_status_unsupported: AtomicBool,
+    /// See [XossDevice::set_allow_while_recording]
+    allow_while_recording: AtomicBool,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TransferDirection {
+    Download,
+    Upload,
+}
+
+/// A file transfer that has completed successfully
+#[derive(Debug)]
+pub struct TransferEvent<'a> {
+    pub filename: &'a str,
+    pub direction: TransferDirection,
+    /// Size of the transferred file in bytes
+    pub size: u64,
+    /// CRC-32 of the transferred file
+    pub crc32: u32,
 }
 
+pub type TransferObserver = Box<dyn Fn(&TransferEvent) + Send + Sync>;
+
+/// Keeps the content of a JSON file that is about to be overwritten, called with the file name and its content
+pub type JsonBackup = Box<dyn Fn(&str, &[u8]) -> Result<()> + Send + Sync>;
+
 #[derive(Debug, Clone)]
 pub struct MemoryCapacity {
     pub free_kb: u32,
@@ -37,8 +166,8 @@ impl Display for MemoryCapacity {
         write!(
             f,
             "{} / {} ({:.02}% used)",
-            humansize::format_size(self.free_kb as u64 * 1024, humansize::BINARY),
-            humansize::format_size(self.total_kb as u64 * 1024, humansize::BINARY),
+            format_size(self.free_kb as u64 * 1024),
+            format_size(self.total_kb as u64 * 1024),
             (self.total_kb - self.free_kb) as f32 / self.total_kb as f32 * 100.0
         )
     }
@@ -59,82 +188,645 @@ impl Display for MgaState {
     }
 }
 
-impl XossDevice {
-    pub async fn new(peripheral: Peripheral) -> Result<Self> {
-        let transport = XossTransport::new(peripheral).await?;
+/// The control messages [XossDevice::probe_ctl] sends: the ones that only read the state of the device
+pub const PROBED_CTL_MESSAGES: &[ControlMessageType] = &[
+    ControlMessageType::StatusReturn,
+    ControlMessageType::DbgCmd,
+    ControlMessageType::RequestCap,
+    ControlMessageType::RequestMga,
+    ControlMessageType::StatusAct,
+    ControlMessageType::RequestDetail,
+];
+
+/// A control message [XossDevice::request_ctl] doesn't send, as it's not in [PROBED_CTL_MESSAGES]
+#[derive(Error, Debug)]
+#[error("{0:?} changes the state of the device, it can only be sent with request_ctl_raw")]
+pub struct NotReadOnly(pub ControlMessageType);
+
+/// How the device replied to a control message sent by [XossDevice::probe_ctl]
+#[derive(Debug)]
+pub enum CtlProbeResult {
+    /// Replied with a message of this type
+    Replied(ControlMessageType),
+    /// Replied with an error, [ControlError::Validation] when the firmware doesn't know the message
+    Refused(ControlError),
+    /// Didn't reply in time, or the reply couldn't be decoded
+    Failed(anyhow::Error),
+}
 
-        let mut buffer = [0; CTL_BUFFER_SIZE];
-        if transport
-            .request_ctl(&mut buffer, ControlMessageType::StatusReturn, &[])
-            .await
-            .context("Getting transfer status")?
-            .message_type
-            != ControlMessageType::Idle
-        {
-            info!("Device has an active transfer, stopping it");
-            transport
-                .request_ctl(&mut buffer, ControlMessageType::RequestStop, &[])
-                .await
-                .context("Stopping the transfer")?
-                .expect_ok(ControlMessageType::Idle)
-                .context("Failed to stop the transfer")?;
+impl CtlProbeResult {
+    pub fn is_supported(&self) -> bool {
+        matches!(self, Self::Replied(_))
+    }
+}
+
+impl Display for CtlProbeResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Replied(message_type) => write!(f, "{:?}", message_type),
+            Self::Refused(error) => write!(f, "{}", error),
+            Self::Failed(error) => write!(f, "{:#}", error),
+        }
+    }
+}
+
+/// The identifier returned by [ControlMessageType::DbgCmd], different for each unit
+///
+/// Unlike the serial number, it's available on the devices that don't have the serial number characteristic
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct DebugId(pub [u8; 8]);
+
+impl DebugId {
+    /// Parse the body of the reply, some firmware versions send the bytes as they are and some as hex text
+    pub fn parse(body: &[u8]) -> Result<Self> {
+        if let Ok(id) = body.try_into() {
+            return Ok(Self(id));
+        }
+        let mut id = [0; 8];
+        if hex::decode_to_slice(body, &mut id).is_err() {
+            bail!("Unexpected device identifier: {}", hex::encode(body));
+        }
+        Ok(Self(id))
+    }
+}
+
+impl Display for DebugId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode_upper(self.0))
+    }
+}
+
+/// What the device is doing, as reported by [ControlMessageType::StatusAct]
+///
+/// The byte values are not documented by the vendor and have not been confirmed with a capture from a real device
+/// yet: 0, 1 and 2 are assumed to be idle, recording and paused. Any other value is treated as possibly recording, so
+/// that a misread status doesn't let a destructive operation through.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ActivityStatus {
+    /// No workout is being recorded
+    Idle,
+    Recording,
+    /// A workout is being recorded, but is paused
+    Paused,
+    /// A value not seen so far, please report it. The device may be recording
+    Unknown(u8),
+}
+
+impl ActivityStatus {
+    pub fn from_byte(value: u8) -> Self {
+        match value {
+            0 => Self::Idle,
+            1 => Self::Recording,
+            2 => Self::Paused,
+            value => Self::Unknown(value),
+        }
+    }
+
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Self::Idle => 0,
+            Self::Recording => 1,
+            Self::Paused => 2,
+            Self::Unknown(value) => value,
+        }
+    }
+
+    /// Whether the device may have an unfinished workout, which the destructive operations could corrupt
+    ///
+    /// True for the unknown values too, see [ActivityStatus].
+    pub fn is_recording(self) -> bool {
+        !matches!(self, Self::Idle)
+    }
+}
+
+impl Display for ActivityStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Idle => write!(f, "Idle"),
+            Self::Recording => write!(f, "Recording a workout"),
+            Self::Paused => write!(f, "Recording a workout (paused)"),
+            Self::Unknown(value) => write!(f, "Unknown ({:#04x}), possibly recording", value),
         }
+    }
+}
+
+/// The device is recording a workout, returned instead of running an operation that could corrupt it
+///
+/// These are deleting the files, writing the JSON configs, a factory reset and rebooting into the DFU mode.
+/// Can be overridden with [XossDevice::set_allow_while_recording].
+#[derive(Error, Debug)]
+#[error("The device is recording a workout ({0}), finish it first")]
+pub struct DeviceRecording(pub ActivityStatus);
+
+/// How much space an upload leaves free on the device, for the workouts it records
+pub const FREE_SPACE_MARGIN: u64 = 256 * 1024;
+
+/// The device doesn't have the space for a file, see [XossDevice::ensure_free_space]
+#[derive(Error, Debug)]
+#[error(
+    "Not enough space on the device for {filename} ({}): {} is free, and {} has to stay free for recording the \
+     workouts. Delete the synced workouts first",
+    format_size(*.size),
+    format_size(.capacity.free_kb as u64 * 1024),
+    format_size(FREE_SPACE_MARGIN)
+)]
+pub struct InsufficientSpace {
+    pub filename: String,
+    pub size: u64,
+    pub capacity: MemoryCapacity,
+}
+
+/// Another operation is using the device, returned by the `try_` methods instead of waiting for it
+#[derive(Error, Debug)]
+#[error("The device is busy with another operation")]
+pub struct DeviceBusy;
+
+/// Make sure the device is not in the middle of a file transfer, stopping it if needed
+async fn stop_transfer(transport: &XossTransport) -> Result<()> {
+    let mut buffer = CtlBuffer::default();
+    if transport
+        .request_ctl(&mut buffer, ControlMessageType::StatusReturn, &[])
+        .await
+        .context("Getting transfer status")?
+        .message_type
+        != ControlMessageType::Idle
+    {
+        info!("Device has an active transfer, stopping it");
+        transport
+            .request_ctl(&mut buffer, ControlMessageType::RequestStop, &[])
+            .await
+            .context("Stopping the transfer")?
+            .expect_ok(ControlMessageType::Idle)
+            .context("Failed to stop the transfer")?;
+    }
+
+    Ok(())
+}
+
+/// Get the state of the device's file transfer state machine, see [XossDevice::get_transfer_status]
+async fn transfer_status(transport: &XossTransport) -> Result<ControlMessageType> {
+    let mut buffer = CtlBuffer::default();
+    transport
+        .request_ctl(&mut buffer, ControlMessageType::StatusReturn, &[])
+        .await
+        .context("Failed to send a control message")?
+        .into_result()
+        .context("Failed to get the transfer status")
+        .map(|m| m.message_type)
+}
+
+/// Send a message that only reads the state of the device, see [XossDevice::request_ctl]
+async fn read_only_ctl(
+    transport: &XossTransport,
+    message_type: ControlMessageType,
+    body: &[u8],
+) -> Result<(ControlMessageType, Vec<u8>)> {
+    let mut buffer = CtlBuffer::default();
+    let reply = transport
+        .request_ctl(&mut buffer, message_type, body)
+        .await
+        .context("Failed to send a control message")?
+        .into_result()
+        .with_context(|| format!("{:?} failed", message_type))?;
+    Ok((reply.message_type, reply.body.to_vec()))
+}
+
+/// Get the activity status, see [XossDevice::get_activity_status]
+async fn activity_status(transport: &XossTransport) -> Result<ActivityStatus> {
+    let mut buffer = CtlBuffer::default();
+    let body = transport
+        .request_ctl(&mut buffer, ControlMessageType::StatusAct, &[])
+        .await
+        .context("Failed to send a control message")?
+        .expect_ok(ControlMessageType::StatusAct)
+        .context("Failed to get the activity status")?;
+    match body {
+        [status, ..] => Ok(ActivityStatus::from_byte(*status)),
+        [] => bail!("Empty activity status"),
+    }
+}
+
+async fn memory_capacity(transport: &XossTransport) -> Result<MemoryCapacity> {
+    let mut buffer = CtlBuffer::default();
+    transport
+        .request_ctl(&mut buffer, ControlMessageType::RequestCap, &[])
+        .await
+        .context("Failed to send a control message")?
+        .expect_ok(ControlMessageType::ReturnCap)
+        .context("Failed to get memory capacity")
+        .and_then(|b| {
+            std::str::from_utf8(b).context("Failed to parse the capacity string as UTF-8")
+        })
+        .and_then(|s| {
+            let (left, right) = s
+                .split_once('/')
+                .context("Failed to parse the capacity string")?;
+            let free_kb = left
+                .parse::<u32>()
+                .context("Failed to parse the free capacity")?;
+            let total_kb = right
+                .parse::<u32>()
+                .context("Failed to parse the total capacity")?;
+            Ok(MemoryCapacity { free_kb, total_kb })
+        })
+}
+
+impl XossDevice {
+    pub async fn new(peripheral: impl BlePeripheral + 'static) -> Result<Self> {
+        Self::with_options(peripheral, TransportOptions::default()).await
+    }
+
+    pub async fn with_options(
+        peripheral: impl BlePeripheral + 'static,
+        options: TransportOptions,
+    ) -> Result<Self> {
+        let transport = XossTransport::new(peripheral, options).await?;
+        Self::with_transport(transport).await
+    }
+
+    /// Use an already connected transport, like the one to a simulated device (see `transport::mock`)
+    pub async fn with_transport(transport: XossTransport) -> Result<Self> {
+        stop_transfer(&transport).await?;
 
         Ok(Self {
+            deviations: transport.deviations().clone(),
+            battery_level: transport.subscribe_battery_level(),
+            link_monitor: transport.link_monitor(),
+            device_info: transport.device_info().clone(),
             transport: Mutex::new(transport),
             json_header: OnceCell::new(),
+            json_protocol: std::sync::Mutex::new(None),
+            transfer_observer: std::sync::Mutex::new(None),
+            json_backup: std::sync::Mutex::new(None),
+            transcript: std::sync::Mutex::new(None),
+            last_used: std::sync::Mutex::new(Instant::now()),
+            activity_status_unsupported: AtomicBool::new(false),
+            allow_while_recording: AtomicBool::new(false),
         })
     }
 
+    /// Take the transport for an operation
+    ///
+    /// The device replies to the control messages in order and the replies don't say which request they are for,
+    /// and a file transfer has its own exchange of them, so the operations can't overlap. The lock is fair: the waiting
+    /// operations get the transport in the order they asked for it, a long transfer can't be overtaken but can't
+    /// starve the others either.
+    async fn transport(&self) -> MutexGuard<'_, XossTransport> {
+        let transport = self.transport.lock().await;
+        *self.last_used.lock().unwrap() = Instant::now();
+        transport
+    }
+
+    /// Take the transport if no operation is using it
+    ///
+    /// Doesn't overtake the queue of [Self::transport] either: when an operation finishes, the transport is handed to
+    /// the next waiting one, so it's only free once all of them are done.
+    fn try_transport(&self) -> Result<MutexGuard<'_, XossTransport>, DeviceBusy> {
+        let transport = self.transport.try_lock().map_err(|_| DeviceBusy)?;
+        *self.last_used.lock().unwrap() = Instant::now();
+        Ok(transport)
+    }
+
+    /// Check the device every `interval` while it's not used, stopping the transfers it got stuck in
+    ///
+    /// In the long sessions the transfer state machine of the device sometimes gets out of sync, after which it
+    /// rejects everything with [ControlMessageType::ErrStatus]. When nothing is being done, the device must be idle,
+    /// so a transfer it reports then is stopped before the next operation runs into it.
+    ///
+    /// Never returns, meant to be run alongside the operations (with `select!`) for as long as the connection is kept.
+    pub async fn keepalive(&self, interval: Duration) {
+        loop {
+            tokio::time::sleep(interval).await;
+            if self.last_used.lock().unwrap().elapsed() < interval {
+                continue;
+            }
+            // an operation is running
+            let Ok(transport) = self.transport.try_lock() else {
+                continue;
+            };
+
+            let mut buffer = CtlBuffer::default();
+            let status = match transport
+                .request_ctl(&mut buffer, ControlMessageType::StatusReturn, &[])
+                .await
+            {
+                Ok(status) => status.message_type,
+                Err(e) => {
+                    warn!("Keepalive: failed to get the transfer status: {:#}", e);
+                    continue;
+                }
+            };
+            if status == ControlMessageType::Idle {
+                trace!("Keepalive: the device is idle");
+                continue;
+            }
+
+            warn!(
+                "Keepalive: the device reports {:?} while nothing is being done, stopping the transfer",
+                status
+            );
+            if let Err(e) = transport
+                .request_ctl(&mut buffer, ControlMessageType::RequestStop, &[])
+                .await
+                .and_then(|reply| reply.expect_ok(ControlMessageType::Idle).map(|_| ()))
+            {
+                warn!("Keepalive: failed to stop the transfer: {:#}", e);
+            }
+        }
+    }
+
     pub async fn disconnect(self) -> Result<()> {
         // TODO: how we handle disconnecting from the device is subject to change
         let transport = self.transport.into_inner();
         transport.disconnect().await
     }
 
-    pub async fn device_info(&self) -> transport::DeviceInformation {
-        let transport = self.transport.lock().await;
-        transport.device_info().clone()
+    /// Make sure the device is not in the middle of a file transfer, stopping it if needed
+    ///
+    /// Should be called after a transfer was abandoned midway (for example, its future was dropped),
+    /// otherwise the device keeps waiting for it and rejects other commands
+    pub async fn stop_transfer(&self) -> Result<()> {
+        let transport = self.transport().await;
+        stop_transfer(&transport).await
     }
 
-    pub async fn battery_level(&self) -> u32 {
-        let transport = self.transport.lock().await;
-        transport.battery_level()
+    /// Fail the operations on the protocol deviations that are normally only logged
+    ///
+    /// These are unknown notifications, unexpected JSON header versions, truncated echoes and the like.
+    /// Useful when checking a new firmware version for protocol changes.
+    pub fn set_strict_protocol(&self, strict: bool) {
+        self.deviations.set_strict(strict);
     }
 
-    pub async fn get_memory_capacity(&self) -> Result<MemoryCapacity> {
-        let transport = self.transport.lock().await;
-        let mut buffer = [0; CTL_BUFFER_SIZE];
-        transport
-            .request_ctl(&mut buffer, ControlMessageType::RequestCap, &[])
+    /// Set a function to be called after each successful file transfer
+    ///
+    /// Can be used to keep a log of the transfers. It's called synchronously, so it should not take long
+    pub fn set_transfer_observer(&self, observer: TransferObserver) {
+        *self.transfer_observer.lock().unwrap() = Some(observer);
+    }
+
+    /// Set a function to keep the current content of the JSON files before they are overwritten
+    ///
+    /// A JSON file is not written if the backup fails. It's called synchronously, so it should not take long
+    pub fn set_json_backup(&self, backup: JsonBackup) {
+        *self.json_backup.lock().unwrap() = Some(backup);
+    }
+
+    /// Treat the JSON files as written in the given format, whatever version their headers have
+    ///
+    /// For the firmware versions that work like one of [JSON_PROTOCOLS](crate::json_protocol::JSON_PROTOCOLS), but
+    /// write another version in the headers. Without it, the files of the unknown versions fail to read with
+    /// [UnsupportedJsonVersion].
+    pub fn set_json_protocol(&self, version: &str) -> Result<(), UnsupportedJsonVersion> {
+        *self.json_protocol.lock().unwrap() = Some(JsonProtocol::find(version)?);
+        Ok(())
+    }
+
+    /// The format of the device JSON files, chosen with [XossDevice::set_json_protocol] or told by their headers
+    pub async fn json_protocol(&self) -> Result<&'static JsonProtocol> {
+        if let Some(protocol) = *self.json_protocol.lock().unwrap() {
+            return Ok(protocol);
+        }
+        let header = self.get_device_json_header().await?;
+        Ok(JsonProtocol::find(&header.version)?)
+    }
+
+    /// The format of a file with the given header
+    fn json_protocol_of(
+        &self,
+        filename: &str,
+        header: &HeaderJson,
+    ) -> Result<&'static JsonProtocol, UnsupportedJsonVersion> {
+        match *self.json_protocol.lock().unwrap() {
+            Some(protocol) => {
+                if header.version != protocol.version {
+                    debug!(
+                        "{} has the version {}, treating it as {}",
+                        filename, header.version, protocol.version
+                    );
+                }
+                Ok(protocol)
+            }
+            None => JsonProtocol::find(&header.version),
+        }
+    }
+
+    /// Record the control messages and the file transfers from now on
+    pub async fn set_transcript(&self, transcript: Transcript) {
+        self.transport
+            .lock()
             .await
-            .context("Failed to send a control message")?
-            .expect_ok(ControlMessageType::ReturnCap)
-            .context("Failed to get memory capacity")
-            .and_then(|b| {
-                std::str::from_utf8(b).context("Failed to parse the capacity string as UTF-8")
-            })
-            .and_then(|s| {
-                let (left, right) = s
-                    .split_once('/')
-                    .context("Failed to parse the capacity string")?;
-                let free_kb = left
-                    .parse::<u32>()
-                    .context("Failed to parse the free capacity")?;
-                let total_kb = right
-                    .parse::<u32>()
-                    .context("Failed to parse the total capacity")?;
-                Ok(MemoryCapacity { free_kb, total_kb })
-            })
+            .set_transcript(transcript.clone());
+        *self.transcript.lock().unwrap() = Some(transcript);
+    }
+
+    fn notify_transfer(&self, filename: &str, direction: TransferDirection, data: &[u8]) {
+        self.notify_transfer_summary(
+            filename,
+            direction,
+            data.len() as u64,
+            crc32fast::hash(data),
+        );
+    }
+
+    fn notify_transfer_summary(
+        &self,
+        filename: &str,
+        direction: TransferDirection,
+        size: u64,
+        crc32: u32,
+    ) {
+        if let Some(transcript) = self.transcript.lock().unwrap().as_ref() {
+            transcript.record(TranscriptEntry::Transfer {
+                filename: filename.to_string(),
+                direction,
+                size,
+            });
+        }
+        if let Some(observer) = self.transfer_observer.lock().unwrap().as_ref() {
+            observer(&TransferEvent {
+                filename,
+                direction,
+                size,
+                crc32,
+            });
+        }
+    }
+
+    /// The device information read when connecting, available while a transfer is running too
+    pub fn device_info(&self) -> &transport::DeviceInformation {
+        &self.device_info
+    }
+
+    /// The GATT characteristics of all the services of the device, as discovered when connecting
+    pub async fn gatt_characteristics(&self) -> BTreeSet<Characteristic> {
+        self.transport().await.characteristics().clone()
+    }
+
+    /// The battery level in percent, `None` if the device doesn't report it
+    pub async fn battery_level(&self) -> Option<u32> {
+        *self.battery_level.borrow()
+    }
+
+    /// Follow the battery level changes, also while a transfer is running
+    ///
+    /// The receiver sees the level the device reported last, and is notified when it reports a new one.
+    pub fn subscribe_battery_level(&self) -> watch::Receiver<Option<u32>> {
+        self.battery_level.clone()
+    }
+
+    /// Where the battery level is read from, `None` if the device doesn't report it
+    pub async fn battery_source(&self) -> Option<transport::BatterySource> {
+        self.transport().await.battery_source()
+    }
+
+    /// Get the current battery level, querying the device if it doesn't notify about the changes
+    ///
+    /// See [XossTransport::refresh_battery_level]
+    pub async fn refresh_battery_level(&self) -> Result<Option<u32>> {
+        self.transport().await.refresh_battery_level().await
+    }
+
+    /// The signal strength and the connection parameters, as far as the BLE stack tells them
+    ///
+    /// Doesn't wait for the running operations, so it can show whether a slow transfer is due to a poor signal
+    pub async fn link_quality(&self) -> transport::LinkQuality {
+        self.link_monitor.link_quality().await
+    }
+
+    /// Follow the link quality from a task that outlives the borrow of the device
+    pub fn link_monitor(&self) -> transport::LinkMonitor {
+        self.link_monitor.clone()
+    }
+
+    /// How many messages were lost, damaged or sent again since the connection was made
+    ///
+    /// Doesn't wait for the running operations either
+    pub fn transport_stats(&self) -> transport::TransportStats {
+        self.link_monitor.stats()
+    }
+
+    /// Get the state of the device's file transfer state machine
+    ///
+    /// [ControlMessageType::Idle] is returned when no transfer is in progress
+    pub async fn get_transfer_status(&self) -> Result<ControlMessageType> {
+        transfer_status(&*self.transport().await).await
+    }
+
+    /// Like [XossDevice::get_transfer_status], but fails with [DeviceBusy] instead of waiting for the running operation
+    pub async fn try_get_transfer_status(&self) -> Result<ControlMessageType> {
+        transfer_status(&*self.try_transport()?).await
+    }
+
+    /// Get whether the device is recording a workout
+    ///
+    /// Returns `None` if the firmware doesn't support it
+    pub async fn get_activity_status(&self) -> Result<Option<ActivityStatus>> {
+        if self.activity_status_unsupported.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        match activity_status(&*self.transport().await).await {
+            Ok(status) => Ok(Some(status)),
+            Err(e)
+                if e.chain().any(|e| {
+                    matches!(
+                        e.downcast_ref::<ControlError>(),
+                        Some(ControlError::Validation)
+                    )
+                }) =>
+            {
+                debug!("The device doesn't report the activity status: {:#}", e);
+                self.activity_status_unsupported
+                    .store(true, Ordering::Relaxed);
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Allow the operations that could corrupt the workout being recorded, see [DeviceRecording]
+    pub fn set_allow_while_recording(&self, allow: bool) {
+        self.allow_while_recording.store(allow, Ordering::Relaxed);
+    }
+
+    /// Fail with [DeviceRecording] if the device is recording a workout, unless it's allowed
+    ///
+    /// The devices that don't report the activity status are checked for a workout in the
+    /// [WorkoutState::Recording] state instead.
+    async fn ensure_not_recording(&self) -> Result<()> {
+        if self.allow_while_recording.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        match self.get_activity_status().await? {
+            Some(status) if status.is_recording() => Err(DeviceRecording(status).into()),
+            Some(_) => Ok(()),
+            None => {
+                let workouts = match self.read_workouts().await {
+                    Ok(workouts) => workouts,
+                    // a device that has never recorded anything
+                    Err(e)
+                        if e.chain().any(|e| {
+                            matches!(
+                                e.downcast_ref::<ControlError>(),
+                                Some(ControlError::NoFile(_))
+                            )
+                        }) =>
+                    {
+                        Vec::new()
+                    }
+                    Err(e) => return Err(e.context("Failed to check for a workout being recorded")),
+                };
+                if workouts.iter().any(|w| w.state == WorkoutState::Recording) {
+                    return Err(DeviceRecording(ActivityStatus::Recording).into());
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub async fn get_memory_capacity(&self) -> Result<MemoryCapacity> {
+        memory_capacity(&*self.transport().await).await
+    }
+
+    /// Like [XossDevice::get_memory_capacity], but fails with [DeviceBusy] instead of waiting for the running operation
+    pub async fn try_get_memory_capacity(&self) -> Result<MemoryCapacity> {
+        memory_capacity(&*self.try_transport()?).await
+    }
+
+    /// Check that a file of `size` bytes can be uploaded, leaving [FREE_SPACE_MARGIN] free
+    ///
+    /// The space of the file it would replace is not counted, the device doesn't tell the sizes of its files.
+    /// Returns the capacity it has checked against, or fails with [InsufficientSpace].
+    pub async fn ensure_free_space(&self, filename: &str, size: u64) -> Result<MemoryCapacity> {
+        let capacity = self.get_memory_capacity().await?;
+        if (capacity.free_kb as u64 * 1024) < size + FREE_SPACE_MARGIN {
+            return Err(InsufficientSpace {
+                filename: filename.to_string(),
+                size,
+                capacity,
+            }
+            .into());
+        }
+        Ok(capacity)
     }
 
     /// Delete a file from the device
     ///
-    /// Don't try to remove the JSON files, the device will not recreate some of them
+    /// Don't try to remove the JSON files, the device will not recreate some of them.
+    /// Fails with [DeviceRecording] while a workout is being recorded.
     #[allow(unused)]
     pub async fn delete_file(&self, filename: &str) -> Result<()> {
-        let transport = self.transport.lock().await;
-        let mut buffer = [0; CTL_BUFFER_SIZE];
+        self.ensure_not_recording().await?;
+        self.delete_file_unguarded(filename).await
+    }
+
+    /// Delete a file without checking for a workout being recorded
+    ///
+    /// Only for the files this library has created itself, like the scratch files, which can't be part of a workout.
+    async fn delete_file_unguarded(&self, filename: &str) -> Result<()> {
+        let transport = self.transport().await;
+        let mut buffer = CtlBuffer::default();
         transport
             .request_ctl(
                 &mut buffer,
@@ -145,11 +837,78 @@ impl XossDevice {
             .context("Failed to send a control message")?
             .expect_ok(ControlMessageType::DelSuccess)
             .context("Failed to delete the file")
-            .map(|b| {
-                assert_eq!(b, filename.as_bytes());
+            .and_then(|b| {
+                self.deviations
+                    .check_echo(ControlMessageType::RequestDel, filename.as_bytes(), b)
             })
     }
 
+    /// Pick a file name that is not used by the firmware, the workouts or the routes on the device
+    ///
+    /// Useful for test uploads, use [Self::with_scratch_file] to make sure the file is deleted afterwards
+    pub async fn scratch_filename(&self) -> Result<String> {
+        let mut used = SYSTEM_FILES
+            .iter()
+            .map(|f| f.to_string())
+            .collect::<HashSet<_>>();
+        used.extend(self.read_workouts().await?.iter().map(|w| w.filename()));
+        used.extend(
+            self.read_routes()
+                .await?
+                .iter()
+                .map(|r| format!("{}.ro", r.rid)),
+        );
+
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        // short enough for the file requests to fit into a single control message, with the type and the checksum
+        let filename = (0..)
+            .map(|n| format!("tmp{:x}-{}.tmp", timestamp, n))
+            .find(|f| !used.contains(f))
+            .unwrap();
+        if filename.len() + 2 > transport::MAX_CTL_WRITE_SIZE {
+            bail!(
+                "The scratch file name {} is too long for a file request",
+                filename
+            );
+        }
+
+        Ok(filename)
+    }
+
+    /// Run `f` with a scratch file name (see [Self::scratch_filename]), deleting the file afterwards
+    ///
+    /// The file is deleted even if `f` fails, the error of `f` takes precedence over the one of the deletion.
+    /// It's fine for `f` to not create the file at all.
+    pub async fn with_scratch_file<T, F, Fut>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(String) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let filename = self.scratch_filename().await?;
+        debug!("Using scratch file {}", filename);
+
+        let result = f(filename.clone()).await;
+
+        // the scratch file is ours, so it's deleted even while a workout is being recorded
+        let delete_result = match self.delete_file_unguarded(&filename).await {
+            Err(e) if is_no_file(&e) => Ok(()),
+            r => r.with_context(|| format!("Deleting the scratch file {}", filename)),
+        };
+
+        match (result, delete_result) {
+            (Ok(value), delete_result) => delete_result.map(|()| value),
+            (Err(e), delete_result) => {
+                if let Err(delete_error) = delete_result {
+                    warn!("{:#}", delete_error);
+                }
+                Err(e)
+            }
+        }
+    }
+
     pub async fn set_time(&self, time: SystemTime) -> Result<()> {
         let unix_time: u32 = time
             .duration_since(SystemTime::UNIX_EPOCH)
@@ -158,8 +917,8 @@ impl XossDevice {
             .try_into()
             .expect("It's that time of the year again... (the unix timestamp has overflowed unsigned 32-bit integer)");
 
-        let transport = self.transport.lock().await;
-        let mut buffer = [0; CTL_BUFFER_SIZE];
+        let transport = self.transport().await;
+        let mut buffer = CtlBuffer::default();
         transport
             .request_ctl(
                 &mut buffer,
@@ -170,27 +929,46 @@ impl XossDevice {
             .context("Failed to send a control message")?
             .expect_ok(ControlMessageType::TimeSetRtn)
             .context("Failed to set the time")
-            .map(|b| {
-                assert_eq!(b, unix_time.to_le_bytes().as_ref());
+            .and_then(|b| {
+                self.deviations
+                    .check_echo(ControlMessageType::TimeSet, &unix_time.to_le_bytes(), b)
             })
     }
 
+    /// Get the identifier of the unit, see [DebugId]
+    pub async fn debug_id(&self) -> Result<DebugId> {
+        let transport = self.transport().await;
+        let mut buffer = CtlBuffer::default();
+        let body = transport
+            .request_ctl(&mut buffer, ControlMessageType::DbgCmd, &[])
+            .await
+            .context("Failed to send a control message")?
+            .expect_ok(ControlMessageType::DbgCmd)
+            .context("Failed to get the device identifier")?;
+        DebugId::parse(body)
+    }
+
     /// Get the current Multi-GNSS Assistance (MGA) status
     pub async fn get_mga_state(&self) -> Result<MgaState> {
-        let transport = self.transport.lock().await;
-        let mut buffer = [0; CTL_BUFFER_SIZE];
+        let transport = self.transport().await;
+        let mut buffer = CtlBuffer::default();
         transport
             .request_ctl(&mut buffer, ControlMessageType::RequestMga, &[])
             .await
             .context("Failed to send a control message")?
             .expect_ok(ControlMessageType::ReturnMga)
             .context("Failed to get the assisted GPS status")
-            .map(|b| {
-                assert_eq!(b.len(), 6);
-                assert_eq!(b[0], 0x01);
-                assert_eq!(b[1], 0x00);
-                let time = u32::from_le_bytes([b[2], b[3], b[4], b[5]]);
-                if time == 0 {
+            .and_then(|b| {
+                let &[0x01, 0x00, t0, t1, t2, t3] = b else {
+                    return Err(UnexpectedReply {
+                        request: ControlMessageType::RequestMga,
+                        expected: "0100 followed by a 32-bit timestamp".to_string(),
+                        actual: b.to_vec(),
+                    }
+                    .into());
+                };
+                let time = u32::from_le_bytes([t0, t1, t2, t3]);
+                Ok(if time == 0 {
                     MgaState::MissingData
                 } else {
                     // convert unix time to NaiveDate
@@ -199,51 +977,316 @@ impl XossDevice {
                             .unwrap()
                             .date(),
                     )
-                }
+                })
             })
     }
 
-    #[instrument(skip(self), fields(size))]
-    pub async fn read_file(&self, filename: &str) -> Result<Vec<u8>> {
+    /// Erase all the user data on the device (workouts, routes, settings)
+    ///
+    /// The device reboots afterwards, so the connection should not be used anymore.
+    /// Fails with [DeviceRecording] while a workout is being recorded.
+    pub async fn factory_reset(&self) -> Result<()> {
+        self.ensure_not_recording().await?;
+        let transport = self.transport().await;
+        let mut buffer = CtlBuffer::default();
+        transport
+            .request_ctl(&mut buffer, ControlMessageType::RequestClr, &[])
+            .await
+            .context("Failed to send a control message")?
+            .expect_ok(ControlMessageType::ReturnClr)
+            .context("Failed to perform a factory reset")?;
+
+        Ok(())
+    }
+
+    /// Reboot the device into the DFU (firmware update) mode
+    ///
+    /// The device doesn't reply and disconnects, so the connection should not be used anymore.
+    /// Fails with [DeviceRecording] while a workout is being recorded.
+    pub async fn enter_dfu(&self) -> Result<()> {
+        self.ensure_not_recording().await?;
+        let transport = self.transport().await;
+        let mut buffer = CtlBuffer::default();
+        transport
+            .send_ctl(&mut buffer, ControlMessageType::DfuEnter, &[])
+            .await
+            .context("Failed to send a control message")
+    }
+
+    /// Send each of [PROBED_CTL_MESSAGES] to find out which of them the firmware supports
+    ///
+    /// The messages only read the state of the device, so they are safe to send while a workout is being recorded.
+    pub async fn probe_ctl(&self) -> Vec<(ControlMessageType, CtlProbeResult)> {
+        let transport = self.transport().await;
+        let mut results = Vec::new();
+        for &message_type in PROBED_CTL_MESSAGES {
+            let mut buffer = CtlBuffer::default();
+            let result = match transport.request_ctl(&mut buffer, message_type, &[]).await {
+                Ok(reply) => match reply.into_result() {
+                    Ok(reply) => CtlProbeResult::Replied(reply.message_type),
+                    Err(e) => CtlProbeResult::Refused(e),
+                },
+                Err(e) => CtlProbeResult::Failed(e),
+            };
+            debug!("{:?}: {}", message_type, result);
+            results.push((message_type, result));
+        }
+        results
+    }
+
+    /// Send one of the control messages that only read the state of the device ([PROBED_CTL_MESSAGES])
+    ///
+    /// Returns the type and the body of the reply, failing if it's an error. The other messages are refused with
+    /// [NotReadOnly], as they can start a transfer or change the data that the other operations rely on.
+    ///
+    /// Waits for its turn like every other operation: the device replies to one message at a time and a file transfer
+    /// has its own exchange of them, so they can't be interleaved. The operations are served in the order they asked,
+    /// see [Self::try_request_ctl] for not waiting behind a transfer.
+    pub async fn request_ctl(
+        &self,
+        message_type: ControlMessageType,
+        body: &[u8],
+    ) -> Result<(ControlMessageType, Vec<u8>)> {
+        if !PROBED_CTL_MESSAGES.contains(&message_type) {
+            return Err(NotReadOnly(message_type).into());
+        }
+        read_only_ctl(&*self.transport().await, message_type, body).await
+    }
+
+    /// Like [Self::request_ctl], but fails with [DeviceBusy] instead of waiting if another operation is running or
+    /// waiting for the device
+    ///
+    /// It never joins the queue and never overtakes it, so polling the status with it (say, for a UI during a sync)
+    /// doesn't delay the transfers. The same policy holds for the other `try_` methods.
+    pub async fn try_request_ctl(
+        &self,
+        message_type: ControlMessageType,
+        body: &[u8],
+    ) -> Result<(ControlMessageType, Vec<u8>)> {
+        if !PROBED_CTL_MESSAGES.contains(&message_type) {
+            return Err(NotReadOnly(message_type).into());
+        }
+        read_only_ctl(&*self.try_transport()?, message_type, body).await
+    }
+
+    /// Send a control message of any type and return the reply bytes as they are
+    ///
+    /// For exploring the undocumented messages, see [XossTransport::request_ctl_raw]. Nothing stops the messages
+    /// that erase or reboot the device from being sent.
+    pub async fn request_ctl_raw(&self, message_type: u8, body: &[u8]) -> Result<Vec<u8>> {
+        let transport = self.transport().await;
+        transport.request_ctl_raw(message_type, body).await
+    }
+
+    #[instrument(skip(self, progress), fields(size))]
+    pub async fn read_file(&self, filename: &str, progress: &dyn ProgressSink) -> Result<Vec<u8>> {
         // even though the underlying implementation of ymodem returns a stream, allowing us to stream the file, we don't do that here
         // it introduces problems with atomicity and will punch us in the face when we try to implement retries
-        // the files are small enough that we can just read them into memory
-        let transport = self.transport.lock().await;
-        let mut uart_stream = transport.open_uart_stream().await;
+        // the files are small enough that we can just read them into memory (see read_file_to_writer for the large ones)
+        let transport = self.transport().await;
+
+        let result = Self::read_file_inner(&transport, filename, progress).await;
+        match &result {
+            Ok(data) => self.notify_transfer(filename, TransferDirection::Download, data),
+            Err(_) => {
+                // try to leave the device in a consistent state, so that the transfer can be retried
+                if let Err(e) = stop_transfer(&transport).await {
+                    warn!("Failed to stop the interrupted transfer: {:#}", e);
+                }
+            }
+        }
 
-        let start = Instant::now();
+        result
+    }
 
-        let mut buffer = CtlBuffer::default();
+    /// Ask the device to start sending the file
+    async fn request_return(
+        transport: &XossTransport,
+        buffer: &mut CtlBuffer,
+        filename: &str,
+    ) -> Result<()> {
         let reply = transport
             .request_ctl(
-                &mut buffer,
+                buffer,
                 ControlMessageType::RequestReturn,
                 filename.as_bytes(),
             )
             .await
             .context("Failed to send a control message")?
             .expect_ok(ControlMessageType::Returning)?;
-        assert_eq!(reply, filename.as_bytes());
+        transport.deviations().check_echo(
+            ControlMessageType::RequestReturn,
+            filename.as_bytes(),
+            reply,
+        )
+    }
+
+    /// Read a file from the device, writing the data to `writer` as it arrives
+    ///
+    /// Unlike [Self::read_file], the file is never held in memory as a whole. On failure `writer` is left with
+    /// the partially received data, see [Self::read_file_to_path] for a version that never leaves a partial file.
+    /// Returns the size of the file.
+    #[instrument(skip(self, writer, progress), fields(size))]
+    pub async fn read_file_to_writer(
+        &self,
+        filename: &str,
+        writer: &mut (impl AsyncWrite + Unpin),
+        progress: &dyn ProgressSink,
+    ) -> Result<u64> {
+        let transport = self.transport().await;
+
+        let result = Self::read_file_to_writer_inner(&transport, filename, writer, progress).await;
+        match &result {
+            Ok((size, crc32)) => {
+                self.notify_transfer_summary(filename, TransferDirection::Download, *size, *crc32)
+            }
+            Err(_) => {
+                if let Err(e) = stop_transfer(&transport).await {
+                    warn!("Failed to stop the interrupted transfer: {:#}", e);
+                }
+            }
+        }
+
+        result.map(|(size, _)| size)
+    }
+
+    /// Read a file from the device into a local file
+    ///
+    /// The data is streamed into a temporary file next to `path`, which replaces `path` only after the device confirms
+    /// the transfer has finished. Returns the size of the file.
+    pub async fn read_file_to_path(
+        &self,
+        filename: &str,
+        path: &Path,
+        progress: &dyn ProgressSink,
+    ) -> Result<u64> {
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".part");
+        let temp_path = PathBuf::from(temp_path);
+
+        let result = async {
+            let mut file = tokio::fs::File::create(&temp_path)
+                .await
+                .with_context(|| format!("Creating {}", temp_path.display()))?;
+            let size = self
+                .read_file_to_writer(filename, &mut file, progress)
+                .await?;
+            file.sync_all()
+                .await
+                .with_context(|| format!("Writing {}", temp_path.display()))?;
+            Ok::<_, anyhow::Error>(size)
+        }
+        .await;
+
+        match result {
+            Ok(size) => {
+                tokio::fs::rename(&temp_path, path)
+                    .await
+                    .with_context(|| format!("Moving the downloaded file to {}", path.display()))?;
+                Ok(size)
+            }
+            Err(e) => {
+                if let Err(e) = tokio::fs::remove_file(&temp_path).await {
+                    if e.kind() != std::io::ErrorKind::NotFound {
+                        warn!("Failed to remove {}: {}", temp_path.display(), e);
+                    }
+                }
+                Err(e)
+            }
+        }
+    }
 
-        let (file_info, out_stream) = transport::ymodem::receive_file(&mut uart_stream).await?;
-        let reader =
-            StreamReader::new(out_stream.map_err(|e| std::io::Error::new(ErrorKind::Other, e)));
-        pin_mut!(reader);
+    /// Returns the size and the CRC-32 of the file
+    async fn read_file_to_writer_inner(
+        transport: &XossTransport,
+        filename: &str,
+        writer: &mut (impl AsyncWrite + Unpin),
+        progress: &dyn ProgressSink,
+    ) -> Result<(u64, u32)> {
+        let mut uart_stream = transport.open_uart_stream().await;
+
+        let start = Instant::now();
+
+        let mut buffer = CtlBuffer::default();
+        Self::request_return(transport, &mut buffer, filename).await?;
+
+        let (file_info, out_stream) = transport::ymodem::receive_file_with_options(
+            &mut uart_stream,
+            progress,
+            &transport.ymodem_options(),
+        )
+        .await?;
+        pin_mut!(out_stream);
 
         Span::current().record("size", file_info.size);
 
+        let mut hasher = crc32fast::Hasher::new();
+        let mut size = 0;
+        while let Some(chunk) = out_stream
+            .try_next()
+            .await
+            .context("Failed to read the file")?
+        {
+            writer
+                .write_all(&chunk)
+                .await
+                .context("Failed to write the received data")?;
+            hasher.update(&chunk);
+            size += chunk.len() as u64;
+        }
+        writer
+            .flush()
+            .await
+            .context("Failed to write the received data")?;
+
+        transport
+            .recv_ctl(&mut buffer)
+            .await
+            .context("Receiving the post-download status message")?
+            .expect_ok(ControlMessageType::Idle)?;
+
         debug!(
-            "Downloading {} ({})",
+            "Downloaded {} ({}) in {:.2} seconds",
             filename,
-            humansize::format_size(file_info.size, humansize::BINARY.decimal_zeroes(2))
+            format_size(size),
+            start.elapsed().as_secs_f64(),
         );
 
-        let mut buf = Vec::new();
-        reader
-            .read_to_end(&mut buf)
+        Ok((size, hasher.finalize()))
+    }
+
+    async fn read_file_inner(
+        transport: &XossTransport,
+        filename: &str,
+        progress: &dyn ProgressSink,
+    ) -> Result<Vec<u8>> {
+        let mut uart_stream = transport.open_uart_stream().await;
+
+        let start = Instant::now();
+
+        let mut buffer = CtlBuffer::default();
+        Self::request_return(transport, &mut buffer, filename).await?;
+
+        let (file_info, out_stream) = transport::ymodem::receive_file_with_options(
+            &mut uart_stream,
+            progress,
+            &transport.ymodem_options(),
+        )
+        .await?;
+        pin_mut!(out_stream);
+
+        Span::current().record("size", file_info.size);
+
+        debug!("Downloading {} ({})", filename, format_size(file_info.size));
+
+        let mut data = Vec::with_capacity(file_info.size as usize);
+        while let Some(chunk) = out_stream
+            .try_next()
             .await
-            .context("Failed to read the file")?;
-        drop(reader);
+            .context("Failed to read the file")?
+        {
+            data.extend_from_slice(&chunk);
+        }
 
         transport
             .recv_ctl(&mut buffer)
@@ -253,23 +1296,28 @@ impl XossDevice {
 
         let time = start.elapsed();
 
-        let speed = (buf.len() as f64) / (time.as_secs_f64()) / 1024.0;
+        let speed = (data.len() as f64) / (time.as_secs_f64()) / 1024.0;
 
         debug!(
             "Downloaded {} ({}) in {:.2} seconds ({:.2} KiB/s)",
             filename,
-            humansize::format_size(buf.len(), humansize::BINARY.decimal_zeroes(2)),
+            format_size(data.len() as u64),
             time.as_secs_f64(),
             speed
         );
 
-        Ok(buf)
+        Ok(data)
     }
 
-    #[instrument(skip(self, content), fields(size = content.len()))]
-    pub async fn write_file(&self, filename: &str, content: &[u8]) -> Result<()> {
+    #[instrument(skip(self, content, progress), fields(size = content.len()))]
+    pub async fn write_file(
+        &self,
+        filename: &str,
+        content: &[u8],
+        progress: &dyn ProgressSink,
+    ) -> Result<()> {
         // we accept the file as a slice, for motivation see the comment in [receive_file]
-        let device = self.transport.lock().await;
+        let device = self.transport().await;
         let mut uart_stream = device.open_uart_stream().await;
 
         let start = Instant::now();
@@ -284,15 +1332,23 @@ impl XossDevice {
             .await
             .context("Failed to send a control message")?
             .expect_ok(ControlMessageType::Accept)?;
-        assert_eq!(reply, filename.as_bytes());
+        self.deviations
+            .check_echo(ControlMessageType::RequestSend, filename.as_bytes(), reply)?;
 
         debug!(
             "Uploading {} ({})",
             filename,
-            humansize::format_size(content.len(), humansize::BINARY.decimal_zeroes(2))
+            format_size(content.len() as u64)
         );
 
-        transport::ymodem::send_file(&mut uart_stream, filename, &mut Cursor::new(content)).await?;
+        transport::ymodem::send_file_with_options(
+            &mut uart_stream,
+            filename,
+            &mut Cursor::new(content),
+            progress,
+            &device.ymodem_options(),
+        )
+        .await?;
 
         let time = start.elapsed();
 
@@ -311,15 +1367,89 @@ impl XossDevice {
         debug!(
             "Uploaded {} ({}) in {:.2} seconds ({:.2} KiB/s). Device processed it in {:.2} seconds",
             filename,
-            humansize::format_size(content.len(), humansize::BINARY.decimal_zeroes(2)),
+            format_size(content.len() as u64),
             time.as_secs_f64(),
             speed,
             device_proc_time.as_secs_f64()
         );
 
+        self.notify_transfer(filename, TransferDirection::Upload, content);
+
         Ok(())
     }
 
+    /// Same as [Self::write_file], then make sure the device has stored the file intact
+    ///
+    /// The device confirms an upload as soon as the transfer ends, so the file is read back and compared by its CRC-32.
+    /// The files that can't be read back are checked by the size in the workout or route index, when they are there.
+    pub async fn write_file_verified(
+        &self,
+        filename: &str,
+        content: &[u8],
+        progress: &dyn ProgressSink,
+    ) -> Result<UploadVerification> {
+        self.write_file(filename, content, progress).await?;
+        self.verify_file(filename, content, progress)
+            .await
+            .with_context(|| format!("Failed to verify {}", filename))
+    }
+
+    async fn verify_file(
+        &self,
+        filename: &str,
+        content: &[u8],
+        progress: &dyn ProgressSink,
+    ) -> Result<UploadVerification> {
+        let expected_size = content.len() as u64;
+        let read_back = match self.read_file(filename, progress).await {
+            Ok(data) => data,
+            Err(e) if is_no_file(&e) => {
+                let indexed_size = if filename.ends_with(".fit") {
+                    self.read_workouts()
+                        .await?
+                        .iter()
+                        .find(|w| w.filename() == filename)
+                        .map(|w| w.size as u64)
+                } else if filename.ends_with(".ro") {
+                    self.read_routes()
+                        .await?
+                        .iter()
+                        .find(|r| format!("{}.ro", r.rid) == filename)
+                        .map(|r| r.size as u64)
+                } else {
+                    None
+                };
+
+                return match indexed_size {
+                    None => Ok(UploadVerification::Unverifiable),
+                    Some(size) if size == expected_size => {
+                        Ok(UploadVerification::IndexedSize(size))
+                    }
+                    Some(size) => Err(VerificationError::SizeMismatch {
+                        expected: expected_size,
+                        actual: size,
+                    }
+                    .into()),
+                };
+            }
+            Err(e) => return Err(e.context("Reading the file back")),
+        };
+
+        if read_back.len() as u64 != expected_size {
+            return Err(VerificationError::SizeMismatch {
+                expected: expected_size,
+                actual: read_back.len() as u64,
+            }
+            .into());
+        }
+        let (expected, actual) = (crc32fast::hash(content), crc32fast::hash(&read_back));
+        if expected != actual {
+            return Err(VerificationError::ChecksumMismatch { expected, actual }.into());
+        }
+
+        Ok(UploadVerification::Checksum(actual))
+    }
+
     pub async fn get_device_json_header(&self) -> Result<HeaderJson> {
         Ok(match self.json_header.get() {
             Some(h) => h.clone(),
@@ -335,33 +1465,88 @@ impl XossDevice {
 
     #[instrument(skip(self), level = Level::DEBUG)]
     pub async fn read_json_file<T: for<'de> Deserialize<'de>>(&self, filename: &str) -> Result<T> {
-        {
-            let data = self.read_file(filename).await?;
-            let data =
-                std::str::from_utf8(&data).context("Failed to parse a json file as UTF-8")?;
-
-            trace!("Retrieved {}: {}", filename, data);
-
-            let WithHeader { header, data } =
-                serde_json::from_str(data).context("Failed to parse the json file")?;
+        let data = self.read_file(filename, &NoProgress).await?;
+        self.parse_json_file(filename, &data)
+    }
 
-            if header.version != "2.0.0" {
-                warn!(
-                    "The json file {} has an unknown version {}",
-                    filename, header.version
-                )
+    fn parse_json_file<T: for<'de> Deserialize<'de>>(
+        &self,
+        filename: &str,
+        data: &[u8],
+    ) -> Result<T> {
+        {
+            let text = std::str::from_utf8(data).context("Failed to parse a json file as UTF-8")?;
+
+            trace!("Retrieved {}: {}", filename, text);
+
+            let (result, unknown_fields) = collect_unknown_fields(|| serde_json::from_str(text));
+            let WithHeader { header, data } = result.context("Failed to parse the json file")?;
+
+            if !unknown_fields.is_empty() {
+                // they are kept when the file is written back, but may mean that the format has changed
+                self.deviations.report(ProtocolDeviation::new(
+                    format!(
+                        "The json file {} has unknown fields: {}",
+                        filename,
+                        unknown_fields.join(", ")
+                    ),
+                    text.as_bytes(),
+                ))?;
             }
 
-            self.json_header.get_or_init(|| async move { header }).await;
+            self.json_protocol_of(filename, &header)?;
+            let _ = self.json_header.set(header);
 
             Ok::<_, anyhow::Error>(data)
         }
         .with_context(|| format!("Failed to read {}", filename))
     }
 
+    /// Same as [XossDevice::read_json_file], but an empty file is treated as the default value
+    ///
+    /// The device may leave a file empty instead of writing an empty list to it (like routebooks.json on a new device).
+    /// The files that are optional in the format of the device (see [JsonProtocol::optional_files]) may be missing too.
+    pub async fn read_json_file_or_default<T: for<'de> Deserialize<'de> + Default>(
+        &self,
+        filename: &str,
+    ) -> Result<T> {
+        let data = match self.read_file(filename, &NoProgress).await {
+            Ok(data) => data,
+            Err(e) if is_no_file(&e) => match self.json_protocol().await {
+                Ok(protocol) if protocol.is_optional(filename) => {
+                    debug!("There is no {}, using the default value", filename);
+                    return Ok(T::default());
+                }
+                _ => return Err(e),
+            },
+            Err(e) => return Err(e),
+        };
+        self.parse_json_file_or_default(filename, &data)
+    }
+
+    fn parse_json_file_or_default<T: for<'de> Deserialize<'de> + Default>(
+        &self,
+        filename: &str,
+        data: &[u8],
+    ) -> Result<T> {
+        if data.iter().all(|b| b.is_ascii_whitespace()) {
+            debug!("{} is empty, using the default value", filename);
+            return Ok(T::default());
+        }
+
+        self.parse_json_file(filename, data)
+    }
+
+    /// Write a JSON file, restoring its previous content if the upload fails
+    ///
+    /// The current content is read first and given to the backup function (see [XossDevice::set_json_backup]).
+    /// The written file is read back to verify it, so that a corrupt upload doesn't leave the device with a broken config.
     #[instrument(skip(self, data), level = Level::DEBUG)]
     pub async fn write_json_file<T: Serialize>(&self, filename: &str, data: &T) -> Result<()> {
+        self.ensure_not_recording().await?;
         let header_json = self.get_device_json_header().await?;
+        // the files of an unknown format could be broken by writing them
+        self.json_protocol().await?;
 
         let data = WithHeader {
             // we should provide the header, as the device doesn't always re-generate it
@@ -374,9 +1559,57 @@ impl XossDevice {
 
         trace!("Writing {}: {}", filename, data);
 
-        self.write_file(filename, data.as_bytes()).await?;
+        self.replace_json_file(filename, data.as_bytes()).await
+    }
 
-        Ok(())
+    /// Write a JSON file as it is, like a copy kept by the backup function (see [XossDevice::set_json_backup])
+    ///
+    /// Only checked to be valid JSON, unlike [XossDevice::write_json_file] the header is written as it is too.
+    #[instrument(skip(self, content), level = Level::DEBUG)]
+    pub async fn write_raw_json_file(&self, filename: &str, content: &[u8]) -> Result<()> {
+        self.ensure_not_recording().await?;
+        serde_json::from_slice::<serde_json::Value>(content)
+            .with_context(|| format!("The new {} is not valid JSON", filename))?;
+
+        self.replace_json_file(filename, content).await
+    }
+
+    async fn replace_json_file(&self, filename: &str, content: &[u8]) -> Result<()> {
+        let previous = match self.read_file(filename, &NoProgress).await {
+            Ok(previous) => Some(previous),
+            Err(e) if is_no_file(&e) => None,
+            Err(e) => return Err(e.context(format!("Failed to read the current {}", filename))),
+        };
+        if let (Some(previous), Some(backup)) = (&previous, &*self.json_backup.lock().unwrap()) {
+            backup(filename, previous)
+                .with_context(|| format!("Failed to back up {}", filename))?;
+        }
+
+        let error = match self
+            .write_file_verified(filename, content, &NoProgress)
+            .await
+        {
+            Ok(_) => return Ok(()),
+            Err(e) => e,
+        };
+        let Some(previous) = previous else {
+            return Err(error);
+        };
+
+        warn!(
+            "Failed to write {}, restoring its previous content: {:#}",
+            filename, error
+        );
+        match self.write_file(filename, &previous, &NoProgress).await {
+            Ok(()) => Err(error.context(format!(
+                "Failed to write {}, the previous content was restored",
+                filename
+            ))),
+            Err(restore_error) => Err(error.context(format!(
+                "Failed to write {}, restoring the previous content failed too: {:#}",
+                filename, restore_error
+            ))),
+        }
     }
 
     pub async fn read_user_profile(&self) -> Result<UserProfile> {
@@ -392,12 +1625,7 @@ impl XossDevice {
     }
 
     pub async fn read_workouts(&self) -> Result<Vec<WorkoutsItem>> {
-        #[derive(Deserialize)]
-        struct WorkoutsWrap {
-            pub workouts: Vec<WorkoutsItem>,
-        }
-
-        self.read_json_file("workouts.json")
+        self.read_json_file_or_default("workouts.json")
             .await
             .context("Failed to read workouts")
             .map(|w: WorkoutsWrap| w.workouts)
@@ -427,12 +1655,12 @@ impl XossDevice {
     }
 
     pub async fn read_gear_profile(&self) -> Result<Vec<Gear>> {
-        #[derive(Deserialize)]
+        #[derive(Deserialize, Default)]
         struct GearProfileWrap {
             pub gears: Vec<Gear>,
         }
 
-        self.read_json_file("gear_profile.json")
+        self.read_json_file_or_default("gear_profile.json")
             .await
             .context("Failed to read gear profile")
             .map(|g: GearProfileWrap| g.gears)
@@ -449,15 +1677,106 @@ impl XossDevice {
             .context("Failed to write gear profile")
     }
 
-    pub async fn read_routes(&self) -> Result<Vec<Route>> {
-        #[derive(Deserialize)]
-        struct RoutesWrap {
-            pub routes: Vec<Route>,
+    /// The external sensors (heart rate straps, speed and cadence sensors) paired with the device
+    pub async fn read_sensors(&self) -> Result<Vec<Sensor>> {
+        #[derive(Deserialize, Default)]
+        struct SensorsWrap {
+            pub sensors: Vec<Sensor>,
         }
 
-        self.read_json_file("routebooks.json")
+        self.read_json_file_or_default("sensors.json")
+            .await
+            .context("Failed to read sensors")
+            .map(|s: SensorsWrap| s.sensors)
+    }
+
+    /// Replace the paired sensors, the device connects to them the next time they are in range
+    pub async fn write_sensors(&self, sensors: &[Sensor]) -> Result<()> {
+        #[derive(Serialize)]
+        struct SensorsWrap<'a> {
+            pub sensors: &'a [Sensor],
+        }
+
+        self.write_json_file("sensors.json", &SensorsWrap { sensors })
+            .await
+            .context("Failed to write sensors")
+    }
+
+    pub async fn read_routes(&self) -> Result<Vec<Route>> {
+        self.read_json_file_or_default("routebooks.json")
             .await
             .context("Failed to read routes")
             .map(|r: RoutesWrap| r.routes)
     }
+
+    pub async fn read_panels(&self) -> Result<Panels> {
+        self.read_json_file_or_default("panels.json")
+            .await
+            .context("Failed to read panels")
+    }
+
+    /// Write the panels, [XossDevice::read_panels] gives the ones to change to keep the rest of the file
+    pub async fn write_panels(&self, panels: &Panels) -> Result<()> {
+        self.write_json_file("panels.json", panels)
+            .await
+            .context("Failed to write panels")
+    }
+
+    /// List the files on the device
+    ///
+    /// The protocol has no way to list the directory, so the listing is put together from what the device tells about
+    /// its contents: the JSON configs (which are read to get their sizes), the workouts and the routes from their indexes,
+    /// and the A-GNSS data if the device has it (its size is unknown). Files uploaded by other means are not listed.
+    pub async fn list_files(&self) -> Result<Vec<DeviceFile>> {
+        let mut files = Vec::new();
+        let mut workouts = Vec::new();
+        let mut routes = Vec::new();
+
+        for &filename in SYSTEM_FILES.iter().filter(|f| f.ends_with(".json")) {
+            let data = match self.read_file(filename, &NoProgress).await {
+                Ok(data) => data,
+                Err(e) if is_no_file(&e) => continue,
+                Err(e) => return Err(e.context(format!("Failed to read {}", filename))),
+            };
+            match filename {
+                "workouts.json" => {
+                    workouts = self
+                        .parse_json_file_or_default::<WorkoutsWrap>(filename, &data)?
+                        .workouts
+                }
+                "routebooks.json" => {
+                    routes = self
+                        .parse_json_file_or_default::<RoutesWrap>(filename, &data)?
+                        .routes
+                }
+                _ => {}
+            }
+            files.push(DeviceFile {
+                name: filename.to_string(),
+                kind: DeviceFileKind::Config,
+                size: Some(data.len() as u64),
+            });
+        }
+
+        if let MgaState::ValidUntil(_) = self.get_mga_state().await? {
+            files.push(DeviceFile {
+                name: "offline.gnss".to_string(),
+                kind: DeviceFileKind::Mga,
+                size: None,
+            });
+        }
+
+        files.extend(workouts.iter().map(|w| DeviceFile {
+            name: w.filename(),
+            kind: DeviceFileKind::Workout,
+            size: Some(w.size as u64),
+        }));
+        files.extend(routes.iter().map(|r| DeviceFile {
+            name: format!("{}.ro", r.rid),
+            kind: DeviceFileKind::Route,
+            size: Some(r.size as u64),
+        }));
+
+        Ok(files)
+    }
 }
@@ -0,0 +1,398 @@
+//! Firmware update over BLE
+//!
+//! XOSS devices are built on Nordic nRF52 chips. After [crate::device::XossDevice::enter_dfu] they reboot into
+//! the Nordic Secure DFU bootloader, which advertises [DFU_SERVICE_UUID] and accepts firmware packages
+//! in the format produced by `nrfutil` (a zip with a manifest, an init packet and a firmware image).
+//!
+//! The firmware is sent in objects of the size chosen by the bootloader. Each object is verified with CRC32
+//! before it's executed (committed), so a corrupted transfer is retried instead of being flashed.
+
+use anyhow::{bail, Context, Result};
+use num_enum::TryFromPrimitive;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::io::{Cursor, Read};
+use std::time::Duration;
+use thiserror::Error;
+use tokio_stream::StreamExt;
+use tracing::{debug, info, instrument, warn};
+use uuid::Uuid;
+
+use crate::progress::ProgressSink;
+use crate::transport::peripheral::{BlePeripheral, Characteristic, NotificationStream, WriteType};
+
+pub use crate::scan::DFU_SERVICE_UUID;
+const CONTROL_POINT_CHARACTERISTIC_UUID: Uuid =
+    Uuid::from_u128(0x8ec90001_f315_4f60_9fb8_838830daea50);
+const PACKET_CHARACTERISTIC_UUID: Uuid = Uuid::from_u128(0x8ec90002_f315_4f60_9fb8_838830daea50);
+
+/// Size of a single write to the packet characteristic, fits into the default ATT MTU
+const PACKET_SIZE: usize = 20;
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Executing the last object makes the bootloader validate the whole image, which takes a while
+const EXECUTE_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_OBJECT_ATTEMPTS: usize = 3;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[repr(u8)]
+enum Opcode {
+    Create = 0x01,
+    SetPrn = 0x02,
+    CalcChecksum = 0x03,
+    Execute = 0x04,
+    Select = 0x06,
+    Response = 0x60,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[repr(u8)]
+enum ObjectType {
+    Command = 0x01,
+    Data = 0x02,
+}
+
+#[derive(TryFromPrimitive, Debug, PartialEq, Eq, Clone, Copy)]
+#[repr(u8)]
+pub enum ResultCode {
+    Invalid = 0x00,
+    Success = 0x01,
+    OpcodeNotSupported = 0x02,
+    InvalidParameter = 0x03,
+    InsufficientResources = 0x04,
+    InvalidObject = 0x05,
+    UnsupportedType = 0x07,
+    OperationNotPermitted = 0x08,
+    OperationFailed = 0x0A,
+    ExtendedError = 0x0B,
+}
+
+#[derive(Error, Debug)]
+pub enum DfuError {
+    #[error("The bootloader rejected {opcode}: {result:?}")]
+    Rejected { opcode: String, result: ResultCode },
+    #[error("CRC mismatch after sending {offset} bytes")]
+    ChecksumMismatch { offset: u32 },
+}
+
+/// The contents of a firmware package
+pub struct DfuPackage {
+    /// Signed metadata of the image, checked by the bootloader before accepting the image
+    pub init_packet: Vec<u8>,
+    pub firmware: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+struct ManifestImage {
+    bin_file: String,
+    dat_file: String,
+}
+
+#[derive(Deserialize)]
+struct Manifest {
+    manifest: BTreeMap<String, ManifestImage>,
+}
+
+impl DfuPackage {
+    /// Parse an `nrfutil` zip package
+    ///
+    /// Only packages with a single image are supported, which is what firmware updates are distributed as
+    pub fn from_zip(data: &[u8]) -> Result<Self> {
+        let mut archive =
+            zip::ZipArchive::new(Cursor::new(data)).context("Failed to open the package")?;
+
+        // the zip crate checks the CRC32 of each file when it's read to the end
+        let mut read_file = |name: &str| -> Result<Vec<u8>> {
+            let mut file = archive
+                .by_name(name)
+                .with_context(|| format!("The package does not contain {}", name))?;
+            let mut data = Vec::new();
+            file.read_to_end(&mut data)
+                .with_context(|| format!("Failed to read {} from the package", name))?;
+            Ok(data)
+        };
+
+        let manifest: Manifest = serde_json::from_slice(&read_file("manifest.json")?)
+            .context("Failed to parse the package manifest")?;
+
+        let mut images = manifest.manifest.into_iter();
+        let (Some((kind, image)), None) = (images.next(), images.next()) else {
+            bail!("Only packages with exactly one image are supported");
+        };
+        debug!("The package contains a {} image", kind);
+
+        Ok(Self {
+            init_packet: read_file(&image.dat_file)?,
+            firmware: read_file(&image.bin_file)?,
+        })
+    }
+}
+
+struct ObjectInfo {
+    max_size: u32,
+    offset: u32,
+    crc: u32,
+}
+
+struct DfuTarget<'a> {
+    peripheral: &'a dyn BlePeripheral,
+    control_point: Characteristic,
+    packet: Characteristic,
+    notifications: NotificationStream,
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+        .context("DFU response is too short")
+}
+
+impl<'a> DfuTarget<'a> {
+    async fn new(peripheral: &'a dyn BlePeripheral) -> Result<DfuTarget<'a>> {
+        peripheral
+            .discover_services()
+            .await
+            .context("Failed to discover services")?;
+
+        let find = |uuid| {
+            peripheral
+                .characteristics()
+                .into_iter()
+                .find(|c| c.uuid == uuid)
+                .with_context(|| format!("Missing DFU characteristic: {}", uuid))
+        };
+        let control_point = find(CONTROL_POINT_CHARACTERISTIC_UUID)?;
+        let packet = find(PACKET_CHARACTERISTIC_UUID)?;
+
+        let notifications = peripheral
+            .notifications()
+            .await
+            .context("Failed to get notifications")?;
+        peripheral
+            .subscribe(&control_point)
+            .await
+            .context("Failed to subscribe to the DFU control point")?;
+
+        Ok(Self {
+            peripheral,
+            control_point,
+            packet,
+            notifications,
+        })
+    }
+
+    async fn request(
+        &mut self,
+        opcode: Opcode,
+        params: &[u8],
+        timeout: Duration,
+    ) -> Result<Vec<u8>> {
+        let mut message = vec![opcode as u8];
+        message.extend_from_slice(params);
+
+        self.peripheral
+            .write(&self.control_point, &message, WriteType::WithResponse)
+            .await
+            .with_context(|| format!("Failed to send {:?}", opcode))?;
+
+        let response = async {
+            while let Some(notification) = self.notifications.next().await {
+                if notification.uuid == CONTROL_POINT_CHARACTERISTIC_UUID {
+                    return Ok(notification.value);
+                }
+            }
+            bail!("The notification stream has ended")
+        };
+        let response = tokio::time::timeout(timeout, response)
+            .await
+            .with_context(|| format!("Timed out waiting for the response to {:?}", opcode))??;
+
+        let [response_opcode, request_opcode, result, data @ ..] = response.as_slice() else {
+            bail!("DFU response is too short");
+        };
+        if *response_opcode != Opcode::Response as u8 || *request_opcode != opcode as u8 {
+            bail!("Unexpected DFU response: {}", hex::encode(&response));
+        }
+        let result = ResultCode::try_from_primitive(*result)
+            .with_context(|| format!("Unknown DFU result code: {}", result))?;
+        if result != ResultCode::Success {
+            return Err(DfuError::Rejected {
+                opcode: format!("{:?}", opcode),
+                result,
+            }
+            .into());
+        }
+
+        Ok(data.to_vec())
+    }
+
+    async fn select(&mut self, ty: ObjectType) -> Result<ObjectInfo> {
+        let data = self
+            .request(Opcode::Select, &[ty as u8], RESPONSE_TIMEOUT)
+            .await?;
+        Ok(ObjectInfo {
+            max_size: read_u32(&data, 0)?,
+            offset: read_u32(&data, 4)?,
+            crc: read_u32(&data, 8)?,
+        })
+    }
+
+    async fn create(&mut self, ty: ObjectType, size: u32) -> Result<()> {
+        let mut params = vec![ty as u8];
+        params.extend_from_slice(&size.to_le_bytes());
+        self.request(Opcode::Create, &params, RESPONSE_TIMEOUT)
+            .await?;
+        Ok(())
+    }
+
+    async fn calc_checksum(&mut self) -> Result<(u32, u32)> {
+        let data = self
+            .request(Opcode::CalcChecksum, &[], RESPONSE_TIMEOUT)
+            .await?;
+        Ok((read_u32(&data, 0)?, read_u32(&data, 4)?))
+    }
+
+    async fn execute(&mut self) -> Result<()> {
+        self.request(Opcode::Execute, &[], EXECUTE_TIMEOUT).await?;
+        Ok(())
+    }
+
+    /// Execute the current object, if it was not executed yet
+    async fn execute_if_needed(&mut self) {
+        if let Err(e) = self.execute().await {
+            debug!("Executing the received object failed: {:#}", e);
+        }
+    }
+
+    async fn write_data(&mut self, data: &[u8]) -> Result<()> {
+        for chunk in data.chunks(PACKET_SIZE) {
+            self.peripheral
+                .write(&self.packet, chunk, WriteType::WithoutResponse)
+                .await
+                .context("Failed to write a DFU packet")?;
+        }
+        Ok(())
+    }
+
+    /// Send a single object, retrying if the bootloader received it corrupted
+    ///
+    /// `prefix_crc` is the CRC state of everything sent before this object, the bootloader checksums are cumulative.
+    async fn send_object(
+        &mut self,
+        ty: ObjectType,
+        data: &[u8],
+        prefix_len: usize,
+        prefix_crc: &crc32fast::Hasher,
+    ) -> Result<()> {
+        let mut crc = prefix_crc.clone();
+        crc.update(data);
+        let expected_crc = crc.finalize();
+        let expected_offset = (prefix_len + data.len()) as u32;
+
+        for attempt in 1..=MAX_OBJECT_ATTEMPTS {
+            self.create(ty, data.len() as u32).await?;
+            self.write_data(data).await?;
+
+            let (offset, crc) = self.calc_checksum().await?;
+            if offset == expected_offset && crc == expected_crc {
+                return self.execute().await;
+            }
+
+            warn!(
+                "The bootloader has received a corrupted object (attempt {}/{})",
+                attempt, MAX_OBJECT_ATTEMPTS
+            );
+        }
+
+        Err(DfuError::ChecksumMismatch {
+            offset: expected_offset,
+        }
+        .into())
+    }
+}
+
+/// Flash the firmware package to a connected device that is in the DFU mode
+///
+/// An interrupted update is continued from the last verified object if the same package is flashed again.
+/// The device reboots into the new firmware when this function succeeds.
+#[instrument(skip(peripheral, package, progress), fields(size = package.firmware.len()))]
+pub async fn flash(
+    peripheral: &dyn BlePeripheral,
+    package: &DfuPackage,
+    progress: &dyn ProgressSink,
+) -> Result<()> {
+    let mut target = DfuTarget::new(peripheral).await?;
+
+    // we verify the objects with checksums instead of packet receipt notifications
+    target
+        .request(Opcode::SetPrn, &0u16.to_le_bytes(), RESPONSE_TIMEOUT)
+        .await?;
+
+    // sending the init packet again would discard the firmware received by an interrupted update
+    let info = target.select(ObjectType::Command).await?;
+    if info.offset as usize == package.init_packet.len()
+        && info.crc == crc32fast::hash(&package.init_packet)
+    {
+        info!("The bootloader already has the init packet");
+        target.execute_if_needed().await;
+    } else {
+        info!("Sending the init packet");
+        target
+            .send_object(
+                ObjectType::Command,
+                &package.init_packet,
+                0,
+                &crc32fast::Hasher::new(),
+            )
+            .await
+            .context("Failed to send the init packet")?;
+    }
+
+    let firmware = &package.firmware;
+    let info = target.select(ObjectType::Data).await?;
+    let max_size = info.max_size as usize;
+    if max_size == 0 {
+        bail!("The bootloader reported a zero object size");
+    }
+
+    // continue from the last complete object if the bootloader has a prefix of this firmware
+    let mut offset = 0;
+    let received = info.offset as usize;
+    if received > 0
+        && received <= firmware.len()
+        && crc32fast::hash(&firmware[..received]) == info.crc
+    {
+        offset = received - received % max_size;
+        if offset == received {
+            // the last object might have been received completely, but not executed
+            target.execute_if_needed().await;
+        }
+        info!("Continuing the interrupted update from {} bytes", offset);
+    }
+
+    progress.start(firmware.len() as u64);
+    progress.advance(offset as u64);
+
+    info!("Sending the firmware");
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(&firmware[..offset]);
+    while offset < firmware.len() {
+        let object = &firmware[offset..std::cmp::min(offset + max_size, firmware.len())];
+        target
+            .send_object(ObjectType::Data, object, offset, &crc)
+            .await
+            .with_context(|| format!("Failed to send the firmware at offset {}", offset))?;
+
+        crc.update(object);
+        offset += object.len();
+        progress.advance(object.len() as u64);
+    }
+
+    info!("Firmware sent, the device is rebooting");
+
+    // the bootloader reboots right away, the disconnect may fail
+    if let Err(e) = peripheral.disconnect().await {
+        debug!("Failed to disconnect after the update: {}", e);
+    }
+
+    Ok(())
+}
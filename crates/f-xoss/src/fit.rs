@@ -0,0 +1,696 @@
+//! A minimal reader for FIT files, the format the device records the workouts in
+//!
+//! Only the parts needed to summarize a workout and export its track are decoded: the messages are
+//! read generically (field number -> value), the profile knowledge lives in [WorkoutSummary] and the helpers here.
+//! Developer fields are skipped. The only writing done is [repair] and [annotate], both keep the records as they are.
+
+use crate::geo::{CoordinateEncoding, LatLon};
+use chrono::{DateTime, TimeZone, Utc};
+use thiserror::Error;
+
+/// Seconds between the unix epoch and the FIT epoch (1989-12-31 00:00:00 UTC)
+pub const FIT_EPOCH_OFFSET: i64 = 631_065_600;
+
+/// Global message numbers used by this crate
+pub mod message {
+    pub const FILE_ID: u16 = 0;
+    pub const SESSION: u16 = 18;
+    pub const LAP: u16 = 19;
+    pub const RECORD: u16 = 20;
+    pub const EVENT: u16 = 21;
+    pub const ACTIVITY: u16 = 34;
+    pub const WORKOUT: u16 = 26;
+}
+
+/// The field number of the timestamp, the same in all the messages
+pub const TIMESTAMP_FIELD: u8 = 253;
+
+#[derive(Error, Debug)]
+pub enum FitError {
+    #[error("The file is too short to be a FIT file")]
+    TooShort,
+    #[error("Invalid FIT header")]
+    InvalidHeader,
+    #[error("The data ends in the middle of a record at offset {0}")]
+    Truncated(usize),
+    #[error("Data message at offset {offset} uses the undefined local message type {local_type}")]
+    UndefinedLocalType { offset: usize, local_type: u8 },
+    #[error("File CRC mismatch: expected {expected:04x}, got {actual:04x}")]
+    CrcMismatch { expected: u16, actual: u16 },
+}
+
+/// The CRC used by the FIT files (CRC-16/ARC)
+pub fn crc(data: &[u8]) -> u16 {
+    crc16::State::<crc16::ARC>::calculate(data)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FitHeader {
+    /// 12 or 14 bytes, the latter has a CRC of its own
+    pub header_size: u8,
+    pub protocol_version: u8,
+    pub profile_version: u16,
+    /// The size of the records, without the header and the file CRC
+    pub data_size: u32,
+}
+
+impl FitHeader {
+    pub fn parse(data: &[u8]) -> Result<Self, FitError> {
+        if data.len() < 12 {
+            return Err(FitError::TooShort);
+        }
+        let header_size = data[0];
+        if !matches!(header_size, 12 | 14) || &data[8..12] != b".FIT" {
+            return Err(FitError::InvalidHeader);
+        }
+        if data.len() < header_size as usize {
+            return Err(FitError::TooShort);
+        }
+
+        Ok(Self {
+            header_size,
+            protocol_version: data[1],
+            profile_version: u16::from_le_bytes([data[2], data[3]]),
+            data_size: u32::from_le_bytes([data[4], data[5], data[6], data[7]]),
+        })
+    }
+
+    /// Serialize the header, computing the header CRC for the 14-byte variant
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.header_size, self.protocol_version];
+        bytes.extend_from_slice(&self.profile_version.to_le_bytes());
+        bytes.extend_from_slice(&self.data_size.to_le_bytes());
+        bytes.extend_from_slice(b".FIT");
+        if self.header_size == 14 {
+            let crc = crc(&bytes);
+            bytes.extend_from_slice(&crc.to_le_bytes());
+        }
+        bytes
+    }
+}
+
+/// A decoded field value, the invalid (unset) values are not included in the messages
+#[derive(Debug, Clone, PartialEq)]
+pub enum FitValue {
+    Unsigned(u64),
+    Signed(i64),
+    Float(f64),
+    String(String),
+    /// Arrays and the `byte` base type
+    Bytes(Vec<u8>),
+}
+
+impl FitValue {
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            FitValue::Unsigned(v) => Some(v),
+            FitValue::Signed(v) => u64::try_from(v).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            FitValue::Unsigned(v) => i64::try_from(v).ok(),
+            FitValue::Signed(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            FitValue::Unsigned(v) => Some(v as f64),
+            FitValue::Signed(v) => Some(v as f64),
+            FitValue::Float(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FitMessage {
+    pub global_number: u16,
+    pub fields: Vec<(u8, FitValue)>,
+}
+
+impl FitMessage {
+    pub fn field(&self, number: u8) -> Option<&FitValue> {
+        self.fields
+            .iter()
+            .find(|(n, _)| *n == number)
+            .map(|(_, v)| v)
+    }
+
+    /// A numeric field with the profile scale and offset applied
+    pub fn scaled(&self, number: u8, scale: f64, offset: f64) -> Option<f64> {
+        self.field(number)?.as_f64().map(|v| v / scale - offset)
+    }
+
+    pub fn timestamp(&self) -> Option<DateTime<Utc>> {
+        self.field(TIMESTAMP_FIELD)
+            .and_then(FitValue::as_i64)
+            .and_then(fit_time)
+    }
+}
+
+/// Convert a FIT `date_time` value to a UTC time
+pub fn fit_time(value: i64) -> Option<DateTime<Utc>> {
+    Utc.timestamp_opt(value + FIT_EPOCH_OFFSET, 0).single()
+}
+
+#[derive(Debug, Clone)]
+struct FieldDefinition {
+    number: u8,
+    size: u8,
+    base_type: u8,
+}
+
+#[derive(Debug, Clone)]
+struct MessageDefinition {
+    big_endian: bool,
+    global_number: u16,
+    fields: Vec<FieldDefinition>,
+    /// Total size of the developer fields, they are skipped
+    developer_size: usize,
+}
+
+impl MessageDefinition {
+    fn data_size(&self) -> usize {
+        self.fields.iter().map(|f| f.size as usize).sum::<usize>() + self.developer_size
+    }
+}
+
+fn read_uint(bytes: &[u8], big_endian: bool) -> u64 {
+    let mut value = 0u64;
+    if big_endian {
+        for &b in bytes {
+            value = value << 8 | b as u64;
+        }
+    } else {
+        for &b in bytes.iter().rev() {
+            value = value << 8 | b as u64;
+        }
+    }
+    value
+}
+
+fn decode_value(field: &FieldDefinition, bytes: &[u8], big_endian: bool) -> Option<FitValue> {
+    // the low 5 bits identify the base type, the rest are flags
+    let base = field.base_type & 0x1F;
+    let type_size = match base {
+        0x00 | 0x01 | 0x02 | 0x07 | 0x0A | 0x0D => 1,
+        0x03 | 0x04 | 0x0B => 2,
+        0x05 | 0x06 | 0x08 | 0x0C => 4,
+        0x09 | 0x0E | 0x0F | 0x10 => 8,
+        _ => return Some(FitValue::Bytes(bytes.to_vec())),
+    };
+
+    if base == 0x07 {
+        let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        return (end > 0)
+            .then(|| FitValue::String(String::from_utf8_lossy(&bytes[..end]).into_owned()));
+    }
+    if bytes.len() != type_size || base == 0x0D {
+        return bytes
+            .iter()
+            .any(|&b| b != 0xFF)
+            .then(|| FitValue::Bytes(bytes.to_vec()));
+    }
+
+    let raw = read_uint(bytes, big_endian);
+    let bits = type_size * 8;
+    let all_ones = if bits == 64 {
+        u64::MAX
+    } else {
+        (1 << bits) - 1
+    };
+    match base {
+        // enum and unsigned types, invalid is all ones
+        0x00 | 0x02 | 0x04 | 0x06 | 0x0F => (raw != all_ones).then_some(FitValue::Unsigned(raw)),
+        // "z" types, invalid is zero
+        0x0A | 0x0B | 0x0C | 0x10 => (raw != 0).then_some(FitValue::Unsigned(raw)),
+        // signed types, invalid is the maximum positive value
+        0x01 | 0x03 | 0x05 | 0x0E => {
+            if raw == all_ones >> 1 {
+                return None;
+            }
+            let shift = 64 - bits;
+            Some(FitValue::Signed(((raw << shift) as i64) >> shift))
+        }
+        0x08 => (raw != 0xFFFF_FFFF).then(|| FitValue::Float(f32::from_bits(raw as u32) as f64)),
+        0x09 => (raw != u64::MAX).then(|| FitValue::Float(f64::from_bits(raw))),
+        _ => unreachable!(),
+    }
+}
+
+/// A parsed FIT file
+#[derive(Debug, Clone)]
+pub struct FitFile {
+    pub header: FitHeader,
+    pub messages: Vec<FitMessage>,
+}
+
+impl FitFile {
+    /// Parse a complete FIT file, checking the CRC
+    pub fn parse(data: &[u8]) -> Result<Self, FitError> {
+        let header = FitHeader::parse(data)?;
+        let end = header.header_size as usize + header.data_size as usize;
+        if data.len() < end + 2 {
+            return Err(FitError::TooShort);
+        }
+
+        let expected = u16::from_le_bytes([data[end], data[end + 1]]);
+        let actual = crc(&data[..end]);
+        if expected != actual {
+            return Err(FitError::CrcMismatch { expected, actual });
+        }
+
+        let (messages, result) = parse_records(&data[header.header_size as usize..end]);
+        result?;
+
+        Ok(Self { header, messages })
+    }
+
+    pub fn messages(&self, global_number: u16) -> impl Iterator<Item = &FitMessage> {
+        self.messages
+            .iter()
+            .filter(move |m| m.global_number == global_number)
+    }
+}
+
+/// Parse the records (everything between the header and the file CRC)
+///
+/// Returns the messages parsed before the first error along with the error,
+/// so that the callers can salvage the beginning of a damaged file.
+/// The offsets in the errors are relative to the start of `records`.
+pub fn parse_records(records: &[u8]) -> (Vec<FitMessage>, Result<(), FitError>) {
+    let mut reader = RecordReader::new(records);
+    let mut messages = Vec::new();
+    loop {
+        match reader.next_record() {
+            Ok(Some(Some(message))) => messages.push(message),
+            Ok(Some(None)) => {}
+            Ok(None) => return (messages, Ok(())),
+            Err(e) => return (messages, Err(e)),
+        }
+    }
+}
+
+/// Find the length of the longest prefix of `records` consisting of complete valid records
+pub fn valid_records_len(records: &[u8]) -> usize {
+    let mut reader = RecordReader::new(records);
+    let mut valid = 0;
+    while let Ok(Some(_)) = reader.next_record() {
+        valid = reader.offset;
+    }
+    valid
+}
+
+/// What [repair] has done to a damaged file
+#[derive(Debug, Clone, PartialEq)]
+pub struct RepairReport {
+    /// The size of the records in the damaged file
+    pub original_len: usize,
+    /// The size of the records kept
+    pub kept_len: usize,
+    /// The header had a wrong data size (the device didn't finish the file)
+    pub header_fixed: bool,
+    /// The file CRC was missing or wrong
+    pub crc_fixed: bool,
+    /// The number of track points recovered
+    pub track_points: usize,
+    /// The time of the last recovered track point
+    pub last_point_time: Option<DateTime<Utc>>,
+}
+
+impl RepairReport {
+    /// The number of bytes of the records that were dropped
+    pub fn dropped_len(&self) -> usize {
+        self.original_len - self.kept_len
+    }
+}
+
+/// Salvage a damaged FIT file: keep the complete records and rewrite the header and the CRC to match them
+///
+/// The workouts the device marks as broken were usually cut short (the battery ran out while recording), leaving a
+/// header with a wrong data size, no CRC and a half-written record at the end. An intact file is returned as is,
+/// with [None] instead of a report.
+pub fn repair(data: &[u8]) -> Result<(Vec<u8>, Option<RepairReport>), FitError> {
+    if FitFile::parse(data).is_ok() {
+        return Ok((data.to_vec(), None));
+    }
+
+    let mut header = FitHeader::parse(data)?;
+    let header_size = header.header_size as usize;
+    let data_size = header.data_size as usize;
+    // a file of the size the header tells ends with the CRC, even if the CRC itself is wrong
+    let has_crc = header_size + data_size + 2 == data.len();
+    let records = if has_crc {
+        &data[header_size..data.len() - 2]
+    } else {
+        &data[header_size..]
+    };
+    let crc_matches = has_crc
+        && crc(&data[..data.len() - 2])
+            == u16::from_le_bytes([data[data.len() - 2], data[data.len() - 1]]);
+    let kept_len = valid_records_len(records);
+    let kept = &records[..kept_len];
+
+    let (messages, _) = parse_records(kept);
+    let track = messages
+        .iter()
+        .filter(|m| m.global_number == message::RECORD)
+        .filter_map(TrackPoint::from_record)
+        .collect::<Vec<_>>();
+
+    let report = RepairReport {
+        original_len: records.len(),
+        kept_len,
+        header_fixed: data_size != kept_len,
+        crc_fixed: !crc_matches,
+        track_points: track.len(),
+        last_point_time: track.last().map(|p| p.time),
+    };
+
+    header.data_size = kept_len as u32;
+    let mut repaired = header.to_bytes();
+    repaired.extend_from_slice(kept);
+    let crc = crc(&repaired);
+    repaired.extend_from_slice(&crc.to_le_bytes());
+
+    Ok((repaired, Some(report)))
+}
+
+/// The longest string a field can hold, the terminating zero takes the last byte of the 255
+const MAX_STRING_LEN: usize = 254;
+
+fn encode_string(text: &str) -> Vec<u8> {
+    let mut end = text.len().min(MAX_STRING_LEN);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    let mut bytes = text.as_bytes()[..end].to_vec();
+    bytes.push(0);
+    bytes
+}
+
+/// Give a workout a title and notes: append a `workout` message with them, rewriting the header and the CRC
+///
+/// The activity files have no field for a title, the `workout` message (meant for the planned workouts) is the closest
+/// one the profile has: its `wkt_name` and `wkt_description` take the title and the notes. The strings are cut to the
+/// 254 bytes a field can hold. A damaged file is [repaired](repair) first.
+pub fn annotate(
+    data: &[u8],
+    title: Option<&str>,
+    notes: Option<&str>,
+) -> Result<Vec<u8>, FitError> {
+    const WKT_NAME: u8 = 8;
+    const WKT_DESCRIPTION: u8 = 17;
+    const STRING: u8 = 0x07;
+    // nothing follows the appended message, so redefining any local type is fine
+    const LOCAL_TYPE: u8 = 15;
+
+    let (data, _) = repair(data)?;
+    let fields = [(WKT_NAME, title), (WKT_DESCRIPTION, notes)]
+        .into_iter()
+        .filter_map(|(number, text)| Some((number, encode_string(text?))))
+        .collect::<Vec<_>>();
+    if fields.is_empty() {
+        return Ok(data);
+    }
+
+    let mut records = vec![0x40 | LOCAL_TYPE, 0, 0];
+    records.extend_from_slice(&message::WORKOUT.to_le_bytes());
+    records.push(fields.len() as u8);
+    for (number, value) in &fields {
+        records.extend_from_slice(&[*number, value.len() as u8, STRING]);
+    }
+    records.push(LOCAL_TYPE);
+    for (_, value) in &fields {
+        records.extend_from_slice(value);
+    }
+
+    let mut header = FitHeader::parse(&data)?;
+    let header_size = header.header_size as usize;
+    let records_end = header_size + header.data_size as usize;
+    header.data_size += records.len() as u32;
+    let mut annotated = header.to_bytes();
+    annotated.extend_from_slice(&data[header_size..records_end]);
+    annotated.extend_from_slice(&records);
+    let crc = crc(&annotated);
+    annotated.extend_from_slice(&crc.to_le_bytes());
+
+    Ok(annotated)
+}
+
+struct RecordReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+    definitions: [Option<MessageDefinition>; 16],
+    last_timestamp: Option<u32>,
+}
+
+impl<'a> RecordReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            offset: 0,
+            definitions: Default::default(),
+            last_timestamp: None,
+        }
+    }
+
+    fn take(&mut self, start: usize, len: usize) -> Result<&'a [u8], FitError> {
+        let bytes = self
+            .data
+            .get(self.offset..self.offset + len)
+            .ok_or(FitError::Truncated(start))?;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    /// `None` at the end of data, `Some(None)` for a definition record
+    fn next_record(&mut self) -> Result<Option<Option<FitMessage>>, FitError> {
+        let start = self.offset;
+        let Some(&header) = self.data.get(start) else {
+            return Ok(None);
+        };
+        self.offset += 1;
+
+        if header & 0x80 != 0 {
+            // compressed timestamp header
+            let local_type = (header >> 5) & 0x3;
+            let time_offset = (header & 0x1F) as u32;
+            let mut message = self.read_data(start, local_type)?;
+            if let Some(last) = self.last_timestamp {
+                let mut timestamp = (last & !0x1F) + time_offset;
+                if time_offset < (last & 0x1F) {
+                    timestamp += 0x20;
+                }
+                self.last_timestamp = Some(timestamp);
+                message
+                    .fields
+                    .push((TIMESTAMP_FIELD, FitValue::Unsigned(timestamp as u64)));
+            }
+            return Ok(Some(Some(message)));
+        }
+
+        let local_type = header & 0x0F;
+        if header & 0x40 != 0 {
+            let has_developer_fields = header & 0x20 != 0;
+            let fixed = self.take(start, 5)?;
+            let big_endian = fixed[1] == 1;
+            let global_number = if big_endian {
+                u16::from_be_bytes([fixed[2], fixed[3]])
+            } else {
+                u16::from_le_bytes([fixed[2], fixed[3]])
+            };
+            let field_count = fixed[4] as usize;
+            let fields = self
+                .take(start, field_count * 3)?
+                .chunks_exact(3)
+                .map(|f| FieldDefinition {
+                    number: f[0],
+                    size: f[1],
+                    base_type: f[2],
+                })
+                .collect();
+            let mut developer_size = 0;
+            if has_developer_fields {
+                let count = self.take(start, 1)?[0] as usize;
+                developer_size = self
+                    .take(start, count * 3)?
+                    .chunks_exact(3)
+                    .map(|f| f[1] as usize)
+                    .sum();
+            }
+
+            self.definitions[local_type as usize] = Some(MessageDefinition {
+                big_endian,
+                global_number,
+                fields,
+                developer_size,
+            });
+            Ok(Some(None))
+        } else {
+            let message = self.read_data(start, local_type)?;
+            if let Some(timestamp) = message.field(TIMESTAMP_FIELD).and_then(FitValue::as_u64) {
+                self.last_timestamp = Some(timestamp as u32);
+            }
+            Ok(Some(Some(message)))
+        }
+    }
+
+    fn read_data(&mut self, start: usize, local_type: u8) -> Result<FitMessage, FitError> {
+        let definition =
+            self.definitions[local_type as usize]
+                .clone()
+                .ok_or(FitError::UndefinedLocalType {
+                    offset: start,
+                    local_type,
+                })?;
+        let data = self.take(start, definition.data_size())?;
+
+        let mut fields = Vec::with_capacity(definition.fields.len());
+        let mut position = 0;
+        for field in &definition.fields {
+            let bytes = &data[position..position + field.size as usize];
+            position += field.size as usize;
+            if let Some(value) = decode_value(field, bytes, definition.big_endian) {
+                fields.push((field.number, value));
+            }
+        }
+
+        Ok(FitMessage {
+            global_number: definition.global_number,
+            fields,
+        })
+    }
+}
+
+/// A single point of the recorded track
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackPoint {
+    pub time: DateTime<Utc>,
+    pub position: Option<LatLon>,
+    /// Meters above the sea level
+    pub altitude: Option<f64>,
+    pub heart_rate: Option<u8>,
+    pub cadence: Option<u8>,
+    /// Meters per second
+    pub speed: Option<f64>,
+    /// Meters from the start
+    pub distance: Option<f64>,
+}
+
+impl TrackPoint {
+    fn from_record(record: &FitMessage) -> Option<Self> {
+        let encoding = CoordinateEncoding::Semicircles;
+        let coordinate = |n| record.field(n).and_then(FitValue::as_i64);
+        let position = match (coordinate(0), coordinate(1)) {
+            (Some(lat), Some(lon)) => Some(encoding.decode_point((lat as i32, lon as i32))),
+            _ => None,
+        };
+
+        Some(Self {
+            time: record.timestamp()?,
+            position,
+            altitude: record
+                .scaled(78, 5.0, 500.0)
+                .or_else(|| record.scaled(2, 5.0, 500.0)),
+            heart_rate: record.field(3).and_then(FitValue::as_u64).map(|v| v as u8),
+            cadence: record.field(4).and_then(FitValue::as_u64).map(|v| v as u8),
+            speed: record
+                .scaled(73, 1000.0, 0.0)
+                .or_else(|| record.scaled(6, 1000.0, 0.0)),
+            distance: record.scaled(5, 100.0, 0.0),
+        })
+    }
+}
+
+impl FitFile {
+    /// The recorded track points, in the order they were recorded
+    pub fn track(&self) -> Vec<TrackPoint> {
+        self.messages(message::RECORD)
+            .filter_map(TrackPoint::from_record)
+            .collect()
+    }
+}
+
+/// The basic information about a recorded workout
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorkoutSummary {
+    pub start_time: Option<DateTime<Utc>>,
+    /// Total time, including the pauses, in seconds
+    pub elapsed_time: Option<f64>,
+    /// Time spent moving (timer running), in seconds
+    pub timer_time: Option<f64>,
+    /// Meters
+    pub distance: Option<f64>,
+    /// Meters
+    pub ascent: Option<f64>,
+    /// Meters per second
+    pub avg_speed: Option<f64>,
+}
+
+impl WorkoutSummary {
+    /// Summarize a workout from its session message, or from the track if there's none
+    ///
+    /// Unfinished and broken workouts don't have the session message
+    pub fn from_fit(fit: &FitFile) -> Self {
+        if let Some(session) = fit.messages(message::SESSION).next() {
+            return Self {
+                start_time: session
+                    .field(2)
+                    .and_then(FitValue::as_i64)
+                    .and_then(fit_time),
+                elapsed_time: session.scaled(7, 1000.0, 0.0),
+                timer_time: session.scaled(8, 1000.0, 0.0),
+                distance: session.scaled(9, 100.0, 0.0),
+                ascent: session.field(22).and_then(FitValue::as_f64),
+                avg_speed: session
+                    .scaled(124, 1000.0, 0.0)
+                    .or_else(|| session.scaled(14, 1000.0, 0.0)),
+            };
+        }
+
+        Self::from_track(&fit.track())
+    }
+
+    pub fn from_track(track: &[TrackPoint]) -> Self {
+        let (Some(first), Some(last)) = (track.first(), track.last()) else {
+            return Self {
+                start_time: None,
+                elapsed_time: None,
+                timer_time: None,
+                distance: None,
+                ascent: None,
+                avg_speed: None,
+            };
+        };
+
+        let elapsed_time = (last.time - first.time).num_seconds() as f64;
+        let distance = last.distance.or_else(|| {
+            let positions = track.iter().filter_map(|p| p.position).collect::<Vec<_>>();
+            (!positions.is_empty()).then(|| crate::geo::track_length(&positions))
+        });
+        let ascent = track
+            .iter()
+            .filter_map(|p| p.altitude)
+            .collect::<Vec<_>>()
+            .windows(2)
+            .map(|w| (w[1] - w[0]).max(0.0))
+            .sum::<f64>();
+
+        Self {
+            start_time: Some(first.time),
+            elapsed_time: Some(elapsed_time),
+            timer_time: None,
+            distance,
+            ascent: track.iter().any(|p| p.altitude.is_some()).then_some(ascent),
+            avg_speed: distance
+                .filter(|_| elapsed_time > 0.0)
+                .map(|d| d / elapsed_time),
+        }
+    }
+}
@@ -0,0 +1,94 @@
+//! Coordinate helpers shared by the route import and the track export
+//!
+//! The device (like the GPS receiver in it) works with WGS84 coordinates only, the FIT files store them as "semicircles".
+//! All the conversions go through this module, so the rounding is done the same way everywhere.
+
+/// Mean Earth radius, in meters
+const EARTH_RADIUS: f64 = 6_371_008.8;
+
+/// A WGS84 point, in degrees
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LatLon {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+impl LatLon {
+    pub fn new(lat: f64, lon: f64) -> Self {
+        Self { lat, lon }
+    }
+
+    /// Great-circle distance to another point, in meters
+    pub fn distance_to(&self, other: &LatLon) -> f64 {
+        let (lat1, lat2) = (self.lat.to_radians(), other.lat.to_radians());
+        let d_lat = lat2 - lat1;
+        let d_lon = (other.lon - self.lon).to_radians();
+
+        let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS * a.sqrt().min(1.0).asin()
+    }
+
+    /// Initial bearing of the great circle to another point, in degrees clockwise from the north (0..360)
+    pub fn bearing_to(&self, other: &LatLon) -> f64 {
+        let (lat1, lat2) = (self.lat.to_radians(), other.lat.to_radians());
+        let d_lon = (other.lon - self.lon).to_radians();
+
+        let y = d_lon.sin() * lat2.cos();
+        let x = lat1.cos() * lat2.sin() - lat1.sin() * lat2.cos() * d_lon.cos();
+        y.atan2(x).to_degrees().rem_euclid(360.0)
+    }
+}
+
+/// The way a single coordinate is stored as an integer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoordinateEncoding {
+    /// 2^31 units per 180 degrees, used by FIT files
+    Semicircles,
+}
+
+impl CoordinateEncoding {
+    fn units_per_degree(self) -> f64 {
+        match self {
+            CoordinateEncoding::Semicircles => 2f64.powi(31) / 180.0,
+        }
+    }
+
+    /// Convert degrees to the encoded value, rounding to the nearest unit
+    ///
+    /// Values out of the representable range are saturated.
+    pub fn encode(self, degrees: f64) -> i32 {
+        // `as` saturates on overflow
+        (degrees * self.units_per_degree()).round() as i32
+    }
+
+    pub fn decode(self, value: i32) -> f64 {
+        value as f64 / self.units_per_degree()
+    }
+
+    pub fn encode_point(self, point: LatLon) -> (i32, i32) {
+        (self.encode(point.lat), self.encode(point.lon))
+    }
+
+    pub fn decode_point(self, (lat, lon): (i32, i32)) -> LatLon {
+        LatLon::new(self.decode(lat), self.decode(lon))
+    }
+
+    /// The largest error introduced by encoding a coordinate, in degrees
+    pub fn max_error(self) -> f64 {
+        0.5 / self.units_per_degree()
+    }
+}
+
+/// Total length of a polyline, in meters
+pub fn track_length<'a>(points: impl IntoIterator<Item = &'a LatLon>) -> f64 {
+    let mut points = points.into_iter();
+    let Some(mut prev) = points.next() else {
+        return 0.0;
+    };
+    let mut length = 0.0;
+    for point in points {
+        length += prev.distance_to(point);
+        prev = point;
+    }
+    length
+}
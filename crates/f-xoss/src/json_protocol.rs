@@ -0,0 +1,62 @@
+//! The formats of the device JSON files across the firmware versions
+//!
+//! Each JSON file starts with a header (see [HeaderJson](crate::model::HeaderJson)) whose `version` tells the format of
+//! the file. [JsonProtocol] describes what is known to differ between the formats. The files of an unknown format are
+//! refused instead of being misread, unless a known format is chosen for the device with
+//! [XossDevice::set_json_protocol](crate::device::XossDevice::set_json_protocol).
+
+use thiserror::Error;
+
+/// What is known about a format of the device JSON files
+#[derive(Debug, PartialEq, Eq)]
+pub struct JsonProtocol {
+    /// The `version` in the header of the files
+    ///
+    /// The header field with the time of the last change is read as `update_at` too, whatever the version, as some
+    /// firmware has it under that name. It's always written as `updated_at`, no version is known to need the other one.
+    pub version: &'static str,
+    /// The files the firmware doesn't create until they are needed, read as empty when they are missing
+    pub optional_files: &'static [&'static str],
+}
+
+/// The formats the library was checked against
+pub const JSON_PROTOCOLS: &[JsonProtocol] = &[JsonProtocol {
+    version: "2.0.0",
+    // there are no workouts on a device that has never recorded one, and no sensors on one that was never paired
+    optional_files: &["workouts.json", "routebooks.json", "sensors.json"],
+}];
+
+impl JsonProtocol {
+    /// The known format with the given header version
+    pub fn find(version: &str) -> Result<&'static JsonProtocol, UnsupportedJsonVersion> {
+        JSON_PROTOCOLS
+            .iter()
+            .find(|protocol| protocol.version == version)
+            .ok_or_else(|| UnsupportedJsonVersion {
+                version: version.to_string(),
+            })
+    }
+
+    pub fn is_optional(&self, filename: &str) -> bool {
+        self.optional_files.contains(&filename)
+    }
+}
+
+fn known_versions() -> String {
+    JSON_PROTOCOLS
+        .iter()
+        .map(|protocol| protocol.version)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The device writes its JSON files in a format the library doesn't know
+#[derive(Error, Debug)]
+#[error(
+    "The device JSON files have the version {version}, which is not supported (known: {}). They could be misread, or \
+     broken when written back. If this firmware works like one of the known versions, choose it for the device",
+    known_versions()
+)]
+pub struct UnsupportedJsonVersion {
+    pub version: String,
+}
@@ -1,4 +1,37 @@
+//! Communicating with the XOSS bike computers over BLE, and the formats of the files they keep
+//!
+//! The library has no UI dependencies, so it can be embedded into any application: the long operations report their
+//! progress through [progress::ProgressSink] and the application renders it the way it wants. Downloading the A-GNSS
+//! data is up to the application too, [mga] only parses it.
+//!
+//! The parts beyond talking to the device are behind the cargo features, all of them enabled by default:
+//! - `progress`: human-readable sizes in the transfer messages
+//! - `cli-models`: the workout, route and map formats ([fit], [geo], [dem], [routes], [time_zone], [wheel])
+//! - `mga-download`: parsing the downloaded A-GNSS data ([mga])
+//! - `dfu`: firmware updates ([dfu])
+//!
+//! The `mock` feature adds a simulated device (`transport::mock`) for testing without the hardware.
+
+#[cfg(feature = "cli-models")]
+pub mod dem;
 pub mod device;
+#[cfg(feature = "dfu")]
+pub mod dfu;
+#[cfg(feature = "cli-models")]
+pub mod fit;
+#[cfg(feature = "cli-models")]
+pub mod geo;
+pub mod json_protocol;
+#[cfg(feature = "mga-download")]
 pub mod mga;
 pub mod model;
+pub mod progress;
+#[cfg(feature = "cli-models")]
+pub mod routes;
+pub mod scan;
+#[cfg(feature = "cli-models")]
+pub mod time_zone;
+pub mod transcript;
 pub mod transport;
+#[cfg(feature = "cli-models")]
+pub mod wheel;
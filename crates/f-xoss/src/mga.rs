@@ -1,10 +1,88 @@
 use binrw::{BinRead, BinReaderExt, BinResult};
 use chrono::NaiveDate;
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter};
+use tracing::warn;
 
+/// A satellite constellation the A-GNSS data can be requested for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Gnss {
+    Gps,
+    Glonass,
+    Galileo,
+    BeiDou,
+}
+
+impl Gnss {
+    /// The constellations supported by all the devices
+    pub const DEFAULT: &'static [Gnss] = &[Gnss::Gps, Gnss::Glonass];
+
+    /// Parse the name as used by AssistNow (`gps`, `glo`, `gal`, `bds`) or the full one (`galileo`, ...)
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "gps" => Some(Self::Gps),
+            "glo" | "glonass" => Some(Self::Glonass),
+            "gal" | "galileo" => Some(Self::Galileo),
+            "bds" | "beidou" => Some(Self::BeiDou),
+            _ => None,
+        }
+    }
+
+    /// The name in the AssistNow requests
+    pub fn query_name(self) -> &'static str {
+        match self {
+            Self::Gps => "gps",
+            Self::Glonass => "glo",
+            Self::Galileo => "gal",
+            Self::BeiDou => "bds",
+        }
+    }
+
+    /// From the message id of the UBX-MGA ephemeris messages
+    fn from_mga_message_id(id: u8) -> Option<Self> {
+        match id {
+            0x00 => Some(Self::Gps),
+            0x02 => Some(Self::Galileo),
+            0x03 => Some(Self::BeiDou),
+            0x06 => Some(Self::Glonass),
+            _ => None,
+        }
+    }
+
+    /// From the gnssId of the UBX messages
+    fn from_ubx_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Self::Gps),
+            2 => Some(Self::Galileo),
+            3 => Some(Self::BeiDou),
+            6 => Some(Self::Glonass),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Gnss {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Gps => "GPS",
+            Self::Glonass => "GLONASS",
+            Self::Galileo => "Galileo",
+            Self::BeiDou => "BeiDou",
+        })
+    }
+}
+
+/// The A-GNSS data from AssistNow Offline (the almanacs for the next weeks) or Online (the current ephemerides)
 pub struct MgaData {
     pub data: Vec<u8>,
     pub valid_since: NaiveDate,
     pub valid_until: NaiveDate,
+    /// The constellations having the data
+    pub constellations: BTreeSet<Gnss>,
+    /// The number of UBX frames in the data
+    pub frames: usize,
+    /// The number of UBX frames dropped because of a wrong checksum, they are not in the data
+    pub dropped_frames: usize,
 }
 
 #[derive(BinRead)]
@@ -23,6 +101,18 @@ struct UbxMgaAno {
     ck_b: u8,
 }
 
+/// The header of any UBX message, the AssistNow Online data is a mix of them
+#[derive(BinRead)]
+#[br(magic = b"\xb5\x62")]
+struct UbxHeader {
+    class: u8,
+    id: u8,
+    /// Of the payload, followed by the two checksum bytes
+    length: u16,
+}
+
+const UBX_CLASS_MGA: u8 = 0x13;
+
 impl UbxMgaAno {
     pub fn date(&self) -> NaiveDate {
         let year = 2000 + self.year as i32;
@@ -32,20 +122,134 @@ impl UbxMgaAno {
     }
 }
 
-pub fn parse_mga_data(data: Vec<u8>) -> BinResult<MgaData> {
-    let mut cursor = std::io::Cursor::new(&data);
+/// What to do with the UBX frames having a wrong checksum
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CorruptFrames {
+    /// Drop them with a warning, the rest of the data is still good
+    #[default]
+    Skip,
+    /// Fail the parsing
+    Fail,
+}
+
+/// The UBX checksum (8-bit Fletcher) over the class, id, length and payload
+fn ubx_checksum(bytes: &[u8]) -> [u8; 2] {
+    let (mut ck_a, mut ck_b) = (0u8, 0u8);
+    for &b in bytes {
+        ck_a = ck_a.wrapping_add(b);
+        ck_b = ck_b.wrapping_add(ck_a);
+    }
+    [ck_a, ck_b]
+}
+
+/// The UBX frames with a good checksum, as found in the data
+struct UbxFrames {
+    /// The data without the dropped frames
+    data: Vec<u8>,
+    /// The headers and the whole frames
+    frames: Vec<(UbxHeader, std::ops::Range<usize>)>,
+    dropped: usize,
+}
+
+fn split_ubx_frames(data: &[u8], corrupt_frames: CorruptFrames) -> BinResult<UbxFrames> {
+    let mut cursor = std::io::Cursor::new(data);
+    let mut frames = UbxFrames {
+        data: Vec::with_capacity(data.len()),
+        frames: Vec::new(),
+        dropped: 0,
+    };
+    while cursor.position() < data.len() as u64 {
+        let start = cursor.position();
+        let header: UbxHeader = cursor.read_le()?;
+        let end = cursor.position() + header.length as u64 + 2;
+        if end > data.len() as u64 {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        cursor.set_position(end);
+
+        let frame = &data[start as usize..end as usize];
+        let (body, checksum) = frame[2..].split_at(frame.len() - 4);
+        if ubx_checksum(body) != checksum {
+            match corrupt_frames {
+                CorruptFrames::Skip => {
+                    warn!(
+                        "Dropping the UBX frame at 0x{:x} with a wrong checksum",
+                        start
+                    );
+                    frames.dropped += 1;
+                    continue;
+                }
+                CorruptFrames::Fail => {
+                    return Err(binrw::Error::AssertFail {
+                        pos: start,
+                        message: "Wrong UBX frame checksum".to_string(),
+                    })
+                }
+            }
+        }
+
+        let range = frames.data.len()..frames.data.len() + frame.len();
+        frames.data.extend_from_slice(frame);
+        frames.frames.push((header, range));
+    }
+    Ok(frames)
+}
+
+/// Parse the AssistNow Offline data
+pub fn parse_mga_data(data: Vec<u8>, corrupt_frames: CorruptFrames) -> BinResult<MgaData> {
+    let frames = split_ubx_frames(&data, corrupt_frames)?;
     let mut items = Vec::new();
-    while cursor.position() < cursor.get_ref().len() as u64 {
-        let ubx_mga_ano: UbxMgaAno = cursor.read_le()?;
+    for (_, range) in &frames.frames {
+        let ubx_mga_ano: UbxMgaAno = std::io::Cursor::new(&frames.data[range.clone()]).read_le()?;
         items.push(ubx_mga_ano);
     }
 
-    let valid_since = items.iter().map(|u| u.date()).min().unwrap();
-    let valid_until = items.iter().map(|u| u.date()).max().unwrap();
+    let (Some(valid_since), Some(valid_until)) = (
+        items.iter().map(|u| u.date()).min(),
+        items.iter().map(|u| u.date()).max(),
+    ) else {
+        return Err(binrw::Error::AssertFail {
+            pos: 0,
+            message: "No good MGA-ANO frames in the data".to_string(),
+        });
+    };
+    let constellations = items
+        .iter()
+        .filter_map(|u| Gnss::from_ubx_id(u.gnss_id))
+        .collect();
 
     Ok(MgaData {
-        data,
+        data: frames.data,
         valid_since,
         valid_until,
+        constellations,
+        frames: items.len(),
+        dropped_frames: frames.dropped,
+    })
+}
+
+/// Parse the AssistNow Online data, downloaded on `date`
+///
+/// It is only good for a few hours, so it's valid just for the day it was downloaded.
+pub fn parse_mga_online_data(
+    data: Vec<u8>,
+    date: NaiveDate,
+    corrupt_frames: CorruptFrames,
+) -> BinResult<MgaData> {
+    let frames = split_ubx_frames(&data, corrupt_frames)?;
+    let constellations = frames
+        .frames
+        .iter()
+        .filter(|(header, _)| header.class == UBX_CLASS_MGA)
+        .filter_map(|(header, _)| Gnss::from_mga_message_id(header.id))
+        .collect();
+
+    Ok(MgaData {
+        frames: frames.frames.len(),
+        dropped_frames: frames.dropped,
+        data: frames.data,
+        valid_since: date,
+        valid_until: date,
+        constellations,
     })
 }
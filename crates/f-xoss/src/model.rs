@@ -1,6 +1,11 @@
-use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Duration, FixedOffset, NaiveDateTime, TimeZone, Utc};
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_repr::{Deserialize_repr, Serialize_repr};
 use serde_tuple::{Deserialize_tuple, Serialize_tuple};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::ops::RangeInclusive;
+use thiserror::Error;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct HeaderJson {
@@ -11,6 +16,38 @@ pub struct HeaderJson {
     pub version: String,
 }
 
+/// The fields of a device JSON object that the models don't know about
+///
+/// Newer firmware versions may add settings, they are kept instead of being dropped when the object is written back.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(transparent)]
+pub struct UnknownFields(pub serde_json::Map<String, serde_json::Value>);
+
+thread_local! {
+    /// The names of the unknown fields met by [collect_unknown_fields]
+    static SEEN_UNKNOWN_FIELDS: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+}
+
+impl<'de> Deserialize<'de> for UnknownFields {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let fields = serde_json::Map::deserialize(deserializer)?;
+        SEEN_UNKNOWN_FIELDS.with(|seen| {
+            if let Some(seen) = seen.borrow_mut().as_mut() {
+                seen.extend(fields.keys().cloned());
+            }
+        });
+        Ok(Self(fields))
+    }
+}
+
+/// Run a deserialization, also returning the names of the unknown fields it has met
+pub fn collect_unknown_fields<T>(deserialize: impl FnOnce() -> T) -> (T, Vec<String>) {
+    SEEN_UNKNOWN_FIELDS.with(|seen| *seen.borrow_mut() = Some(Vec::new()));
+    let result = deserialize();
+    let fields = SEEN_UNKNOWN_FIELDS.with(|seen| seen.borrow_mut().take().unwrap_or_default());
+    (result, fields)
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct WithHeader<T> {
     #[serde(flatten)]
@@ -37,6 +74,9 @@ pub struct UserProfileInner {
     /// Time zone offset in seconds
     pub time_zone: i32,
     pub weight: i64,
+    /// The fields this version doesn't know about, written back as they were
+    #[serde(flatten)]
+    pub unknown: UnknownFields,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -44,12 +84,18 @@ pub struct User {
     pub platform: String,
     pub uid: u32,
     pub user_name: String,
+    /// The fields this version doesn't know about, written back as they were
+    #[serde(flatten)]
+    pub unknown: UnknownFields,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct UserProfile {
     pub user: Option<User>,
     pub user_profile: UserProfileInner,
+    /// The fields this version doesn't know about, written back as they were
+    #[serde(flatten)]
+    pub unknown: UnknownFields,
 }
 
 #[derive(Serialize_repr, Deserialize_repr, PartialEq, Debug, Clone, Copy)]
@@ -64,6 +110,16 @@ pub enum WorkoutState {
     Broken = 4,
 }
 
+impl WorkoutState {
+    /// Whether the device has stopped writing to the workout file
+    ///
+    /// A workout being recorded goes to [WorkoutState::NotSynchronized] when the recording is stopped,
+    /// downloading it before that would produce a truncated file.
+    pub fn is_finished(&self) -> bool {
+        *self != WorkoutState::Recording
+    }
+}
+
 #[derive(Serialize_tuple, Deserialize_tuple, Debug, Clone)]
 pub struct WorkoutsItem {
     pub name: u64,
@@ -75,6 +131,83 @@ impl WorkoutsItem {
     pub fn filename(&self) -> String {
         format!("{}.fit", self.name)
     }
+
+    /// When the workout was started, going by its name, `time_zone` is the device offset from UTC in seconds
+    pub fn start_time(&self, time_zone: i32) -> Option<DateTime<Utc>> {
+        WorkoutNaming::detect(std::slice::from_ref(self))?.start_time(self.name, time_zone)
+    }
+}
+
+/// How the workout files on the device are named, derived from the workout start time
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum WorkoutNaming {
+    /// Unix time, like `1687000000`
+    UnixTime,
+    /// Local date and time as digits, like `20230617120000`
+    DateTime,
+}
+
+impl WorkoutNaming {
+    /// Guess the naming from the workouts already on the device, `None` if there are none
+    pub fn detect(workouts: &[WorkoutsItem]) -> Option<Self> {
+        // date-time names have 14 digits, unix times are going to have 10 for a while
+        workouts.iter().map(|w| w.name).max().map(|name| {
+            if name >= 10_000_000_000_000 {
+                WorkoutNaming::DateTime
+            } else {
+                WorkoutNaming::UnixTime
+            }
+        })
+    }
+
+    /// The name of a workout started at `start`, `time_zone` is the device offset from UTC in seconds
+    pub fn name(self, start: DateTime<Utc>, time_zone: i32) -> u64 {
+        match self {
+            WorkoutNaming::UnixTime => start.timestamp().max(0) as u64,
+            WorkoutNaming::DateTime => {
+                let offset = FixedOffset::east_opt(time_zone)
+                    .unwrap_or_else(|| FixedOffset::east_opt(0).unwrap());
+                start
+                    .with_timezone(&offset)
+                    .format("%Y%m%d%H%M%S")
+                    .to_string()
+                    .parse()
+                    .unwrap()
+            }
+        }
+    }
+
+    /// The start time of a workout with this name, the reverse of [WorkoutNaming::name]
+    ///
+    /// `None` if the name is not a valid time
+    pub fn start_time(self, name: u64, time_zone: i32) -> Option<DateTime<Utc>> {
+        match self {
+            WorkoutNaming::UnixTime => Utc.timestamp_opt(name.try_into().ok()?, 0).single(),
+            WorkoutNaming::DateTime => {
+                let local =
+                    NaiveDateTime::parse_from_str(&name.to_string(), "%Y%m%d%H%M%S").ok()?;
+                let offset = FixedOffset::east_opt(time_zone)?;
+                Some(
+                    offset
+                        .from_local_datetime(&local)
+                        .single()?
+                        .with_timezone(&Utc),
+                )
+            }
+        }
+    }
+
+    /// A name for a workout started at `start` that is not in `taken`
+    ///
+    /// Workouts started at the same second are moved one second later, and so on,
+    /// so the same set of workouts always gets the same names.
+    pub fn unique_name(self, start: DateTime<Utc>, time_zone: i32, taken: &HashSet<u64>) -> u64 {
+        (0..)
+            .map(|shift| self.name(start + Duration::seconds(shift), time_zone))
+            .find(|name| !taken.contains(name))
+            .unwrap()
+    }
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
@@ -138,6 +271,9 @@ pub struct Settings {
     pub overwrite: u8,
     /// Whether to play a tone when device keys are pressed
     pub keytone: bool,
+    /// The fields this version doesn't know about, written back as they were
+    #[serde(flatten)]
+    pub unknown: UnknownFields,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
@@ -160,6 +296,9 @@ pub struct Gear {
     pub name: String,
     #[serde(rename = "type")]
     pub type_: GearType,
+    /// The fields this version doesn't know about, written back as they were
+    #[serde(flatten)]
+    pub unknown: UnknownFields,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
@@ -187,3 +326,378 @@ pub struct Route {
     /// Route total elevation gain, in meters
     pub gain: u32,
 }
+
+/// The contents of panels.json
+///
+/// The schema is not verified: it is put together from the files of a single device and firmware version.
+/// Everything else the file has is kept in [Panels::unknown] and written back as it was.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+pub struct Panels {
+    pub panels: Vec<Panel>,
+    /// The fields this version doesn't know about, written back as they were
+    #[serde(flatten)]
+    pub unknown: UnknownFields,
+}
+
+/// A single data screen
+///
+/// The meaning of the field identifiers is not documented, they are the same numbers the official app writes
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct Panel {
+    /// Whether the panel is shown on the device
+    pub enabled: bool,
+    /// Data fields shown on the panel, in order of their position on the screen
+    ///
+    /// The number of fields also selects the layout of the panel
+    pub items: Vec<u16>,
+    /// The fields this version doesn't know about, written back as they were
+    #[serde(flatten)]
+    pub unknown: UnknownFields,
+}
+
+/// What an external sensor measures
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum SensorType {
+    HeartRate,
+    Speed,
+    Cadence,
+    /// A combined speed and cadence sensor
+    SpeedCadence,
+    Power,
+}
+
+impl SensorType {
+    pub const ALL: [SensorType; 5] = [
+        SensorType::HeartRate,
+        SensorType::Speed,
+        SensorType::Cadence,
+        SensorType::SpeedCadence,
+        SensorType::Power,
+    ];
+
+    /// The name used on the command line, like `heart-rate`
+    pub fn name(self) -> &'static str {
+        match self {
+            SensorType::HeartRate => "heart-rate",
+            SensorType::Speed => "speed",
+            SensorType::Cadence => "cadence",
+            SensorType::SpeedCadence => "speed-cadence",
+            SensorType::Power => "power",
+        }
+    }
+}
+
+impl std::fmt::Display for SensorType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl std::str::FromStr for SensorType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|t| t.name() == s)
+            .ok_or_else(|| {
+                let names = Self::ALL.map(|t| t.name()).join(", ");
+                format!("unknown sensor type {:?}, expected one of: {}", s, names)
+            })
+    }
+}
+
+/// An external BLE sensor paired with the device
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Sensor {
+    /// The BLE address, like `AA:BB:CC:DD:EE:FF`
+    pub address: String,
+    #[serde(rename = "type")]
+    pub type_: SensorType,
+    /// The name shown on the device
+    pub name: String,
+    /// The fields this version doesn't know about, written back as they were
+    #[serde(flatten)]
+    pub unknown: UnknownFields,
+}
+
+impl Sensor {
+    pub fn new(address: String, type_: SensorType, name: String) -> Self {
+        Self {
+            address,
+            type_,
+            name,
+            unknown: UnknownFields::default(),
+        }
+    }
+}
+
+/// A value that the device would reject (or silently misbehave with)
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    #[error("{field} must be between {min} and {max}, got {value}")]
+    OutOfRange {
+        field: &'static str,
+        value: i64,
+        min: i64,
+        max: i64,
+    },
+    #[error("The gear name must not be empty")]
+    EmptyGearName,
+}
+
+fn check_range(
+    field: &'static str,
+    value: i64,
+    range: RangeInclusive<i64>,
+) -> Result<(), ValidationError> {
+    if range.contains(&value) {
+        Ok(())
+    } else {
+        Err(ValidationError::OutOfRange {
+            field,
+            value,
+            min: *range.start(),
+            max: *range.end(),
+        })
+    }
+}
+
+/// Builds [Settings], filling the fields the device doesn't use
+#[derive(Debug, Clone, Default)]
+pub struct SettingsBuilder {
+    settings: Settings,
+}
+
+impl SettingsBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn language(mut self, language: Language) -> Self {
+        self.settings.language = language;
+        self
+    }
+
+    pub fn unit(mut self, unit: DistanceUnit) -> Self {
+        self.settings.unit = unit;
+        self
+    }
+
+    pub fn temperature_unit(mut self, unit: TemperatureUnit) -> Self {
+        self.settings.temperature_unit = unit;
+        self
+    }
+
+    pub fn backlight(mut self, backlight: Backlight) -> Self {
+        self.settings.backlight = backlight;
+        self
+    }
+
+    pub fn auto_pause(mut self, auto_pause: AutoPause) -> Self {
+        self.settings.auto_pause = auto_pause;
+        self
+    }
+
+    pub fn keytone(mut self, keytone: bool) -> Self {
+        self.settings.keytone = keytone;
+        self
+    }
+
+    pub fn build(self) -> Settings {
+        Settings {
+            time_formatter: 0,
+            overwrite: 0,
+            ..self.settings
+        }
+    }
+}
+
+impl From<Settings> for SettingsBuilder {
+    fn from(settings: Settings) -> Self {
+        Self { settings }
+    }
+}
+
+/// Modifies a [UserProfile] read from the device
+///
+/// Only the fields that were changed are validated: the device keeps zeros in the fields the user never filled in,
+/// and these have to be written back as they are.
+#[derive(Debug, Clone)]
+pub struct UserProfileBuilder {
+    base: UserProfileInner,
+    profile: UserProfile,
+}
+
+impl UserProfileBuilder {
+    pub fn new(profile: UserProfile) -> Self {
+        Self {
+            base: profile.user_profile.clone(),
+            profile,
+        }
+    }
+
+    pub fn user(mut self, user: User) -> Self {
+        self.profile.user = Some(user);
+        self
+    }
+
+    /// Replace all the profile values at once, like when applying the overrides from a template
+    pub fn values(mut self, values: UserProfileInner) -> Self {
+        self.profile.user_profile = values;
+        self
+    }
+
+    /// Functional threshold power, in watts
+    pub fn ftp(mut self, ftp: i64) -> Self {
+        self.profile.user_profile.ftp = ftp;
+        self
+    }
+
+    /// Lactate threshold heart rate, in bpm
+    pub fn lthr(mut self, lthr: i64) -> Self {
+        self.profile.user_profile.lthr = lthr;
+        self
+    }
+
+    /// Maximum heart rate, in bpm
+    pub fn max_hr(mut self, max_hr: i64) -> Self {
+        self.profile.user_profile.maxhr = max_hr;
+        self
+    }
+
+    /// Heart rate alarm threshold in bpm, 0 to disable
+    pub fn alarm_hr(mut self, alarm_hr: i64) -> Self {
+        self.profile.user_profile.alahr = alarm_hr;
+        self
+    }
+
+    /// Rider weight, in kilograms
+    pub fn weight(mut self, weight: i64) -> Self {
+        self.profile.user_profile.weight = weight;
+        self
+    }
+
+    /// Rider height, in centimeters
+    pub fn height(mut self, height: i64) -> Self {
+        self.profile.user_profile.height = height;
+        self
+    }
+
+    /// Offset from UTC, in seconds
+    pub fn time_zone(mut self, time_zone: i32) -> Self {
+        self.profile.user_profile.time_zone = time_zone;
+        self
+    }
+
+    pub fn build(self) -> Result<UserProfile, ValidationError> {
+        let (base, new) = (&self.base, &self.profile.user_profile);
+        let checks: [(&'static str, i64, i64, RangeInclusive<i64>); 7] = [
+            ("FTP", base.ftp, new.ftp, 1..=2000),
+            ("LTHR", base.lthr, new.lthr, 40..=250),
+            ("MAXHR", base.maxhr, new.maxhr, 40..=250),
+            ("ALAHR", base.alahr, new.alahr, 0..=250),
+            ("weight", base.weight, new.weight, 20..=300),
+            ("height", base.height, new.height, 50..=250),
+            (
+                "time_zone",
+                base.time_zone as i64,
+                new.time_zone as i64,
+                -12 * 3600..=14 * 3600,
+            ),
+        ];
+        for (field, old, value, range) in checks {
+            if value != old {
+                check_range(field, value, range)?;
+            }
+        }
+
+        Ok(self.profile)
+    }
+}
+
+/// Builds a [Gear] profile, or modifies one read from the device
+///
+/// Like with [UserProfileBuilder], only the changed fields of a modified gear are validated, and the fields the builder
+/// doesn't set are written back as they were.
+#[derive(Debug, Clone)]
+pub struct GearBuilder {
+    base: Option<Gear>,
+    gear: Gear,
+}
+
+impl GearBuilder {
+    /// A gear with the defaults of the official app: a 10 kg bike with 700x23c wheels
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            gear: Gear {
+                gid: 1,
+                weight: 10000,
+                wheel_size: 2096,
+                activated: false,
+                name: name.into(),
+                type_: GearType::Bike,
+                unknown: UnknownFields::default(),
+            },
+            base: None,
+        }
+    }
+
+    pub fn modify(gear: Gear) -> Self {
+        Self {
+            base: Some(gear.clone()),
+            gear,
+        }
+    }
+
+    pub fn gid(mut self, gid: u32) -> Self {
+        self.gear.gid = gid;
+        self
+    }
+
+    /// Bike weight, in grams
+    pub fn weight(mut self, weight: u32) -> Self {
+        self.gear.weight = weight;
+        self
+    }
+
+    /// Wheel circumference, in mm
+    pub fn wheel_size(mut self, wheel_size: u32) -> Self {
+        self.gear.wheel_size = wheel_size;
+        self
+    }
+
+    pub fn activated(mut self, activated: bool) -> Self {
+        self.gear.activated = activated;
+        self
+    }
+
+    pub fn build(self) -> Result<Gear, ValidationError> {
+        let (base, gear) = (self.base.as_ref(), self.gear);
+        if base.map(|base| &base.name) != Some(&gear.name) && gear.name.trim().is_empty() {
+            return Err(ValidationError::EmptyGearName);
+        }
+        let checks: [(&'static str, Option<u32>, u32, RangeInclusive<i64>); 2] = [
+            (
+                "weight",
+                base.map(|base| base.weight),
+                gear.weight,
+                1000..=50_000,
+            ),
+            // from the 12" wheels of kids' bikes to the 29x3.0 tires
+            (
+                "wheel_size",
+                base.map(|base| base.wheel_size),
+                gear.wheel_size,
+                900..=2500,
+            ),
+        ];
+        for (field, old, value, range) in checks {
+            if old != Some(value) {
+                check_range(field, value as i64, range)?;
+            }
+        }
+        Ok(gear)
+    }
+}
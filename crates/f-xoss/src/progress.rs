@@ -0,0 +1,30 @@
+//! Reporting the progress of long operations (file transfers, firmware updates)
+//!
+//! The library doesn't render anything itself, the applications implement [ProgressSink] to show the progress in their own way.
+
+/// Receives the progress of a single operation, in bytes
+pub trait ProgressSink: Send + Sync {
+    /// The operation has started, `total` bytes are to be transferred
+    fn start(&self, total: u64);
+    /// `delta` more bytes have been transferred
+    fn advance(&self, delta: u64);
+    /// A packet was damaged or lost and is transferred again
+    fn retry(&self) {}
+}
+
+/// Discards the progress
+#[derive(Debug, Default, Copy, Clone)]
+pub struct NoProgress;
+
+impl ProgressSink for NoProgress {
+    fn start(&self, _total: u64) {}
+    fn advance(&self, _delta: u64) {}
+}
+
+/// A size for the progress and the log messages, like "1.5 MiB", or "1536 B" without the `progress` feature
+pub fn format_size(size: u64) -> String {
+    #[cfg(feature = "progress")]
+    return humansize::format_size(size, humansize::BINARY);
+    #[cfg(not(feature = "progress"))]
+    return format!("{} B", size);
+}
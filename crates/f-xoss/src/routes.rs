@@ -0,0 +1,311 @@
+//! Turn-by-turn cues for the routes
+//!
+//! The NAV models show the upcoming turns while following a route. How the cues are stored in the `.ro` files is
+//! not known yet (the files made by the official app haven't been decoded), so nothing here is written to the device.
+//! This module only works out the cues themselves: from the named waypoints of a planned route, from a turn list
+//! computed by a routing engine (the OSRM maneuver modifiers are understood by [CueKind::parse]), or from the shape
+//! of the track when there's nothing else.
+//!
+//! The routes listed in `routebooks.json` also carry their length and elevation gain. The tracks drawn in a planner
+//! often come without elevation, [route_stats] takes it from the offline DEM tiles (see [crate::dem]) instead.
+
+use crate::dem::{Dem, DemError};
+use crate::geo::LatLon;
+use std::fmt::Display;
+use thiserror::Error;
+
+/// How far along the track the bearings are taken before and after a point, in meters
+///
+/// Short enough to tell apart the turns of a street grid, long enough to ignore the GPS noise of a recorded track.
+const TURN_LOOK_DISTANCE: f64 = 25.0;
+/// The smallest change of direction reported as a turn, in degrees
+const MIN_TURN_ANGLE: f64 = 30.0;
+/// Waypoints farther from the track than this are not on the route, in meters
+const MAX_WAYPOINT_DISTANCE: f64 = 100.0;
+/// A computed turn this close to a waypoint cue is the same turn, in meters
+const SAME_CUE_DISTANCE: f64 = 30.0;
+/// Elevation changes smaller than this are not counted as climbs, in meters
+///
+/// The DEM heights are interpolated from a grid of 30-90 m cells, summing every tiny rise along a flat road would
+/// inflate the gain noticeably.
+const GAIN_THRESHOLD: f64 = 3.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CueKind {
+    Straight,
+    SlightLeft,
+    Left,
+    SharpLeft,
+    SlightRight,
+    Right,
+    SharpRight,
+    UTurn,
+    /// A named point on the route without a turn (a summit, a water stop)
+    Checkpoint,
+}
+
+impl CueKind {
+    /// The turn for a change of direction, positive angles (in degrees) being to the right
+    ///
+    /// Returns [CueKind::Straight] for the changes too small to be a turn.
+    pub fn from_angle(angle: f64) -> Self {
+        let magnitude = angle.abs();
+        let right = angle > 0.0;
+        match magnitude {
+            m if m < MIN_TURN_ANGLE => Self::Straight,
+            m if m < 60.0 => {
+                if right {
+                    Self::SlightRight
+                } else {
+                    Self::SlightLeft
+                }
+            }
+            m if m < 120.0 => {
+                if right {
+                    Self::Right
+                } else {
+                    Self::Left
+                }
+            }
+            m if m < 165.0 => {
+                if right {
+                    Self::SharpRight
+                } else {
+                    Self::SharpLeft
+                }
+            }
+            _ => Self::UTurn,
+        }
+    }
+
+    /// Parse the cue names used by the route planners, case-insensitively
+    ///
+    /// Accepts the OSRM maneuver modifiers (`slight left`, `uturn`) and the GPX waypoint types (`Left`), also with
+    /// dashes or underscores instead of spaces. Anything else (like `Summit`) is a checkpoint.
+    pub fn parse(name: &str) -> Self {
+        let normalized = name.trim().to_ascii_lowercase().replace(['-', '_'], " ");
+        match normalized.as_str() {
+            "straight" | "continue" => Self::Straight,
+            "slight left" | "bear left" => Self::SlightLeft,
+            "left" | "turn left" => Self::Left,
+            "sharp left" => Self::SharpLeft,
+            "slight right" | "bear right" => Self::SlightRight,
+            "right" | "turn right" => Self::Right,
+            "sharp right" => Self::SharpRight,
+            "uturn" | "u turn" => Self::UTurn,
+            _ => Self::Checkpoint,
+        }
+    }
+
+    pub fn is_turn(self) -> bool {
+        !matches!(self, Self::Straight | Self::Checkpoint)
+    }
+}
+
+impl Display for CueKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Straight => "straight",
+            Self::SlightLeft => "slight left",
+            Self::Left => "left",
+            Self::SharpLeft => "sharp left",
+            Self::SlightRight => "slight right",
+            Self::Right => "right",
+            Self::SharpRight => "sharp right",
+            Self::UTurn => "u-turn",
+            Self::Checkpoint => "checkpoint",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("The waypoint {name:?} is {distance:.0} m away from the route")]
+pub struct WaypointOffRoute {
+    pub name: String,
+    pub distance: f64,
+}
+
+/// A named point of a planned route, like a GPX `<wpt>`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Waypoint {
+    pub position: LatLon,
+    pub name: String,
+    /// The turn made there, if the planner tells it
+    pub kind: Option<CueKind>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cue {
+    /// Index of the track point the cue is at
+    pub index: usize,
+    /// Distance from the start of the route, in meters
+    pub distance: f64,
+    pub kind: CueKind,
+    /// What to show with the cue, the waypoint name or the street
+    pub name: Option<String>,
+}
+
+/// Distance from the start of the track to each of its points, in meters
+fn cumulative_distances(track: &[LatLon]) -> Vec<f64> {
+    let mut distance = 0.0;
+    let mut result = Vec::with_capacity(track.len());
+    for (i, point) in track.iter().enumerate() {
+        if i > 0 {
+            distance += track[i - 1].distance_to(point);
+        }
+        result.push(distance);
+    }
+    result
+}
+
+/// The change of direction at a point, positive to the right, in degrees
+///
+/// Compares the bearings [TURN_LOOK_DISTANCE] before and after the point, `None` if it's too close to the ends.
+fn turn_angle(track: &[LatLon], distances: &[f64], index: usize) -> Option<f64> {
+    let before = (0..index)
+        .rev()
+        .find(|&j| distances[index] - distances[j] >= TURN_LOOK_DISTANCE)?;
+    let after = (index + 1..track.len())
+        .find(|&j| distances[j] - distances[index] >= TURN_LOOK_DISTANCE)?;
+    let incoming = track[before].bearing_to(&track[index]);
+    let outgoing = track[index].bearing_to(&track[after]);
+    Some((outgoing - incoming + 540.0).rem_euclid(360.0) - 180.0)
+}
+
+/// Find the turns from the shape of the track
+///
+/// A bend spanning several points produces a single cue at its sharpest point.
+pub fn cues_from_turns(track: &[LatLon]) -> Vec<Cue> {
+    let distances = cumulative_distances(track);
+    // the cues with their angles
+    let mut cues: Vec<(Cue, f64)> = Vec::new();
+
+    for index in 0..track.len() {
+        let Some(angle) = turn_angle(track, &distances, index) else {
+            continue;
+        };
+        let kind = CueKind::from_angle(angle);
+        if !kind.is_turn() {
+            continue;
+        }
+
+        let cue = Cue {
+            index,
+            distance: distances[index],
+            kind,
+            name: None,
+        };
+        match cues.last_mut() {
+            // the same bend
+            Some((last, last_angle)) if cue.distance - last.distance < TURN_LOOK_DISTANCE => {
+                if angle.abs() > last_angle.abs() {
+                    *last = cue;
+                    *last_angle = angle;
+                }
+            }
+            _ => cues.push((cue, angle)),
+        }
+    }
+
+    cues.into_iter().map(|(cue, _)| cue).collect()
+}
+
+/// Place the waypoints on the track, each at the closest track point
+///
+/// Fails with [WaypointOffRoute] if a waypoint is farther than [MAX_WAYPOINT_DISTANCE] from the track,
+/// it's most likely from another route.
+pub fn cues_from_waypoints(
+    track: &[LatLon],
+    waypoints: &[Waypoint],
+) -> Result<Vec<Cue>, WaypointOffRoute> {
+    let distances = cumulative_distances(track);
+    let mut cues = waypoints
+        .iter()
+        .filter_map(|waypoint| {
+            let (index, distance) = track
+                .iter()
+                .map(|point| point.distance_to(&waypoint.position))
+                .enumerate()
+                .min_by(|(_, a), (_, b)| a.total_cmp(b))?;
+            if distance > MAX_WAYPOINT_DISTANCE {
+                return Some(Err(WaypointOffRoute {
+                    name: waypoint.name.clone(),
+                    distance,
+                }));
+            }
+            Some(Ok(Cue {
+                index,
+                distance: distances[index],
+                kind: waypoint
+                    .kind
+                    .unwrap_or_else(|| CueKind::parse(&waypoint.name)),
+                name: Some(waypoint.name.clone()),
+            }))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    cues.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+    Ok(cues)
+}
+
+/// The cues for a route: the waypoints, and the turns of the track not covered by them
+pub fn route_cues(track: &[LatLon], waypoints: &[Waypoint]) -> Result<Vec<Cue>, WaypointOffRoute> {
+    let mut cues = cues_from_waypoints(track, waypoints)?;
+    let planned = cues.clone();
+    cues.extend(cues_from_turns(track).into_iter().filter(|turn| {
+        !planned.iter().any(|cue| {
+            cue.kind.is_turn() && (cue.distance - turn.distance).abs() < SAME_CUE_DISTANCE
+        })
+    }));
+    cues.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+    Ok(cues)
+}
+
+/// Terrain elevation of each point of the track, `None` for the points outside of the available tiles
+pub fn track_elevation(track: &[LatLon], dem: &mut Dem) -> Result<Vec<Option<f64>>, DemError> {
+    track.iter().map(|point| dem.elevation(*point)).collect()
+}
+
+/// Total climb along an elevation profile, in meters
+///
+/// Only the rises of at least [GAIN_THRESHOLD] from the last low point are counted.
+pub fn elevation_gain(elevations: impl IntoIterator<Item = f64>) -> f64 {
+    let mut elevations = elevations.into_iter();
+    let Some(mut reference) = elevations.next() else {
+        return 0.0;
+    };
+    let mut gain = 0.0;
+    for elevation in elevations {
+        if elevation < reference {
+            reference = elevation;
+        } else if elevation - reference >= GAIN_THRESHOLD {
+            gain += elevation - reference;
+            reference = elevation;
+        }
+    }
+    gain
+}
+
+/// The route summary shown by the device, see [crate::model::Route]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RouteStats {
+    /// In meters
+    pub length: u32,
+    /// In meters, 0 when the elevation is unknown
+    pub gain: u32,
+}
+
+/// Work out the length of the route and, with the DEM tiles, its elevation gain
+///
+/// The points outside of the available tiles are skipped, use [Dem::missing_tiles] to tell the user which ones
+/// are needed.
+pub fn route_stats(track: &[LatLon], dem: Option<&mut Dem>) -> Result<RouteStats, DemError> {
+    let gain = match dem {
+        Some(dem) => elevation_gain(track_elevation(track, dem)?.into_iter().flatten()),
+        None => 0.0,
+    };
+    Ok(RouteStats {
+        length: crate::geo::track_length(track).round() as u32,
+        gain: gain.round() as u32,
+    })
+}
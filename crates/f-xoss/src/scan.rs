@@ -0,0 +1,180 @@
+//! Discovering XOSS devices nearby
+
+use anyhow::{anyhow, bail, Context, Result};
+use btleplug::api::{BDAddr, Central, CentralEvent, Peripheral as _, ScanFilter};
+use btleplug::platform::{Adapter, Peripheral, PeripheralId};
+use std::collections::{BTreeSet, HashMap};
+use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio_stream::{Stream, StreamExt};
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+/// The service advertised by the DFU bootloader
+///
+/// The devices in the DFU mode are told apart when scanning, even without the `dfu` feature to update them.
+pub const DFU_SERVICE_UUID: Uuid = Uuid::from_u128(0x0000fe59_0000_1000_8000_00805f9b34fb);
+
+/// Name the DFU bootloader advertises with
+const DFU_TARGET_NAME: &str = "DfuTarg";
+
+#[derive(Debug, Clone)]
+pub struct DiscoveredDevice {
+    pub peripheral: Peripheral,
+    pub id: PeripheralId,
+    pub address: BDAddr,
+    pub name: Option<String>,
+    pub rssi: Option<i16>,
+    /// Whether the device looks like a XOSS device judging by its name
+    pub likely_xoss: bool,
+    /// Whether the device is running the firmware update bootloader
+    pub in_dfu_mode: bool,
+}
+
+/// Whether the advertised name looks like one of a XOSS device
+pub fn is_likely_xoss_name(name: &str) -> bool {
+    name.contains("XOSS")
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ScanOptions {
+    /// Report only the devices that look like XOSS devices (including the ones in the DFU mode)
+    pub only_xoss: bool,
+    /// Also report the devices again when their signal strength changes, for the lists showing it
+    pub report_rssi_changes: bool,
+}
+
+/// Stops the scan when the stream is dropped
+///
+/// Outside of a runtime (like when the stream is dropped with it) the scan is left to the BLE stack to stop.
+struct ScanGuard(Adapter);
+
+impl Drop for ScanGuard {
+    fn drop(&mut self) {
+        let Ok(runtime) = Handle::try_current() else {
+            debug!("Not stopping the scan, there is no runtime to do it");
+            return;
+        };
+        let adapter = self.0.clone();
+        runtime.spawn(async move {
+            if let Err(e) = adapter.stop_scan().await {
+                warn!("Failed to stop scan: {}", e);
+            }
+        });
+    }
+}
+
+/// Scan for BLE devices nearby
+///
+/// Each device (told apart by its address and advertised services, so that a device restarted into the DFU bootloader
+/// is a new one) is reported when it's discovered and then again every time its advertised name changes
+/// (some platforms report the name only after the first advertisement), or its RSSI if
+/// [ScanOptions::report_rssi_changes] is set.
+/// The scan runs until the stream is dropped.
+pub async fn scan_devices(
+    adapter: &Adapter,
+    options: ScanOptions,
+) -> Result<impl Stream<Item = Result<DiscoveredDevice>>> {
+    let mut events = adapter
+        .events()
+        .await
+        .context("Failed to get adapter events stream")?;
+
+    adapter
+        .start_scan(ScanFilter::default())
+        .await
+        .context("Failed to start scan")?;
+    let guard = ScanGuard(adapter.clone());
+
+    Ok(async_stream::try_stream! {
+        let guard = guard;
+        let adapter = &guard.0;
+        let mut seen = HashMap::<(BDAddr, BTreeSet<Uuid>), (Option<String>, Option<i16>)>::new();
+
+        while let Some(event) = events.next().await {
+            let (CentralEvent::DeviceDiscovered(id) | CentralEvent::DeviceUpdated(id)) = event
+            else {
+                continue;
+            };
+
+            let peripheral = adapter
+                .peripheral(&id)
+                .await
+                .context("Failed to get the discovered peripheral")?;
+            let properties = peripheral
+                .properties()
+                .await
+                .context("Failed to get peripheral properties")?;
+            let Some(properties) = properties else {
+                continue;
+            };
+
+            // the updates are mostly the RSSI changes, don't report the same device over and over
+            let key = (
+                properties.address,
+                properties.services.iter().copied().collect::<BTreeSet<_>>(),
+            );
+            let state = (
+                properties.local_name.clone(),
+                properties.rssi.filter(|_| options.report_rssi_changes),
+            );
+            if seen.get(&key) == Some(&state) {
+                continue;
+            }
+            seen.insert(key, state);
+
+            let in_dfu_mode = properties.services.contains(&DFU_SERVICE_UUID)
+                || properties.local_name.as_deref() == Some(DFU_TARGET_NAME);
+            let likely_xoss = properties
+                .local_name
+                .as_deref()
+                .is_some_and(is_likely_xoss_name);
+
+            if options.only_xoss && !likely_xoss && !in_dfu_mode {
+                continue;
+            }
+
+            debug!(
+                "Discovered {} ({:?})",
+                properties.address, properties.local_name
+            );
+
+            yield DiscoveredDevice {
+                peripheral,
+                id,
+                address: properties.address,
+                name: properties.local_name,
+                rssi: properties.rssi,
+                likely_xoss,
+                in_dfu_mode,
+            };
+        }
+    })
+}
+
+/// Scan until the device is found: the one with `address`, or the first XOSS device if it's `None`
+///
+/// The devices in the DFU mode are skipped. Gives up when the device is not found in `timeout`.
+pub async fn find_device(
+    adapter: &Adapter,
+    address: Option<BDAddr>,
+    timeout: Duration,
+) -> Result<Peripheral> {
+    let find = async {
+        let options = ScanOptions {
+            only_xoss: address.is_none(),
+            ..Default::default()
+        };
+        let mut devices = Box::pin(scan_devices(adapter, options).await?);
+        while let Some(device) = devices.next().await {
+            let device = device?;
+            if !device.in_dfu_mode && address.map_or(true, |a| a == device.address) {
+                return Ok(device.peripheral);
+            }
+        }
+        bail!("The scan ended before the device was found")
+    };
+    tokio::time::timeout(timeout, find)
+        .await
+        .map_err(|_| anyhow!("The device was not found"))?
+}
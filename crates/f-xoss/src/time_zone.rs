@@ -0,0 +1,201 @@
+//! Time zone offsets as the device sees them
+//!
+//! The device knows only a fixed offset from UTC (the `time_zone` of the user profile, in seconds). It's used to
+//! display the times and to name the workouts. The offset is updated on sync, so a DST change between two syncs
+//! shifts the start times of the workouts recorded in between (the FIT files themselves store UTC and are fine).
+//!
+//! [zone_at] tells the time zone of a place from the bundled time zone boundaries (of the timezone-boundary-builder
+//! project, via `tzf-rs`), with the offset rules of the tz database (via `chrono-tz`). It's for setting the offset from
+//! where the rides are rather than from the computer doing the sync.
+
+use crate::geo::LatLon;
+use chrono::{DateTime, Duration, FixedOffset, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
+use std::sync::OnceLock;
+use tzf_rs::DefaultFinder;
+
+/// Parse a fixed offset like `+05:30`, `-0330`, `+5` or `UTC+01:00` into seconds east of UTC
+pub fn parse_offset(s: &str) -> Option<i32> {
+    let s = s.trim();
+    let s = s
+        .strip_prefix("UTC")
+        .or_else(|| s.strip_prefix("GMT"))
+        .unwrap_or(s);
+    if s.is_empty() {
+        return Some(0);
+    }
+
+    let (sign, s) = match s.as_bytes()[0] {
+        b'+' => (1, &s[1..]),
+        b'-' => (-1, &s[1..]),
+        _ => return None,
+    };
+    let (hours, minutes) = match s.split_once(':') {
+        Some((h, m)) => (h, m),
+        None if s.len() > 2 => s.split_at(s.len() - 2),
+        None => (s, "0"),
+    };
+    if hours.is_empty()
+        || !hours
+            .bytes()
+            .chain(minutes.bytes())
+            .all(|b| b.is_ascii_digit())
+    {
+        return None;
+    }
+    let (hours, minutes) = (hours.parse::<i32>().ok()?, minutes.parse::<i32>().ok()?);
+    if hours > 14 || minutes >= 60 {
+        return None;
+    }
+
+    Some(sign * (hours * 3600 + minutes * 60))
+}
+
+/// Format an offset in seconds like `+05:30`
+pub fn format_offset(offset: i32) -> String {
+    FixedOffset::east_opt(offset)
+        .map(|o| o.to_string())
+        .unwrap_or_else(|| format!("{} seconds", offset))
+}
+
+/// A moment the UTC offset of a time zone changes at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OffsetChange {
+    /// The first moment with the new offset
+    pub time: DateTime<Utc>,
+    pub from: i32,
+    pub to: i32,
+}
+
+/// Find the offset changes (like the DST transitions) between `start` and `end`
+///
+/// `offset_at` gives the offset of the time zone in seconds at a moment. The changes less than an hour apart
+/// may be missed, no real time zone has those.
+pub fn offset_changes(
+    offset_at: impl Fn(DateTime<Utc>) -> i32,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Vec<OffsetChange> {
+    let step = Duration::hours(1);
+
+    let mut changes = Vec::new();
+    let mut time = start;
+    let mut offset = offset_at(time);
+    while time < end {
+        let next = (time + step).min(end);
+        let next_offset = offset_at(next);
+        if next_offset != offset {
+            // bisect to the second
+            let (mut before, mut after) = (time, next);
+            while after - before > Duration::seconds(1) {
+                let middle = before + (after - before) / 2;
+                if offset_at(middle) == offset {
+                    before = middle;
+                } else {
+                    after = middle;
+                }
+            }
+            changes.push(OffsetChange {
+                time: after,
+                from: offset,
+                to: next_offset,
+            });
+        }
+        time = next;
+        offset = next_offset;
+    }
+
+    changes
+}
+
+/// How far from a place [zone_at] looks for a border with another zone, in degrees (about 2 km)
+///
+/// The bundled borders are simplified, so close to a border the place may end up on the wrong side of it.
+const BORDER_MARGIN: f64 = 0.02;
+
+/// A time zone of the tz database, as found by [zone_at]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Zone(pub Tz);
+
+impl Zone {
+    /// The zone with the given IANA name, like `Europe/Berlin`
+    pub fn named(name: &str) -> Option<Zone> {
+        name.parse().ok().map(Zone)
+    }
+
+    /// The IANA name of the zone
+    pub fn name(&self) -> &'static str {
+        self.0.name()
+    }
+
+    /// The offset from UTC at the given moment, in seconds
+    pub fn offset_at(&self, time: DateTime<Utc>) -> i32 {
+        self.0
+            .offset_from_utc_datetime(&time.naive_utc())
+            .fix()
+            .local_minus_utc()
+    }
+
+    /// Whether the two zones have the same offsets, checked hourly over the years around now
+    ///
+    /// The zones on both sides of a border often differ only in the history before that.
+    fn same_offsets(&self, other: &Zone) -> bool {
+        let start = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        let end = Utc.with_ymd_and_hms(2031, 1, 1, 0, 0, 0).unwrap();
+        let mut time = start;
+        while time < end {
+            if self.offset_at(time) != other.offset_at(time) {
+                return false;
+            }
+            time += Duration::hours(1);
+        }
+        true
+    }
+}
+
+fn finder() -> &'static DefaultFinder {
+    static FINDER: OnceLock<DefaultFinder> = OnceLock::new();
+    FINDER.get_or_init(DefaultFinder::new)
+}
+
+/// The zones the time zone boundaries put the place into, `None` if it's not covered or a zone is not known to chrono-tz
+fn zones_at(lat: f64, lon: f64) -> Option<Vec<Zone>> {
+    let names = finder().get_tz_names(lon, lat);
+    if names.is_empty() {
+        return None;
+    }
+    names
+        .into_iter()
+        .map(|name| name.parse().ok().map(Zone))
+        .collect()
+}
+
+/// The time zone of a place, from the bundled time zone boundaries
+///
+/// Returns `None` when the zone is not certain: near a border with a zone that has other offsets, where the
+/// boundaries overlap, and out at sea, where only the nautical `Etc/GMT` zones are.
+pub fn zone_at(position: LatLon) -> Option<Zone> {
+    let zone = *zones_at(position.lat, position.lon)?.first()?;
+    if zone.name().starts_with("Etc/") {
+        return None;
+    }
+
+    let around = [
+        (0.0, 0.0),
+        (BORDER_MARGIN, 0.0),
+        (-BORDER_MARGIN, 0.0),
+        (0.0, BORDER_MARGIN),
+        (0.0, -BORDER_MARGIN),
+    ];
+    for (lat, lon) in around {
+        let lat = (position.lat + lat).clamp(-90.0, 90.0);
+        let lon = position.lon + lon;
+        for other in zones_at(lat, lon)? {
+            if other != zone && !zone.same_offsets(&other) {
+                return None;
+            }
+        }
+    }
+
+    Some(zone)
+}
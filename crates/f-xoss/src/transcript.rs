@@ -0,0 +1,164 @@
+//! A readable log of a session with the device: the decoded control messages and a summary of each file transfer
+//!
+//! Unlike a hex dump, a transcript can be read (and written) by a human, so it's good for the bug reports and for
+//! checking the exchanges in the tests. One event per line:
+//!
+//! ```text
+//! > RequestReturn "workouts.json"
+//! < Returning "workouts.json"
+//! = download workouts.json 1234 bytes
+//! < Idle
+//! ```
+//!
+//! `>` is a message sent to the device, `<` is one received from it, `=` is a completed file transfer.
+//! The bodies are shown as strings when they are printable, as hex otherwise.
+
+use crate::device::TransferDirection;
+use crate::transport::ctl_message::{ControlMessageType, RawControlMessage};
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TranscriptEntry {
+    Sent {
+        message_type: ControlMessageType,
+        body: Vec<u8>,
+    },
+    Received {
+        message_type: ControlMessageType,
+        body: Vec<u8>,
+    },
+    Transfer {
+        filename: String,
+        direction: TransferDirection,
+        size: u64,
+    },
+}
+
+fn format_body(body: &[u8]) -> String {
+    if body.is_empty() {
+        String::new()
+    } else if body.iter().all(|b| (0x20..0x7f).contains(b)) {
+        let text = String::from_utf8_lossy(body)
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"");
+        format!(" \"{}\"", text)
+    } else {
+        format!(" 0x{}", hex::encode(body))
+    }
+}
+
+impl Display for TranscriptEntry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TranscriptEntry::Sent { message_type, body } => {
+                write!(f, "> {:?}{}", message_type, format_body(body))
+            }
+            TranscriptEntry::Received { message_type, body } => {
+                write!(f, "< {:?}{}", message_type, format_body(body))
+            }
+            TranscriptEntry::Transfer {
+                filename,
+                direction,
+                size,
+            } => write!(
+                f,
+                "= {} {} {} bytes",
+                match direction {
+                    TransferDirection::Download => "download",
+                    TransferDirection::Upload => "upload",
+                },
+                filename,
+                size
+            ),
+        }
+    }
+}
+
+/// The transcript doesn't match the expected script
+#[derive(Error, Debug)]
+#[error("The transcript doesn't match the expected one\n--- expected:\n{expected}\n--- actual:\n{actual}")]
+pub struct TranscriptMismatch {
+    pub expected: String,
+    pub actual: String,
+}
+
+/// Whether the lines match the script, `...` in the script matching any number of lines
+fn matches(script: &[&str], lines: &[String]) -> bool {
+    match script.split_first() {
+        None => lines.is_empty(),
+        Some((&"...", rest)) => (0..=lines.len()).any(|skip| matches(rest, &lines[skip..])),
+        Some((expected, rest)) => lines
+            .split_first()
+            .is_some_and(|(line, lines)| line == expected && matches(rest, lines)),
+    }
+}
+
+/// A transcript being recorded, cloning it gives another handle to the same one
+#[derive(Debug, Clone, Default)]
+pub struct Transcript {
+    entries: Arc<Mutex<Vec<TranscriptEntry>>>,
+}
+
+impl Transcript {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, entry: TranscriptEntry) {
+        self.entries.lock().unwrap().push(entry);
+    }
+
+    pub(crate) fn record_sent(&self, message: &RawControlMessage) {
+        self.record(TranscriptEntry::Sent {
+            message_type: message.message_type,
+            body: message.body.to_vec(),
+        });
+    }
+
+    pub(crate) fn record_received(&self, message: &RawControlMessage) {
+        self.record(TranscriptEntry::Received {
+            message_type: message.message_type,
+            body: message.body.to_vec(),
+        });
+    }
+
+    pub fn entries(&self) -> Vec<TranscriptEntry> {
+        self.entries.lock().unwrap().clone()
+    }
+
+    /// Check the transcript against a script in the same format
+    ///
+    /// Blank lines and the lines starting with `#` are ignored, a `...` line matches any number of events.
+    pub fn check(&self, expected: &str) -> Result<(), TranscriptMismatch> {
+        let script = expected
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .collect::<Vec<_>>();
+        let lines = self
+            .entries()
+            .iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<_>>();
+
+        if matches(&script, &lines) {
+            Ok(())
+        } else {
+            Err(TranscriptMismatch {
+                expected: script.join("\n"),
+                actual: lines.join("\n"),
+            })
+        }
+    }
+}
+
+impl Display for Transcript {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for entry in self.entries.lock().unwrap().iter() {
+            writeln!(f, "{}", entry)?;
+        }
+        Ok(())
+    }
+}
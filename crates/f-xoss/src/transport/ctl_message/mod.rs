@@ -1,6 +1,7 @@
 use anyhow::{bail, Context, Result};
 use num_enum::TryFromPrimitive;
 use thiserror::Error;
+use tracing::warn;
 
 #[derive(TryFromPrimitive, Debug, PartialEq, Eq, Clone, Copy)]
 #[repr(u8)]
@@ -53,6 +54,10 @@ pub enum ControlMessageType {
     RequestMga = 0x77,
     ReturnMga = 0x78,
 
+    /// Get the activity status: whether a workout is being recorded
+    ///
+    /// The reply has the same type and a single byte body, see `device::ActivityStatus`. Older firmware versions
+    /// reply with [ControlMessageType::ErrVali].
     StatusAct = 0xAC,
 
     /// Perform factory reset
@@ -73,13 +78,20 @@ pub struct RawControlMessage<'a> {
     pub body: &'a [u8],
 }
 
-fn calc_checksum(buf: &[u8]) -> u8 {
+pub(crate) fn calc_checksum(buf: &[u8]) -> u8 {
     buf.iter().fold(0, |acc, x| acc ^ x)
 }
 
 impl<'a> RawControlMessage<'a> {
     pub fn read(buf: &'a [u8]) -> Result<Self> {
         let len = buf.len();
+        if len < 2 {
+            bail!(
+                "Control message too short ({} bytes): {}",
+                len,
+                hex::encode(buf)
+            );
+        }
 
         let msg_type = buf[0];
         let data = &buf[1..len - 1];
@@ -105,12 +117,13 @@ impl<'a> RawControlMessage<'a> {
 
     pub fn write<'b>(&self, buf: &'b mut [u8]) -> Result<&'b [u8]> {
         let len = self.body.len();
-        assert!(
-            len + 2 <= buf.len(),
-            "Message too long ({} > {})",
-            len + 2,
-            buf.len()
-        );
+        if len + 2 > buf.len() {
+            bail!(
+                "Control message too long for the buffer ({} > {})",
+                len + 2,
+                buf.len()
+            );
+        }
 
         buf[0] = self.message_type as u8;
         buf[1..len + 1].copy_from_slice(self.body);
@@ -124,23 +137,17 @@ impl<'a> RawControlMessage<'a> {
         match self.message_type {
             ErrVali => Err(ControlError::Validation),
             ErrNoFile => Err(ControlError::NoFile(
-                std::str::from_utf8(self.body)
-                    .expect("Invalid UTF-8 in ErrNoFile")
-                    .to_string(),
+                String::from_utf8_lossy(self.body).into_owned(),
             )),
             ErrMemory => Err(ControlError::NoMemory),
             ErrStatus => match self.body {
                 b"\0" => Err(ControlError::InvalidTransactionStatus),
                 body => Err(ControlError::InvalidFileStatus(
-                    std::str::from_utf8(body)
-                        .expect("Invalid UTF-8 in ErrStatus")
-                        .to_string(),
+                    String::from_utf8_lossy(body).into_owned(),
                 )),
             },
             ErrDecode => Err(ControlError::DecodeFailed(
-                std::str::from_utf8(self.body)
-                    .expect("Invalid UTF-8 in ErrDecode")
-                    .to_string(),
+                String::from_utf8_lossy(self.body).into_owned(),
             )),
             _ => Ok(self),
         }
@@ -170,3 +177,39 @@ pub enum ControlError {
     #[error("JSON decode failed: {0}")]
     DecodeFailed(String),
 }
+
+/// The device has replied with a body different from the expected one
+#[derive(Error, Debug)]
+#[error("Unexpected reply to {request:?}: expected {expected}, got {}", hex::encode(.actual))]
+pub struct UnexpectedReply {
+    pub request: ControlMessageType,
+    /// What the reply should have looked like, as hex or as a description
+    pub expected: String,
+    pub actual: Vec<u8>,
+}
+
+/// Check that the device has echoed the request body back
+///
+/// Some firmware versions truncate long file names in the echo, this is tolerated with a warning.
+pub fn check_echo(
+    request: ControlMessageType,
+    expected: &[u8],
+    actual: &[u8],
+) -> Result<(), UnexpectedReply> {
+    if actual == expected {
+        return Ok(());
+    }
+
+    let error = UnexpectedReply {
+        request,
+        expected: hex::encode(expected),
+        actual: actual.to_vec(),
+    };
+    if !actual.is_empty() && expected.starts_with(actual) {
+        warn!("{} (truncated echo, ignoring)", error);
+        return Ok(());
+    }
+
+    warn!("{}", error);
+    Err(error)
+}
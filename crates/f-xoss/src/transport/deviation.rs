@@ -0,0 +1,132 @@
+//! Handling of the device behaviour that doesn't match the protocol as we know it
+//!
+//! By default the deviations that can be worked around are only logged. In the strict mode they fail the operation instead,
+//! which is useful to notice the protocol changes in the new firmware versions.
+
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use thiserror::Error;
+use tracing::{error, warn};
+
+use crate::transport::ctl_message::{check_echo, ControlMessageType, UnexpectedReply};
+
+/// The device did something unexpected that is normally tolerated
+#[derive(Error, Debug, Clone)]
+#[error("Protocol deviation: {description}\n{dump}")]
+pub struct ProtocolDeviation {
+    pub description: String,
+    /// Hex dump of the data related to the deviation
+    pub dump: String,
+}
+
+impl ProtocolDeviation {
+    pub fn new(description: impl Into<String>, data: &[u8]) -> Self {
+        Self {
+            description: description.into(),
+            dump: hex_dump(data),
+        }
+    }
+}
+
+/// Format the data as offset, hex bytes and ASCII, 16 bytes per line
+pub fn hex_dump(data: &[u8]) -> String {
+    if data.is_empty() {
+        return "(no data)".to_string();
+    }
+
+    let mut result = String::new();
+    for (i, line) in data.chunks(16).enumerate() {
+        if i > 0 {
+            result.push('\n');
+        }
+        write!(result, "{:08x}  ", i * 16).unwrap();
+        for j in 0..16 {
+            match line.get(j) {
+                Some(b) => write!(result, "{:02x} ", b).unwrap(),
+                None => result.push_str("   "),
+            }
+        }
+        result.push(' ');
+        result.extend(line.iter().map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '.'
+            }
+        }));
+    }
+    result
+}
+
+/// Decides whether the protocol deviations are logged or fail the operations
+#[derive(Debug, Default)]
+pub struct DeviationPolicy {
+    strict: AtomicBool,
+    /// A deviation noticed outside of any operation, to be reported by the next one
+    pending: Mutex<Option<ProtocolDeviation>>,
+}
+
+impl DeviationPolicy {
+    pub fn set_strict(&self, strict: bool) {
+        self.strict.store(strict, Ordering::Relaxed);
+    }
+
+    pub fn is_strict(&self) -> bool {
+        self.strict.load(Ordering::Relaxed)
+    }
+
+    /// Report a deviation noticed during an operation, the error should fail it
+    pub fn report(&self, deviation: ProtocolDeviation) -> Result<(), ProtocolDeviation> {
+        if self.is_strict() {
+            return Err(deviation);
+        }
+        warn!("{}", deviation);
+        Ok(())
+    }
+
+    /// Report a deviation noticed in the background (like an unexpected notification)
+    ///
+    /// In the strict mode it fails the next operation, see [Self::take_pending].
+    pub fn report_background(&self, deviation: ProtocolDeviation) {
+        if !self.is_strict() {
+            warn!("{}", deviation);
+            return;
+        }
+        error!("{}", deviation);
+        // keep the first one, the rest are likely its consequences
+        self.pending.lock().unwrap().get_or_insert(deviation);
+    }
+
+    /// Fail with the deviation reported in the background since the last call, if any
+    pub fn take_pending(&self) -> Result<(), ProtocolDeviation> {
+        match self.pending.lock().unwrap().take() {
+            Some(deviation) => Err(deviation),
+            None => Ok(()),
+        }
+    }
+
+    /// Same as [check_echo], but the truncated echo is a deviation
+    pub fn check_echo(
+        &self,
+        request: ControlMessageType,
+        expected: &[u8],
+        actual: &[u8],
+    ) -> anyhow::Result<()> {
+        if self.is_strict()
+            && actual != expected
+            && !actual.is_empty()
+            && expected.starts_with(actual)
+        {
+            let error = UnexpectedReply {
+                request,
+                expected: hex::encode(expected),
+                actual: actual.to_vec(),
+            };
+            return Err(
+                ProtocolDeviation::new(format!("{} (truncated echo)", error), actual).into(),
+            );
+        }
+        check_echo(request, expected, actual).map_err(Into::into)
+    }
+}
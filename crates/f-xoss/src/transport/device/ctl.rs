@@ -1,31 +1,50 @@
 use crate::transport::ctl_message::RawControlMessage;
+use crate::transport::deviation::ProtocolDeviation;
 use crate::transport::device::Shared;
+use crate::transport::stats::StatsEvent;
 use anyhow::{bail, Context};
-use btleplug::api::{Characteristic, Peripheral, WriteType};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::mpsc::Receiver;
+use tracing::trace;
 
-pub const CTL_BUFFER_SIZE: usize = 20;
-pub type CtlBuffer = [u8; CTL_BUFFER_SIZE];
+/// The longest control message that fits into a single write with the default ATT MTU
+///
+/// btleplug doesn't tell the negotiated MTU, so the longer messages are not sent at all
+pub const MAX_CTL_WRITE_SIZE: usize = 20;
+
+/// Holds the bytes of a control message, so that a [RawControlMessage] can borrow its body from it
+///
+/// The size is not fixed: a received message is stored as it has arrived, without copying, and the buffer grows
+/// to fit a message being sent. Some firmware versions send replies longer than 20 bytes on the links with larger MTUs.
+#[derive(Debug, Default, Clone)]
+pub struct CtlBuffer {
+    data: Vec<u8>,
+}
+
+impl CtlBuffer {
+    /// Serialize the message into the buffer
+    pub fn encode(&mut self, message: &RawControlMessage) -> anyhow::Result<&[u8]> {
+        self.data.clear();
+        self.data.resize(message.body.len() + 2, 0);
+        message.write(&mut self.data)
+    }
+
+    /// Take the received bytes and parse them
+    pub fn decode(&mut self, data: Vec<u8>) -> anyhow::Result<RawControlMessage<'_>> {
+        self.data = data;
+        RawControlMessage::read(&self.data)
+    }
+}
 
 pub struct CtlChannel {
     shared: Arc<Shared>,
-    ctl_characteristic: Characteristic,
     ctl_recv: Receiver<Vec<u8>>,
 }
 
 impl CtlChannel {
-    pub(super) fn new(
-        shared: Arc<Shared>,
-        ctl_characteristic: Characteristic,
-        ctl_recv: Receiver<Vec<u8>>,
-    ) -> Self {
-        Self {
-            shared,
-            ctl_characteristic,
-            ctl_recv,
-        }
+    pub(super) fn new(shared: Arc<Shared>, ctl_recv: Receiver<Vec<u8>>) -> Self {
+        Self { shared, ctl_recv }
     }
 
     pub async fn send_ctl(
@@ -36,57 +55,88 @@ impl CtlChannel {
         // TODO: we may have troubles handling failures after sending but before receiving the reply
         // maybe send the command reset if it happens?
 
-        let message = message
-            .write(buffer.as_mut())
-            .context("Encoding the message")?;
+        self.drop_stale()?;
+
+        let encoded = buffer.encode(&message).context("Encoding the message")?;
 
-        self.send_ctl_raw(&message)
+        self.send_ctl_raw(encoded)
             .await
             .context("Sending the message & receiving reply")?;
 
+        if let Some(transcript) = self.shared.transcript.lock().unwrap().as_ref() {
+            transcript.record_sent(&message);
+        }
+
         Ok(())
     }
 
+    /// Send an already encoded message, which doesn't have to be a valid one
+    pub async fn send_ctl_bytes(&mut self, message: &[u8]) -> anyhow::Result<()> {
+        self.drop_stale()?;
+        self.send_ctl_raw(message).await
+    }
+
     pub async fn recv_ctl<'a>(
         &mut self,
         buffer: &'a mut CtlBuffer,
         timeout: Duration,
     ) -> anyhow::Result<RawControlMessage<'a>> {
-        let reply = self.recv_ctl_raw(buffer, timeout).await?;
-        let reply = RawControlMessage::read(reply).context("Decoding the control reply")?;
+        let reply = self.recv_ctl_bytes(timeout).await?;
+        let reply = match buffer.decode(reply) {
+            Ok(reply) => reply,
+            Err(e) => {
+                self.shared.stats.count(StatsEvent::CtlMalformed);
+                return Err(e).context("Decoding the control reply");
+            }
+        };
+
+        if let Some(transcript) = self.shared.transcript.lock().unwrap().as_ref() {
+            transcript.record_received(&reply);
+        }
+
         Ok(reply)
     }
 
-    async fn recv_ctl_raw<'a>(
-        &mut self,
-        buffer: &'a mut CtlBuffer,
-        timeout: Duration,
-    ) -> anyhow::Result<&'a [u8]> {
+    /// Receive a message without decoding it
+    pub async fn recv_ctl_bytes(&mut self, timeout: Duration) -> anyhow::Result<Vec<u8>> {
         let recv = self.ctl_recv.recv();
         let timeout = tokio::time::sleep(timeout);
 
-        let recv = tokio::select! {
+        tokio::select! {
             msg = recv => msg.context("Failed to receive control reply"),
-            _ = timeout => bail!("Timeout waiting for control reply"),
-        }?;
-
-        let reply = recv.as_slice();
-        buffer[..reply.len()].copy_from_slice(reply);
+            _ = timeout => {
+                self.shared.stats.count(StatsEvent::CtlTimeout);
+                bail!("Timeout waiting for control reply")
+            }
+        }
+    }
 
-        Ok(&buffer[..reply.len()])
+    /// Drop the messages nobody has asked for
+    ///
+    /// A reply arriving after its request has timed out would otherwise be taken for the reply to the next one
+    fn drop_stale(&mut self) -> anyhow::Result<()> {
+        while let Ok(stale) = self.ctl_recv.try_recv() {
+            self.shared.stats.count(StatsEvent::CtlUnsolicited);
+            self.shared.deviations.report(ProtocolDeviation::new(
+                "Dropping an unsolicited control message",
+                &stale,
+            ))?;
+        }
+        Ok(())
     }
 
     async fn send_ctl_raw(&mut self, message: &[u8]) -> anyhow::Result<()> {
-        if message.len() > 20 {
-            bail!("Control message too long");
+        if message.len() > MAX_CTL_WRITE_SIZE {
+            bail!(
+                "Control message too long ({} bytes, at most {} can be sent)",
+                message.len(),
+                MAX_CTL_WRITE_SIZE
+            );
         }
 
-        self.shared
-            .device
-            .write(&self.ctl_characteristic, message, WriteType::WithResponse)
-            .await
-            .context("Failed to send control message")?;
+        trace!("CTL TX: {}", hex::encode(message));
+        self.shared.stats.count(StatsEvent::CtlSent);
 
-        Ok(())
+        self.shared.link.write_ctl(message).await
     }
 }
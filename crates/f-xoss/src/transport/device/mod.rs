@@ -1,31 +1,43 @@
 mod ctl;
 mod uart;
 
-use super::ctl_message::RawControlMessage;
-pub use ctl::{CtlBuffer, CTL_BUFFER_SIZE};
+use super::ctl_message::{calc_checksum, RawControlMessage};
+pub use ctl::{CtlBuffer, MAX_CTL_WRITE_SIZE};
 use uart::UartChannel;
 pub use uart::UartStream;
 
-use std::collections::BTreeMap;
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
+use crate::transcript::Transcript;
+use crate::transport::ctl_message::ControlMessageType;
+use crate::transport::deviation::{DeviationPolicy, ProtocolDeviation};
+use crate::transport::peripheral::{BlePeripheral, Characteristic, WriteType};
+use crate::transport::stats::{StatsCounters, TransportStats};
+use crate::transport::ymodem::YModemOptions;
 use anyhow::{bail, Context, Result};
-use btleplug::api::{Characteristic, Peripheral as _};
-use btleplug::platform::Peripheral;
+use async_trait::async_trait;
+use btleplug::api::CharPropFlags;
 use ctl::CtlChannel;
 use futures_util::future::{AbortHandle, Abortable};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::watch;
 use tokio::sync::Mutex;
 use tokio_stream::StreamExt;
 use tracing::{debug, info, instrument, trace, warn, Level};
 use uuid::Uuid;
-use crate::transport::ctl_message::ControlMessageType;
 
+const UART_SERVICE_UUID: Uuid = Uuid::from_u128(0x6e400001_b5a3_f393_e0a9_e50e24dcca9e);
 const TX_CHARACTERISTIC_UUID: Uuid = Uuid::from_u128(0x6e400002_b5a3_f393_e0a9_e50e24dcca9e);
 const RX_CHARACTERISTIC_UUID: Uuid = Uuid::from_u128(0x6e400003_b5a3_f393_e0a9_e50e24dcca9e);
 const CTL_CHARACTERISTIC_UUID: Uuid = Uuid::from_u128(0x6e400004_b5a3_f393_e0a9_e50e24dcca9e);
 
+const DEVICE_INFORMATION_SERVICE_UUID: Uuid =
+    Uuid::from_u128(0x0000180a_0000_1000_8000_00805f9b34fb);
 const FIRMWARE_REVISION_CHARACTERISTIC_UUID: Uuid =
     Uuid::from_u128(0x00002a26_0000_1000_8000_00805f9b34fb);
 const MANUFACTURER_NAME_CHARACTERISTIC_UUID: Uuid =
@@ -37,13 +49,437 @@ const HARDWARE_REVISION_CHARACTERISTIC_UUID: Uuid =
 const SERIAL_NUMBER_CHARACTERISTIC_UUID: Uuid =
     Uuid::from_u128(0x00002a25_0000_1000_8000_00805f9b34fb);
 
+const BATTERY_SERVICE_UUID: Uuid = Uuid::from_u128(0x0000180f_0000_1000_8000_00805f9b34fb);
 const BATTERY_LEVEL_CHARACTERISTIC_UUID: Uuid =
     Uuid::from_u128(0x00002a19_0000_1000_8000_00805f9b34fb);
 
+/// The names of the GATT services and characteristics seen on the XOSS devices
+const GATT_NAMES: &[(Uuid, &str)] = &[
+    (UART_SERVICE_UUID, "Nordic UART"),
+    (TX_CHARACTERISTIC_UUID, "UART TX (file data to the device)"),
+    (
+        RX_CHARACTERISTIC_UUID,
+        "UART RX (file data from the device)",
+    ),
+    (CTL_CHARACTERISTIC_UUID, "Control messages"),
+    (DEVICE_INFORMATION_SERVICE_UUID, "Device Information"),
+    (FIRMWARE_REVISION_CHARACTERISTIC_UUID, "Firmware Revision"),
+    (MANUFACTURER_NAME_CHARACTERISTIC_UUID, "Manufacturer Name"),
+    (MODEL_NUMBER_CHARACTERISTIC_UUID, "Model Number"),
+    (HARDWARE_REVISION_CHARACTERISTIC_UUID, "Hardware Revision"),
+    (SERIAL_NUMBER_CHARACTERISTIC_UUID, "Serial Number"),
+    (
+        Uuid::from_u128(0x00002a28_0000_1000_8000_00805f9b34fb),
+        "Software Revision",
+    ),
+    (
+        Uuid::from_u128(0x00002a23_0000_1000_8000_00805f9b34fb),
+        "System ID",
+    ),
+    (BATTERY_SERVICE_UUID, "Battery"),
+    (BATTERY_LEVEL_CHARACTERISTIC_UUID, "Battery Level"),
+    (
+        Uuid::from_u128(0x00001800_0000_1000_8000_00805f9b34fb),
+        "Generic Access",
+    ),
+    (
+        Uuid::from_u128(0x00002a00_0000_1000_8000_00805f9b34fb),
+        "Device Name",
+    ),
+    (
+        Uuid::from_u128(0x00002a01_0000_1000_8000_00805f9b34fb),
+        "Appearance",
+    ),
+    (
+        Uuid::from_u128(0x00002a04_0000_1000_8000_00805f9b34fb),
+        "Peripheral Preferred Connection Parameters",
+    ),
+    (
+        Uuid::from_u128(0x00001801_0000_1000_8000_00805f9b34fb),
+        "Generic Attribute",
+    ),
+    (
+        Uuid::from_u128(0x00002a05_0000_1000_8000_00805f9b34fb),
+        "Service Changed",
+    ),
+    (
+        Uuid::from_u128(0x0000fe59_0000_1000_8000_00805f9b34fb),
+        "Nordic Secure DFU",
+    ),
+];
+
+/// The name of a GATT service or characteristic, for the ones seen on the XOSS devices
+pub fn gatt_name(uuid: Uuid) -> Option<&'static str> {
+    GATT_NAMES
+        .iter()
+        .find(|(known, _)| *known == uuid)
+        .map(|(_, name)| *name)
+}
+
+/// The characteristics the transport uses, as a XOSS device has them
+///
+/// For the simulated devices, which have no GATT of their own
+#[cfg(feature = "mock")]
+pub(crate) fn xoss_characteristics() -> BTreeSet<Characteristic> {
+    let characteristic = |service_uuid, uuid, properties| Characteristic {
+        uuid,
+        service_uuid,
+        properties,
+    };
+    BTreeSet::from([
+        characteristic(
+            UART_SERVICE_UUID,
+            TX_CHARACTERISTIC_UUID,
+            CharPropFlags::WRITE | CharPropFlags::WRITE_WITHOUT_RESPONSE,
+        ),
+        characteristic(
+            UART_SERVICE_UUID,
+            RX_CHARACTERISTIC_UUID,
+            CharPropFlags::NOTIFY,
+        ),
+        characteristic(
+            UART_SERVICE_UUID,
+            CTL_CHARACTERISTIC_UUID,
+            CharPropFlags::WRITE | CharPropFlags::NOTIFY,
+        ),
+        characteristic(
+            DEVICE_INFORMATION_SERVICE_UUID,
+            FIRMWARE_REVISION_CHARACTERISTIC_UUID,
+            CharPropFlags::READ,
+        ),
+        characteristic(
+            DEVICE_INFORMATION_SERVICE_UUID,
+            MANUFACTURER_NAME_CHARACTERISTIC_UUID,
+            CharPropFlags::READ,
+        ),
+        characteristic(
+            DEVICE_INFORMATION_SERVICE_UUID,
+            MODEL_NUMBER_CHARACTERISTIC_UUID,
+            CharPropFlags::READ,
+        ),
+        characteristic(
+            DEVICE_INFORMATION_SERVICE_UUID,
+            HARDWARE_REVISION_CHARACTERISTIC_UUID,
+            CharPropFlags::READ,
+        ),
+        characteristic(
+            DEVICE_INFORMATION_SERVICE_UUID,
+            SERIAL_NUMBER_CHARACTERISTIC_UUID,
+            CharPropFlags::READ,
+        ),
+        characteristic(
+            BATTERY_SERVICE_UUID,
+            BATTERY_LEVEL_CHARACTERISTIC_UUID,
+            CharPropFlags::READ | CharPropFlags::NOTIFY,
+        ),
+    ])
+}
+
+/// The connection to the device, either a real BLE one or a simulated one
+#[async_trait]
+pub(crate) trait Link: Send + Sync {
+    /// Write to the control characteristic
+    async fn write_ctl(&self, data: &[u8]) -> Result<()>;
+    /// Write a packet to the UART TX characteristic
+    async fn write_uart(&self, data: &[u8]) -> Result<()>;
+    /// The largest packet [Link::write_uart] can send
+    fn uart_packet_size(&self) -> usize;
+    /// Whether several [Link::write_uart] calls may be in flight at once
+    ///
+    /// A failed write can make it change, once the link switches to another write type.
+    fn pipelines_uart_writes(&self) -> bool {
+        true
+    }
+    async fn link_quality(&self) -> LinkQuality;
+    async fn disconnect(&self) -> Result<()>;
+}
+
+/// How good the connection to the device is, as far as the BLE stack tells
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LinkQuality {
+    /// The received signal strength, in dBm
+    pub rssi: Option<i16>,
+    /// The interval of the connection events, few BLE stacks tell it
+    pub connection_interval: Option<Duration>,
+    /// The negotiated ATT MTU
+    pub mtu: Option<usize>,
+}
+
+impl LinkQuality {
+    pub fn signal(&self) -> Option<SignalStrength> {
+        self.rssi.map(SignalStrength::from_rssi)
+    }
+}
+
+impl Display for LinkQuality {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match (self.rssi, self.signal()) {
+            (Some(rssi), Some(signal)) => write!(f, "{} dBm ({})", rssi, signal)?,
+            _ => write!(f, "unknown signal")?,
+        }
+        if let Some(interval) = self.connection_interval {
+            write!(f, ", {:.1} ms interval", interval.as_secs_f64() * 1000.0)?;
+        }
+        Ok(())
+    }
+}
+
+/// A rough rating of the signal strength, for the users who don't think in dBm
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SignalStrength {
+    /// The transfers are likely to be slow and to fail
+    Poor,
+    Fair,
+    Good,
+    Excellent,
+}
+
+impl SignalStrength {
+    pub fn from_rssi(rssi: i16) -> Self {
+        match rssi {
+            -60.. => Self::Excellent,
+            -70..=-61 => Self::Good,
+            -80..=-71 => Self::Fair,
+            _ => Self::Poor,
+        }
+    }
+}
+
+impl Display for SignalStrength {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Poor => "poor",
+            Self::Fair => "fair",
+            Self::Good => "good",
+            Self::Excellent => "excellent",
+        })
+    }
+}
+
+/// The UART packet size when the MTU is not known, the one the device negotiates with the phones (209 bytes MTU)
+pub(crate) const DEFAULT_UART_PACKET_SIZE: usize = 206;
+
+/// The ATT header taking a part of each packet
+const ATT_HEADER_SIZE: usize = 3;
+
+/// How the file data is written to the device
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UartWriteType {
+    /// Without response if the characteristic allows it, switching to the writes with response if they fail
+    #[default]
+    Auto,
+    /// Each packet waits for the device to acknowledge it, slow but works with any adapter
+    WithResponse,
+    /// The packets are sent without waiting, the fastest
+    WithoutResponse,
+}
+
+/// Where the battery level is read from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BatterySource {
+    /// The Battery Level characteristic of the standard Battery Service, which the XOSS devices have
+    BatteryService,
+    /// A vendor characteristic with the level in percent as its single byte, like the standard one
+    Characteristic(Uuid),
+    /// A control message of this type, answered with the level in percent as the first byte of the body
+    ///
+    /// The device doesn't tell when the level changes, it's queried again with [XossTransport::refresh_battery_level].
+    Ctl(u8),
+}
+
+impl Display for BatterySource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BatterySource::BatteryService => write!(f, "Battery Service"),
+            BatterySource::Characteristic(uuid) => write!(f, "characteristic {}", uuid),
+            BatterySource::Ctl(message_type) => write!(f, "control message {:#04x}", message_type),
+        }
+    }
+}
+
+async fn read_battery_level(
+    device: &dyn BlePeripheral,
+    characteristic: &Characteristic,
+) -> Result<u32> {
+    device
+        .subscribe(characteristic)
+        .await
+        .context("Failed to subscribe to the battery level characteristic")?;
+    let value = device
+        .read(characteristic)
+        .await
+        .context("Failed to read battery level")?;
+    trace!(
+        "GATT read {} (battery level): {}",
+        characteristic.uuid,
+        hex::encode(&value)
+    );
+    characteristic_battery_level(&value)
+        .with_context(|| format!("Malformed battery level: {}", hex::encode(&value)))
+}
+
+/// The battery level in a characteristic value or notification
+fn characteristic_battery_level(value: &[u8]) -> Option<u32> {
+    match value {
+        &[level] => Some(level as u32),
+        _ => None,
+    }
+}
+
+/// The battery level in the reply to a [BatterySource::Ctl] query
+fn ctl_battery_level(reply: &[u8]) -> Result<u32> {
+    // the type of the reply doesn't have to be a known one, only the errors are told apart
+    if let Ok(message) = RawControlMessage::read(reply) {
+        message.into_result().context("Error response")?;
+    }
+    match reply {
+        [_, level, .., checksum] if calc_checksum(&reply[..reply.len() - 1]) == *checksum => {
+            Ok(*level as u32)
+        }
+        _ => bail!("Malformed battery level reply: {}", hex::encode(reply)),
+    }
+}
+
+struct BleLink {
+    device: Box<dyn BlePeripheral>,
+    ctl_characteristic: Characteristic,
+    tx_characteristic: Characteristic,
+    uart_packet_size: usize,
+    /// Set when the UART writes are done with response
+    with_response: AtomicBool,
+    /// Whether to switch to the writes with response when a write without one fails
+    fall_back: bool,
+}
+
+impl BleLink {
+    fn new(
+        device: Box<dyn BlePeripheral>,
+        ctl_characteristic: Characteristic,
+        tx_characteristic: Characteristic,
+        options: &TransportOptions,
+    ) -> Self {
+        let mtu = device.mtu();
+        let uart_packet_size = options
+            .uart_packet_size
+            .or(mtu.map(|mtu| mtu.saturating_sub(ATT_HEADER_SIZE)))
+            .unwrap_or(DEFAULT_UART_PACKET_SIZE)
+            .max(1);
+
+        let without_response_supported = tx_characteristic
+            .properties
+            .contains(CharPropFlags::WRITE_WITHOUT_RESPONSE);
+        let with_response = match options.uart_write_type {
+            UartWriteType::Auto => !without_response_supported,
+            UartWriteType::WithResponse => true,
+            UartWriteType::WithoutResponse => false,
+        };
+
+        info!(
+            "UART packets: {} bytes (MTU {}), written {}",
+            uart_packet_size,
+            mtu.map_or_else(|| "unknown".to_string(), |mtu| mtu.to_string()),
+            if with_response {
+                "with response"
+            } else {
+                "without response"
+            }
+        );
+
+        Self {
+            device,
+            ctl_characteristic,
+            tx_characteristic,
+            uart_packet_size,
+            with_response: AtomicBool::new(with_response),
+            fall_back: options.uart_write_type == UartWriteType::Auto && !with_response,
+        }
+    }
+}
+
+#[async_trait]
+impl Link for BleLink {
+    async fn write_ctl(&self, data: &[u8]) -> Result<()> {
+        self.device
+            .write(&self.ctl_characteristic, data, WriteType::WithResponse)
+            .await
+            .context("Failed to send control message")
+    }
+
+    async fn write_uart(&self, data: &[u8]) -> Result<()> {
+        let write_type = if self.with_response.load(Ordering::Relaxed) {
+            WriteType::WithResponse
+        } else {
+            WriteType::WithoutResponse
+        };
+
+        let result = self
+            .device
+            .write(&self.tx_characteristic, data, write_type)
+            .await;
+        if let Err(e) = &result {
+            // the packet is not written again here: the writes issued after it may already be on their way
+            if write_type == WriteType::WithoutResponse && self.fall_back {
+                warn!(
+                    "A UART write without response failed ({:#}), writing with response from now on",
+                    e
+                );
+                self.with_response.store(true, Ordering::Relaxed);
+            }
+        }
+        result.context("Failed to write to the UART")
+    }
+
+    fn uart_packet_size(&self) -> usize {
+        self.uart_packet_size
+    }
+
+    /// BlueZ rejects a write with response while another one to the same characteristic is in progress
+    fn pipelines_uart_writes(&self) -> bool {
+        !self.with_response.load(Ordering::Relaxed)
+    }
+
+    async fn link_quality(&self) -> LinkQuality {
+        let rssi = self.device.rssi().await.unwrap_or_else(|e| {
+            debug!("Failed to get the RSSI: {:#}", e);
+            None
+        });
+        LinkQuality {
+            rssi,
+            connection_interval: self.device.connection_interval(),
+            mtu: self.device.mtu(),
+        }
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        self.device.disconnect().await
+    }
+}
+
+/// The channels the notifications from the device are pumped into
+pub(crate) struct Notifications {
+    pub ctl_recv: Receiver<Vec<u8>>,
+    pub rx_recv: Receiver<Vec<u8>>,
+    /// Stops the task pumping the notifications when the transport is dropped
+    pub abort_handle: AbortHandle,
+}
+
+/// The battery level of the device and where it comes from
+pub(crate) struct Battery {
+    /// `None` while the level is unknown
+    pub level: Arc<watch::Sender<Option<u32>>>,
+    pub source: Option<BatterySource>,
+}
+
 struct Shared {
-    device: Peripheral,
+    link: Box<dyn Link>,
+    transcript: std::sync::Mutex<Option<Transcript>>,
     device_information: DeviceInformation,
-    battery_level: Arc<AtomicU32>,
+    characteristics: BTreeSet<Characteristic>,
+    battery: Battery,
+    deviations: Arc<DeviationPolicy>,
+    stats: Arc<StatsCounters>,
     #[allow(unused)] // yeah lol, it's used to keep the event pump task alive
     abort_handle: AbortHandle,
 }
@@ -53,8 +489,24 @@ struct Inner {
     uart_channel: UartChannel,
 }
 
+/// Tells the quality of the connection, also while a transfer is running
+#[derive(Clone)]
+pub struct LinkMonitor(Arc<Shared>);
+
+impl LinkMonitor {
+    pub async fn link_quality(&self) -> LinkQuality {
+        self.0.link.link_quality().await
+    }
+
+    /// The communication problems since the connection was made, see [XossTransport::stats]
+    pub fn stats(&self) -> TransportStats {
+        self.0.stats.snapshot()
+    }
+}
+
 pub struct XossTransport {
     shared: Arc<Shared>,
+    options: TransportOptions,
     inner: Mutex<Inner>,
 }
 
@@ -67,12 +519,54 @@ pub struct DeviceInformation {
     pub serial_number: String,
 }
 
-const NORMAL_RESPONSE_TIMEOUT: Duration = Duration::from_secs(1);
-const FILE_RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Timeouts of the communication with the device
+///
+/// The defaults work for most adapters, slow adapters and congested radio environments may need longer ones.
+#[derive(Debug, Clone)]
+pub struct TransportOptions {
+    /// How long to wait for a reply to a control message
+    pub ctl_response_timeout: Duration,
+    /// How long to wait for the device to process a transferred file
+    pub file_response_timeout: Duration,
+    /// How long to wait for each packet of a file transfer
+    pub uart_timeout: Duration,
+    /// The size of the file data packets, instead of the one derived from the MTU
+    pub uart_packet_size: Option<usize>,
+    pub uart_write_type: UartWriteType,
+    /// Where to read the battery level from when the device has no standard Battery Service, like some of the clones
+    ///
+    /// Without it (or when it doesn't work either) the battery level is unknown.
+    pub battery_fallback: Option<BatterySource>,
+}
+
+impl Default for TransportOptions {
+    fn default() -> Self {
+        Self {
+            ctl_response_timeout: Duration::from_secs(1),
+            file_response_timeout: Duration::from_secs(10),
+            uart_timeout: YModemOptions::default().timeout,
+            uart_packet_size: None,
+            uart_write_type: UartWriteType::default(),
+            battery_fallback: None,
+        }
+    }
+}
+
+impl TransportOptions {
+    pub fn ymodem(&self) -> YModemOptions {
+        YModemOptions {
+            timeout: self.uart_timeout,
+            ..Default::default()
+        }
+    }
+}
 
 impl XossTransport {
     #[instrument(skip(device), fields(id = %device.id()))]
-    pub async fn new(device: Peripheral) -> Result<Self> {
+    pub async fn new(
+        device: impl BlePeripheral + 'static,
+        options: TransportOptions,
+    ) -> Result<Self> {
         info!("Discovering XOSS services...");
 
         device
@@ -90,8 +584,6 @@ impl XossTransport {
         let mut hardware_revision_characteristic = None;
         let mut serial_number_characteristic = None;
 
-        let mut battery_level_characteristic = None;
-
         let mut required_characteristics = BTreeMap::from([
             (TX_CHARACTERISTIC_UUID, &mut tx_characteristic),
             (RX_CHARACTERISTIC_UUID, &mut rx_characteristic),
@@ -116,20 +608,17 @@ impl XossTransport {
                 SERIAL_NUMBER_CHARACTERISTIC_UUID,
                 &mut serial_number_characteristic,
             ),
-            (
-                BATTERY_LEVEL_CHARACTERISTIC_UUID,
-                &mut battery_level_characteristic,
-            ),
         ]);
 
-        for characteristic in device.characteristics() {
+        let characteristics = device.characteristics();
+        for characteristic in &characteristics {
             debug!(
                 "BLE characteristic {}: {} {:?}",
                 characteristic.service_uuid, characteristic.uuid, characteristic.properties
             );
 
             if let Some(c) = required_characteristics.get_mut(&characteristic.uuid) {
-                **c = Some(characteristic);
+                **c = Some(characteristic.clone());
             }
         }
 
@@ -139,12 +628,27 @@ impl XossTransport {
             }
         }
 
+        // the standard one first, some clones don't have the Battery Service
+        let find_characteristic = |uuid| characteristics.iter().find(|c| c.uuid == uuid).cloned();
+        let mut battery_level_characteristic =
+            find_characteristic(BATTERY_LEVEL_CHARACTERISTIC_UUID)
+                .map(|c| (BatterySource::BatteryService, c));
+        if battery_level_characteristic.is_none() {
+            if let Some(BatterySource::Characteristic(uuid)) = options.battery_fallback {
+                battery_level_characteristic =
+                    find_characteristic(uuid).map(|c| (BatterySource::Characteristic(uuid), c));
+            }
+        }
+        let battery_level_uuid = battery_level_characteristic.as_ref().map(|(_, c)| c.uuid);
+
         // pump messages to their respective channels
 
         let (ctl_send, ctl_recv) = tokio::sync::mpsc::channel(3);
         let (rx_send, rx_recv) = tokio::sync::mpsc::channel(3);
-        let battery_level = Arc::new(AtomicU32::new(0));
-        let battery_level_copy = battery_level.clone();
+        let battery_level_send = Arc::new(watch::channel(None).0);
+        let battery_level_send_copy = battery_level_send.clone();
+        let deviations = Arc::new(DeviationPolicy::default());
+        let deviations_copy = deviations.clone();
 
         let mut events = device
             .notifications()
@@ -163,15 +667,20 @@ impl XossTransport {
                         let _ = rx_send.send(data).await;
                     } else if characteristic == CTL_CHARACTERISTIC_UUID {
                         let data = notification.value;
-                        trace!("CTL: {}", hex::encode(&data));
+                        trace!("CTL RX: {}", hex::encode(&data));
                         // this can error out only if the recv side is closed. We have a different way to stop the loop (abort_token), so just ignore the error
                         let _ = ctl_send.send(data).await;
-                    } else if characteristic == BATTERY_LEVEL_CHARACTERISTIC_UUID {
+                    } else if Some(characteristic) == battery_level_uuid {
                         let data = notification.value;
-                        assert_eq!(data.len(), 1);
-                        let new_battery_level = data[0] as u32;
-                        trace!("Battery level: {}", new_battery_level);
-                        battery_level_copy.store(new_battery_level, Ordering::Relaxed);
+                        if let Some(new_battery_level) = characteristic_battery_level(&data) {
+                            trace!("Battery level notification: {}", new_battery_level);
+                            battery_level_send_copy.send_replace(Some(new_battery_level));
+                        } else {
+                            deviations_copy.report_background(ProtocolDeviation::new(
+                                "Ignoring a malformed battery level notification",
+                                &data,
+                            ));
+                        }
                     }
                     // for some reason we are getting notifications for these, even though we are not subscribed to them
                     else if matches!(
@@ -187,7 +696,10 @@ impl XossTransport {
                             characteristic
                         )
                     } else {
-                        warn!("Unknown notification: {:?}", notification);
+                        deviations_copy.report_background(ProtocolDeviation::new(
+                            format!("Unknown notification for characteristic {}", characteristic),
+                            &notification.value,
+                        ));
                     };
                 }
 
@@ -206,8 +718,6 @@ impl XossTransport {
         let hardware_revision_characteristic = hardware_revision_characteristic.unwrap();
         let serial_number_characteristic = serial_number_characteristic.unwrap();
 
-        let battery_level_characteristic = battery_level_characteristic.unwrap();
-
         // make sure we are subscribed to the characteristics
         device
             .subscribe(&rx_characteristic)
@@ -217,20 +727,20 @@ impl XossTransport {
             .subscribe(&ctl_characteristic)
             .await
             .context("Failed to subscribe to the CTL characteristic")?;
-        device
-            .subscribe(&battery_level_characteristic)
-            .await
-            .context("Failed to subscribe to the battery level characteristic")?;
 
         async fn read_chara_string(
-            device: &Peripheral,
+            device: &dyn BlePeripheral,
             chara: &Characteristic,
             name: &str,
         ) -> Result<String> {
             device
-                .read(&chara)
+                .read(chara)
                 .await
                 .with_context(|| format!("Failed to read {} characteristic", name))
+                .map(|s| {
+                    trace!("GATT read {} ({}): {}", chara.uuid, name, hex::encode(&s));
+                    s
+                })
                 .and_then(|s| {
                     String::from_utf8(s).with_context(|| format!("{} is not valid UTF-8", name))
                 })
@@ -265,37 +775,95 @@ impl XossTransport {
             .await?,
         };
 
-        battery_level.store(
-            device
-                .read(&battery_level_characteristic)
-                .await
-                .context("Failed to read battery level")?[0] as u32,
-            Ordering::Relaxed,
+        let mut battery_source = None;
+        if let Some((source, characteristic)) = battery_level_characteristic {
+            match read_battery_level(&device, &characteristic).await {
+                Ok(level) => {
+                    battery_level_send.send_replace(Some(level));
+                    battery_source = Some(source);
+                }
+                Err(e) => warn!("Not reading the battery level from the {}: {:#}", source, e),
+            }
+        }
+        if battery_source.is_none() {
+            if let Some(BatterySource::Ctl(message_type)) = options.battery_fallback {
+                // queried once the control channel is up
+                battery_source = Some(BatterySource::Ctl(message_type));
+            }
+        }
+
+        let link = BleLink::new(
+            Box::new(device),
+            ctl_characteristic,
+            tx_characteristic,
+            &options,
+        );
+
+        let transport = Self::from_parts(
+            Box::new(link),
+            device_information,
+            characteristics,
+            Battery {
+                level: battery_level_send,
+                source: battery_source,
+            },
+            deviations,
+            Notifications {
+                ctl_recv,
+                rx_recv,
+                abort_handle,
+            },
+            options,
         );
+        match transport.battery_source() {
+            Some(BatterySource::Ctl(_)) => {
+                if let Err(e) = transport.refresh_battery_level().await {
+                    warn!("Failed to query the battery level: {:#}", e);
+                }
+            }
+            Some(_) => {}
+            None => info!("The device doesn't report its battery level"),
+        }
+
+        Ok(transport)
+    }
+
+    pub(crate) fn from_parts(
+        link: Box<dyn Link>,
+        device_information: DeviceInformation,
+        characteristics: BTreeSet<Characteristic>,
+        battery: Battery,
+        deviations: Arc<DeviationPolicy>,
+        notifications: Notifications,
+        options: TransportOptions,
+    ) -> Self {
+        let Notifications {
+            ctl_recv,
+            rx_recv,
+            abort_handle,
+        } = notifications;
 
         let shared = Arc::new(Shared {
-            device,
+            link,
+            transcript: std::sync::Mutex::new(None),
             device_information,
-            battery_level,
+            characteristics,
+            battery,
+            deviations,
+            stats: Default::default(),
             abort_handle,
         });
 
-        let result = Self {
+        Self {
             shared: shared.clone(),
+            options,
             // mutex is needed to ensure that we receive the correct reply
             // (we don't allow sending a new command until the previous one is replied to)
             inner: Mutex::new(Inner {
-                ctl_channel: CtlChannel::new(shared.clone(), ctl_characteristic, ctl_recv),
-                uart_channel: UartChannel::new(
-                    shared,
-                    tx_characteristic,
-                    rx_characteristic,
-                    rx_recv,
-                ),
+                ctl_channel: CtlChannel::new(shared.clone(), ctl_recv),
+                uart_channel: UartChannel::new(shared, rx_recv),
             }),
-        };
-
-        Ok(result)
+        }
     }
 
     pub fn device_info(&self) -> &DeviceInformation {
@@ -303,8 +871,71 @@ impl XossTransport {
         &self.shared.device_information
     }
 
-    pub fn battery_level(&self) -> u32 {
-        self.shared.battery_level.load(Ordering::Relaxed)
+    /// The GATT characteristics of all the services, as discovered when connecting
+    pub fn characteristics(&self) -> &BTreeSet<Characteristic> {
+        &self.shared.characteristics
+    }
+
+    /// The battery level in percent, `None` if the device doesn't report it
+    pub fn battery_level(&self) -> Option<u32> {
+        *self.shared.battery.level.borrow()
+    }
+
+    /// Follow the battery level, as the device notifies about its changes
+    pub fn subscribe_battery_level(&self) -> watch::Receiver<Option<u32>> {
+        self.shared.battery.level.subscribe()
+    }
+
+    /// Where the battery level is read from, `None` if the device doesn't report it
+    pub fn battery_source(&self) -> Option<BatterySource> {
+        self.shared.battery.source
+    }
+
+    /// Query the battery level again if it's read with a control message
+    ///
+    /// The characteristics notify about the changes themselves, the level they reported last is returned as it is.
+    pub async fn refresh_battery_level(&self) -> Result<Option<u32>> {
+        if let Some(BatterySource::Ctl(message_type)) = self.shared.battery.source {
+            let reply = self.request_ctl_raw(message_type, &[]).await?;
+            let level = ctl_battery_level(&reply).context("Failed to query the battery level")?;
+            trace!("Battery level reply: {}", level);
+            self.shared.battery.level.send_replace(Some(level));
+        }
+        Ok(self.battery_level())
+    }
+
+    /// Check the link quality while the transport is busy, see [LinkMonitor]
+    pub fn link_monitor(&self) -> LinkMonitor {
+        LinkMonitor(self.shared.clone())
+    }
+
+    pub fn options(&self) -> &TransportOptions {
+        &self.options
+    }
+
+    /// The YMODEM settings of the file transfers, counting their retransmits in the [stats](XossTransport::stats)
+    pub fn ymodem_options(&self) -> YModemOptions {
+        YModemOptions {
+            stats: Some(self.shared.stats.clone()),
+            ..self.options.ymodem()
+        }
+    }
+
+    /// How many messages were lost, damaged or sent again since the connection was made
+    ///
+    /// Tells how lossy the combination of the adapter and the device is
+    pub fn stats(&self) -> TransportStats {
+        self.shared.stats.snapshot()
+    }
+
+    /// Record the control messages sent and received from now on
+    pub fn set_transcript(&self, transcript: Transcript) {
+        *self.shared.transcript.lock().unwrap() = Some(transcript);
+    }
+
+    /// How the deviations from the expected protocol are handled
+    pub fn deviations(&self) -> &Arc<DeviationPolicy> {
+        &self.shared.deviations
     }
 
     #[instrument(skip(self, buffer), ret, level = Level::DEBUG)]
@@ -314,34 +945,89 @@ impl XossTransport {
         message_type: ControlMessageType,
         body: &[u8],
     ) -> Result<RawControlMessage<'a>> {
-        let message = RawControlMessage {
-            message_type,
-            body,
-        }; 
-        
+        let message = RawControlMessage { message_type, body };
+
         let mut inner = self.inner.lock().await;
-        
+        self.shared.deviations.take_pending()?;
+
         inner
             .ctl_channel
             .send_ctl(buffer, message)
             .await
             .context("Sending control message")?;
 
+        let reply = inner
+            .ctl_channel
+            .recv_ctl(buffer, self.options.ctl_response_timeout)
+            .await
+            .context("Reading control message")?;
+        self.shared.deviations.take_pending()?;
+
+        Ok(reply)
+    }
+
+    /// Send a control message of any type and return the reply bytes as they are
+    ///
+    /// Meant for exploring the protocol: the message type doesn't have to be one of [ControlMessageType] and the
+    /// reply is not decoded, so it includes the type and the checksum bytes. The messages are not recorded in the
+    /// transcript.
+    #[instrument(skip(self), level = Level::DEBUG)]
+    pub async fn request_ctl_raw(&self, message_type: u8, body: &[u8]) -> Result<Vec<u8>> {
+        let mut message = Vec::with_capacity(body.len() + 2);
+        message.push(message_type);
+        message.extend_from_slice(body);
+        message.push(calc_checksum(&message));
+
+        let mut inner = self.inner.lock().await;
+        self.shared.deviations.take_pending()?;
+
         inner
             .ctl_channel
-            .recv_ctl(buffer, NORMAL_RESPONSE_TIMEOUT)
+            .send_ctl_bytes(&message)
+            .await
+            .context("Sending control message")?;
+
+        let reply = inner
+            .ctl_channel
+            .recv_ctl_bytes(self.options.ctl_response_timeout)
             .await
-            .context("Reading control message")
+            .context("Reading control message")?;
+        self.shared.deviations.take_pending()?;
+
+        Ok(reply)
+    }
+
+    /// Send a control message without waiting for a reply
+    ///
+    /// For the messages after which the device doesn't reply (like rebooting into DFU mode)
+    #[instrument(skip(self, buffer), level = Level::DEBUG)]
+    pub async fn send_ctl(
+        &self,
+        buffer: &mut CtlBuffer,
+        message_type: ControlMessageType,
+        body: &[u8],
+    ) -> Result<()> {
+        let message = RawControlMessage { message_type, body };
+
+        let mut inner = self.inner.lock().await;
+        self.shared.deviations.take_pending()?;
+
+        inner
+            .ctl_channel
+            .send_ctl(buffer, message)
+            .await
+            .context("Sending control message")
     }
 
     #[instrument(skip(self, buffer), ret, level = Level::DEBUG)]
     pub async fn recv_ctl<'a>(&self, buffer: &'a mut CtlBuffer) -> Result<RawControlMessage<'a>> {
         let mut inner = self.inner.lock().await;
+        self.shared.deviations.take_pending()?;
         inner
             .ctl_channel
             // This API is used to wait for device to process the file after the file transfer
             // it may take a while, hence the larger timeout
-            .recv_ctl(buffer, FILE_RESPONSE_TIMEOUT)
+            .recv_ctl(buffer, self.options.file_response_timeout)
             .await
             .context("Reading (isolated) control message")
     }
@@ -352,8 +1038,6 @@ impl XossTransport {
     }
 
     pub async fn disconnect(self) -> Result<()> {
-        self.shared.device.disconnect().await?;
-
-        Ok(())
+        self.shared.link.disconnect().await
     }
 }
@@ -1,25 +1,33 @@
 use super::Shared;
-use btleplug::api::{Characteristic, Peripheral, WriteType};
+use crate::transport::stats::StatsEvent;
+use crate::transport::ymodem::FrameAssembler;
 use bytes::Bytes;
-use futures_util::stream::Map;
-use futures_util::{ready, StreamExt};
+use futures_util::stream::{FuturesOrdered, Map};
+use futures_util::{ready, FutureExt, StreamExt};
+use std::future::Future;
 use std::io::{Cursor, ErrorKind};
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Duration;
 use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, ReadBuf};
 use tokio::select;
 use tokio::sync::mpsc::Receiver;
 use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_util::io::StreamReader;
-use tokio_util::sync::ReusableBoxFuture;
+use tokio_util::sync::PollSender;
 use tracing::{debug, trace, warn};
 
+/// How long the rest of a YModem packet may take to arrive, it's considered lost after that
+///
+/// The pieces of a packet come a connection interval or so apart, much less than a second.
+const INCOMPLETE_PACKET_TIMEOUT: Duration = Duration::from_secs(1);
+
 pub struct UartChannel {
     shared: Arc<Shared>,
     mtu: usize,
-    tx_characteristic: Characteristic,
     stream_sender: Sender<Sender<Vec<u8>>>,
 }
 
@@ -28,26 +36,32 @@ fn recv_map_fn(vec: Vec<u8>) -> std::io::Result<Cursor<Vec<u8>>> {
 }
 
 type RecvMapFnType = fn(Vec<u8>) -> std::io::Result<Cursor<Vec<u8>>>;
+type UartReader = StreamReader<Map<ReceiverStream<Vec<u8>>, RecvMapFnType>, Cursor<Vec<u8>>>;
 
 impl UartChannel {
-    pub(super) fn new(
-        shared: Arc<Shared>,
-        tx_characteristic: Characteristic,
-        _rx_characteristic: Characteristic,
-        mut rx_recv: Receiver<Vec<u8>>,
-    ) -> Self {
+    pub(super) fn new(shared: Arc<Shared>, mut rx_recv: Receiver<Vec<u8>>) -> Self {
         let (stream_sender, mut stream_reader) = tokio::sync::mpsc::channel::<Sender<Vec<u8>>>(1);
 
+        let stats = shared.stats.clone();
         // spawn a task managing the streams
         tokio::spawn(async move {
             let mut current_stream = None;
+            // the notifications are passed on to the streams as whole YModem packets
+            let mut frames = FrameAssembler::default();
 
             loop {
                 select! {
+                    // a stream is opened before the transfer is requested, so it has to be in place before the
+                    // reply data is handled, otherwise the first bytes go to the previous stream
+                    biased;
                     new_stream = stream_reader.recv() => {
                         match new_stream {
                             Some(new_stream) => {
                                 debug!("A new stream has been opened");
+                                let dropped = frames.drop_incomplete();
+                                if dropped > 0 {
+                                    debug!("Dropping {} bytes of a packet sent to the previous stream", dropped);
+                                }
                                 current_stream = Some(new_stream);
                             },
                             None => {
@@ -57,26 +71,35 @@ impl UartChannel {
                         }
                     }
                     data = rx_recv.recv() => {
+                        let Some(data) = data else {
+                            // a closed channel stays ready, polling it again would spin
+                            debug!("The rx channel has been closed, stopping the stream manager task");
+                            break;
+                        };
+                        stats.count(StatsEvent::UartNotification);
                         if let Some(stream) = &current_stream {
-                            if let Some(data) = data {
-                                if stream.send(data).await.is_err() {
-                                    debug!("The receiving end of the stream has been dropped, considering it closed");
-                                    current_stream = None;
-                                }
+                            let ready = frames.push(&data);
+                            if !ready.is_empty() && stream.send(ready).await.is_err() {
+                                debug!("The receiving end of the stream has been dropped, considering it closed");
+                                current_stream = None;
                             }
                         } else {
+                            stats.count(StatsEvent::UartDropped);
                             warn!("Received data but no stream is open, dropping it");
                         }
                     }
+                    _ = tokio::time::sleep(INCOMPLETE_PACKET_TIMEOUT), if frames.is_incomplete() => {
+                        let dropped = frames.drop_incomplete();
+                        stats.count(StatsEvent::DamagedPacket);
+                        warn!("The rest of a YModem packet didn't come, dropping the {} bytes received", dropped);
+                    }
                 }
             }
         });
 
         Self {
+            mtu: shared.link.uart_packet_size(),
             shared,
-            // FIXME: actually use MTU from the BT stack when it's implemented in btleplug or find a better way idk
-            mtu: 206,
-            tx_characteristic,
             stream_sender,
         }
     }
@@ -92,58 +115,191 @@ impl UartChannel {
         let receiver = ReceiverStream::new(receiver).map(recv_map_fn as RecvMapFnType);
         let reader = StreamReader::new(receiver);
 
+        let (writer, queue) = tokio::sync::mpsc::channel(1);
+        tokio::spawn(run_writer(self.shared.clone(), queue));
+
         UartStream {
-            shared: self.shared.clone(),
             mtu: self.mtu,
-            tx_characteristic: self.tx_characteristic.clone(),
             reader,
-            write_finished: true,
-            write_box_future: ReusableBoxFuture::new(async move { Ok(()) }),
+            writer: PollSender::new(writer),
+            unflushed: false,
+            flush_reply: None,
         }
     }
 }
 
-// pin_project! {
-pub struct UartStream {
+/// How many UART packets may be written at once
+///
+/// The writes are started in order, and the BLE stack sends them in the order they were issued, so waiting for each
+/// packet to be written before starting the next one only adds the round trips to the stack to each packet.
+/// Only the writes without response are pipelined, see [Link::pipelines_uart_writes](super::Link).
+const MAX_WRITES_IN_FLIGHT: usize = 8;
+
+enum UartWrite {
+    Packet(Bytes),
+    /// Reply once the packets queued before are written, with the error of any of them
+    Flush(oneshot::Sender<Result<(), String>>),
+}
+
+/// A packet being written, with the outcome of the write
+struct Written {
+    packet: Bytes,
+    /// Whether the write was issued while the link pipelined the writes
+    pipelined: bool,
+    result: anyhow::Result<()>,
+}
+
+/// Writes the queued packets, keeping up to [MAX_WRITES_IN_FLIGHT] of them in flight
+struct Writer {
     shared: Arc<Shared>,
+    in_flight: FuturesOrdered<Pin<Box<dyn Future<Output = Written> + Send>>>,
+    /// The packets after a failed one are dropped until the failure is reported by a flush
+    error: Option<anyhow::Error>,
+}
+
+impl Writer {
+    fn max_in_flight(&self) -> usize {
+        if self.shared.link.pipelines_uart_writes() {
+            MAX_WRITES_IN_FLIGHT
+        } else {
+            1
+        }
+    }
+
+    fn issue(&mut self, packet: Bytes) {
+        let shared = self.shared.clone();
+        let pipelined = shared.link.pipelines_uart_writes();
+        self.in_flight.push_back(Box::pin(async move {
+            trace!("TX: {}", hex::encode(&packet));
+            let result = shared.link.write_uart(&packet).await;
+            Written {
+                packet,
+                pipelined,
+                result,
+            }
+        }));
+        // start the write right away: a write failing at once switches the link from pipelining before the next
+        // packet is issued
+        if let Some(Some(written)) = self.in_flight.next().now_or_never() {
+            self.settle(written);
+        }
+    }
+
+    fn settle(&mut self, written: Written) {
+        let Err(e) = written.result else {
+            return;
+        };
+        if self.error.is_some() {
+            return;
+        }
+        // the link has switched to the writes with response: the packet can be written again as long as nothing
+        // was issued after it, otherwise it would arrive after the later packets
+        if written.pipelined
+            && !self.shared.link.pipelines_uart_writes()
+            && self.in_flight.is_empty()
+        {
+            debug!("Writing the failed UART packet again: {:#}", e);
+            self.issue(written.packet);
+            return;
+        }
+        self.error = Some(e);
+    }
+
+    async fn write(&mut self, packet: Bytes) {
+        if self.error.is_some() {
+            return;
+        }
+        // the link may have stopped pipelining since the packet was let in
+        while self.in_flight.len() >= self.max_in_flight() {
+            let Some(written) = self.in_flight.next().await else {
+                break;
+            };
+            self.settle(written);
+        }
+        if self.error.is_none() {
+            self.issue(packet);
+        }
+    }
+
+    async fn flush(&mut self) -> Result<(), String> {
+        while let Some(written) = self.in_flight.next().await {
+            self.settle(written);
+        }
+        match self.error.take() {
+            None => Ok(()),
+            Some(e) => {
+                debug!("Error while writing to the UART: {:?}", e);
+                Err(format!("{:#}", e))
+            }
+        }
+    }
+}
+
+async fn run_writer(shared: Arc<Shared>, mut queue: Receiver<UartWrite>) {
+    let mut writer = Writer {
+        shared,
+        in_flight: FuturesOrdered::new(),
+        error: None,
+    };
+
+    loop {
+        select! {
+            biased;
+            Some(written) = writer.in_flight.next(), if !writer.in_flight.is_empty() => {
+                writer.settle(written);
+            }
+            write = queue.recv(), if writer.in_flight.len() < writer.max_in_flight() => match write {
+                Some(UartWrite::Packet(packet)) => writer.write(packet).await,
+                Some(UartWrite::Flush(reply)) => {
+                    let _ = reply.send(writer.flush().await);
+                }
+                None => break,
+            }
+        }
+    }
+
+    // the stream is closed, but the packets already queued still have to be sent
+    let _ = writer.flush().await;
+}
+
+fn writer_stopped<T>(_: T) -> std::io::Error {
+    std::io::Error::new(ErrorKind::BrokenPipe, "The UART writer task has stopped")
+}
+
+pub struct UartStream {
     mtu: usize,
-    tx_characteristic: Characteristic,
-    // #[pin]
-    reader: StreamReader<Map<ReceiverStream<Vec<u8>>, RecvMapFnType>, Cursor<Vec<u8>>>,
-    write_finished: bool,
-    write_box_future: ReusableBoxFuture<'static, btleplug::Result<()>>,
-    // #[pin]
-    // writer: SinkWriter<
-    //     CopyToBytes<
-    //         SinkMapErr<
-    //             PollSender<
-    //                 Bytes
-    //             >,
-    //             SendMapErrFnType,
-    //         >
-    //     >
-    // >,
+    reader: UartReader,
+    writer: PollSender<UartWrite>,
+    /// Whether packets were queued since the last flush
+    unflushed: bool,
+    flush_reply: Option<oneshot::Receiver<Result<(), String>>>,
 }
-// }
 
 impl UartStream {
+    /// Wait for the queued packets to be written
     fn poll_write_ready(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
-        // let mut proj = self.project();
-
-        if !self.write_finished {
-            match self.write_box_future.poll(cx) {
-                Poll::Pending => return Poll::Pending,
-                Poll::Ready(Err(e)) => {
-                    debug!("Error while writing to the UART: {:?}", e);
-                    return Poll::Ready(Err(std::io::Error::new(ErrorKind::BrokenPipe, e)));
-                }
-                Poll::Ready(Ok(())) => {
-                    self.write_finished = true;
-                }
+        loop {
+            if let Some(flush_reply) = &mut self.flush_reply {
+                let result = ready!(Pin::new(flush_reply).poll(cx));
+                self.flush_reply = None;
+                return Poll::Ready(match result {
+                    Ok(Ok(())) => Ok(()),
+                    Ok(Err(e)) => Err(std::io::Error::new(ErrorKind::BrokenPipe, e)),
+                    Err(e) => Err(writer_stopped(e)),
+                });
+            }
+            if !self.unflushed {
+                return Poll::Ready(Ok(()));
             }
-        }
 
-        Poll::Ready(Ok(()))
+            ready!(self.writer.poll_reserve(cx)).map_err(writer_stopped)?;
+            let (reply, flush_reply) = oneshot::channel();
+            self.writer
+                .send_item(UartWrite::Flush(reply))
+                .map_err(writer_stopped)?;
+            self.unflushed = false;
+            self.flush_reply = Some(flush_reply);
+        }
     }
 }
 
@@ -185,26 +341,14 @@ impl AsyncWrite for UartStream {
     ) -> Poll<std::io::Result<usize>> {
         let this = Pin::into_inner(self);
 
-        ready!(this.poll_write_ready(cx)?);
+        // the queue being full is what holds the writer back
+        ready!(this.writer.poll_reserve(cx)).map_err(writer_stopped)?;
 
         let buf_len = std::cmp::min(buf.len(), this.mtu);
-        let buf = &buf[..buf_len];
-
-        // FIXME: cloning is bad!
-        let shared = this.shared.clone();
-        let buf = Bytes::copy_from_slice(buf);
-        let tx_characteristic = this.tx_characteristic.clone();
-
-        let fut = async move {
-            trace!("TX: {}", hex::encode(&buf));
-            shared
-                .device
-                .write(&tx_characteristic, &buf, WriteType::WithoutResponse)
-                .await
-        };
-
-        this.write_box_future.set(fut);
-        this.write_finished = false;
+        this.writer
+            .send_item(UartWrite::Packet(Bytes::copy_from_slice(&buf[..buf_len])))
+            .map_err(writer_stopped)?;
+        this.unflushed = true;
 
         Poll::Ready(Ok(buf_len))
     }
@@ -0,0 +1,441 @@
+//! A simulated device, to use the library (and the apps built on it) without the hardware
+//!
+//! [MockDevice] keeps the files in memory and answers the control messages the way the real firmware does,
+//! transferring the files over the same YModem implementation as the real transport. It knows nothing about
+//! the meaning of the files: the JSON configs are stored as they are sent, and the workouts are only there if they were put there.
+//!
+//! The failures of the real device can be simulated with [MockDevice::inject_fault].
+//!
+//! Only available with the `mock` feature.
+
+use crate::device::ActivityStatus;
+use crate::mga::{parse_mga_data, CorruptFrames};
+use crate::progress::NoProgress;
+use crate::transport::ctl_message::{ControlMessageType, RawControlMessage};
+use crate::transport::deviation::DeviationPolicy;
+use crate::transport::device::{
+    xoss_characteristics, Battery, Link, Notifications, DEFAULT_UART_PACKET_SIZE,
+};
+use crate::transport::ymodem;
+use crate::transport::{
+    BatterySource, CtlBuffer, DeviceInformation, LinkQuality, TransportOptions, XossTransport,
+};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use chrono::NaiveTime;
+use futures_util::future::{AbortHandle, Abortable};
+use futures_util::TryStreamExt;
+use std::collections::BTreeMap;
+use std::io::Cursor;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream, WriteHalf};
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::watch;
+use tracing::{debug, trace, warn};
+
+/// The size of the UART notifications, same as the real device sends
+const UART_CHUNK_SIZE: usize = 200;
+
+/// A misbehavior of the simulated device, see [MockDevice::inject_fault]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Reply with a message of this type (echoing the request body) instead of handling the request
+    Reply(ControlMessageType),
+    /// Don't reply at all, as if the request was lost
+    Ignore,
+}
+
+/// The state of a simulated device, shared by all the connections to it
+#[derive(Clone)]
+pub struct MockDevice {
+    info: DeviceInformation,
+    debug_id: [u8; 8],
+    activity_status: Arc<Mutex<ActivityStatus>>,
+    battery_level: Arc<watch::Sender<Option<u32>>>,
+    rssi: Arc<Mutex<Option<i16>>>,
+    total_kb: u32,
+    files: Arc<Mutex<BTreeMap<String, Vec<u8>>>>,
+    faults: Arc<Mutex<Vec<(ControlMessageType, Fault)>>>,
+}
+
+impl MockDevice {
+    pub fn new(info: DeviceInformation) -> Self {
+        // stable for the same serial number, like the identifier of a real unit
+        let mut debug_id = [0; 8];
+        for (i, b) in info.serial_number.bytes().rev().enumerate() {
+            debug_id[7 - i % 8] ^= b;
+        }
+
+        Self {
+            info,
+            debug_id,
+            activity_status: Arc::new(Mutex::new(ActivityStatus::Idle)),
+            battery_level: Arc::new(watch::channel(Some(100)).0),
+            rssi: Arc::new(Mutex::new(Some(-62))),
+            total_kb: 8 * 1024,
+            files: Default::default(),
+            faults: Default::default(),
+        }
+    }
+
+    /// Change the battery level, notifying the connected hosts about it
+    pub fn set_battery_level(&self, level: u32) {
+        self.battery_level.send_replace(Some(level));
+    }
+
+    /// Change the signal strength the hosts see, `None` for a BLE stack that doesn't tell it
+    pub fn set_rssi(&self, rssi: Option<i16>) {
+        *self.rssi.lock().unwrap() = rssi;
+    }
+
+    /// Start or stop recording a workout, as reported by [ControlMessageType::StatusAct]
+    pub fn set_activity_status(&self, status: ActivityStatus) {
+        *self.activity_status.lock().unwrap() = status;
+    }
+
+    pub fn set_file(&self, filename: impl Into<String>, content: impl Into<Vec<u8>>) {
+        self.files
+            .lock()
+            .unwrap()
+            .insert(filename.into(), content.into());
+    }
+
+    pub fn file(&self, filename: &str) -> Option<Vec<u8>> {
+        self.files.lock().unwrap().get(filename).cloned()
+    }
+
+    /// All the files on the device, sorted by name
+    pub fn files(&self) -> BTreeMap<String, Vec<u8>> {
+        self.files.lock().unwrap().clone()
+    }
+
+    pub fn remove_file(&self, filename: &str) -> Option<Vec<u8>> {
+        self.files.lock().unwrap().remove(filename)
+    }
+
+    /// Make the device misbehave when it receives the next request of this type
+    ///
+    /// The faults for the same request type are used in the order they were injected, each only once.
+    pub fn inject_fault(&self, request: ControlMessageType, fault: Fault) {
+        self.faults.lock().unwrap().push((request, fault));
+    }
+
+    fn take_fault(&self, request: ControlMessageType) -> Option<Fault> {
+        let mut faults = self.faults.lock().unwrap();
+        let index = faults.iter().position(|(r, _)| *r == request)?;
+        Some(faults.remove(index).1)
+    }
+
+    fn free_kb(&self) -> u32 {
+        let used: usize = self.files.lock().unwrap().values().map(|f| f.len()).sum();
+        self.total_kb.saturating_sub(((used + 1023) / 1024) as u32)
+    }
+
+    /// The expiration time of the stored A-GNSS data as reported by [ControlMessageType::ReturnMga], 0 if there's none
+    fn mga_valid_until(&self) -> u32 {
+        self.file("offline.gnss")
+            .and_then(|data| parse_mga_data(data, CorruptFrames::Skip).ok())
+            .map(|mga| {
+                mga.valid_until
+                    .and_time(NaiveTime::MIN)
+                    .timestamp()
+                    .try_into()
+                    .unwrap_or(0)
+            })
+            .unwrap_or(0)
+    }
+}
+
+struct MockLink {
+    ctl_send: Sender<Vec<u8>>,
+    uart: tokio::sync::Mutex<WriteHalf<DuplexStream>>,
+    uart_packet_size: usize,
+    rssi: Arc<Mutex<Option<i16>>>,
+}
+
+#[async_trait]
+impl Link for MockLink {
+    async fn write_ctl(&self, data: &[u8]) -> Result<()> {
+        self.ctl_send
+            .send(data.to_vec())
+            .await
+            .ok()
+            .context("The simulated device has stopped")
+    }
+
+    async fn write_uart(&self, data: &[u8]) -> Result<()> {
+        let mut uart = self.uart.lock().await;
+        uart.write_all(data)
+            .await
+            .context("The simulated device has stopped")
+    }
+
+    fn uart_packet_size(&self) -> usize {
+        self.uart_packet_size
+    }
+
+    async fn link_quality(&self) -> LinkQuality {
+        LinkQuality {
+            rssi: *self.rssi.lock().unwrap(),
+            ..Default::default()
+        }
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl XossTransport {
+    /// Connect to a simulated device
+    pub fn mock(device: &MockDevice, options: TransportOptions) -> Self {
+        let (host_ctl_send, device_ctl_recv) = tokio::sync::mpsc::channel(3);
+        let (device_ctl_send, host_ctl_recv) = tokio::sync::mpsc::channel(3);
+        let (rx_send, rx_recv) = tokio::sync::mpsc::channel(3);
+
+        let (host_io, device_io) = tokio::io::duplex(4096);
+        let (mut host_read, host_write) = tokio::io::split(host_io);
+
+        let server = Server {
+            device: device.clone(),
+            ctl_recv: device_ctl_recv,
+            ctl_send: device_ctl_send,
+            io: device_io,
+        };
+
+        let (abort_handle, registration) = AbortHandle::new_pair();
+        tokio::spawn(Abortable::new(
+            async move {
+                // split the data into the notification-sized chunks, like the BLE does
+                let pump = async move {
+                    let mut buffer = [0; UART_CHUNK_SIZE];
+                    while let Ok(len @ 1..) = host_read.read(&mut buffer).await {
+                        trace!("RX: {}", hex::encode(&buffer[..len]));
+                        if rx_send.send(buffer[..len].to_vec()).await.is_err() {
+                            break;
+                        }
+                    }
+                };
+                tokio::join!(pump, server.run());
+            },
+            registration,
+        ));
+
+        let link = MockLink {
+            ctl_send: host_ctl_send,
+            uart: tokio::sync::Mutex::new(host_write),
+            uart_packet_size: options.uart_packet_size.unwrap_or(DEFAULT_UART_PACKET_SIZE),
+            rssi: device.rssi.clone(),
+        };
+
+        Self::from_parts(
+            Box::new(link),
+            device.info.clone(),
+            xoss_characteristics(),
+            Battery {
+                level: device.battery_level.clone(),
+                source: Some(BatterySource::BatteryService),
+            },
+            Arc::new(DeviationPolicy::default()),
+            Notifications {
+                ctl_recv: host_ctl_recv,
+                rx_recv,
+                abort_handle,
+            },
+            options,
+        )
+    }
+}
+
+/// A file transfer the simulated device is busy with
+enum Transfer {
+    Sending(String),
+    Receiving,
+}
+
+impl Transfer {
+    fn status(&self) -> ControlMessageType {
+        match self {
+            Transfer::Sending(_) => ControlMessageType::Returning,
+            Transfer::Receiving => ControlMessageType::Accept,
+        }
+    }
+}
+
+/// The firmware of the simulated device
+struct Server {
+    device: MockDevice,
+    ctl_recv: Receiver<Vec<u8>>,
+    ctl_send: Sender<Vec<u8>>,
+    io: DuplexStream,
+}
+
+impl Server {
+    async fn run(mut self) {
+        while let Some(message) = self.ctl_recv.recv().await {
+            if let Err(e) = self.handle(&message).await {
+                warn!("The simulated device failed to handle a message: {:#}", e);
+            }
+        }
+        debug!("The host has disconnected from the simulated device");
+    }
+
+    async fn reply(&self, message_type: ControlMessageType, body: &[u8]) -> Result<()> {
+        let mut buffer = CtlBuffer::default();
+        let message = buffer.encode(&RawControlMessage { message_type, body })?;
+        trace!("CTL RX: {}", hex::encode(message));
+        self.ctl_send
+            .send(message.to_vec())
+            .await
+            .ok()
+            .context("The host has disconnected")
+    }
+
+    async fn handle(&mut self, message: &[u8]) -> Result<()> {
+        use ControlMessageType::*;
+
+        let message = RawControlMessage::read(message).context("Decoding the control message")?;
+        let body = message.body;
+
+        match self.device.take_fault(message.message_type) {
+            Some(Fault::Reply(reply)) => return self.reply(reply, body).await,
+            Some(Fault::Ignore) => return Ok(()),
+            None => {}
+        }
+
+        match message.message_type {
+            StatusReturn | RequestStop => self.reply(Idle, &[]).await,
+            DbgCmd => self.reply(DbgCmd, &self.device.debug_id).await,
+            StatusAct => {
+                let status = *self.device.activity_status.lock().unwrap();
+                self.reply(StatusAct, &[status.to_byte()]).await
+            }
+            RequestCap => {
+                let capacity = format!("{}/{}", self.device.free_kb(), self.device.total_kb);
+                self.reply(ReturnCap, capacity.as_bytes()).await
+            }
+            RequestDel => {
+                let filename = String::from_utf8_lossy(body);
+                match self.device.remove_file(&filename) {
+                    Some(_) => self.reply(DelSuccess, body).await,
+                    None => self.reply(ErrNoFile, body).await,
+                }
+            }
+            TimeSet => self.reply(TimeSetRtn, body).await,
+            RequestMga => {
+                let mut reply = vec![0x01, 0x00];
+                reply.extend_from_slice(&self.device.mga_valid_until().to_le_bytes());
+                self.reply(ReturnMga, &reply).await
+            }
+            RequestClr => {
+                self.device.files.lock().unwrap().clear();
+                self.reply(ReturnClr, &[]).await
+            }
+            // the real device reboots without replying
+            DfuEnter => Ok(()),
+            RequestReturn => {
+                let filename = String::from_utf8_lossy(body).into_owned();
+                if self.device.file(&filename).is_none() {
+                    return self.reply(ErrNoFile, body).await;
+                }
+                self.reply(Returning, body).await?;
+                self.transfer(Transfer::Sending(filename)).await
+            }
+            RequestSend => {
+                self.reply(Accept, body).await?;
+                self.transfer(Transfer::Receiving).await
+            }
+            _ => self.reply(ErrVali, &[]).await,
+        }
+    }
+
+    /// Run a transfer, answering the control messages the host sends meanwhile
+    async fn transfer(&mut self, transfer: Transfer) -> Result<()> {
+        let status = transfer.status();
+        let device = self.device.clone();
+        let io = &mut self.io;
+        let ctl_recv = &mut self.ctl_recv;
+        let ctl_send = &self.ctl_send;
+
+        // the transfer borrows the UART, so it has to end before the cleanup
+        let result = {
+            let run = async {
+                match &transfer {
+                    Transfer::Sending(filename) => {
+                        let content = device.file(filename).unwrap_or_default();
+                        ymodem::send_file(io, filename, &mut Cursor::new(content), &NoProgress)
+                            .await
+                    }
+                    Transfer::Receiving => {
+                        let (info, stream) = ymodem::receive_file(io, &NoProgress).await?;
+                        let content = stream
+                            .try_fold(Vec::new(), |mut content, chunk| async move {
+                                content.extend_from_slice(&chunk);
+                                Ok(content)
+                            })
+                            .await?;
+                        debug!("The simulated device has received {}", info.name);
+                        device.set_file(info.name, content);
+                        Ok(())
+                    }
+                }
+            };
+            tokio::pin!(run);
+
+            let mut buffer = CtlBuffer::default();
+            loop {
+                tokio::select! {
+                    result = &mut run => break Some(result),
+                    message = ctl_recv.recv() => {
+                        let Some(message) = message else {
+                            return Ok(());
+                        };
+                        let message_type = RawControlMessage::read(&message)
+                            .context("Decoding the control message")?
+                            .message_type;
+                        let (reply, body): (_, &[u8]) = match message_type {
+                            ControlMessageType::StatusReturn => (status, &[]),
+                            ControlMessageType::RequestStop => (ControlMessageType::Idle, &[]),
+                            _ => (ControlMessageType::ErrStatus, b"\0"),
+                        };
+                        let reply = buffer.encode(&RawControlMessage {
+                            message_type: reply,
+                            body,
+                        })?;
+                        ctl_send
+                            .send(reply.to_vec())
+                            .await
+                            .ok()
+                            .context("The host has disconnected")?;
+                        if message_type == ControlMessageType::RequestStop {
+                            break None;
+                        }
+                    }
+                }
+            }
+        };
+
+        match result {
+            Some(Ok(())) => self.reply(ControlMessageType::Idle, &[]).await,
+            Some(Err(e)) => {
+                self.purge().await;
+                Err(e.context("The transfer has failed"))
+            }
+            None => {
+                debug!("The host has stopped the transfer");
+                self.purge().await;
+                Ok(())
+            }
+        }
+    }
+
+    /// Drop the data left from an interrupted transfer
+    async fn purge(&mut self) {
+        let mut buffer = [0; UART_CHUNK_SIZE];
+        while let Ok(Ok(1..)) =
+            tokio::time::timeout(Duration::from_millis(50), self.io.read(&mut buffer)).await
+        {
+        }
+    }
+}
@@ -1,7 +1,17 @@
 //! This module provides low-level functions to communicate with device. They may leave the device in an inconsistent state if used incorrectly.
 
 pub mod ctl_message;
+pub mod deviation;
 mod device;
+#[cfg(feature = "mock")]
+pub mod mock;
+pub mod peripheral;
+pub mod session;
+mod stats;
 pub mod ymodem;
 
-pub use device::{CtlBuffer, DeviceInformation, UartStream, XossTransport, CTL_BUFFER_SIZE};
+pub use device::{
+    gatt_name, BatterySource, CtlBuffer, DeviceInformation, LinkMonitor, LinkQuality,
+    SignalStrength, TransportOptions, UartStream, UartWriteType, XossTransport, MAX_CTL_WRITE_SIZE,
+};
+pub use stats::{StatsCounters, TransportStats};
@@ -0,0 +1,154 @@
+//! The GATT operations the transport needs from a BLE peripheral
+//!
+//! [XossTransport](super::XossTransport) works with anything implementing [BlePeripheral], not only with the btleplug
+//! peripherals: another BLE stack, a simulator or a replay of a recorded session can be plugged in.
+
+use anyhow::Result;
+use async_trait::async_trait;
+use futures_util::Stream;
+use std::collections::BTreeSet;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub use btleplug::api::{Characteristic, ValueNotification, WriteType};
+
+pub type NotificationStream = Pin<Box<dyn Stream<Item = ValueNotification> + Send>>;
+
+/// A connected BLE peripheral
+#[async_trait]
+pub trait BlePeripheral: Send + Sync {
+    /// An identifier of the peripheral, for the logs
+    fn id(&self) -> String;
+    async fn discover_services(&self) -> Result<()>;
+    /// The characteristics found by [Self::discover_services]
+    fn characteristics(&self) -> BTreeSet<Characteristic>;
+    async fn read(&self, characteristic: &Characteristic) -> Result<Vec<u8>>;
+    async fn write(
+        &self,
+        characteristic: &Characteristic,
+        data: &[u8],
+        write_type: WriteType,
+    ) -> Result<()>;
+    async fn subscribe(&self, characteristic: &Characteristic) -> Result<()>;
+    /// The negotiated ATT MTU, if the BLE stack tells it
+    ///
+    /// btleplug doesn't (as of 0.10), so the transport assumes the MTU the device negotiates with the phones
+    fn mtu(&self) -> Option<usize> {
+        None
+    }
+    /// The received signal strength in dBm, if the BLE stack tells it
+    ///
+    /// Some stacks (like BlueZ) only update it while scanning, so it can be the one seen before connecting
+    async fn rssi(&self) -> Result<Option<i16>> {
+        Ok(None)
+    }
+    /// The interval of the connection events, if the BLE stack tells it
+    ///
+    /// btleplug doesn't (as of 0.10)
+    fn connection_interval(&self) -> Option<Duration> {
+        None
+    }
+    /// The notifications of all the subscribed characteristics
+    async fn notifications(&self) -> Result<NotificationStream>;
+    async fn disconnect(&self) -> Result<()>;
+}
+
+#[async_trait]
+impl BlePeripheral for btleplug::platform::Peripheral {
+    fn id(&self) -> String {
+        btleplug::api::Peripheral::id(self).to_string()
+    }
+
+    async fn discover_services(&self) -> Result<()> {
+        Ok(btleplug::api::Peripheral::discover_services(self).await?)
+    }
+
+    fn characteristics(&self) -> BTreeSet<Characteristic> {
+        btleplug::api::Peripheral::characteristics(self)
+    }
+
+    async fn read(&self, characteristic: &Characteristic) -> Result<Vec<u8>> {
+        Ok(btleplug::api::Peripheral::read(self, characteristic).await?)
+    }
+
+    async fn write(
+        &self,
+        characteristic: &Characteristic,
+        data: &[u8],
+        write_type: WriteType,
+    ) -> Result<()> {
+        Ok(btleplug::api::Peripheral::write(self, characteristic, data, write_type).await?)
+    }
+
+    async fn subscribe(&self, characteristic: &Characteristic) -> Result<()> {
+        Ok(btleplug::api::Peripheral::subscribe(self, characteristic).await?)
+    }
+
+    async fn rssi(&self) -> Result<Option<i16>> {
+        Ok(btleplug::api::Peripheral::properties(self)
+            .await?
+            .and_then(|properties| properties.rssi))
+    }
+
+    async fn notifications(&self) -> Result<NotificationStream> {
+        Ok(btleplug::api::Peripheral::notifications(self).await?)
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        Ok(btleplug::api::Peripheral::disconnect(self).await?)
+    }
+}
+
+/// Lets the caller keep a handle to the peripheral given to the transport, to inspect it afterwards
+#[async_trait]
+impl<P: BlePeripheral + ?Sized> BlePeripheral for Arc<P> {
+    fn id(&self) -> String {
+        (**self).id()
+    }
+
+    async fn discover_services(&self) -> Result<()> {
+        (**self).discover_services().await
+    }
+
+    fn characteristics(&self) -> BTreeSet<Characteristic> {
+        (**self).characteristics()
+    }
+
+    async fn read(&self, characteristic: &Characteristic) -> Result<Vec<u8>> {
+        (**self).read(characteristic).await
+    }
+
+    async fn write(
+        &self,
+        characteristic: &Characteristic,
+        data: &[u8],
+        write_type: WriteType,
+    ) -> Result<()> {
+        (**self).write(characteristic, data, write_type).await
+    }
+
+    async fn subscribe(&self, characteristic: &Characteristic) -> Result<()> {
+        (**self).subscribe(characteristic).await
+    }
+
+    fn mtu(&self) -> Option<usize> {
+        (**self).mtu()
+    }
+
+    async fn rssi(&self) -> Result<Option<i16>> {
+        (**self).rssi().await
+    }
+
+    fn connection_interval(&self) -> Option<Duration> {
+        (**self).connection_interval()
+    }
+
+    async fn notifications(&self) -> Result<NotificationStream> {
+        (**self).notifications().await
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        (**self).disconnect().await
+    }
+}
@@ -0,0 +1,398 @@
+//! Recording the BLE traffic of a session and replaying it without the device
+//!
+//! [RecordingPeripheral] wraps a real peripheral and logs every read, write and notification into a [Session].
+//! [ReplayPeripheral] plays a saved session back: it answers the reads with the recorded values and sends the
+//! recorded notifications after each write, so the commands that ran against a user's device can be run again
+//! (and turned into the regression tests) without it.
+//!
+//! The replay only works as long as the host does the same thing it did during the recording. The writes to a
+//! different characteristic than recorded stop it, the writes with a different content (like the current time)
+//! are accepted and listed in [ReplayPeripheral::mismatches].
+
+use crate::transport::peripheral::{
+    BlePeripheral, Characteristic, NotificationStream, ValueNotification, WriteType,
+};
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use btleplug::api::CharPropFlags;
+use futures_util::StreamExt;
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter};
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tracing::debug;
+use uuid::Uuid;
+
+const MAGIC: &[u8; 16] = b"f-xoss session\0\x01";
+
+const TAG_READ: u8 = 1;
+const TAG_WRITE: u8 = 2;
+const TAG_NOTIFICATION: u8 = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionEvent {
+    Read { uuid: Uuid, value: Vec<u8> },
+    Write { uuid: Uuid, data: Vec<u8> },
+    Notification { uuid: Uuid, value: Vec<u8> },
+}
+
+impl Display for SessionEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SessionEvent::Read { uuid, value } => {
+                write!(f, "read {}: {}", uuid, hex::encode(value))
+            }
+            SessionEvent::Write { uuid, data } => {
+                write!(f, "write {}: {}", uuid, hex::encode(data))
+            }
+            SessionEvent::Notification { uuid, value } => {
+                write!(f, "notification {}: {}", uuid, hex::encode(value))
+            }
+        }
+    }
+}
+
+/// The BLE traffic of a session, in the order it happened
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Session {
+    pub characteristics: Vec<Characteristic>,
+    pub events: Vec<SessionEvent>,
+}
+
+fn write_uuid(w: &mut impl Write, uuid: Uuid) -> std::io::Result<()> {
+    w.write_all(uuid.as_bytes())
+}
+
+fn read_uuid(r: &mut impl Read) -> std::io::Result<Uuid> {
+    let mut bytes = [0; 16];
+    r.read_exact(&mut bytes)?;
+    Ok(Uuid::from_bytes(bytes))
+}
+
+impl Session {
+    pub fn write_to(&self, mut w: impl Write) -> std::io::Result<()> {
+        w.write_all(MAGIC)?;
+
+        w.write_all(&(self.characteristics.len() as u16).to_le_bytes())?;
+        for characteristic in &self.characteristics {
+            write_uuid(&mut w, characteristic.uuid)?;
+            write_uuid(&mut w, characteristic.service_uuid)?;
+            w.write_all(&[characteristic.properties.bits()])?;
+        }
+
+        for event in &self.events {
+            let (tag, uuid, data) = match event {
+                SessionEvent::Read { uuid, value } => (TAG_READ, uuid, value),
+                SessionEvent::Write { uuid, data } => (TAG_WRITE, uuid, data),
+                SessionEvent::Notification { uuid, value } => (TAG_NOTIFICATION, uuid, value),
+            };
+            w.write_all(&[tag])?;
+            write_uuid(&mut w, *uuid)?;
+            w.write_all(&(data.len() as u32).to_le_bytes())?;
+            w.write_all(data)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn read_from(mut r: impl Read) -> Result<Self> {
+        let mut magic = [0; 16];
+        r.read_exact(&mut magic)
+            .context("Reading the session header")?;
+        if &magic != MAGIC {
+            bail!("Not a recorded session, or recorded by an incompatible version");
+        }
+
+        let mut count = [0; 2];
+        r.read_exact(&mut count)?;
+        let mut characteristics = Vec::new();
+        for _ in 0..u16::from_le_bytes(count) {
+            let uuid = read_uuid(&mut r)?;
+            let service_uuid = read_uuid(&mut r)?;
+            let mut properties = [0];
+            r.read_exact(&mut properties)?;
+            characteristics.push(Characteristic {
+                uuid,
+                service_uuid,
+                properties: CharPropFlags::from_bits_truncate(properties[0]),
+            });
+        }
+
+        let mut events = Vec::new();
+        loop {
+            let mut tag = [0];
+            if r.read(&mut tag)? == 0 {
+                break;
+            }
+            let uuid = read_uuid(&mut r).context("Reading an event")?;
+            let mut len = [0; 4];
+            r.read_exact(&mut len).context("Reading an event")?;
+            let mut data = vec![0; u32::from_le_bytes(len) as usize];
+            r.read_exact(&mut data).context("Reading an event")?;
+            events.push(match tag[0] {
+                TAG_READ => SessionEvent::Read { uuid, value: data },
+                TAG_WRITE => SessionEvent::Write { uuid, data },
+                TAG_NOTIFICATION => SessionEvent::Notification { uuid, value: data },
+                tag => bail!("Unknown event type {} in the session", tag),
+            });
+        }
+
+        Ok(Self {
+            characteristics,
+            events,
+        })
+    }
+}
+
+/// A session being recorded, cloning it gives another handle to the same one
+#[derive(Debug, Clone, Default)]
+pub struct SessionRecording {
+    session: Arc<Mutex<Session>>,
+}
+
+impl SessionRecording {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The traffic recorded so far
+    pub fn session(&self) -> Session {
+        self.session.lock().unwrap().clone()
+    }
+
+    fn record(&self, event: SessionEvent) {
+        self.session.lock().unwrap().events.push(event);
+    }
+}
+
+/// A peripheral recording all the traffic going through it
+pub struct RecordingPeripheral<P> {
+    peripheral: P,
+    recording: SessionRecording,
+}
+
+impl<P: BlePeripheral> RecordingPeripheral<P> {
+    pub fn new(peripheral: P, recording: SessionRecording) -> Self {
+        Self {
+            peripheral,
+            recording,
+        }
+    }
+}
+
+#[async_trait]
+impl<P: BlePeripheral> BlePeripheral for RecordingPeripheral<P> {
+    fn id(&self) -> String {
+        self.peripheral.id()
+    }
+
+    async fn discover_services(&self) -> Result<()> {
+        self.peripheral.discover_services().await
+    }
+
+    fn characteristics(&self) -> BTreeSet<Characteristic> {
+        let characteristics = self.peripheral.characteristics();
+        self.recording.session.lock().unwrap().characteristics =
+            characteristics.iter().cloned().collect();
+        characteristics
+    }
+
+    async fn read(&self, characteristic: &Characteristic) -> Result<Vec<u8>> {
+        let value = self.peripheral.read(characteristic).await?;
+        self.recording.record(SessionEvent::Read {
+            uuid: characteristic.uuid,
+            value: value.clone(),
+        });
+        Ok(value)
+    }
+
+    async fn write(
+        &self,
+        characteristic: &Characteristic,
+        data: &[u8],
+        write_type: WriteType,
+    ) -> Result<()> {
+        // recorded before writing, so that the notifications caused by the write come after it
+        self.recording.record(SessionEvent::Write {
+            uuid: characteristic.uuid,
+            data: data.to_vec(),
+        });
+        self.peripheral
+            .write(characteristic, data, write_type)
+            .await
+    }
+
+    async fn subscribe(&self, characteristic: &Characteristic) -> Result<()> {
+        self.peripheral.subscribe(characteristic).await
+    }
+
+    fn mtu(&self) -> Option<usize> {
+        self.peripheral.mtu()
+    }
+
+    // the link quality is not recorded, the replays don't have any
+
+    async fn rssi(&self) -> Result<Option<i16>> {
+        self.peripheral.rssi().await
+    }
+
+    fn connection_interval(&self) -> Option<Duration> {
+        self.peripheral.connection_interval()
+    }
+
+    async fn notifications(&self) -> Result<NotificationStream> {
+        let recording = self.recording.clone();
+        let notifications = self.peripheral.notifications().await?;
+        Ok(Box::pin(notifications.map(move |notification| {
+            recording.record(SessionEvent::Notification {
+                uuid: notification.uuid,
+                value: notification.value.clone(),
+            });
+            notification
+        })))
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        self.peripheral.disconnect().await
+    }
+}
+
+struct ReplayState {
+    /// The next event to be replayed
+    position: usize,
+    notifications_send: mpsc::UnboundedSender<ValueNotification>,
+    notifications_recv: Option<mpsc::UnboundedReceiver<ValueNotification>>,
+    mismatches: Vec<String>,
+}
+
+/// A peripheral playing a recorded session back
+pub struct ReplayPeripheral {
+    session: Session,
+    state: Mutex<ReplayState>,
+}
+
+impl ReplayPeripheral {
+    pub fn new(session: Session) -> Self {
+        let (notifications_send, notifications_recv) = mpsc::unbounded_channel();
+        Self {
+            session,
+            state: Mutex::new(ReplayState {
+                position: 0,
+                notifications_send,
+                notifications_recv: Some(notifications_recv),
+                mismatches: Vec::new(),
+            }),
+        }
+    }
+
+    /// The writes that differed from the recorded ones
+    pub fn mismatches(&self) -> Vec<String> {
+        self.state.lock().unwrap().mismatches.clone()
+    }
+
+    /// Send the notifications recorded up to the next read or write
+    fn send_notifications(&self, state: &mut ReplayState) {
+        while let Some(SessionEvent::Notification { uuid, value }) =
+            self.session.events.get(state.position)
+        {
+            // the receiver is gone only if the transport is, nobody is interested then
+            let _ = state.notifications_send.send(ValueNotification {
+                uuid: *uuid,
+                value: value.clone(),
+            });
+            state.position += 1;
+        }
+    }
+
+    /// Take the next recorded event, which should be the one the host is doing
+    fn next_event(&self, state: &mut ReplayState, doing: &str) -> Result<&SessionEvent> {
+        self.send_notifications(state);
+        let Some(event) = self.session.events.get(state.position) else {
+            bail!(
+                "The recorded session has ended, but the host does a {}",
+                doing
+            );
+        };
+        state.position += 1;
+        Ok(event)
+    }
+}
+
+#[async_trait]
+impl BlePeripheral for ReplayPeripheral {
+    fn id(&self) -> String {
+        "replay".to_string()
+    }
+
+    async fn discover_services(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn characteristics(&self) -> BTreeSet<Characteristic> {
+        self.session.characteristics.iter().cloned().collect()
+    }
+
+    async fn read(&self, characteristic: &Characteristic) -> Result<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        let value = match self.next_event(&mut state, "read")? {
+            SessionEvent::Read { uuid, value } if *uuid == characteristic.uuid => value.clone(),
+            event => bail!(
+                "The host diverges from the recorded session: it reads {}, but the recording has {}",
+                characteristic.uuid,
+                event
+            ),
+        };
+        self.send_notifications(&mut state);
+        Ok(value)
+    }
+
+    async fn write(
+        &self,
+        characteristic: &Characteristic,
+        data: &[u8],
+        _write_type: WriteType,
+    ) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let recorded = match self.next_event(&mut state, "write")? {
+            SessionEvent::Write { uuid, data } if *uuid == characteristic.uuid => data.clone(),
+            event => bail!(
+                "The host diverges from the recorded session: it writes {}, but the recording has {}",
+                characteristic.uuid,
+                event
+            ),
+        };
+        if recorded != data {
+            let mismatch = format!(
+                "write {}: {} instead of the recorded {}",
+                characteristic.uuid,
+                hex::encode(data),
+                hex::encode(&recorded)
+            );
+            debug!("Replay mismatch: {}", mismatch);
+            state.mismatches.push(mismatch);
+        }
+        self.send_notifications(&mut state);
+        Ok(())
+    }
+
+    async fn subscribe(&self, _characteristic: &Characteristic) -> Result<()> {
+        Ok(())
+    }
+
+    async fn notifications(&self) -> Result<NotificationStream> {
+        let mut state = self.state.lock().unwrap();
+        let recv = state
+            .notifications_recv
+            .take()
+            .context("The notifications of a replay can only be taken once")?;
+        self.send_notifications(&mut state);
+        Ok(Box::pin(UnboundedReceiverStream::new(recv)))
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        Ok(())
+    }
+}
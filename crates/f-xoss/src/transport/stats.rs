@@ -0,0 +1,106 @@
+//! Counting the communication problems of a connection, to tell how lossy an adapter and a device are together
+//!
+//! The channels and the YModem transfers count the problems in [StatsCounters] as they happen, and
+//! [XossTransport::stats](super::XossTransport::stats) takes a [TransportStats] snapshot of them.
+
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The number of the events of each kind since the connection was made
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TransportStats {
+    /// Control messages sent
+    pub ctl_sent: u64,
+    /// Control messages without a reply in time
+    pub ctl_timeouts: u64,
+    /// Control replies that could not be decoded (bad checksum, unknown type)
+    pub ctl_malformed: u64,
+    /// Control messages that came when no reply was expected, like the late replies to the timed out requests
+    pub ctl_unsolicited: u64,
+    /// UART notifications received
+    pub uart_notifications: u64,
+    /// UART notifications received while no transfer was reading them
+    pub uart_dropped: u64,
+    /// YModem packets sent or asked for again
+    pub retransmits: u64,
+    /// YModem packets received damaged (bad CRC, length or sequence number)
+    pub damaged_packets: u64,
+    /// YModem packets or acknowledgements that didn't come in time
+    pub packet_timeouts: u64,
+}
+
+impl TransportStats {
+    /// The events that happened after `earlier` was taken
+    pub fn since(&self, earlier: &TransportStats) -> TransportStats {
+        TransportStats {
+            ctl_sent: self.ctl_sent - earlier.ctl_sent,
+            ctl_timeouts: self.ctl_timeouts - earlier.ctl_timeouts,
+            ctl_malformed: self.ctl_malformed - earlier.ctl_malformed,
+            ctl_unsolicited: self.ctl_unsolicited - earlier.ctl_unsolicited,
+            uart_notifications: self.uart_notifications - earlier.uart_notifications,
+            uart_dropped: self.uart_dropped - earlier.uart_dropped,
+            retransmits: self.retransmits - earlier.retransmits,
+            damaged_packets: self.damaged_packets - earlier.damaged_packets,
+            packet_timeouts: self.packet_timeouts - earlier.packet_timeouts,
+        }
+    }
+}
+
+impl Display for TransportStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "control: {} sent, {} timed out, {} malformed, {} unsolicited; \
+             UART: {} notifications, {} dropped; \
+             YModem: {} retransmits, {} damaged, {} timed out",
+            self.ctl_sent,
+            self.ctl_timeouts,
+            self.ctl_malformed,
+            self.ctl_unsolicited,
+            self.uart_notifications,
+            self.uart_dropped,
+            self.retransmits,
+            self.damaged_packets,
+            self.packet_timeouts
+        )
+    }
+}
+
+/// What [StatsCounters] counts, see the fields of [TransportStats]
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum StatsEvent {
+    CtlSent,
+    CtlTimeout,
+    CtlMalformed,
+    CtlUnsolicited,
+    UartNotification,
+    UartDropped,
+    Retransmit,
+    DamagedPacket,
+    PacketTimeout,
+}
+
+/// The counters behind [TransportStats], shared by everything using the connection
+#[derive(Debug, Default)]
+pub struct StatsCounters([AtomicU64; 9]);
+
+impl StatsCounters {
+    pub(crate) fn count(&self, event: StatsEvent) {
+        self.0[event as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> TransportStats {
+        let get = |event: StatsEvent| self.0[event as usize].load(Ordering::Relaxed);
+        TransportStats {
+            ctl_sent: get(StatsEvent::CtlSent),
+            ctl_timeouts: get(StatsEvent::CtlTimeout),
+            ctl_malformed: get(StatsEvent::CtlMalformed),
+            ctl_unsolicited: get(StatsEvent::CtlUnsolicited),
+            uart_notifications: get(StatsEvent::UartNotification),
+            uart_dropped: get(StatsEvent::UartDropped),
+            retransmits: get(StatsEvent::Retransmit),
+            damaged_packets: get(StatsEvent::DamagedPacket),
+            packet_timeouts: get(StatsEvent::PacketTimeout),
+        }
+    }
+}
@@ -2,16 +2,18 @@ use anyhow::{anyhow, bail, Context, Result};
 use async_stream::try_stream;
 use async_trait::async_trait;
 use bytes::Bytes;
-use indicatif::ProgressStyle;
 use std::io::Cursor;
+use std::sync::Arc;
 use std::time::Duration;
 use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::time::timeout;
 use tokio_stream::Stream;
-use tracing::{debug_span, info_span, warn, Span};
+
+use crate::progress::ProgressSink;
+use crate::transport::stats::{StatsCounters, StatsEvent};
+use tracing::{debug_span, info_span, warn};
 use tracing_futures::Instrument;
-use tracing_indicatif::span_ext::IndicatifSpanExt;
 
 #[derive(Error, Debug)]
 #[allow(clippy::enum_variant_names)]
@@ -26,12 +28,17 @@ pub enum Error {
     InvalidCrc,
 }
 
+/// The other side aborted the transfer with `CAN CAN`
+#[derive(Error, Debug)]
+#[error("The transfer was cancelled by the other side")]
+pub struct TransferCancelled;
+
 const SOH: u8 = 0x01;
 const STX: u8 = 0x02;
 const EOT: u8 = 0x04;
 const ACK: u8 = 0x06;
 const NAK: u8 = 0x15;
-// const CAN: u8 = 0x18;
+const CAN: u8 = 0x18;
 
 pub const MAX_PACKET_SIZE: usize = 1024 + 5;
 pub const SMALL_DATA_SIZE: usize = 128;
@@ -142,6 +149,67 @@ impl<'a> YModemPacket<'a> {
     }
 }
 
+/// Cuts the bytes arriving in chunks of any size (like the BLE notifications) along the YModem packet boundaries
+///
+/// A packet is passed on once all of its bytes have come, by the length its start byte tells, no matter how the
+/// platform split or joined the notifications. When a piece of a packet is lost, [FrameAssembler::drop_incomplete]
+/// throws the rest away, so that the retransmitted packet is not read as the end of the broken one. A start byte not
+/// followed by a valid sequence number is not a packet start, it's passed on as garbage along with the bytes between
+/// the packets (the acknowledgements).
+#[derive(Debug, Default)]
+pub struct FrameAssembler {
+    /// The received bytes not passed on yet, starting with an incomplete packet
+    pending: Vec<u8>,
+}
+
+impl FrameAssembler {
+    /// Add the received bytes, returns the ones that can be passed on
+    pub fn push(&mut self, data: &[u8]) -> Vec<u8> {
+        self.pending.extend_from_slice(data);
+
+        let mut ready = Vec::new();
+        let mut consumed = 0;
+        loop {
+            let rest = &self.pending[consumed..];
+            let Some(start) = rest.iter().position(|&b| b == SOH || b == STX) else {
+                ready.extend_from_slice(rest);
+                consumed = self.pending.len();
+                break;
+            };
+            ready.extend_from_slice(&rest[..start]);
+            consumed += start;
+
+            let packet = &rest[start..];
+            if packet.len() >= 3 && packet[1] != packet[2] ^ 0xff {
+                ready.push(packet[0]);
+                consumed += 1;
+                continue;
+            }
+            let len = YModemPacket::data_len(packet[0]).expect("checked to be a start byte") + 5;
+            if packet.len() < len {
+                break;
+            }
+            ready.extend_from_slice(&packet[..len]);
+            consumed += len;
+        }
+
+        self.pending.drain(..consumed);
+        ready
+    }
+
+    /// Whether a packet is waiting for the rest of its bytes
+    pub fn is_incomplete(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Drop the packet waiting for the rest of its bytes, returns the number of bytes dropped
+    pub fn drop_incomplete(&mut self) -> usize {
+        let dropped = self.pending.len();
+        self.pending.clear();
+        dropped
+    }
+}
+
 #[derive(Debug)]
 pub struct YModemHeader {
     pub name: String,
@@ -167,7 +235,7 @@ impl YModemHeader {
                 if name.is_empty() {
                     name = s.to_string();
                 } else {
-                    size = u64::from_str_radix(s, 10).context("Invalid size")?;
+                    size = s.parse::<u64>().context("Invalid size")?;
                 }
 
                 Ok(())
@@ -176,6 +244,18 @@ impl YModemHeader {
 
         Ok(Self { name, size })
     }
+
+    /// Encode the header as the data of the first (zeroth) packet: the name and the size separated by a space
+    pub fn encode(&self) -> Result<[u8; SMALL_DATA_SIZE]> {
+        let header_str = format!("{} {}", self.name, self.size);
+        if header_str.len() > SMALL_DATA_SIZE {
+            bail!("Filename too long");
+        }
+
+        let mut header_data = [0u8; SMALL_DATA_SIZE];
+        header_data[..header_str.len()].copy_from_slice(header_str.as_bytes());
+        Ok(header_data)
+    }
 }
 
 pub struct ReceivingFileInfo {
@@ -183,7 +263,207 @@ pub struct ReceivingFileInfo {
     pub size: u64,
 }
 
-const UART_TIMEOUT: Duration = Duration::from_secs(5);
+/// Settings of a YMODEM transfer
+#[derive(Debug, Clone)]
+pub struct YModemOptions {
+    /// How long to wait for each packet to be sent or received
+    pub timeout: Duration,
+    /// How many times a damaged or lost packet is requested again before giving up
+    pub retries: u32,
+    /// Where to count the retransmits, the damaged and the lost packets
+    pub stats: Option<Arc<StatsCounters>>,
+}
+
+impl YModemOptions {
+    fn count(&self, event: StatsEvent) {
+        if let Some(stats) = &self.stats {
+            stats.count(event);
+        }
+    }
+}
+
+impl Default for YModemOptions {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(5),
+            retries: 5,
+            stats: None,
+        }
+    }
+}
+
+/// How long the line has to be quiet after a damaged packet before asking for it again
+const PURGE_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Read and drop everything until nothing arrives for [PURGE_TIMEOUT], returns the number of bytes dropped
+async fn purge(reader: &mut (impl AsyncRead + Unpin)) -> Result<usize> {
+    let mut buffer = [0u8; MAX_PACKET_SIZE];
+    let mut dropped = 0;
+    loop {
+        match timeout(PURGE_TIMEOUT, reader.read(&mut buffer)).await {
+            Err(_) | Ok(Ok(0)) => return Ok(dropped),
+            Ok(Ok(len)) => dropped += len,
+            Ok(Err(e)) => return Err(e).context("Purging the input"),
+        }
+    }
+}
+
+/// Read bytes until one of `expected` comes, the rest is considered line garbage
+///
+/// Two `CAN` bytes in a row fail with [TransferCancelled], a single one is likely garbage too.
+async fn read_one_of(reader: &mut (impl AsyncRead + Unpin), expected: &[u8]) -> Result<u8> {
+    let mut skipped = 0;
+    let mut previous = None;
+    loop {
+        let byte = reader.read_u8().await?;
+        if expected.contains(&byte) {
+            if skipped > 0 {
+                warn!("Skipped {} garbage bytes", skipped);
+            }
+            return Ok(byte);
+        }
+        if byte == CAN && previous == Some(CAN) {
+            return Err(TransferCancelled.into());
+        }
+        previous = Some(byte);
+        skipped += 1;
+    }
+}
+
+/// Abort the transfer, the other side should give up on it too
+///
+/// The spec requires only two `CAN` bytes, more are sent in case some get lost.
+pub async fn cancel(writer: &mut (impl AsyncWrite + Unpin)) -> Result<()> {
+    writer.write_all(&[CAN; 5]).await.context("Sending CAN")?;
+    writer.flush().await.context("Flushing")?;
+    Ok(())
+}
+
+/// Cancel the transfer because of `error`, which is returned
+async fn abort(writer: &mut (impl AsyncWrite + Unpin), error: anyhow::Error) -> anyhow::Error {
+    if let Err(e) = cancel(writer).await {
+        warn!("Failed to cancel the transfer: {:#}", e);
+    }
+    error
+}
+
+/// Read a packet, skipping the garbage before its start byte
+async fn read_packet<'a>(
+    reader: &mut (impl AsyncRead + Unpin),
+    buffer: &'a mut [u8; MAX_PACKET_SIZE],
+) -> Result<YModemPacket<'a>> {
+    let start = read_one_of(reader, &[SOH, STX]).await?;
+    read_packet_after(reader, start, buffer).await
+}
+
+/// Read the rest of a packet which start byte was already read
+async fn read_packet_after<'a>(
+    reader: &mut (impl AsyncRead + Unpin),
+    start: u8,
+    buffer: &'a mut [u8; MAX_PACKET_SIZE],
+) -> Result<YModemPacket<'a>> {
+    buffer[0] = start;
+    let data_len = YModemPacket::data_len(start)?;
+    reader.read_exact(&mut buffer[1..data_len + 5]).await?;
+    YModemPacket::parse(&buffer[..data_len + 5]).map_err(|e| e.into())
+}
+
+/// Receive the data of the packet `seq`, asking the sender to repeat it when it's damaged or lost
+///
+/// `retry` is the byte asking for the repeat: `C` for the header, `NAK` for the data packets.
+/// The packet is not acknowledged, this is up to the caller.
+async fn receive_packet(
+    io: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    buffer: &mut [u8; MAX_PACKET_SIZE],
+    seq: u8,
+    retry: u8,
+    options: &YModemOptions,
+    progress: &dyn ProgressSink,
+) -> Result<Bytes> {
+    for _ in 0..=options.retries {
+        match timeout(options.timeout, read_packet(io, buffer)).await {
+            Ok(Ok(packet)) if packet.seq == seq => return Ok(Bytes::copy_from_slice(packet.data)),
+            Ok(Ok(packet)) if seq != 0 && packet.seq == seq.wrapping_sub(1) => {
+                // the sender didn't get our ACK and sent the previous packet again
+                warn!("Got packet {} again, acknowledging", packet.seq);
+                io.write_all(&[ACK]).await.context("Sending ACK")?;
+                continue;
+            }
+            Ok(Ok(packet)) => {
+                let error = anyhow!(
+                    "Invalid sequence number: expected {}, got {}",
+                    seq,
+                    packet.seq
+                );
+                return Err(abort(io, error).await);
+            }
+            Ok(Err(e)) if e.downcast_ref::<Error>().is_some() => {
+                warn!("Damaged packet {}: {}", seq, e);
+                options.count(StatsEvent::DamagedPacket);
+            }
+            Ok(Err(e)) => return Err(e).context("Reading YModem packet"),
+            Err(_) => {
+                warn!("Timed out reading packet {}", seq);
+                options.count(StatsEvent::PacketTimeout);
+            }
+        }
+
+        let dropped = purge(io).await?;
+        if dropped > 0 {
+            warn!("Dropped {} bytes of the damaged packet", dropped);
+        }
+        io.write_all(&[retry])
+            .await
+            .context("Requesting the packet again")?;
+        options.count(StatsEvent::Retransmit);
+        progress.retry();
+    }
+
+    let error = anyhow!(
+        "Failed to receive packet {} after {} attempts",
+        seq,
+        options.retries + 1
+    );
+    Err(abort(io, error).await)
+}
+
+/// Send a packet until the receiver acknowledges it
+///
+/// The packet is sent again when the receiver replies with `retry` (`C` for the header, `NAK` for the data packets)
+/// or doesn't reply at all.
+async fn send_packet(
+    io: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    packet: &YModemPacket<'_>,
+    retry: u8,
+    options: &YModemOptions,
+    progress: &dyn ProgressSink,
+) -> Result<()> {
+    for attempt in 0..=options.retries {
+        if attempt > 0 {
+            // the replies to the previous attempt should not be taken for the replies to this one
+            purge(io).await?;
+            options.count(StatsEvent::Retransmit);
+            progress.retry();
+        }
+        packet.write(io).await.context("Writing YModem packet")?;
+        match timeout(options.timeout, read_one_of(io, &[ACK, retry])).await {
+            Ok(Ok(ACK)) => return Ok(()),
+            Ok(Ok(_)) => warn!("Packet {} was rejected, sending again", packet.seq),
+            Ok(Err(e)) => return Err(e).context("Reading ACK"),
+            Err(_) => {
+                warn!("Timed out waiting for ACK of packet {}", packet.seq);
+                options.count(StatsEvent::PacketTimeout);
+            }
+        }
+    }
+
+    let error = anyhow!(
+        "Packet {} was not acknowledged after {} attempts",
+        packet.seq,
+        options.retries + 1
+    );
+    Err(abort(io, error).await)
+}
 
 #[async_trait]
 pub trait SizedAsyncRead: AsyncRead {
@@ -205,38 +485,40 @@ impl<T: AsRef<[u8]> + Unpin + Sync> SizedAsyncRead for Cursor<T> {
     }
 }
 
-fn progressbar_style() -> ProgressStyle {
-    ProgressStyle::default_bar()
-        .template("{span_child_prefix}{spinner:.green} [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta} @ {binary_bytes_per_sec})")
-        .unwrap()
-        .progress_chars("#>-")
+pub async fn receive_file<'a>(
+    io: &'a mut (impl AsyncRead + AsyncWrite + Unpin),
+    progress: &'a dyn ProgressSink,
+) -> Result<(ReceivingFileInfo, impl Stream<Item = Result<Bytes>> + 'a)> {
+    receive_file_with_options(io, progress, &YModemOptions::default()).await
 }
 
-pub async fn receive_file(
-    io: &mut (impl AsyncRead + AsyncWrite + Unpin),
-) -> Result<(ReceivingFileInfo, impl Stream<Item = Result<Bytes>> + '_)> {
+pub async fn receive_file_with_options<'a>(
+    io: &'a mut (impl AsyncRead + AsyncWrite + Unpin),
+    progress: &'a dyn ProgressSink,
+    options: &YModemOptions,
+) -> Result<(ReceivingFileInfo, impl Stream<Item = Result<Bytes>> + 'a)> {
+    let options = options.clone();
     let mut buffer = [0u8; MAX_PACKET_SIZE];
     let mut seq = 0;
 
-    let fut = async {
-        io.write_all(b"C").await.context("Sending C")?;
-
-        let header_packet = YModemPacket::read(io, &mut buffer)
-            .await
-            .context("Reading YModem header")?;
-        let header = YModemHeader::parse(&header_packet).context("Parsing YModem header")?;
-
-        if seq != header_packet.seq {
-            Err(anyhow!("Invalid sequence number"))?;
-        }
-        io.write_all(&[ACK]).await.context("Sending ACK")?;
-        io.write_all(b"C").await.context("Sending C")?;
-
-        Ok::<_, anyhow::Error>(header)
-    };
-    let header = timeout(UART_TIMEOUT, fut)
+    io.write_all(b"C").await.context("Sending C")?;
+    let header_data = receive_packet(io, &mut buffer, seq, b'C', &options, progress)
         .await
-        .context("Timed out initialing the transfer")??;
+        .context("Reading YModem header")?;
+    let header = YModemHeader::parse(&YModemPacket {
+        seq,
+        data: &header_data,
+    })
+    .context("Parsing YModem header")?;
+
+    // an empty header ends the batch, it is sent instead of a file when there's nothing to send
+    // NOTE: an empty file still has a name, its header has the size of 0 and is followed by EOT right away
+    if header.name.is_empty() {
+        io.write_all(&[ACK]).await.context("Sending ACK")?;
+        bail!("The sender ended the transfer without sending a file");
+    }
+    io.write_all(&[ACK]).await.context("Sending ACK")?;
+    io.write_all(b"C").await.context("Sending C")?;
 
     let file_info = ReceivingFileInfo {
         name: header.name,
@@ -248,57 +530,44 @@ pub async fn receive_file(
     Ok((
         file_info,
         try_stream! {
-            let cur_span = Span::current();
-
-            cur_span.pb_set_style(&progressbar_style());
-            cur_span.pb_set_length(len_left);
+            progress.start(len_left);
 
             while len_left > 0 {
                 seq = seq.wrapping_add(1);
 
-                let fut = async {
-                    let packet = YModemPacket::read(io, &mut buffer)
-                        .await
-                        .context("Reading YModem packet")?;
-
-                    if seq != packet.seq {
-                        Err(anyhow!("Invalid sequence number"))?;
-                    }
-
-                    // tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
-
-                    io.write_all(&[ACK]).await.context("Sending ACK")?;
-
-                    let data_len = std::cmp::min(len_left, packet.data.len() as u64) as usize;
-                    let data = Bytes::copy_from_slice(&packet.data[..data_len]);
-                    cur_span.pb_inc(data_len as u64);
-                    len_left -= data_len as u64;
-
-                    Ok::<_, anyhow::Error>(data)
-                };
-                let data = timeout(UART_TIMEOUT, fut)
+                let packet_data = receive_packet(io, &mut buffer, seq, NAK, &options, progress)
                     .instrument(debug_span!("read_packet", seq))
-                    .await
-                    .context("Timed out reading packet")??;
+                    .await?;
+                io.write_all(&[ACK]).await.context("Sending ACK")?;
+
+                let data_len = std::cmp::min(len_left, packet_data.len() as u64) as usize;
+                let data = packet_data.slice(..data_len);
+                len_left -= data_len as u64;
 
+                progress.advance(data.len() as u64);
                 yield data;
             }
 
             let fut = async {
-                if io.read_u8().await.context("Reading EOT")? != EOT {
-                    Err(anyhow!("Invalid EOT"))?;
+                // the last packet is repeated if the sender missed our ACK
+                loop {
+                    let byte = read_one_of(io, &[EOT, SOH, STX]).await.context("Reading EOT")?;
+                    if byte == EOT {
+                        break;
+                    }
+                    read_packet_after(io, byte, &mut buffer).await.context("Reading YModem packet")?;
+                    warn!("Got the last packet again, acknowledging");
+                    io.write_all(&[ACK]).await.context("Sending ACK")?;
                 }
                 io.write_all(&[NAK]).await.context("Sending NAK")?;
-                if io.read_u8().await.context("Reading EOT")? != EOT {
-                    Err(anyhow!("Invalid EOT"))?;
-                }
+                read_one_of(io, &[EOT]).await.context("Reading EOT")?;
                 io.write_all(&[ACK]).await.context("Sending ACK")?;
                 // make sure the last ACK gets written
                 io.flush().await.context("Flushing")?;
 
                 Ok::<_, anyhow::Error>(())
             };
-            timeout(UART_TIMEOUT, fut)
+            timeout(options.timeout, fut)
                 .await
                 .context("Timed out reading EOT")??;
         }
@@ -310,11 +579,27 @@ pub async fn send_file(
     io: &mut (impl AsyncRead + AsyncWrite + Unpin),
     filename: &str,
     file: &mut (impl SizedAsyncRead + Unpin),
+    progress: &dyn ProgressSink,
+) -> Result<()> {
+    send_file_with_options(io, filename, file, progress, &YModemOptions::default()).await
+}
+
+pub async fn send_file_with_options(
+    io: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    filename: &str,
+    file: &mut (impl SizedAsyncRead + Unpin),
+    progress: &dyn ProgressSink,
+    options: &YModemOptions,
 ) -> Result<()> {
+    let uart_timeout = options.timeout;
     let mut seq = 0;
 
     let file_size = file.size().await.context("Getting file size")?;
-    let header_str = format!("{} {}", filename, file_size);
+    let header_data = YModemHeader {
+        name: filename.to_string(),
+        size: file_size,
+    }
+    .encode()?;
 
     let packet_data_size = if file_size < LARGE_DATA_SIZE as u64 {
         SMALL_DATA_SIZE
@@ -322,39 +607,30 @@ pub async fn send_file(
         LARGE_DATA_SIZE
     };
 
-    if header_str.len() > SMALL_DATA_SIZE {
-        bail!("Filename too long");
-    }
-
-    let cur_span = Span::current();
-    cur_span.pb_set_style(&progressbar_style());
-    cur_span.pb_set_length(file_size);
-
-    let mut header_data = [0u8; SMALL_DATA_SIZE];
-    header_data[..header_str.len()].copy_from_slice(header_str.as_bytes());
-
     let fut = async {
-        let header_packet = YModemPacket::new(seq, &header_data);
-        if io.read_u8().await.context("Reading C")? != b'C' {
-            bail!("Expected C");
-        }
-        header_packet
-            .write(io)
-            .await
-            .context("Writing YModem header")?;
-        if io.read_u8().await.context("Reading ACK")? != ACK {
-            bail!("Expected ACK");
-        }
-        if io.read_u8().await.context("Reading C")? != b'C' {
-            bail!("Expected C");
-        }
-
+        read_one_of(io, b"C").await.context("Reading C")?;
         Ok::<_, anyhow::Error>(())
     };
-    timeout(UART_TIMEOUT, fut)
+    timeout(uart_timeout, fut)
         .await
         .context("Timed out initialing the transfer")??;
 
+    send_packet(
+        io,
+        &YModemPacket::new(seq, &header_data),
+        b'C',
+        options,
+        progress,
+    )
+    .await
+    .context("Writing YModem header")?;
+    timeout(uart_timeout, read_one_of(io, b"C"))
+        .await
+        .context("Timed out waiting for C")?
+        .context("Reading C")?;
+
+    progress.start(file_size);
+
     let mut data_buffer = vec![0u8; packet_data_size];
 
     let mut len_left = file_size;
@@ -362,43 +638,37 @@ pub async fn send_file(
         seq = seq.wrapping_add(1);
 
         let data_len = std::cmp::min(len_left, packet_data_size as u64) as usize;
-        file.read_exact(&mut data_buffer[..data_len])
-            .await
-            .context("Reading file")?;
+        if let Err(e) = file.read_exact(&mut data_buffer[..data_len]).await {
+            return Err(abort(io, anyhow::Error::new(e).context("Reading file")).await);
+        }
         // zero out the rest of the buffer
         data_buffer[data_len..].iter_mut().for_each(|b| *b = 0);
 
-        let fut = async {
-            let packet = YModemPacket::new(seq, &data_buffer);
-            packet.write(io).await.context("Writing YModem packet")?;
-            if io.read_u8().await.context("Reading ACK")? != ACK {
-                bail!("Expected ACK");
-            }
-            Ok::<_, anyhow::Error>(())
-        };
-        timeout(UART_TIMEOUT, fut)
-            .instrument(debug_span!("write_packet", seq))
-            .await
-            .context("Timed out writing packet")??;
-
-        cur_span.pb_inc(data_len as u64);
+        send_packet(
+            io,
+            &YModemPacket::new(seq, &data_buffer),
+            NAK,
+            options,
+            progress,
+        )
+        .instrument(debug_span!("write_packet", seq))
+        .await?;
+
+        progress.advance(data_len as u64);
         len_left -= data_len as u64;
     }
 
     let fut = async {
         io.write_all(&[EOT]).await.context("Sending EOT")?;
-        if io.read_u8().await.context("Reading NAK")? != NAK {
-            bail!("Expected NAK");
-        }
+        // a late ACK of a repeated packet may still come before the NAK
+        read_one_of(io, &[NAK]).await.context("Reading NAK")?;
         io.write_all(&[EOT]).await.context("Sending EOT")?;
-        if io.read_u8().await.context("Reading ACK")? != ACK {
-            bail!("Expected ACK");
-        }
+        read_one_of(io, &[ACK]).await.context("Reading ACK")?;
 
         Ok::<_, anyhow::Error>(())
     };
 
-    timeout(UART_TIMEOUT, fut)
+    timeout(uart_timeout, fut)
         .await
         .context("Timed out writing EOT")??;
 
@@ -0,0 +1,84 @@
+//! Wheel circumference, as set in the gear profiles (`wheel_size`)
+//!
+//! With a wheel speed sensor the device computes the speed and the distance by counting the wheel revolutions, so they
+//! are only as accurate as the circumference. The best way to get it is to measure a rollout: mark the tire, roll the bike
+//! in a straight line with the rider on it and measure the distance. The tire size gives a good estimate too.
+
+use std::f64::consts::PI;
+
+/// Bead seat diameters (ETRTO), in mm, of the rim sizes as they are named in the tire sizes
+const RIM_SIZES: &[(&str, u32)] = &[
+    ("700", 622),
+    ("29", 622),
+    ("28", 622),
+    ("27", 630),
+    ("27.5", 584),
+    ("650b", 584),
+    ("650c", 571),
+    ("26", 559),
+    ("24", 507),
+    ("20", 406),
+    ("18", 355),
+    ("16", 305),
+    ("14", 254),
+    ("12", 203),
+];
+
+/// The tires narrower than that are given in inches
+const MAX_WIDTH_INCHES: f64 = 5.0;
+
+/// The size of a tire, as printed on its sidewall
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TireSize {
+    /// In mm
+    pub width: f64,
+    /// The diameter of the rim where the tire sits, in mm
+    pub bead_seat_diameter: u32,
+}
+
+impl TireSize {
+    /// Parse a tire size like `25-622` (ETRTO), `700x25c`, `650bx47` or `29x2.2`
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.to_ascii_lowercase().replace(' ', "");
+
+        if let Some((width, diameter)) = s.split_once('-') {
+            let width = width.parse::<f64>().ok()?;
+            let bead_seat_diameter = diameter.parse().ok()?;
+            return (width > 0.0).then_some(Self {
+                width,
+                bead_seat_diameter,
+            });
+        }
+
+        let (rim, width) = s.split_once('x')?;
+        let (_, bead_seat_diameter) = RIM_SIZES.iter().find(|(name, _)| *name == rim)?;
+        let width = width
+            .strip_suffix('c')
+            .unwrap_or(width)
+            .parse::<f64>()
+            .ok()?;
+        if width <= 0.0 {
+            return None;
+        }
+        Some(Self {
+            width: if width < MAX_WIDTH_INCHES {
+                width * 25.4
+            } else {
+                width
+            },
+            bead_seat_diameter: *bead_seat_diameter,
+        })
+    }
+
+    /// The estimated circumference, in mm
+    ///
+    /// Within a percent of the measured one, depending on the tire pressure and the rider weight.
+    pub fn circumference(&self) -> u32 {
+        (PI * (self.bead_seat_diameter as f64 + 2.0 * self.width)).round() as u32
+    }
+}
+
+/// The circumference, in mm, from a distance rolled over a number of wheel revolutions
+pub fn rollout_circumference(distance: f64, revolutions: u32) -> Option<u32> {
+    (distance > 0.0 && revolutions > 0).then(|| (distance / revolutions as f64).round() as u32)
+}
@@ -0,0 +1,6 @@
+//! Helpers shared by the tests and the benchmarks
+
+/// File contents that don't repeat with the YModem block size, so that a misplaced block is noticed
+pub fn test_pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + i / 256) as u8).collect()
+}
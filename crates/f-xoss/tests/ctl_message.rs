@@ -0,0 +1,105 @@
+use f_xoss::device::DebugId;
+use f_xoss::transport::ctl_message::{check_echo, ControlMessageType, RawControlMessage};
+use f_xoss::transport::deviation::{DeviationPolicy, ProtocolDeviation};
+use f_xoss::transport::CtlBuffer;
+
+#[test]
+fn exact_echo_is_accepted() {
+    check_echo(
+        ControlMessageType::RequestReturn,
+        b"workouts.json",
+        b"workouts.json",
+    )
+    .unwrap();
+}
+
+#[test]
+fn truncated_echo_is_tolerated() {
+    check_echo(
+        ControlMessageType::RequestSend,
+        b"a_rather_long_route_name_from_komoot.gpx",
+        b"a_rather_long_route_name",
+    )
+    .unwrap();
+}
+
+#[test]
+fn mismatched_echo_is_an_error() {
+    let err = check_echo(
+        ControlMessageType::RequestDel,
+        b"20230101.fit",
+        b"20230102.fit",
+    )
+    .unwrap_err();
+    assert_eq!(err.request, ControlMessageType::RequestDel);
+    assert_eq!(err.actual, b"20230102.fit");
+
+    check_echo(ControlMessageType::RequestDel, b"20230101.fit", b"").unwrap_err();
+}
+
+#[test]
+fn truncated_echo_fails_in_strict_mode() {
+    let policy = DeviationPolicy::default();
+    let (expected, actual) = (b"long_file_name.gpx", b"long_file");
+    policy
+        .check_echo(ControlMessageType::RequestSend, expected, actual)
+        .unwrap();
+
+    policy.set_strict(true);
+    let err = policy
+        .check_echo(ControlMessageType::RequestSend, expected, actual)
+        .unwrap_err();
+    let deviation = err.downcast::<ProtocolDeviation>().unwrap();
+    assert_eq!(
+        deviation.dump,
+        "00000000  6c 6f 6e 67 5f 66 69 6c 65                       long_file"
+    );
+}
+
+#[test]
+fn long_messages_fit_into_the_buffer() {
+    let body = b"a_rather_long_route_name_from_komoot.gpx";
+    let mut buffer = CtlBuffer::default();
+    let encoded = buffer
+        .encode(&RawControlMessage {
+            message_type: ControlMessageType::Returning,
+            body,
+        })
+        .unwrap()
+        .to_vec();
+    assert_eq!(encoded.len(), body.len() + 2);
+
+    let message = buffer.decode(encoded).unwrap();
+    assert_eq!(message.message_type, ControlMessageType::Returning);
+    assert_eq!(message.body, body);
+}
+
+#[test]
+fn message_longer_than_the_slice_is_an_error() {
+    let mut buf = [0; 20];
+    let message = RawControlMessage {
+        message_type: ControlMessageType::RequestSend,
+        body: b"a_rather_long_route_name.gpx",
+    };
+    assert!(message.write(&mut buf).is_err());
+}
+
+#[test]
+fn truncated_message_is_an_error() {
+    assert!(RawControlMessage::read(&[]).is_err());
+    assert!(RawControlMessage::read(&[0x05]).is_err());
+}
+
+#[test]
+fn debug_id_is_parsed_as_bytes_or_hex() {
+    let expected = DebugId([0xDE, 0xAD, 0xBE, 0xEF, 0x00, 0x11, 0x22, 0x33]);
+    assert_eq!(
+        DebugId::parse(&[0xDE, 0xAD, 0xBE, 0xEF, 0x00, 0x11, 0x22, 0x33]).unwrap(),
+        expected
+    );
+    assert_eq!(DebugId::parse(b"deadbeef00112233").unwrap(), expected);
+    assert_eq!(expected.to_string(), "DEADBEEF00112233");
+
+    assert!(DebugId::parse(b"dead").is_err());
+    assert!(DebugId::parse(b"not a hex string").is_err());
+}
@@ -0,0 +1,87 @@
+use chrono::{TimeZone, Utc};
+use f_xoss::dem::{self, Dem, DemTile, ElevationProblem};
+use f_xoss::fit::TrackPoint;
+use f_xoss::geo::LatLon;
+
+/// A 3x3 tile rising from 100 m in the south to 300 m in the north, with a void in the north-east corner
+fn tile_data() -> Vec<u8> {
+    [300i16, 300, -32768, 200, 200, 200, 100, 100, 100]
+        .iter()
+        .flat_map(|v| v.to_be_bytes())
+        .collect()
+}
+
+fn dem_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("f-xoss-dem-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("N45E007.hgt"), tile_data()).unwrap();
+    dir
+}
+
+fn track(altitudes: &[f64]) -> Vec<TrackPoint> {
+    altitudes
+        .iter()
+        .enumerate()
+        .map(|(i, &altitude)| TrackPoint {
+            time: Utc.timestamp_opt(1_600_000_000 + i as i64 * 10, 0).unwrap(),
+            position: Some(LatLon::new(45.25, 7.1 + i as f64 * 0.01)),
+            altitude: Some(altitude),
+            heart_rate: None,
+            cadence: None,
+            speed: None,
+            distance: None,
+        })
+        .collect()
+}
+
+#[test]
+fn tiles_are_named_after_the_south_west_corner() {
+    assert_eq!(dem::tile_name(LatLon::new(45.5, 7.9)), "N45E007.hgt");
+    assert_eq!(dem::tile_name(LatLon::new(-0.5, -0.5)), "S01W001.hgt");
+    assert!(DemTile::parse(&[0; 10]).is_err());
+}
+
+#[test]
+fn elevation_is_interpolated() {
+    let dir = dem_dir("interpolate");
+    let mut dem = Dem::new(&dir);
+
+    assert_eq!(dem.elevation(LatLon::new(45.0, 7.0)).unwrap(), Some(100.0));
+    assert_eq!(dem.elevation(LatLon::new(45.25, 7.1)).unwrap(), Some(150.0));
+    // next to the void
+    assert_eq!(dem.elevation(LatLon::new(45.9, 7.9)).unwrap(), None);
+    // no tile
+    assert_eq!(dem.elevation(LatLon::new(46.5, 7.5)).unwrap(), None);
+    assert_eq!(
+        dem.missing_tiles(&[LatLon::new(45.5, 7.5), LatLon::new(46.5, 7.5)])
+            .unwrap(),
+        vec!["N46E007.hgt"]
+    );
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn broken_elevation_is_detected_and_corrected() {
+    assert_eq!(dem::elevation_problem(&track(&[150.0, 151.0, 152.0])), None);
+    assert_eq!(
+        dem::elevation_problem(&track(&[150.0, 9000.0, 152.0])),
+        Some(ElevationProblem::OutOfRange)
+    );
+    assert_eq!(
+        dem::elevation_problem(&track(&[150.0, 450.0, 152.0])),
+        Some(ElevationProblem::Spikes)
+    );
+
+    let dir = dem_dir("correct");
+    let mut dem = Dem::new(&dir);
+    let mut points = track(&[1150.0, 1151.0, 1152.0]);
+    assert_eq!(
+        dem::elevation_problem_with_dem(&points, &mut dem).unwrap(),
+        Some(ElevationProblem::Offset(1001.0))
+    );
+    assert_eq!(dem::correct_elevation(&mut points, &mut dem).unwrap(), 3);
+    assert_eq!(points[0].altitude, Some(150.0));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,252 @@
+mod common;
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use btleplug::api::CharPropFlags;
+use common::test_pattern;
+use f_xoss::dfu::{flash, DfuPackage};
+use f_xoss::progress::NoProgress;
+use f_xoss::transport::peripheral::{
+    BlePeripheral, Characteristic, NotificationStream, ValueNotification, WriteType,
+};
+use std::collections::BTreeSet;
+use std::io::{Cursor, Write};
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use uuid::Uuid;
+
+const DFU_SERVICE: Uuid = Uuid::from_u128(0x0000fe59_0000_1000_8000_00805f9b34fb);
+const CONTROL_POINT: Uuid = Uuid::from_u128(0x8ec90001_f315_4f60_9fb8_838830daea50);
+const PACKET: Uuid = Uuid::from_u128(0x8ec90002_f315_4f60_9fb8_838830daea50);
+
+const COMMAND: u8 = 0x01;
+const DATA: u8 = 0x02;
+
+/// What the bootloader has received, the objects of each type are appended to one buffer
+#[derive(Default)]
+struct BootloaderState {
+    command: Vec<u8>,
+    data: Vec<u8>,
+    /// The length of the data in the executed objects, the rest is discarded by the next create
+    executed_data: usize,
+    current: u8,
+    control_writes: Vec<Vec<u8>>,
+    packet_writes: Vec<usize>,
+}
+
+/// A Nordic Secure DFU bootloader that accepts everything and checksums what it got
+struct FakeBootloader {
+    max_size: u32,
+    state: Mutex<BootloaderState>,
+    notifications_send: mpsc::Sender<ValueNotification>,
+    notifications_recv: Mutex<Option<mpsc::Receiver<ValueNotification>>>,
+}
+
+impl FakeBootloader {
+    fn new(max_size: u32, state: BootloaderState) -> Self {
+        let (notifications_send, notifications_recv) = mpsc::channel(8);
+        Self {
+            max_size,
+            state: Mutex::new(state),
+            notifications_send,
+            notifications_recv: Mutex::new(Some(notifications_recv)),
+        }
+    }
+
+    fn respond(&self, request: &[u8]) -> Result<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        state.control_writes.push(request.to_vec());
+
+        let mut data = Vec::new();
+        match request {
+            [0x02, ..] | [0x04] => {}
+            [0x06, ty] => {
+                state.current = *ty;
+                let buffer = if *ty == COMMAND {
+                    &state.command
+                } else {
+                    &state.data
+                };
+                data.extend_from_slice(&self.max_size.to_le_bytes());
+                data.extend_from_slice(&(buffer.len() as u32).to_le_bytes());
+                data.extend_from_slice(&crc32fast::hash(buffer).to_le_bytes());
+            }
+            [0x01, ty, ..] => {
+                state.current = *ty;
+                if *ty == COMMAND {
+                    state.command.clear();
+                } else {
+                    let executed = state.executed_data;
+                    state.data.truncate(executed);
+                }
+            }
+            [0x03] => {
+                let buffer = if state.current == COMMAND {
+                    &state.command
+                } else {
+                    &state.data
+                };
+                data.extend_from_slice(&(buffer.len() as u32).to_le_bytes());
+                data.extend_from_slice(&crc32fast::hash(buffer).to_le_bytes());
+            }
+            _ => bail!("Unexpected DFU request: {:02x?}", request),
+        }
+        if request == [0x04] && state.current == DATA {
+            state.executed_data = state.data.len();
+        }
+
+        let mut response = vec![0x60, request[0], 0x01];
+        response.extend(data);
+        Ok(response)
+    }
+}
+
+#[async_trait]
+impl BlePeripheral for FakeBootloader {
+    fn id(&self) -> String {
+        "fake-dfu".to_string()
+    }
+
+    async fn discover_services(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn characteristics(&self) -> BTreeSet<Characteristic> {
+        [CONTROL_POINT, PACKET]
+            .into_iter()
+            .map(|uuid| Characteristic {
+                uuid,
+                service_uuid: DFU_SERVICE,
+                properties: CharPropFlags::WRITE | CharPropFlags::NOTIFY,
+            })
+            .collect()
+    }
+
+    async fn read(&self, characteristic: &Characteristic) -> Result<Vec<u8>> {
+        Err(anyhow!("Unexpected read of {}", characteristic.uuid))
+    }
+
+    async fn write(
+        &self,
+        characteristic: &Characteristic,
+        data: &[u8],
+        _write_type: WriteType,
+    ) -> Result<()> {
+        if characteristic.uuid == PACKET {
+            let mut state = self.state.lock().unwrap();
+            state.packet_writes.push(data.len());
+            if state.current == COMMAND {
+                state.command.extend_from_slice(data);
+            } else {
+                state.data.extend_from_slice(data);
+            }
+            return Ok(());
+        }
+
+        let value = self.respond(data)?;
+        self.notifications_send
+            .send(ValueNotification {
+                uuid: CONTROL_POINT,
+                value,
+            })
+            .await?;
+        Ok(())
+    }
+
+    async fn subscribe(&self, _characteristic: &Characteristic) -> Result<()> {
+        Ok(())
+    }
+
+    async fn notifications(&self) -> Result<NotificationStream> {
+        let recv = self.notifications_recv.lock().unwrap().take().unwrap();
+        Ok(Box::pin(ReceiverStream::new(recv)))
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+fn package() -> DfuPackage {
+    DfuPackage {
+        init_packet: test_pattern(141),
+        firmware: test_pattern(1000),
+    }
+}
+
+/// The `Create` requests sent, as (object type, size)
+fn created_objects(state: &BootloaderState) -> Vec<(u8, u32)> {
+    state
+        .control_writes
+        .iter()
+        .filter(|w| w[0] == 0x01)
+        .map(|w| (w[1], u32::from_le_bytes(w[2..6].try_into().unwrap())))
+        .collect()
+}
+
+#[test]
+fn packages_are_read_from_nrfutil_zips() {
+    let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    let options = zip::write::FileOptions::default();
+    zip.start_file("manifest.json", options).unwrap();
+    zip.write_all(br#"{"manifest":{"application":{"bin_file":"app.bin","dat_file":"app.dat"}}}"#)
+        .unwrap();
+    zip.start_file("app.dat", options).unwrap();
+    zip.write_all(&[1, 2, 3]).unwrap();
+    zip.start_file("app.bin", options).unwrap();
+    zip.write_all(&[4, 5, 6, 7]).unwrap();
+    let data = zip.finish().unwrap().into_inner();
+
+    let package = DfuPackage::from_zip(&data).unwrap();
+    assert_eq!(package.init_packet, [1, 2, 3]);
+    assert_eq!(package.firmware, [4, 5, 6, 7]);
+}
+
+#[tokio::test]
+async fn firmware_is_sent_in_bootloader_sized_objects() {
+    let bootloader = FakeBootloader::new(256, BootloaderState::default());
+    let package = package();
+
+    flash(&bootloader, &package, &NoProgress).await.unwrap();
+
+    let state = bootloader.state.lock().unwrap();
+    assert_eq!(state.command, package.init_packet);
+    assert_eq!(state.data, package.firmware);
+    // no packet receipt notifications, then the init packet
+    assert_eq!(state.control_writes[0], [0x02, 0x00, 0x00]);
+    assert_eq!(state.control_writes[1], [0x06, COMMAND]);
+    assert_eq!(state.control_writes[2], [0x01, COMMAND, 141, 0, 0, 0]);
+    assert_eq!(
+        created_objects(&state),
+        [
+            (COMMAND, 141),
+            (DATA, 256),
+            (DATA, 256),
+            (DATA, 256),
+            (DATA, 232)
+        ]
+    );
+    assert!(state.packet_writes.iter().all(|&len| len <= 20));
+}
+
+#[tokio::test]
+async fn interrupted_update_is_continued() {
+    let package = package();
+    let bootloader = FakeBootloader::new(
+        256,
+        BootloaderState {
+            command: package.init_packet.clone(),
+            data: package.firmware[..600].to_vec(),
+            executed_data: 512,
+            ..Default::default()
+        },
+    );
+
+    flash(&bootloader, &package, &NoProgress).await.unwrap();
+
+    let state = bootloader.state.lock().unwrap();
+    assert_eq!(state.data, package.firmware);
+    // the init packet is not sent again, the firmware continues from the last complete object
+    assert_eq!(created_objects(&state), [(DATA, 256), (DATA, 232)]);
+}
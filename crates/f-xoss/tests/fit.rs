@@ -0,0 +1,269 @@
+use f_xoss::fit::{self, message, FitError, FitFile, FitHeader, FitValue, WorkoutSummary};
+
+/// Builds FIT files field by field, all the fields are little-endian
+struct Builder {
+    records: Vec<u8>,
+}
+
+impl Builder {
+    fn new() -> Self {
+        Self {
+            records: Vec::new(),
+        }
+    }
+
+    /// `fields` are (number, size, base type)
+    fn define(mut self, local_type: u8, global: u16, fields: &[(u8, u8, u8)]) -> Self {
+        self.records.push(0x40 | local_type);
+        self.records.push(0);
+        self.records.push(0);
+        self.records.extend_from_slice(&global.to_le_bytes());
+        self.records.push(fields.len() as u8);
+        for &(number, size, base_type) in fields {
+            self.records.extend_from_slice(&[number, size, base_type]);
+        }
+        self
+    }
+
+    fn data(mut self, local_type: u8, bytes: &[&[u8]]) -> Self {
+        self.records.push(local_type);
+        for b in bytes {
+            self.records.extend_from_slice(b);
+        }
+        self
+    }
+
+    fn compressed(mut self, local_type: u8, time_offset: u8, bytes: &[&[u8]]) -> Self {
+        self.records.push(0x80 | local_type << 5 | time_offset);
+        for b in bytes {
+            self.records.extend_from_slice(b);
+        }
+        self
+    }
+
+    fn build(self) -> Vec<u8> {
+        let mut file = FitHeader {
+            header_size: 14,
+            protocol_version: 0x10,
+            profile_version: 2132,
+            data_size: self.records.len() as u32,
+        }
+        .to_bytes();
+        file.extend_from_slice(&self.records);
+        let crc = fit::crc(&file);
+        file.extend_from_slice(&crc.to_le_bytes());
+        file
+    }
+}
+
+const START: u32 = 1_000_000_000;
+
+fn record_fields() -> [(u8, u8, u8); 5] {
+    [
+        (253, 4, 0x86),
+        (0, 4, 0x85),
+        (1, 4, 0x85),
+        (2, 2, 0x84),
+        (5, 4, 0x86),
+    ]
+}
+
+fn record(timestamp: u32, lat: i32, altitude: f64, distance: f64) -> Vec<Vec<u8>> {
+    vec![
+        timestamp.to_le_bytes().to_vec(),
+        lat.to_le_bytes().to_vec(),
+        0i32.to_le_bytes().to_vec(),
+        (((altitude + 500.0) * 5.0) as u16).to_le_bytes().to_vec(),
+        ((distance * 100.0) as u32).to_le_bytes().to_vec(),
+    ]
+}
+
+fn refs(v: &[Vec<u8>]) -> Vec<&[u8]> {
+    v.iter().map(|b| b.as_slice()).collect()
+}
+
+#[test]
+fn session_summary_is_decoded() {
+    let data = Builder::new()
+        .define(
+            0,
+            message::SESSION,
+            &[(2, 4, 0x86), (7, 4, 0x86), (9, 4, 0x86), (22, 2, 0x84)],
+        )
+        .data(
+            0,
+            &[
+                &START.to_le_bytes(),
+                &3_600_500u32.to_le_bytes(),
+                &2_512_345u32.to_le_bytes(),
+                &0xFFFFu16.to_le_bytes(),
+            ],
+        )
+        .build();
+
+    let fit = FitFile::parse(&data).unwrap();
+    let session = fit.messages(message::SESSION).next().unwrap();
+    // the invalid value is dropped
+    assert!(session.field(22).is_none());
+
+    let summary = WorkoutSummary::from_fit(&fit);
+    assert_eq!(
+        summary.start_time.unwrap().timestamp(),
+        START as i64 + fit::FIT_EPOCH_OFFSET
+    );
+    assert_eq!(summary.elapsed_time, Some(3600.5));
+    assert_eq!(summary.distance, Some(25123.45));
+    assert_eq!(summary.ascent, None);
+}
+
+#[test]
+fn summary_falls_back_to_the_track() {
+    let mut builder = Builder::new().define(0, message::RECORD, &record_fields());
+    for (i, altitude) in [100.0, 110.0, 105.0, 120.0].into_iter().enumerate() {
+        builder = builder.data(
+            0,
+            &refs(&record(
+                START + i as u32 * 10,
+                1 << 29,
+                altitude,
+                i as f64 * 50.0,
+            )),
+        );
+    }
+    let fit = FitFile::parse(&builder.build()).unwrap();
+
+    let track = fit.track();
+    assert_eq!(track.len(), 4);
+    assert_eq!(track[0].position.unwrap().lat, 45.0);
+    assert_eq!(track[3].altitude, Some(120.0));
+
+    let summary = WorkoutSummary::from_fit(&fit);
+    assert_eq!(summary.elapsed_time, Some(30.0));
+    assert_eq!(summary.distance, Some(150.0));
+    assert_eq!(summary.ascent, Some(25.0));
+    assert_eq!(summary.avg_speed, Some(5.0));
+}
+
+#[test]
+fn compressed_timestamps_are_expanded() {
+    let data = Builder::new()
+        .define(0, message::RECORD, &record_fields())
+        .data(0, &refs(&record(START, 0, 0.0, 0.0)))
+        .define(1, message::RECORD, &[(5, 4, 0x86)])
+        // START is 0x3B9ACA00, its low 5 bits are 0
+        .compressed(1, 3, &[&100u32.to_le_bytes()])
+        // wraps around the 32 second window
+        .compressed(1, 1, &[&200u32.to_le_bytes()])
+        .build();
+
+    let fit = FitFile::parse(&data).unwrap();
+    let timestamps = fit
+        .messages(message::RECORD)
+        .map(|m| m.field(fit::TIMESTAMP_FIELD).and_then(FitValue::as_u64))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        timestamps,
+        vec![
+            Some(START as u64),
+            Some(START as u64 + 3),
+            Some(START as u64 + 33)
+        ]
+    );
+}
+
+#[test]
+fn corrupted_file_is_rejected() {
+    let mut data = Builder::new()
+        .define(0, message::RECORD, &record_fields())
+        .data(0, &refs(&record(START, 0, 0.0, 0.0)))
+        .build();
+    let len = data.len();
+    data[len - 5] ^= 0x55;
+
+    assert!(matches!(
+        FitFile::parse(&data),
+        Err(FitError::CrcMismatch { .. })
+    ));
+}
+
+#[test]
+fn truncated_records_keep_the_valid_prefix() {
+    let data = Builder::new()
+        .define(0, message::RECORD, &record_fields())
+        .data(0, &refs(&record(START, 0, 0.0, 0.0)))
+        .data(0, &refs(&record(START + 1, 0, 0.0, 0.0)))
+        .build();
+    let records = &data[14..data.len() - 2];
+    let cut = &records[..records.len() - 3];
+
+    let (messages, result) = fit::parse_records(cut);
+    assert_eq!(messages.len(), 1);
+    assert!(matches!(result, Err(FitError::Truncated(_))));
+    assert_eq!(fit::valid_records_len(cut), records.len() - 19);
+}
+
+#[test]
+fn cut_short_files_are_repaired() {
+    let data = Builder::new()
+        .define(0, message::RECORD, &record_fields())
+        .data(0, &refs(&record(START, 0, 0.0, 0.0)))
+        .data(0, &refs(&record(START + 1, 0, 0.0, 0.0)))
+        .data(0, &refs(&record(START + 2, 0, 0.0, 0.0)))
+        .build();
+    assert!(fit::repair(&data).unwrap().1.is_none());
+
+    // the battery ran out in the middle of the last record, before the CRC was written
+    let cut = &data[..data.len() - 2 - 5];
+    assert!(FitFile::parse(cut).is_err());
+    let (repaired, report) = fit::repair(cut).unwrap();
+    let report = report.unwrap();
+
+    let fit = FitFile::parse(&repaired).unwrap();
+    assert_eq!(fit.track().len(), 2);
+    assert_eq!(report.track_points, 2);
+    assert_eq!(report.original_len, cut.len() - 14);
+    assert_eq!(report.dropped_len(), 19 - 5);
+    assert!(report.header_fixed);
+    assert!(report.crc_fixed);
+    assert_eq!(
+        report.last_point_time.unwrap().timestamp(),
+        (START + 1) as i64 + fit::FIT_EPOCH_OFFSET
+    );
+
+    // a corrupted CRC with complete records only needs the CRC
+    let mut data = data;
+    let len = data.len();
+    data[len - 1] ^= 0x55;
+    let report = fit::repair(&data).unwrap().1.unwrap();
+    assert!(!report.header_fixed);
+    assert!(report.crc_fixed);
+    assert_eq!(report.dropped_len(), 0);
+
+    assert!(matches!(
+        fit::repair(b"not a FIT file"),
+        Err(FitError::InvalidHeader)
+    ));
+}
+
+#[test]
+fn annotations_are_appended() {
+    let data = Builder::new()
+        .define(0, message::RECORD, &record_fields())
+        .data(0, &refs(&record(START, 0, 0.0, 0.0)))
+        .data(0, &refs(&record(START + 1, 0, 0.0, 0.0)))
+        .build();
+
+    let long_notes = "é".repeat(200);
+    let annotated = fit::annotate(&data, Some("Morning ride"), Some(&long_notes)).unwrap();
+    let fit = FitFile::parse(&annotated).unwrap();
+    assert_eq!(fit.track().len(), 2);
+    let workout = fit.messages(message::WORKOUT).next().unwrap();
+    assert_eq!(
+        workout.field(8),
+        Some(&FitValue::String("Morning ride".to_string()))
+    );
+    // cut to the field size, at a character boundary
+    assert_eq!(workout.field(17), Some(&FitValue::String("é".repeat(127))));
+
+    assert_eq!(fit::annotate(&data, None, None).unwrap(), data);
+}
@@ -0,0 +1,21 @@
+use f_xoss::geo::{track_length, CoordinateEncoding, LatLon};
+
+#[test]
+fn semicircles_match_fit_values() {
+    let enc = CoordinateEncoding::Semicircles;
+    assert_eq!(enc.encode(90.0), 1 << 30);
+    assert_eq!(enc.encode(55.7558), 665_192_604);
+    assert!((enc.decode(665_192_604) - 55.7558).abs() < enc.max_error());
+    assert_eq!(enc.encode(180.0), i32::MAX);
+    assert_eq!(enc.encode(-180.0), i32::MIN);
+}
+
+#[test]
+fn distances() {
+    let a = LatLon::new(55.7558, 37.6173);
+    let b = LatLon::new(59.9343, 30.3351);
+    let d = a.distance_to(&b);
+    assert!((d - 633_000.0).abs() < 1_000.0, "{}", d);
+    assert_eq!(track_length(&[a]), 0.0);
+    assert!((track_length(&[a, b, a]) - 2.0 * d).abs() < 1e-6);
+}
@@ -0,0 +1,99 @@
+use chrono::NaiveDate;
+use f_xoss::mga::{parse_mga_data, parse_mga_online_data, CorruptFrames, Gnss};
+
+/// Append the UBX checksum to a message
+fn with_checksum(mut message: Vec<u8>) -> Vec<u8> {
+    let (mut ck_a, mut ck_b) = (0u8, 0u8);
+    for &b in &message[2..] {
+        ck_a = ck_a.wrapping_add(b);
+        ck_b = ck_b.wrapping_add(ck_a);
+    }
+    message.extend_from_slice(&[ck_a, ck_b]);
+    message
+}
+
+/// A UBX-MGA-ANO message for 2023-06-17
+fn mga_ano_message(gnss_id: u8, satellite: u8) -> Vec<u8> {
+    let mut message = vec![0xb5, 0x62, 0x13, 0x20, 0x4c, 0x00, 0x00, 0x00];
+    message.extend_from_slice(&[satellite, gnss_id, 23, 6, 17, 0]);
+    message.extend_from_slice(&[satellite; 64 + 4]);
+    with_checksum(message)
+}
+
+#[test]
+fn constellations_are_found() {
+    let mut data = Vec::new();
+    for (gnss_id, satellite) in [(0, 1), (0, 2), (2, 1), (3, 5)] {
+        data.extend(mga_ano_message(gnss_id, satellite));
+    }
+
+    let data = parse_mga_data(data, CorruptFrames::Fail).unwrap();
+    assert_eq!(data.frames, 4);
+    assert_eq!(
+        data.constellations.into_iter().collect::<Vec<_>>(),
+        [Gnss::Gps, Gnss::Galileo, Gnss::BeiDou]
+    );
+}
+
+/// A UBX message with a zero payload
+fn ubx_message(class: u8, id: u8, length: u16) -> Vec<u8> {
+    let mut message = vec![0xb5, 0x62, class, id];
+    message.extend_from_slice(&length.to_le_bytes());
+    message.extend(std::iter::repeat(0).take(length as usize));
+    with_checksum(message)
+}
+
+#[test]
+fn corrupt_frames_are_dropped() {
+    let good = mga_ano_message(0, 1);
+    let mut corrupt = mga_ano_message(0, 2);
+    corrupt[20] ^= 0xff;
+    let data = [good.clone(), corrupt, good.clone()].concat();
+
+    let parsed = parse_mga_data(data.clone(), CorruptFrames::Skip).unwrap();
+    assert_eq!(parsed.frames, 2);
+    assert_eq!(parsed.dropped_frames, 1);
+    // the corrupt frame won't be uploaded
+    assert_eq!(parsed.data, [good.clone(), good].concat());
+
+    assert!(parse_mga_data(data, CorruptFrames::Fail).is_err());
+}
+
+#[test]
+fn data_without_good_frames_is_an_error() {
+    let mut corrupt = mga_ano_message(0, 1);
+    corrupt[20] ^= 0xff;
+    assert!(parse_mga_data(corrupt, CorruptFrames::Skip).is_err());
+    assert!(parse_mga_data(Vec::new(), CorruptFrames::Skip).is_err());
+}
+
+#[test]
+fn online_data_is_parsed() {
+    let mut data = Vec::new();
+    // MGA-INI-TIME_UTC, MGA-GPS-EPH, MGA-GLO-EPH
+    data.extend(ubx_message(0x13, 0x40, 24));
+    data.extend(ubx_message(0x13, 0x00, 68));
+    data.extend(ubx_message(0x13, 0x06, 48));
+    let date = NaiveDate::from_ymd_opt(2023, 6, 17).unwrap();
+
+    let parsed = parse_mga_online_data(data.clone(), date, CorruptFrames::Fail).unwrap();
+    assert_eq!(parsed.valid_since, date);
+    assert_eq!(parsed.valid_until, date);
+    assert_eq!(
+        parsed.constellations.into_iter().collect::<Vec<_>>(),
+        [Gnss::Gps, Gnss::Glonass]
+    );
+
+    data.pop();
+    assert!(parse_mga_online_data(data, date, CorruptFrames::Skip).is_err());
+}
+
+#[test]
+fn constellation_names() {
+    assert_eq!(Gnss::parse("glo"), Some(Gnss::Glonass));
+    assert_eq!(Gnss::parse("BeiDou"), Some(Gnss::BeiDou));
+    assert_eq!(Gnss::parse("qzss"), None);
+    for &gnss in &[Gnss::Gps, Gnss::Glonass, Gnss::Galileo, Gnss::BeiDou] {
+        assert_eq!(Gnss::parse(gnss.query_name()), Some(gnss));
+    }
+}
@@ -0,0 +1,742 @@
+mod common;
+
+use common::test_pattern;
+use f_xoss::device::{
+    ActivityStatus, CtlProbeResult, DeviceBusy, DeviceFileKind, DeviceRecording, InsufficientSpace,
+    MgaState, NotReadOnly, UploadVerification, XossDevice, FREE_SPACE_MARGIN, PROBED_CTL_MESSAGES,
+};
+use f_xoss::json_protocol::UnsupportedJsonVersion;
+use f_xoss::model::{Sensor, SensorType, User, UserProfile, UserProfileInner};
+use f_xoss::progress::NoProgress;
+use f_xoss::transport::ctl_message::{ControlError, ControlMessageType, RawControlMessage};
+use f_xoss::transport::mock::{Fault, MockDevice};
+use f_xoss::transport::{
+    gatt_name, DeviceInformation, SignalStrength, TransportOptions, XossTransport,
+    MAX_CTL_WRITE_SIZE,
+};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+fn mock_device() -> MockDevice {
+    MockDevice::new(DeviceInformation {
+        firmware_revision: "1.0.0".to_string(),
+        manufacturer_name: "XOSS".to_string(),
+        model_number: "XOSS NAV".to_string(),
+        hardware_revision: "A1".to_string(),
+        serial_number: "0000000001".to_string(),
+    })
+}
+
+async fn connect(device: &MockDevice) -> XossDevice {
+    XossDevice::with_transport(XossTransport::mock(device, TransportOptions::default()))
+        .await
+        .expect("Connecting to the simulated device failed")
+}
+
+fn has_control_error(error: &anyhow::Error, f: impl Fn(&ControlError) -> bool) -> bool {
+    error
+        .chain()
+        .any(|e| e.downcast_ref::<ControlError>().is_some_and(&f))
+}
+
+#[tokio::test]
+async fn files_round_trip() {
+    let mock = mock_device();
+    let device = connect(&mock).await;
+
+    let content = test_pattern(3000);
+    device
+        .write_file("test.bin", &content, &NoProgress)
+        .await
+        .unwrap();
+    assert_eq!(mock.file("test.bin").as_deref(), Some(content.as_slice()));
+
+    let read = device.read_file("test.bin", &NoProgress).await.unwrap();
+    assert_eq!(read, content);
+
+    device.delete_file("test.bin").await.unwrap();
+    let error = device.read_file("test.bin", &NoProgress).await.unwrap_err();
+    assert!(has_control_error(&error, |e| matches!(
+        e,
+        ControlError::NoFile(_)
+    )));
+}
+
+#[tokio::test]
+async fn json_files_round_trip() {
+    // the device always has the profile, an empty one when fresh
+    let mut fresh = serde_json::to_value(UserProfile {
+        user: None,
+        user_profile: UserProfileInner::default(),
+        unknown: Default::default(),
+    })
+    .unwrap();
+    fresh["device_model"] = "XOSS NAV".into();
+    fresh["sn"] = "0000000001".into();
+    fresh["updated_at"] = 1686990000.into();
+    fresh["version"] = "2.0.0".into();
+    let mock = mock_device();
+    mock.set_file("user_profile.json", fresh.to_string());
+    let device = connect(&mock).await;
+
+    let profile = UserProfile {
+        user: Some(User {
+            platform: "XOSS".to_string(),
+            uid: 42,
+            user_name: "rider".to_string(),
+            unknown: Default::default(),
+        }),
+        user_profile: UserProfileInner {
+            time_zone: 7200,
+            ..Default::default()
+        },
+        unknown: Default::default(),
+    };
+    device.write_user_profile(&profile).await.unwrap();
+
+    let read = device.read_user_profile().await.unwrap();
+    assert_eq!(
+        serde_json::to_value(&read).unwrap(),
+        serde_json::to_value(&profile).unwrap()
+    );
+}
+
+#[tokio::test]
+async fn device_errors_are_reported() {
+    let mock = mock_device();
+    mock.set_file("test.bin", b"content".to_vec());
+    let device = connect(&mock).await;
+
+    mock.inject_fault(
+        ControlMessageType::RequestSend,
+        Fault::Reply(ControlMessageType::ErrMemory),
+    );
+    let error = device
+        .write_file("other.bin", b"content", &NoProgress)
+        .await
+        .unwrap_err();
+    assert!(has_control_error(&error, |e| matches!(
+        e,
+        ControlError::NoMemory
+    )));
+    assert!(mock.file("other.bin").is_none());
+
+    mock.inject_fault(
+        ControlMessageType::RequestReturn,
+        Fault::Reply(ControlMessageType::ErrStatus),
+    );
+    let error = device.read_file("test.bin", &NoProgress).await.unwrap_err();
+    assert!(has_control_error(&error, |e| matches!(
+        e,
+        ControlError::InvalidFileStatus(_)
+    )));
+
+    // the faults are used only once
+    let read = device.read_file("test.bin", &NoProgress).await.unwrap();
+    assert_eq!(read, b"content");
+}
+
+#[tokio::test]
+async fn lost_replies_time_out() {
+    let mock = mock_device();
+    let options = TransportOptions {
+        ctl_response_timeout: Duration::from_millis(100),
+        ..Default::default()
+    };
+    let device = XossDevice::with_transport(XossTransport::mock(&mock, options))
+        .await
+        .unwrap();
+
+    mock.inject_fault(ControlMessageType::RequestCap, Fault::Ignore);
+    assert!(device.get_memory_capacity().await.is_err());
+
+    // the connection recovers after the timeout
+    device.get_memory_capacity().await.unwrap();
+}
+
+#[tokio::test]
+async fn lossy_links_are_counted() {
+    let mock = mock_device();
+    let options = TransportOptions {
+        ctl_response_timeout: Duration::from_millis(100),
+        ..Default::default()
+    };
+    let device = XossDevice::with_transport(XossTransport::mock(&mock, options))
+        .await
+        .unwrap();
+    let before = device.transport_stats();
+
+    mock.inject_fault(ControlMessageType::RequestCap, Fault::Ignore);
+    assert!(device.get_memory_capacity().await.is_err());
+    device
+        .write_file("test.bin", b"content", &NoProgress)
+        .await
+        .unwrap();
+
+    let stats = device.transport_stats().since(&before);
+    assert_eq!(stats.ctl_timeouts, 1);
+    assert!(stats.ctl_sent >= 2, "{}", stats);
+    assert!(stats.uart_notifications > 0, "{}", stats);
+    assert_eq!(stats.retransmits, 0);
+    assert_eq!(stats.damaged_packets, 0);
+}
+
+#[tokio::test]
+async fn capabilities_are_probed() {
+    let mock = mock_device();
+    let options = TransportOptions {
+        ctl_response_timeout: Duration::from_millis(100),
+        ..Default::default()
+    };
+    let device = XossDevice::with_transport(XossTransport::mock(&mock, options))
+        .await
+        .unwrap();
+
+    assert!(device
+        .gatt_characteristics()
+        .await
+        .iter()
+        .any(|c| gatt_name(c.uuid) == Some("Battery Level")));
+
+    mock.inject_fault(
+        ControlMessageType::StatusAct,
+        Fault::Reply(ControlMessageType::ErrVali),
+    );
+    mock.inject_fault(ControlMessageType::RequestMga, Fault::Ignore);
+    let results = device.probe_ctl().await;
+    assert_eq!(results.len(), PROBED_CTL_MESSAGES.len());
+    for (message_type, result) in results {
+        match message_type {
+            ControlMessageType::StatusAct | ControlMessageType::RequestDetail => assert!(
+                matches!(result, CtlProbeResult::Refused(ControlError::Validation)),
+                "{:?}: {}",
+                message_type,
+                result
+            ),
+            ControlMessageType::RequestMga => {
+                assert!(matches!(result, CtlProbeResult::Failed(_)), "{}", result)
+            }
+            _ => assert!(result.is_supported(), "{:?}: {}", message_type, result),
+        }
+    }
+}
+
+#[tokio::test]
+async fn sensors_are_paired() {
+    let mock = mock_device();
+    mock.set_file(
+        "user_profile.json",
+        serde_json::json!({
+            "device_model": "XOSS NAV",
+            "sn": "0000000001",
+            "updated_at": 1686990000,
+            "version": "2.0.0",
+            "user_profile": UserProfileInner::default(),
+        })
+        .to_string(),
+    );
+    let device = connect(&mock).await;
+
+    // a device that was never paired has no sensors file
+    assert!(device.read_sensors().await.unwrap().is_empty());
+
+    let strap = Sensor::new(
+        "AA:BB:CC:DD:EE:01".to_string(),
+        SensorType::HeartRate,
+        "Strap".to_string(),
+    );
+    device.write_sensors(&[strap]).await.unwrap();
+
+    let written: serde_json::Value =
+        serde_json::from_slice(&mock.file("sensors.json").unwrap()).unwrap();
+    assert_eq!(written["sensors"][0]["type"], "heart_rate");
+
+    let sensors = device.read_sensors().await.unwrap();
+    assert_eq!(sensors.len(), 1);
+    assert_eq!(sensors[0].address, "AA:BB:CC:DD:EE:01");
+    assert_eq!(sensors[0].type_, SensorType::HeartRate);
+    assert_eq!("speed-cadence".parse(), Ok(SensorType::SpeedCadence));
+}
+
+#[tokio::test]
+async fn unknown_json_fields_are_preserved() {
+    let mock = mock_device();
+    mock.set_file(
+        "settings.json",
+        serde_json::json!({
+            "device_model": "XOSS NAV",
+            "sn": "0000000001",
+            "updated_at": 1686990000,
+            "version": "2.0.0",
+            "settings": {
+                "language_i18n": "en",
+                "unit": 0,
+                "temperature_unit": 0,
+                "time_formatter": 0,
+                "backlight": 0,
+                "auto_pause": 0,
+                "overwrite": 0,
+                "keytone": true,
+                "new_option": {"level": 3}
+            }
+        })
+        .to_string(),
+    );
+    let device = connect(&mock).await;
+
+    let mut settings = device.read_settings().await.unwrap();
+    assert_eq!(
+        settings.unknown.0.keys().collect::<Vec<_>>(),
+        ["new_option"]
+    );
+    settings.keytone = false;
+    device.write_settings(&settings).await.unwrap();
+
+    let written: serde_json::Value =
+        serde_json::from_slice(&mock.file("settings.json").unwrap()).unwrap();
+    assert_eq!(written["settings"]["keytone"], false);
+    assert_eq!(written["settings"]["new_option"]["level"], 3);
+
+    device.set_strict_protocol(true);
+    let error = device.read_settings().await.unwrap_err();
+    assert!(format!("{:#}", error).contains("unknown fields: new_option"));
+}
+
+#[tokio::test]
+async fn unknown_json_versions_are_refused_unless_chosen() {
+    let mock = mock_device();
+    mock.set_file(
+        "user_profile.json",
+        r#"{"device_model":"XOSS NAV","sn":"0000000001","updated_at":1686990000,"version":"3.1.0","user_profile":{"ALAHR":0,"ALASPEED":0,"FTP":0,"LTHR":0,"MAXHR":0,"birthday":0,"gender":0,"height":0,"time_zone":0,"weight":0}}"#,
+    );
+    let device = connect(&mock).await;
+
+    let error = device.read_user_profile().await.unwrap_err();
+    assert!(error.chain().any(|e| e.is::<UnsupportedJsonVersion>()));
+    assert!(device.set_json_protocol("3.1.0").is_err());
+
+    device.set_json_protocol("2.0.0").unwrap();
+    device.read_user_profile().await.unwrap();
+    // there are no workouts on a new device
+    assert!(device.read_workouts().await.unwrap().is_empty());
+    // but the settings are always there
+    let error = device.read_settings().await.unwrap_err();
+    assert!(has_control_error(&error, |e| matches!(
+        e,
+        ControlError::NoFile(_)
+    )));
+}
+
+#[tokio::test]
+async fn json_files_are_backed_up_and_restored_on_failure() {
+    let mock = mock_device();
+    mock.set_file("settings.json", r#"{"settings":{"old":true}}"#);
+    let device = connect(&mock).await;
+
+    let backups = Arc::new(Mutex::new(Vec::new()));
+    let backups_clone = backups.clone();
+    device.set_json_backup(Box::new(move |filename, content| {
+        backups_clone
+            .lock()
+            .unwrap()
+            .push((filename.to_string(), content.to_vec()));
+        Ok(())
+    }));
+
+    let new = br#"{"settings":{"old":false}}"#;
+    device
+        .write_raw_json_file("settings.json", new)
+        .await
+        .unwrap();
+    assert_eq!(mock.file("settings.json").unwrap(), new);
+    assert_eq!(
+        backups.lock().unwrap().as_slice(),
+        [(
+            "settings.json".to_string(),
+            br#"{"settings":{"old":true}}"#.to_vec()
+        )]
+    );
+
+    mock.inject_fault(
+        ControlMessageType::RequestSend,
+        Fault::Reply(ControlMessageType::ErrMemory),
+    );
+    let error = device
+        .write_raw_json_file("settings.json", br#"{"settings":{}}"#)
+        .await
+        .unwrap_err();
+    assert!(format!("{:#}", error).contains("the previous content was restored"));
+    assert_eq!(mock.file("settings.json").unwrap(), new);
+
+    assert!(device
+        .write_raw_json_file("settings.json", b"{not json")
+        .await
+        .is_err());
+    assert_eq!(backups.lock().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn uploads_leave_space_for_recording() {
+    let mock = mock_device();
+    mock.set_file("20230617120000.fit", vec![0; 7 * 1024 * 1024]);
+    let device = connect(&mock).await;
+
+    let capacity = device
+        .ensure_free_space("route.ro", 100 * 1024)
+        .await
+        .unwrap();
+    assert_eq!(capacity.free_kb, 1024);
+
+    let error = device
+        .ensure_free_space("offline.gnss", 1024 * 1024 - FREE_SPACE_MARGIN + 1)
+        .await
+        .unwrap_err();
+    let error = error.downcast_ref::<InsufficientSpace>().unwrap();
+    assert_eq!(error.filename, "offline.gnss");
+    assert_eq!(error.capacity.free_kb, 1024);
+}
+
+#[tokio::test]
+async fn uploads_are_verified_by_checksum() {
+    let mock = mock_device();
+    let device = connect(&mock).await;
+
+    let content = b"some file content".to_vec();
+    let verification = device
+        .write_file_verified("test.bin", &content, &NoProgress)
+        .await
+        .unwrap();
+    assert_eq!(
+        verification,
+        UploadVerification::Checksum(crc32fast::hash(&content))
+    );
+}
+
+#[tokio::test]
+async fn device_state() {
+    let mock = mock_device();
+    mock.set_battery_level(42);
+    mock.set_file("big.bin", vec![0; 2048]);
+    let device = connect(&mock).await;
+
+    assert_eq!(device.battery_level().await, Some(42));
+    assert_eq!(device.device_info().serial_number, "0000000001");
+    assert_eq!(
+        device.debug_id().await.unwrap(),
+        connect(&mock).await.debug_id().await.unwrap()
+    );
+
+    let capacity = device.get_memory_capacity().await.unwrap();
+    assert_eq!(capacity.total_kb - capacity.free_kb, 2);
+
+    assert!(matches!(
+        device.get_mga_state().await.unwrap(),
+        MgaState::MissingData
+    ));
+}
+
+#[tokio::test]
+async fn files_are_listed_from_the_indexes() {
+    let header =
+        r#""device_model":"XOSS NAV","sn":"0000000001","updated_at":1686990000,"version":"2.0.0""#;
+    let workouts = format!(r#"{{{},"workouts":[[1686990000,1234,0]]}}"#, header);
+    let routes = format!(
+        r#"{{{},"routes":[{{"rid":42,"size":777,"source":0,"name":"Loop","type":"Cycling","verison":2,"length":10000,"gain":100}}]}}"#,
+        header
+    );
+
+    let mock = mock_device();
+    mock.set_file("workouts.json", workouts.clone());
+    mock.set_file("routebooks.json", routes);
+    let device = connect(&mock).await;
+
+    let files = device.list_files().await.unwrap();
+    let listing = files
+        .iter()
+        .map(|f| (f.name.as_str(), f.kind, f.size))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        listing,
+        [
+            (
+                "workouts.json",
+                DeviceFileKind::Config,
+                Some(workouts.len() as u64)
+            ),
+            (
+                "routebooks.json",
+                DeviceFileKind::Config,
+                Some(mock.file("routebooks.json").unwrap().len() as u64)
+            ),
+            ("1686990000.fit", DeviceFileKind::Workout, Some(1234)),
+            ("42.ro", DeviceFileKind::Route, Some(777)),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn too_long_requests_are_rejected() {
+    let device = mock_device();
+    let xoss = connect(&device).await;
+
+    let filename = "a_rather_long_route_name_from_komoot.gpx";
+    device.set_file(filename, b"<gpx/>".to_vec());
+    assert!(xoss.delete_file(filename).await.is_err());
+    assert!(device.file(filename).is_some());
+
+    // the connection is still usable
+    xoss.get_memory_capacity().await.unwrap();
+}
+
+#[tokio::test]
+async fn scratch_files_fit_into_file_requests() {
+    let header =
+        r#""device_model":"XOSS NAV","sn":"0000000001","updated_at":1686990000,"version":"2.0.0""#;
+    let device = mock_device();
+    device.set_file(
+        "workouts.json",
+        format!(r#"{{{},"workouts":[[1686990000,1234,0]]}}"#, header),
+    );
+    device.set_file("routebooks.json", format!(r#"{{{},"routes":[]}}"#, header));
+    let xoss = connect(&device).await;
+
+    let filename = xoss.scratch_filename().await.unwrap();
+    // the request type and the checksum are sent along with the name
+    assert!(filename.len() + 2 <= MAX_CTL_WRITE_SIZE, "{}", filename);
+
+    let content = test_pattern(300);
+    let (filename, read) = xoss
+        .with_scratch_file(|filename| {
+            let (xoss, content) = (&xoss, &content);
+            async move {
+                xoss.write_file(&filename, content, &NoProgress).await?;
+                let read = xoss.read_file(&filename, &NoProgress).await?;
+                Ok((filename, read))
+            }
+        })
+        .await
+        .unwrap();
+    assert_eq!(read, content);
+    assert!(device.file(&filename).is_none());
+}
+
+#[tokio::test]
+async fn scratch_files_are_deleted_while_recording() {
+    let header =
+        r#""device_model":"XOSS NAV","sn":"0000000001","updated_at":1686990000,"version":"2.0.0""#;
+    let device = mock_device();
+    device.set_file("workouts.json", format!(r#"{{{},"workouts":[]}}"#, header));
+    device.set_file("routebooks.json", format!(r#"{{{},"routes":[]}}"#, header));
+    let xoss = connect(&device).await;
+
+    device.set_activity_status(ActivityStatus::Recording);
+    let filename = xoss
+        .with_scratch_file(|filename| {
+            let xoss = &xoss;
+            async move {
+                xoss.write_file(&filename, &test_pattern(100), &NoProgress)
+                    .await?;
+                Ok(filename)
+            }
+        })
+        .await
+        .unwrap();
+    assert!(device.file(&filename).is_none());
+}
+
+#[tokio::test]
+async fn raw_control_messages_are_not_decoded() {
+    let mock = mock_device();
+    let device = connect(&mock).await;
+
+    // RequestCap, the reply comes with its type and checksum
+    let reply = device.request_ctl_raw(0x09, &[]).await.unwrap();
+    let body = RawControlMessage::read(&reply)
+        .unwrap()
+        .expect_ok(ControlMessageType::ReturnCap)
+        .unwrap();
+    assert!(body.contains(&b'/'));
+
+    // RequestDetail is always rejected
+    let reply = device
+        .request_ctl_raw(ControlMessageType::RequestDetail as u8, &[])
+        .await
+        .unwrap();
+    assert_eq!(reply, [0x11, 0x11]);
+}
+
+#[tokio::test]
+async fn busy_device_is_reported_instead_of_waiting() {
+    let mock = mock_device();
+    mock.set_file("big.bin", vec![0x55; 64 * 1024]);
+    let device = connect(&mock).await;
+
+    let (content, busy, info) = tokio::join!(
+        device.read_file("big.bin", &NoProgress),
+        async {
+            // let the transfer start first
+            tokio::task::yield_now().await;
+            device.try_get_memory_capacity().await
+        },
+        async {
+            tokio::task::yield_now().await;
+            device.device_info()
+        },
+    );
+    assert_eq!(content.unwrap().len(), 64 * 1024);
+    assert!(busy.unwrap_err().downcast_ref::<DeviceBusy>().is_some());
+    assert_eq!(info.serial_number, "0000000001");
+
+    // nothing is running now
+    device.try_get_memory_capacity().await.unwrap();
+    assert_eq!(
+        device.try_get_transfer_status().await.unwrap(),
+        ControlMessageType::Idle
+    );
+}
+
+#[tokio::test]
+async fn read_only_control_messages_wait_or_fail_fast() {
+    let mock = mock_device();
+    mock.set_file("big.bin", vec![0x55; 64 * 1024]);
+    let device = connect(&mock).await;
+
+    let (content, busy, queued) = tokio::join!(
+        device.read_file("big.bin", &NoProgress),
+        async {
+            tokio::task::yield_now().await;
+            device
+                .try_request_ctl(ControlMessageType::RequestCap, &[])
+                .await
+        },
+        async {
+            tokio::task::yield_now().await;
+            device
+                .request_ctl(ControlMessageType::StatusReturn, &[])
+                .await
+        },
+    );
+    assert_eq!(content.unwrap().len(), 64 * 1024);
+    assert!(busy.unwrap_err().downcast_ref::<DeviceBusy>().is_some());
+    // served after the transfer
+    assert_eq!(queued.unwrap().0, ControlMessageType::Idle);
+
+    let (reply_type, body) = device
+        .try_request_ctl(ControlMessageType::RequestCap, &[])
+        .await
+        .unwrap();
+    assert_eq!(reply_type, ControlMessageType::ReturnCap);
+    assert!(body.contains(&b'/'));
+
+    let error = device
+        .request_ctl(ControlMessageType::RequestDel, b"big.bin")
+        .await
+        .unwrap_err();
+    assert!(error.downcast_ref::<NotReadOnly>().is_some());
+    assert!(mock.file("big.bin").is_some());
+}
+
+#[tokio::test]
+async fn link_quality_is_available_during_transfers() {
+    let mock = mock_device();
+    mock.set_file("big.bin", vec![0x55; 64 * 1024]);
+    mock.set_rssi(Some(-75));
+    let device = connect(&mock).await;
+
+    let (content, link_quality) = tokio::join!(device.read_file("big.bin", &NoProgress), async {
+        tokio::task::yield_now().await;
+        device.link_quality().await
+    });
+    assert_eq!(content.unwrap().len(), 64 * 1024);
+    assert_eq!(link_quality.rssi, Some(-75));
+    assert_eq!(link_quality.signal(), Some(SignalStrength::Fair));
+    assert_eq!(link_quality.to_string(), "-75 dBm (fair)");
+
+    mock.set_rssi(None);
+    let link_quality = device.link_quality().await;
+    assert_eq!(link_quality.signal(), None);
+    assert_eq!(link_quality.to_string(), "unknown signal");
+}
+
+#[tokio::test]
+async fn destructive_operations_are_refused_while_recording() {
+    let mock = mock_device();
+    mock.set_file("1686990000.fit", b"fit".to_vec());
+    let device = connect(&mock).await;
+
+    assert_eq!(
+        device.get_activity_status().await.unwrap(),
+        Some(ActivityStatus::Idle)
+    );
+
+    mock.set_activity_status(ActivityStatus::Recording);
+    let error = device.delete_file("1686990000.fit").await.unwrap_err();
+    assert!(error.downcast_ref::<DeviceRecording>().is_some());
+    assert!(device.factory_reset().await.is_err());
+    assert!(mock.file("1686990000.fit").is_some());
+
+    // the reading still works
+    device
+        .read_file("1686990000.fit", &NoProgress)
+        .await
+        .unwrap();
+
+    mock.set_activity_status(ActivityStatus::Idle);
+    device.delete_file("1686990000.fit").await.unwrap();
+}
+
+#[tokio::test]
+async fn missing_activity_status_is_not_an_error() {
+    let mock = mock_device();
+    mock.set_file("test.bin", b"test".to_vec());
+    let device = connect(&mock).await;
+
+    mock.inject_fault(
+        ControlMessageType::StatusAct,
+        Fault::Reply(ControlMessageType::ErrVali),
+    );
+    assert_eq!(device.get_activity_status().await.unwrap(), None);
+    // not asked again
+    mock.set_activity_status(ActivityStatus::Recording);
+    device.delete_file("test.bin").await.unwrap();
+}
+
+#[tokio::test]
+async fn recording_is_detected_from_the_workouts_without_activity_status() {
+    let header =
+        r#""device_model":"XOSS NAV","sn":"0000000001","updated_at":1686990000,"version":"2.0.0""#;
+    let mock = mock_device();
+    mock.set_file(
+        "workouts.json",
+        format!(r#"{{{},"workouts":[[1686990000,1234,1]]}}"#, header),
+    );
+    mock.set_file("test.bin", b"test".to_vec());
+    let device = connect(&mock).await;
+
+    mock.inject_fault(
+        ControlMessageType::StatusAct,
+        Fault::Reply(ControlMessageType::ErrVali),
+    );
+    let error = device.delete_file("test.bin").await.unwrap_err();
+    assert!(error.downcast_ref::<DeviceRecording>().is_some());
+
+    device.set_allow_while_recording(true);
+    device.delete_file("test.bin").await.unwrap();
+    assert!(mock.file("test.bin").is_none());
+}
+
+#[tokio::test]
+async fn unknown_activity_status_is_treated_as_recording() {
+    let mock = mock_device();
+    mock.set_file("test.bin", b"test".to_vec());
+    let device = connect(&mock).await;
+
+    mock.set_activity_status(ActivityStatus::Unknown(0x05));
+    assert_eq!(
+        device.get_activity_status().await.unwrap(),
+        Some(ActivityStatus::Unknown(0x05))
+    );
+    let error = device.delete_file("test.bin").await.unwrap_err();
+    assert!(error.downcast_ref::<DeviceRecording>().is_some());
+    assert!(mock.file("test.bin").is_some());
+}
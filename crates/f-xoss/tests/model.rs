@@ -0,0 +1,155 @@
+use chrono::{TimeZone, Utc};
+use f_xoss::model::{
+    GearBuilder, HeaderJson, Panels, UserProfile, UserProfileBuilder, UserProfileInner,
+    ValidationError, WorkoutNaming, WorkoutState, WorkoutsItem,
+};
+use std::collections::HashSet;
+
+fn workout(name: u64) -> WorkoutsItem {
+    WorkoutsItem {
+        name,
+        size: 1000,
+        state: WorkoutState::Synced,
+    }
+}
+
+#[test]
+fn naming_is_detected_from_existing_workouts() {
+    assert_eq!(WorkoutNaming::detect(&[]), None);
+    assert_eq!(
+        WorkoutNaming::detect(&[workout(1687000000)]),
+        Some(WorkoutNaming::UnixTime)
+    );
+    assert_eq!(
+        WorkoutNaming::detect(&[workout(20230617120000)]),
+        Some(WorkoutNaming::DateTime)
+    );
+}
+
+#[test]
+fn names_follow_the_device_time_zone() {
+    let start = Utc.with_ymd_and_hms(2023, 6, 17, 10, 0, 0).unwrap();
+    assert_eq!(WorkoutNaming::UnixTime.name(start, 7200), 1686996000);
+    assert_eq!(WorkoutNaming::DateTime.name(start, 7200), 20230617120000);
+    // half-hour zones
+    assert_eq!(WorkoutNaming::DateTime.name(start, 19800), 20230617153000);
+}
+
+#[test]
+fn start_times_are_parsed_from_names() {
+    let start = Utc.with_ymd_and_hms(2023, 6, 17, 10, 0, 0).unwrap();
+    assert_eq!(workout(1686996000).start_time(7200), Some(start));
+    assert_eq!(workout(20230617120000).start_time(7200), Some(start));
+    assert_eq!(workout(20230617153000).start_time(19800), Some(start));
+    // not a date
+    assert_eq!(workout(20231345120000).start_time(0), None);
+}
+
+#[test]
+fn clashing_names_are_moved_later() {
+    let start = Utc.with_ymd_and_hms(2023, 6, 17, 23, 59, 59).unwrap();
+    let taken = HashSet::from([20230617235959, 20230618000000]);
+    assert_eq!(
+        WorkoutNaming::DateTime.unique_name(start, 0, &taken),
+        20230618000001
+    );
+    assert_eq!(
+        WorkoutNaming::UnixTime.unique_name(start, 0, &taken),
+        start.timestamp() as u64
+    );
+}
+
+#[test]
+fn only_changed_profile_fields_are_validated() {
+    // a fresh device has zeros everywhere
+    let profile = UserProfile {
+        user: None,
+        user_profile: UserProfileInner::default(),
+        unknown: Default::default(),
+    };
+
+    let built = UserProfileBuilder::new(profile.clone())
+        .time_zone(3600)
+        .build()
+        .unwrap();
+    assert_eq!(built.user_profile.time_zone, 3600);
+    assert_eq!(built.user_profile.ftp, 0);
+
+    assert_eq!(
+        UserProfileBuilder::new(profile)
+            .ftp(5000)
+            .build()
+            .unwrap_err(),
+        ValidationError::OutOfRange {
+            field: "FTP",
+            value: 5000,
+            min: 1,
+            max: 2000
+        }
+    );
+}
+
+#[test]
+fn gears_are_validated() {
+    let gear = GearBuilder::new("Road").gid(2).build().unwrap();
+    assert_eq!((gear.gid, gear.wheel_size), (2, 2096));
+
+    assert_eq!(
+        GearBuilder::new(" ").build().unwrap_err(),
+        ValidationError::EmptyGearName
+    );
+    assert!(matches!(
+        GearBuilder::new("Road").wheel_size(26).build(),
+        Err(ValidationError::OutOfRange {
+            field: "wheel_size",
+            ..
+        })
+    ));
+}
+
+#[test]
+fn modified_gears_keep_the_other_fields() {
+    // the weight is out of the range the builder allows, but it's not the field being changed
+    let json = r#"{"gid":3,"weight":500,"wheel_size":2096,"activated":true,"name":"Gravel","type":"bike","color":7}"#;
+    let gear = serde_json::from_str(json).unwrap();
+
+    let gear = GearBuilder::modify(gear).wheel_size(2150).build().unwrap();
+
+    let mut expected = serde_json::from_str::<serde_json::Value>(json).unwrap();
+    expected["wheel_size"] = 2150.into();
+    assert_eq!(serde_json::to_value(&gear).unwrap(), expected);
+
+    assert!(matches!(
+        GearBuilder::modify(gear).wheel_size(26).build(),
+        Err(ValidationError::OutOfRange {
+            field: "wheel_size",
+            ..
+        })
+    ));
+}
+
+#[test]
+fn unknown_panel_fields_are_kept() {
+    let json = r#"{"panels":[{"enabled":true,"items":[1,2],"style":3}],"layout":"grid"}"#;
+    let panels: Panels = serde_json::from_str(json).unwrap();
+    assert_eq!(panels.panels[0].items, [1, 2]);
+
+    let written: serde_json::Value = serde_json::to_value(&panels).unwrap();
+    assert_eq!(
+        written,
+        serde_json::from_str::<serde_json::Value>(json).unwrap()
+    );
+}
+
+#[test]
+fn misspelled_header_time_is_read_and_written_back_fixed() {
+    let header: HeaderJson = serde_json::from_str(
+        r#"{"device_model":"XOSS G+","sn":"0000000001","update_at":1686990000,"version":"2.0.0"}"#,
+    )
+    .unwrap();
+    assert_eq!(header.updated_at, 1686990000);
+
+    let written = serde_json::to_value(&header).unwrap();
+    assert_eq!(written["updated_at"], 1686990000);
+    assert!(written.get("update_at").is_none());
+}
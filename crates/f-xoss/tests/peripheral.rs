@@ -0,0 +1,484 @@
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use btleplug::api::CharPropFlags;
+use f_xoss::transport::ctl_message::{ControlMessageType, RawControlMessage};
+use f_xoss::transport::peripheral::{
+    BlePeripheral, Characteristic, NotificationStream, ValueNotification, WriteType,
+};
+use f_xoss::transport::{BatterySource, CtlBuffer, TransportOptions, XossTransport};
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use uuid::Uuid;
+
+const UART_SERVICE: Uuid = Uuid::from_u128(0x6e400001_b5a3_f393_e0a9_e50e24dcca9e);
+const UART_TX: Uuid = Uuid::from_u128(0x6e400002_b5a3_f393_e0a9_e50e24dcca9e);
+const CTL: Uuid = Uuid::from_u128(0x6e400004_b5a3_f393_e0a9_e50e24dcca9e);
+const BATTERY_LEVEL: Uuid = Uuid::from_u128(0x00002a19_0000_1000_8000_00805f9b34fb);
+const SERIAL_NUMBER: Uuid = Uuid::from_u128(0x00002a25_0000_1000_8000_00805f9b34fb);
+/// Where a clone reports the battery level instead of the Battery Service
+const VENDOR_BATTERY_LEVEL: Uuid = Uuid::from_u128(0x0000fff1_0000_1000_8000_00805f9b34fb);
+const VENDOR_BATTERY_CTL: u8 = 0x42;
+
+const CHARACTERISTICS: &[u128] = &[
+    0x6e400002_b5a3_f393_e0a9_e50e24dcca9e,
+    0x6e400003_b5a3_f393_e0a9_e50e24dcca9e,
+    0x6e400004_b5a3_f393_e0a9_e50e24dcca9e,
+    0x00002a26_0000_1000_8000_00805f9b34fb,
+    0x00002a29_0000_1000_8000_00805f9b34fb,
+    0x00002a24_0000_1000_8000_00805f9b34fb,
+    0x00002a27_0000_1000_8000_00805f9b34fb,
+    0x00002a25_0000_1000_8000_00805f9b34fb,
+];
+
+type UartWrite = (Vec<u8>, WriteType);
+
+/// A peripheral that only knows the device information and the memory capacity request, recording the UART writes
+struct FakePeripheral {
+    notifications_send: mpsc::Sender<ValueNotification>,
+    notifications_recv: Mutex<Option<mpsc::Receiver<ValueNotification>>>,
+    mtu: Option<usize>,
+    /// The characteristic with the battery level, if any
+    battery_level: Option<Uuid>,
+    /// Whether the [VENDOR_BATTERY_CTL] message is answered
+    battery_ctl: bool,
+    /// Whether the UART writes without response are advertised
+    write_without_response: bool,
+    /// Whether the UART writes without response fail, like with some adapters
+    without_response_fails: bool,
+    /// The number of the UART write without response to fail, counting from zero
+    fail_write: Option<usize>,
+    /// Whether [Self::fail_write] fails after the write delay, when the later writes are already issued
+    fail_late: bool,
+    writes_without_response: AtomicUsize,
+    /// Set while a UART write with response is in progress, BlueZ rejects another one
+    write_with_response_in_progress: AtomicBool,
+    /// How long each UART write takes
+    write_delay: Duration,
+    uart_writes: Arc<Mutex<Vec<UartWrite>>>,
+    writes_in_flight: Arc<AtomicUsize>,
+    max_writes_in_flight: Arc<AtomicUsize>,
+}
+
+impl FakePeripheral {
+    fn new() -> Self {
+        let (notifications_send, notifications_recv) = mpsc::channel(8);
+        Self {
+            notifications_send,
+            notifications_recv: Mutex::new(Some(notifications_recv)),
+            mtu: None,
+            battery_level: Some(BATTERY_LEVEL),
+            battery_ctl: false,
+            write_without_response: true,
+            without_response_fails: false,
+            fail_write: None,
+            fail_late: false,
+            writes_without_response: Default::default(),
+            write_with_response_in_progress: Default::default(),
+            write_delay: Duration::ZERO,
+            uart_writes: Default::default(),
+            writes_in_flight: Default::default(),
+            max_writes_in_flight: Default::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl BlePeripheral for FakePeripheral {
+    fn id(&self) -> String {
+        "fake".to_string()
+    }
+
+    async fn discover_services(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn characteristics(&self) -> BTreeSet<Characteristic> {
+        CHARACTERISTICS
+            .iter()
+            .map(|&uuid| Uuid::from_u128(uuid))
+            .chain(self.battery_level)
+            .map(|uuid| {
+                let properties = if uuid == UART_TX {
+                    if self.write_without_response {
+                        CharPropFlags::WRITE | CharPropFlags::WRITE_WITHOUT_RESPONSE
+                    } else {
+                        CharPropFlags::WRITE
+                    }
+                } else {
+                    CharPropFlags::READ | CharPropFlags::NOTIFY
+                };
+                Characteristic {
+                    uuid,
+                    service_uuid: UART_SERVICE,
+                    properties,
+                }
+            })
+            .collect()
+    }
+
+    async fn read(&self, characteristic: &Characteristic) -> Result<Vec<u8>> {
+        Ok(match characteristic.uuid {
+            BATTERY_LEVEL | VENDOR_BATTERY_LEVEL => vec![55],
+            SERIAL_NUMBER => b"0000000042".to_vec(),
+            _ => b"fake".to_vec(),
+        })
+    }
+
+    async fn write(
+        &self,
+        characteristic: &Characteristic,
+        data: &[u8],
+        write_type: WriteType,
+    ) -> Result<()> {
+        if characteristic.uuid == UART_TX {
+            if write_type == WriteType::WithoutResponse {
+                if self.without_response_fails {
+                    bail!("Writes without response are not supported");
+                }
+                let number = self.writes_without_response.fetch_add(1, Ordering::SeqCst);
+                if self.fail_write == Some(number) {
+                    if self.fail_late {
+                        tokio::time::sleep(self.write_delay).await;
+                    }
+                    bail!("Write without response failed");
+                }
+            } else if self
+                .write_with_response_in_progress
+                .swap(true, Ordering::SeqCst)
+            {
+                bail!("org.bluez.Error.InProgress");
+            }
+            let in_flight = self.writes_in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_writes_in_flight
+                .fetch_max(in_flight, Ordering::SeqCst);
+            // the packet is taken in the order the writes are issued, like the BLE stack does
+            self.uart_writes
+                .lock()
+                .unwrap()
+                .push((data.to_vec(), write_type));
+            tokio::time::sleep(self.write_delay).await;
+            self.writes_in_flight.fetch_sub(1, Ordering::SeqCst);
+            if write_type == WriteType::WithResponse {
+                self.write_with_response_in_progress
+                    .store(false, Ordering::SeqCst);
+            }
+            return Ok(());
+        }
+        if characteristic.uuid == CTL && data[0] == VENDOR_BATTERY_CTL && self.battery_ctl {
+            let value = vec![VENDOR_BATTERY_CTL, 63, VENDOR_BATTERY_CTL ^ 63];
+            self.notifications_send
+                .send(ValueNotification { uuid: CTL, value })
+                .await?;
+            return Ok(());
+        }
+        if characteristic.uuid != CTL || data[0] != ControlMessageType::RequestCap as u8 {
+            bail!("Unexpected write to {}", characteristic.uuid);
+        }
+        let value = CtlBuffer::default()
+            .encode(&RawControlMessage {
+                message_type: ControlMessageType::ReturnCap,
+                body: b"1/2",
+            })?
+            .to_vec();
+        self.notifications_send
+            .send(ValueNotification { uuid: CTL, value })
+            .await?;
+        Ok(())
+    }
+
+    async fn subscribe(&self, _characteristic: &Characteristic) -> Result<()> {
+        Ok(())
+    }
+
+    fn mtu(&self) -> Option<usize> {
+        self.mtu
+    }
+
+    async fn notifications(&self) -> Result<NotificationStream> {
+        let recv = self.notifications_recv.lock().unwrap().take().unwrap();
+        Ok(Box::pin(ReceiverStream::new(recv)))
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn transport_works_over_any_peripheral() {
+    let transport = XossTransport::new(FakePeripheral::new(), TransportOptions::default())
+        .await
+        .unwrap();
+
+    assert_eq!(transport.device_info().serial_number, "0000000042");
+    assert_eq!(transport.battery_level(), Some(55));
+
+    let mut buffer = CtlBuffer::default();
+    let reply = transport
+        .request_ctl(&mut buffer, ControlMessageType::RequestCap, &[])
+        .await
+        .unwrap();
+    assert_eq!(reply.message_type, ControlMessageType::ReturnCap);
+    assert_eq!(reply.body, b"1/2");
+}
+
+#[tokio::test]
+async fn battery_level_notifications_are_followed() {
+    let peripheral = FakePeripheral::new();
+    let notifications_send = peripheral.notifications_send.clone();
+    let transport = XossTransport::new(peripheral, TransportOptions::default())
+        .await
+        .unwrap();
+
+    let mut battery_level = transport.subscribe_battery_level();
+    assert_eq!(*battery_level.borrow_and_update(), Some(55));
+
+    notifications_send
+        .send(ValueNotification {
+            uuid: BATTERY_LEVEL,
+            value: vec![54],
+        })
+        .await
+        .unwrap();
+    tokio::time::timeout(Duration::from_secs(1), battery_level.changed())
+        .await
+        .expect("No battery level change seen")
+        .unwrap();
+    assert_eq!(*battery_level.borrow(), Some(54));
+    assert_eq!(transport.battery_level(), Some(54));
+}
+
+#[tokio::test]
+async fn battery_level_is_unknown_without_battery_service() {
+    let peripheral = FakePeripheral {
+        battery_level: None,
+        ..FakePeripheral::new()
+    };
+    let options = TransportOptions {
+        battery_fallback: Some(BatterySource::Characteristic(VENDOR_BATTERY_LEVEL)),
+        ..Default::default()
+    };
+    let transport = XossTransport::new(peripheral, options).await.unwrap();
+
+    assert_eq!(transport.battery_source(), None);
+    assert_eq!(transport.battery_level(), None);
+    assert_eq!(transport.refresh_battery_level().await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn battery_level_falls_back_to_vendor_sources() {
+    let peripheral = FakePeripheral {
+        battery_level: Some(VENDOR_BATTERY_LEVEL),
+        ..FakePeripheral::new()
+    };
+    let notifications_send = peripheral.notifications_send.clone();
+    let options = TransportOptions {
+        battery_fallback: Some(BatterySource::Characteristic(VENDOR_BATTERY_LEVEL)),
+        ..Default::default()
+    };
+    let transport = XossTransport::new(peripheral, options).await.unwrap();
+    assert_eq!(
+        transport.battery_source(),
+        Some(BatterySource::Characteristic(VENDOR_BATTERY_LEVEL))
+    );
+    assert_eq!(transport.battery_level(), Some(55));
+
+    let mut battery_level = transport.subscribe_battery_level();
+    notifications_send
+        .send(ValueNotification {
+            uuid: VENDOR_BATTERY_LEVEL,
+            value: vec![54],
+        })
+        .await
+        .unwrap();
+    tokio::time::timeout(Duration::from_secs(1), battery_level.changed())
+        .await
+        .expect("No battery level change seen")
+        .unwrap();
+    assert_eq!(transport.battery_level(), Some(54));
+
+    let peripheral = FakePeripheral {
+        battery_level: None,
+        battery_ctl: true,
+        ..FakePeripheral::new()
+    };
+    let options = TransportOptions {
+        battery_fallback: Some(BatterySource::Ctl(VENDOR_BATTERY_CTL)),
+        ..Default::default()
+    };
+    let transport = XossTransport::new(peripheral, options).await.unwrap();
+    assert_eq!(
+        transport.battery_source(),
+        Some(BatterySource::Ctl(VENDOR_BATTERY_CTL))
+    );
+    assert_eq!(transport.battery_level(), Some(63));
+    assert_eq!(transport.refresh_battery_level().await.unwrap(), Some(63));
+}
+
+/// Write 1000 bytes to the UART, returning the sizes of the writes the peripheral got
+async fn uart_writes(peripheral: FakePeripheral) -> Vec<(usize, WriteType)> {
+    let uart_writes = peripheral.uart_writes.clone();
+    let transport = XossTransport::new(peripheral, TransportOptions::default())
+        .await
+        .unwrap();
+
+    let mut stream = transport.open_uart_stream().await;
+    stream.write_all(&[0; 1000]).await.unwrap();
+    stream.flush().await.unwrap();
+
+    let writes = uart_writes.lock().unwrap();
+    writes
+        .iter()
+        .map(|(data, write_type)| (data.len(), *write_type))
+        .collect()
+}
+
+#[tokio::test]
+async fn uart_writes_follow_the_mtu_and_the_characteristic() {
+    // the size the device negotiates with the phones is assumed when the MTU is not known
+    let writes = uart_writes(FakePeripheral::new()).await;
+    assert_eq!(
+        writes.iter().map(|&(len, _)| len).collect::<Vec<_>>(),
+        [206, 206, 206, 206, 176]
+    );
+    assert!(writes
+        .iter()
+        .all(|&(_, write_type)| write_type == WriteType::WithoutResponse));
+
+    let writes = uart_writes(FakePeripheral {
+        mtu: Some(517),
+        write_without_response: false,
+        ..FakePeripheral::new()
+    })
+    .await;
+    assert_eq!(
+        writes,
+        [
+            (514, WriteType::WithResponse),
+            (486, WriteType::WithResponse)
+        ]
+    );
+}
+
+#[tokio::test]
+async fn failing_writes_without_response_fall_back() {
+    let writes = uart_writes(FakePeripheral {
+        without_response_fails: true,
+        ..FakePeripheral::new()
+    })
+    .await;
+    assert_eq!(writes.len(), 5);
+    assert!(writes
+        .iter()
+        .all(|&(_, write_type)| write_type == WriteType::WithResponse));
+}
+
+#[tokio::test]
+async fn uart_writes_are_pipelined_in_order() {
+    let peripheral = FakePeripheral {
+        write_delay: Duration::from_millis(10),
+        ..FakePeripheral::new()
+    };
+    let uart_writes = peripheral.uart_writes.clone();
+    let max_writes_in_flight = peripheral.max_writes_in_flight.clone();
+    let transport = XossTransport::new(peripheral, TransportOptions::default())
+        .await
+        .unwrap();
+
+    let data = (0..4000).map(|i| i as u8).collect::<Vec<_>>();
+    let mut stream = transport.open_uart_stream().await;
+    stream.write_all(&data).await.unwrap();
+    stream.flush().await.unwrap();
+
+    let written = uart_writes
+        .lock()
+        .unwrap()
+        .iter()
+        .flat_map(|(data, _)| data.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(written, data);
+    assert!(max_writes_in_flight.load(Ordering::SeqCst) > 1);
+}
+
+#[tokio::test]
+async fn uart_writes_with_response_are_not_pipelined() {
+    let peripheral = FakePeripheral {
+        write_without_response: false,
+        write_delay: Duration::from_millis(10),
+        ..FakePeripheral::new()
+    };
+    let max_writes_in_flight = peripheral.max_writes_in_flight.clone();
+    let transport = XossTransport::new(peripheral, TransportOptions::default())
+        .await
+        .unwrap();
+
+    let mut stream = transport.open_uart_stream().await;
+    stream.write_all(&[0; 2000]).await.unwrap();
+    stream.flush().await.unwrap();
+
+    assert_eq!(max_writes_in_flight.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn uart_write_failing_mid_stream_keeps_the_order() {
+    let peripheral = FakePeripheral {
+        fail_write: Some(5),
+        write_delay: Duration::from_millis(10),
+        ..FakePeripheral::new()
+    };
+    let uart_writes = peripheral.uart_writes.clone();
+    let transport = XossTransport::new(peripheral, TransportOptions::default())
+        .await
+        .unwrap();
+
+    let data = (0..4000).map(|i| i as u8).collect::<Vec<_>>();
+    let mut stream = transport.open_uart_stream().await;
+    stream.write_all(&data).await.unwrap();
+    stream.flush().await.unwrap();
+
+    let uart_writes = uart_writes.lock().unwrap();
+    let written = uart_writes
+        .iter()
+        .flat_map(|(data, _)| data.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(written, data);
+    // the failed packet is written again with response, and so are the ones after it
+    let write_types = uart_writes
+        .iter()
+        .map(|&(_, write_type)| write_type)
+        .collect::<Vec<_>>();
+    assert_eq!(write_types[..5], [WriteType::WithoutResponse; 5]);
+    assert!(write_types[5..]
+        .iter()
+        .all(|&write_type| write_type == WriteType::WithResponse));
+}
+
+#[tokio::test]
+async fn uart_write_failing_late_fails_the_stream() {
+    let peripheral = FakePeripheral {
+        fail_write: Some(5),
+        fail_late: true,
+        write_delay: Duration::from_millis(10),
+        ..FakePeripheral::new()
+    };
+    let uart_writes = peripheral.uart_writes.clone();
+    let transport = XossTransport::new(peripheral, TransportOptions::default())
+        .await
+        .unwrap();
+
+    let mut stream = transport.open_uart_stream().await;
+    stream.write_all(&[0; 4000]).await.unwrap();
+    assert!(stream.flush().await.is_err());
+
+    // the later packets are already written, so the failed one is not written again after them
+    assert!(uart_writes
+        .lock()
+        .unwrap()
+        .iter()
+        .all(|&(_, write_type)| write_type == WriteType::WithoutResponse));
+}
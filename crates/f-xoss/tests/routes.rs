@@ -0,0 +1,121 @@
+use f_xoss::dem::Dem;
+use f_xoss::geo::LatLon;
+use f_xoss::routes::{
+    cues_from_turns, elevation_gain, route_cues, route_stats, CueKind, RouteStats, Waypoint,
+};
+
+/// Points every ~5 m going `meters` to the north from `start`, then to the east (`east` > 0) or west
+fn l_shaped_track(start: LatLon, meters: usize, east: i32) -> Vec<LatLon> {
+    let step = 5.0 / 111_195.0;
+    let mut track = (0..meters / 5)
+        .map(|i| LatLon::new(start.lat + i as f64 * step, start.lon))
+        .collect::<Vec<_>>();
+    let corner = *track.last().unwrap();
+    let lon_step = step / corner.lat.to_radians().cos();
+    track.extend(
+        (1..meters / 5)
+            .map(|i| LatLon::new(corner.lat, corner.lon + (east * i as i32) as f64 * lon_step)),
+    );
+    track
+}
+
+#[test]
+fn turns_are_found_from_the_track_shape() {
+    let start = LatLon::new(55.75, 37.61);
+
+    let cues = cues_from_turns(&l_shaped_track(start, 200, 1));
+    assert_eq!(cues.len(), 1);
+    assert_eq!(cues[0].kind, CueKind::Right);
+    assert!((cues[0].distance - 195.0).abs() < 6.0);
+
+    let cues = cues_from_turns(&l_shaped_track(start, 200, -1));
+    assert_eq!(
+        cues.iter().map(|c| c.kind).collect::<Vec<_>>(),
+        [CueKind::Left]
+    );
+
+    // a straight line
+    assert!(cues_from_turns(&l_shaped_track(start, 200, 0)[..40]).is_empty());
+}
+
+#[test]
+fn waypoints_replace_the_computed_turns() {
+    let start = LatLon::new(55.75, 37.61);
+    let track = l_shaped_track(start, 200, 1);
+
+    let waypoints = [
+        Waypoint {
+            position: track[38],
+            name: "Turn onto Tverskaya".to_string(),
+            kind: Some(CueKind::Right),
+        },
+        Waypoint {
+            position: track[10],
+            name: "Water".to_string(),
+            kind: None,
+        },
+    ];
+    let cues = route_cues(&track, &waypoints).unwrap();
+    assert_eq!(
+        cues.iter()
+            .map(|c| (c.index, c.kind, c.name.as_deref()))
+            .collect::<Vec<_>>(),
+        [
+            (10, CueKind::Checkpoint, Some("Water")),
+            (38, CueKind::Right, Some("Turn onto Tverskaya")),
+        ]
+    );
+
+    let far = Waypoint {
+        position: LatLon::new(56.0, 37.61),
+        name: "Elsewhere".to_string(),
+        kind: None,
+    };
+    assert!(route_cues(&track, &[far]).is_err());
+}
+
+#[test]
+fn osrm_modifiers_are_parsed() {
+    assert_eq!(CueKind::parse("slight left"), CueKind::SlightLeft);
+    assert_eq!(CueKind::parse("uturn"), CueKind::UTurn);
+    assert_eq!(CueKind::parse("Sharp_Right"), CueKind::SharpRight);
+    assert_eq!(CueKind::parse("Summit"), CueKind::Checkpoint);
+    assert_eq!(CueKind::from_angle(-90.0), CueKind::Left);
+    assert_eq!(CueKind::from_angle(10.0), CueKind::Straight);
+}
+
+#[test]
+fn small_elevation_changes_are_not_climbs() {
+    assert_eq!(elevation_gain([]), 0.0);
+    assert_eq!(
+        elevation_gain([100.0, 101.0, 100.0, 101.5, 100.5, 102.0]),
+        0.0
+    );
+    assert_eq!(elevation_gain([100.0, 110.0, 105.0, 120.0, 90.0]), 25.0);
+    // a slow climb made of small steps still counts
+    assert_eq!(elevation_gain((0..100).map(|i| i as f64 * 0.5)), 48.0);
+}
+
+#[test]
+fn route_gain_comes_from_the_dem() {
+    // a 3x3 tile rising from 100 m in the south to 300 m in the north
+    let dir = std::env::temp_dir().join(format!("f-xoss-routes-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let tile = [300i16, 300, 300, 200, 200, 200, 100, 100, 100]
+        .iter()
+        .flat_map(|v| v.to_be_bytes())
+        .collect::<Vec<_>>();
+    std::fs::write(dir.join("N45E007.hgt"), tile).unwrap();
+
+    // up the slope and back down, with the last point out of the tile
+    let track = [45.1, 45.5, 45.9, 45.5, 44.5].map(|lat| LatLon::new(lat, 7.5));
+    let stats = route_stats(&track, Some(&mut Dem::new(&dir))).unwrap();
+    assert_eq!(stats.gain, 160);
+    assert_eq!(stats.length, route_stats(&track, None).unwrap().length);
+    assert_eq!(
+        route_stats(&track[..1], None).unwrap(),
+        RouteStats { length: 0, gain: 0 }
+    );
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,152 @@
+use btleplug::api::CharPropFlags;
+use f_xoss::transport::ctl_message::{ControlMessageType, RawControlMessage};
+use f_xoss::transport::peripheral::Characteristic;
+use f_xoss::transport::session::{ReplayPeripheral, Session, SessionEvent};
+use f_xoss::transport::{CtlBuffer, TransportOptions, XossTransport};
+use std::sync::Arc;
+use uuid::Uuid;
+
+const UART_SERVICE: Uuid = Uuid::from_u128(0x6e400001_b5a3_f393_e0a9_e50e24dcca9e);
+const RX: Uuid = Uuid::from_u128(0x6e400003_b5a3_f393_e0a9_e50e24dcca9e);
+const CTL: Uuid = Uuid::from_u128(0x6e400004_b5a3_f393_e0a9_e50e24dcca9e);
+
+const CHARACTERISTICS: &[u128] = &[
+    0x6e400002_b5a3_f393_e0a9_e50e24dcca9e,
+    0x6e400003_b5a3_f393_e0a9_e50e24dcca9e,
+    0x6e400004_b5a3_f393_e0a9_e50e24dcca9e,
+    0x00002a26_0000_1000_8000_00805f9b34fb,
+    0x00002a29_0000_1000_8000_00805f9b34fb,
+    0x00002a24_0000_1000_8000_00805f9b34fb,
+    0x00002a27_0000_1000_8000_00805f9b34fb,
+    0x00002a25_0000_1000_8000_00805f9b34fb,
+    0x00002a19_0000_1000_8000_00805f9b34fb,
+];
+
+/// The reads done by the transport when connecting, in order
+const CONNECTION_READS: &[(u128, &[u8])] = &[
+    (0x00002a26_0000_1000_8000_00805f9b34fb, b"2.4.3"),
+    (0x00002a29_0000_1000_8000_00805f9b34fb, b"XOSS"),
+    (0x00002a24_0000_1000_8000_00805f9b34fb, b"XOSS NAV"),
+    (0x00002a27_0000_1000_8000_00805f9b34fb, b"1.0"),
+    (0x00002a25_0000_1000_8000_00805f9b34fb, b"0000000042"),
+    (0x00002a19_0000_1000_8000_00805f9b34fb, &[55]),
+];
+
+fn ctl_message(message_type: ControlMessageType, body: &[u8]) -> Vec<u8> {
+    CtlBuffer::default()
+        .encode(&RawControlMessage { message_type, body })
+        .unwrap()
+        .to_vec()
+}
+
+/// A session of connecting and asking for the memory capacity
+fn recorded_session() -> Session {
+    let characteristics = CHARACTERISTICS
+        .iter()
+        .map(|&uuid| Characteristic {
+            uuid: Uuid::from_u128(uuid),
+            service_uuid: UART_SERVICE,
+            properties: CharPropFlags::READ | CharPropFlags::NOTIFY,
+        })
+        .collect();
+
+    let mut events = CONNECTION_READS
+        .iter()
+        .map(|&(uuid, value)| SessionEvent::Read {
+            uuid: Uuid::from_u128(uuid),
+            value: value.to_vec(),
+        })
+        .collect::<Vec<_>>();
+    events.push(SessionEvent::Write {
+        uuid: CTL,
+        data: ctl_message(ControlMessageType::RequestCap, &[]),
+    });
+    events.push(SessionEvent::Notification {
+        uuid: CTL,
+        value: ctl_message(ControlMessageType::ReturnCap, b"1/2"),
+    });
+
+    Session {
+        characteristics,
+        events,
+    }
+}
+
+#[test]
+fn session_round_trips() {
+    let session = recorded_session();
+
+    let mut bytes = Vec::new();
+    session.write_to(&mut bytes).unwrap();
+    assert_eq!(Session::read_from(bytes.as_slice()).unwrap(), session);
+
+    assert!(Session::read_from(&bytes[..bytes.len() - 1]).is_err());
+    assert!(Session::read_from(&b"not a session at all"[..]).is_err());
+}
+
+#[tokio::test]
+async fn session_is_replayed() {
+    let replay = Arc::new(ReplayPeripheral::new(recorded_session()));
+    let transport = XossTransport::new(replay.clone(), TransportOptions::default())
+        .await
+        .unwrap();
+
+    assert_eq!(transport.device_info().serial_number, "0000000042");
+    assert_eq!(transport.battery_level(), Some(55));
+
+    let mut buffer = CtlBuffer::default();
+    let reply = transport
+        .request_ctl(&mut buffer, ControlMessageType::RequestCap, &[])
+        .await
+        .unwrap();
+    assert_eq!(reply.message_type, ControlMessageType::ReturnCap);
+    assert_eq!(reply.body, b"1/2");
+    assert!(replay.mismatches().is_empty());
+
+    // nothing more was recorded
+    transport
+        .request_ctl(&mut buffer, ControlMessageType::RequestCap, &[])
+        .await
+        .unwrap_err();
+}
+
+#[tokio::test]
+async fn different_writes_are_reported() {
+    let replay = Arc::new(ReplayPeripheral::new(recorded_session()));
+    let transport = XossTransport::new(replay.clone(), TransportOptions::default())
+        .await
+        .unwrap();
+
+    let mut buffer = CtlBuffer::default();
+    let reply = transport
+        .request_ctl(&mut buffer, ControlMessageType::RequestCap, b"1")
+        .await
+        .unwrap();
+    assert_eq!(reply.message_type, ControlMessageType::ReturnCap);
+    assert_eq!(replay.mismatches().len(), 1);
+}
+
+#[tokio::test]
+async fn divergence_is_an_error() {
+    let mut session = recorded_session();
+    // the device was asked for the memory capacity over the UART instead
+    let Some(SessionEvent::Write { uuid, .. }) = session.events.get_mut(CONNECTION_READS.len())
+    else {
+        unreachable!()
+    };
+    *uuid = RX;
+
+    let transport = XossTransport::new(ReplayPeripheral::new(session), TransportOptions::default())
+        .await
+        .unwrap();
+    let mut buffer = CtlBuffer::default();
+    let error = transport
+        .request_ctl(&mut buffer, ControlMessageType::RequestCap, &[])
+        .await
+        .unwrap_err();
+    assert!(
+        format!("{:#}", error).contains("diverges from the recorded session"),
+        "{:#}",
+        error
+    );
+}
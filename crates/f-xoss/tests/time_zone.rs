@@ -0,0 +1,135 @@
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use f_xoss::geo::LatLon;
+use f_xoss::model::WorkoutNaming;
+use f_xoss::time_zone::{format_offset, offset_changes, parse_offset, zone_at, OffsetChange};
+
+#[test]
+fn offsets_are_parsed() {
+    assert_eq!(parse_offset("+05:30"), Some(19800));
+    assert_eq!(parse_offset("-0330"), Some(-12600));
+    assert_eq!(parse_offset("UTC+5"), Some(18000));
+    assert_eq!(parse_offset("+545"), Some(20700));
+    assert_eq!(parse_offset("UTC"), Some(0));
+    assert_eq!(parse_offset("Europe/Berlin"), None);
+    assert_eq!(parse_offset("+25:00"), None);
+    assert_eq!(parse_offset("+05:60"), None);
+
+    assert_eq!(format_offset(19800), "+05:30");
+    assert_eq!(format_offset(-12600), "-03:30");
+}
+
+/// Adelaide: +09:30 in winter, +10:30 from 02:00 local time on the first Sunday of October (2023-10-01)
+fn adelaide(time: DateTime<Utc>) -> i32 {
+    let change = Utc.with_ymd_and_hms(2023, 9, 30, 16, 30, 0).unwrap();
+    if time < change {
+        9 * 3600 + 1800
+    } else {
+        10 * 3600 + 1800
+    }
+}
+
+#[test]
+fn dst_change_is_found_to_the_second() {
+    let start = Utc.with_ymd_and_hms(2023, 9, 20, 0, 0, 0).unwrap();
+    let changes = offset_changes(adelaide, start, start + Duration::days(20));
+    assert_eq!(
+        changes,
+        vec![OffsetChange {
+            time: Utc.with_ymd_and_hms(2023, 9, 30, 16, 30, 0).unwrap(),
+            from: 34200,
+            to: 37800,
+        }]
+    );
+
+    assert!(offset_changes(adelaide, start, start + Duration::days(5)).is_empty());
+}
+
+#[test]
+fn workout_recorded_after_a_missed_dst_change_is_named_with_the_old_offset() {
+    // synced before the change, recorded after it
+    let synced = Utc.with_ymd_and_hms(2023, 9, 25, 8, 0, 0).unwrap();
+    let start = Utc.with_ymd_and_hms(2023, 10, 3, 0, 0, 0).unwrap();
+
+    let device_offset = adelaide(synced);
+    assert_eq!(
+        WorkoutNaming::DateTime.name(start, device_offset),
+        20231003093000
+    );
+    assert_eq!(
+        WorkoutNaming::DateTime.name(start, adelaide(start)),
+        20231003103000
+    );
+}
+
+#[test]
+fn zones_are_found_by_position() {
+    let zone = |lat, lon| zone_at(LatLon { lat, lon }).map(|zone| zone.name());
+    assert_eq!(zone(52.52, 13.40), Some("Europe/Berlin"));
+    assert_eq!(zone(38.72, -9.14), Some("Europe/Lisbon"));
+    assert_eq!(zone(55.75, 37.62), Some("Europe/Moscow"));
+    assert_eq!(zone(33.45, -112.07), Some("America/Phoenix"));
+    assert_eq!(zone(-34.93, 138.60), Some("Australia/Adelaide"));
+    assert_eq!(zone(0.0, -140.0), None);
+
+    // the places close to the neighbours with other offsets
+    assert_eq!(zone(59.94, 30.31), Some("Europe/Moscow")); // St Petersburg
+    assert_eq!(zone(47.24, 39.71), Some("Europe/Moscow")); // Rostov-on-Don
+    assert_eq!(zone(51.67, 39.18), Some("Europe/Moscow")); // Voronezh
+    assert_eq!(zone(50.60, 36.59), Some("Europe/Moscow")); // Belgorod
+    assert_eq!(zone(51.25, 22.57), Some("Europe/Warsaw")); // Lublin
+    assert_eq!(zone(38.88, -6.97), Some("Europe/Madrid")); // Badajoz
+    assert_eq!(zone(37.26, -6.95), Some("Europe/Madrid")); // Huelva
+    assert_eq!(zone(69.73, 30.05), Some("Europe/Oslo")); // Kirkenes
+    assert_eq!(zone(65.80, 23.90), Some("Europe/Stockholm")); // west of Haparanda
+                                                              // Haparanda itself is a kilometre from Tornio, Finland, on the other side of the river
+    assert_eq!(zone(65.84, 24.14), None);
+    // the zones across the Swiss-German border have the same offsets, so the border doesn't matter there
+    assert_eq!(zone(47.56, 7.59), Some("Europe/Zurich")); // Basel
+
+    let at = |y, m, d, h, min| Utc.with_ymd_and_hms(y, m, d, h, min, 0).unwrap();
+
+    // the last Sunday of March 2024 is the 31st, the change is at 01:00 UTC
+    let berlin = zone_at(LatLon {
+        lat: 52.52,
+        lon: 13.40,
+    })
+    .unwrap();
+    assert_eq!(berlin.offset_at(at(2024, 3, 31, 0, 59)), 3600);
+    assert_eq!(berlin.offset_at(at(2024, 3, 31, 1, 0)), 7200);
+    assert_eq!(berlin.offset_at(at(2024, 10, 27, 1, 0)), 3600);
+
+    // 02:00 EST on the second Sunday of March 2024, 02:00 EDT on the first Sunday of November
+    let new_york = zone_at(LatLon {
+        lat: 40.71,
+        lon: -74.01,
+    })
+    .unwrap();
+    assert_eq!(new_york.offset_at(at(2024, 3, 10, 6, 59)), -5 * 3600);
+    assert_eq!(new_york.offset_at(at(2024, 3, 10, 7, 0)), -4 * 3600);
+    assert_eq!(new_york.offset_at(at(2024, 11, 3, 5, 59)), -4 * 3600);
+    assert_eq!(new_york.offset_at(at(2024, 11, 3, 6, 0)), -5 * 3600);
+
+    // the summer spans the new year in the south
+    let adelaide_zone = zone_at(LatLon {
+        lat: -34.93,
+        lon: 138.60,
+    })
+    .unwrap();
+    for time in [
+        at(2023, 7, 1, 0, 0),
+        at(2023, 9, 30, 16, 29),
+        at(2023, 9, 30, 16, 30),
+        at(2024, 1, 15, 0, 0),
+    ] {
+        assert_eq!(adelaide_zone.offset_at(time), adelaide(time));
+    }
+    // 03:00 ACDT on the first Sunday of April 2024
+    assert_eq!(
+        adelaide_zone.offset_at(at(2024, 4, 6, 16, 29)),
+        10 * 3600 + 1800
+    );
+    assert_eq!(
+        adelaide_zone.offset_at(at(2024, 4, 6, 16, 30)),
+        9 * 3600 + 1800
+    );
+}
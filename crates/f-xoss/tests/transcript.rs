@@ -0,0 +1,106 @@
+use f_xoss::device::XossDevice;
+use f_xoss::progress::NoProgress;
+use f_xoss::transcript::Transcript;
+use f_xoss::transport::ctl_message::ControlMessageType;
+use f_xoss::transport::mock::{Fault, MockDevice};
+use f_xoss::transport::{DeviceInformation, TransportOptions, XossTransport};
+use std::time::Duration;
+
+async fn connect_with_transcript() -> (MockDevice, XossDevice, Transcript) {
+    let mock = MockDevice::new(DeviceInformation {
+        firmware_revision: "1.0.0".to_string(),
+        manufacturer_name: "XOSS".to_string(),
+        model_number: "XOSS NAV".to_string(),
+        hardware_revision: "A1".to_string(),
+        serial_number: "0000000001".to_string(),
+    });
+    let device =
+        XossDevice::with_transport(XossTransport::mock(&mock, TransportOptions::default()))
+            .await
+            .unwrap();
+    let transcript = Transcript::new();
+    device.set_transcript(transcript.clone()).await;
+    (mock, device, transcript)
+}
+
+#[tokio::test]
+async fn session_is_transcribed() {
+    let (mock, device, transcript) = connect_with_transcript().await;
+    mock.set_file("test.bin", b"content".to_vec());
+
+    device.read_file("test.bin", &NoProgress).await.unwrap();
+    device.delete_file("test.bin").await.unwrap();
+    device.delete_file("test.bin").await.unwrap_err();
+
+    transcript
+        .check(
+            r#"
+            > RequestReturn "test.bin"
+            < Returning "test.bin"
+            # the device reports the end of the transfer
+            < Idle
+            = download test.bin 7 bytes
+            # deleting is refused while recording
+            > StatusAct
+            < StatusAct 0x00
+            > RequestDel "test.bin"
+            < DelSuccess "test.bin"
+            > StatusAct
+            < StatusAct 0x00
+            > RequestDel "test.bin"
+            < ErrNoFile "test.bin"
+            "#,
+        )
+        .unwrap();
+    transcript
+        .check(
+            r#"
+            > RequestReturn "test.bin"
+            ...
+            < ErrNoFile "test.bin"
+            "#,
+        )
+        .unwrap();
+}
+
+#[tokio::test]
+async fn mismatches_are_reported() {
+    let (_, device, transcript) = connect_with_transcript().await;
+
+    device.get_memory_capacity().await.unwrap();
+
+    let error = transcript
+        .check("> RequestCap\n< ReturnCap \"1/2\"")
+        .unwrap_err();
+    assert_eq!(error.actual, "> RequestCap\n< ReturnCap \"8192/8192\"");
+    assert!(transcript.check("...\n> RequestDel\n...").is_err());
+}
+
+#[tokio::test]
+async fn keepalive_stops_a_stuck_transfer() {
+    let (mock, device, transcript) = connect_with_transcript().await;
+    mock.inject_fault(
+        ControlMessageType::StatusReturn,
+        Fault::Reply(ControlMessageType::Returning),
+    );
+
+    tokio::select! {
+        _ = device.keepalive(Duration::from_millis(20)) => unreachable!(),
+        _ = tokio::time::sleep(Duration::from_millis(100)) => {}
+    }
+
+    transcript
+        .check(
+            r#"
+            > StatusReturn
+            < Returning
+            > RequestStop
+            < Idle
+            # the device is idle after that
+            > StatusReturn
+            < Idle
+            ...
+            "#,
+        )
+        .unwrap();
+}
@@ -0,0 +1,42 @@
+use f_xoss::wheel::{rollout_circumference, TireSize};
+
+#[test]
+fn tire_sizes_are_parsed() {
+    let road = TireSize {
+        width: 25.0,
+        bead_seat_diameter: 622,
+    };
+    assert_eq!(TireSize::parse("25-622"), Some(road));
+    assert_eq!(TireSize::parse("700x25c"), Some(road));
+    assert_eq!(TireSize::parse("700 x 25C"), Some(road));
+
+    let mtb = TireSize::parse("29x2.2").unwrap();
+    assert_eq!(mtb.bead_seat_diameter, 622);
+    assert!((mtb.width - 55.88).abs() < 0.01);
+
+    assert_eq!(
+        TireSize::parse("650bx47").map(|t| t.bead_seat_diameter),
+        Some(584)
+    );
+
+    assert_eq!(TireSize::parse("25"), None);
+    assert_eq!(TireSize::parse("33x2.0"), None);
+    assert_eq!(TireSize::parse("700x0"), None);
+}
+
+#[test]
+fn circumference_is_estimated_from_the_tire_size() {
+    // the official app uses 2096 for 700x23c and 2288 for 29x2.2, the estimate is close to those
+    let road = TireSize::parse("700x23c").unwrap().circumference();
+    assert!((2090..=2105).contains(&road), "{}", road);
+    let mtb = TireSize::parse("29x2.2").unwrap().circumference();
+    assert!((2280..=2310).contains(&mtb), "{}", mtb);
+}
+
+#[test]
+fn circumference_is_computed_from_a_rollout() {
+    assert_eq!(rollout_circumference(2105.0, 1), Some(2105));
+    assert_eq!(rollout_circumference(10520.0, 5), Some(2104));
+    assert_eq!(rollout_circumference(2105.0, 0), None);
+    assert_eq!(rollout_circumference(0.0, 1), None);
+}
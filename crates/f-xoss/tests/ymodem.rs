@@ -0,0 +1,307 @@
+mod common;
+
+use common::test_pattern;
+use f_xoss::progress::{NoProgress, ProgressSink};
+use f_xoss::transport::ymodem::{
+    cancel, receive_file, send_file, FrameAssembler, TransferCancelled, YModemHeader, YModemPacket,
+    LARGE_DATA_SIZE, MAX_PACKET_SIZE, SMALL_DATA_SIZE,
+};
+use std::io::Cursor;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_stream::StreamExt;
+
+#[derive(Default)]
+struct CountingProgress {
+    total: AtomicU64,
+    done: AtomicU64,
+    retries: AtomicU64,
+}
+
+impl ProgressSink for CountingProgress {
+    fn start(&self, total: u64) {
+        self.total.store(total, Ordering::SeqCst);
+    }
+
+    fn advance(&self, delta: u64) {
+        self.done.fetch_add(delta, Ordering::SeqCst);
+    }
+
+    fn retry(&self) {
+        self.retries.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Send a file through an in-memory pipe and return what the receiving side got
+async fn round_trip(content: &[u8]) -> (String, u64, Vec<u8>) {
+    round_trip_with_progress(content, &NoProgress, &NoProgress).await
+}
+
+async fn round_trip_with_progress(
+    content: &[u8],
+    send_progress: &dyn ProgressSink,
+    receive_progress: &dyn ProgressSink,
+) -> (String, u64, Vec<u8>) {
+    let (mut sender_io, mut receiver_io) = tokio::io::duplex(4096);
+
+    let send = async {
+        send_file(
+            &mut sender_io,
+            "test.bin",
+            &mut Cursor::new(content),
+            send_progress,
+        )
+        .await
+        .expect("Sending failed");
+    };
+    let receive = async {
+        let (info, stream) = receive_file(&mut receiver_io, receive_progress)
+            .await
+            .expect("Receiving the header failed");
+        let chunks = stream
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .expect("Receiving failed");
+        (info.name, info.size, chunks.concat())
+    };
+
+    let ((), received) = tokio::join!(send, receive);
+    received
+}
+
+#[tokio::test]
+async fn empty_file() {
+    let (name, size, data) = round_trip(&[]).await;
+    assert_eq!(name, "test.bin");
+    assert_eq!(size, 0);
+    assert!(data.is_empty());
+}
+
+#[tokio::test]
+async fn single_byte_file() {
+    let (_, size, data) = round_trip(&[42]).await;
+    assert_eq!(size, 1);
+    assert_eq!(data, [42]);
+}
+
+#[tokio::test]
+async fn packet_boundaries() {
+    for len in [
+        SMALL_DATA_SIZE - 1,
+        SMALL_DATA_SIZE,
+        SMALL_DATA_SIZE + 1,
+        LARGE_DATA_SIZE - 1,
+        LARGE_DATA_SIZE,
+        LARGE_DATA_SIZE + 1,
+        3 * LARGE_DATA_SIZE,
+    ] {
+        let content = test_pattern(len);
+        let (_, size, data) = round_trip(&content).await;
+        assert_eq!(size, len as u64);
+        assert_eq!(data, content, "content mismatch for {} bytes", len);
+    }
+}
+
+#[tokio::test]
+async fn progress_covers_the_whole_file() {
+    let content = test_pattern(2 * LARGE_DATA_SIZE + 5);
+    let send_progress = CountingProgress::default();
+    let receive_progress = CountingProgress::default();
+
+    round_trip_with_progress(&content, &send_progress, &receive_progress).await;
+
+    for progress in [&send_progress, &receive_progress] {
+        assert_eq!(progress.total.load(Ordering::SeqCst), content.len() as u64);
+        assert_eq!(progress.done.load(Ordering::SeqCst), content.len() as u64);
+    }
+}
+
+#[tokio::test]
+async fn null_header_is_an_error() {
+    let (mut sender_io, mut receiver_io) = tokio::io::duplex(4096);
+
+    // an all-zero header ends the batch without a file
+    YModemPacket::new(0, &[0; SMALL_DATA_SIZE])
+        .write(&mut sender_io)
+        .await
+        .unwrap();
+
+    assert!(receive_file(&mut receiver_io, &NoProgress).await.is_err());
+}
+
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const EOT: u8 = 0x04;
+
+async fn expect_byte(io: &mut (impl AsyncReadExt + Unpin), expected: u8) {
+    assert_eq!(io.read_u8().await.unwrap(), expected);
+}
+
+async fn read_small_packet(io: &mut (impl AsyncReadExt + Unpin)) -> Vec<u8> {
+    let mut raw = vec![0; 3 + SMALL_DATA_SIZE + 2];
+    io.read_exact(&mut raw).await.unwrap();
+    raw
+}
+
+#[tokio::test]
+async fn damaged_packets_are_requested_again() {
+    let (mut sender_io, mut receiver_io) = tokio::io::duplex(4096);
+    let content = test_pattern(SMALL_DATA_SIZE);
+
+    let send = async {
+        let header = YModemHeader {
+            name: "test.bin".to_string(),
+            size: content.len() as u64,
+        }
+        .encode()
+        .unwrap();
+
+        expect_byte(&mut sender_io, b'C').await;
+        // some line noise before the header
+        sender_io.write_all(&[0xaa, 0x55]).await.unwrap();
+        YModemPacket::new(0, &header)
+            .write(&mut sender_io)
+            .await
+            .unwrap();
+        expect_byte(&mut sender_io, ACK).await;
+        expect_byte(&mut sender_io, b'C').await;
+
+        let mut buffer = [0; MAX_PACKET_SIZE];
+        let mut damaged = YModemPacket::new(1, &content)
+            .serialize(&mut buffer)
+            .to_vec();
+        damaged[10] ^= 0xff;
+        sender_io.write_all(&damaged).await.unwrap();
+        expect_byte(&mut sender_io, NAK).await;
+
+        YModemPacket::new(1, &content)
+            .write(&mut sender_io)
+            .await
+            .unwrap();
+        expect_byte(&mut sender_io, ACK).await;
+
+        sender_io.write_all(&[EOT]).await.unwrap();
+        expect_byte(&mut sender_io, NAK).await;
+        sender_io.write_all(&[EOT]).await.unwrap();
+        expect_byte(&mut sender_io, ACK).await;
+    };
+    let progress = CountingProgress::default();
+    let receive = async {
+        let (_, stream) = receive_file(&mut receiver_io, &progress).await.unwrap();
+        let chunks = stream.collect::<Result<Vec<_>, _>>().await.unwrap();
+        chunks.concat()
+    };
+
+    let ((), received) = tokio::join!(send, receive);
+    assert_eq!(received, content);
+    assert_eq!(progress.retries.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn rejected_packets_are_sent_again() {
+    let (mut sender_io, mut receiver_io) = tokio::io::duplex(4096);
+    let content = test_pattern(SMALL_DATA_SIZE);
+
+    let progress = CountingProgress::default();
+    let send = async {
+        send_file(
+            &mut sender_io,
+            "test.bin",
+            &mut Cursor::new(&content),
+            &progress,
+        )
+        .await
+        .unwrap();
+    };
+    let receive = async {
+        receiver_io.write_all(b"C").await.unwrap();
+        read_small_packet(&mut receiver_io).await;
+        receiver_io.write_all(&[ACK, b'C']).await.unwrap();
+
+        let first = read_small_packet(&mut receiver_io).await;
+        receiver_io.write_all(&[NAK]).await.unwrap();
+        let second = read_small_packet(&mut receiver_io).await;
+        receiver_io.write_all(&[ACK]).await.unwrap();
+
+        expect_byte(&mut receiver_io, EOT).await;
+        receiver_io.write_all(&[NAK]).await.unwrap();
+        expect_byte(&mut receiver_io, EOT).await;
+        receiver_io.write_all(&[ACK]).await.unwrap();
+        (first, second)
+    };
+
+    let ((), (first, second)) = tokio::join!(send, receive);
+    let mut buffer = [0; MAX_PACKET_SIZE];
+    let expected = YModemPacket::new(1, &content).serialize(&mut buffer);
+    assert_eq!(first, expected);
+    assert_eq!(second, expected);
+    assert_eq!(progress.retries.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn cancel_aborts_the_transfer() {
+    let (mut sender_io, mut receiver_io) = tokio::io::duplex(4096);
+
+    let send = async {
+        expect_byte(&mut sender_io, b'C').await;
+        cancel(&mut sender_io).await.unwrap();
+    };
+    let receive = receive_file(&mut receiver_io, &NoProgress);
+
+    let ((), result) = tokio::join!(send, receive);
+    let error = result.err().expect("The transfer should fail");
+    assert!(error.downcast_ref::<TransferCancelled>().is_some());
+}
+
+#[tokio::test]
+async fn receiver_cancel_stops_the_sender() {
+    let (mut sender_io, mut receiver_io) = tokio::io::duplex(4096);
+    let mut file = Cursor::new(test_pattern(SMALL_DATA_SIZE));
+
+    let send = send_file(&mut sender_io, "test.bin", &mut file, &NoProgress);
+    let receive = async {
+        receiver_io.write_all(b"C").await.unwrap();
+        read_small_packet(&mut receiver_io).await;
+        cancel(&mut receiver_io).await.unwrap();
+    };
+
+    let (result, ()) = tokio::join!(send, receive);
+    let error = result.expect_err("The transfer should fail");
+    assert!(error.downcast_ref::<TransferCancelled>().is_some());
+}
+
+fn packet(seq: u8, fill: u8) -> Vec<u8> {
+    let data = [fill; SMALL_DATA_SIZE];
+    let mut buffer = [0; MAX_PACKET_SIZE];
+    YModemPacket::new(seq, &data)
+        .serialize(&mut buffer)
+        .to_vec()
+}
+
+#[test]
+fn packets_are_reassembled_from_any_notifications() {
+    let (first, second) = (packet(1, 0xaa), packet(2, 0x01));
+
+    // split at odd places, and the end of one packet coalesced with the start of the next
+    let mut frames = FrameAssembler::default();
+    assert_eq!(frames.push(&[0x06]), [0x06]);
+    assert!(frames.push(&first[..1]).is_empty());
+    assert!(frames.push(&first[1..20]).is_empty());
+    let mut coalesced = first[20..].to_vec();
+    coalesced.extend_from_slice(&second[..50]);
+    assert_eq!(frames.push(&coalesced), first);
+    assert!(frames.is_incomplete());
+    assert_eq!(frames.push(&second[50..]), second);
+    assert!(!frames.is_incomplete());
+
+    // a start byte without a valid sequence number after it is garbage
+    assert_eq!(
+        frames.push(&[0x01, 0x05, 0x05, 0x43]),
+        [0x01, 0x05, 0x05, 0x43]
+    );
+
+    // the packet that lost its end doesn't swallow the retransmission
+    assert!(frames.push(&second[..100]).is_empty());
+    assert_eq!(frames.drop_incomplete(), 100);
+    assert_eq!(frames.push(&second), second);
+}